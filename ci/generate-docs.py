@@ -316,6 +316,7 @@ TOC = [
                 children=[
                     Gen("cli", "cli/cli"),
                     Page("show-keys", "cli/show-keys.md"),
+                    Page("start --layout", "cli/layout.md"),
                 ],
             ),
             Page(
@@ -347,8 +348,10 @@ TOC = [
                         "config/lua/keyassignment",
                     ),
                     Gen("object: Color", "config/lua/color"),
+                    Page("object: DockerDomain", "config/lua/DockerDomain.md"),
                     Page("object: ExecDomain", "config/lua/ExecDomain.md"),
                     Page("object: LocalProcessInfo", "config/lua/LocalProcessInfo.md"),
+                    Page("object: MuxDomain", "config/lua/MuxDomain.md"),
                     Gen("object: MuxWindow", "config/lua/mux-window"),
                     Page("object: MuxTab", "config/lua/MuxTab.md"),
                     Page("object: MuxPane", "config/lua/MuxPane.md"),
@@ -12,27 +12,45 @@
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::range_plus_one))]
 
 use anyhow::{bail, Context as _, Error};
+use mux::bookmark::Bookmark;
 use mux::client::{ClientId, ClientInfo};
-use mux::pane::PaneId;
+use mux::closeconfirm::{CloseOutcome, CloseRequestId, CloseScope, PendingClose};
+use mux::dirlist::DirListing;
+use mux::domain::DomainInfo;
+use mux::domainexec::{ExecOutput, ExecRequest};
+use mux::editscrollback::EditScrollbackRequest;
+use mux::globalsearch::{GlobalSearchOptions, GlobalSearchResults};
+use mux::history::RecentDir;
+use mux::marker::Marker;
+use mux::outputwatch::{OutputMatch, OutputWatcherInfo, OutputWatcherSpec, WatcherId};
+use mux::pane::{ForegroundProcessInfo, PaneId};
 use mux::renderable::{RenderableDimensions, StableCursorPosition};
-use mux::tab::{PaneNode, SerdeUrl, SplitRequest, TabId};
+use mux::resources::PaneResourceUsage;
+use mux::share::{ShareId, ShareInfo};
+use mux::spawnquota::{SpawnLimit, SpawnLimitInfo, SpawnLimitValue};
+use mux::spawntiming::SpawnTiming;
+use mux::tab::{PaneNode, SerdeUrl, SplitPreview, SplitRequest, TabId};
+use mux::viewstate::PaneViewState;
 use mux::window::WindowId;
+use portable_pty::serial::SerialParams;
 use portable_pty::CommandBuilder;
 use rangeset::*;
 use serde::{Deserialize, Serialize};
 use smol::io::AsyncWriteExt;
 use smol::prelude::*;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Cursor;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use termwiz::hyperlink::Hyperlink;
-use termwiz::image::{ImageData, TextureCoordinate};
+use termwiz::image::{ImageData, ImageDataType, TextureCoordinate};
 use termwiz::surface::{Line, SequenceNo};
 use thiserror::Error;
 use wezterm_term::color::ColorPalette;
-use wezterm_term::{Alert, ClipboardSelection, StableRowIndex, TerminalSize};
+use wezterm_term::{Alert, ClipboardSelection, SemanticZone, StableRowIndex, TerminalSize};
 
 #[derive(Error, Debug)]
 #[error("Corrupt Response")]
@@ -330,6 +348,16 @@ macro_rules! pdu {
         }
 
         impl Pdu {
+            /// Returns the name of the variant, for use in logs and metrics
+            pub fn pdu_name(&self) -> &'static str {
+                match self {
+                    Pdu::Invalid{..} => "Invalid",
+                    $(
+                        Pdu::$name(_) => stringify!($name),
+                    )*
+                }
+            }
+
             pub fn encode<W: std::io::Write>(&self, w: W, serial: u64) -> Result<(), Error> {
                 match self {
                     Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
@@ -345,7 +373,8 @@ macro_rules! pdu {
                 }
             }
 
-            pub async fn encode_async<W: Unpin + AsyncWriteExt>(&self, w: &mut W, serial: u64) -> Result<(), Error> {
+            /// Writes the PDU to `w`, returning the number of bytes written
+            pub async fn encode_async<W: Unpin + AsyncWriteExt>(&self, w: &mut W, serial: u64) -> Result<usize, Error> {
                 match self {
                     Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
                     $(
@@ -354,7 +383,7 @@ macro_rules! pdu {
                             let encoded_size = encode_raw_async($vers, serial, &data, is_compressed, w).await?;
                             metrics::histogram!("pdu.size", encoded_size as f64, "pdu" => stringify!($name));
                             metrics::histogram!("pdu.size.rate", encoded_size as f64, "pdu" => stringify!($name));
-                            Ok(())
+                            Ok(encoded_size)
                         }
                     ,)*
                 }
@@ -416,7 +445,7 @@ macro_rules! pdu {
 /// The overall version of the codec.
 /// This must be bumped when backwards incompatible changes
 /// are made to the types and protocol.
-pub const CODEC_VERSION: usize = 25;
+pub const CODEC_VERSION: usize = 80;
 
 // Defines the Pdu enum.
 // Each struct has an explicit identifying number.
@@ -464,6 +493,98 @@ pdu! {
     GetImageCellResponse: 47,
     MovePaneToNewTab: 48,
     MovePaneToNewTabResponse: 49,
+    SetSerialParams: 50,
+    ListDomains: 51,
+    ListDomainsResponse: 52,
+    NudgePane: 53,
+    GetSemanticZones: 54,
+    GetSemanticZonesResponse: 55,
+    GetSemanticZoneText: 56,
+    GetSemanticZoneTextResponse: 57,
+    MarkSessionRemote: 58,
+    PermissionDeniedResponse: 59,
+    PaneResourceUsageUpdate: 60,
+    CloneTab: 61,
+    DomainErrorResponse: 62,
+    SetPaneOutputPaused: 63,
+    PaneOutputPausedChanged: 64,
+    CancelAttach: 65,
+    CancelAttachResponse: 66,
+    ScanPane: 67,
+    ScanPaneResponse: 68,
+    LinkPanes: 69,
+    PaneLinksChanged: 70,
+    AdoptPty: 71,
+    AdoptPtyResponse: 72,
+    SetPanePaletteOverrides: 73,
+    PanePaletteOverridesChanged: 74,
+    NegotiateImageLimits: 75,
+    NegotiateImageLimitsResponse: 76,
+    ChangeDir: 77,
+    RenderScrollback: 78,
+    RenderScrollbackResponse: 79,
+    SyncPanes: 80,
+    SyncPanesResponse: 81,
+    RequestPaneSnapshot: 82,
+    VerifyPane: 83,
+    VerifyPaneResponse: 84,
+    ListDirectory: 85,
+    ListDirectoryResponse: 86,
+    AddBookmark: 87,
+    AddBookmarkResponse: 88,
+    GetBookmarks: 89,
+    GetBookmarksResponse: 90,
+    ScrollToBookmark: 91,
+    ScrollToBookmarkResponse: 92,
+    ScrollPaneToRow: 93,
+    GetSpawnTimings: 94,
+    GetSpawnTimingsResponse: 95,
+    SetClientWorkspace: 96,
+    WindowCreatedInWorkspace: 97,
+    ExecCommand: 98,
+    ExecCommandResponse: 99,
+    AddOutputWatcher: 100,
+    AddOutputWatcherResponse: 101,
+    RemoveOutputWatcher: 102,
+    ListOutputWatchers: 103,
+    ListOutputWatchersResponse: 104,
+    OutputWatcherMatched: 105,
+    PickPanes: 106,
+    PickPanesResponse: 107,
+    AddMarker: 108,
+    AddMarkerResponse: 109,
+    RemoveMarker: 110,
+    PaneMarkersChanged: 111,
+    ShareTab: 112,
+    ShareTabResponse: 113,
+    ListShares: 114,
+    ListSharesResponse: 115,
+    RevokeShare: 116,
+    RedeemShare: 117,
+    RedeemShareResponse: 118,
+    GetRecentDirs: 119,
+    GetRecentDirsResponse: 120,
+    PreviewSplit: 121,
+    PreviewSplitResponse: 122,
+    MarkSessionInteractive: 123,
+    SetSpawnLimit: 124,
+    GetSpawnLimits: 125,
+    GetSpawnLimitsResponse: 126,
+    Authenticate: 127,
+    AuthenticateResponse: 128,
+    GetForegroundProcess: 129,
+    GetForegroundProcessResponse: 130,
+    SetPaneViewState: 131,
+    GetPaneViewStates: 132,
+    GetPaneViewStatesResponse: 133,
+    EditScrollback: 134,
+    SearchPanes: 135,
+    SearchPanesResponse: 136,
+    ConfirmClose: 137,
+    ConfirmCloseResponse: 138,
+    CloseConfirmationPending: 139,
+    AnswerCloseConfirmation: 140,
+    CloseConfirmationResolved: 141,
 }
 
 impl Pdu {
@@ -539,6 +660,13 @@ impl Pdu {
             | Pdu::SetPalette(SetPalette { pane_id, .. })
             | Pdu::NotifyAlert(NotifyAlert { pane_id, .. })
             | Pdu::SetClipboard(SetClipboard { pane_id, .. })
+            | Pdu::PaneOutputPausedChanged(PaneOutputPausedChanged { pane_id, .. })
+            | Pdu::PanePaletteOverridesChanged(PanePaletteOverridesChanged { pane_id, .. })
+            | Pdu::PaneMarkersChanged(PaneMarkersChanged { pane_id, .. })
+            | Pdu::ScrollPaneToRow(ScrollPaneToRow { pane_id, .. })
+            | Pdu::OutputWatcherMatched(OutputWatcherMatched {
+                matched: OutputMatch { pane_id, .. },
+            })
             | Pdu::PaneRemoved(PaneRemoved { pane_id }) => Some(*pane_id),
             _ => None,
         }
@@ -562,6 +690,9 @@ pub struct GetCodecVersionResponse {
     pub version_string: String,
     pub executable_path: PathBuf,
     pub config_file_path: Option<PathBuf>,
+    /// Identifies this incarnation of the server; changes
+    /// each time the server is restarted
+    pub server_instance_id: String,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -589,6 +720,8 @@ pub struct ListPanes {}
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct ListPanesResponse {
     pub tabs: Vec<PaneNode>,
+    /// The active tab of each window, in the order of the windows
+    pub active_tabs: Vec<TabId>,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -596,11 +729,16 @@ pub struct SplitPane {
     pub pane_id: PaneId,
     pub split_request: SplitRequest,
     pub command: Option<CommandBuilder>,
-    pub command_dir: Option<String>,
+    /// Ignored if `command` sets its own cwd; see
+    /// `mux::domain::resolve_command_dir`
+    pub command_dir: Option<config::keyassignment::CommandDir>,
     pub domain: config::keyassignment::SpawnTabDomain,
     /// Instead of spawning a command, move the specified
     /// pane into the new split target
     pub move_pane_id: Option<PaneId>,
+    /// Don't let `domain_rules` choose another domain for the
+    /// spawned pane
+    pub no_domain_rules: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -622,9 +760,20 @@ pub struct SpawnV2 {
     /// If None, create a new window for this new tab
     pub window_id: Option<WindowId>,
     pub command: Option<CommandBuilder>,
-    pub command_dir: Option<String>,
+    /// Ignored if `command` sets its own cwd; see
+    /// `mux::domain::resolve_command_dir`
+    pub command_dir: Option<config::keyassignment::CommandDir>,
     pub size: TerminalSize,
     pub workspace: String,
+    /// If set, and a pane matches, that pane is activated and
+    /// returned instead of spawning the command
+    pub reuse: Option<config::keyassignment::ReusePredicate>,
+    /// If set, the server resolves it to the window that is used in
+    /// place of `window_id`
+    pub window_target: Option<config::keyassignment::SpawnWindowTarget>,
+    /// Don't let `domain_rules` choose another domain for the
+    /// spawned pane
+    pub no_domain_rules: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -637,6 +786,26 @@ pub struct KillPane {
     pub pane_id: PaneId,
 }
 
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetSerialParams {
+    pub pane_id: PaneId,
+    pub params: SerialParams,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NudgePane {
+    pub pane_id: PaneId,
+}
+
+/// Creates a new tab with the same layout as `tab_id`.
+/// The response is a `SpawnResponse` that describes the active pane
+/// of the new tab.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CloneTab {
+    pub tab_id: TabId,
+    pub same_commands: bool,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SpawnResponse {
     pub tab_id: TabId,
@@ -649,12 +818,18 @@ pub struct SpawnResponse {
 pub struct WriteToPane {
     pub pane_id: PaneId,
     pub data: Vec<u8>,
+    /// True if the data was sent by `wezterm cli`, and so has not been
+    /// through the input filters of a frontend
+    pub from_cli: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SendPaste {
     pub pane_id: PaneId,
     pub data: String,
+    /// True if the data was sent by `wezterm cli`, and so has not been
+    /// through the input filters of a frontend
+    pub from_cli: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -740,6 +915,20 @@ pub struct SetClientId {
     pub client_id: ClientId,
 }
 
+/// Tells the server which workspace the client is presenting
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetClientWorkspace {
+    pub workspace: String,
+}
+
+/// Sent to the clients that present a different workspace than the
+/// one in which a window was created, in place of the window itself
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct WindowCreatedInWorkspace {
+    pub window_id: WindowId,
+    pub workspace: String,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SetFocusedPane {
     pub pane_id: PaneId,
@@ -753,6 +942,83 @@ pub struct GetClientListResponse {
     pub clients: Vec<ClientInfo>,
 }
 
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDomains;
+
+/// Sent by `wezterm cli proxy` before it relays the stream of a client
+/// on another host, so that the server applies its restrictions for
+/// remote clients to the session.  There is no response.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct MarkSessionRemote {}
+
+/// Sent in place of the usual response when the server doesn't
+/// permit the client to perform the request
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PermissionDeniedResponse {
+    pub reason: String,
+}
+
+/// Sent in place of the usual response when the request failed
+/// because of a `DomainError`.
+/// `code` is the stable code of the error and `reason` is its
+/// rendering, including the errors that caused it.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct DomainErrorResponse {
+    pub code: u32,
+    pub reason: String,
+}
+
+/// Sent unilaterally by the server each time that it samples the
+/// resource usage of its panes
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PaneResourceUsageUpdate {
+    pub samples: Vec<(PaneId, PaneResourceUsage)>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetPaneOutputPaused {
+    pub pane_id: PaneId,
+    pub paused: bool,
+}
+
+/// Sent unilaterally by the server when the output of a pane
+/// is paused or resumed
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PaneOutputPausedChanged {
+    pub pane_id: PaneId,
+    pub paused: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetPanePaletteOverrides {
+    pub pane_id: PaneId,
+    pub overrides: Option<config::Palette>,
+}
+
+/// Sent unilaterally by the server when the palette overrides
+/// of a pane are set or cleared
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PanePaletteOverridesChanged {
+    pub pane_id: PaneId,
+    pub overrides: Option<config::Palette>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CancelAttach {
+    pub domain_name: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CancelAttachResponse {
+    /// false if the domain was not being attached
+    pub cancelled: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDomainsResponse {
+    pub domains: Vec<DomainInfo>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Resize {
     pub containing_tab_id: TabId,
@@ -793,6 +1059,18 @@ pub struct GetPaneRenderChangesResponse {
 
     pub input_serial: Option<InputSerial>,
     pub seqno: SequenceNo,
+    /// True for the first response for a pane in a session.
+    /// `bonus_lines` then holds the screen and the most recent
+    /// scrollback, `dirty_lines` doesn't cover the older scrollback,
+    /// and any lines that the client retained from an earlier session
+    /// must be treated as stale.
+    pub snapshot: bool,
+    /// Counts the responses sent for this pane in the session, so that
+    /// the client can tell when one was missed or applied out of order
+    pub push_serial: u64,
+    /// When set, the `lines_checksum` of the rows of the viewport, which
+    /// the client compares against its own copy of them
+    pub checksum: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -829,6 +1107,12 @@ pub struct SerializedImageCell {
     pub padding_bottom: u16,
     pub image_id: Option<u32>,
     pub placement_id: Option<u32>,
+    /// The size of the image data, in bytes
+    pub data_len: usize,
+    /// The dimensions of the image in pixels, or 0 if the data is
+    /// held in an encoded form whose dimensions are not known
+    pub width: u32,
+    pub height: u32,
 }
 
 /// What's all this?
@@ -933,6 +1217,7 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
                     for imcell in cell_images {
                         let (padding_left, padding_top, padding_right, padding_bottom) =
                             imcell.padding();
+                        let (width, height) = image_dimensions(imcell.image_data());
                         images.push(SerializedImageCell {
                             line_idx: *stable_row_idx,
                             cell_idx: x,
@@ -946,6 +1231,9 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
                             image_id: imcell.image_id(),
                             placement_id: imcell.placement_id(),
                             data_hash: imcell.image_data().hash(),
+                            data_len: imcell.image_data().len(),
+                            width,
+                            height,
                         });
                     }
                 }
@@ -988,6 +1276,75 @@ pub struct SearchScrollbackResponse {
     pub results: Vec<mux::pane::SearchResult>,
 }
 
+/// Requests the matches of a set of regexes, and the implicit
+/// hyperlinks made by a set of hyperlink rules, in the rows of a pane,
+/// so that they can be found where the scrollback lives
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScanPane {
+    pub pane_id: PaneId,
+    pub patterns: Vec<String>,
+    pub hyperlink_rules: Vec<mux::scan::HyperlinkRuleSpec>,
+    pub range: Range<StableRowIndex>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScanPaneResponse {
+    pub matches: Vec<mux::scan::ScanMatch>,
+    pub hyperlinks: Vec<mux::scan::HyperlinkMatch>,
+}
+
+/// Links panes together; see `Mux::link_panes`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct LinkPanes {
+    pub pane_ids: Vec<PaneId>,
+    pub policy: mux::panelink::LinkPolicy,
+}
+
+/// Sent unilaterally by the server when panes were linked, so that
+/// the client can refresh the links from the pane list
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PaneLinksChanged {}
+
+/// Asks the server to prepare to replace the pty of a local pane.
+/// The response names a unix socket to which the master end of the
+/// new pty is to be passed; see `mux::ptyhandoff`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AdoptPty {
+    pub pane_id: PaneId,
+    pub pid: Option<u32>,
+    pub reset_parser: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AdoptPtyResponse {
+    pub socket_path: PathBuf,
+}
+
+/// Requests the semantic zones of a pane.
+/// If `range` is specified, only zones that overlap those rows are
+/// returned.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSemanticZones {
+    pub pane_id: PaneId,
+    pub range: Option<Range<StableRowIndex>>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSemanticZonesResponse {
+    pub zones: Vec<SemanticZone>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSemanticZoneText {
+    pub pane_id: PaneId,
+    pub zone: SemanticZone,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSemanticZoneTextResponse {
+    pub text: String,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetImageCell {
     pub pane_id: PaneId,
@@ -1002,9 +1359,471 @@ pub struct GetImageCellResponse {
     pub data: Option<Arc<ImageData>>,
 }
 
+/// The largest images that are transferred between the client and
+/// the server.  Each side proposes its own limits, and the smaller
+/// of each are used for the session.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_image_bytes: usize,
+    pub max_image_dimension: u32,
+}
+
+impl ImageLimits {
+    pub fn from_config(config: &config::ConfigHandle) -> Self {
+        Self {
+            max_image_bytes: config.mux_image_max_bytes,
+            max_image_dimension: config.mux_image_max_dimension,
+        }
+    }
+
+    /// Returns the limits that satisfy both self and other
+    pub fn intersect(&self, other: &Self) -> Self {
+        Self {
+            max_image_bytes: self.max_image_bytes.min(other.max_image_bytes),
+            max_image_dimension: self.max_image_dimension.min(other.max_image_dimension),
+        }
+    }
+
+    pub fn allows(&self, data_len: usize, width: u32, height: u32) -> bool {
+        data_len <= self.max_image_bytes
+            && width <= self.max_image_dimension
+            && height <= self.max_image_dimension
+    }
+
+    pub fn allows_cell(&self, cell: &SerializedImageCell) -> bool {
+        self.allows(cell.data_len, cell.width, cell.height)
+    }
+
+    pub fn allows_data(&self, data: &ImageData) -> bool {
+        let (width, height) = image_dimensions(data);
+        self.allows(data.len(), width, height)
+    }
+}
+
+/// Returns the dimensions of an image in pixels, or 0 if the data is
+/// held in an encoded form whose dimensions are not known
+fn image_dimensions(data: &ImageData) -> (u32, u32) {
+    match &*data.data() {
+        ImageDataType::Rgba8 { width, height, .. }
+        | ImageDataType::AnimRgba8 { width, height, .. } => (*width, *height),
+        ImageDataType::EncodedFile(_) => (0, 0),
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NegotiateImageLimits {
+    pub limits: ImageLimits,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NegotiateImageLimitsResponse {
+    pub limits: ImageLimits,
+}
+
+/// Asks the server to type a `cd` command into the shell of a pane.
+/// The checks that the pane is at a shell prompt are made by the
+/// server, which knows the foreground process of the pane.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ChangeDir {
+    pub pane_id: PaneId,
+    pub dir: String,
+    pub enter: bool,
+}
+
+/// Asks the server to render a chunk of the lines of a pane; see
+/// `Pane::render_scrollback`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RenderScrollback {
+    pub pane_id: PaneId,
+    pub format: mux::scrollback::ScrollbackFormat,
+    pub range: Option<Range<StableRowIndex>>,
+    pub header: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RenderScrollbackResponse {
+    pub chunk: mux::scrollback::ScrollbackChunk,
+}
+
+/// Asks the server to push the current state of these panes, in
+/// this order, before it responds.  A client that is attaching lists
+/// the panes that it shows first ahead of the others.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SyncPanes {
+    pub pane_ids: Vec<PaneId>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SyncPanesResponse {
+    /// The panes that no longer exist
+    pub missing: Vec<PaneId>,
+}
+
+/// Sent by a client that found its copy of a pane to be out of step
+/// with the server; the next update for the pane is a snapshot
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RequestPaneSnapshot {
+    pub pane_id: PaneId,
+}
+
+/// Asks the server to send a checksum of the screen of a pane to each
+/// of its clients, which compare it against their copy and recover
+/// from any difference
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct VerifyPane {
+    pub pane_id: PaneId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct VerifyPaneResponse {
+    pub checksum: u64,
+    pub seqno: SequenceNo,
+}
+
+/// Lists a directory as seen by the panes of a domain, in order to
+/// complete the working directory of a command that is about to be
+/// spawned into it
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDirectory {
+    pub domain: config::keyassignment::SpawnTabDomain,
+    pub path: String,
+    /// The most entries to return
+    pub limit: usize,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDirectoryResponse {
+    pub listing: DirListing,
+}
+
+/// Runs a short command in a domain without creating a pane, and
+/// captures its output
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ExecCommand {
+    pub domain: config::keyassignment::SpawnTabDomain,
+    pub request: ExecRequest,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ExecCommandResponse {
+    pub output: ExecOutput,
+}
+
+/// Bookmarks `row` of a pane, or the row of its cursor if None
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddBookmark {
+    pub pane_id: PaneId,
+    pub name: String,
+    pub row: Option<StableRowIndex>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddBookmarkResponse {
+    pub bookmark: Bookmark,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetBookmarks {
+    pub pane_id: PaneId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetBookmarksResponse {
+    pub bookmarks: Vec<Bookmark>,
+}
+
+/// Scrolls the pane to a bookmark in the windows of each client that
+/// shows it
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScrollToBookmark {
+    pub pane_id: PaneId,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScrollToBookmarkResponse {
+    pub row: StableRowIndex,
+}
+
+/// Sent by the server to ask its clients to scroll the pane so that
+/// `row` is at the top of the viewport
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScrollPaneToRow {
+    pub pane_id: PaneId,
+    pub row: StableRowIndex,
+}
+
+/// Returns the timings of the most recent spawns and splits
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSpawnTimings {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSpawnTimingsResponse {
+    /// Oldest first
+    pub timings: Vec<SpawnTiming>,
+}
+
+/// Adds an output watcher to a pane on behalf of the client, which is
+/// sent the matches of the watcher
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddOutputWatcher {
+    pub pane_id: PaneId,
+    pub spec: OutputWatcherSpec,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddOutputWatcherResponse {
+    pub watcher_id: WatcherId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RemoveOutputWatcher {
+    pub pane_id: PaneId,
+    pub watcher_id: WatcherId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListOutputWatchers {
+    pub pane_id: PaneId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListOutputWatchersResponse {
+    pub watchers: Vec<OutputWatcherInfo>,
+}
+
+/// Sent by the server to the client that added an output watcher
+/// when the watcher matches
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct OutputWatcherMatched {
+    pub matched: OutputMatch,
+}
+
+/// Asks the GUI that serves the connection to let the user choose a
+/// pane, or several if `multi` is true
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PickPanes {
+    pub prompt: Option<String>,
+    pub multi: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PickPanesResponse {
+    /// None if the user cancelled the pick
+    pub pane_ids: Option<Vec<PaneId>>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddMarker {
+    pub pane_id: PaneId,
+    pub marker: Marker,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AddMarkerResponse {
+    pub marker: Marker,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RemoveMarker {
+    pub pane_id: PaneId,
+    pub id: String,
+}
+
+/// Sent unilaterally by the server when a marker is added to or
+/// removed from a pane
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PaneMarkersChanged {
+    pub pane_id: PaneId,
+    pub markers: Vec<Marker>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ShareTab {
+    pub tab_id: TabId,
+    /// How long the token may be redeemed for
+    pub expires: Duration,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ShareTabResponse {
+    pub share: ShareInfo,
+    /// The `TOKEN@host:port` link that the viewer connects with
+    pub link: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListShares {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListSharesResponse {
+    pub shares: Vec<ShareInfo>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RevokeShare {
+    pub id: ShareId,
+}
+
+/// Sent by a client that connected with a share token, to limit its
+/// session to the shared tab
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RedeemShare {
+    pub secret: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RedeemShareResponse {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetRecentDirs {
+    /// The most directories to return; all of them if None
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetRecentDirsResponse {
+    /// Most recently visited first
+    pub dirs: Vec<RecentDir>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PreviewSplit {
+    pub pane_id: PaneId,
+    pub split_request: SplitRequest,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PreviewSplitResponse {
+    /// None if the pane cannot be split that way
+    pub preview: Option<SplitPreview>,
+}
+
+/// Sent by the GUI when it attaches to a mux, so that the spawns that
+/// it requests are treated as those of the user rather than of a script
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct MarkSessionInteractive {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetSpawnLimit {
+    pub limit: SpawnLimit,
+    pub value: SpawnLimitValue,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSpawnLimits {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetSpawnLimitsResponse {
+    pub limits: Vec<SpawnLimitInfo>,
+}
+
+/// Sent right after the version check to a TLS server that requires
+/// clients to authenticate with a token.  The server closes the
+/// connection if the token is refused.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Authenticate {
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AuthenticateResponse {
+    pub role: config::AuthRole,
+}
+
+/// Asks the server for the foreground process of a pane, which only
+/// the server can see
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetForegroundProcess {
+    pub pane_id: PaneId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetForegroundProcessResponse {
+    /// None if the server doesn't know the foreground process
+    pub info: Option<ForegroundProcessInfo>,
+}
+
+/// Tells the server how the client views a pane, so that the view can
+/// be restored when the client reattaches
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetPaneViewState {
+    pub pane_id: PaneId,
+    pub state: PaneViewState,
+}
+
+/// Asks the server for the views of its panes that the client, or an
+/// earlier connection of the same user from the same host, reported
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneViewStates {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneViewStatesResponse {
+    pub states: HashMap<PaneId, PaneViewState>,
+}
+
+/// Opens the scrollback of a pane in an editor, in a split of its tab.
+/// The response is a `SpawnResponse` for the pane of the editor.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EditScrollback {
+    pub pane_id: PaneId,
+    pub request: EditScrollbackRequest,
+}
+
+/// Searches the scrollback of the panes that `options` selects
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SearchPanes {
+    pub pattern: mux::pane::Pattern,
+    pub options: GlobalSearchOptions,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SearchPanesResponse {
+    pub results: GlobalSearchResults,
+}
+
+/// Closes `scope`, once a client has confirmed it if any of its panes
+/// are running something, or right away if `force` is true.
+/// The response is sent once the close has been decided.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ConfirmClose {
+    pub scope: CloseScope,
+    pub force: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ConfirmCloseResponse {
+    pub outcome: CloseOutcome,
+}
+
+/// Sent unilaterally by the server when a close is waiting to be
+/// confirmed, so that the client prompts for it
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CloseConfirmationPending {
+    pub request: PendingClose,
+}
+
+/// Answers a `CloseConfirmationPending` request; the first answer wins
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AnswerCloseConfirmation {
+    pub id: CloseRequestId,
+    pub confirmed: bool,
+}
+
+/// Sent unilaterally by the server when a close request was decided,
+/// so that the client dismisses its prompt for it
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CloseConfirmationResolved {
+    pub id: CloseRequestId,
+    pub outcome: CloseOutcome,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_frame() {
@@ -1110,6 +1929,57 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_image_limits() {
+        let client = ImageLimits {
+            max_image_bytes: 1000,
+            max_image_dimension: 4096,
+        };
+        let server = ImageLimits {
+            max_image_bytes: 5000,
+            max_image_dimension: 256,
+        };
+        let limits = client.intersect(&server);
+        assert_eq!(
+            limits,
+            ImageLimits {
+                max_image_bytes: 1000,
+                max_image_dimension: 256,
+            }
+        );
+        assert!(limits.allows(1000, 256, 10));
+        assert!(!limits.allows(1001, 256, 10));
+        assert!(!limits.allows(1000, 10, 257));
+        // Encoded images of unknown size are limited by bytes alone
+        assert!(limits.allows(10, 0, 0));
+    }
+
+    #[test]
+    fn test_pdu_palette_overrides() {
+        let mut overrides = config::Palette::default();
+        overrides.background = Some(config::RgbaColor::try_from("#3b0000".to_string()).unwrap());
+        overrides.indexed.insert(
+            52,
+            config::RgbaColor::try_from("#ff0000".to_string()).unwrap(),
+        );
+        let pdu = || {
+            Pdu::PanePaletteOverridesChanged(PanePaletteOverridesChanged {
+                pane_id: 1,
+                overrides: Some(overrides.clone()),
+            })
+        };
+
+        let mut encoded = Vec::new();
+        pdu().encode(&mut encoded, 0x42).unwrap();
+        assert_eq!(
+            DecodedPdu {
+                serial: 0x42,
+                pdu: pdu(),
+            },
+            Pdu::decode(encoded.as_slice()).unwrap()
+        );
+    }
+
     #[test]
     fn test_bogus_pdu() {
         let mut encoded = Vec::new();
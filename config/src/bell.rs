@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use wezterm_dynamic::{FromDynamic, ToDynamic};
 
 /// <https://developer.mozilla.org/en-US/docs/Web/CSS/easing-function>
@@ -76,3 +77,50 @@ impl Default for AudibleBell {
         Self::SystemBeep
     }
 }
+
+/// Controls which desktop notifications (OSC 9, OSC 777) emitted by
+/// the panes in a domain are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+#[dynamic(try_from = "String", into = "String")]
+pub enum NotificationHandling {
+    /// Show notifications from all panes
+    All,
+    /// Only show notifications from the active pane of the active
+    /// tab of a window
+    FocusedPaneOnly,
+    /// Don't show notifications
+    None,
+}
+
+impl Default for NotificationHandling {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl TryFrom<String> for NotificationHandling {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        match s.as_str() {
+            "all" => Ok(Self::All),
+            "focused-pane-only" => Ok(Self::FocusedPaneOnly),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "invalid notification handling {}, \
+                 expected one of all, focused-pane-only, none",
+                s
+            )),
+        }
+    }
+}
+
+impl Into<String> for &NotificationHandling {
+    fn into(self) -> String {
+        match self {
+            NotificationHandling::All => "all",
+            NotificationHandling::FocusedPaneOnly => "focused-pane-only",
+            NotificationHandling::None => "none",
+        }
+        .to_string()
+    }
+}
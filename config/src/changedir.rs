@@ -0,0 +1,60 @@
+//! Describes how to type a `cd` command into each of the shells that
+//! `pane:change_dir` and `wezterm cli change-dir` know about
+use std::collections::HashMap;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// How a directory is quoted for a shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub enum ShellQuoting {
+    /// Single quotes, with each `'` written as `'\''`; bash, zsh etc.
+    Posix,
+    /// Single quotes, with `\` and `'` escaped by a backslash
+    Fish,
+    /// Single quotes, with each `'` doubled
+    PowerShell,
+    /// Double quotes; cmd.exe has no way to escape a `"`, but
+    /// Windows doesn't allow one in a path either
+    Cmd,
+}
+
+/// The command that changes the directory of a shell
+#[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct ChangeDirShell {
+    /// The command, in which `{dir}` is replaced by the quoted directory
+    pub command: String,
+    pub quoting: ShellQuoting,
+}
+
+impl ChangeDirShell {
+    fn new(command: &str, quoting: ShellQuoting) -> Self {
+        Self {
+            command: command.to_string(),
+            quoting,
+        }
+    }
+}
+
+pub(crate) fn default_change_dir_shells() -> HashMap<String, ChangeDirShell> {
+    let mut shells = HashMap::new();
+    for name in &["sh", "bash", "zsh", "dash", "ksh", "mksh", "yash"] {
+        shells.insert(
+            name.to_string(),
+            ChangeDirShell::new("cd -- {dir}", ShellQuoting::Posix),
+        );
+    }
+    shells.insert(
+        "fish".to_string(),
+        ChangeDirShell::new("cd {dir}", ShellQuoting::Fish),
+    );
+    for name in &["pwsh", "powershell"] {
+        shells.insert(
+            name.to_string(),
+            ChangeDirShell::new("Set-Location -LiteralPath {dir}", ShellQuoting::PowerShell),
+        );
+    }
+    shells.insert(
+        "cmd".to_string(),
+        ChangeDirShell::new("cd /d {dir}", ShellQuoting::Cmd),
+    );
+    shells
+}
@@ -1,5 +1,6 @@
 use crate::*;
 use luahelper::impl_lua_conversion_dynamic;
+use serde::{Deserialize, Serialize};
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
 use termwiz::cell::CellAttributes;
@@ -27,8 +28,11 @@ impl Default for HsbTransform {
     }
 }
 
-#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, FromDynamic, ToDynamic)]
+#[derive(
+    Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, FromDynamic, ToDynamic,
+)]
 #[dynamic(try_from = "String", into = "String")]
+#[serde(try_from = "String", into = "String")]
 pub struct RgbaColor {
     #[dynamic(flatten)]
     color: SrgbaTuple,
@@ -83,7 +87,7 @@ impl TryFrom<String> for RgbaColor {
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, FromDynamic, ToDynamic)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize, FromDynamic, ToDynamic)]
 pub struct Palette {
     /// The text color to use when the attributes are reset to default
     pub foreground: Option<RgbaColor>,
@@ -161,6 +165,15 @@ impl From<ColorPalette> for Palette {
 impl From<Palette> for ColorPalette {
     fn from(cfg: Palette) -> ColorPalette {
         let mut p = ColorPalette::default();
+        cfg.apply_to(&mut p);
+        p
+    }
+}
+
+impl Palette {
+    /// Replaces the colors of `p` with those that are set in this palette
+    pub fn apply_to(&self, p: &mut ColorPalette) {
+        let cfg = self;
         macro_rules! apply_color {
             ($name:ident) => {
                 if let Some($name) = cfg.$name {
@@ -199,12 +212,11 @@ impl From<Palette> for ColorPalette {
             }
             p.colors.0[idx as usize] = col.into();
         }
-        p
     }
 }
 
 /// Specify the text styling for a tab in the tab bar
-#[derive(Debug, Clone, Default, PartialEq, FromDynamic, ToDynamic)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, FromDynamic, ToDynamic)]
 pub struct TabBarColor {
     /// Specifies the intensity attribute for the tab title text
     #[dynamic(default)]
@@ -240,7 +252,7 @@ impl TabBarColor {
 /// Specifies the colors to use for the tab bar portion of the UI.
 /// These are not part of the terminal model and cannot be updated
 /// in the same way that the dynamic color schemes are.
-#[derive(Debug, Clone, PartialEq, FromDynamic, ToDynamic)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, FromDynamic, ToDynamic)]
 pub struct TabBarColors {
     /// The background color for the tab bar
     #[dynamic(default = "default_background")]
@@ -1,9 +1,12 @@
 use crate::background::{BackgroundLayer, Gradient};
 use crate::bell::{AudibleBell, EasingFunction, VisualBell};
+use crate::changedir::{default_change_dir_shells, ChangeDirShell};
 use crate::color::{
     ColorSchemeFile, HsbTransform, Palette, SrgbaTuple, TabBarStyle, WindowFrameConfig,
 };
+use crate::cwd::{expand_local_cwd, expand_wsl_cwd, CwdExpansion};
 use crate::daemon::DaemonOptions;
+use crate::domain::{local_hostname, DefaultDomainRule, DomainKind, DomainNameCollision};
 use crate::exec_domain::ExecDomain;
 use crate::font::{
     AllowSquareGlyphOverflow, FontLocatorSelection, FontRasterizerSelection, FontShaperSelection,
@@ -18,7 +21,7 @@ use crate::lua::make_lua_context;
 use crate::ssh::{SshBackend, SshDomain};
 use crate::tls::{TlsDomainClient, TlsDomainServer};
 use crate::units::Dimension;
-use crate::unix::UnixDomain;
+use crate::unix::{CliListen, UnixDomain};
 use crate::wsl::WslDomain;
 use crate::{
     default_config_with_overrides_applied, default_one_point_oh, default_one_point_oh_f64,
@@ -30,7 +33,7 @@ use luahelper::impl_lua_conversion_dynamic;
 use mlua::FromLua;
 use portable_pty::CommandBuilder;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
@@ -138,6 +141,15 @@ pub struct Config {
     /// as the positional arguments to that command.
     pub default_prog: Option<Vec<String>>,
 
+    /// Whether the default program runs as a login shell.  When true,
+    /// the default shell is started as a login shell, and `-l` is
+    /// passed to a `default_prog` that runs a shell.  When false, the
+    /// default shell is not started as a login shell, even on macOS.
+    /// When unset, the default shell is a login shell on unix.
+    /// Exec domains may override this with their own `login_shell`.
+    #[dynamic(default)]
+    pub login_shell: Option<bool>,
+
     #[dynamic(default = "default_gui_startup_args")]
     pub default_gui_startup_args: Vec<String>,
 
@@ -149,9 +161,100 @@ pub struct Config {
     #[dynamic(default)]
     pub exit_behavior: ExitBehavior,
 
+    /// When true, the mux server persists its pane, tab, window
+    /// and domain id counters to its runtime directory so that
+    /// ids are not reused after the server is restarted.
+    #[dynamic(default)]
+    pub mux_persist_ids: bool,
+
+    /// When true and there are no persisted id counters, the mux
+    /// server starts allocating ids from a random offset.
+    #[dynamic(default)]
+    pub mux_randomize_id_epoch: bool,
+
+    /// How many lines of scrollback, in addition to the screen, the
+    /// mux server sends when a client first syncs a pane.  Older lines
+    /// are fetched by the client when it scrolls back to them.
+    #[dynamic(default = "default_mux_snapshot_scrollback_lines")]
+    pub mux_snapshot_scrollback_lines: usize,
+
+    /// How many panes per second a client synchronizes in the
+    /// background after it attaches a multiplexer domain, once the
+    /// panes that are visible have been synchronized.  0 removes the
+    /// limit.
+    #[dynamic(default = "default_mux_background_sync_panes_per_second")]
+    pub mux_background_sync_panes_per_second: u32,
+
+    /// Once the output of a pane has been quiet for this many seconds,
+    /// the mux server sends a checksum of its screen so that a client
+    /// can detect that its copy is out of step and fetch a snapshot.
+    /// 0 disables the checksums.
+    #[dynamic(default = "default_mux_pane_checksum_interval")]
+    pub mux_pane_checksum_interval: u64,
+
+    /// The shells into which `pane:change_dir` may type a `cd`
+    /// command, keyed by the name of their executable
+    #[dynamic(default = "default_change_dir_shells")]
+    pub change_dir_shells: HashMap<String, ChangeDirShell>,
+
+    /// The names of the domains whose new panes are not told, via a
+    /// notice printed in the pane, that the cwd they were intended to
+    /// have was dropped
+    #[dynamic(default)]
+    pub suppress_dropped_cwd_notice: Vec<String>,
+
+    /// Spawns new tabs, windows and splits into another domain than
+    /// the one that they would otherwise use, when the directory that
+    /// they start in matches a rule.  Only spawns that use the domain
+    /// of the current pane, or the default domain, are redirected; the
+    /// first rule that matches wins.
+    #[dynamic(default)]
+    pub domain_rules: Vec<DomainRule>,
+
+    /// Images larger than this many bytes are not transferred between
+    /// the multiplexer server and its clients
+    #[dynamic(default = "default_mux_image_max_bytes")]
+    pub mux_image_max_bytes: usize,
+
+    /// Images whose width or height, in pixels, exceeds this are not
+    /// transferred between the multiplexer server and its clients
+    #[dynamic(default = "default_mux_image_max_dimension")]
+    pub mux_image_max_dimension: u32,
+
+    /// The number of bytes of image data that the multiplexer server
+    /// keeps for each pane, so that it can serve clients' requests for
+    /// images without having to find them in the pane's lines
+    #[dynamic(default = "default_mux_image_cache_size")]
+    pub mux_image_cache_size: usize,
+
+    /// If set, the mux appends a JSON record describing each significant
+    /// operation (attach, spawn, split, kill, input) to this file.
+    #[dynamic(default)]
+    pub audit_log: Option<PathBuf>,
+
+    /// When true, the audit log records the text that was sent to panes
+    /// rather than just its length in bytes
+    #[dynamic(default)]
+    pub audit_log_input: bool,
+
+    /// When the audit log grows beyond this many bytes it is rotated
+    #[dynamic(default = "default_audit_log_max_size")]
+    pub audit_log_max_size: u64,
+
+    /// How many rotated audit log files are kept
+    #[dynamic(default = "default_audit_log_keep_files")]
+    pub audit_log_keep_files: usize,
+
     #[dynamic(default = "default_clean_exits")]
     pub clean_exit_codes: Vec<u32>,
 
+    /// If a program exits unsuccessfully within this many milliseconds
+    /// of being spawned, its final output and exit status are logged
+    /// and the pane is held open with an explanation, regardless of
+    /// the exit_behavior setting.  Set to 0 to disable.
+    #[dynamic(default = "default_early_exit_threshold_ms")]
+    pub early_exit_threshold_ms: u64,
+
     /// Specifies a map of environment variables that should be set
     /// when spawning commands in the local domain.
     /// This is not used when working with remote domains.
@@ -246,10 +349,82 @@ pub struct Config {
     #[dynamic(default)]
     pub exec_domains: Vec<ExecDomain>,
 
+    /// When true, the `fixup_command` and `label` callbacks of exec
+    /// domains are evaluated in a restricted lua context
+    #[dynamic(default)]
+    pub sandbox_exec_domains: bool,
+
+    /// The number of lua instructions that a sandboxed exec domain
+    /// callback may execute
+    #[dynamic(default = "default_sandbox_exec_domain_max_instructions")]
+    pub sandbox_exec_domain_max_instructions: u64,
+
+    /// The number of bytes that a sandboxed exec domain callback
+    /// may allocate
+    #[dynamic(default = "default_sandbox_exec_domain_max_memory")]
+    pub sandbox_exec_domain_max_memory: usize,
+
+    /// The set of docker domains
+    #[dynamic(default)]
+    pub docker_domains: Vec<DockerDomain>,
+
+    /// When true, a docker domain named `docker:<container>` is
+    /// registered for each running container
+    #[dynamic(default)]
+    pub docker_domain_discovery: bool,
+
+    /// The docker cli that is used to discover containers and
+    /// to run commands in them
+    #[dynamic(default = "default_docker_command")]
+    pub docker_command: String,
+
+    /// On Linux, run each pane spawned by a local domain in its own
+    /// transient systemd scope
+    #[dynamic(default)]
+    pub use_systemd_scope: bool,
+
+    /// The `MemoryMax` property of the systemd scope of each pane
+    #[dynamic(default)]
+    pub systemd_scope_memory_max: Option<String>,
+
+    /// The `CPUWeight` property of the systemd scope of each pane
+    #[dynamic(default)]
+    pub systemd_scope_cpu_weight: Option<u32>,
+
+    /// The number of default-prog shells that a local domain keeps
+    /// spawned ahead of time, so that new panes can claim one rather
+    /// than waiting for a shell to start.  0 disables the pool.
+    #[dynamic(default)]
+    pub prewarm_pane_pool_size: usize,
+
+    /// A prewarmed shell that has not been claimed after this many
+    /// seconds is killed and replaced by a fresh one
+    #[dynamic(default = "default_prewarm_pane_idle_timeout")]
+    pub prewarm_pane_idle_timeout: u64,
+
+    /// The names of the local domains that keep a prewarm pool
+    #[dynamic(default = "default_prewarm_pane_domains")]
+    pub prewarm_pane_domains: Vec<String>,
+
+    /// If true, the panes of the local domain are run under supervisor
+    /// processes that keep them alive if wezterm crashes, so that they
+    /// can be adopted when wezterm is started again
+    #[dynamic(default)]
+    pub resilient_local_panes: bool,
+
+    /// How many bytes of recent output the supervisor of a resilient
+    /// pane keeps, to recreate the screen when the pane is adopted
+    #[dynamic(default = "default_resilient_pane_replay_buffer_size")]
+    pub resilient_pane_replay_buffer_size: usize,
+
     /// The set of unix domains
     #[dynamic(default = "UnixDomain::default_unix_domains")]
     pub unix_domains: Vec<UnixDomain>,
 
+    /// An additional, fixed, endpoint that the multiplexer listens on
+    #[dynamic(default)]
+    pub cli_listen: Option<CliListen>,
+
     #[dynamic(default)]
     pub ssh_domains: Vec<SshDomain>,
 
@@ -261,6 +436,17 @@ pub struct Config {
     #[dynamic(default)]
     pub tls_servers: Vec<TlsDomainServer>,
 
+    /// When running in server mode, the address on which metrics
+    /// are served over plain HTTP in the Prometheus text format
+    #[dynamic(default)]
+    pub metrics_bind_address: Option<String>,
+
+    /// When running in server mode, the names of the domains that
+    /// remote clients can see and spawn into.  If unset, all
+    /// domains are available to remote clients.
+    #[dynamic(default)]
+    pub remote_spawnable_domains: Option<Vec<String>>,
+
     /// The set of tls domains that we can connect to as a client
     #[dynamic(default)]
     pub tls_clients: Vec<TlsDomainClient>,
@@ -280,6 +466,68 @@ pub struct Config {
     #[dynamic(default = "default_mux_output_parser_buffer_size")]
     pub mux_output_parser_buffer_size: usize,
 
+    /// The maximum number of PDUs that may be queued for a client
+    /// of the mux server.  When a slow client reaches this limit,
+    /// updates to its panes are deferred and coalesced rather than
+    /// being queued, and its requests are not read until it has
+    /// caught up.
+    #[dynamic(default = "default_mux_client_output_queue_limit")]
+    pub mux_client_output_queue_limit: usize,
+
+    /// If the reader for a pane has been blocked for at least this
+    /// long while the pty has output waiting to be read, the reader
+    /// is considered to have stalled and is restarted.
+    /// Set to 0 to disable the watchdog.
+    #[dynamic(default = "default_pty_reader_stall_timeout_ms")]
+    pub pty_reader_stall_timeout_ms: u64,
+
+    /// How often to sample the memory and CPU usage of the processes
+    /// in local panes.  Set to 0, the default, to disable sampling.
+    #[dynamic(default)]
+    pub pane_resource_sample_interval_ms: u64,
+
+    /// Title, working directory and user var changes that a pane makes
+    /// within this many milliseconds of the previous ones are coalesced,
+    /// so that only the latest value of each is sent to mux clients and
+    /// dispatched to the GUI.
+    #[dynamic(default = "default_pane_metadata_coalesce_ms")]
+    pub pane_metadata_coalesce_ms: u64,
+
+    /// How many of the most recently visited working directories the
+    /// mux remembers for `SpawnInRecentDir` and `wezterm cli recent-dirs`
+    #[dynamic(default = "default_recent_dirs_max_entries")]
+    pub recent_dirs_max_entries: usize,
+
+    /// Whether the recently visited working directories are saved in
+    /// the runtime directory, so that they survive a restart
+    #[dynamic(default = "default_true")]
+    pub persist_recent_dirs: bool,
+
+    /// Whether the history of a pane includes the commands that were
+    /// run in it, which requires OSC 133 shell integration
+    #[dynamic(default = "default_true")]
+    pub pane_history_commands: bool,
+
+    /// The most panes that the mux may have before spawns made through
+    /// the mux protocol, such as by `wezterm cli`, are refused
+    #[dynamic(default)]
+    pub spawn_limit_max_panes: Option<usize>,
+
+    /// The most panes that a domain may have before spawns into it that
+    /// are made through the mux protocol are refused
+    #[dynamic(default)]
+    pub spawn_limit_max_panes_per_domain: Option<usize>,
+
+    /// The most panes that may be spawned through the mux protocol
+    /// within a second
+    #[dynamic(default)]
+    pub spawn_limit_max_spawns_per_second: Option<usize>,
+
+    /// Whether the spawn limits also apply to spawns that the user
+    /// makes in the GUI
+    #[dynamic(default)]
+    pub spawn_limits_apply_to_interactive: bool,
+
     #[dynamic(default = "default_mux_env_remove")]
     pub mux_env_remove: Vec<String>,
 
@@ -617,6 +865,16 @@ pub struct Config {
     #[dynamic(default = "default_true")]
     pub unzoom_on_switch_pane: bool,
 
+    #[dynamic(default = "default_true")]
+    pub unzoom_on_split: bool,
+
+    /// How long the key of a `SplitPanePreview` assignment must be held
+    /// before the outline of the new pane is shown.  Releasing the key
+    /// makes the split.  Set to 0 to split right away, as `SplitPane`
+    /// does.
+    #[dynamic(default = "default_split_preview_hold_delay_ms")]
+    pub split_preview_hold_delay_ms: u64,
+
     #[dynamic(default = "default_max_fps")]
     pub max_fps: u8,
 
@@ -626,9 +884,24 @@ pub struct Config {
     #[dynamic(default)]
     pub audible_bell: AudibleBell,
 
+    /// Which desktop notifications are shown for panes in domains
+    /// that don't specify their own `notifications` policy
+    #[dynamic(default)]
+    pub notification_handling: NotificationHandling,
+
+    /// The maximum number of desktop notifications that a single
+    /// pane may show per minute; 0 means unlimited
+    #[dynamic(default = "default_ratelimit_notifications_per_pane_per_minute")]
+    pub ratelimit_notifications_per_pane_per_minute: u32,
+
     #[dynamic(default)]
     pub canonicalize_pasted_newlines: Option<NewlineCanon>,
 
+    /// Which pastes into a shell are held until the user confirms
+    /// them; domains may override this with their own `paste_guard`
+    #[dynamic(default)]
+    pub paste_guard: PasteGuard,
+
     #[dynamic(default = "default_unicode_version")]
     pub unicode_version: u8,
 
@@ -641,9 +914,31 @@ pub struct Config {
     #[dynamic(default = "default_true")]
     pub allow_win32_input_mode: bool,
 
+    #[dynamic(default = "default_conpty_resize_debounce_ms")]
+    pub conpty_resize_debounce_ms: u64,
+
     #[dynamic(default)]
     pub default_domain: Option<String>,
 
+    /// Select the default domain by the name of the local host; the
+    /// first rule that matches takes precedence over `default_domain`
+    #[dynamic(default)]
+    pub default_domain_rules: Vec<DefaultDomainRule>,
+
+    /// The names of domains that are listed first, in this order, by
+    /// the launcher and `wezterm cli list-domains`
+    #[dynamic(default)]
+    pub domain_order: Vec<String>,
+
+    /// When several domains share a name, the name refers to the one
+    /// whose kind comes first in this list
+    #[dynamic(default = "DomainKind::default_priority")]
+    pub domain_name_priority: Vec<DomainKind>,
+
+    /// Refuse to load a config in which several domains share a name
+    #[dynamic(default)]
+    pub strict_domain_names: bool,
+
     #[dynamic(default)]
     pub default_workspace: Option<String>,
 
@@ -788,6 +1083,7 @@ impl Config {
         )?;
         let config = Config::apply_overrides_to(&lua, config)?;
         let config = Config::apply_overrides_obj_to(&lua, config, overrides)?;
+        crate::lua::register_wsl_domain_callbacks(&lua, &config)?;
         cfg = Config::from_lua(config, &lua).with_context(|| {
             format!(
                 "Error converting lua value returned by script {} to Config struct",
@@ -798,6 +1094,8 @@ impl Config {
         // Compute but discard the key bindings here so that we raise any
         // problems earlier than we use them.
         let _ = cfg.key_bindings();
+        cfg.check_domain_names()?;
+        cfg.check_default_domain_rules()?;
 
         std::env::set_var("WEZTERM_CONFIG_FILE", p);
         if let Some(dir) = p.parent() {
@@ -1092,6 +1390,291 @@ impl Config {
         }
     }
 
+    /// Returns the limits that apply to sandboxed exec domain callbacks
+    pub fn sandbox_limits(&self) -> crate::sandbox::SandboxLimits {
+        crate::sandbox::SandboxLimits {
+            max_instructions: self.sandbox_exec_domain_max_instructions,
+            max_memory: self.sandbox_exec_domain_max_memory,
+        }
+    }
+
+    /// Returns true if any domain is marked as sensitive
+    pub fn has_sensitive_domains(&self) -> bool {
+        self.ssh_domains.iter().any(|d| d.sensitive)
+            || self.unix_domains.iter().any(|d| d.sensitive)
+            || self.tls_clients.iter().any(|d| d.sensitive)
+            || self.docker_domains.iter().any(|d| d.sensitive)
+    }
+
+    /// Returns the period without input after which the panes of the
+    /// domain named `name` are locked, if it is marked as sensitive
+    pub fn domain_lock_after(&self, name: &str) -> Option<Duration> {
+        let ssh = self
+            .ssh_domains
+            .iter()
+            .map(|d| (&d.name, d.sensitive, d.lock_after));
+        let unix = self
+            .unix_domains
+            .iter()
+            .map(|d| (&d.name, d.sensitive, d.lock_after));
+        let tls = self
+            .tls_clients
+            .iter()
+            .map(|d| (&d.name, d.sensitive, d.lock_after));
+        let docker = self
+            .docker_domains
+            .iter()
+            .map(|d| (&d.name, d.sensitive, d.lock_after));
+        ssh.chain(unix)
+            .chain(tls)
+            .chain(docker)
+            .find(|(domain_name, _, _)| domain_name.as_str() == name)
+            .and_then(|(_, sensitive, lock_after)| if sensitive { Some(lock_after) } else { None })
+    }
+
+    /// Returns the palette overrides that are configured for the
+    /// domain named `name`, if any
+    pub fn domain_palette_overrides(&self, name: &str) -> Option<Palette> {
+        let ssh = self
+            .ssh_domains
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        let unix = self
+            .unix_domains
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        let tls = self
+            .tls_clients
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        let docker = self
+            .docker_domains
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        let wsl = self
+            .wsl_domains
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        let exec = self
+            .exec_domains
+            .iter()
+            .map(|d| (&d.name, &d.palette_overrides));
+        ssh.chain(unix)
+            .chain(tls)
+            .chain(docker)
+            .chain(wsl)
+            .chain(exec)
+            .find(|(domain_name, _)| domain_name.as_str() == name)
+            .and_then(|(_, overrides)| overrides.clone())
+    }
+
+    /// Returns the exit behavior that is configured for the domain
+    /// named `name`, if it overrides the global `exit_behavior`
+    pub fn domain_exit_behavior(&self, name: &str) -> Option<ExitBehavior> {
+        let ssh = self.ssh_domains.iter().map(|d| (&d.name, d.exit_behavior));
+        let unix = self.unix_domains.iter().map(|d| (&d.name, d.exit_behavior));
+        let tls = self.tls_clients.iter().map(|d| (&d.name, d.exit_behavior));
+        let docker = self
+            .docker_domains
+            .iter()
+            .map(|d| (&d.name, d.exit_behavior));
+        let wsl = self.wsl_domains.iter().map(|d| (&d.name, d.exit_behavior));
+        let exec = self.exec_domains.iter().map(|d| (&d.name, d.exit_behavior));
+        ssh.chain(unix)
+            .chain(tls)
+            .chain(docker)
+            .chain(wsl)
+            .chain(exec)
+            .find(|(domain_name, _)| domain_name.as_str() == name)
+            .and_then(|(_, exit_behavior)| exit_behavior)
+    }
+
+    /// Returns the `max_concurrent_spawns` that is configured for the
+    /// domain named `name`, or None if its spawns are not limited
+    pub fn domain_max_concurrent_spawns(&self, name: &str) -> Option<usize> {
+        let ssh = self
+            .ssh_domains
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        let unix = self
+            .unix_domains
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        let tls = self
+            .tls_clients
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        let docker = self
+            .docker_domains
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        let wsl = self
+            .wsl_domains
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        let exec = self
+            .exec_domains
+            .iter()
+            .map(|d| (&d.name, d.max_concurrent_spawns));
+        ssh.chain(unix)
+            .chain(tls)
+            .chain(docker)
+            .chain(wsl)
+            .chain(exec)
+            .find(|(domain_name, _)| domain_name.as_str() == name)
+            .and_then(|(_, limit)| limit)
+    }
+
+    /// Returns the paste guard that is configured for the domain
+    /// named `name`, if it overrides the global `paste_guard`
+    pub fn domain_paste_guard(&self, name: &str) -> Option<PasteGuard> {
+        let ssh = self.ssh_domains.iter().map(|d| (&d.name, d.paste_guard));
+        let unix = self.unix_domains.iter().map(|d| (&d.name, d.paste_guard));
+        let tls = self.tls_clients.iter().map(|d| (&d.name, d.paste_guard));
+        let docker = self.docker_domains.iter().map(|d| (&d.name, d.paste_guard));
+        let wsl = self.wsl_domains.iter().map(|d| (&d.name, d.paste_guard));
+        let exec = self.exec_domains.iter().map(|d| (&d.name, d.paste_guard));
+        ssh.chain(unix)
+            .chain(tls)
+            .chain(docker)
+            .chain(wsl)
+            .chain(exec)
+            .find(|(domain_name, _)| domain_name.as_str() == name)
+            .and_then(|(_, guard)| guard)
+    }
+
+    /// Returns the rank of `kind` in `domain_name_priority`; lower ranks
+    /// take precedence.  Kinds that are not listed rank after those
+    /// that are, in their default order.
+    pub fn domain_name_rank(&self, kind: DomainKind) -> usize {
+        match self.domain_name_priority.iter().position(|k| *k == kind) {
+            Some(idx) => idx,
+            None => {
+                let default_rank = DomainKind::default_priority()
+                    .iter()
+                    .position(|k| *k == kind)
+                    .unwrap_or(0);
+                self.domain_name_priority.len() + default_rank
+            }
+        }
+    }
+
+    /// Returns each name that is given to more than one of the domains
+    /// defined by this config, including the built-in `local` domain
+    pub fn domain_name_collisions(&self) -> Vec<DomainNameCollision> {
+        let names = std::iter::once(("local", DomainKind::Local))
+            .chain(
+                self.unix_domains
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Unix)),
+            )
+            .chain(
+                self.ssh_domains
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Ssh)),
+            )
+            .chain(
+                self.tls_clients
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Tls)),
+            )
+            .chain(
+                self.wsl_domains
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Wsl)),
+            )
+            .chain(
+                self.exec_domains
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Exec)),
+            )
+            .chain(
+                self.docker_domains
+                    .iter()
+                    .map(|d| (d.name.as_str(), DomainKind::Docker)),
+            );
+
+        let mut collisions: Vec<DomainNameCollision> = vec![];
+        for (name, kind) in names {
+            match collisions.iter_mut().find(|c| c.name == name) {
+                Some(collision) => collision.kinds.push(kind),
+                None => collisions.push(DomainNameCollision {
+                    name: name.to_string(),
+                    kinds: vec![kind],
+                }),
+            }
+        }
+        collisions.retain(|c| c.kinds.len() > 1);
+        for collision in &mut collisions {
+            // The sort is stable, so domains of the same kind keep
+            // the order in which they are defined
+            collision
+                .kinds
+                .sort_by_key(|kind| self.domain_name_rank(*kind));
+        }
+        collisions
+    }
+
+    /// Fails if `strict_domain_names` is set and several domains
+    /// share a name
+    pub fn check_domain_names(&self) -> anyhow::Result<()> {
+        if !self.strict_domain_names {
+            return Ok(());
+        }
+        let collisions = self.domain_name_collisions();
+        if !collisions.is_empty() {
+            let collisions: Vec<String> = collisions.iter().map(|c| c.to_string()).collect();
+            anyhow::bail!(
+                "strict_domain_names is set, but several domains share a name:\n{}",
+                collisions.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails if the hostname of any of the `default_domain_rules` is
+    /// not a valid regex
+    pub fn check_default_domain_rules(&self) -> anyhow::Result<()> {
+        for rule in &self.default_domain_rules {
+            regex::Regex::new(&rule.hostname).with_context(|| {
+                format!(
+                    "invalid hostname regex in default_domain_rules: {}",
+                    rule.hostname
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the default domain for the host named
+    /// `hostname`: that of the first of the `default_domain_rules`
+    /// that matches it, or else `default_domain`
+    pub fn default_domain_for_host(&self, hostname: Option<&str>) -> &str {
+        if let Some(hostname) = hostname {
+            for rule in &self.default_domain_rules {
+                match regex::Regex::new(&rule.hostname) {
+                    Ok(re) if re.is_match(hostname) => return &rule.domain,
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::error!("invalid hostname regex in default_domain_rules: {:#}", err)
+                    }
+                }
+            }
+        }
+        self.default_domain.as_deref().unwrap_or("local")
+    }
+
+    /// Returns the name of the default domain for this host
+    pub fn effective_default_domain(&self) -> &str {
+        self.default_domain_for_host(local_hostname())
+    }
+
+    /// Returns the position of the domain named `name` in
+    /// `domain_order`, or None if it isn't listed
+    pub fn domain_order_rank(&self, name: &str) -> Option<usize> {
+        self.domain_order.iter().position(|n| n == name)
+    }
+
     pub fn initial_size(&self, dpi: u32) -> TerminalSize {
         TerminalSize {
             rows: self.initial_rows as usize,
@@ -1111,6 +1694,123 @@ impl Config {
         }
     }
 
+    /// Checks that the programs named by the various `default_prog`
+    /// fields can be found, returning a warning for each one that
+    /// cannot.  This is advisory: the PATH may be different by the
+    /// time that the program is spawned.
+    /// Programs for wsl and ssh domains run on a different system,
+    /// so we can only check that they are non-empty.
+    pub fn default_prog_warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        if let Some(prog) = &self.default_prog {
+            match prog.first() {
+                Some(exe) => {
+                    if let Err(reason) = self.resolve_program(exe) {
+                        warnings.push(format!("default_prog: {}", reason));
+                    }
+                }
+                None => warnings.push(empty_default_prog_warning("default_prog")),
+            }
+        }
+
+        let remote_progs = self
+            .wsl_domains
+            .iter()
+            .map(|dom| ("wsl_domains", &dom.name, &dom.default_prog))
+            .chain(
+                self.ssh_domains
+                    .iter()
+                    .map(|dom| ("ssh_domains", &dom.name, &dom.default_prog)),
+            );
+        for (kind, name, prog) in remote_progs {
+            if matches!(prog, Some(prog) if prog.is_empty()) {
+                warnings.push(empty_default_prog_warning(&format!(
+                    "{}[\"{}\"].default_prog",
+                    kind, name
+                )));
+            }
+        }
+
+        warnings
+    }
+
+    /// Resolve `exe` in the same way that spawning it would, taking into
+    /// account any PATH override in `set_environment_variables`.
+    /// Returns a description of the resolution that was attempted if
+    /// the program could not be found.
+    fn resolve_program(&self, exe: &str) -> Result<PathBuf, String> {
+        let exe_path = Path::new(exe);
+        if exe_path.is_absolute() {
+            return if exe_path.exists() {
+                Ok(exe_path.to_path_buf())
+            } else {
+                Err(format!("{} does not exist", exe))
+            };
+        }
+
+        // Relative paths are resolved against the cwd first
+        let default_cwd = self.default_cwd.as_ref().map(|cwd| {
+            expand_local_cwd(cwd, |name| {
+                self.set_environment_variables
+                    .get(name)
+                    .cloned()
+                    .or_else(|| std::env::var(name).ok())
+            })
+        });
+        if let Some(cwd) = &default_cwd {
+            let candidate = cwd.join(exe_path);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+        if exe_path.components().count() > 1 {
+            return Err(format!(
+                "{} is a relative path that does not exist in default_cwd {}",
+                exe,
+                default_cwd
+                    .as_ref()
+                    .map(|cwd| cwd.display().to_string())
+                    .unwrap_or_else(|| "(unset)".to_string())
+            ));
+        }
+
+        let path = match self.set_environment_variables.get("PATH") {
+            Some(path) => OsString::from(path),
+            None => std::env::var_os("PATH").unwrap_or_default(),
+        };
+
+        // On Windows, CreateProcess will try the PATHEXT extensions
+        let extensions: Vec<String> = if cfg!(windows) {
+            std::env::var("PATHEXT")
+                .unwrap_or_else(|_| ".EXE".to_string())
+                .split(';')
+                .map(|ext| ext.to_string())
+                .collect()
+        } else {
+            vec![]
+        };
+
+        for dir in std::env::split_paths(&path) {
+            let candidate = dir.join(exe);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+            for ext in &extensions {
+                let candidate = dir.join(format!("{}{}", exe, ext));
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+            }
+        }
+
+        Err(format!(
+            "{} was not found in PATH={}",
+            exe,
+            path.to_string_lossy()
+        ))
+    }
+
     pub fn build_prog(
         &self,
         prog: Option<Vec<&OsStr>>,
@@ -1127,7 +1827,10 @@ impl Config {
             None => {
                 if let Some(prog) = default_prog {
                     let mut args = prog.iter();
-                    let mut cmd = CommandBuilder::new(args.next().expect("executable name"));
+                    let mut cmd = CommandBuilder::new(
+                        args.next()
+                            .ok_or_else(|| anyhow::anyhow!("default_prog is empty"))?,
+                    );
                     cmd.args(args);
                     cmd
                 } else {
@@ -1142,12 +1845,6 @@ impl Config {
     }
 
     pub fn apply_cmd_defaults(&self, cmd: &mut CommandBuilder, default_cwd: Option<&PathBuf>) {
-        // Apply `default_cwd` only if `cwd` is not already set, allows `--cwd`
-        // option to take precedence
-        if let (None, Some(cwd)) = (cmd.get_cwd(), default_cwd) {
-            cmd.cwd(cwd);
-        }
-
         // Augment WSLENV so that TERM related environment propagates
         // across the win32/wsl boundary
         let mut wsl_env = std::env::var("WSLENV").ok();
@@ -1188,6 +1885,37 @@ impl Config {
         // de-facto standard for identifying the terminal.
         cmd.env("TERM_PROGRAM", "WezTerm");
         cmd.env("TERM_PROGRAM_VERSION", crate::wezterm_version());
+
+        // This happens after the environment has been set up, so that
+        // the cwd can refer to set_environment_variables
+        self.apply_default_cwd(cmd, default_cwd, CwdExpansion::Local);
+    }
+
+    /// Applies `default_cwd` to `cmd`, unless it already has a cwd,
+    /// which allows the `--cwd` option to take precedence.
+    /// The cwd is expanded against the environment of `cmd` as
+    /// described by `expansion`.
+    pub fn apply_default_cwd(
+        &self,
+        cmd: &mut CommandBuilder,
+        default_cwd: Option<&PathBuf>,
+        expansion: CwdExpansion,
+    ) {
+        let cwd = match (cmd.get_cwd(), default_cwd) {
+            (None, Some(cwd)) => cwd,
+            _ => return,
+        };
+        let lookup_var = |name: &str| {
+            cmd.get_env(name)
+                .and_then(|value| value.to_str())
+                .map(|value| value.to_string())
+        };
+        let cwd: OsString = match expansion {
+            CwdExpansion::Local => expand_local_cwd(cwd, lookup_var).into(),
+            CwdExpansion::Wsl => expand_wsl_cwd(cwd, lookup_var).into(),
+            CwdExpansion::Verbatim => cwd.into(),
+        };
+        cmd.cwd(cwd);
     }
 }
 
@@ -1215,10 +1943,86 @@ fn default_mux_output_parser_buffer_size() -> usize {
     128 * 1024
 }
 
+fn default_pty_reader_stall_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_docker_command() -> String {
+    "docker".to_string()
+}
+
+fn default_prewarm_pane_idle_timeout() -> u64 {
+    300
+}
+
+fn default_pane_metadata_coalesce_ms() -> u64 {
+    50
+}
+
+fn default_recent_dirs_max_entries() -> usize {
+    200
+}
+
+fn default_split_preview_hold_delay_ms() -> u64 {
+    250
+}
+
+fn default_mux_snapshot_scrollback_lines() -> usize {
+    100
+}
+
+fn default_mux_background_sync_panes_per_second() -> u32 {
+    10
+}
+
+fn default_mux_pane_checksum_interval() -> u64 {
+    5
+}
+
+fn default_mux_image_max_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_mux_image_max_dimension() -> u32 {
+    8192
+}
+
+fn default_mux_image_cache_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_audit_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_log_keep_files() -> usize {
+    5
+}
+
+fn default_resilient_pane_replay_buffer_size() -> usize {
+    1024 * 1024
+}
+
+fn default_prewarm_pane_domains() -> Vec<String> {
+    vec!["local".to_string()]
+}
+
+fn default_sandbox_exec_domain_max_instructions() -> u64 {
+    10_000_000
+}
+
+fn default_sandbox_exec_domain_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
 fn default_ratelimit_line_prefetches_per_second() -> u32 {
     10
 }
 
+fn default_ratelimit_notifications_per_pane_per_minute() -> u32 {
+    10
+}
+
 fn default_cursor_blink_rate() -> u64 {
     800
 }
@@ -1295,6 +2099,10 @@ pub fn default_write_timeout() -> Duration {
     Duration::from_secs(60)
 }
 
+pub fn default_lock_after() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
 pub fn default_local_echo_threshold_ms() -> Option<u64> {
     Some(100)
 }
@@ -1377,10 +2185,29 @@ fn default_prefer_egl() -> bool {
     !cfg!(windows)
 }
 
+fn default_mux_client_output_queue_limit() -> usize {
+    32
+}
+
 fn default_clean_exits() -> Vec<u32> {
     vec![]
 }
 
+fn default_early_exit_threshold_ms() -> u64 {
+    2000
+}
+
+fn empty_default_prog_warning(field: &str) -> String {
+    format!(
+        "{}: is empty; it must contain at least the name of the program to run",
+        field
+    )
+}
+
+fn default_conpty_resize_debounce_ms() -> u64 {
+    50
+}
+
 fn default_inactive_pane_hsb() -> HsbTransform {
     HsbTransform {
         brightness: 0.8,
@@ -1515,6 +2342,53 @@ impl Default for ExitBehavior {
     }
 }
 
+impl ExitBehavior {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Close => "Close",
+            Self::CloseOnCleanExit => "CloseOnCleanExit",
+            Self::Hold => "Hold",
+        }
+    }
+}
+
+impl std::str::FromStr for ExitBehavior {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "Close" => Ok(Self::Close),
+            "CloseOnCleanExit" => Ok(Self::CloseOnCleanExit),
+            "Hold" => Ok(Self::Hold),
+            _ => Err(format!(
+                "invalid exit_behavior {:?}; expected one of \
+                 Close, CloseOnCleanExit or Hold",
+                s
+            )),
+        }
+    }
+}
+
+/// Which pastes into the shell of a pane are held until the user
+/// confirms them
+#[derive(Debug, FromDynamic, ToDynamic, Clone, Copy, PartialEq, Eq)]
+pub enum PasteGuard {
+    /// Every paste into a shell
+    Always,
+    /// Pastes into a shell that contain a newline
+    MultilineOnly,
+    /// Pastes into a shell that contain a newline, when the shell
+    /// appears to be running as root
+    RootOnly,
+    /// No pastes
+    Never,
+}
+
+impl Default for PasteGuard {
+    fn default() -> Self {
+        PasteGuard::Never
+    }
+}
+
 #[derive(Debug, FromDynamic, ToDynamic, Clone, Copy, PartialEq, Eq)]
 pub enum DroppedFileQuoting {
     /// No quoting is performed, the file name is passed through as-is
@@ -1582,3 +2456,189 @@ fn validate_line_height(value: f64) -> Result<(), String> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_prog_warnings() {
+        let mut config = Config::default();
+        assert!(config.default_prog_warnings().is_empty());
+
+        config.default_prog = Some(vec![]);
+        assert_eq!(
+            config.default_prog_warnings(),
+            vec![empty_default_prog_warning("default_prog")]
+        );
+
+        config.default_prog = Some(vec!["wezterm-no-such-program".to_string()]);
+        config
+            .set_environment_variables
+            .insert("PATH".to_string(), "/wezterm-no-such-dir".to_string());
+        assert_eq!(
+            config.default_prog_warnings(),
+            vec!["default_prog: wezterm-no-such-program was not found \
+                 in PATH=/wezterm-no-such-dir"
+                .to_string()]
+        );
+
+        config.wsl_domains = vec![WslDomain {
+            name: "WSL:Ubuntu".to_string(),
+            default_prog: Some(vec![]),
+            ..Default::default()
+        }];
+        assert_eq!(
+            config.default_prog_warnings().last(),
+            Some(&empty_default_prog_warning(
+                "wsl_domains[\"WSL:Ubuntu\"].default_prog"
+            ))
+        );
+    }
+
+    #[test]
+    fn domain_name_collisions() {
+        let mut config = Config::default();
+        config.wsl_domains = vec![];
+        assert!(config.domain_name_collisions().is_empty());
+
+        config.wsl_domains = vec![WslDomain {
+            name: "devbox".to_string(),
+            ..Default::default()
+        }];
+        config.ssh_domains = vec![SshDomain {
+            name: "devbox".to_string(),
+            ..Default::default()
+        }];
+        let collisions = config.domain_name_collisions();
+        assert_eq!(
+            collisions,
+            vec![DomainNameCollision {
+                name: "devbox".to_string(),
+                kinds: vec![DomainKind::Ssh, DomainKind::Wsl],
+            }]
+        );
+        assert_eq!(
+            collisions[0].to_string(),
+            "domain name \"devbox\" is defined by ssh_domains[\"devbox\"] and \
+             wsl_domains[\"devbox\"]; it resolves to ssh_domains[\"devbox\"]"
+        );
+        assert!(config.check_domain_names().is_ok());
+
+        config.domain_name_priority = vec![DomainKind::Wsl];
+        assert_eq!(
+            config.domain_name_collisions()[0].kinds,
+            vec![DomainKind::Wsl, DomainKind::Ssh]
+        );
+
+        config.strict_domain_names = true;
+        assert!(config.check_domain_names().is_err());
+    }
+
+    #[test]
+    fn default_domain_rules() {
+        let mut config = Config::default();
+        assert_eq!(config.default_domain_for_host(Some("work-laptop")), "local");
+
+        config.default_domain = Some("home".to_string());
+        config.default_domain_rules = vec![
+            DefaultDomainRule {
+                hostname: "^work-laptop$".to_string(),
+                domain: "work-wsl".to_string(),
+            },
+            DefaultDomainRule {
+                hostname: "^work-".to_string(),
+                domain: "work-ssh".to_string(),
+            },
+        ];
+        assert!(config.check_default_domain_rules().is_ok());
+        assert_eq!(
+            config.default_domain_for_host(Some("work-laptop")),
+            "work-wsl"
+        );
+        assert_eq!(
+            config.default_domain_for_host(Some("work-desktop")),
+            "work-ssh"
+        );
+        assert_eq!(config.default_domain_for_host(Some("gaming-pc")), "home");
+        assert_eq!(config.default_domain_for_host(None), "home");
+
+        config.default_domain_rules[0].hostname = "(".to_string();
+        assert!(config.check_default_domain_rules().is_err());
+        assert_eq!(
+            config.default_domain_for_host(Some("work-laptop")),
+            "work-ssh"
+        );
+    }
+
+    #[test]
+    fn domain_palette_overrides() {
+        let mut config = Config::default();
+        let overrides = Palette {
+            background: Some(crate::RgbColor::new_8bpc(0x3b, 0, 0).into()),
+            ..Default::default()
+        };
+        config.ssh_domains = vec![
+            SshDomain {
+                name: "prod".to_string(),
+                palette_overrides: Some(overrides.clone()),
+                ..Default::default()
+            },
+            SshDomain {
+                name: "staging".to_string(),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(config.domain_palette_overrides("prod"), Some(overrides));
+        assert_eq!(config.domain_palette_overrides("staging"), None);
+        assert_eq!(config.domain_palette_overrides("local"), None);
+    }
+
+    #[test]
+    fn domain_exit_behavior() {
+        let mut config = Config::default();
+        config.ssh_domains = vec![SshDomain {
+            name: "prod".to_string(),
+            exit_behavior: Some(ExitBehavior::Hold),
+            ..Default::default()
+        }];
+        assert_eq!(
+            config.domain_exit_behavior("prod"),
+            Some(ExitBehavior::Hold)
+        );
+        assert_eq!(config.domain_exit_behavior("local"), None);
+        for behavior in [
+            ExitBehavior::Close,
+            ExitBehavior::CloseOnCleanExit,
+            ExitBehavior::Hold,
+        ] {
+            assert_eq!(behavior.as_str().parse::<ExitBehavior>(), Ok(behavior));
+        }
+        assert!("hold".parse::<ExitBehavior>().is_err());
+    }
+
+    #[test]
+    fn domain_max_concurrent_spawns() {
+        let mut config = Config::default();
+        config.ssh_domains = vec![SshDomain {
+            name: "build".to_string(),
+            max_concurrent_spawns: Some(1),
+            ..Default::default()
+        }];
+        assert_eq!(config.domain_max_concurrent_spawns("build"), Some(1));
+        assert_eq!(config.domain_max_concurrent_spawns("local"), None);
+    }
+
+    #[test]
+    fn domain_paste_guard() {
+        let mut config = Config::default();
+        config.unix_domains = vec![UnixDomain {
+            name: "prod".to_string(),
+            paste_guard: Some(PasteGuard::Always),
+            ..Default::default()
+        }];
+        assert_eq!(config.paste_guard, PasteGuard::Never);
+        assert_eq!(config.domain_paste_guard("prod"), Some(PasteGuard::Always));
+        assert_eq!(config.domain_paste_guard("local"), None);
+    }
+}
@@ -0,0 +1,303 @@
+//! Expands `~`, `~user` and environment variables in `default_cwd`.
+//! This happens each time that a command is spawned, against the
+//! environment of that command, so that the result reflects that
+//! environment and directories created after the config was loaded.
+use crate::HOME_DIR;
+use std::path::{Path, PathBuf};
+
+/// How a configured cwd is interpreted when it is applied to a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CwdExpansion {
+    /// The cwd is a local path
+    Local,
+    /// The cwd is expanded against the environment on the Windows
+    /// side, then translated to a path in a WSL distribution.
+    /// A leading `~` is left for `wsl.exe` to resolve to the home
+    /// directory in the distribution.
+    Wsl,
+    /// The cwd is used as-is, because it is interpreted on another host
+    Verbatim,
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// Expands a leading `~` or `~user`, and `$VAR` and `${VAR}` anywhere
+/// in `cwd`.  `lookup_var` returns the value of a variable, and
+/// `home_dir` returns the home directory of the named user, or of
+/// the current user when passed None.
+/// Returns an error describing the first variable or user that
+/// couldn't be resolved.
+pub fn expand_cwd<V, H>(cwd: &str, lookup_var: V, home_dir: H) -> Result<String, String>
+where
+    V: Fn(&str) -> Option<String>,
+    H: Fn(Option<&str>) -> Option<PathBuf>,
+{
+    let mut result = String::new();
+    let mut rest = cwd;
+
+    if let Some(after_tilde) = cwd.strip_prefix('~') {
+        let end = after_tilde
+            .find(is_separator)
+            .unwrap_or_else(|| after_tilde.len());
+        let user = &after_tilde[..end];
+        let home = if user.is_empty() {
+            home_dir(None).ok_or_else(|| "the home directory is unknown".to_string())?
+        } else {
+            home_dir(Some(user)).ok_or_else(|| format!("unknown user {}", user))?
+        };
+        result.push_str(&home.to_string_lossy());
+        rest = &after_tilde[end..];
+    }
+
+    while let Some(dollar) = rest.find('$') {
+        result.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, remain) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(close) => (&braced[..close], &braced[close + 1..]),
+                None => return Err(format!("unterminated ${{ in {}", cwd)),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or_else(|| after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            // A lone `$` is taken literally
+            result.push('$');
+        } else {
+            let value = lookup_var(name).ok_or_else(|| format!("${} is not set", name))?;
+            result.push_str(&value);
+        }
+        rest = remain;
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Returns the home directory of `user`, or of the current user when
+/// passed None
+pub fn home_dir_for_user(user: Option<&str>) -> Option<PathBuf> {
+    let user = match user {
+        Some(user) => user,
+        None => return Some(HOME_DIR.clone()),
+    };
+
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(user).ok()?;
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if rc != 0 || result.is_null() || pwd.pw_dir.is_null() {
+            return None;
+        }
+        let dir = unsafe { std::ffi::CStr::from_ptr(pwd.pw_dir) };
+        Some(PathBuf::from(dir.to_string_lossy().into_owned()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        // Profiles are conventionally siblings of each other
+        let candidate = HOME_DIR.parent()?.join(user);
+        if candidate.is_dir() {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Expands `cwd` for a local command.  If it can't be expanded, the
+/// reason is logged and the home directory is used instead.
+pub fn expand_local_cwd<V>(cwd: &Path, lookup_var: V) -> PathBuf
+where
+    V: Fn(&str) -> Option<String>,
+{
+    match expand_cwd(&cwd.to_string_lossy(), lookup_var, home_dir_for_user) {
+        Ok(expanded) => PathBuf::from(expanded),
+        Err(err) => {
+            log::error!(
+                "Unable to expand default_cwd {}: {}; using {} instead",
+                cwd.display(),
+                err,
+                HOME_DIR.display()
+            );
+            HOME_DIR.clone()
+        }
+    }
+}
+
+/// Translates a Windows path to the path of the same file in a WSL
+/// distribution.  Drive paths map to their `/mnt` mount point and
+/// paths of the form `\\wsl$\distro\...` map to `/...`.  Other paths
+/// are returned unchanged.
+pub fn windows_path_to_wsl(path: &str) -> String {
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        let drive = (bytes[0] as char).to_ascii_lowercase();
+        let rest = path[2..].replace('\\', "/");
+        let rest = rest.trim_start_matches('/');
+        return if rest.is_empty() {
+            format!("/mnt/{}", drive)
+        } else {
+            format!("/mnt/{}/{}", drive, rest)
+        };
+    }
+
+    let unc = path.replace('\\', "/");
+    let lower = unc.to_ascii_lowercase();
+    for prefix in &["//wsl$/", "//wsl.localhost/"] {
+        if lower.starts_with(prefix) {
+            let after_host = &unc[prefix.len()..];
+            return match after_host.find('/') {
+                Some(slash) => after_host[slash..].to_string(),
+                None => "/".to_string(),
+            };
+        }
+    }
+
+    path.to_string()
+}
+
+/// Expands `cwd` for a command in a WSL distribution; see
+/// `CwdExpansion::Wsl`.  If it can't be expanded, the reason is logged
+/// and the home directory in the distribution is used instead.
+pub fn expand_wsl_cwd<V>(cwd: &Path, lookup_var: V) -> String
+where
+    V: Fn(&str) -> Option<String>,
+{
+    let cwd = cwd.to_string_lossy();
+    if cwd.starts_with('~') {
+        // wsl.exe resolves the home directory in the distribution
+        return cwd.into_owned();
+    }
+    match expand_cwd(&cwd, lookup_var, |_| None) {
+        Ok(expanded) => windows_path_to_wsl(&expanded),
+        Err(err) => {
+            log::error!(
+                "Unable to expand default_cwd {} for WSL: {}; \
+                 using the home directory instead",
+                cwd,
+                err
+            );
+            "~".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| map.get(name).cloned()
+    }
+
+    fn homes(user: Option<&str>) -> Option<PathBuf> {
+        match user {
+            None => Some(PathBuf::from("/home/me")),
+            Some("wez") => Some(PathBuf::from("/home/wez")),
+            Some(_) => None,
+        }
+    }
+
+    #[test]
+    fn tilde() {
+        let env = vars(&[]);
+        assert_eq!(expand_cwd("~", &env, homes).unwrap(), "/home/me");
+        assert_eq!(expand_cwd("~/src", &env, homes).unwrap(), "/home/me/src");
+        assert_eq!(
+            expand_cwd("~wez/src", &env, homes).unwrap(),
+            "/home/wez/src"
+        );
+        assert_eq!(
+            expand_cwd("~nobody/src", &env, homes).unwrap_err(),
+            "unknown user nobody"
+        );
+        // Only a leading tilde is special
+        assert_eq!(expand_cwd("/tmp/~", &env, homes).unwrap(), "/tmp/~");
+    }
+
+    #[test]
+    fn variables() {
+        let env = vars(&[("PROJECTS", "/work"), ("SUB", "foo")]);
+        assert_eq!(
+            expand_cwd("$PROJECTS/foo", &env, homes).unwrap(),
+            "/work/foo"
+        );
+        assert_eq!(
+            expand_cwd("${PROJECTS}/${SUB}-bar", &env, homes).unwrap(),
+            "/work/foo-bar"
+        );
+        assert_eq!(expand_cwd("~/$SUB", &env, homes).unwrap(), "/home/me/foo");
+        assert_eq!(expand_cwd("/cost/$", &env, homes).unwrap(), "/cost/$");
+        assert_eq!(
+            expand_cwd("${UNSET}/foo", &env, homes).unwrap_err(),
+            "$UNSET is not set"
+        );
+        assert!(expand_cwd("${PROJECTS", &env, homes).is_err());
+    }
+
+    #[test]
+    fn local_fallback_is_home() {
+        let env = vars(&[]);
+        assert_eq!(
+            expand_local_cwd(Path::new("$UNSET/foo"), &env),
+            HOME_DIR.clone()
+        );
+    }
+
+    #[test]
+    fn wsl_paths() {
+        assert_eq!(
+            windows_path_to_wsl(r"C:\Users\me\src"),
+            "/mnt/c/Users/me/src"
+        );
+        assert_eq!(windows_path_to_wsl("d:/data"), "/mnt/d/data");
+        assert_eq!(windows_path_to_wsl(r"C:\"), "/mnt/c");
+        assert_eq!(windows_path_to_wsl(r"\\wsl$\Ubuntu\home\me"), "/home/me");
+        assert_eq!(
+            windows_path_to_wsl(r"\\wsl.localhost\Ubuntu\home\me"),
+            "/home/me"
+        );
+        assert_eq!(windows_path_to_wsl("/home/me"), "/home/me");
+    }
+
+    #[test]
+    fn wsl_expands_against_windows_environment() {
+        let env = vars(&[("USERPROFILE", r"C:\Users\me"), ("PROJECTS", r"D:\work")]);
+        assert_eq!(
+            expand_wsl_cwd(Path::new(r"${USERPROFILE}\src"), &env),
+            "/mnt/c/Users/me/src"
+        );
+        assert_eq!(
+            expand_wsl_cwd(Path::new("$PROJECTS/foo"), &env),
+            "/mnt/d/work/foo"
+        );
+        // Paths inside the distribution are left alone
+        assert_eq!(expand_wsl_cwd(Path::new("/srv/app"), &env), "/srv/app");
+        // The home directory is that of the distribution
+        assert_eq!(expand_wsl_cwd(Path::new("~/src"), &env), "~/src");
+        // Failures fall back to the home directory in the distribution
+        assert_eq!(expand_wsl_cwd(Path::new("${UNSET}/src"), &env), "~");
+    }
+}
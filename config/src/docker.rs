@@ -0,0 +1,57 @@
+use crate::{default_lock_after, ExitBehavior, Palette, PasteGuard};
+use luahelper::impl_lua_conversion_dynamic;
+use std::time::Duration;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// Configures a domain whose panes run inside a docker container,
+/// via `docker exec`
+#[derive(Default, Debug, Clone, FromDynamic, ToDynamic)]
+pub struct DockerDomain {
+    /// The name of this domain
+    pub name: String,
+    /// The name or id of the container
+    pub container: String,
+    /// The user to run commands as inside the container.
+    /// Defaults to the user configured for the container.
+    pub username: Option<String>,
+    /// The program to run when spawning without a command.
+    /// Defaults to the login shell of the user in the container.
+    pub default_prog: Option<Vec<String>>,
+    /// If true, the panes in this domain are locked after `lock_after`
+    /// without input, and input is held until the user unlocks them
+    #[dynamic(default)]
+    pub sensitive: bool,
+    /// How long a pane in this domain can go without input before it
+    /// is locked, if the domain is `sensitive`.  Defaults to 15 minutes.
+    #[dynamic(default = "default_lock_after")]
+    pub lock_after: Duration,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
+}
+impl_lua_conversion_dynamic!(DockerDomain);
+
+impl DockerDomain {
+    /// Returns the domain that is registered for a running container
+    /// when `docker_domain_discovery` is enabled
+    pub fn for_discovered_container(container: &str) -> Self {
+        Self {
+            name: format!("docker:{}", container),
+            container: container.to_string(),
+            ..Default::default()
+        }
+    }
+}
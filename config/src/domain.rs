@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// The kind of configuration that defines a domain.
+/// When several domains share a name, the kind decides which one
+/// of them the name refers to; see `domain_name_priority`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, FromDynamic, ToDynamic,
+)]
+pub enum DomainKind {
+    /// The built-in `local` domain
+    Local,
+    /// An entry in `unix_domains`
+    Unix,
+    /// An entry in `ssh_domains`
+    Ssh,
+    /// An entry in `tls_clients`
+    Tls,
+    /// An entry in `wsl_domains`
+    Wsl,
+    /// An entry in `exec_domains`
+    Exec,
+    /// An entry in `docker_domains`, or a discovered container
+    Docker,
+}
+
+impl DomainKind {
+    /// The order in which domains used to be registered, which is
+    /// the order in which a name resolved before collisions were
+    /// detected
+    pub fn default_priority() -> Vec<Self> {
+        vec![
+            Self::Local,
+            Self::Unix,
+            Self::Ssh,
+            Self::Tls,
+            Self::Wsl,
+            Self::Exec,
+            Self::Docker,
+        ]
+    }
+
+    /// The name of the config option that defines domains of this kind
+    pub fn config_key(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Unix => "unix_domains",
+            Self::Ssh => "ssh_domains",
+            Self::Tls => "tls_clients",
+            Self::Wsl => "wsl_domains",
+            Self::Exec => "exec_domains",
+            Self::Docker => "docker_domains",
+        }
+    }
+}
+
+/// Spawns new panes whose working directory matches `cwd_pattern`
+/// into `domain`; see `domain_rules`
+#[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct DomainRule {
+    /// A lua pattern that is matched against the path of the working
+    /// directory, as by `string.find`
+    pub cwd_pattern: String,
+    /// The name of the domain in which the panes are spawned
+    pub domain: String,
+}
+
+/// A name that is given to more than one domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainNameCollision {
+    pub name: String,
+    /// The kinds of the domains that share the name, in the order
+    /// that they are resolved; the first one owns the name
+    pub kinds: Vec<DomainKind>,
+}
+
+impl std::fmt::Display for DomainNameCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let definitions: Vec<String> = self
+            .kinds
+            .iter()
+            .map(|kind| match kind {
+                DomainKind::Local => "the built-in local domain".to_string(),
+                kind => format!("{}[\"{}\"]", kind.config_key(), self.name),
+            })
+            .collect();
+        write!(
+            f,
+            "domain name {:?} is defined by {}; it resolves to {}",
+            self.name,
+            definitions.join(" and "),
+            definitions[0]
+        )
+    }
+}
+
+/// Selects the default domain on hosts whose name matches `hostname`;
+/// see `default_domain_rules`
+#[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct DefaultDomainRule {
+    /// A regex that is matched against the name of the local host
+    pub hostname: String,
+    /// The name of the domain to use as the default
+    pub domain: String,
+}
+
+lazy_static::lazy_static! {
+    /// The name of the local host, which is looked up once
+    static ref LOCAL_HOSTNAME: Option<String> = match hostname::get() {
+        Ok(name) => Some(name.to_string_lossy().into_owned()),
+        Err(err) => {
+            log::warn!("failed to look up the hostname: {:#}", err);
+            None
+        }
+    };
+}
+
+/// Returns the name of the local host, if it could be determined
+pub fn local_hostname() -> Option<&'static str> {
+    LOCAL_HOSTNAME.as_deref()
+}
+
+/// What happens to the panes of a domain when a local window or tab
+/// that contains them is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub enum ClosePolicy {
+    /// The panes are killed
+    Kill,
+    /// The panes are left running in the domain, so that they can be
+    /// reattached later
+    Detach,
+    /// The user is asked whether to kill or detach the panes
+    Ask,
+}
+
+/// How a domain is presented in the launcher.
+/// This doesn't affect whether the domain can be spawned into by name.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct LauncherHints {
+    /// If true, the domain is left out of the launcher
+    #[dynamic(default)]
+    pub hidden: bool,
+    /// Domains with a sort key are listed first, in ascending order
+    /// of the key; the rest keep their usual order
+    #[dynamic(default)]
+    pub sort_key: Option<i64>,
+}
@@ -1,17 +1,102 @@
+use crate::{ExitBehavior, LauncherHints, Palette, PasteGuard};
 use luahelper::impl_lua_conversion_dynamic;
-use wezterm_dynamic::{FromDynamic, ToDynamic, Value};
+use wezterm_dynamic::{FromDynamic, FromDynamicOptions, ToDynamic, Value};
 
-#[derive(Debug, Clone, FromDynamic, ToDynamic)]
+/// Either a value, or the name of the event under which the lua
+/// function that computes it is registered
+#[derive(Debug, Clone)]
 pub enum ValueOrFunc {
     Value(Value),
     Func(String),
 }
 impl_lua_conversion_dynamic!(ValueOrFunc);
 
+impl ToDynamic for ValueOrFunc {
+    /// A value is represented as itself, so that lua sees a plain
+    /// string, and a function as `{Func=...}`
+    fn to_dynamic(&self) -> Value {
+        match self {
+            Self::Value(value) => value.clone(),
+            Self::Func(name) => {
+                let mut obj = wezterm_dynamic::Object::default();
+                obj.insert(
+                    Value::String("Func".to_string()),
+                    Value::String(name.clone()),
+                );
+                Value::Object(obj)
+            }
+        }
+    }
+}
+
+impl FromDynamic for ValueOrFunc {
+    /// Accepts the forms produced by `to_dynamic`, along with the
+    /// `{Value=...}` form
+    fn from_dynamic(
+        value: &Value,
+        options: FromDynamicOptions,
+    ) -> Result<Self, wezterm_dynamic::Error> {
+        if let Value::Object(obj) = value {
+            if obj.len() == 1 {
+                if let Some(func) = obj.get_by_str("Func") {
+                    return Ok(Self::Func(String::from_dynamic(func, options)?));
+                }
+                if let Some(value) = obj.get_by_str("Value") {
+                    return Ok(Self::Value(value.clone()));
+                }
+            }
+        }
+        Ok(Self::Value(value.clone()))
+    }
+}
+
 #[derive(Debug, Clone, FromDynamic, ToDynamic)]
 pub struct ExecDomain {
     pub name: String,
     pub fixup_command: String,
     pub label: Option<ValueOrFunc>,
+    /// Overrides `use_systemd_scope` for panes in this domain
+    #[dynamic(default)]
+    pub use_systemd_scope: Option<bool>,
+    /// Overrides `login_shell` for panes in this domain
+    #[dynamic(default)]
+    pub login_shell: Option<bool>,
+    /// How the domain is presented in the launcher
+    #[dynamic(default)]
+    pub launcher: LauncherHints,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
 }
 impl_lua_conversion_dynamic!(ExecDomain);
+
+/// The optional fields of an `ExecDomain`, which may be passed as the
+/// last parameter of `wezterm.exec_domain` rather than being assigned
+/// to the domain that it returns
+#[derive(Debug, Clone, Default, FromDynamic, ToDynamic)]
+pub struct ExecDomainOptions {
+    #[dynamic(default)]
+    pub use_systemd_scope: Option<bool>,
+    #[dynamic(default)]
+    pub login_shell: Option<bool>,
+    #[dynamic(default)]
+    pub launcher: LauncherHints,
+    pub palette_overrides: Option<Palette>,
+    pub exit_behavior: Option<ExitBehavior>,
+    pub max_concurrent_spawns: Option<usize>,
+    pub paste_guard: Option<PasteGuard>,
+}
+impl_lua_conversion_dynamic!(ExecDomainOptions);
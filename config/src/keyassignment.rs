@@ -1,11 +1,12 @@
 use crate::keys::KeyNoAction;
 use luahelper::impl_lua_conversion_dynamic;
 use ordered_float::NotNan;
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::path::PathBuf;
-use wezterm_dynamic::{FromDynamic, ToDynamic};
+use url::Url;
+use wezterm_dynamic::{FromDynamic, FromDynamicOptions, ToDynamic, Value};
 use wezterm_input_types::{KeyCode, Modifiers};
 use wezterm_term::input::MouseButton;
 
@@ -27,6 +28,7 @@ bitflags::bitflags! {
         const KEY_ASSIGNMENTS = 16;
         const WORKSPACES = 32;
         const COMMANDS = 64;
+        const RECENT_DIRS = 128;
     }
 }
 
@@ -66,6 +68,9 @@ impl ToString for LauncherFlags {
         if self.contains(Self::COMMANDS) {
             s.push("COMMANDS");
         }
+        if self.contains(Self::RECENT_DIRS) {
+            s.push("RECENT_DIRS");
+        }
         s.join("|")
     }
 }
@@ -85,6 +90,7 @@ impl TryFrom<String> for LauncherFlags {
                 "KEY_ASSIGNMENTS" => flags |= Self::KEY_ASSIGNMENTS,
                 "WORKSPACES" => flags |= Self::WORKSPACES,
                 "COMMANDS" => flags |= Self::COMMANDS,
+                "RECENT_DIRS" => flags |= Self::RECENT_DIRS,
                 _ => {
                     return Err(format!("invalid LauncherFlags `{}` in `{}`", ele, s));
                 }
@@ -163,6 +169,19 @@ impl Default for SpawnTabDomain {
     }
 }
 
+/// Which window a command that is spawned into a new window goes into
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub enum SpawnWindowTarget {
+    /// A new tab in the existing window with this id
+    ExistingWindow(usize),
+    /// A new window on the screen with this name, or with this index
+    /// when the screens are numbered from 0, from left to right
+    NewWindowOnScreen(String),
+    /// A new tab in the window that the mouse pointer is over, or a
+    /// new window if the pointer isn't over any
+    WindowUnderMouse,
+}
+
 #[derive(Default, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
 pub struct SpawnCommand {
     /// Optional descriptive label
@@ -180,7 +199,9 @@ pub struct SpawnCommand {
     /// current working directory of the wezterm process when
     /// it was launched, or for some domains it may be some
     /// other location appropriate to the domain.
-    pub cwd: Option<PathBuf>,
+    /// May be a `file://host/path` URL to indicate the host on which
+    /// the directory exists.
+    pub cwd: Option<CommandDir>,
 
     /// Specifies a map of environment variables that should be set.
     /// Whether this is used depends on the domain.
@@ -189,9 +210,146 @@ pub struct SpawnCommand {
 
     #[dynamic(default)]
     pub domain: SpawnTabDomain,
+
+    /// If set, an existing pane that matches is activated instead
+    /// of spawning the command
+    pub reuse: Option<ReusePredicate>,
+
+    /// Parameters whose values are supplied when the command is
+    /// spawned, and substituted for `${name}` in the args, cwd
+    /// and environment
+    #[dynamic(default)]
+    pub prompt_args: Vec<PromptArg>,
+
+    /// What happens to the pane when the command exits, in place of
+    /// the `exit_behavior` of the domain
+    pub exit_behavior: Option<crate::ExitBehavior>,
+
+    /// Where the command goes when it is spawned into a new window.
+    /// Resolved by the GUI; ignored when spawning a tab or a split.
+    pub window: Option<SpawnWindowTarget>,
+
+    /// Don't let `domain_rules` choose another domain for the command
+    #[dynamic(default)]
+    pub no_domain_rules: bool,
 }
 impl_lua_conversion_dynamic!(SpawnCommand);
 
+impl SpawnCommand {
+    /// Returns a copy of this command in which the values of its
+    /// `prompt_args` have been substituted.  Values are taken from
+    /// `params`, falling back to the default of each parameter.
+    /// It is an error for a parameter to have neither, or for `params`
+    /// to name a parameter that the command doesn't have.
+    pub fn with_params(&self, params: &HashMap<String, String>) -> anyhow::Result<Self> {
+        for name in params.keys() {
+            if !self.prompt_args.iter().any(|arg| &arg.name == name) {
+                anyhow::bail!("the command has no parameter named `{}`", name);
+            }
+        }
+
+        let mut values = HashMap::new();
+        let mut missing = vec![];
+        for arg in &self.prompt_args {
+            match params.get(&arg.name).or(arg.default.as_ref()) {
+                Some(value) => {
+                    values.insert(arg.name.as_str(), value.as_str());
+                }
+                None => missing.push(arg.name.as_str()),
+            }
+        }
+        if !missing.is_empty() {
+            anyhow::bail!("no value was supplied for {}", missing.join(", "));
+        }
+
+        let mut spawn = self.clone();
+        spawn.prompt_args.clear();
+        if let Some(args) = &mut spawn.args {
+            for arg in args.iter_mut() {
+                *arg = expand_params(arg, &values);
+            }
+        }
+        if let Some(cwd) = &mut spawn.cwd {
+            let path = expand_params(&cwd.path, &values);
+            // A parameter may supply the host of a `file://` URL
+            *cwd = match &cwd.host {
+                Some(host) => CommandDir {
+                    host: Some(host.clone()),
+                    path,
+                },
+                None => CommandDir::parse(&path),
+            };
+        }
+        for value in spawn.set_environment_variables.values_mut() {
+            *value = expand_params(value, &values);
+        }
+        Ok(spawn)
+    }
+}
+
+/// Replaces `${name}` with the value of `name` for each of `values`.
+/// Other `${...}` sequences, such as references to shell variables,
+/// are left as they are.
+fn expand_params(s: &str, values: &HashMap<&str, &str>) -> String {
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after
+            .find('}')
+            .and_then(|end| values.get(&after[..end]).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A parameter of a `SpawnCommand`.
+/// When the command is spawned from the GUI, the user is prompted for
+/// its value; other ways of spawning must supply it.
+#[derive(Debug, Default, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct PromptArg {
+    pub name: String,
+    /// The text shown when prompting for the value; the name if omitted
+    pub prompt: Option<String>,
+    /// The value used if no other is supplied
+    pub default: Option<String>,
+    /// Values that are offered for tab completion
+    #[dynamic(default)]
+    pub choices: Vec<String>,
+    /// The name of an event whose handler returns additional
+    /// values to offer for tab completion
+    pub choices_event: Option<String>,
+}
+impl_lua_conversion_dynamic!(PromptArg);
+
+/// Describes an existing pane that can satisfy a request to spawn a
+/// command, such as one that is already running the same tool.
+/// A pane matches if it meets all of the criteria that are set.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize, FromDynamic, ToDynamic)]
+pub struct ReusePredicate {
+    /// User vars that the pane must have, with these values
+    #[dynamic(default)]
+    pub user_vars: HashMap<String, String>,
+    /// A regex that the title of the pane must match
+    pub title: Option<String>,
+    /// The current working directory that the pane must have
+    pub cwd: Option<CommandDir>,
+    /// The name of the domain that the pane must belong to
+    pub domain: Option<String>,
+}
+impl_lua_conversion_dynamic!(ReusePredicate);
+
 impl std::fmt::Debug for SpawnCommand {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(fmt, "{}", self)
@@ -209,15 +367,169 @@ impl std::fmt::Display for SpawnCommand {
             write!(fmt, " args={:?}", args)?;
         }
         if let Some(cwd) = &self.cwd {
-            write!(fmt, " cwd={}", cwd.display())?;
+            write!(fmt, " cwd={}", cwd)?;
         }
         for (k, v) in &self.set_environment_variables {
             write!(fmt, " {}={}", k, v)?;
         }
+        if let Some(reuse) = &self.reuse {
+            write!(fmt, " reuse={:?}", reuse)?;
+        }
+        for arg in &self.prompt_args {
+            write!(fmt, " ${{{}}}", arg.name)?;
+        }
+        if let Some(exit_behavior) = &self.exit_behavior {
+            write!(fmt, " exit_behavior={}", exit_behavior.as_str())?;
+        }
+        if let Some(window) = &self.window {
+            write!(fmt, " window={:?}", window)?;
+        }
+        if self.no_domain_rules {
+            write!(fmt, " no_domain_rules")?;
+        }
         Ok(())
     }
 }
 
+/// The working directory for a command that is to be spawned.
+/// `path` is interpreted by the domain that spawns the command, so it
+/// may be a path on a remote host or inside a container.
+/// `host` is set when the directory was specified as a
+/// `file://host/path` URL, such as those reported via OSC 7, and
+/// names the host on which `path` exists.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct CommandDir {
+    pub host: Option<String>,
+    pub path: String,
+}
+
+impl CommandDir {
+    /// Creates a directory without a host component
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Self {
+            host: None,
+            path: path.into(),
+        }
+    }
+
+    /// Parses either a plain path or a `file://` URL
+    pub fn parse(s: &str) -> Self {
+        if s.starts_with("file:") {
+            if let Some(dir) = Url::parse(s).ok().and_then(|url| Self::from_url(&url)) {
+                return dir;
+            }
+        }
+        Self::new(s)
+    }
+
+    /// Converts a `file://host/path` URL.
+    /// Returns None for other schemes, or if the path isn't utf8 or
+    /// contains control characters.
+    pub fn from_url(url: &Url) -> Option<Self> {
+        if url.scheme() != "file" || !wezterm_term::cwd::is_safe_cwd_url(url) {
+            return None;
+        }
+        let path = percent_decode_str(url.path()).decode_utf8().ok()?;
+        // On Windows the file URI can produce a path like:
+        // `/C:\Users` which is valid in a file URI, but the leading slash
+        // is not liked by the windows file APIs, so we strip it off here.
+        let bytes = path.as_bytes();
+        let path = if bytes.len() > 2 && bytes[0] == b'/' && bytes[2] == b':' {
+            path[1..].to_owned()
+        } else {
+            path.into_owned()
+        };
+        Some(Self {
+            host: url
+                .host_str()
+                .filter(|host| !host.is_empty())
+                .map(|host| host.to_string()),
+            path,
+        })
+    }
+
+    /// Returns true if the directory has no host component, or if
+    /// its host is this machine
+    pub fn is_on_local_host(&self) -> bool {
+        match &self.host {
+            Some(host) if host.eq_ignore_ascii_case("localhost") => true,
+            Some(_) => match hostname::get() {
+                Ok(local) => self.is_on_host(&local.to_string_lossy()),
+                Err(_) => false,
+            },
+            None => true,
+        }
+    }
+
+    /// Returns true if the directory has a host component that names
+    /// the host `name`.
+    /// Shells typically report the short name of the host, so only
+    /// the first label of each name is compared, unless either is
+    /// an IP address.
+    pub fn is_on_host(&self, name: &str) -> bool {
+        let host = match &self.host {
+            Some(host) => host,
+            None => return false,
+        };
+        if host.parse::<std::net::IpAddr>().is_ok() || name.parse::<std::net::IpAddr>().is_ok() {
+            return host.eq_ignore_ascii_case(name);
+        }
+        fn short_name(name: &str) -> &str {
+            name.split('.').next().unwrap_or(name)
+        }
+        short_name(name).eq_ignore_ascii_case(short_name(host))
+    }
+}
+
+impl From<String> for CommandDir {
+    fn from(path: String) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<&str> for CommandDir {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
+}
+
+impl std::fmt::Display for CommandDir {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(host) = &self.host {
+            if let Ok(mut url) = Url::parse(&format!("file://{}/", host)) {
+                if self.path.starts_with('/') {
+                    url.set_path(&self.path);
+                } else {
+                    url.set_path(&format!("/{}", self.path));
+                }
+                return write!(fmt, "{}", url);
+            }
+        }
+        write!(fmt, "{}", self.path)
+    }
+}
+
+impl ToDynamic for CommandDir {
+    fn to_dynamic(&self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl FromDynamic for CommandDir {
+    fn from_dynamic(
+        value: &Value,
+        _options: FromDynamicOptions,
+    ) -> Result<Self, wezterm_dynamic::Error> {
+        match value {
+            Value::String(s) => Ok(Self::parse(s)),
+            other => Err(wezterm_dynamic::Error::NoConversion {
+                source_type: other.variant_name().to_string(),
+                dest_type: "CommandDir",
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
 pub enum PaneDirection {
     Up,
@@ -301,6 +613,40 @@ pub struct QuickSelectArguments {
     /// Label to use in place of "copy" when `action` is set
     #[dynamic(default)]
     pub label: String,
+    /// How many rows above and below the viewport to match
+    #[dynamic(default)]
+    pub scope_lines: Option<usize>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct EditScrollbackArguments {
+    /// The most lines to edit, counting back from the bottom of the
+    /// screen; all of them if None
+    #[dynamic(default)]
+    pub lines: Option<usize>,
+    /// Keep the escape sequences for the attributes of the text
+    #[dynamic(default)]
+    pub escapes: bool,
+    /// Copy the lines of a pane of a multiplexer server down, and run
+    /// the editor locally, rather than on the server
+    #[dynamic(default)]
+    pub local: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct GlobalSearchArguments {
+    /// What to search for; the user is asked for it if it is empty
+    #[dynamic(default)]
+    pub pattern: Pattern,
+    /// Only search the panes of the domain with this name
+    #[dynamic(default)]
+    pub domain: Option<String>,
+    /// Only search the panes of the active workspace
+    #[dynamic(default)]
+    pub current_workspace_only: bool,
+    /// The most matches that are kept for each pane
+    #[dynamic(default)]
+    pub max_matches_per_pane: Option<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
@@ -348,6 +694,9 @@ pub enum KeyAssignment {
     SplitVertical(SpawnCommand),
     ShowLauncher,
     ShowLauncherArgs(LauncherActionArgs),
+    SpawnInDomainWithCwd,
+    SpawnInRecentDir,
+    ListBookmarks,
     ClearScrollback(ScrollbackEraseMode),
     Search(Pattern),
     ActivateCopyMode,
@@ -394,11 +743,23 @@ pub enum KeyAssignment {
     ClearKeyTableStack,
     DetachDomain(SpawnTabDomain),
     AttachDomain(String),
+    RefreshDockerDomains,
+    PerformDomainAction {
+        domain: String,
+        action: String,
+    },
+    LockPane,
+    CloneTab,
+    TogglePanePause,
+    CancelAttach(String),
 
     CopyMode(CopyModeAssignment),
     RotatePanes(RotationDirection),
     SplitPane(SplitPane),
+    SplitPanePreview(SplitPane),
     PaneSelect(PaneSelectArguments),
+    EditScrollback(EditScrollbackArguments),
+    GlobalSearch(GlobalSearchArguments),
 }
 impl_lua_conversion_dynamic!(KeyAssignment);
 
@@ -411,6 +772,26 @@ pub struct SplitPane {
     pub command: SpawnCommand,
     #[dynamic(default)]
     pub top_level: bool,
+    #[dynamic(default)]
+    pub target: SplitPaneTarget,
+}
+
+/// Chooses the pane that a `SplitPane` assignment splits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub enum SplitPaneTarget {
+    /// Split the active pane
+    ActivePane,
+    /// Show the pane selector, then split the selected pane
+    Selection,
+    /// Show the pane selector, then move the selected pane into
+    /// the split of the active pane instead of spawning a command
+    MoveSelection,
+}
+
+impl Default for SplitPaneTarget {
+    fn default() -> Self {
+        Self::ActivePane
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
@@ -476,3 +857,103 @@ pub struct KeyTables {
 pub struct KeyTableEntry {
     pub action: KeyAssignment,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_dir() {
+        assert_eq!(CommandDir::parse("/home/wez"), CommandDir::new("/home/wez"));
+        assert_eq!(
+            CommandDir::parse("file://foo/home/wez%20t"),
+            CommandDir {
+                host: Some("foo".to_string()),
+                path: "/home/wez t".to_string(),
+            }
+        );
+        assert_eq!(
+            CommandDir::parse("file:///home/wez"),
+            CommandDir::new("/home/wez")
+        );
+        assert_eq!(
+            CommandDir::parse("file://foo/C:/Users/wez"),
+            CommandDir {
+                host: Some("foo".to_string()),
+                path: "C:/Users/wez".to_string(),
+            }
+        );
+
+        let dir = CommandDir::parse("file://foo/home/wez%20t");
+        assert_eq!(dir.to_string(), "file://foo/home/wez%20t");
+        assert_eq!(CommandDir::parse(&dir.to_string()), dir);
+        assert!(!dir.is_on_local_host());
+        assert!(CommandDir::parse("file://localhost/tmp").is_on_local_host());
+        assert!(dir.is_on_host("foo.example.com"));
+        assert!(!dir.is_on_host("bar"));
+        assert!(!CommandDir::parse("file://10.0.0.1/tmp").is_on_host("10.1.1.1"));
+        assert!(!CommandDir::new("/tmp").is_on_host("foo"));
+
+        // Encoded control characters are not decoded into the path
+        let url = Url::parse("file://foo/tmp/%0Aevil%1B]0;x").unwrap();
+        assert_eq!(CommandDir::from_url(&url), None);
+        let url = Url::parse("file://foo/tmp/caf%C3%A9%20dir").unwrap();
+        assert_eq!(
+            CommandDir::from_url(&url).map(|dir| dir.path),
+            Some("/tmp/café dir".to_string())
+        );
+    }
+
+    #[test]
+    fn spawn_command_params() {
+        let spawn = SpawnCommand {
+            args: Some(vec![
+                "ssh".to_string(),
+                "${user}@${host}".to_string(),
+                "echo ${HOME}".to_string(),
+            ]),
+            cwd: Some(CommandDir::new("file://${host}/tmp")),
+            set_environment_variables: vec![("TARGET".to_string(), "${host}".to_string())]
+                .into_iter()
+                .collect(),
+            prompt_args: vec![
+                PromptArg {
+                    name: "host".to_string(),
+                    ..Default::default()
+                },
+                PromptArg {
+                    name: "user".to_string(),
+                    default: Some("root".to_string()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let params: HashMap<String, String> = vec![("host".to_string(), "devbox".to_string())]
+            .into_iter()
+            .collect();
+        let expanded = spawn.with_params(&params).unwrap();
+        assert_eq!(
+            expanded.args.unwrap(),
+            vec!["ssh", "root@devbox", "echo ${HOME}"]
+        );
+        assert_eq!(
+            expanded.cwd.unwrap(),
+            CommandDir {
+                host: Some("devbox".to_string()),
+                path: "/tmp".to_string(),
+            }
+        );
+        assert_eq!(expanded.set_environment_variables["TARGET"], "devbox");
+        assert!(expanded.prompt_args.is_empty());
+
+        let err = spawn.with_params(&HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "no value was supplied for host");
+
+        let params: HashMap<String, String> = vec![("port".to_string(), "22".to_string())]
+            .into_iter()
+            .collect();
+        assert!(spawn.with_params(&params).is_err());
+    }
+}
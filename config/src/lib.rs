@@ -21,15 +21,20 @@ use wezterm_dynamic::{FromDynamic, FromDynamicOptions, ToDynamic, UnknownFieldAc
 
 mod background;
 mod bell;
+mod changedir;
 mod color;
 mod config;
+mod cwd;
 mod daemon;
+mod docker;
+mod domain;
 mod exec_domain;
 mod font;
 mod frontend;
 pub mod keyassignment;
 mod keys;
 pub mod lua;
+pub mod sandbox;
 mod scheme_data;
 mod ssh;
 mod terminal;
@@ -42,8 +47,12 @@ mod wsl;
 pub use crate::config::*;
 pub use background::*;
 pub use bell::*;
+pub use changedir::*;
 pub use color::*;
+pub use cwd::*;
 pub use daemon::*;
+pub use docker::*;
+pub use domain::*;
 pub use exec_domain::*;
 pub use font::*;
 pub use frontend::*;
@@ -263,6 +272,74 @@ where
     promise::spawn::spawn_into_main_thread(async move { schedule_with_lua(func).await }).await
 }
 
+type LuaThreadJob =
+    Box<dyn FnOnce(Option<Rc<mlua::Lua>>) -> std::pin::Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+lazy_static! {
+    static ref LUA_THREAD: Sender<LuaThreadJob> = spawn_lua_thread();
+}
+
+/// Spawns the dedicated lua executor thread.
+/// That thread maintains its own lua context, loaded the same way as
+/// that of the main thread, so that the `--config` file and any
+/// overrides apply to it too.  The context is loaded when the first
+/// job arrives, and again once the configuration has been reloaded.
+fn spawn_lua_thread() -> Sender<LuaThreadJob> {
+    let (sender, receiver) = smol::channel::unbounded::<LuaThreadJob>();
+
+    std::thread::Builder::new()
+        .name("lua-executor".to_string())
+        .spawn(move || {
+            let executor = smol::LocalExecutor::new();
+            let mut lua: Option<Rc<mlua::Lua>> = None;
+            let mut generation = None;
+
+            smol::block_on(executor.run(async {
+                while let Ok(job) = receiver.recv().await {
+                    let current = configuration().generation();
+                    if generation != Some(current) {
+                        lua = Config::load().lua.map(Rc::new);
+                        generation.replace(current);
+                    }
+                    executor.spawn(job(lua.clone())).detach();
+                }
+            }));
+        })
+        .expect("failed to spawn lua-executor thread");
+
+    sender
+}
+
+/// Evaluate `func` with an optional Lua state on the dedicated lua
+/// executor thread, rather than the main thread.
+/// This is intended for running user supplied callbacks that may
+/// take a while to complete (eg: ExecDomain fixups) without blocking
+/// the GUI; awaiting the result yields rather than blocks.
+///
+/// The lua context on the lua executor thread is distinct from
+/// the one used by the main thread, so state stored in lua globals
+/// by the main thread is not visible to `func`.
+pub async fn with_lua_config_on_lua_thread<F, RETF, RET>(func: F) -> anyhow::Result<RET>
+where
+    F: FnOnce(Option<Rc<mlua::Lua>>) -> RETF + Send + 'static,
+    RETF: Future<Output = anyhow::Result<RET>> + 'static,
+    RET: Send + 'static,
+{
+    let (tx, rx) = smol::channel::bounded(1);
+    let job: LuaThreadJob = Box::new(move |lua| {
+        Box::pin(async move {
+            let result = func(lua).await;
+            tx.send(result).await.ok();
+        })
+    });
+    LUA_THREAD
+        .try_send(job)
+        .map_err(|_| anyhow!("lua executor thread is not running"))?;
+    rx.recv()
+        .await
+        .map_err(|_| anyhow!("lua executor thread dropped the request"))?
+}
+
 fn default_config_with_overrides_applied() -> anyhow::Result<Config> {
     // Cause the default config to be re-evaluated with the overrides applied
     let lua = lua::make_lua_context(Path::new("override"))?;
@@ -394,9 +471,17 @@ pub fn configuration_result() -> Result<ConfigHandle, Error> {
     Ok(CONFIG.get())
 }
 
+/// Returns any advisory warnings about the current configuration.
+/// Unlike errors, warnings don't prevent the configuration from
+/// being used.
+pub fn configuration_warnings() -> Vec<String> {
+    CONFIG.get_warnings()
+}
+
 struct ConfigInner {
     config: Arc<Config>,
     error: Option<String>,
+    warnings: Vec<String>,
     generation: usize,
     watcher: Option<notify::RecommendedWatcher>,
     subscribers: HashMap<usize, Box<dyn Fn() -> bool + Send>>,
@@ -407,6 +492,7 @@ impl ConfigInner {
         Self {
             config: Arc::new(Config::default_config()),
             error: None,
+            warnings: vec![],
             generation: 0,
             watcher: None,
             subscribers: HashMap::new(),
@@ -530,6 +616,20 @@ impl ConfigInner {
 
         match config {
             Ok(config) => {
+                let warnings = config.default_prog_warnings();
+                for warning in &warnings {
+                    log::warn!("{}", warning);
+                }
+                if self.generation > 0 && warnings != self.warnings {
+                    // Only generate the message for an actual reload,
+                    // and only when something changed, so that an
+                    // unrelated edit doesn't keep nagging about it
+                    for warning in &warnings {
+                        show_error(&format!("Warning: {}", warning));
+                    }
+                }
+                self.warnings = warnings;
+
                 self.config = Arc::new(config);
                 self.error.take();
                 self.generation += 1;
@@ -568,6 +668,7 @@ impl ConfigInner {
     fn use_defaults(&mut self) {
         self.config = Arc::new(Config::default_config());
         self.error.take();
+        self.warnings.clear();
         self.generation += 1;
     }
 
@@ -677,6 +778,13 @@ impl Configuration {
         inner.error.as_ref().cloned()
     }
 
+    /// Returns a copy of any advisory warnings about the
+    /// current configuration.
+    pub fn get_warnings(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.warnings.clone()
+    }
+
     /// Returns any captured error message, and clears
     /// it from the config state.
     #[allow(dead_code)]
@@ -726,3 +834,25 @@ fn default_one_point_oh() -> f32 {
 fn default_true() -> bool {
     true
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn lua_thread_does_not_block_the_caller() {
+        let (slow, quick) = smol::block_on(smol::future::zip(
+            with_lua_config_on_lua_thread(|_lua| async {
+                // Stands in for a fixup function that takes a while
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(Instant::now())
+            }),
+            async {
+                smol::Timer::after(Duration::from_millis(10)).await;
+                Instant::now()
+            },
+        ));
+        assert!(quick < slow.unwrap());
+    }
+}
@@ -1,10 +1,10 @@
-use crate::exec_domain::{ExecDomain, ValueOrFunc};
+use crate::exec_domain::{ExecDomain, ExecDomainOptions, ValueOrFunc};
 use crate::keyassignment::KeyAssignment;
 use crate::{
     FontAttributes, FontStretch, FontStyle, FontWeight, FreeTypeLoadTarget, RgbaColor, TextStyle,
 };
 use anyhow::anyhow;
-use luahelper::{from_lua_value_dynamic, lua_value_to_dynamic};
+use luahelper::{dynamic_to_lua_value, from_lua_value_dynamic, lua_value_to_dynamic};
 use mlua::{FromLua, Lua, Table, ToLuaMulti, Value, Variadic};
 use ordered_float::NotNan;
 use std::convert::TryFrom;
@@ -213,17 +213,17 @@ end
     Ok(lua)
 }
 
-fn shell_split<'lua>(_: &'lua Lua, line: String) -> mlua::Result<Vec<String>> {
+pub(crate) fn shell_split<'lua>(_: &'lua Lua, line: String) -> mlua::Result<Vec<String>> {
     shlex::split(&line).ok_or_else(|| {
         mlua::Error::external(format!("cannot tokenize `{line}` using posix shell rules"))
     })
 }
 
-fn shell_join_args<'lua>(_: &'lua Lua, args: Vec<String>) -> mlua::Result<String> {
+pub(crate) fn shell_join_args<'lua>(_: &'lua Lua, args: Vec<String>) -> mlua::Result<String> {
     Ok(shlex::join(args.iter().map(|arg| arg.as_ref())))
 }
 
-fn shell_quote_arg<'lua>(_: &'lua Lua, arg: String) -> mlua::Result<String> {
+pub(crate) fn shell_quote_arg<'lua>(_: &'lua Lua, arg: String) -> mlua::Result<String> {
     Ok(shlex::quote(&arg).into_owned().to_string())
 }
 
@@ -478,7 +478,12 @@ fn action_callback<'lua>(lua: &'lua Lua, callback: mlua::Function) -> mlua::Resu
 
 fn exec_domain<'lua>(
     lua: &'lua Lua,
-    (name, fixup_command, label): (String, mlua::Function, Option<mlua::Value>),
+    (name, fixup_command, label, options): (
+        String,
+        mlua::Function,
+        Option<mlua::Value>,
+        Option<ExecDomainOptions>,
+    ),
 ) -> mlua::Result<ExecDomain> {
     let fixup_command = {
         let event_name = format!("exec-domain-{name}");
@@ -502,14 +507,22 @@ fn exec_domain<'lua>(
         }
         None => None,
     };
+    let options = options.unwrap_or_default();
     Ok(ExecDomain {
         name,
         fixup_command,
         label,
+        use_systemd_scope: options.use_systemd_scope,
+        login_shell: options.login_shell,
+        launcher: options.launcher,
+        palette_overrides: options.palette_overrides,
+        exit_behavior: options.exit_behavior,
+        max_concurrent_spawns: options.max_concurrent_spawns,
+        paste_guard: options.paste_guard,
     })
 }
 
-fn split_by_newlines<'lua>(_: &'lua Lua, text: String) -> mlua::Result<Vec<String>> {
+pub(crate) fn split_by_newlines<'lua>(_: &'lua Lua, text: String) -> mlua::Result<Vec<String>> {
     Ok(text
         .lines()
         .map(|s| {
@@ -521,6 +534,39 @@ fn split_by_newlines<'lua>(_: &'lua Lua, text: String) -> mlua::Result<Vec<Strin
         .collect())
 }
 
+/// Registers the lua functions that the entries of `wsl_domains` use
+/// to compute their `distribution` and `username`, replacing each of
+/// them with the name of the event under which it was registered, so
+/// that the config can be converted to a `Config` struct
+pub(crate) fn register_wsl_domain_callbacks<'lua>(
+    lua: &'lua Lua,
+    config: &Value<'lua>,
+) -> mlua::Result<()> {
+    let domains = match config {
+        Value::Table(config) => match config.get("wsl_domains")? {
+            Value::Table(domains) => domains,
+            _ => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+    for domain in domains.sequence_values::<Value>() {
+        let domain = match domain? {
+            Value::Table(domain) => domain,
+            _ => continue,
+        };
+        let name: String = domain.get::<_, Option<String>>("name")?.unwrap_or_default();
+        for field in &["distribution", "username"] {
+            if let Value::Function(func) = domain.get(*field)? {
+                let event_name = format!("wsl-domain-{name}-{field}");
+                register_event(lua, (event_name.clone(), func))?;
+                let value = dynamic_to_lua_value(lua, ValueOrFunc::Func(event_name).to_dynamic())?;
+                domain.set(*field, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
 /// This implements `wezterm.on`, whose goal is to register an event handler
 /// callback.
 /// The callback function may return `false` to prevent other handlers from
@@ -647,8 +693,16 @@ where
     }
 }
 
+/// Returns true if at least one handler has been registered for the
+/// event `name` via `wezterm.on`
+pub fn has_event_handler(lua: &Lua, name: &str) -> mlua::Result<bool> {
+    let decorated_name = format!("wezterm-event-{}", name);
+    let tbl: mlua::Value = lua.named_registry_value(&decorated_name)?;
+    Ok(matches!(tbl, mlua::Value::Table(tbl) if tbl.raw_len() > 0))
+}
+
 /// Ungh: https://github.com/microsoft/WSL/issues/4456
-fn utf16_to_utf8<'lua>(_: &'lua Lua, text: mlua::String) -> mlua::Result<String> {
+pub(crate) fn utf16_to_utf8<'lua>(_: &'lua Lua, text: mlua::String) -> mlua::Result<String> {
     let bytes = text.as_bytes();
 
     if bytes.len() % 2 != 0 {
@@ -764,4 +818,53 @@ assert(wezterm.emit('bar', 42, 'woot') == true)
 
         Ok(())
     }
+    #[test]
+    fn wsl_domain_callbacks() -> anyhow::Result<()> {
+        let lua = make_lua_context(Path::new("testing"))?;
+        let config: Value = lua
+            .load(
+                r#"
+return {
+  wsl_domains = {
+    {
+      name = 'WSL:Ubuntu',
+      distribution = function(name, distros)
+        return name .. ':' .. #distros
+      end,
+      username = 'hunter',
+    },
+  },
+}
+"#,
+            )
+            .eval()?;
+        register_wsl_domain_callbacks(&lua, &config)?;
+
+        let config: Table = match config {
+            Value::Table(config) => config,
+            _ => unreachable!(),
+        };
+        let domains: Vec<crate::WslDomain> =
+            from_lua_value_dynamic(config.get::<_, Value>("wsl_domains")?)?;
+        let func = match &domains[0].distribution {
+            Some(ValueOrFunc::Func(func)) => func.clone(),
+            other => panic!("unexpected distribution {:?}", other),
+        };
+        assert_eq!(func, "wsl-domain-WSL:Ubuntu-distribution");
+        assert!(matches!(
+            &domains[0].username,
+            Some(ValueOrFunc::Value(wezterm_dynamic::Value::String(user))) if user == "hunter"
+        ));
+
+        let distros: Vec<crate::WslDistro> = vec![];
+        let result = smol::block_on(emit_async_callback(
+            &lua,
+            (func, ("WSL:Ubuntu".to_string(), distros)),
+        ))?;
+        assert_eq!(
+            from_lua_value_dynamic::<String>(result)?,
+            "WSL:Ubuntu:0".to_string()
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,393 @@
+//! Evaluates the `fixup_command` and `label` callbacks of exec domains
+//! in a restricted lua context when `sandbox_exec_domains` is enabled.
+//!
+//! The callback is copied out of the main lua context into a new one
+//! that has only the `string`, `table`, `math` and `utf8` modules, a
+//! handful of base functions and a reduced `wezterm` module, so that
+//! it cannot reach `io`, `os` or anything that spawns processes.
+//! The main context is an ordinary one without the `debug` module, so
+//! the values that the callback captured from the config file cannot
+//! be read; a callback that captures anything other than `wezterm` is
+//! refused.
+use crate::lua::{shell_join_args, shell_quote_arg, shell_split, split_by_newlines, utf16_to_utf8};
+use anyhow::{anyhow, bail, Context};
+use luahelper::{dynamic_to_lua_value, lua_value_to_dynamic};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use wezterm_dynamic::{ToDynamic, Value as DynValue};
+
+/// The instruction budget is checked each time this many
+/// instructions have been executed
+const HOOK_INTERVAL: u32 = 1000;
+
+/// The base functions that are available in the sandbox.
+/// `pcall` and `xpcall` are left out so that the callback cannot
+/// catch the error that stops it when it exceeds its budget.
+const SAFE_GLOBALS: &[&str] = &[
+    "_VERSION",
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "math",
+    "next",
+    "pairs",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "setmetatable",
+    "string",
+    "table",
+    "tonumber",
+    "tostring",
+    "type",
+    "utf8",
+];
+
+/// The limits applied to a sandboxed callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxLimits {
+    /// The number of lua instructions that the callback may execute
+    pub max_instructions: u64,
+    /// The number of bytes that the sandboxed lua context may allocate
+    pub max_memory: usize,
+}
+
+/// Copies `func` from the main lua context into the sandbox.
+/// Only the code of the function can be copied, as the main context
+/// has no access to the values that a function captures: the copy sees
+/// the sandbox environment as its globals, and the reduced `wezterm`
+/// module in place of a captured `wezterm`; a function that captures
+/// any other value is refused.
+fn copy_function<'sandbox>(
+    domain: &str,
+    func: Function,
+    dump: Function,
+    sandbox: &'sandbox Lua,
+    env: Table<'sandbox>,
+    sandbox_wezterm: Table<'sandbox>,
+) -> anyhow::Result<Function<'sandbox>> {
+    let dumped: mlua::String = dump.call(func).map_err(|_| {
+        anyhow!(
+            "exec domain '{}' uses a function that is not written in lua, \
+             which cannot be used in the sandbox",
+            domain
+        )
+    })?;
+    let sandbox_globals = sandbox.globals();
+    let load: Function = sandbox_globals.get("load")?;
+    let sandbox_debug: Table = sandbox_globals.get("debug")?;
+    let getupvalue: Function = sandbox_debug.get("getupvalue")?;
+    let setupvalue: Function = sandbox_debug.get("setupvalue")?;
+
+    let chunk = sandbox.create_string(dumped.as_bytes())?;
+    let (copy, err): (Option<Function>, Option<String>) =
+        load.call((chunk, format!("=exec_domain {}", domain), "b"))?;
+    let copy = copy.ok_or_else(|| {
+        anyhow!(
+            "exec domain '{}': failed to load callback into the sandbox: {}",
+            domain,
+            err.unwrap_or_default()
+        )
+    })?;
+
+    for idx in 1.. {
+        let (name, _): (Option<String>, Value) = getupvalue.call((copy.clone(), idx))?;
+        let value = match name.as_deref() {
+            None => break,
+            Some("_ENV") => env.clone(),
+            Some("wezterm") => sandbox_wezterm.clone(),
+            Some(name) => bail!(
+                "exec domain '{}' refers to the local `{}`, which cannot be used \
+                 in the sandbox; callbacks can only use their arguments, `wezterm` \
+                 and the standard functions of the sandbox",
+                domain,
+                name
+            ),
+        };
+        setupvalue.call::<_, Option<String>>((copy.clone(), idx, value))?;
+    }
+
+    Ok(copy)
+}
+
+/// Builds the reduced `wezterm` module that is visible in the sandbox
+fn sandbox_wezterm_module(lua: &Lua) -> anyhow::Result<Table> {
+    let wezterm = lua.create_table()?;
+    wezterm.set("version", crate::wezterm_version())?;
+    wezterm.set("target_triple", crate::wezterm_target_triple())?;
+    wezterm.set("home_dir", crate::HOME_DIR.to_str())?;
+    wezterm.set(
+        "log_info",
+        lua.create_function(|_, args: Variadic<String>| {
+            log::info!("lua: {}", args.join(" "));
+            Ok(())
+        })?,
+    )?;
+    wezterm.set(
+        "log_warn",
+        lua.create_function(|_, args: Variadic<String>| {
+            log::warn!("lua: {}", args.join(" "));
+            Ok(())
+        })?,
+    )?;
+    wezterm.set(
+        "log_error",
+        lua.create_function(|_, args: Variadic<String>| {
+            log::error!("lua: {}", args.join(" "));
+            Ok(())
+        })?,
+    )?;
+    wezterm.set("shell_join_args", lua.create_function(shell_join_args)?)?;
+    wezterm.set("shell_quote_arg", lua.create_function(shell_quote_arg)?)?;
+    wezterm.set("shell_split", lua.create_function(shell_split)?)?;
+    wezterm.set("split_by_newlines", lua.create_function(split_by_newlines)?)?;
+    wezterm.set("utf16_to_utf8", lua.create_function(utf16_to_utf8)?)?;
+    Ok(wezterm)
+}
+
+fn is_memory_error(err: &mlua::Error) -> bool {
+    match err {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// Calls the handler that was registered for `event_name` in the main
+/// lua context `lua` with `arg`, in a new sandboxed lua context that is
+/// subject to `limits`.
+/// `domain` is the name of the exec domain that the handler belongs
+/// to, and is used in error messages.
+/// Returns Null if no handler is registered.
+pub fn call_exec_domain_callback<A: ToDynamic>(
+    lua: &Lua,
+    domain: &str,
+    event_name: &str,
+    arg: A,
+    limits: SandboxLimits,
+) -> anyhow::Result<DynValue> {
+    let decorated_name = format!("wezterm-event-{}", event_name);
+    let handlers: Value = lua.named_registry_value(&decorated_name)?;
+    let func = match handlers {
+        Value::Table(tbl) => match tbl.get::<_, Option<Function>>(1)? {
+            Some(func) => func,
+            None => return Ok(DynValue::Null),
+        },
+        _ => return Ok(DynValue::Null),
+    };
+
+    let string: Table = lua.globals().get("string")?;
+
+    // The debug module is needed to set the upvalues of the copied
+    // function, but it is not reachable from the callback, which
+    // only sees the environment built below
+    let sandbox = unsafe {
+        Lua::unsafe_new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8 | StdLib::DEBUG,
+            LuaOptions::default(),
+        )
+    };
+    let sandbox_globals = sandbox.globals();
+    let env = sandbox.create_table()?;
+    for name in SAFE_GLOBALS {
+        env.set(*name, sandbox_globals.get::<_, Value>(*name)?)?;
+    }
+    let sandbox_wezterm = sandbox_wezterm_module(&sandbox)?;
+    env.set("wezterm", sandbox_wezterm.clone())?;
+    env.set("_G", env.clone())?;
+
+    let func = copy_function(
+        domain,
+        func,
+        string.get("dump")?,
+        &sandbox,
+        env,
+        sandbox_wezterm,
+    )?;
+    let arg = dynamic_to_lua_value(&sandbox, arg.to_dynamic())?;
+
+    sandbox
+        .set_memory_limit(limits.max_memory)
+        .context("setting sandbox memory limit")?;
+    let exceeded_instructions = Arc::new(AtomicBool::new(false));
+    {
+        let executed = AtomicU64::new(0);
+        let exceeded_instructions = Arc::clone(&exceeded_instructions);
+        let max_instructions = limits.max_instructions;
+        sandbox.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(HOOK_INTERVAL),
+                ..Default::default()
+            },
+            move |_, _| {
+                let count = executed.fetch_add(HOOK_INTERVAL as u64, Ordering::Relaxed)
+                    + HOOK_INTERVAL as u64;
+                if count > max_instructions {
+                    exceeded_instructions.store(true, Ordering::Relaxed);
+                    Err(mlua::Error::external(anyhow!("cpu budget exceeded")))
+                } else {
+                    Ok(())
+                }
+            },
+        )?;
+    }
+
+    match func.call::<_, Value>(arg) {
+        Ok(value) => Ok(lua_value_to_dynamic(value)?),
+        Err(_) if exceeded_instructions.load(Ordering::Relaxed) => bail!(
+            "exec domain '{}' exceeded cpu budget of {} instructions",
+            domain,
+            limits.max_instructions
+        ),
+        Err(err) if is_memory_error(&err) => bail!(
+            "exec domain '{}' exceeded memory budget of {} bytes",
+            domain,
+            limits.max_memory
+        ),
+        Err(err) => Err(anyhow!("exec domain '{}': {}", domain, err)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lua::make_lua_context;
+    use std::path::Path;
+
+    const LIMITS: SandboxLimits = SandboxLimits {
+        max_instructions: 1_000_000,
+        max_memory: 8 * 1024 * 1024,
+    };
+
+    fn register(lua: &Lua, code: &str) {
+        lua.load(code).exec().unwrap();
+    }
+
+    #[test]
+    fn runs_callback() {
+        let lua = make_lua_context(Path::new("testing")).unwrap();
+        register(
+            &lua,
+            r#"
+local wezterm = require 'wezterm'
+wezterm.on('exec-domain-test', function(cmd)
+  local prefix = { 'ssh', 'devbox' }
+  return { prefix[1], prefix[2], wezterm.shell_join_args(cmd.args) }
+end)
+"#,
+        );
+        let result = call_exec_domain_callback(
+            &lua,
+            "test",
+            "exec-domain-test",
+            crate::keyassignment::SpawnCommand {
+                args: Some(vec!["echo".to_string(), "a b".to_string()]),
+                ..Default::default()
+            },
+            LIMITS,
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            vec!["ssh", "devbox", "echo 'a b'"]
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .to_dynamic()
+        );
+    }
+
+    #[test]
+    fn refuses_captured_locals() {
+        let lua = make_lua_context(Path::new("testing")).unwrap();
+        register(
+            &lua,
+            r#"
+local wezterm = require 'wezterm'
+local prefix = 'ssh'
+wezterm.on('exec-domain-test', function()
+  return prefix
+end)
+"#,
+        );
+        let err = call_exec_domain_callback(&lua, "test", "exec-domain-test", (), LIMITS)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "exec domain 'test' refers to the local `prefix`, which cannot be used \
+             in the sandbox; callbacks can only use their arguments, `wezterm` \
+             and the standard functions of the sandbox"
+        );
+    }
+
+    #[test]
+    fn main_context_has_no_debug_module() {
+        let lua = make_lua_context(Path::new("testing")).unwrap();
+        let kind: String = lua.load("return type(debug)").eval().unwrap();
+        assert_eq!(kind, "nil");
+    }
+
+    #[test]
+    fn restricts_globals() {
+        let lua = make_lua_context(Path::new("testing")).unwrap();
+        register(
+            &lua,
+            r#"
+local wezterm = require 'wezterm'
+wezterm.on('exec-domain-test', function()
+  return {
+    type(io), type(os), type(require), type(load), type(pcall),
+    type(wezterm.run_child_process),
+  }
+end)
+"#,
+        );
+        let result =
+            call_exec_domain_callback(&lua, "test", "exec-domain-test", (), LIMITS).unwrap();
+        assert_eq!(result, vec!["nil".to_string(); 6].to_dynamic());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let lua = make_lua_context(Path::new("testing")).unwrap();
+        register(
+            &lua,
+            r#"
+local wezterm = require 'wezterm'
+wezterm.on('exec-domain-spin', function()
+  while true do end
+end)
+wezterm.on('exec-domain-hog', function()
+  local t = {}
+  for i = 1, 100000000 do
+    t[i] = string.rep('x', 1024) .. i
+  end
+end)
+"#,
+        );
+        let err = call_exec_domain_callback(&lua, "spin", "exec-domain-spin", (), LIMITS)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "exec domain 'spin' exceeded cpu budget of 1000000 instructions"
+        );
+
+        let limits = SandboxLimits {
+            max_instructions: u64::MAX,
+            ..LIMITS
+        };
+        let err = call_exec_domain_callback(&lua, "hog", "exec-domain-hog", (), limits)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "exec domain 'hog' exceeded memory budget of 8388608 bytes"
+        );
+    }
+}
@@ -89,8 +89,54 @@ pub struct SshDomain {
 
     pub default_prog: Option<Vec<String>>,
 
+    /// The directory on the remote host in which `default_prog`, or
+    /// the login shell, is started
+    pub default_cwd: Option<PathBuf>,
+
     #[dynamic(default)]
     pub assume_shell: Shell,
+
+    /// Which desktop notifications from the panes in this domain
+    /// are shown.  Defaults to `notification_handling`.
+    pub notifications: Option<NotificationHandling>,
+
+    /// What happens to the panes in this domain when a window that
+    /// contains them is closed.  Defaults to `Detach`.
+    pub on_window_close: Option<ClosePolicy>,
+
+    /// What happens to the panes in this domain when a tab that
+    /// contains them is closed.  Defaults to `Kill`.
+    pub on_tab_close: Option<ClosePolicy>,
+
+    /// If true, the panes in this domain are locked after `lock_after`
+    /// without input, and input is held until the user unlocks them
+    #[dynamic(default)]
+    pub sensitive: bool,
+
+    /// How long a pane in this domain can go without input before it
+    /// is locked, if the domain is `sensitive`.  Defaults to 15 minutes.
+    #[dynamic(default = "default_lock_after")]
+    pub lock_after: Duration,
+
+    /// How the domain is presented in the launcher
+    #[dynamic(default)]
+    pub launcher: LauncherHints,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
 }
 
 #[derive(Clone, Debug)]
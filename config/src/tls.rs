@@ -1,6 +1,31 @@
 use crate::*;
+use serde::{Deserialize, Serialize};
 use wezterm_dynamic::{FromDynamic, ToDynamic};
 
+/// What a client that authenticated with a token may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, FromDynamic, ToDynamic)]
+pub enum AuthRole {
+    /// Anything that a client with a certificate may do
+    Full,
+    /// Watch the panes, but not type into them or change the mux
+    ReadOnly,
+}
+
+impl Default for AuthRole {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
+/// A token that clients of a TLS server may authenticate with
+#[derive(Default, Debug, Clone, FromDynamic, ToDynamic)]
+pub struct TlsAuthToken {
+    pub token: String,
+    /// What clients that present the token may do
+    #[dynamic(default)]
+    pub role: AuthRole,
+}
+
 #[derive(Default, Debug, Clone, FromDynamic, ToDynamic)]
 pub struct TlsDomainServer {
     /// The address:port combination on which the server will listen
@@ -23,6 +48,29 @@ pub struct TlsDomainServer {
     /// to the trust store.
     #[dynamic(default)]
     pub pem_root_certs: Vec<PathBuf>,
+
+    /// If true, clients may connect without a certificate in order to
+    /// redeem a share token made by `wezterm cli share-tab`, after
+    /// which they may only watch the shared tab
+    #[dynamic(default)]
+    pub accept_shares: bool,
+
+    /// If true, clients must authenticate with a token right after
+    /// they connect.  The token is checked by the `mux-authenticate`
+    /// event, or else against `auth_tokens`.
+    #[dynamic(default)]
+    pub require_auth_token: bool,
+
+    /// The tokens that are accepted when `require_auth_token` is set
+    #[dynamic(default)]
+    pub auth_tokens: Vec<TlsAuthToken>,
+
+    /// If false, clients may connect without a certificate, as long
+    /// as they authenticate with a token, which requires
+    /// `require_auth_token`.  The default is true, in which case
+    /// clients need both a certificate and, if required, a token.
+    #[dynamic(default = "default_true")]
+    pub require_client_cert: bool,
 }
 
 #[derive(Default, Debug, Clone, FromDynamic, ToDynamic)]
@@ -39,6 +87,12 @@ pub struct TlsDomainClient {
     /// identifies the host:port pair of the remote server.
     pub remote_address: String,
 
+    /// Instead of connecting to `remote_address` using TLS, spawn
+    /// this command and speak the mux protocol over its stdin/stdout.
+    /// The command is expected to run `wezterm cli proxy` on the
+    /// remote host, and is run again when reconnecting.
+    pub proxy_command: Option<Vec<String>>,
+
     /// the path to an x509 PEM encoded private key file
     pub pem_private_key: Option<PathBuf>,
 
@@ -85,9 +139,89 @@ pub struct TlsDomainClient {
 
     /// The path to the wezterm binary on the remote host
     pub remote_wezterm_path: Option<String>,
+
+    /// A token made by `wezterm cli share-tab` on the server.  When
+    /// set, no client certificate is used; the certificate of the
+    /// server is checked against the token, which is then redeemed
+    /// to watch the shared tab.  Set by `wezterm connect --share`.
+    pub share_token: Option<String>,
+
+    /// The token to authenticate with, for a server that sets
+    /// `require_auth_token`
+    pub auth_token: Option<String>,
+
+    /// A command that prints the token to authenticate with, which
+    /// is run each time the client connects.  Takes precedence over
+    /// `auth_token`.
+    pub auth_token_command: Option<Vec<String>>,
+
+    /// Which desktop notifications from the panes in this domain
+    /// are shown.  Defaults to `notification_handling`.
+    pub notifications: Option<NotificationHandling>,
+
+    /// What happens to the panes in this domain when a window that
+    /// contains them is closed.  Defaults to `Detach`.
+    pub on_window_close: Option<ClosePolicy>,
+
+    /// What happens to the panes in this domain when a tab that
+    /// contains them is closed.  Defaults to `Kill`.
+    pub on_tab_close: Option<ClosePolicy>,
+
+    /// If true, the panes in this domain are locked after `lock_after`
+    /// without input, and input is held until the user unlocks them
+    #[dynamic(default)]
+    pub sensitive: bool,
+
+    /// How long a pane in this domain can go without input before it
+    /// is locked, if the domain is `sensitive`.  Defaults to 15 minutes.
+    #[dynamic(default = "default_lock_after")]
+    pub lock_after: Duration,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
 }
 
 impl TlsDomainClient {
+    /// Returns the token to authenticate with, if any, running
+    /// `auth_token_command` to obtain it if that is set
+    pub fn resolve_auth_token(&self) -> anyhow::Result<Option<String>> {
+        let argv = match &self.auth_token_command {
+            Some(argv) => argv,
+            None => return Ok(self.auth_token.clone()),
+        };
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("auth_token_command is empty"))?;
+        let output = std::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .with_context(|| format!("running auth_token_command {:?}", argv))?;
+        anyhow::ensure!(
+            output.status.success(),
+            "auth_token_command {:?} failed: {}",
+            argv,
+            output.status
+        );
+        let token = String::from_utf8(output.stdout)
+            .with_context(|| format!("auth_token_command {:?} printed invalid UTF-8", argv))?;
+        Ok(Some(token.trim().to_string()))
+    }
+
     pub fn ssh_parameters(&self) -> Option<anyhow::Result<SshParameters>> {
         self.bootstrap_via_ssh
             .as_ref()
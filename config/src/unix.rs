@@ -1,5 +1,5 @@
 use crate::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use wezterm_dynamic::{FromDynamic, ToDynamic};
 
 /// Configures an instance of a multiplexer that can be communicated
@@ -53,6 +53,44 @@ pub struct UnixDomain {
     /// Don't use default_local_echo_threshold_ms() here to
     /// disable the predictive echo for Unix domains by default.
     pub local_echo_threshold_ms: Option<u64>,
+
+    /// Which desktop notifications from the panes in this domain
+    /// are shown.  Defaults to `notification_handling`.
+    pub notifications: Option<NotificationHandling>,
+
+    /// What happens to the panes in this domain when a window that
+    /// contains them is closed.  Defaults to `Detach`.
+    pub on_window_close: Option<ClosePolicy>,
+
+    /// What happens to the panes in this domain when a tab that
+    /// contains them is closed.  Defaults to `Kill`.
+    pub on_tab_close: Option<ClosePolicy>,
+
+    /// If true, the panes in this domain are locked after `lock_after`
+    /// without input, and input is held until the user unlocks them
+    #[dynamic(default)]
+    pub sensitive: bool,
+
+    /// How long a pane in this domain can go without input before it
+    /// is locked, if the domain is `sensitive`.  Defaults to 15 minutes.
+    #[dynamic(default = "default_lock_after")]
+    pub lock_after: Duration,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
 }
 
 impl Default for UnixDomain {
@@ -68,10 +106,67 @@ impl Default for UnixDomain {
             write_timeout: default_write_timeout(),
             local_echo_threshold_ms: None,
             proxy_command: None,
+            notifications: None,
+            on_window_close: None,
+            on_tab_close: None,
+            sensitive: false,
+            lock_after: default_lock_after(),
+            palette_overrides: None,
+            exit_behavior: None,
+            max_concurrent_spawns: None,
+            paste_guard: None,
+        }
+    }
+}
+
+/// Configures a fixed endpoint that the multiplexer listens on in
+/// addition to its default socket, so that programs that can't
+/// discover the per-instance socket can still control it
+#[derive(Debug, Clone, Default, PartialEq, FromDynamic, ToDynamic)]
+pub struct CliListen {
+    /// The name of the named pipe to listen on, such as
+    /// `\\.\pipe\wezterm-ctl`.  Only supported on Windows.
+    pub pipe: Option<String>,
+    /// The path of the unix socket to listen on.
+    /// Not supported on Windows.
+    pub socket_path: Option<PathBuf>,
+}
+
+impl CliListen {
+    /// Returns the endpoint to listen on: the name of a named pipe on
+    /// Windows, or the path of a unix socket elsewhere
+    pub fn endpoint(&self) -> anyhow::Result<PathBuf> {
+        self.endpoint_for(cfg!(windows))
+    }
+
+    fn endpoint_for(&self, windows: bool) -> anyhow::Result<PathBuf> {
+        match (&self.pipe, &self.socket_path) {
+            (Some(pipe), None) if windows => {
+                if !is_pipe_name(Path::new(pipe)) {
+                    anyhow::bail!(
+                        "cli_listen.pipe must be of the form \\\\.\\pipe\\NAME, not {}",
+                        pipe
+                    );
+                }
+                Ok(PathBuf::from(pipe))
+            }
+            (None, Some(socket_path)) if !windows => Ok(socket_path.clone()),
+            _ if windows => {
+                anyhow::bail!("cli_listen must set `pipe`, and only `pipe`, on Windows")
+            }
+            _ => anyhow::bail!("cli_listen must set `socket_path`, and only `socket_path`"),
         }
     }
 }
 
+/// Returns true if `path` names a Windows named pipe, such as
+/// `\\.\pipe\wezterm-ctl`, rather than a file system path
+pub fn is_pipe_name(path: &Path) -> bool {
+    path.to_str()
+        .map(|path| path.to_ascii_lowercase().starts_with(r"\\.\pipe\"))
+        .unwrap_or(false)
+}
+
 #[derive(Debug)]
 pub enum UnixTarget {
     Socket(PathBuf),
@@ -118,3 +213,45 @@ impl UnixDomain {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(lua: &str) -> CliListen {
+        let lua_ctx = crate::lua::make_lua_context(Path::new("testing")).unwrap();
+        let value: mlua::Value = lua_ctx.load(lua).eval().unwrap();
+        luahelper::from_lua_value_dynamic(value).unwrap()
+    }
+
+    #[test]
+    fn cli_listen_endpoint() {
+        let pipe = parse(r#"return { pipe = "\\\\.\\pipe\\wezterm-ctl" }"#);
+        assert_eq!(pipe.pipe.as_deref(), Some(r"\\.\pipe\wezterm-ctl"));
+        assert_eq!(
+            pipe.endpoint_for(true).unwrap(),
+            PathBuf::from(r"\\.\pipe\wezterm-ctl")
+        );
+        assert!(pipe.endpoint_for(false).is_err());
+
+        let socket = parse(r#"return { socket_path = "/run/user/1000/wezterm-ctl" }"#);
+        assert_eq!(
+            socket.endpoint_for(false).unwrap(),
+            PathBuf::from("/run/user/1000/wezterm-ctl")
+        );
+        assert!(socket.endpoint_for(true).is_err());
+
+        let not_a_pipe = parse(r#"return { pipe = "C:\\wezterm-ctl" }"#);
+        assert!(not_a_pipe.endpoint_for(true).is_err());
+        assert!(CliListen::default().endpoint_for(true).is_err());
+        assert!(CliListen::default().endpoint_for(false).is_err());
+    }
+
+    #[test]
+    fn pipe_names() {
+        assert!(is_pipe_name(Path::new(r"\\.\pipe\wezterm-ctl")));
+        assert!(is_pipe_name(Path::new(r"\\.\PIPE\wezterm-ctl")));
+        assert!(!is_pipe_name(Path::new(r"C:\Users\wez\control.sock")));
+        assert!(!is_pipe_name(Path::new("/tmp/control.sock")));
+    }
+}
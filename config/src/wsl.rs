@@ -1,15 +1,37 @@
 use crate::*;
 use luahelper::impl_lua_conversion_dynamic;
 use std::collections::HashMap;
-use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_dynamic::{FromDynamic, ToDynamic, Value};
 
 #[derive(Default, Debug, Clone, FromDynamic, ToDynamic)]
 pub struct WslDomain {
     pub name: String,
-    pub distribution: Option<String>,
-    pub username: Option<String>,
+    /// Either the name of the distribution, or a lua function that
+    /// returns it; see `LocalDomain::resolve_wsl_identity`
+    pub distribution: Option<ValueOrFunc>,
+    /// Either the name of the user, or a lua function that returns it
+    pub username: Option<ValueOrFunc>,
     pub default_cwd: Option<PathBuf>,
     pub default_prog: Option<Vec<String>>,
+    /// How the domain is presented in the launcher
+    #[dynamic(default)]
+    pub launcher: LauncherHints,
+
+    /// Colors that replace those of the window palette when the
+    /// panes in this domain are rendered
+    pub palette_overrides: Option<Palette>,
+
+    /// What happens to the panes in this domain when their program
+    /// exits, in place of the global `exit_behavior`
+    pub exit_behavior: Option<ExitBehavior>,
+    /// How many spawns into this domain may be in progress at once.
+    /// Further spawns wait in a queue until earlier ones complete.
+    /// The default is unlimited.
+    pub max_concurrent_spawns: Option<usize>,
+
+    /// Which pastes into the shells in this domain are held for
+    /// confirmation, in place of the global `paste_guard`
+    pub paste_guard: Option<PasteGuard>,
 }
 impl_lua_conversion_dynamic!(WslDomain);
 
@@ -23,10 +45,15 @@ impl WslDomain {
             for distro in distros {
                 domains.push(WslDomain {
                     name: format!("WSL:{}", distro.name),
-                    distribution: Some(distro.name.clone()),
+                    distribution: Some(ValueOrFunc::Value(Value::String(distro.name.clone()))),
                     username: None,
                     default_cwd: None,
                     default_prog: None,
+                    launcher: LauncherHints::default(),
+                    palette_overrides: None,
+                    exit_behavior: None,
+                    max_concurrent_spawns: None,
+                    paste_guard: None,
                 });
             }
         }
@@ -35,13 +62,14 @@ impl WslDomain {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
 pub struct WslDistro {
     pub name: String,
     pub state: String,
     pub version: String,
     pub is_default: bool,
 }
+impl_lua_conversion_dynamic!(WslDistro);
 
 impl WslDistro {
     pub fn load_distro_list() -> anyhow::Result<Vec<Self>> {
@@ -0,0 +1,122 @@
+//! Emits the `pane-input` event for the input that the user sends to
+//! panes, via an input filter that is registered with the mux only
+//! while the lua config has a handler for the event.
+use crate::MuxPane;
+use config::lua::mlua::Value as LuaValue;
+use config::ConfigSubscription;
+use mux::inputfilter::{InputFilter, InputRejection, InputSource, InputVerdict};
+use mux::pane::Pane;
+use mux::Mux;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PANE_INPUT_EVENT: &str = "pane-input";
+
+thread_local! {
+    static SUBSCRIPTION: RefCell<Option<ConfigSubscription>> = RefCell::new(None);
+}
+
+struct LuaInputFilter;
+
+impl InputFilter for LuaInputFilter {
+    fn name(&self) -> &str {
+        PANE_INPUT_EVENT
+    }
+
+    fn filter_input(&self, pane: &Rc<dyn Pane>, data: &[u8], source: InputSource) -> InputVerdict {
+        let result = config::run_immediate_with_lua_config(|lua| {
+            let lua = match lua {
+                Some(lua) => lua,
+                None => return Ok(InputVerdict::Pass),
+            };
+            let v = config::lua::emit_sync_callback(
+                &*lua,
+                (
+                    PANE_INPUT_EVENT.to_string(),
+                    (
+                        MuxPane(pane.pane_id()),
+                        lua.create_string(data)?,
+                        source.as_str(),
+                    ),
+                ),
+            )?;
+            verdict_from_lua(v)
+        });
+        match result {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                // Don't leave the user unable to type because of a
+                // broken handler
+                log::error!("while processing {} event: {:#}", PANE_INPUT_EVENT, err);
+                InputVerdict::Pass
+            }
+        }
+    }
+}
+
+/// Interprets the value returned by a `pane-input` handler:
+/// nil or true passes the input, a string replaces it, and false or a
+/// table with a `reject` field rejects it
+fn verdict_from_lua(value: LuaValue) -> anyhow::Result<InputVerdict> {
+    let rejection = |reason: Option<String>, confirm: bool| {
+        InputVerdict::Reject(InputRejection {
+            filter: PANE_INPUT_EVENT.to_string(),
+            reason: reason.unwrap_or_else(|| "rejected by a pane-input handler".to_string()),
+            confirm,
+        })
+    };
+    match value {
+        LuaValue::Nil | LuaValue::Boolean(true) => Ok(InputVerdict::Pass),
+        LuaValue::Boolean(false) => Ok(rejection(None, false)),
+        LuaValue::String(s) => Ok(InputVerdict::Replace(s.as_bytes().to_vec())),
+        LuaValue::Table(tbl) => {
+            let reason: Option<String> = tbl.get("reject")?;
+            let confirm: Option<bool> = tbl.get("confirm")?;
+            match reason {
+                Some(reason) => Ok(rejection(Some(reason), confirm.unwrap_or(false))),
+                None => anyhow::bail!("a table returned by a handler must have a reject field"),
+            }
+        }
+        other => anyhow::bail!(
+            "handler returned {}; expected nil, a boolean, a string or a table",
+            other.type_name()
+        ),
+    }
+}
+
+/// Registers or removes the filter according to whether the current
+/// lua config has a handler for the event
+fn update_filter() {
+    let mux = match Mux::get() {
+        Some(mux) => mux,
+        None => return,
+    };
+    let have_handler = config::run_immediate_with_lua_config(|lua| match lua {
+        Some(lua) => Ok(config::lua::has_event_handler(&lua, PANE_INPUT_EVENT)?),
+        None => Ok(false),
+    })
+    .unwrap_or_else(|err| {
+        log::error!("checking for {} handlers: {:#}", PANE_INPUT_EVENT, err);
+        false
+    });
+    if have_handler {
+        mux.add_input_filter(Rc::new(LuaInputFilter));
+    } else {
+        mux.remove_input_filter(PANE_INPUT_EVENT);
+    }
+}
+
+/// Starts emitting the `pane-input` event, keeping the filter in step
+/// with the lua config as it is reloaded.
+/// Must be called on the main thread once the mux has been set up.
+pub fn enable_pane_input_event() {
+    update_filter();
+    let subscription = config::subscribe_to_config_reload(|| {
+        promise::spawn::spawn_into_main_thread(async {
+            update_filter();
+        })
+        .detach();
+        true
+    });
+    SUBSCRIPTION.with(|sub| sub.borrow_mut().replace(subscription));
+}
@@ -1,24 +1,114 @@
-use config::keyassignment::SpawnTabDomain;
-use config::lua::mlua::{self, Lua, UserData, UserDataMethods, Value as LuaValue};
+use config::keyassignment::{CommandDir, PromptArg, ReusePredicate, SpawnCommand, SpawnTabDomain};
+use config::lua::mlua::{self, Lua, UserData, UserDataFields, UserDataMethods, Value as LuaValue};
 use config::lua::{get_or_create_module, get_or_create_sub_module};
+use config::{ExitBehavior, Palette};
 use luahelper::impl_lua_conversion_dynamic;
-use mux::domain::SplitSource;
+use mux::bookmark::scroll_to_bookmark;
+use mux::domain::{Domain, DomainError, DomainId, DomainState, PaletteEntry, SplitSource};
+use mux::domainexec::ExecRequest;
+use mux::history::{HistoryEntry, HistoryEvent, RecentDir};
+use mux::inputfilter::InputSource;
+use mux::marker::Marker;
+use mux::outputwatch::{OutputWatcherSpec, WatcherId};
 use mux::pane::{Pane, PaneId};
+use mux::panelink::LinkPolicy;
+use mux::scrollback::{save_scrollback, ScrollbackFormat};
+use mux::streampane::{self, StreamPaneSpec};
 use mux::tab::{SplitDirection, SplitRequest, SplitSize, Tab, TabId};
 use mux::window::{Window, WindowId};
 use mux::Mux;
 use portable_pty::CommandBuilder;
 use std::cell::{Ref, RefMut};
 use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 use wezterm_dynamic::{FromDynamic, ToDynamic};
-use wezterm_term::TerminalSize;
+use wezterm_term::{StableRowIndex, TerminalSize};
+
+mod inputfilter;
+mod outputwatch;
+
+pub use inputfilter::enable_pane_input_event;
+pub use outputwatch::enable_output_watcher_events;
 
 fn get_mux() -> mlua::Result<Rc<Mux>> {
     Mux::get()
         .ok_or_else(|| mlua::Error::external("cannot get Mux: not running on the mux thread?"))
 }
 
+const LUA_REGISTRY_DOMAIN_ERROR: &str = "wezterm-mux-domain-error";
+
+/// Calls the function that it wraps, and raises the stashed table
+/// that describes a domain error in place of the error that it
+/// returned, if there is one.
+/// Errors that are returned from rust are raised as opaque userdata,
+/// so the table has to be raised from lua.
+const RAISE_DOMAIN_ERRORS: &str = r#"
+local func, take_domain_error = ...
+return function(...)
+  local result = table.pack(pcall(func, ...))
+  local domain_error = take_domain_error()
+  if result[1] then
+    return table.unpack(result, 2, result.n)
+  end
+  error(domain_error or result[2], 0)
+end
+"#;
+
+/// Converts an error from a mux operation into a lua error.
+/// If it was caused by a `DomainError`, a table with `code`, `kind`
+/// and `message` fields is stashed so that the function, which must
+/// have been wrapped by `raise_domain_errors`, raises that instead.
+fn mux_error(lua: &Lua, err: anyhow::Error) -> mlua::Error {
+    if let Some(domain_err) = DomainError::find(&err) {
+        let stashed = lua.create_table().and_then(|tbl| {
+            tbl.set("code", domain_err.code())?;
+            tbl.set("kind", domain_err.kind())?;
+            tbl.set("message", format!("{:#}", err))?;
+            lua.set_named_registry_value(LUA_REGISTRY_DOMAIN_ERROR, tbl)
+        });
+        if let Err(stash_err) = stashed {
+            log::error!("failed to stash domain error: {:#}", stash_err);
+        }
+    }
+    mlua::Error::external(format!("{:#?}", err))
+}
+
+fn raise_domain_errors<'lua>(
+    lua: &'lua Lua,
+    func: mlua::Function<'lua>,
+) -> mlua::Result<mlua::Function<'lua>> {
+    let take_domain_error = lua.create_function(|lua, _: ()| {
+        let err: LuaValue = lua.named_registry_value(LUA_REGISTRY_DOMAIN_ERROR)?;
+        lua.set_named_registry_value(LUA_REGISTRY_DOMAIN_ERROR, LuaValue::Nil)?;
+        Ok(err)
+    })?;
+    lua.load(RAISE_DOMAIN_ERRORS)
+        .set_name("=raise_domain_errors")?
+        .call((func, take_domain_error))
+}
+
+/// Returns the method `name`, which is exposed as a field so that
+/// it can be wrapped by `raise_domain_errors`.
+/// The wrapped function is made by `make` on first use and then
+/// kept in the registry.
+fn domain_method<'lua>(
+    lua: &'lua Lua,
+    name: &str,
+    make: impl FnOnce(&'lua Lua) -> mlua::Result<mlua::Function<'lua>>,
+) -> mlua::Result<mlua::Function<'lua>> {
+    let key = format!("wezterm-mux-method-{}", name);
+    if let LuaValue::Function(func) = lua.named_registry_value(&key)? {
+        return Ok(func);
+    }
+    let func = raise_domain_errors(lua, make(lua)?)?;
+    lua.set_named_registry_value(&key, func.clone())?;
+    Ok(func)
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let mux_mod = get_or_create_sub_module(lua, "mux")?;
 
@@ -84,9 +174,86 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    mux_mod.set(
+        "get_domain",
+        lua.create_function(|_, domain: LuaValue| {
+            let mux = get_mux()?;
+            let domain = match domain {
+                LuaValue::Nil => mux.default_domain(),
+                LuaValue::String(name) => {
+                    let name = name.to_str()?;
+                    mux.get_domain_by_name(name).ok_or_else(|| {
+                        mlua::Error::external(format!("domain {} not found in mux", name))
+                    })?
+                }
+                LuaValue::Integer(id) => MuxDomain(id as DomainId).resolve(&mux)?,
+                _ => {
+                    return Err(mlua::Error::external(
+                        "get_domain expects a domain name or id",
+                    ))
+                }
+            };
+            Ok(MuxDomain(domain.domain_id()))
+        })?,
+    )?;
+
+    mux_mod.set(
+        "get_default_domain",
+        lua.create_function(|_, _: ()| {
+            let mux = get_mux()?;
+            Ok(MuxDomain(mux.default_domain().domain_id()))
+        })?,
+    )?;
+
+    mux_mod.set(
+        "all_domains",
+        lua.create_function(|_, _: ()| {
+            let mux = get_mux()?;
+            Ok(mux
+                .ordered_domains()
+                .into_iter()
+                .map(|domain| MuxDomain(domain.domain_id()))
+                .collect::<Vec<MuxDomain>>())
+        })?,
+    )?;
+
     mux_mod.set(
         "spawn_window",
-        lua.create_async_function(|_, spawn: SpawnWindow| async move { spawn.spawn().await })?,
+        raise_domain_errors(
+            lua,
+            lua.create_async_function(
+                |lua, spawn: SpawnWindow| async move { spawn.spawn(lua).await },
+            )?,
+        )?,
+    )?;
+
+    mux_mod.set(
+        "spawn_stream_pane",
+        lua.create_function(|_, spawn: SpawnStreamPane| spawn.spawn())?,
+    )?;
+
+    mux_mod.set(
+        "detached_tabs",
+        lua.create_function(|_, _: ()| {
+            let mux = get_mux()?;
+            Ok(mux
+                .iter_detached_tabs()
+                .into_iter()
+                .map(|tab| MuxTab(tab.tab_id()))
+                .collect::<Vec<MuxTab>>())
+        })?,
+    )?;
+
+    mux_mod.set(
+        "recent_dirs",
+        lua.create_function(|_, limit: Option<usize>| {
+            let mux = get_mux()?;
+            Ok(mux
+                .recent_dirs(limit)
+                .into_iter()
+                .map(MuxRecentDir::from)
+                .collect::<Vec<MuxRecentDir>>())
+        })?,
     )?;
 
     mux_mod.set(
@@ -107,25 +274,71 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 #[derive(Debug, Default, FromDynamic, ToDynamic)]
 struct CommandBuilderFrag {
     args: Option<Vec<String>>,
-    cwd: Option<String>,
+    cwd: Option<CommandDir>,
     #[dynamic(default)]
     set_environment_variables: HashMap<String, String>,
+    label: Option<String>,
+    exit_behavior: Option<ExitBehavior>,
+    /// Allows passing a `SpawnCommand` that has parameters, such as
+    /// an entry from the launch menu
+    #[dynamic(default)]
+    prompt_args: Vec<PromptArg>,
+    /// The values of the `prompt_args`
+    #[dynamic(default)]
+    params: HashMap<String, String>,
 }
 
 impl CommandBuilderFrag {
-    fn to_command_builder(self) -> (Option<CommandBuilder>, Option<String>) {
-        if let Some(args) = self.args {
-            let mut builder = CommandBuilder::from_argv(args.iter().map(Into::into).collect());
-            for (k, v) in self.set_environment_variables.iter() {
-                builder.env(k, v);
+    /// Substitutes the values of `params` into the command
+    fn apply_params(self) -> anyhow::Result<Self> {
+        if self.prompt_args.is_empty() && self.params.is_empty() {
+            return Ok(self);
+        }
+        let spawn = SpawnCommand {
+            args: self.args,
+            cwd: self.cwd,
+            set_environment_variables: self.set_environment_variables,
+            prompt_args: self.prompt_args,
+            ..Default::default()
+        }
+        .with_params(&self.params)?;
+        Ok(Self {
+            args: spawn.args,
+            cwd: spawn.cwd,
+            set_environment_variables: spawn.set_environment_variables,
+            label: self.label,
+            exit_behavior: self.exit_behavior,
+            prompt_args: vec![],
+            params: HashMap::new(),
+        })
+    }
+
+    /// The cwd is returned separately, rather than being set on the
+    /// builder, so that the domain can tell which host it belongs to
+    fn to_command_builder(self) -> anyhow::Result<(Option<CommandBuilder>, Option<CommandDir>)> {
+        let frag = self.apply_params()?;
+        let mut builder = match frag.args {
+            Some(args) => {
+                let mut builder = CommandBuilder::from_argv(args.iter().map(Into::into).collect());
+                for (k, v) in frag.set_environment_variables.iter() {
+                    builder.env(k, v);
+                }
+                Some(builder)
             }
-            if let Some(cwd) = self.cwd.clone() {
-                builder.cwd(cwd);
+            // The domain runs its default program in place of a
+            // builder that has nothing but a label or exit behavior
+            None if frag.label.is_some() || frag.exit_behavior.is_some() => {
+                Some(CommandBuilder::new_default_prog())
             }
-            (Some(builder), None)
-        } else {
-            (None, self.cwd)
+            None => None,
+        };
+        if let (Some(builder), Some(label)) = (&mut builder, frag.label) {
+            builder.label(label);
+        }
+        if let (Some(builder), Some(exit_behavior)) = (&mut builder, frag.exit_behavior) {
+            builder.exit_behavior(exit_behavior.as_str());
         }
+        Ok((builder, frag.cwd))
     }
 }
 
@@ -163,9 +376,39 @@ fn default_split_size() -> f32 {
     0.5
 }
 
+#[derive(Debug, Default, FromDynamic, ToDynamic)]
+struct SerialParams {
+    #[dynamic(default)]
+    baud: Option<usize>,
+    #[dynamic(default)]
+    char_size: Option<u8>,
+    #[dynamic(default)]
+    parity: Option<String>,
+    #[dynamic(default)]
+    stop_bits: Option<u8>,
+    #[dynamic(default)]
+    flow_control: Option<String>,
+}
+impl_lua_conversion_dynamic!(SerialParams);
+
+impl SerialParams {
+    fn into_serial_params(self) -> portable_pty::serial::SerialParams {
+        portable_pty::serial::SerialParams {
+            baud: self.baud,
+            char_size: self.char_size,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            flow_control: self.flow_control,
+        }
+    }
+}
+
 impl SplitPane {
-    async fn run(self, pane: MuxPane) -> mlua::Result<MuxPane> {
-        let (command, command_dir) = self.cmd_builder.to_command_builder();
+    async fn run(self, lua: &Lua, pane: MuxPane) -> mlua::Result<MuxPane> {
+        let (command, command_dir) = self
+            .cmd_builder
+            .to_command_builder()
+            .map_err(|e| mux_error(lua, e))?;
         let source = SplitSource::Spawn {
             command,
             command_dir,
@@ -198,7 +441,7 @@ impl SplitPane {
         let (pane, _size) = mux
             .split_pane(pane.0, request, source, self.domain)
             .await
-            .map_err(|e| mlua::Error::external(format!("{:#?}", e)))?;
+            .map_err(|e| mux_error(lua, e))?;
 
         Ok(MuxPane(pane.pane_id()))
     }
@@ -211,6 +454,7 @@ struct SpawnWindow {
     width: Option<usize>,
     height: Option<usize>,
     workspace: Option<String>,
+    reuse: Option<ReusePredicate>,
     #[dynamic(flatten)]
     cmd_builder: CommandBuilderFrag,
 }
@@ -221,7 +465,7 @@ fn spawn_tab_default_domain() -> SpawnTabDomain {
 }
 
 impl SpawnWindow {
-    async fn spawn(self) -> mlua::Result<(MuxTab, MuxPane, MuxWindow)> {
+    async fn spawn(self, lua: &Lua) -> mlua::Result<(MuxTab, MuxPane, MuxWindow)> {
         let mux = get_mux()?;
 
         let size = match (self.width, self.height) {
@@ -233,19 +477,28 @@ impl SpawnWindow {
             _ => config::configuration().initial_size(0),
         };
 
-        let (cmd_builder, cwd) = self.cmd_builder.to_command_builder();
+        let workspace = self.workspace.unwrap_or_else(|| mux.active_workspace());
+        if let Some(reuse) = &self.reuse {
+            if let Some((tab, pane, window_id)) = mux
+                .reuse_pane(None, &workspace, reuse)
+                .map_err(|e| mux_error(lua, e))?
+            {
+                return Ok((
+                    MuxTab(tab.tab_id()),
+                    MuxPane(pane.pane_id()),
+                    MuxWindow(window_id),
+                ));
+            }
+        }
+
+        let (cmd_builder, cwd) = self
+            .cmd_builder
+            .to_command_builder()
+            .map_err(|e| mux_error(lua, e))?;
         let (tab, pane, window_id) = mux
-            .spawn_tab_or_window(
-                None,
-                self.domain,
-                cmd_builder,
-                cwd,
-                size,
-                None,
-                self.workspace.unwrap_or_else(|| mux.active_workspace()),
-            )
+            .spawn_tab_or_window(None, self.domain, cmd_builder, cwd, size, None, workspace)
             .await
-            .map_err(|e| mlua::Error::external(format!("{:#?}", e)))?;
+            .map_err(|e| mux_error(lua, e))?;
 
         Ok((
             MuxTab(tab.tab_id()),
@@ -255,12 +508,70 @@ impl SpawnWindow {
     }
 }
 
+#[derive(Debug, FromDynamic, ToDynamic)]
+struct SpawnStreamPane {
+    #[dynamic(default = "spawn_tab_default_domain")]
+    domain: SpawnTabDomain,
+    #[dynamic(default = "default_stream_pane_title")]
+    title: String,
+    reader_fd: i64,
+    writer_fd: i64,
+    window: Option<WindowId>,
+    width: Option<usize>,
+    height: Option<usize>,
+    workspace: Option<String>,
+}
+impl_lua_conversion_dynamic!(SpawnStreamPane);
+
+fn default_stream_pane_title() -> String {
+    "stream".to_string()
+}
+
+impl SpawnStreamPane {
+    fn spawn(self) -> mlua::Result<(MuxTab, MuxPane, MuxWindow)> {
+        let mux = get_mux()?;
+        let domain = mux
+            .resolve_spawn_tab_domain(None, &self.domain)
+            .map_err(|e| mlua::Error::external(format!("{:#}", e)))?;
+        let (reader, writer) = streampane::streams_from_fds(self.reader_fd, self.writer_fd)
+            .map_err(|e| mlua::Error::external(format!("{:#}", e)))?;
+        let size = match (self.width, self.height) {
+            (Some(cols), Some(rows)) => Some(TerminalSize {
+                rows,
+                cols,
+                ..Default::default()
+            }),
+            _ => None,
+        };
+        let workspace = self.workspace.unwrap_or_else(|| mux.active_workspace());
+        let (tab, pane, window_id) = streampane::spawn_stream_pane(
+            StreamPaneSpec {
+                domain_id: domain.domain_id(),
+                title: self.title,
+                size,
+                reader,
+                writer,
+            },
+            self.window,
+            workspace,
+        )
+        .map_err(|e| mlua::Error::external(format!("{:#}", e)))?;
+        Ok((
+            MuxTab(tab.tab_id()),
+            MuxPane(pane.pane_id()),
+            MuxWindow(window_id),
+        ))
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct MuxWindow(pub WindowId);
 #[derive(Clone, Copy, Debug)]
 pub struct MuxTab(pub TabId);
 #[derive(Clone, Copy, Debug)]
 pub struct MuxPane(pub PaneId);
+#[derive(Clone, Copy, Debug)]
+pub struct MuxDomain(pub DomainId);
 
 impl MuxWindow {
     fn resolve<'a>(&self, mux: &'a Rc<Mux>) -> mlua::Result<Ref<'a, Window>> {
@@ -278,14 +589,32 @@ impl MuxWindow {
 struct SpawnTab {
     #[dynamic(default)]
     domain: SpawnTabDomain,
+    reuse: Option<ReusePredicate>,
     #[dynamic(flatten)]
     cmd_builder: CommandBuilderFrag,
 }
 impl_lua_conversion_dynamic!(SpawnTab);
 
 impl SpawnTab {
-    async fn spawn(self, window: MuxWindow) -> mlua::Result<(MuxTab, MuxPane, MuxWindow)> {
+    async fn spawn(
+        self,
+        lua: &Lua,
+        window: MuxWindow,
+    ) -> mlua::Result<(MuxTab, MuxPane, MuxWindow)> {
         let mux = get_mux()?;
+        if let Some(reuse) = &self.reuse {
+            if let Some((tab, pane, window_id)) = mux
+                .reuse_pane(Some(window.0), "", reuse)
+                .map_err(|e| mux_error(lua, e))?
+            {
+                return Ok((
+                    MuxTab(tab.tab_id()),
+                    MuxPane(pane.pane_id()),
+                    MuxWindow(window_id),
+                ));
+            }
+        }
+
         let size;
         let pane;
 
@@ -301,7 +630,10 @@ impl SpawnTab {
                 .and_then(|tab| tab.get_active_pane().map(|pane| pane.pane_id()));
         };
 
-        let (cmd_builder, cwd) = self.cmd_builder.to_command_builder();
+        let (cmd_builder, cwd) = self
+            .cmd_builder
+            .to_command_builder()
+            .map_err(|e| mux_error(lua, e))?;
 
         let (tab, pane, window_id) = mux
             .spawn_tab_or_window(
@@ -314,7 +646,7 @@ impl SpawnTab {
                 String::new(),
             )
             .await
-            .map_err(|e| mlua::Error::external(format!("{:#?}", e)))?;
+            .map_err(|e| mux_error(lua, e))?;
 
         Ok((
             MuxTab(tab.tab_id()),
@@ -324,6 +656,116 @@ impl SpawnTab {
     }
 }
 
+#[derive(Debug, FromDynamic, ToDynamic)]
+struct DomainSpawnTab {
+    #[dynamic(default = "default_attach")]
+    attach: bool,
+    window: Option<WindowId>,
+    width: Option<usize>,
+    height: Option<usize>,
+    #[dynamic(flatten)]
+    cmd_builder: CommandBuilderFrag,
+}
+impl_lua_conversion_dynamic!(DomainSpawnTab);
+
+fn default_attach() -> bool {
+    true
+}
+
+#[derive(Debug, FromDynamic, ToDynamic)]
+struct DomainExec {
+    args: Vec<String>,
+    cwd: Option<String>,
+    /// In seconds
+    timeout: Option<f64>,
+}
+impl_lua_conversion_dynamic!(DomainExec);
+
+impl DomainExec {
+    fn into_request(self) -> mlua::Result<ExecRequest> {
+        let timeout = match self.timeout {
+            Some(secs) if secs.is_finite() && secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+            Some(secs) => {
+                return Err(mlua::Error::external(format!(
+                    "timeout must be a positive number of seconds, not {}",
+                    secs
+                )))
+            }
+            None => None,
+        };
+        Ok(ExecRequest::new(self.args, self.cwd, timeout))
+    }
+}
+
+impl Default for DomainSpawnTab {
+    fn default() -> Self {
+        Self {
+            attach: default_attach(),
+            window: None,
+            width: None,
+            height: None,
+            cmd_builder: CommandBuilderFrag::default(),
+        }
+    }
+}
+
+impl DomainSpawnTab {
+    async fn spawn(
+        self,
+        lua: &Lua,
+        domain: MuxDomain,
+    ) -> mlua::Result<(MuxTab, MuxPane, Option<MuxWindow>)> {
+        let mux = get_mux()?;
+        let size = match (self.width, self.height) {
+            (Some(cols), Some(rows)) => TerminalSize {
+                rows,
+                cols,
+                ..Default::default()
+            },
+            _ => config::configuration().initial_size(0),
+        };
+        let (cmd_builder, cwd) = self
+            .cmd_builder
+            .to_command_builder()
+            .map_err(|e| mux_error(lua, e))?;
+
+        if self.attach {
+            let (tab, pane, window_id) = mux
+                .spawn_tab_or_window(
+                    self.window,
+                    SpawnTabDomain::DomainId(domain.0),
+                    cmd_builder,
+                    cwd,
+                    size,
+                    None,
+                    mux.active_workspace(),
+                )
+                .await
+                .map_err(|e| mux_error(lua, e))?;
+            return Ok((
+                MuxTab(tab.tab_id()),
+                MuxPane(pane.pane_id()),
+                Some(MuxWindow(window_id)),
+            ));
+        }
+
+        let domain = domain.resolve(&mux)?;
+        if domain.state() == DomainState::Detached {
+            mux.attach_domain(&domain, None)
+                .await
+                .map_err(|e| mux_error(lua, e.into()))?;
+        }
+        let tab = domain
+            .spawn_detached_tab(size, cmd_builder, cwd)
+            .await
+            .map_err(|e| mux_error(lua, e.into()))?;
+        let pane = tab
+            .get_active_pane()
+            .ok_or_else(|| mlua::Error::external("missing active pane on tab!?"))?;
+        Ok((MuxTab(tab.tab_id()), MuxPane(pane.pane_id()), None))
+    }
+}
+
 #[derive(Clone, FromDynamic, ToDynamic)]
 struct MuxTabInfo {
     pub index: usize,
@@ -331,7 +773,61 @@ struct MuxTabInfo {
 }
 impl_lua_conversion_dynamic!(MuxTabInfo);
 
+/// An entry of `wezterm.mux.recent_dirs`
+#[derive(Clone, FromDynamic, ToDynamic)]
+struct MuxRecentDir {
+    pub cwd: String,
+    pub domain: String,
+    pub last_visited: String,
+    pub visits: usize,
+}
+impl_lua_conversion_dynamic!(MuxRecentDir);
+
+impl From<RecentDir> for MuxRecentDir {
+    fn from(dir: RecentDir) -> Self {
+        Self {
+            cwd: dir.cwd,
+            domain: dir.domain,
+            last_visited: dir.last_visited.to_rfc3339(),
+            visits: dir.visits,
+        }
+    }
+}
+
+/// An entry of `pane:get_history`, which has either a `cwd` or a `command`
+#[derive(Clone, FromDynamic, ToDynamic)]
+struct MuxHistoryEntry {
+    pub time: String,
+    pub cwd: Option<String>,
+    pub command: Option<String>,
+}
+impl_lua_conversion_dynamic!(MuxHistoryEntry);
+
+impl From<HistoryEntry> for MuxHistoryEntry {
+    fn from(entry: HistoryEntry) -> Self {
+        let (cwd, command) = match entry.event {
+            HistoryEvent::WorkingDir(cwd) => (Some(cwd), None),
+            HistoryEvent::Command(command) => (None, Some(command)),
+        };
+        Self {
+            time: entry.time.to_rfc3339(),
+            cwd,
+            command,
+        }
+    }
+}
+
 impl UserData for MuxWindow {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_function_get("spawn_tab", |lua, _| {
+            domain_method(lua, "window.spawn_tab", |lua| {
+                lua.create_async_function(|lua, (this, spawn): (MuxWindow, SpawnTab)| async move {
+                    spawn.spawn(lua, this).await
+                })
+            })
+        });
+    }
+
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method("window_id", |_, this, _: ()| Ok(this.0));
         methods.add_async_method("gui_window", |lua, this, _: ()| async move {
@@ -353,9 +849,15 @@ impl UserData for MuxWindow {
             let mut window = this.resolve_mut(&mux)?;
             Ok(window.set_workspace(&new_name))
         });
-        methods.add_async_method("spawn_tab", |_, this, spawn: SpawnTab| async move {
-            spawn.spawn(this).await
-        });
+        methods.add_method(
+            "attach_tab",
+            |_, this, (tab, index): (MuxTab, Option<usize>)| {
+                let mux = get_mux()?;
+                let tab = tab.resolve(&mux)?;
+                mux.attach_tab_to_window(&tab, this.0, index)
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
         methods.add_method("get_title", |_, this, _: ()| {
             let mux = get_mux()?;
             let window = this.resolve(&mux)?;
@@ -405,28 +907,277 @@ impl MuxPane {
     }
 }
 
+/// The options of `pane:change_dir`
+#[derive(Debug, FromDynamic, ToDynamic)]
+pub struct ChangeDirOptions {
+    /// Whether to press enter after typing the `cd` command
+    #[dynamic(default = "default_change_dir_enter")]
+    pub enter: bool,
+}
+impl_lua_conversion_dynamic!(ChangeDirOptions);
+
+fn default_change_dir_enter() -> bool {
+    true
+}
+
+/// The rows for `pane:save_scrollback`, as stable row indices.
+/// Both are inclusive; the first and last rows of the pane are used
+/// when they are omitted.
+#[derive(Debug, Default, FromDynamic, ToDynamic)]
+pub struct ScrollbackRowRange {
+    #[dynamic(default)]
+    pub first: Option<StableRowIndex>,
+    #[dynamic(default)]
+    pub last: Option<StableRowIndex>,
+}
+
+/// The options of `pane:save_scrollback`
+#[derive(Debug, Default, FromDynamic, ToDynamic)]
+pub struct SaveScrollbackOptions {
+    #[dynamic(default)]
+    pub format: ScrollbackFormat,
+    #[dynamic(default)]
+    pub range: Option<ScrollbackRowRange>,
+}
+impl_lua_conversion_dynamic!(SaveScrollbackOptions);
+
+impl SaveScrollbackOptions {
+    pub fn rows(&self) -> Option<Range<StableRowIndex>> {
+        let range = self.range.as_ref()?;
+        Some(
+            range.first.unwrap_or(StableRowIndex::MIN)
+                ..range
+                    .last
+                    .map(|last| last.saturating_add(1))
+                    .unwrap_or(StableRowIndex::MAX),
+        )
+    }
+}
+
+impl Default for ChangeDirOptions {
+    fn default() -> Self {
+        Self {
+            enter: default_change_dir_enter(),
+        }
+    }
+}
+
 impl UserData for MuxPane {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_function_get("split", |lua, _| {
+            domain_method(lua, "pane.split", |lua| {
+                lua.create_async_function(
+                    |lua, (this, args): (MuxPane, Option<SplitPane>)| async move {
+                        let args = args.unwrap_or_default();
+                        args.run(lua, this).await
+                    },
+                )
+            })
+        });
+    }
+
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method("pane_id", |_, this, _: ()| Ok(this.0));
-        methods.add_async_method("split", |_, this, args: Option<SplitPane>| async move {
-            let args = args.unwrap_or_default();
-            args.run(this).await
-        });
         methods.add_method("send_paste", |_, this, text: String| {
             let mux = get_mux()?;
             let pane = this.resolve(&mux)?;
-            pane.send_paste(&text)
+            mux.send_input_to_pane(&pane, text.as_bytes(), InputSource::Paste)
                 .map_err(|e| mlua::Error::external(format!("{:#}", e)))?;
             Ok(())
         });
         methods.add_method("send_text", |_, this, text: String| {
             let mux = get_mux()?;
             let pane = this.resolve(&mux)?;
-            pane.writer()
-                .write_all(text.as_bytes())
+            mux.send_input_to_pane(&pane, text.as_bytes(), InputSource::Keyboard)
                 .map_err(|e| mlua::Error::external(format!("{:#}", e)))?;
             Ok(())
         });
+        methods.add_async_method(
+            "change_dir",
+            |_, this, (dir, options): (String, Option<ChangeDirOptions>)| async move {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                let options = options.unwrap_or_default();
+                pane.change_dir(dir, options.enter)
+                    .await
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_async_method(
+            "save_scrollback",
+            |_, this, (path, options): (String, Option<SaveScrollbackOptions>)| async move {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                let options = options.unwrap_or_default();
+                save_scrollback(&pane, Path::new(&path), options.format, options.rows())
+                    .await
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_async_method(
+            "add_bookmark",
+            |_, this, (name, row): (String, Option<StableRowIndex>)| async move {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                pane.add_bookmark(name, row)
+                    .await
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_method("get_history", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            Ok(mux
+                .pane_history(pane.pane_id())
+                .into_iter()
+                .map(MuxHistoryEntry::from)
+                .collect::<Vec<MuxHistoryEntry>>())
+        });
+        methods.add_async_method("get_bookmarks", |_, this, _: ()| async move {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            pane.get_bookmarks()
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_async_method("scroll_to_bookmark", |_, this, name: String| async move {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            scroll_to_bookmark(&pane, &name)
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_async_method(
+            "add_output_watcher",
+            |_, this, spec: OutputWatcherSpec| async move {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                pane.add_output_watcher(spec)
+                    .await
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_async_method(
+            "remove_output_watcher",
+            |_, this, id: WatcherId| async move {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                pane.remove_output_watcher(id)
+                    .await
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_async_method("get_output_watchers", |_, this, _: ()| async move {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            pane.get_output_watchers()
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_async_method("add_marker", |_, this, marker: Marker| async move {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            pane.add_marker(marker)
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_async_method("remove_marker", |_, this, id: String| async move {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            pane.remove_marker(id)
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_method("get_markers", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            Ok(pane.get_markers())
+        });
+        methods.add_method("lock", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            mux.lock_pane(this.0);
+            Ok(())
+        });
+        methods.add_method("unlock", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            mux.unlock_pane(this.0);
+            Ok(())
+        });
+        methods.add_method("is_locked", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            Ok(mux.is_pane_locked(this.0))
+        });
+        methods.add_method("pause", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            mux.set_pane_paused(this.0, true)
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_method("resume", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            mux.set_pane_paused(this.0, false)
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_method("is_paused", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            Ok(mux.is_pane_paused(this.0))
+        });
+        methods.add_method("mark_dead", |_, this, _: ()| {
+            streampane::mark_dead(this.0).map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_method("get_resource_usage", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            Ok(pane.get_resource_usage())
+        });
+        methods.add_method("get_spawn_label", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            Ok(pane.get_spawn_label())
+        });
+        methods.add_method(
+            "set_palette_overrides",
+            |_, this, overrides: Option<Palette>| {
+                let mux = get_mux()?;
+                let pane = this.resolve(&mux)?;
+                pane.set_palette_overrides(overrides)
+                    .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_method("get_palette_overrides", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            Ok(pane.get_palette_overrides())
+        });
+        methods.add_method("get_modes", |lua, this, _: ()| {
+            let mux = get_mux()?;
+            let pane = this.resolve(&mux)?;
+            match pane.get_modes() {
+                Some(modes) => luahelper::dynamic_to_lua_value(lua, modes.to_dynamic()),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+        methods.add_method(
+            "link_with",
+            |_, this, (other, policy): (MuxPane, Option<LinkPolicy>)| {
+                let mux = get_mux()?;
+                mux.link_panes(
+                    vec![this.0, other.0],
+                    policy.unwrap_or(LinkPolicy::FocusTogether),
+                )
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+            },
+        );
+        methods.add_method("set_serial_params", |_, this, params: SerialParams| {
+            let mux = get_mux()?;
+            mux.set_serial_params(this.0, &params.into_serial_params())
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
         methods.add_method("window", |_, this, _: ()| {
             let mux = get_mux()?;
             Ok(mux
@@ -472,7 +1223,36 @@ struct MuxPaneInfo {
 }
 impl_lua_conversion_dynamic!(MuxPaneInfo);
 
+#[derive(Debug, Default, FromDynamic, ToDynamic)]
+struct CloneTab {
+    #[dynamic(default)]
+    same_commands: bool,
+}
+impl_lua_conversion_dynamic!(CloneTab);
+
 impl UserData for MuxTab {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_function_get("clone", |lua, _| {
+            domain_method(lua, "tab.clone", |lua| {
+                lua.create_async_function(
+                    |lua, (this, args): (MuxTab, Option<CloneTab>)| async move {
+                        let args = args.unwrap_or_default();
+                        let mux = get_mux()?;
+                        let (tab, pane, window_id) = mux
+                            .clone_tab(this.0, args.same_commands)
+                            .await
+                            .map_err(|e| mux_error(lua, e))?;
+                        Ok((
+                            MuxTab(tab.tab_id()),
+                            MuxPane(pane.pane_id()),
+                            MuxWindow(window_id),
+                        ))
+                    },
+                )
+            })
+        });
+    }
+
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method("tab_id", |_, this, _: ()| Ok(this.0));
         methods.add_method("window", |_, this, _: ()| {
@@ -493,6 +1273,17 @@ impl UserData for MuxTab {
             let tab = this.resolve(&mux)?;
             Ok(tab.get_title().to_string())
         });
+        methods.add_method("layout_generation", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let tab = this.resolve(&mux)?;
+            Ok(tab.layout_generation())
+        });
+        methods.add_method("kill", |_, this, _: ()| {
+            let mux = get_mux()?;
+            this.resolve(&mux)?;
+            mux.remove_tab(this.0);
+            Ok(())
+        });
         methods.add_method("set_title", |_, this, title: String| {
             let mux = get_mux()?;
             let tab = this.resolve(&mux)?;
@@ -538,3 +1329,88 @@ impl UserData for MuxTab {
         });
     }
 }
+
+impl MuxDomain {
+    fn resolve<'a>(&self, mux: &'a Rc<Mux>) -> mlua::Result<Arc<dyn Domain>> {
+        mux.get_domain(self.0)
+            .ok_or_else(|| mlua::Error::external(format!("domain id {} not found in mux", self.0)))
+    }
+}
+
+impl UserData for MuxDomain {
+    fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_function_get("spawn_tab", |lua, _| {
+            domain_method(lua, "domain.spawn_tab", |lua| {
+                lua.create_async_function(
+                    |lua, (this, spawn): (MuxDomain, Option<DomainSpawnTab>)| async move {
+                        let spawn = spawn.unwrap_or_default();
+                        spawn.spawn(lua, this).await
+                    },
+                )
+            })
+        });
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("domain_id", |_, this, _: ()| Ok(this.0));
+        methods.add_method("name", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            Ok(domain.domain_name().to_string())
+        });
+        methods.add_async_method("label", |_, this, _: ()| async move {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            Ok(domain.domain_label().await)
+        });
+        methods.add_method("state", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            Ok(format!("{:?}", domain.state()))
+        });
+        methods.add_method("sync_progress", |lua, this, _: ()| {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            match domain.sync_progress() {
+                Some((synced, total)) => {
+                    let progress = lua.create_table()?;
+                    progress.set("synced", synced)?;
+                    progress.set("total", total)?;
+                    Ok(Some(progress))
+                }
+                None => Ok(None),
+            }
+        });
+        methods.add_method("panes", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            Ok(mux
+                .iter_panes_for_domain(domain.domain_id())
+                .into_iter()
+                .map(|pane| MuxPane(pane.pane_id()))
+                .collect::<Vec<MuxPane>>())
+        });
+        methods.add_method("palette_entries", |_, this, _: ()| {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            Ok(domain.palette_entries())
+        });
+        methods.add_async_method("perform_action", |_, this, action: String| async move {
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            domain
+                .perform_palette_action(&action)
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+        methods.add_async_method("exec", |_, this, exec: DomainExec| async move {
+            let request = exec.into_request()?;
+            let mux = get_mux()?;
+            let domain = this.resolve(&mux)?;
+            domain
+                .exec(request)
+                .await
+                .map_err(|e| mlua::Error::external(format!("{:#}", e)))
+        });
+    }
+}
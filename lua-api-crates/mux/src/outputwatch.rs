@@ -0,0 +1,42 @@
+//! Emits the events of the output watchers that were added by
+//! `pane:add_output_watcher`, as their matches are reported by the mux.
+//! Matches of watchers that belong to a client of this mux are sent to
+//! that client by the server instead, and emitted there.
+use crate::MuxPane;
+use mux::outputwatch::OutputMatch;
+use mux::{Mux, MuxNotification};
+
+fn emit_match(matched: OutputMatch) {
+    promise::spawn::spawn(config::with_lua_config_on_main_thread(
+        move |lua| async move {
+            if let Some(lua) = lua {
+                let event = matched.event.clone();
+                let args = lua.pack_multi((MuxPane(matched.pane_id), matched))?;
+                if let Err(err) = config::lua::emit_event(&lua, (event.clone(), args)).await {
+                    log::error!("while processing {} event: {:#}", event, err);
+                }
+            }
+            Ok(())
+        },
+    ))
+    .detach();
+}
+
+/// Starts emitting the events of output watchers.
+/// Must be called on the main thread once the mux has been set up.
+pub fn enable_output_watcher_events() {
+    let mux = match Mux::get() {
+        Some(mux) => mux,
+        None => return,
+    };
+    mux.subscribe(|n| {
+        if let MuxNotification::OutputMatched {
+            owner: None,
+            matched,
+        } = n
+        {
+            emit_match(matched);
+        }
+        true
+    });
+}
@@ -0,0 +1,110 @@
+//! This example embeds the multiplexer in a standalone program.
+//! It builds a mux with a programmatic configuration and a single
+//! local domain, spawns a pane, splits it, prints the output of
+//! both panes, kills one of them and exits once the mux is empty.
+use config::keyassignment::SpawnTabDomain;
+use mux::builder::MuxBuilder;
+use mux::domain::{Domain, LocalDomain, SplitSource};
+use mux::tab::{SplitDirection, SplitRequest, SplitSize};
+use mux::{Mux, MuxNotification};
+use portable_pty::CommandBuilder;
+use promise::spawn::SimpleExecutor;
+use std::sync::Arc;
+use std::time::Duration;
+use wezterm_term::TerminalSize;
+
+fn main() -> anyhow::Result<()> {
+    // The executor must be set up on the same thread as the mux,
+    // and then driven by calling `tick` in a loop below.
+    let executor = SimpleExecutor::new();
+
+    // The configuration is built in code rather than loaded from
+    // the user's config file.
+    let mut config = config::Config::default_config();
+    config.scrollback_lines = 1000;
+    config.exit_behavior = config::ExitBehavior::Close;
+
+    let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new("build-shells")?);
+    let mux = MuxBuilder::new().config(config).domain(domain).build()?;
+
+    mux.subscribe(|n| {
+        match n {
+            MuxNotification::PaneAdded(pane_id) => println!("pane {} added", pane_id),
+            MuxNotification::PaneRemoved(pane_id) => println!("pane {} removed", pane_id),
+            MuxNotification::Empty => {
+                println!("mux is empty");
+                std::process::exit(0);
+            }
+            _ => {}
+        }
+        true
+    });
+
+    promise::spawn::spawn(async {
+        if let Err(err) = run().await {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+    })
+    .detach();
+
+    loop {
+        executor.tick()?;
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let mux = Mux::get().expect("running on the mux thread");
+
+    let (_tab, pane, _window_id) = mux
+        .spawn_tab_or_window(
+            None,
+            SpawnTabDomain::DefaultDomain,
+            Some(CommandBuilder::from_argv(vec![
+                "sh".into(),
+                "-c".into(),
+                "echo building; sleep 1; echo done".into(),
+            ])),
+            None,
+            TerminalSize::default(),
+            None,
+            mux.active_workspace(),
+        )
+        .await?;
+
+    let (split, _size) = mux
+        .split_pane(
+            pane.pane_id(),
+            SplitRequest {
+                direction: SplitDirection::Horizontal,
+                target_is_second: true,
+                top_level: false,
+                size: SplitSize::Percent(50),
+            },
+            SplitSource::Spawn {
+                command: Some(CommandBuilder::from_argv(vec![
+                    "sh".into(),
+                    "-c".into(),
+                    "echo watching; sleep 60".into(),
+                ])),
+                command_dir: None,
+            },
+            SpawnTabDomain::CurrentPaneDomain,
+        )
+        .await?;
+
+    smol::Timer::after(Duration::from_millis(500)).await;
+
+    for pane in [&pane, &split] {
+        let dims = pane.get_dimensions();
+        let (_first_row, lines) = pane.get_lines(dims.physical_top..dims.physical_top + 2);
+        for line in lines {
+            println!("pane {}: {}", pane.pane_id(), line.as_str().trim_end());
+        }
+    }
+
+    // The first pane exits by itself; the second needs to be killed
+    mux.remove_pane(split.pane_id());
+
+    Ok(())
+}
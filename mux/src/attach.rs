@@ -0,0 +1,202 @@
+//! Coordinates attaching to domains, so that a domain is only attached
+//! once at a time regardless of how many callers ask for it.
+//! A request to attach a domain that is already being attached waits
+//! for that attach to finish and shares its outcome, rather than
+//! making a second connection.  An attach that is in flight can be
+//! cancelled, which abandons the connection that it was making and
+//! leaves the domain detached.
+use crate::domain::{Domain, DomainError, DomainId, DomainResult, DomainState};
+use crate::window::WindowId;
+use crate::{Mux, MuxNotification};
+use anyhow::anyhow;
+use smol::channel::{bounded, Sender};
+use std::sync::Arc;
+
+/// An attach that is in flight
+pub(crate) struct PendingAttach {
+    /// Receive the outcome of the attach once it completes
+    waiters: Vec<Sender<DomainResult<()>>>,
+    /// Aborts the attach
+    cancel: Sender<()>,
+}
+
+/// Produces an equivalent error for each of the callers that share
+/// the outcome of an attach, preserving its code and message
+fn share_error(err: &DomainError) -> DomainError {
+    match err {
+        DomainError::DomainDetached { domain } => DomainError::DomainDetached {
+            domain: domain.clone(),
+        },
+        DomainError::DomainNotFound { domain } => DomainError::DomainNotFound {
+            domain: domain.clone(),
+        },
+        DomainError::SpawnFailed { source } => match DomainError::find(source) {
+            Some(inner) => DomainError::SpawnFailed {
+                source: anyhow::Error::new(share_error(inner)).context(source.to_string()),
+            },
+            None => DomainError::SpawnFailed {
+                source: anyhow!("{:#}", source),
+            },
+        },
+        DomainError::SplitTooSmall { needed, available } => DomainError::SplitTooSmall {
+            needed: *needed,
+            available: *available,
+        },
+        DomainError::PermissionDenied { reason } => DomainError::PermissionDenied {
+            reason: reason.clone(),
+        },
+        DomainError::Timeout { reason } => DomainError::Timeout {
+            reason: reason.clone(),
+        },
+        DomainError::AttachCancelled { domain } => DomainError::AttachCancelled {
+            domain: domain.clone(),
+        },
+        DomainError::ConfigUnavailable { domain } => DomainError::ConfigUnavailable {
+            domain: domain.clone(),
+        },
+        DomainError::QuotaExceeded { limit, reason } => DomainError::QuotaExceeded {
+            limit: *limit,
+            reason: reason.clone(),
+        },
+        DomainError::MoveDenied {
+            domain,
+            pane_id,
+            reason,
+        } => DomainError::MoveDenied {
+            domain: domain.clone(),
+            pane_id: *pane_id,
+            reason: reason.clone(),
+        },
+        DomainError::RemoteError { code, message } => DomainError::RemoteError {
+            code: *code,
+            message: message.clone(),
+        },
+    }
+}
+
+fn share_result(result: &DomainResult<()>) -> DomainResult<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => Err(share_error(err)),
+    }
+}
+
+impl Mux {
+    /// Attaches `domain`, or waits for the attach that is already in
+    /// flight for it.
+    /// Attaching a domain that is already attached succeeds without
+    /// doing anything beyond notifying `DomainAlreadyAttached`.
+    /// Fails with `DomainError::AttachCancelled` if the attach is
+    /// cancelled via `cancel_attach`.
+    pub async fn attach_domain(
+        &self,
+        domain: &Arc<dyn Domain>,
+        window_id: Option<WindowId>,
+    ) -> DomainResult<()> {
+        let domain_id = domain.domain_id();
+        if domain.state() == DomainState::Attached {
+            log::debug!("domain {} is already attached", domain.domain_name());
+            self.notify(MuxNotification::DomainAlreadyAttached(domain_id));
+            return Ok(());
+        }
+
+        let waiter = match self.pending_attaches.borrow_mut().get_mut(&domain_id) {
+            Some(attach) => {
+                let (tx, rx) = bounded(1);
+                attach.waiters.push(tx);
+                Some(rx)
+            }
+            None => None,
+        };
+        if let Some(rx) = waiter {
+            log::debug!(
+                "waiting for the attach of domain {} that is in flight",
+                domain.domain_name()
+            );
+            return rx.recv().await.unwrap_or_else(|_| {
+                Err(DomainError::AttachCancelled {
+                    domain: domain.domain_name().to_string(),
+                })
+            });
+        }
+
+        let (cancel, cancelled) = bounded(1);
+        self.pending_attaches.borrow_mut().insert(
+            domain_id,
+            PendingAttach {
+                waiters: vec![],
+                cancel,
+            },
+        );
+
+        // Dropping the attach future when it is cancelled abandons
+        // the connection that it was making
+        let result = smol::future::or(domain.attach(window_id), async {
+            let _ = cancelled.recv().await;
+            Err(DomainError::AttachCancelled {
+                domain: domain.domain_name().to_string(),
+            })
+        })
+        .await;
+
+        if let Err(DomainError::AttachCancelled { .. }) = &result {
+            log::info!("cancelled attaching domain {}", domain.domain_name());
+            if domain.state() == DomainState::Attached {
+                domain.detach().ok();
+            }
+        }
+
+        let attach = self.pending_attaches.borrow_mut().remove(&domain_id);
+        if let Some(attach) = attach {
+            for waiter in attach.waiters {
+                waiter.try_send(share_result(&result)).ok();
+            }
+        }
+
+        result
+    }
+
+    /// Cancels the attach of the domain that is in flight.
+    /// Returns false if the domain is not being attached.
+    pub fn cancel_attach(&self, domain_id: DomainId) -> bool {
+        match self.pending_attaches.borrow().get(&domain_id) {
+            Some(attach) => {
+                attach.cancel.try_send(()).ok();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_errors_keep_their_code() {
+        let err = DomainError::from(
+            anyhow::Error::new(DomainError::Timeout {
+                reason: "no response".to_string(),
+            })
+            .context("attaching"),
+        );
+        let shared = share_error(&err);
+        assert_eq!(shared.code(), DomainError::TIMEOUT);
+        assert_eq!(shared.to_string(), err.to_string());
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(shared)),
+            "attaching: no response"
+        );
+
+        let err = DomainError::from(anyhow!("connection refused"));
+        let shared = share_error(&err);
+        assert_eq!(shared.code(), DomainError::SPAWN_FAILED);
+        assert_eq!(shared.to_string(), "connection refused");
+
+        let err = DomainError::AttachCancelled {
+            domain: "remote".to_string(),
+        };
+        assert_eq!(share_error(&err).code(), DomainError::ATTACH_CANCELLED);
+    }
+}
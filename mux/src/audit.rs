@@ -0,0 +1,298 @@
+//! An opt-in log of the significant operations performed through the mux.
+//! When `audit_log` is configured, each operation is appended to that file
+//! as a single line of JSON.  Records are handed to a background thread
+//! for writing so that a slow or failing disk never holds up the mux.
+use crate::client::ClientId;
+use crate::pane::PaneId;
+use crate::tab::TabId;
+use crate::window::WindowId;
+use chrono::{DateTime, Utc};
+use config::configuration;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref WRITER: Mutex<Option<Sender<(AuditSettings, AuditRecord)>>> = Mutex::new(None);
+}
+
+/// Describes a single operation for the audit log
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub client: Option<Arc<ClientId>>,
+    pub operation: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pane_id: Option<PaneId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tab_id: Option<TabId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_id: Option<WindowId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// The address of a client on another host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// The number of bytes of input that were sent to the pane
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<usize>,
+    /// The input itself; only recorded when `audit_log_input = true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// Either "ok" or the error that caused the operation to fail
+    pub outcome: String,
+}
+
+impl AuditRecord {
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            client: None,
+            operation,
+            pane_id: None,
+            tab_id: None,
+            window_id: None,
+            domain: None,
+            peer: None,
+            bytes: None,
+            input: None,
+            outcome: "ok".to_string(),
+        }
+    }
+
+    pub fn client(mut self, client: Option<Arc<ClientId>>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn pane(mut self, pane_id: PaneId) -> Self {
+        self.pane_id.replace(pane_id);
+        self
+    }
+
+    pub fn tab(mut self, tab_id: TabId) -> Self {
+        self.tab_id.replace(tab_id);
+        self
+    }
+
+    pub fn window(mut self, window_id: WindowId) -> Self {
+        self.window_id.replace(window_id);
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain.replace(domain.to_string());
+        self
+    }
+
+    pub fn peer(mut self, peer: &str) -> Self {
+        self.peer.replace(peer.to_string());
+        self
+    }
+
+    /// Records the size of the input sent to a pane.  The content is
+    /// only kept if `record_content` is true.
+    pub fn input(mut self, data: &[u8], record_content: bool) -> Self {
+        self.bytes.replace(data.len());
+        if record_content {
+            self.input
+                .replace(String::from_utf8_lossy(data).into_owned());
+        }
+        self
+    }
+
+    pub fn outcome<T>(mut self, result: &anyhow::Result<T>) -> Self {
+        if let Err(err) = result {
+            self.outcome = format!("{:#}", err);
+        }
+        self
+    }
+}
+
+/// Returns true if the audit log is enabled
+pub fn enabled() -> bool {
+    configuration().audit_log.is_some()
+}
+
+/// Returns true if the audit log should record the content of input
+pub fn record_input() -> bool {
+    configuration().audit_log_input
+}
+
+/// Queues `record` for writing to the audit log, if it is enabled
+pub fn log(record: AuditRecord) {
+    let config = configuration();
+    let path = match &config.audit_log {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    let settings = AuditSettings {
+        path,
+        max_size: config.audit_log_max_size,
+        keep_files: config.audit_log_keep_files,
+    };
+
+    let mut writer = WRITER.lock().unwrap();
+    if writer.is_none() {
+        let (tx, rx) = channel::<(AuditSettings, AuditRecord)>();
+        let spawned = std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                let mut file = AuditFile::default();
+                while let Ok((settings, record)) = rx.recv() {
+                    if let Err(err) = file.write(&settings, &record) {
+                        log::error!(
+                            "failed to write to audit log {}: {:#}",
+                            settings.path.display(),
+                            err
+                        );
+                    }
+                }
+            });
+        match spawned {
+            Ok(_) => {
+                writer.replace(tx);
+            }
+            Err(err) => {
+                log::error!("failed to start audit log thread: {:#}", err);
+                return;
+            }
+        }
+    }
+    if let Some(tx) = writer.as_ref() {
+        tx.send((settings, record)).ok();
+    }
+}
+
+/// The configuration that applied when a record was produced
+#[derive(Debug, Clone, PartialEq)]
+struct AuditSettings {
+    path: PathBuf,
+    max_size: u64,
+    keep_files: usize,
+}
+
+/// The file that is currently being appended to
+#[derive(Default)]
+struct AuditFile {
+    file: Option<(PathBuf, File, u64)>,
+}
+
+impl AuditFile {
+    fn write(&mut self, settings: &AuditSettings, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        if self
+            .file
+            .as_ref()
+            .map(|(path, _, _)| path != &settings.path)
+            .unwrap_or(true)
+        {
+            self.file = Some(open_log(&settings.path)?);
+        }
+
+        let size = self.file.as_ref().map(|(_, _, size)| *size).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > settings.max_size {
+            self.file.take();
+            rotate(&settings.path, settings.keep_files)?;
+            self.file = Some(open_log(&settings.path)?);
+        }
+
+        let (_, file, size) = self.file.as_mut().expect("opened above");
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> anyhow::Result<(PathBuf, File, u64)> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((path.to_path_buf(), file, size))
+}
+
+fn rotated_name(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shifts `path.1` to `path.2` and so on, discarding the oldest, then
+/// moves `path` to `path.1`.  With `keep_files == 0` the log is
+/// simply truncated.
+fn rotate(path: &Path, keep_files: usize) -> anyhow::Result<()> {
+    if keep_files == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(());
+    }
+    std::fs::remove_file(rotated_name(path, keep_files)).ok();
+    for n in (1..keep_files).rev() {
+        let from = rotated_name(path, n);
+        if from.exists() {
+            std::fs::rename(&from, rotated_name(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_name(path, 1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn input_content_is_opt_in() {
+        let record = AuditRecord::new("write").pane(3).input(b"secret", false);
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["operation"], "write");
+        assert_eq!(json["pane_id"], 3);
+        assert_eq!(json["bytes"], 6);
+        assert_eq!(json["outcome"], "ok");
+        assert!(json.get("input").is_none());
+        assert!(json.get("tab_id").is_none());
+
+        let record = AuditRecord::new("write").input(b"secret", true);
+        assert_eq!(record.input.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn outcome_records_errors() {
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("no such pane 4"));
+        let record = AuditRecord::new("kill").outcome(&result);
+        assert_eq!(record.outcome, "no such pane 4");
+    }
+
+    #[test]
+    fn rotates_and_keeps_files() {
+        let dir = std::env::temp_dir().join(format!("wezterm-audit-test-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let settings = AuditSettings {
+            path: dir.join("audit.jsonl"),
+            max_size: 1,
+            keep_files: 2,
+        };
+
+        let mut file = AuditFile::default();
+        for _ in 0..4 {
+            file.write(&settings, &AuditRecord::new("attach")).unwrap();
+        }
+
+        assert!(settings.path.exists());
+        assert!(rotated_name(&settings.path, 1).exists());
+        assert!(rotated_name(&settings.path, 2).exists());
+        assert!(!rotated_name(&settings.path, 3).exists());
+
+        let current = std::fs::read_to_string(&settings.path).unwrap();
+        assert_eq!(current.lines().count(), 1);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,402 @@
+//! Synchronous wrappers around common mux operations, for use by code
+//! that cannot drive the mux's futures itself, such as plugins or
+//! bindings written in other languages.
+//!
+//! Each function dispatches its work onto the mux thread and blocks the
+//! calling thread until that work completes or the timeout elapses.
+//! Since the mux thread would then be waiting on itself, calling these
+//! functions from the mux thread is an error.
+//!
+//! With the `ffi` feature, a minimal C ABI over these wrappers is also
+//! exported; see the `ffi` module.  The `wezterm-mux-ffi` crate builds
+//! it into a shared library.
+use crate::domain::SplitSource;
+use crate::pane::PaneId;
+use crate::tab::{SplitRequest, TabId};
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::{anyhow, bail};
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io::Write;
+use std::time::Duration;
+use wezterm_term::TerminalSize;
+
+/// Describes a tab to be spawned by `spawn_tab`
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SpawnTabRequest {
+    /// The name of the domain; the default domain if omitted
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// The window in which to spawn the tab; a new window if omitted
+    #[serde(default)]
+    pub window_id: Option<WindowId>,
+    /// The workspace of a new window; the active workspace if omitted
+    #[serde(default)]
+    pub workspace: Option<String>,
+    /// The program and its arguments; the default program if omitted
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+}
+
+/// Describes the split to be made by `split_pane`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SplitPaneRequest {
+    /// The pane that is split
+    pub pane_id: PaneId,
+    /// The name of the domain; that of `pane_id` if omitted
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub split: SplitRequest,
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+}
+
+/// The ids of a pane that was spawned by `spawn_tab` or `split_pane`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawnedPane {
+    pub pane_id: PaneId,
+    pub tab_id: TabId,
+    pub window_id: WindowId,
+}
+
+/// Describes a pane, as returned by `list`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PaneEntry {
+    pub window_id: WindowId,
+    pub tab_id: TabId,
+    pub pane_id: PaneId,
+    pub workspace: String,
+    pub domain: String,
+    pub title: String,
+    pub is_active: bool,
+}
+
+/// Runs the future produced by `f` on the mux thread, and waits up to
+/// `timeout` for its result
+fn run_on_mux<F, FUT, T>(timeout: Duration, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> FUT + Send + 'static,
+    FUT: Future<Output = anyhow::Result<T>> + 'static,
+    T: Send + 'static,
+{
+    if Mux::get().is_some() {
+        bail!("mux::blocking functions cannot be called from the mux thread");
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    // The future holds Rc's across await points, so it cannot be sent
+    // to the mux thread; instead, we send `f` and spawn the future that
+    // it produces once we are on the mux thread.
+    promise::spawn::spawn_into_main_thread(async move {
+        promise::spawn::spawn(async move {
+            tx.send(f().await).ok();
+        })
+        .detach();
+    })
+    .detach();
+
+    rx.recv_timeout(timeout)
+        .map_err(|_| anyhow!("timed out after {:?} waiting for the mux", timeout))?
+}
+
+fn get_mux() -> anyhow::Result<std::rc::Rc<Mux>> {
+    Mux::get().ok_or_else(|| anyhow!("no mux"))
+}
+
+fn spawn_tab_domain(domain: Option<String>, default: SpawnTabDomain) -> SpawnTabDomain {
+    domain.map(SpawnTabDomain::DomainName).unwrap_or(default)
+}
+
+fn command_builder(command: Option<Vec<String>>) -> Option<CommandBuilder> {
+    command.map(|argv| CommandBuilder::from_argv(argv.into_iter().map(Into::into).collect()))
+}
+
+/// Spawns a new tab, returning the ids of its pane
+pub fn spawn_tab(request: SpawnTabRequest, timeout: Duration) -> anyhow::Result<SpawnedPane> {
+    run_on_mux(timeout, move || async move {
+        let mux = get_mux()?;
+        let workspace = request.workspace.unwrap_or_else(|| mux.active_workspace());
+        let (tab, pane, window_id) = mux
+            .spawn_tab_or_window(
+                request.window_id,
+                spawn_tab_domain(request.domain, SpawnTabDomain::DefaultDomain),
+                command_builder(request.command),
+                request.cwd.map(CommandDir::new),
+                TerminalSize::default(),
+                None,
+                workspace,
+            )
+            .await?;
+        Ok(SpawnedPane {
+            pane_id: pane.pane_id(),
+            tab_id: tab.tab_id(),
+            window_id,
+        })
+    })
+}
+
+/// Splits a pane, returning the ids of the new pane
+pub fn split_pane(request: SplitPaneRequest, timeout: Duration) -> anyhow::Result<SpawnedPane> {
+    run_on_mux(timeout, move || async move {
+        let mux = get_mux()?;
+        let (_domain_id, window_id, tab_id) = mux
+            .resolve_pane_id(request.pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", request.pane_id))?;
+        let (pane, _size) = mux
+            .split_pane(
+                request.pane_id,
+                request.split,
+                SplitSource::Spawn {
+                    command: command_builder(request.command),
+                    command_dir: request.cwd.map(CommandDir::new),
+                },
+                spawn_tab_domain(request.domain, SpawnTabDomain::CurrentPaneDomain),
+            )
+            .await?;
+        Ok(SpawnedPane {
+            pane_id: pane.pane_id(),
+            tab_id,
+            window_id,
+        })
+    })
+}
+
+/// Sends `text` to a pane, as though it had been typed
+pub fn send_text(pane_id: PaneId, text: String, timeout: Duration) -> anyhow::Result<()> {
+    run_on_mux(timeout, move || async move {
+        let mux = get_mux()?;
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        pane.writer().write_all(text.as_bytes())?;
+        Ok(())
+    })
+}
+
+/// Lists the panes in all windows
+pub fn list(timeout: Duration) -> anyhow::Result<Vec<PaneEntry>> {
+    run_on_mux(timeout, move || async move {
+        let mux = get_mux()?;
+        let mut entries = vec![];
+        for window_id in mux.iter_windows() {
+            let window = match mux.get_window(window_id) {
+                Some(window) => window,
+                None => continue,
+            };
+            for tab in window.iter() {
+                for pos in tab.iter_panes() {
+                    let domain = mux
+                        .get_domain(pos.pane.domain_id())
+                        .map(|domain| domain.domain_name().to_string())
+                        .unwrap_or_default();
+                    entries.push(PaneEntry {
+                        window_id,
+                        tab_id: tab.tab_id(),
+                        pane_id: pos.pane.pane_id(),
+                        workspace: window.get_workspace().to_string(),
+                        domain,
+                        title: pos.pane.get_title(),
+                        is_active: pos.is_active,
+                    });
+                }
+            }
+        }
+        Ok(entries)
+    })
+}
+
+/// A C ABI over the wrappers in this module.
+/// Requests and results are exchanged as JSON strings.  Each function
+/// returns a newly allocated string holding either `{"ok": RESULT}` or
+/// `{"error": "MESSAGE"}`, which must be released by passing it to
+/// `wezterm_mux_free_string`.  A panic while handling a request is
+/// reported as an error rather than unwinding into the caller.
+#[cfg(feature = "ffi")]
+pub mod ffi {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+
+    fn respond<T: Serialize>(result: anyhow::Result<T>) -> *mut c_char {
+        let value = match result.and_then(|value| Ok(serde_json::to_value(value)?)) {
+            Ok(value) => serde_json::json!({ "ok": value }),
+            Err(err) => serde_json::json!({ "error": format!("{:#}", err) }),
+        };
+        // A json string cannot contain a NUL
+        CString::new(value.to_string())
+            .expect("json has no NUL")
+            .into_raw()
+    }
+
+    /// Calls `f` and responds with its result.  A panic must not unwind
+    /// across the C ABI, so it is caught and reported as an error.
+    fn respond_with<T: Serialize>(f: impl FnOnce() -> anyhow::Result<T>) -> *mut c_char {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+            Ok(result) => respond(result),
+            Err(payload) => respond::<()>(Err(anyhow!(
+                "panicked: {}",
+                crate::panic_message(&*payload)
+            ))),
+        }
+    }
+
+    unsafe fn parse<T: serde::de::DeserializeOwned>(json: *const c_char) -> anyhow::Result<T> {
+        if json.is_null() {
+            bail!("request is NULL");
+        }
+        let json = CStr::from_ptr(json).to_str()?;
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// # Safety
+    /// `request` must be NULL or a valid NUL terminated string
+    #[no_mangle]
+    pub unsafe extern "C" fn wezterm_mux_spawn_tab(
+        request: *const c_char,
+        timeout_ms: u64,
+    ) -> *mut c_char {
+        respond_with(|| {
+            parse(request).and_then(|request| spawn_tab(request, Duration::from_millis(timeout_ms)))
+        })
+    }
+
+    /// # Safety
+    /// `request` must be NULL or a valid NUL terminated string
+    #[no_mangle]
+    pub unsafe extern "C" fn wezterm_mux_split_pane(
+        request: *const c_char,
+        timeout_ms: u64,
+    ) -> *mut c_char {
+        respond_with(|| {
+            parse(request)
+                .and_then(|request| split_pane(request, Duration::from_millis(timeout_ms)))
+        })
+    }
+
+    /// # Safety
+    /// `text` must be NULL or a valid NUL terminated string
+    #[no_mangle]
+    pub unsafe extern "C" fn wezterm_mux_send_text(
+        pane_id: u64,
+        text: *const c_char,
+        timeout_ms: u64,
+    ) -> *mut c_char {
+        respond_with(|| {
+            if text.is_null() {
+                bail!("text is NULL");
+            }
+            let text = CStr::from_ptr(text).to_str()?.to_string();
+            send_text(pane_id as PaneId, text, Duration::from_millis(timeout_ms))
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn wezterm_mux_list(timeout_ms: u64) -> *mut c_char {
+        respond_with(|| list(Duration::from_millis(timeout_ms)))
+    }
+
+    /// # Safety
+    /// `s` must be NULL or a string returned by one of the functions
+    /// in this module, and must not be used after this call
+    #[no_mangle]
+    pub unsafe extern "C" fn wezterm_mux_free_string(s: *mut c_char) {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::with_test_mux;
+
+    #[test]
+    fn request_defaults() {
+        let request: SpawnTabRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(request, SpawnTabRequest::default());
+
+        let request: SplitPaneRequest = serde_json::from_str(r#"{"pane_id": 3}"#).unwrap();
+        assert_eq!(request.pane_id, 3);
+        assert_eq!(request.split, SplitRequest::default());
+    }
+
+    #[test]
+    fn dispatches_to_mux_thread() {
+        let result = with_test_mux(|_mux| {
+            // Waiting on the mux from its own thread would never finish
+            let err = list(Duration::from_secs(1)).unwrap_err();
+            assert!(err.to_string().contains("mux thread"), "{:#}", err);
+
+            || list(Duration::from_secs(10))
+        });
+        assert_eq!(result.unwrap(), vec![]);
+    }
+}
+
+#[cfg(all(test, feature = "ffi"))]
+mod ffi_test {
+    use super::ffi::*;
+    use crate::testing::with_test_mux;
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+
+    /// Parses a response from the C ABI, and then frees it
+    fn take_response(response: *mut c_char) -> serde_json::Value {
+        assert!(!response.is_null());
+        let value =
+            serde_json::from_str(unsafe { CStr::from_ptr(response) }.to_str().unwrap()).unwrap();
+        unsafe { wezterm_mux_free_string(response) };
+        value
+    }
+
+    #[test]
+    fn spawn_and_list() {
+        let (spawned, listed) = with_test_mux(|_mux| {
+            || {
+                let request = CString::new(r#"{"command": ["sleep", "10"]}"#).unwrap();
+                let spawned =
+                    take_response(unsafe { wezterm_mux_spawn_tab(request.as_ptr(), 10_000) });
+                let listed = take_response(wezterm_mux_list(10_000));
+                (spawned, listed)
+            }
+        });
+        let pane_id = &spawned["ok"]["pane_id"];
+        assert!(pane_id.is_u64(), "{}", spawned);
+        let panes = listed["ok"].as_array().unwrap();
+        assert_eq!(panes.len(), 1, "{}", listed);
+        assert_eq!(&panes[0]["pane_id"], pane_id);
+        assert_eq!(panes[0]["domain"], "local");
+    }
+
+    #[test]
+    fn error_on_mux_thread() {
+        with_test_mux(|_mux| {
+            // Mux::get() is set on this thread, so the request would
+            // wait on itself
+            let response = take_response(wezterm_mux_list(1000));
+            let err = response["error"].as_str().unwrap();
+            assert!(
+                err.contains("cannot be called from the mux thread"),
+                "{}",
+                err
+            );
+            || ()
+        });
+    }
+
+    #[test]
+    fn null_request() {
+        let response = take_response(unsafe { wezterm_mux_spawn_tab(std::ptr::null(), 1000) });
+        assert_eq!(response["error"], "request is NULL");
+        unsafe { wezterm_mux_free_string(std::ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,140 @@
+//! Named positions in the scrollback of a pane, which can be jumped
+//! back to later.  A bookmark is anchored to the stable index of its
+//! row, so it keeps referring to the same line as more output arrives.
+//! Once that line has been trimmed from the scrollback the bookmark is
+//! still listed, but can no longer be jumped to.
+//! Bookmarks are kept by the mux that hosts the pane, and are
+//! forgotten when the pane is removed.
+use crate::pane::{Pane, PaneId};
+use crate::{Mux, MuxNotification};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::rc::Rc;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::StableRowIndex;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct Bookmark {
+    pub name: String,
+    /// The stable index of the bookmarked row
+    pub row: StableRowIndex,
+    /// The text of the row when it was bookmarked
+    pub text: String,
+    /// True if the row has since been trimmed from the scrollback
+    #[dynamic(default)]
+    pub trimmed: bool,
+}
+luahelper::impl_lua_conversion_dynamic!(Bookmark);
+
+#[derive(Default)]
+pub struct PaneBookmarks {
+    by_pane: HashMap<PaneId, Vec<Bookmark>>,
+}
+
+impl PaneBookmarks {
+    /// Adds `bookmark` to the pane, replacing any bookmark of the
+    /// same name
+    pub fn add(&mut self, pane_id: PaneId, bookmark: Bookmark) {
+        let bookmarks = self.by_pane.entry(pane_id).or_default();
+        bookmarks.retain(|existing| existing.name != bookmark.name);
+        bookmarks.push(bookmark);
+    }
+
+    /// Returns the bookmarks of the pane in the order in which they
+    /// were added.  Those whose row lies above `scrollback_top`, the
+    /// first row that the pane still holds, are marked as trimmed.
+    pub fn list(&self, pane_id: PaneId, scrollback_top: StableRowIndex) -> Vec<Bookmark> {
+        self.by_pane
+            .get(&pane_id)
+            .map(|bookmarks| {
+                bookmarks
+                    .iter()
+                    .map(|bookmark| Bookmark {
+                        trimmed: bookmark.row < scrollback_top,
+                        ..bookmark.clone()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        self.by_pane.remove(&pane_id);
+    }
+}
+
+/// Returns the row of the bookmark `name`, failing if there is no such
+/// bookmark or if its row has been trimmed from the scrollback
+pub fn resolve_bookmark(bookmarks: &[Bookmark], name: &str) -> anyhow::Result<StableRowIndex> {
+    let bookmark = bookmarks
+        .iter()
+        .find(|bookmark| bookmark.name == name)
+        .ok_or_else(|| anyhow::anyhow!("the pane has no bookmark named `{}`", name))?;
+    if bookmark.trimmed {
+        anyhow::bail!(
+            "bookmark content trimmed: row {} of `{}` is no longer in the scrollback",
+            bookmark.row,
+            name
+        );
+    }
+    Ok(bookmark.row)
+}
+
+/// Asks the frontends that show `pane` to scroll it to the bookmark
+/// `name`, returning the row of the bookmark
+pub async fn scroll_to_bookmark(pane: &Rc<dyn Pane>, name: &str) -> anyhow::Result<StableRowIndex> {
+    let bookmarks = pane.get_bookmarks().await?;
+    let row = resolve_bookmark(&bookmarks, name)?;
+    if let Some(mux) = Mux::get() {
+        mux.notify(MuxNotification::ScrollToRow {
+            pane_id: pane.pane_id(),
+            row,
+        });
+    }
+    Ok(row)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bookmark(name: &str, row: StableRowIndex) -> Bookmark {
+        Bookmark {
+            name: name.to_string(),
+            row,
+            text: format!("line {}", row),
+            trimmed: false,
+        }
+    }
+
+    #[test]
+    fn replace_by_name() {
+        let mut bookmarks = PaneBookmarks::default();
+        bookmarks.add(1, bookmark("a", 10));
+        bookmarks.add(1, bookmark("b", 20));
+        bookmarks.add(1, bookmark("a", 30));
+        bookmarks.add(2, bookmark("a", 5));
+        assert_eq!(
+            bookmarks.list(1, 0),
+            vec![bookmark("b", 20), bookmark("a", 30)]
+        );
+        bookmarks.remove_pane(1);
+        assert_eq!(bookmarks.list(1, 0), vec![]);
+        assert_eq!(bookmarks.list(2, 0), vec![bookmark("a", 5)]);
+    }
+
+    #[test]
+    fn trimmed() {
+        let mut bookmarks = PaneBookmarks::default();
+        bookmarks.add(1, bookmark("old", 10));
+        bookmarks.add(1, bookmark("new", 100));
+        let listed = bookmarks.list(1, 50);
+        assert!(listed[0].trimmed);
+        assert!(!listed[1].trimmed);
+
+        assert_eq!(resolve_bookmark(&listed, "new").unwrap(), 100);
+        let err = resolve_bookmark(&listed, "old").unwrap_err();
+        assert!(err.to_string().starts_with("bookmark content trimmed"));
+        assert!(resolve_bookmark(&listed, "missing").is_err());
+    }
+}
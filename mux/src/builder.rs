@@ -0,0 +1,110 @@
+//! Constructs a `Mux` for applications that embed the multiplexer
+//! without using any of the wezterm frontends.
+//!
+//! The shipped binaries load the configuration from the user's config
+//! file, evaluate it with Lua and set up their own executor before
+//! creating the mux.  `MuxBuilder` performs the equivalent setup from
+//! values supplied by the embedding application:
+//!
+//! * The configuration is a `config::Config` constructed in code; no
+//!   config file is read and no Lua is evaluated.
+//! * The executor is the pair of scheduling functions that is passed to
+//!   `promise::spawn::set_schedulers`.  `promise::spawn::SimpleExecutor`
+//!   can be used if the application doesn't have an event loop of its own.
+//! * Domains are any `Domain` implementations, including those defined
+//!   outside of this crate.
+//!
+//! Once built, the mux is driven through the usual `Mux` methods such as
+//! `spawn_tab_or_window` and `split_pane`, and changes are observed via
+//! `Mux::subscribe`.  See `examples/embed.rs` for a complete program.
+//!
+//! The mux is bound to the thread that called `MuxBuilder::build`, and the
+//! executor must run the futures scheduled onto the main thread on that
+//! same thread.
+use crate::domain::Domain;
+use crate::Mux;
+use anyhow::bail;
+use config::Config;
+use promise::spawn::ScheduleFunc;
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct MuxBuilder {
+    config: Option<Config>,
+    schedulers: Option<(ScheduleFunc, ScheduleFunc)>,
+    default_domain: Option<Arc<dyn Domain>>,
+    domains: Vec<Arc<dyn Domain>>,
+}
+
+impl MuxBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `config` as the configuration for the process.
+    /// If no config is specified, the default configuration is used;
+    /// the config file is never loaded by the builder.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config.replace(config);
+        self
+    }
+
+    /// Specify the functions that schedule normal and low priority
+    /// futures onto the mux thread.
+    /// If not specified, the caller is responsible for calling
+    /// `promise::spawn::set_schedulers`, for example by creating a
+    /// `promise::spawn::SimpleExecutor`, before building the mux.
+    pub fn schedulers(mut self, main: ScheduleFunc, low_pri: ScheduleFunc) -> Self {
+        self.schedulers.replace((main, low_pri));
+        self
+    }
+
+    /// Set the domain that is used when spawning without
+    /// naming a specific domain.
+    /// If not specified, the first domain added via `domain` is used.
+    pub fn default_domain(mut self, domain: Arc<dyn Domain>) -> Self {
+        self.default_domain.replace(domain);
+        self
+    }
+
+    /// Register an additional domain
+    pub fn domain(mut self, domain: Arc<dyn Domain>) -> Self {
+        self.domains.push(domain);
+        self
+    }
+
+    /// Creates the mux and makes it the mux for the current thread,
+    /// so that it is returned by `Mux::get`.
+    /// Must be called on the thread that will run the mux.
+    pub fn build(self) -> anyhow::Result<Rc<Mux>> {
+        if Mux::get().is_some() {
+            bail!("a Mux has already been set up on this thread");
+        }
+
+        let mut domains = self.domains.into_iter();
+        let default_domain = match self.default_domain {
+            Some(domain) => domain,
+            None => match domains.next() {
+                Some(domain) => domain,
+                None => bail!("MuxBuilder requires at least one domain"),
+            },
+        };
+
+        config::designate_this_as_the_main_thread();
+        match self.config {
+            Some(config) => config::use_this_configuration(config),
+            None => config::use_default_configuration(),
+        }
+        if let Some((main, low_pri)) = self.schedulers {
+            promise::spawn::set_schedulers(main, low_pri);
+        }
+
+        let mux = Rc::new(Mux::new(Some(default_domain)));
+        for domain in domains {
+            mux.add_domain(&domain);
+        }
+        Mux::set_mux(&mux);
+        Ok(mux)
+    }
+}
@@ -0,0 +1,129 @@
+//! Changes the working directory of the shell in a pane by typing a
+//! `cd` command into it; the reverse of a shell reporting its
+//! directory via OSC 7.
+//! This is only done when the foreground process of the pane is one of
+//! the shells in `change_dir_shells`, and the pane isn't showing the
+//! alternate screen, so that the command isn't typed into an editor or
+//! some other program that happens to be running.
+use anyhow::bail;
+use config::{ChangeDirShell, ShellQuoting};
+use std::collections::HashMap;
+
+/// Quotes `dir` so that `quoting`'s shell treats it as a single word
+pub fn quote(dir: &str, quoting: ShellQuoting) -> String {
+    match quoting {
+        ShellQuoting::Posix => format!("'{}'", dir.replace('\'', r"'\''")),
+        ShellQuoting::Fish => format!("'{}'", dir.replace('\\', r"\\").replace('\'', r"\'")),
+        ShellQuoting::PowerShell => format!("'{}'", dir.replace('\'', "''")),
+        ShellQuoting::Cmd => format!("\"{}\"", dir),
+    }
+}
+
+/// Returns the name by which `change_dir_shells` knows the executable
+/// at `path`
+pub(crate) fn shell_name(path: &str) -> String {
+    let name = path
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or(path);
+    let name = name.strip_prefix('-').unwrap_or(name);
+    let lower = name.to_ascii_lowercase();
+    match lower.strip_suffix(".exe") {
+        Some(stem) => stem.to_string(),
+        None => name.to_string(),
+    }
+}
+
+/// Returns the text to type into a pane to change its directory to
+/// `dir`, or the reason that it cannot be done.
+/// `foreground` is the path of the foreground process of the pane.
+pub fn change_dir_input(
+    shells: &HashMap<String, ChangeDirShell>,
+    foreground: Option<&str>,
+    alt_screen: bool,
+    dir: &str,
+    enter: bool,
+) -> anyhow::Result<String> {
+    if dir.is_empty() {
+        bail!("the directory is empty");
+    }
+    // A control character, such as a newline, would be acted upon by
+    // the shell or its line editor rather than becoming part of the
+    // directory, however it is quoted
+    if dir.chars().any(char::is_control) {
+        bail!("the directory contains a control character");
+    }
+    if alt_screen {
+        bail!("the pane is showing the alternate screen, so a shell is not at its prompt");
+    }
+    let foreground = match foreground {
+        Some(foreground) => foreground,
+        None => bail!("the foreground process of the pane is not known"),
+    };
+    let name = shell_name(foreground);
+    let shell = match shells.get(&name) {
+        Some(shell) => shell,
+        None => bail!(
+            "the foreground process of the pane, {}, is not one of the \
+             shells in change_dir_shells",
+            name
+        ),
+    };
+    if shell.quoting == ShellQuoting::Cmd && dir.contains('"') {
+        bail!("cmd cannot change to a directory whose name contains a double quote");
+    }
+
+    let mut input = shell.command.replace("{dir}", &quote(dir, shell.quoting));
+    if enter {
+        input.push('\r');
+    }
+    Ok(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        let dir = "/tmp/it's a dir\\";
+        assert_eq!(quote(dir, ShellQuoting::Posix), r"'/tmp/it'\''s a dir\'");
+        assert_eq!(quote(dir, ShellQuoting::Fish), r"'/tmp/it\'s a dir\\'");
+        assert_eq!(quote(dir, ShellQuoting::PowerShell), r"'/tmp/it''s a dir\'");
+        assert_eq!(
+            quote(r"C:\Program Files", ShellQuoting::Cmd),
+            r#""C:\Program Files""#
+        );
+    }
+
+    #[test]
+    fn shell_names() {
+        assert_eq!(shell_name("/usr/bin/bash"), "bash");
+        assert_eq!(shell_name("-zsh"), "zsh");
+        assert_eq!(shell_name(r"C:\Windows\System32\CMD.EXE"), "cmd");
+        assert_eq!(shell_name("pwsh.exe"), "pwsh");
+    }
+
+    #[test]
+    fn input() {
+        let shells = config::Config::default_config().change_dir_shells;
+        assert_eq!(
+            change_dir_input(&shells, Some("/bin/bash"), false, "/a b", true).unwrap(),
+            "cd -- '/a b'\r"
+        );
+        assert_eq!(
+            change_dir_input(&shells, Some("/usr/bin/fish"), false, "/a", false).unwrap(),
+            "cd '/a'"
+        );
+
+        let refused = |foreground, alt_screen, dir: &str| {
+            change_dir_input(&shells, foreground, alt_screen, dir, true)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(refused(Some("/usr/bin/vim"), false, "/a").contains("vim"));
+        assert!(refused(None, false, "/a").contains("not known"));
+        assert!(refused(Some("/bin/bash"), true, "/a").contains("alternate screen"));
+        assert!(refused(Some("/bin/bash"), false, "/a\n").contains("control"));
+    }
+}
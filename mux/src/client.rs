@@ -50,6 +50,9 @@ pub struct ClientInfo {
     pub last_input: DateTime<Utc>,
     /// The currently-focused pane
     pub focused_pane_id: Option<PaneId>,
+    /// How many PDUs are queued to be written to this client
+    #[serde(default)]
+    pub output_queue_depth: usize,
 }
 
 impl ClientInfo {
@@ -60,6 +63,7 @@ impl ClientInfo {
             active_workspace: None,
             last_input: Utc::now(),
             focused_pane_id: None,
+            output_queue_depth: 0,
         }
     }
 
@@ -0,0 +1,322 @@
+//! Clones a tab: its split layout is recreated in a new tab, with a
+//! fresh shell in each pane that starts in the cwd of the pane that it
+//! replaces.
+//! The splits are made in the same order in which the layout was
+//! originally built up, so that each one spans the same region as the
+//! split in the source tab.
+use crate::domain::SplitSource;
+use crate::localpane::LocalPane;
+use crate::pane::{alloc_pane_id, Pane, PaneId};
+use crate::splitplan::{plan_splits, PlannedSplit};
+use crate::tab::{
+    PaneEntry, PaneNode, SplitDirection, SplitDirectionAndSize, SplitRequest, SplitSize, Tab, TabId,
+};
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::{anyhow, Context};
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use portable_pty::CommandBuilder;
+use std::rc::Rc;
+
+/// A split that is made to recreate the layout of the source tab,
+/// creating a clone of `source_pane_id`
+#[derive(Debug, PartialEq)]
+struct CloneSplit {
+    request: SplitRequest,
+    source_pane_id: PaneId,
+}
+
+fn first_leaf(node: &PaneNode) -> Option<&PaneEntry> {
+    match node {
+        PaneNode::Empty => None,
+        PaneNode::Leaf(entry) => Some(entry),
+        PaneNode::Split { left, right, .. } => first_leaf(left).or_else(|| first_leaf(right)),
+    }
+}
+
+fn split_request(node: &SplitDirectionAndSize) -> SplitRequest {
+    SplitRequest {
+        direction: node.direction,
+        target_is_second: true,
+        top_level: false,
+        size: SplitSize::Cells(match node.direction {
+            SplitDirection::Horizontal => node.second.cols,
+            SplitDirection::Vertical => node.second.rows,
+        }),
+    }
+}
+
+/// Computes the splits that recreate the layout of `root`
+fn plan_clone_splits(root: &PaneNode) -> Vec<PlannedSplit<CloneSplit>> {
+    plan_splits(root, |mut node| loop {
+        match node {
+            PaneNode::Split {
+                left,
+                right,
+                node: split,
+            } => match first_leaf(right) {
+                Some(entry) => {
+                    let split = CloneSplit {
+                        request: split_request(split),
+                        source_pane_id: entry.pane_id,
+                    };
+                    break Some((split, &**left, &**right));
+                }
+                // A split with no panes on one side is just its other side
+                None => node = &**left,
+            },
+            _ => break None,
+        }
+    })
+}
+
+/// Returns the command that is running in the foreground of `pane`,
+/// or None if that is the shell that was spawned for it, or if it
+/// can't be determined
+fn foreground_command(pane: &Rc<dyn Pane>) -> Option<CommandBuilder> {
+    let info = pane.get_foreground_process_info()?;
+    if let Some(local) = pane.downcast_ref::<LocalPane>() {
+        if local.root_pid() == Some(info.pid) {
+            return None;
+        }
+    }
+    if info.argv.is_empty() {
+        return None;
+    }
+    Some(CommandBuilder::from_argv(
+        info.argv.iter().map(Into::into).collect(),
+    ))
+}
+
+impl Mux {
+    /// Returns the command and cwd for the clone of `pane_id`
+    fn clone_pane_spawn(
+        &self,
+        pane_id: PaneId,
+        same_commands: bool,
+    ) -> anyhow::Result<(Rc<dyn Pane>, Option<CommandBuilder>, Option<CommandDir>)> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        let command = if same_commands {
+            foreground_command(&pane)
+        } else {
+            None
+        };
+        let cwd = pane
+            .get_current_working_dir()
+            .and_then(|url| CommandDir::from_url(&url));
+        Ok((pane, command, cwd))
+    }
+
+    /// Creates a new tab in the same window as `tab_id`, with the same
+    /// split layout.
+    /// Each pane is spawned in the domain and the cwd of the pane that
+    /// it replaces, running the default program for the domain; panes
+    /// whose cwd is unknown use the default cwd of the domain.
+    /// If `same_commands` is true, panes that are running something
+    /// other than their shell run the same command line instead.
+    /// Returns the new tab, its active pane and its window.
+    pub async fn clone_tab(
+        &self,
+        tab_id: TabId,
+        same_commands: bool,
+    ) -> anyhow::Result<(Rc<Tab>, Rc<dyn Pane>, WindowId)> {
+        let src_tab = self
+            .get_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab {} not found", tab_id))?;
+        let window_id = self
+            .window_containing_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab {} is not in a window", tab_id))?;
+        let workspace = match self.get_window(window_id) {
+            Some(window) => window.get_workspace().to_string(),
+            None => self.active_workspace(),
+        };
+
+        let root = src_tab.codec_pane_tree();
+        let first = first_leaf(&root)
+            .ok_or_else(|| anyhow!("tab {} has no panes", tab_id))?
+            .pane_id;
+        let splits = plan_clone_splits(&root);
+        let src_active = src_tab.get_active_pane().map(|pane| pane.pane_id());
+
+        let (src_pane, command, cwd) = self.clone_pane_spawn(first, same_commands)?;
+        let (tab, pane, window_id) = self
+            .spawn_tab_or_window(
+                Some(window_id),
+                SpawnTabDomain::DomainId(src_pane.domain_id()),
+                command,
+                cwd,
+                src_tab.get_size(),
+                None,
+                workspace,
+            )
+            .await
+            .with_context(|| format!("cloning pane {} of tab {}", first, tab_id))?;
+
+        let mut slots = vec![(first, pane)];
+        for PlannedSplit { slot, split } in splits {
+            let result = async {
+                let target = Rc::clone(&slots[slot].1);
+                let (src_pane, command, cwd) =
+                    self.clone_pane_spawn(split.source_pane_id, same_commands)?;
+                let domain = self
+                    .get_domain(src_pane.domain_id())
+                    .ok_or_else(|| anyhow!("domain of pane {} not found", src_pane.pane_id()))?;
+                let pane = domain
+                    .split_pane(
+                        SplitSource::Spawn {
+                            command,
+                            command_dir: cwd,
+                        },
+                        tab.tab_id(),
+                        target.pane_id(),
+                        split.request,
+                        alloc_pane_id(),
+                    )
+                    .await?;
+                if let Some(config) = target.get_config() {
+                    pane.set_config(config);
+                }
+                anyhow::Result::<Rc<dyn Pane>>::Ok(pane)
+            }
+            .await
+            .with_context(|| format!("cloning pane {} of tab {}", split.source_pane_id, tab_id));
+            match result {
+                Ok(pane) => slots.push((split.source_pane_id, pane)),
+                Err(err) => {
+                    // Don't leave a partial copy of the layout behind
+                    self.remove_tab(tab.tab_id());
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some((_, pane)) = slots.iter().find(|(src, _)| Some(*src) == src_active) {
+            tab.set_active_pane(pane);
+        }
+        let pane = tab
+            .get_active_pane()
+            .ok_or_else(|| anyhow!("cloned tab has no active pane"))?;
+
+        Ok((tab, pane, window_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wezterm_term::TerminalSize;
+
+    fn size(cols: usize, rows: usize) -> TerminalSize {
+        TerminalSize {
+            cols,
+            rows,
+            ..TerminalSize::default()
+        }
+    }
+
+    fn leaf(pane_id: PaneId) -> Box<PaneNode> {
+        Box::new(PaneNode::Leaf(PaneEntry {
+            window_id: 0,
+            tab_id: 0,
+            pane_id,
+            title: String::new(),
+            size: size(10, 10),
+            working_dir: None,
+            is_active_pane: false,
+            is_zoomed_pane: false,
+            workspace: "default".to_string(),
+            systemd_scope: None,
+            spawn_label: None,
+            resource_usage: None,
+            modes: None,
+            links: vec![],
+            layout_generation: 0,
+            tab_title: None,
+            palette_overrides: None,
+            markers: vec![],
+        }))
+    }
+
+    fn split(
+        direction: SplitDirection,
+        first: TerminalSize,
+        second: TerminalSize,
+        left: Box<PaneNode>,
+        right: Box<PaneNode>,
+    ) -> Box<PaneNode> {
+        Box::new(PaneNode::Split {
+            left,
+            right,
+            node: SplitDirectionAndSize {
+                direction,
+                first,
+                second,
+            },
+        })
+    }
+
+    #[test]
+    fn splits_follow_the_layout() {
+        // +---+-------+
+        // | 1 |   2   |
+        // |   +---+---+
+        // |   | 3 | 4 |
+        // +---+---+---+
+        let root = split(
+            SplitDirection::Horizontal,
+            size(20, 24),
+            size(59, 24),
+            leaf(1),
+            split(
+                SplitDirection::Vertical,
+                size(59, 10),
+                size(59, 13),
+                leaf(2),
+                split(
+                    SplitDirection::Horizontal,
+                    size(30, 13),
+                    size(28, 13),
+                    leaf(3),
+                    leaf(4),
+                ),
+            ),
+        );
+
+        let splits = plan_clone_splits(&root);
+        let request = |direction, size| SplitRequest {
+            direction,
+            target_is_second: true,
+            top_level: false,
+            size: SplitSize::Cells(size),
+        };
+        assert_eq!(
+            splits,
+            vec![
+                PlannedSplit {
+                    slot: 0,
+                    split: CloneSplit {
+                        request: request(SplitDirection::Horizontal, 59),
+                        source_pane_id: 2,
+                    },
+                },
+                PlannedSplit {
+                    slot: 1,
+                    split: CloneSplit {
+                        request: request(SplitDirection::Vertical, 13),
+                        source_pane_id: 3,
+                    },
+                },
+                PlannedSplit {
+                    slot: 2,
+                    split: CloneSplit {
+                        request: request(SplitDirection::Horizontal, 28),
+                        source_pane_id: 4,
+                    },
+                },
+            ]
+        );
+        assert_eq!(first_leaf(&root).map(|entry| entry.pane_id), Some(1));
+    }
+}
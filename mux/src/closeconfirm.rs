@@ -0,0 +1,469 @@
+//! Asks the user to confirm the closing of a pane, tab or window whose
+//! panes are running something, on behalf of every client at once.
+//! A request that needs confirming becomes a pending close, which lists
+//! the panes that would be closed; `MuxNotification::CloseConfirmationRequested`
+//! tells the frontends, and the clients of a multiplexer server, to show
+//! their own prompt for it.  The first answer wins: it closes the panes,
+//! or not, and `MuxNotification::CloseConfirmationResolved` dismisses
+//! the prompts of the others.  A request that nobody answers within
+//! `CLOSE_CONFIRMATION_TIMEOUT` is cancelled, and one whose panes all
+//! close on their own first is forgotten.
+//! The panes of a multiplexer domain are confirmed by its server, so
+//! that every client attached to it sees the same prompt; the client
+//! mirrors the requests of the server, and passes its answers on.
+//! A handler of the `confirm-close-pane` event may answer a request
+//! before anyone is prompted.
+use crate::domain::DomainId;
+use crate::pane::{CloseReason, Pane, PaneId};
+use crate::tab::TabId;
+use crate::window::WindowId;
+use crate::{Mux, MuxNotification};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use smol::channel::{bounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// How long a request waits for an answer before it is cancelled
+pub const CLOSE_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub type CloseRequestId = usize;
+
+/// What is to be closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub enum CloseScope {
+    Pane(PaneId),
+    Tab(TabId),
+    Window(WindowId),
+}
+
+impl CloseScope {
+    pub fn reason(&self) -> CloseReason {
+        match self {
+            Self::Pane(_) => CloseReason::Pane,
+            Self::Tab(_) => CloseReason::Tab,
+            Self::Window(_) => CloseReason::Window,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CloseOutcome {
+    /// The panes were closed, because the close was confirmed or
+    /// didn't need to be
+    Closed,
+    /// The close was declined
+    Declined,
+    /// Nobody answered within `CLOSE_CONFIRMATION_TIMEOUT`
+    TimedOut,
+    /// The panes closed on their own before anybody answered
+    PanesGone,
+}
+
+/// A close that is waiting to be confirmed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct PendingClose {
+    pub id: CloseRequestId,
+    pub scope: CloseScope,
+    /// The panes that would be closed
+    pub panes: Vec<PaneId>,
+    /// What the prompt asks
+    pub message: String,
+}
+luahelper::impl_lua_conversion_dynamic!(PendingClose);
+
+struct Pending {
+    request: PendingClose,
+    tx: Sender<CloseOutcome>,
+    /// The domain and id of the request of a server that this mirrors
+    remote: Option<(DomainId, CloseRequestId)>,
+}
+
+#[derive(Default)]
+pub struct PendingCloses {
+    next_id: CloseRequestId,
+    pending: HashMap<CloseRequestId, Pending>,
+}
+
+impl PendingCloses {
+    /// Records a request to close `scope`.  The receiver yields the
+    /// outcome once the request is decided.
+    pub fn start(
+        &mut self,
+        scope: CloseScope,
+        panes: Vec<PaneId>,
+        message: String,
+        remote: Option<(DomainId, CloseRequestId)>,
+    ) -> (PendingClose, Receiver<CloseOutcome>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = bounded(1);
+        let request = PendingClose {
+            id,
+            scope,
+            panes,
+            message,
+        };
+        self.pending.insert(
+            id,
+            Pending {
+                request: request.clone(),
+                tx,
+                remote,
+            },
+        );
+        (request, rx)
+    }
+
+    /// Decides the request `id` with `outcome`, returning it, or None
+    /// if it was already decided
+    pub fn finish(&mut self, id: CloseRequestId, outcome: CloseOutcome) -> Option<PendingClose> {
+        let pending = self.pending.remove(&id)?;
+        pending.tx.try_send(outcome).ok();
+        Some(pending.request)
+    }
+
+    /// Returns the domain and id of the request of a server that the
+    /// request `id` mirrors
+    pub fn remote(&self, id: CloseRequestId) -> Option<(DomainId, CloseRequestId)> {
+        self.pending.get(&id).and_then(|pending| pending.remote)
+    }
+
+    /// Returns the request that mirrors the request `remote_id` of the
+    /// server of `domain_id`
+    pub fn find_remote(
+        &self,
+        domain_id: DomainId,
+        remote_id: CloseRequestId,
+    ) -> Option<CloseRequestId> {
+        self.pending
+            .iter()
+            .find(|(_, pending)| pending.remote == Some((domain_id, remote_id)))
+            .map(|(&id, _)| id)
+    }
+
+    /// Forgets the pane `pane_id`, which has closed, and decides the
+    /// requests that have no panes left as `CloseOutcome::PanesGone`,
+    /// returning their ids
+    pub fn remove_pane(&mut self, pane_id: PaneId) -> Vec<CloseRequestId> {
+        let mut gone = vec![];
+        for (&id, pending) in self.pending.iter_mut() {
+            pending.request.panes.retain(|&id| id != pane_id);
+            if pending.request.panes.is_empty() {
+                gone.push(id);
+            }
+        }
+        for &id in &gone {
+            self.finish(id, CloseOutcome::PanesGone);
+        }
+        gone
+    }
+}
+
+/// Passes `request` to the handler of the `confirm-close-pane` event,
+/// if there is one, returning its answer, or None if it didn't give one
+async fn ask_lua(request: PendingClose) -> anyhow::Result<Option<bool>> {
+    let has_handler = config::run_immediate_with_lua_config(|lua| match lua {
+        Some(lua) => Ok(config::lua::has_event_handler(&lua, "confirm-close-pane")?),
+        None => Ok(false),
+    })?;
+    if !has_handler {
+        return Ok(None);
+    }
+    config::with_lua_config_on_main_thread(move |lua| async move {
+        let lua = match lua {
+            Some(lua) => lua,
+            None => return Ok(None),
+        };
+        let value =
+            config::lua::emit_async_callback(&*lua, ("confirm-close-pane".to_string(), request))
+                .await?;
+        match value {
+            mlua::Value::Boolean(confirmed) => Ok(Some(confirmed)),
+            _ => Ok(None),
+        }
+    })
+    .await
+}
+
+impl Mux {
+    /// Returns the panes that closing `scope` would close, and whether
+    /// they can be closed without asking
+    fn close_scope_panes(&self, scope: CloseScope) -> anyhow::Result<(Vec<Rc<dyn Pane>>, bool)> {
+        let reason = scope.reason();
+        let tabs = match scope {
+            CloseScope::Pane(pane_id) => {
+                let pane = self
+                    .get_pane(pane_id)
+                    .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+                let can_close = pane.can_close_without_prompting(reason);
+                return Ok((vec![pane], can_close));
+            }
+            CloseScope::Tab(tab_id) => vec![self
+                .get_tab(tab_id)
+                .ok_or_else(|| anyhow!("tab {} not found", tab_id))?],
+            CloseScope::Window(window_id) => self
+                .get_window(window_id)
+                .ok_or_else(|| anyhow!("window {} not found", window_id))?
+                .iter()
+                .cloned()
+                .collect(),
+        };
+        let can_close = tabs
+            .iter()
+            .all(|tab| tab.can_close_without_prompting(reason));
+        let panes = tabs
+            .iter()
+            .flat_map(|tab| tab.iter_panes_ignoring_zoom())
+            .map(|pos| pos.pane)
+            .collect();
+        Ok((panes, can_close))
+    }
+
+    /// Closes `scope` right away
+    pub fn close_scope(&self, scope: CloseScope) -> anyhow::Result<()> {
+        match scope {
+            CloseScope::Pane(pane_id) => {
+                let (_domain_id, _window_id, tab_id) = self
+                    .resolve_pane_id(pane_id)
+                    .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+                if let Some(tab) = self.get_tab(tab_id) {
+                    tab.kill_pane(pane_id);
+                }
+                self.remove_pane(pane_id);
+            }
+            CloseScope::Tab(tab_id) => {
+                self.close_tab(tab_id, &Default::default())?;
+            }
+            CloseScope::Window(window_id) => {
+                anyhow::ensure!(
+                    self.get_window(window_id).is_some(),
+                    "window {} not found",
+                    window_id
+                );
+                self.close_window(window_id, &Default::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the window that holds `scope`, in which its prompt is shown
+    pub fn close_scope_window(&self, scope: CloseScope) -> Option<WindowId> {
+        match scope {
+            CloseScope::Pane(pane_id) => self
+                .resolve_pane_id(pane_id)
+                .map(|(_domain_id, window_id, _tab_id)| window_id),
+            CloseScope::Tab(tab_id) => self.window_containing_tab(tab_id),
+            CloseScope::Window(window_id) => Some(window_id),
+        }
+    }
+
+    fn close_message(&self, scope: CloseScope) -> String {
+        match scope {
+            CloseScope::Pane(pane_id) => {
+                match self
+                    .get_pane(pane_id)
+                    .and_then(|pane| pane.get_spawn_label())
+                {
+                    Some(label) => format!("🛑 Really kill '{}'?", label),
+                    None => "🛑 Really kill this pane?".to_string(),
+                }
+            }
+            CloseScope::Tab(_) => "🛑 Really kill this tab and all contained panes?".to_string(),
+            CloseScope::Window(_) => {
+                "🛑 Really kill this window and all contained tabs and panes?".to_string()
+            }
+        }
+    }
+
+    /// Closes `scope`, once the user has confirmed it if any of its
+    /// panes are running something, and returns how that was decided.
+    /// The panes of a multiplexer domain are confirmed by its server.
+    pub async fn request_close(&self, scope: CloseScope) -> anyhow::Result<CloseOutcome> {
+        let (panes, can_close) = self.close_scope_panes(scope)?;
+        let domain_id = panes.first().map(|pane| pane.domain_id());
+        if panes.iter().all(|pane| Some(pane.domain_id()) == domain_id) {
+            if let Some(domain) = domain_id.and_then(|id| self.get_domain(id)) {
+                if let Some(outcome) = domain.confirm_close(scope).await? {
+                    return Ok(outcome);
+                }
+            }
+        }
+        if can_close {
+            self.close_scope(scope)?;
+            return Ok(CloseOutcome::Closed);
+        }
+
+        let pane_ids = panes.iter().map(|pane| pane.pane_id()).collect();
+        let message = self.close_message(scope);
+        let (request, rx) = self
+            .pending_closes
+            .borrow_mut()
+            .start(scope, pane_ids, message, None);
+        let id = request.id;
+        match ask_lua(request.clone()).await {
+            Ok(Some(confirmed)) => self.answer_close_confirmation(id, confirmed),
+            Ok(None) => self.notify(MuxNotification::CloseConfirmationRequested(request)),
+            Err(err) => {
+                log::error!("while processing confirm-close-pane event: {:#}", err);
+                self.notify(MuxNotification::CloseConfirmationRequested(request));
+            }
+        }
+
+        let answer = async { rx.recv().await.ok() };
+        let timeout = async {
+            smol::Timer::after(CLOSE_CONFIRMATION_TIMEOUT).await;
+            None
+        };
+        match smol::future::or(answer, timeout).await {
+            Some(outcome) => Ok(outcome),
+            None => {
+                self.finish_close(id, CloseOutcome::TimedOut);
+                Ok(CloseOutcome::TimedOut)
+            }
+        }
+    }
+
+    /// Decides the request `id` with `outcome`, and tells the frontends
+    /// to dismiss their prompts for it.  Returns false if it was
+    /// already decided.
+    fn finish_close(&self, id: CloseRequestId, outcome: CloseOutcome) -> bool {
+        let finished = self.pending_closes.borrow_mut().finish(id, outcome);
+        if finished.is_none() {
+            return false;
+        }
+        self.notify(MuxNotification::CloseConfirmationResolved { id, outcome });
+        true
+    }
+
+    /// Answers the pending close `id`, closing its panes if `confirmed`.
+    /// Only the first answer counts.
+    pub fn answer_close_confirmation(&self, id: CloseRequestId, confirmed: bool) {
+        let remote = self.pending_closes.borrow().remote(id);
+        if let Some((domain_id, remote_id)) = remote {
+            // The server decides, and tells every client, this one
+            // included, what it decided
+            let domain = match self.get_domain(domain_id) {
+                Some(domain) => domain,
+                None => return,
+            };
+            promise::spawn::spawn(async move {
+                if let Err(err) = domain.answer_close_confirmation(remote_id, confirmed).await {
+                    log::error!(
+                        "answering close request {} of domain `{}`: {:#}",
+                        remote_id,
+                        domain.domain_name(),
+                        err
+                    );
+                }
+            })
+            .detach();
+            return;
+        }
+
+        let outcome = if confirmed {
+            CloseOutcome::Closed
+        } else {
+            CloseOutcome::Declined
+        };
+        let request = match self.pending_closes.borrow_mut().finish(id, outcome) {
+            Some(request) => request,
+            None => {
+                log::debug!("close request {} is no longer pending", id);
+                return;
+            }
+        };
+        self.notify(MuxNotification::CloseConfirmationResolved { id, outcome });
+        if confirmed {
+            if let Err(err) = self.close_scope(request.scope) {
+                log::error!("closing {:?}: {:#}", request.scope, err);
+            }
+        }
+    }
+
+    /// Mirrors the pending close `request` of the server of the domain
+    /// `domain_id`, whose ids have been mapped to those of this mux, so
+    /// that the frontend prompts for it
+    pub fn mirror_close_confirmation(&self, domain_id: DomainId, request: PendingClose) {
+        let remote = Some((domain_id, request.id));
+        let (request, _rx) = self.pending_closes.borrow_mut().start(
+            request.scope,
+            request.panes,
+            request.message,
+            remote,
+        );
+        self.notify(MuxNotification::CloseConfirmationRequested(request));
+    }
+
+    /// Decides the mirror of the request `remote_id` of the server of
+    /// the domain `domain_id` as the server did
+    pub fn remote_close_resolved(
+        &self,
+        domain_id: DomainId,
+        remote_id: CloseRequestId,
+        outcome: CloseOutcome,
+    ) {
+        let id = self
+            .pending_closes
+            .borrow()
+            .find_remote(domain_id, remote_id);
+        if let Some(id) = id {
+            self.finish_close(id, outcome);
+        }
+    }
+
+    /// Forgets the pane `pane_id` in the pending closes, dismissing
+    /// those that have no panes left
+    pub(crate) fn forget_pane_in_pending_closes(&self, pane_id: PaneId) {
+        let gone = self.pending_closes.borrow_mut().remove_pane(pane_id);
+        for id in gone {
+            self.notify(MuxNotification::CloseConfirmationResolved {
+                id,
+                outcome: CloseOutcome::PanesGone,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use smol::future::block_on;
+
+    #[test]
+    fn first_answer_wins() {
+        let mut closes = PendingCloses::default();
+        let (request, rx) = closes.start(CloseScope::Tab(1), vec![1, 2], "?".to_string(), None);
+        assert_eq!(
+            closes.finish(request.id, CloseOutcome::Declined),
+            Some(request.clone())
+        );
+        assert_eq!(closes.finish(request.id, CloseOutcome::Closed), None);
+        assert_eq!(block_on(rx.recv()).unwrap(), CloseOutcome::Declined);
+    }
+
+    #[test]
+    fn panes_that_close_on_their_own() {
+        let mut closes = PendingCloses::default();
+        let (tab, tab_rx) = closes.start(CloseScope::Tab(1), vec![1, 2], "?".to_string(), None);
+        let (pane, _pane_rx) = closes.start(CloseScope::Pane(3), vec![3], "?".to_string(), None);
+
+        assert!(closes.remove_pane(1).is_empty());
+        assert_eq!(closes.remove_pane(2), vec![tab.id]);
+        assert_eq!(block_on(tab_rx.recv()).unwrap(), CloseOutcome::PanesGone);
+        assert_eq!(closes.finish(pane.id, CloseOutcome::Closed), Some(pane));
+    }
+
+    #[test]
+    fn mirrors() {
+        let mut closes = PendingCloses::default();
+        let (local, _) = closes.start(CloseScope::Pane(1), vec![1], "?".to_string(), None);
+        let (mirror, _) = closes.start(CloseScope::Pane(2), vec![2], "?".to_string(), Some((4, 7)));
+        assert_eq!(closes.remote(local.id), None);
+        assert_eq!(closes.remote(mirror.id), Some((4, 7)));
+        assert_eq!(closes.find_remote(4, 7), Some(mirror.id));
+        assert_eq!(closes.find_remote(5, 7), None);
+    }
+}
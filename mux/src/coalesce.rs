@@ -0,0 +1,213 @@
+//! Coalescing of the title, working directory and user var updates
+//! that some shells generate as often as once per keystroke.
+//! Within a window of time only the latest value of each of these is
+//! delivered, and a value that is the same as the one that was most
+//! recently delivered is not delivered at all.
+use crate::pane::Pane;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use wezterm_term::Alert;
+
+/// Counts the updates that the mux server did not send to its clients
+pub static SUPPRESSED_REMOTE: AtomicUsize = AtomicUsize::new(0);
+/// Counts the updates that did not trigger a refresh of the GUI title
+/// and tab bar, and thus of `format-tab-title`
+pub static SUPPRESSED_LOCAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the (remote, local) counts of suppressed updates
+pub fn suppressed_counts() -> (usize, usize) {
+    (
+        SUPPRESSED_REMOTE.load(Ordering::Relaxed),
+        SUPPRESSED_LOCAL.load(Ordering::Relaxed),
+    )
+}
+
+/// The coalescing window, taken from the configuration
+pub fn coalesce_window() -> Duration {
+    Duration::from_millis(config::configuration().pane_metadata_coalesce_ms)
+}
+
+/// Identifies the value that an update replaces
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UpdateKey {
+    IconTitle,
+    WindowTitle,
+    TabTitle,
+    WorkingDir,
+    UserVar(String),
+}
+
+impl UpdateKey {
+    /// If `alert` is subject to coalescing, returns its key and the
+    /// value that it sets.  The value of a working directory change
+    /// is not part of the alert, so it is taken from `pane`.
+    pub fn for_alert(alert: &Alert, pane: &dyn Pane) -> Option<(Self, String)> {
+        match alert {
+            Alert::IconTitleChanged(title) => {
+                Some((Self::IconTitle, title.clone().unwrap_or_default()))
+            }
+            Alert::WindowTitleChanged(title) => Some((Self::WindowTitle, title.clone())),
+            Alert::TabTitleChanged(title) => {
+                Some((Self::TabTitle, title.clone().unwrap_or_default()))
+            }
+            Alert::CurrentWorkingDirectoryChanged => Some((
+                Self::WorkingDir,
+                pane.get_current_working_dir()
+                    .map(|url| url.to_string())
+                    .unwrap_or_default(),
+            )),
+            Alert::SetUserVar { name, value } => Some((Self::UserVar(name.clone()), value.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Holds back updates until they are due, keeping only the latest
+/// value for each key.
+#[derive(Debug)]
+pub struct UpdateCoalescer<K> {
+    /// The values that were most recently delivered
+    delivered: HashMap<K, String>,
+    /// The updates that have yet to be delivered, in the order in
+    /// which their keys were first updated
+    pending: Vec<(K, String, Alert)>,
+    last_flush: Option<Instant>,
+    flush_scheduled: bool,
+    /// The number of updates dropped since `take_suppressed` was called
+    suppressed: usize,
+}
+
+impl<K> Default for UpdateCoalescer<K> {
+    fn default() -> Self {
+        Self {
+            delivered: HashMap::new(),
+            pending: vec![],
+            last_flush: None,
+            flush_scheduled: false,
+            suppressed: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> UpdateCoalescer<K> {
+    /// Queues an update, replacing any pending update for the same key
+    pub fn push(&mut self, key: K, value: String, alert: Alert) {
+        if let Some(existing) = self.pending.iter_mut().find(|(k, _, _)| *k == key) {
+            self.suppressed += 1;
+            existing.1 = value;
+            existing.2 = alert;
+        } else {
+            self.pending.push((key, value, alert));
+        }
+    }
+
+    /// Returns the number of updates that were dropped since the
+    /// previous call, so that the caller can account for them
+    pub fn take_suppressed(&mut self) -> usize {
+        std::mem::take(&mut self.suppressed)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Returns the earliest time at which the next flush may happen,
+    /// or None if it may happen right away
+    pub fn next_flush(&self, window: Duration) -> Option<Instant> {
+        self.last_flush.map(|last| last + window)
+    }
+
+    /// Returns true if updates may be delivered at `now`
+    pub fn is_due(&self, now: Instant, window: Duration) -> bool {
+        self.next_flush(window).map_or(true, |due| due <= now)
+    }
+
+    /// Returns true if the caller should arrange to flush the pending
+    /// updates once they are due, or false if that is already arranged
+    pub fn schedule_flush(&mut self) -> bool {
+        !std::mem::replace(&mut self.flush_scheduled, true)
+    }
+
+    /// Returns the pending updates whose value differs from the one
+    /// that was most recently delivered, and starts a new window
+    pub fn flush(&mut self, now: Instant) -> Vec<Alert> {
+        self.last_flush.replace(now);
+        self.flush_scheduled = false;
+        let mut alerts = vec![];
+        for (key, value, alert) in self.pending.drain(..) {
+            if self.delivered.get(&key) == Some(&value) {
+                self.suppressed += 1;
+                continue;
+            }
+            self.delivered.insert(key, value);
+            alerts.push(alert);
+        }
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn title(s: &str) -> Alert {
+        Alert::WindowTitleChanged(s.to_string())
+    }
+
+    #[test]
+    fn latest_value_wins() {
+        let mut c = UpdateCoalescer::default();
+        for t in ["a", "ab", "abc"] {
+            c.push(UpdateKey::WindowTitle, t.to_string(), title(t));
+        }
+        c.push(
+            UpdateKey::UserVar("x".to_string()),
+            "1".to_string(),
+            Alert::SetUserVar {
+                name: "x".to_string(),
+                value: "1".to_string(),
+            },
+        );
+        assert_eq!(
+            c.flush(Instant::now()),
+            vec![
+                title("abc"),
+                Alert::SetUserVar {
+                    name: "x".to_string(),
+                    value: "1".to_string(),
+                }
+            ]
+        );
+        assert_eq!(c.take_suppressed(), 2);
+        assert!(!c.has_pending());
+    }
+
+    #[test]
+    fn identical_values_are_suppressed() {
+        let mut c = UpdateCoalescer::default();
+        c.push(UpdateKey::WindowTitle, "a".to_string(), title("a"));
+        assert_eq!(c.flush(Instant::now()), vec![title("a")]);
+        c.push(UpdateKey::WindowTitle, "a".to_string(), title("a"));
+        assert_eq!(c.flush(Instant::now()), vec![]);
+        assert_eq!(c.take_suppressed(), 1);
+        c.push(UpdateKey::WindowTitle, "b".to_string(), title("b"));
+        assert_eq!(c.flush(Instant::now()), vec![title("b")]);
+    }
+
+    #[test]
+    fn window() {
+        let mut c: UpdateCoalescer<UpdateKey> = UpdateCoalescer::default();
+        let window = Duration::from_millis(50);
+        let now = Instant::now();
+        assert!(c.is_due(now, window));
+        c.flush(now);
+        assert!(!c.is_due(now + Duration::from_millis(10), window));
+        assert!(c.is_due(now + window, window));
+        assert!(c.schedule_flush());
+        assert!(!c.schedule_flush());
+        c.flush(now + window);
+        assert!(c.schedule_flush());
+    }
+}
@@ -0,0 +1,167 @@
+//! Listing of the directories of a domain, used to complete the
+//! working directory of a command that is about to be spawned into
+//! that domain.  Local domains read the directory themselves, wsl
+//! domains ask wsl.exe and client domains ask their mux server.
+use anyhow::Context;
+use config::HOME_DIR;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The most entries that are returned for a single directory
+pub const MAX_ENTRIES: usize = 500;
+
+/// The entries of a directory, sorted by name.  The names of
+/// directories end with a `/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirListing {
+    pub entries: Vec<String>,
+    /// True if the directory has more entries than were returned
+    pub truncated: bool,
+}
+
+impl DirListing {
+    /// Keeps at most `limit` of `entries`
+    fn new(mut entries: Vec<String>, limit: usize) -> Self {
+        let truncated = entries.len() > limit;
+        entries.truncate(limit);
+        entries.sort();
+        Self { entries, truncated }
+    }
+
+    /// Returns the entries whose names start with `prefix`
+    pub fn matching<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .map(|entry| entry.as_str())
+            .filter(move |entry| entry.starts_with(prefix))
+    }
+}
+
+/// If `dir` is `~` or starts with `~/`, returns the remainder
+fn strip_home(dir: &str) -> Option<&str> {
+    let rest = dir.strip_prefix('~')?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Lists `dir` on the local filesystem; a leading `~` stands for the
+/// home directory.  Reading stops once more than `limit` entries have
+/// been seen, so that a huge directory is not read in full.
+pub fn list_local_dir(dir: &str, limit: usize) -> anyhow::Result<DirListing> {
+    let path = match strip_home(dir) {
+        Some(rest) => HOME_DIR.join(rest.trim_start_matches('/')),
+        None => PathBuf::from(dir),
+    };
+
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(&path).with_context(|| format!("listing {}", path.display()))? {
+        if entries.len() > limit {
+            break;
+        }
+        let entry = entry?;
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        // Follow symlinks, so that a link to a directory can be
+        // completed through
+        if entry.path().is_dir() {
+            name.push('/');
+        }
+        entries.push(name);
+    }
+    Ok(DirListing::new(entries, limit))
+}
+
+/// Lists `dir` inside a wsl distribution by running `ls` through
+/// wsl.exe; a leading `~` stands for the home directory of the user
+pub fn list_wsl_dir(
+    distribution: &str,
+    username: Option<&str>,
+    dir: &str,
+    limit: usize,
+) -> anyhow::Result<DirListing> {
+    #[cfg(windows)]
+    use std::os::windows::process::CommandExt;
+    let mut cmd = std::process::Command::new("wsl.exe");
+    cmd.arg("--distribution").arg(distribution);
+    if let Some(user) = username {
+        cmd.arg("--user").arg(user);
+    }
+    let dir = match strip_home(dir) {
+        Some(rest) => {
+            cmd.arg("--cd").arg("~");
+            format!(".{}", rest)
+        }
+        None => dir.to_string(),
+    };
+    cmd.args(["--exec", "ls", "-1Ap", "--"]).arg(&dir);
+    #[cfg(windows)]
+    cmd.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
+    let output = cmd.output().context("running wsl.exe")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "listing {} in {} failed: {}",
+        dir,
+        distribution,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(parse_ls_output(
+        &String::from_utf8_lossy(&output.stdout),
+        limit,
+    ))
+}
+
+/// Parses the output of `ls -1Ap`, which lists one entry per line and
+/// marks directories with a trailing `/`
+fn parse_ls_output(output: &str, limit: usize) -> DirListing {
+    DirListing::new(
+        output
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty())
+            .take(limit + 1)
+            .map(|line| line.to_string())
+            .collect(),
+        limit,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ls() {
+        let listing = parse_ls_output("bin/\r\n.bashrc\nsrc/\n\n", 10);
+        assert_eq!(
+            listing,
+            DirListing {
+                entries: vec![
+                    ".bashrc".to_string(),
+                    "bin/".to_string(),
+                    "src/".to_string()
+                ],
+                truncated: false,
+            }
+        );
+        assert_eq!(listing.matching("s").collect::<Vec<_>>(), vec!["src/"]);
+    }
+
+    #[test]
+    fn truncated() {
+        let listing = parse_ls_output("a\nb\nc\n", 2);
+        assert_eq!(listing.entries, vec!["a".to_string(), "b".to_string()]);
+        assert!(listing.truncated);
+        assert!(!parse_ls_output("a\nb\n", 2).truncated);
+    }
+
+    #[test]
+    fn home() {
+        assert_eq!(strip_home("~"), Some(""));
+        assert_eq!(strip_home("~/src/"), Some("/src/"));
+        assert_eq!(strip_home("~wez/"), None);
+        assert_eq!(strip_home("/home/"), None);
+    }
+}
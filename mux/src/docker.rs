@@ -0,0 +1,328 @@
+//! Support for domains whose panes run inside docker containers.
+//! The panes are local panes that run `docker exec -it` on the host;
+//! the docker cli is used to discover and inspect containers.
+use crate::connui::ConnectionUI;
+use crate::domain::{Domain, LocalDomain};
+use crate::Mux;
+use anyhow::{bail, Context};
+use config::{configuration, DockerDomain, DomainKind};
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+use std::process::Command;
+use std::sync::Arc;
+
+/// Runs the login shell of the user inside the container.
+/// `docker exec` doesn't provide a way to ask for that directly.
+const LOGIN_SHELL_SCRIPT: &str = "shell=$(awk -F: -v u=\"$(id -un)\" \
+     '$1 == u { print $7 }' /etc/passwd 2>/dev/null); \
+     exec \"${shell:-/bin/sh}\" -l";
+
+/// The `PaletteEntry` action that restarts the container of a domain
+pub const RESTART_ACTION: &str = "restart-container";
+
+/// Information about a container, as reported by `docker inspect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DockerContainer {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub status: String,
+    pub running: bool,
+}
+
+fn run_docker(docker: &str, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new(docker)
+        .args(args)
+        .output()
+        .with_context(|| format!("running {} {}", docker, args.join(" ")))?;
+    if !output.status.success() {
+        bail!(
+            "{} {} failed: {}",
+            docker,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn list_containers(docker: &str, args: &[&str]) -> anyhow::Result<Vec<String>> {
+    let mut args = args.to_vec();
+    args.extend_from_slice(&["--format", "{{.Names}}"]);
+    Ok(run_docker(docker, &args)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Returns the names of the running containers
+pub fn list_running_containers(docker: &str) -> anyhow::Result<Vec<String>> {
+    list_containers(docker, &["ps"])
+}
+
+/// Returns the names of all containers, including those that
+/// have stopped
+pub fn list_all_containers(docker: &str) -> anyhow::Result<Vec<String>> {
+    list_containers(docker, &["ps", "--all"])
+}
+
+fn parse_inspect_output(output: &str) -> anyhow::Result<DockerContainer> {
+    let fields: Vec<&str> = output.trim().split('\t').collect();
+    match fields.as_slice() {
+        [id, name, image, status, running] => Ok(DockerContainer {
+            id: id.to_string(),
+            name: name.trim_start_matches('/').to_string(),
+            image: image.to_string(),
+            status: status.to_string(),
+            running: *running == "true",
+        }),
+        _ => bail!("unexpected output from docker inspect: {:?}", output),
+    }
+}
+
+/// Returns information about the specified container
+pub fn inspect_container(docker: &str, container: &str) -> anyhow::Result<DockerContainer> {
+    let output = run_docker(
+        docker,
+        &[
+            "inspect",
+            "--type",
+            "container",
+            "--format",
+            "{{.Id}}\t{{.Name}}\t{{.Config.Image}}\t{{.State.Status}}\t{{.State.Running}}",
+            container,
+        ],
+    )?;
+    parse_inspect_output(&output)
+}
+
+/// Returns the argv that runs `cmd` inside the container of `domain`.
+/// The cwd and the environment variables that were explicitly set on
+/// `cmd` are passed through to the container; the cwd is a path in the
+/// container, never on the host.  A tty is allocated in the container
+/// if `tty` is true, which it is for the command of a pane.
+pub fn docker_exec_argv(
+    docker: &str,
+    domain: &DockerDomain,
+    cmd: &CommandBuilder,
+    tty: bool,
+) -> Vec<OsString> {
+    let flags = if tty { "-it" } else { "-i" };
+    let mut argv: Vec<OsString> = vec![docker.into(), "exec".into(), flags.into()];
+
+    if let Some(cwd) = cmd.get_cwd() {
+        argv.push("-w".into());
+        argv.push(cwd.clone());
+    }
+
+    if let Some(user) = &domain.username {
+        argv.push("-u".into());
+        argv.push(user.into());
+    }
+
+    for (key, value) in cmd.iter_extra_env_as_str() {
+        argv.push("-e".into());
+        argv.push(format!("{}={}", key, value).into());
+    }
+
+    argv.push(domain.container.as_str().into());
+
+    if cmd.is_default_prog() {
+        argv.push("/bin/sh".into());
+        argv.push("-c".into());
+        argv.push(LOGIN_SHELL_SCRIPT.into());
+    } else {
+        argv.extend(cmd.get_argv().iter().cloned());
+    }
+
+    argv
+}
+
+/// Returns a label describing the container of `domain`
+pub async fn container_label(domain: &DockerDomain) -> String {
+    let docker = configuration().docker_command.clone();
+    let container = domain.container.clone();
+    match promise::spawn::spawn_into_new_thread(move || inspect_container(&docker, &container))
+        .await
+    {
+        Ok(info) => format!("Docker {} ({}, {})", info.name, info.image, info.status),
+        Err(err) => {
+            log::debug!("inspecting container {}: {:#}", domain.container, err);
+            format!("Docker {} (unavailable)", domain.container)
+        }
+    }
+}
+
+/// Checks that the container of `domain` is running.
+/// If it has stopped, asks the user whether to start it, and starts it
+/// if they agree.  Returns an error if the container isn't running
+/// at the end of that process.
+pub async fn ensure_container_running(domain: &DockerDomain) -> anyhow::Result<()> {
+    let docker = configuration().docker_command.clone();
+    let container = domain.container.clone();
+    promise::spawn::spawn_into_new_thread(move || {
+        let info = inspect_container(&docker, &container)?;
+        if info.running {
+            return Ok(());
+        }
+
+        let ui = ConnectionUI::new();
+        ui.title(&format!("Docker container {}", info.name));
+        ui.output_str(&format!(
+            "The container {} ({}) is {}.\n",
+            info.name, info.image, info.status
+        ));
+        let start = match ui.input("Start it? [y/n]> ") {
+            Ok(line) => matches!(line.as_ref(), "y" | "Y" | "yes" | "YES"),
+            Err(_) => false,
+        };
+        if !start {
+            ui.close();
+            bail!("the container {} is {}", info.name, info.status);
+        }
+
+        ui.output_str(&format!("Starting {}\n", info.name));
+        ui.run_and_log_error(|| {
+            run_docker(&docker, &["start", &container])?;
+            let info = inspect_container(&docker, &container)?;
+            if !info.running {
+                bail!(
+                    "the container {} is {} after starting it",
+                    info.name,
+                    info.status
+                );
+            }
+            Ok(())
+        })?;
+        ui.close();
+        Ok(())
+    })
+    .await
+}
+
+/// Restarts the container of `domain`.
+/// The panes of the domain are not respawned; their `docker exec`
+/// sessions end when the container stops.
+pub async fn restart_container(domain: &DockerDomain) -> anyhow::Result<()> {
+    let docker = configuration().docker_command.clone();
+    let container = domain.container.clone();
+    promise::spawn::spawn_into_new_thread(move || {
+        run_docker(&docker, &["restart", &container])?;
+        Ok(())
+    })
+    .await
+}
+
+/// Registers a domain for each running container that doesn't
+/// already have one, and removes the discovered domains whose
+/// container no longer exists.  A domain whose container has merely
+/// stopped is kept, so that spawning into it can offer to start it,
+/// and a domain that still has panes is kept until a later refresh
+/// finds it without any.
+pub async fn discover_docker_domains() -> anyhow::Result<()> {
+    let config = configuration();
+    let docker = config.docker_command.clone();
+    let (running, existing) = promise::spawn::spawn_into_new_thread(move || {
+        Ok((
+            list_running_containers(&docker)?,
+            list_all_containers(&docker)?,
+        ))
+    })
+    .await?;
+
+    let mux = Mux::get().expect("to be called on the mux thread");
+    for domain in mux.iter_domains() {
+        let name = domain.domain_name();
+        if domain.domain_kind() != Some(DomainKind::Docker)
+            || existing
+                .iter()
+                .any(|container| DockerDomain::for_discovered_container(container).name == name)
+            || config.docker_domains.iter().any(|dom| dom.name == name)
+        {
+            continue;
+        }
+        log::trace!("removing docker domain {}", name);
+        if let Err(err) = mux.remove_domain(domain.domain_id()) {
+            log::debug!("not removing docker domain {}: {:#}", name, err);
+        }
+    }
+
+    for container in running {
+        let docker_dom = DockerDomain::for_discovered_container(&container);
+        if mux
+            .get_domain_by_name_and_kind(&docker_dom.name, DomainKind::Docker)
+            .is_some()
+        {
+            continue;
+        }
+        log::trace!("registering docker domain {}", docker_dom.name);
+        let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new_docker(docker_dom)?);
+        mux.add_domain(&domain);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inspect_output() {
+        let info =
+            parse_inspect_output("0123abc\t/builder\tdebian:bookworm\texited\tfalse\n").unwrap();
+        assert_eq!(
+            info,
+            DockerContainer {
+                id: "0123abc".to_string(),
+                name: "builder".to_string(),
+                image: "debian:bookworm".to_string(),
+                status: "exited".to_string(),
+                running: false,
+            }
+        );
+        assert!(parse_inspect_output("Error: No such object").is_err());
+    }
+
+    #[test]
+    fn exec_argv() {
+        let domain = DockerDomain {
+            name: "docker:builder".to_string(),
+            container: "builder".to_string(),
+            username: Some("build".to_string()),
+            ..Default::default()
+        };
+
+        let mut cmd = CommandBuilder::from_argv(vec!["make".into(), "-j4".into()]);
+        cmd.cwd("/src");
+        cmd.env("WEZTERM_PANE", "3");
+        cmd.env("WSLENV", "WEZTERM_PANE/u");
+        assert_eq!(
+            docker_exec_argv("docker", &domain, &cmd, true),
+            vec![
+                "docker",
+                "exec",
+                "-it",
+                "-w",
+                "/src",
+                "-u",
+                "build",
+                "-e",
+                "WEZTERM_PANE=3",
+                "-e",
+                "WSLENV=WEZTERM_PANE/u",
+                "builder",
+                "make",
+                "-j4"
+            ]
+        );
+
+        let cmd = CommandBuilder::new_default_prog();
+        let argv = docker_exec_argv("docker", &domain, &cmd, true);
+        assert_eq!(
+            &argv[argv.len() - 4..],
+            &["builder", "/bin/sh", "-c", LOGIN_SHELL_SCRIPT]
+        );
+    }
+}
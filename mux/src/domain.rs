@@ -5,45 +5,364 @@
 //! container or actually remote, running on the other end
 //! of an ssh session somewhere.
 
+use crate::closeconfirm::{CloseOutcome, CloseRequestId, CloseScope};
+use crate::dirlist::DirListing;
+use crate::domainexec::{ExecOutput, ExecRequest};
 use crate::localpane::LocalPane;
 use crate::pane::{alloc_pane_id, Pane, PaneId};
-use crate::tab::{SplitRequest, Tab, TabId};
+use crate::prewarm::{PrewarmPool, PrewarmedShell};
+use crate::resources::PaneResourceUsage;
+use crate::spawnquota::SpawnLimit;
+use crate::spawntiming;
+use crate::tab::{SplitError, SplitPreview, SplitRequest, Tab, TabId};
 use crate::window::WindowId;
 use crate::Mux;
 use anyhow::{bail, Context, Error};
 use async_trait::async_trait;
-use config::keyassignment::{SpawnCommand, SpawnTabDomain};
-use config::{configuration, ExecDomain, ValueOrFunc, WslDomain};
+use config::keyassignment::{CommandDir, SpawnCommand, SpawnTabDomain};
+use config::{
+    configuration, ClosePolicy, ConfigHandle, CwdExpansion, DockerDomain, DomainKind, ExecDomain,
+    LauncherHints, NotificationHandling, Palette, ValueOrFunc, WslDistro, WslDomain,
+};
 use downcast_rs::{impl_downcast, Downcast};
 use portable_pty::{native_pty_system, CommandBuilder, PtySystem};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::OsString;
+use std::io::Write;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
 use wezterm_term::TerminalSize;
 
-static DOMAIN_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+pub(crate) static DOMAIN_ID: ::std::sync::atomic::AtomicUsize =
+    ::std::sync::atomic::AtomicUsize::new(0);
 pub type DomainId = usize;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromDynamic, ToDynamic)]
 pub enum DomainState {
     Detached,
     Attached,
 }
 
+/// The number of live windows, tabs and panes that belong to a domain.
+/// A window or tab that contains panes from several domains is
+/// counted once for each of those domains.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DomainInventory {
+    pub num_windows: usize,
+    pub num_tabs: usize,
+    pub num_panes: usize,
+}
+
+/// A snapshot of a domain and its inventory, as reported by
+/// `wezterm cli list-domains` and passed to the launcher
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct DomainInfo {
+    pub domain_id: DomainId,
+    pub name: String,
+    pub label: String,
+    pub state: DomainState,
+    pub spawnable: bool,
+    pub num_windows: usize,
+    pub num_tabs: usize,
+    pub num_panes: usize,
+    /// The sum of the resource usage of the panes in the domain,
+    /// if resource sampling is enabled
+    pub resource_usage: Option<PaneResourceUsage>,
+    /// The kind of config entry that defines the domain
+    pub kind: Option<DomainKind>,
+    /// If another domain has the same name and takes precedence, the
+    /// id of that domain; this domain cannot be resolved by name
+    pub shadowed_by: Option<DomainId>,
+    /// True if the domain is left out of the launcher
+    #[dynamic(default)]
+    pub hidden: bool,
+    /// How many spawns into the domain are waiting for its
+    /// `max_concurrent_spawns` to allow them
+    #[dynamic(default)]
+    pub queued_spawns: usize,
+    /// See `Domain::pty_quirks`
+    #[dynamic(default)]
+    pub pty_quirks: Option<String>,
+}
+luahelper::impl_lua_conversion_dynamic!(DomainInfo);
+
+impl DomainInfo {
+    /// Must be called on the mux thread
+    pub async fn new(domain: &Arc<dyn Domain>) -> Self {
+        let mux = Mux::get().unwrap();
+        let inventory = mux.domain_inventory(domain.domain_id());
+        Self {
+            domain_id: domain.domain_id(),
+            name: domain.domain_name().to_string(),
+            label: domain.domain_label().await,
+            state: domain.state(),
+            spawnable: domain.spawnable(),
+            num_windows: inventory.num_windows,
+            num_tabs: inventory.num_tabs,
+            num_panes: inventory.num_panes,
+            resource_usage: mux.domain_resource_usage(domain.domain_id()),
+            kind: domain.domain_kind(),
+            shadowed_by: mux.domain_shadowed_by(domain.domain_id()),
+            hidden: domain.launcher_hints().hidden,
+            queued_spawns: mux.queued_spawns(domain.domain_id()),
+            pty_quirks: domain.pty_quirks(),
+        }
+    }
+}
+
+/// An action, other than spawning, that a domain offers in the
+/// launcher and to lua via `MuxDomain:perform_action`
+#[derive(Debug, Clone, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct PaletteEntry {
+    /// The text shown to the user
+    pub label: String,
+    /// An optional glyph shown before the label
+    #[dynamic(default)]
+    pub icon: Option<String>,
+    /// Passed to `Domain::perform_palette_action` to run the action
+    pub action: String,
+}
+luahelper::impl_lua_conversion_dynamic!(PaletteEntry);
+
+impl PaletteEntry {
+    pub fn new(label: &str, action: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            icon: None,
+            action: action.to_string(),
+        }
+    }
+}
+
 pub fn alloc_domain_id() -> DomainId {
-    DOMAIN_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed)
+    let id = DOMAIN_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+    crate::ids::note_id_allocated();
+    id
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SplitSource {
     Spawn {
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
+        command_dir: Option<CommandDir>,
     },
     MovePane(PaneId),
 }
 
+/// Where a pane is about to be moved to; see `Domain::authorize_move`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveDestination {
+    /// Into a split of the pane `pane_id` of the tab `tab_id`; that
+    /// pane belongs to the domain `domain_id`
+    Split {
+        tab_id: TabId,
+        pane_id: PaneId,
+        domain_id: DomainId,
+    },
+    /// Into a tab of its own in the window `window_id`, or in a new
+    /// window if it is None
+    NewTab { window_id: Option<WindowId> },
+    /// Along with the rest of the tab `tab_id`, into the window `window_id`
+    Window { tab_id: TabId, window_id: WindowId },
+}
+
+/// The reason that a domain gave for refusing to let a pane be moved
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{reason}")]
+pub struct MoveDenied {
+    pub reason: String,
+}
+
+impl MoveDenied {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Explains why a domain operation failed.
+/// Each variant has a stable numeric code that is sent to clients of
+/// the mux server in a `DomainErrorResponse`, so that scripts can tell
+/// the failures apart without parsing the message.
+#[derive(Debug, thiserror::Error)]
+pub enum DomainError {
+    #[error("domain {domain} is detached")]
+    DomainDetached { domain: String },
+    #[error("domain {domain} not found")]
+    DomainNotFound { domain: String },
+    /// The domain failed to carry out the operation; `source` explains
+    /// why.  This is also the result of converting an `anyhow::Error`
+    /// that doesn't wrap a `DomainError`.
+    #[error(transparent)]
+    SpawnFailed { source: anyhow::Error },
+    #[error(
+        "not enough space to split the pane: \
+         {needed} cells are needed but only {available} are available"
+    )]
+    SplitTooSmall { needed: usize, available: usize },
+    #[error("permission denied: {reason}")]
+    PermissionDenied { reason: String },
+    #[error("{reason}")]
+    Timeout { reason: String },
+    #[error("attaching domain {domain} was cancelled")]
+    AttachCancelled { domain: String },
+    /// The domain calls lua functions from the configuration, but
+    /// there is no lua context because the configuration failed to load
+    #[error(
+        "domain {domain} cannot be used because the configuration \
+         failed to load; fix the configuration and reload it"
+    )]
+    ConfigUnavailable { domain: String },
+    /// A domain operation failed in a mux server; `code` is the code
+    /// of the error that it reported
+    /// The spawn would exceed one of the limits on programmatic spawns;
+    /// see `Mux::check_spawn_quota`
+    #[error("spawn refused by the {limit} limit: {reason}")]
+    QuotaExceeded { limit: SpawnLimit, reason: String },
+    /// A domain refused to let a pane be moved; see
+    /// `Domain::authorize_move`
+    #[error("domain {domain} refused to move pane {pane_id}: {reason}")]
+    MoveDenied {
+        domain: String,
+        pane_id: PaneId,
+        reason: String,
+    },
+    #[error("{message}")]
+    RemoteError { code: u32, message: String },
+}
+
+pub type DomainResult<T> = Result<T, DomainError>;
+
+impl DomainError {
+    pub const DOMAIN_DETACHED: u32 = 1;
+    pub const DOMAIN_NOT_FOUND: u32 = 2;
+    pub const SPAWN_FAILED: u32 = 3;
+    pub const SPLIT_TOO_SMALL: u32 = 4;
+    pub const PERMISSION_DENIED: u32 = 5;
+    pub const TIMEOUT: u32 = 6;
+    pub const ATTACH_CANCELLED: u32 = 7;
+    pub const CONFIG_UNAVAILABLE: u32 = 8;
+    pub const QUOTA_EXCEEDED: u32 = 9;
+    pub const MOVE_DENIED: u32 = 10;
+
+    /// Returns the stable numeric code for this error.
+    /// Failures that wrap a more specific `DomainError` report the
+    /// code of that error.
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::DomainDetached { .. } => Self::DOMAIN_DETACHED,
+            Self::DomainNotFound { .. } => Self::DOMAIN_NOT_FOUND,
+            Self::SpawnFailed { source } => source
+                .chain()
+                .find_map(|err| err.downcast_ref::<DomainError>())
+                .map(|err| err.code())
+                .unwrap_or(Self::SPAWN_FAILED),
+            Self::SplitTooSmall { .. } => Self::SPLIT_TOO_SMALL,
+            Self::PermissionDenied { .. } => Self::PERMISSION_DENIED,
+            Self::Timeout { .. } => Self::TIMEOUT,
+            Self::AttachCancelled { .. } => Self::ATTACH_CANCELLED,
+            Self::ConfigUnavailable { .. } => Self::CONFIG_UNAVAILABLE,
+            Self::QuotaExceeded { .. } => Self::QUOTA_EXCEEDED,
+            Self::MoveDenied { .. } => Self::MOVE_DENIED,
+            Self::RemoteError { code, .. } => *code,
+        }
+    }
+
+    /// Returns the name of the variant that has the code `code`, or
+    /// `RemoteError` if the code is not known to this version
+    pub fn kind_for_code(code: u32) -> &'static str {
+        match code {
+            Self::DOMAIN_DETACHED => "DomainDetached",
+            Self::DOMAIN_NOT_FOUND => "DomainNotFound",
+            Self::SPAWN_FAILED => "SpawnFailed",
+            Self::SPLIT_TOO_SMALL => "SplitTooSmall",
+            Self::PERMISSION_DENIED => "PermissionDenied",
+            Self::TIMEOUT => "Timeout",
+            Self::ATTACH_CANCELLED => "AttachCancelled",
+            Self::CONFIG_UNAVAILABLE => "ConfigUnavailable",
+            Self::QUOTA_EXCEEDED => "QuotaExceeded",
+            Self::MOVE_DENIED => "MoveDenied",
+            _ => "RemoteError",
+        }
+    }
+
+    /// Returns the name of the kind of error, which is the same for
+    /// an error and its counterpart that is received from a mux server
+    pub fn kind(&self) -> &'static str {
+        Self::kind_for_code(self.code())
+    }
+
+    /// Returns the outermost `DomainError` in the chain of `err`
+    pub fn find(err: &anyhow::Error) -> Option<&DomainError> {
+        err.chain()
+            .find_map(|err| err.downcast_ref::<DomainError>())
+    }
+}
+
+impl From<anyhow::Error> for DomainError {
+    fn from(err: anyhow::Error) -> Self {
+        // Unwrap a DomainError that passed through an anyhow::Result,
+        // unless context was added to it that would be lost
+        let is_domain_error = err
+            .chain()
+            .next()
+            .map(|err| err.is::<DomainError>())
+            .unwrap_or(false);
+        if !is_domain_error {
+            return Self::SpawnFailed { source: err };
+        }
+        match err.downcast::<DomainError>() {
+            Ok(err) => err,
+            Err(source) => Self::SpawnFailed { source },
+        }
+    }
+}
+
+impl From<SplitError> for DomainError {
+    fn from(err: SplitError) -> Self {
+        match err {
+            SplitError::TooSmall { needed, available } => Self::SplitTooSmall { needed, available },
+            err => Self::SpawnFailed { source: err.into() },
+        }
+    }
+}
+
+/// The outcome of `Domain::map_cwd`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CwdMapping {
+    /// Spawn the command in this directory
+    Use(CommandDir),
+    /// The directory is meaningless to the domain, for the given
+    /// reason, so the command is spawned in its default directory
+    Dropped(String),
+}
+
+/// The default implementation of `Domain::map_cwd` for the domain
+/// `domain_id`, which accepts only explicit directories and those of
+/// the panes of that same domain
+pub fn map_cwd_within_domain(
+    domain_id: DomainId,
+    domain_name: &str,
+    dir: &CommandDir,
+    from_domain: Option<&Arc<dyn Domain>>,
+) -> CwdMapping {
+    match from_domain {
+        Some(from) if from.domain_id() != domain_id => CwdMapping::Dropped(format!(
+            "it is the cwd of a pane in the `{}` domain, which is not \
+             meaningful in the `{}` domain",
+            from.domain_name(),
+            domain_name
+        )),
+        _ => CwdMapping::Use(dir.clone()),
+    }
+}
+
 #[async_trait(?Send)]
 pub trait Domain: Downcast {
     /// Spawn a new command within this domain
@@ -51,87 +370,189 @@ pub trait Domain: Downcast {
         &self,
         size: TerminalSize,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
+        command_dir: Option<CommandDir>,
         window: WindowId,
-    ) -> anyhow::Result<Rc<Tab>> {
-        let pane = self.spawn_pane(size, command, command_dir).await?;
+    ) -> DomainResult<Rc<Tab>> {
+        let tab = self.spawn_detached_tab(size, command, command_dir).await?;
+
+        let mux = Mux::get().unwrap();
+        let _span = spawntiming::span("attach_window");
+        if let Err(err) = mux.attach_tab_to_window(&tab, window, None) {
+            mux.remove_tab(tab.tab_id());
+            return Err(err.into());
+        }
 
+        Ok(tab)
+    }
+
+    /// Spawn a new command within this domain, in a new tab that is
+    /// registered with the mux but not added to any window.
+    /// The tab can be added to a window later using
+    /// `Mux::attach_tab_to_window`; until then it is listed by
+    /// `Mux::iter_detached_tabs` and can be killed via `Mux::remove_tab`.
+    async fn spawn_detached_tab(
+        &self,
+        size: TerminalSize,
+        command: Option<CommandBuilder>,
+        command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<Tab>> {
+        let span = spawntiming::span("spawn_pane");
+        let pane = self
+            .spawn_pane(alloc_pane_id(), size, command, command_dir)
+            .await?;
+        drop(span);
+
+        let _span = spawntiming::span("register_tab");
         let tab = Rc::new(Tab::new(&size));
         tab.assign_pane(&pane);
 
         let mux = Mux::get().unwrap();
         mux.add_tab_and_active_pane(&tab)?;
-        mux.add_tab_to_window(&tab, window)?;
 
         Ok(tab)
     }
 
+    /// Splits the pane `pane_id` of `tab`, placing the pane that
+    /// `source` describes in the new split.
+    /// If `source` spawns a pane, it is given the id `new_pane_id`.
     async fn split_pane(
         &self,
         source: SplitSource,
         tab: TabId,
         pane_id: PaneId,
         split_request: SplitRequest,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
+        new_pane_id: PaneId,
+    ) -> DomainResult<Rc<dyn Pane>> {
         let mux = Mux::get().unwrap();
         let tab = match mux.get_tab(tab) {
             Some(t) => t,
-            None => anyhow::bail!("Invalid tab id {}", tab),
+            None => return Err(anyhow::anyhow!("Invalid tab id {}", tab).into()),
         };
 
-        let pane_index = match tab
+        let find_pane_index = || match tab
             .iter_panes()
             .iter()
             .find(|p| p.pane.pane_id() == pane_id)
         {
-            Some(p) => p.index,
-            None => anyhow::bail!("invalid pane id {}", pane_id),
-        };
-
-        let split_size = match tab.compute_split_size(pane_index, split_request) {
-            Some(s) => s,
-            None => anyhow::bail!("invalid pane index {}", pane_index),
+            Some(p) => Ok(p.index),
+            None => Err(anyhow::anyhow!("invalid pane id {}", pane_id)),
         };
+        // Fail before a pane is moved out of its tab
+        find_pane_index()?;
 
         let pane = match source {
             SplitSource::Spawn {
                 command,
                 command_dir,
             } => {
-                self.spawn_pane(split_size.second, command, command_dir)
+                let split_size = tab.compute_split_size(find_pane_index()?, split_request)?;
+                let _span = spawntiming::span("spawn_pane");
+                self.spawn_pane(new_pane_id, split_size.second, command, command_dir)
                     .await?
             }
             SplitSource::MovePane(src_pane_id) => {
-                let (_domain, _window, src_tab) = mux
-                    .resolve_pane_id(src_pane_id)
+                let pane = mux
+                    .get_pane(src_pane_id)
                     .ok_or_else(|| anyhow::anyhow!("pane {} not found", src_pane_id))?;
-                let src_tab = match mux.get_tab(src_tab) {
-                    Some(t) => t,
-                    None => anyhow::bail!("Invalid tab id {}", src_tab),
-                };
+                let target = mux
+                    .get_pane(pane_id)
+                    .ok_or_else(|| anyhow::anyhow!("pane {} not found", pane_id))?;
+                mux.authorize_move(
+                    src_pane_id,
+                    MoveDestination::Split {
+                        tab_id: tab.tab_id(),
+                        pane_id,
+                        domain_id: target.domain_id(),
+                    },
+                )?;
 
-                let pane = src_tab.remove_pane(src_pane_id).ok_or_else(|| {
-                    anyhow::anyhow!("pane {} not found in its containing tab!?", src_pane_id)
-                })?;
+                // Panes that were spawned without a window, or whose tab
+                // is detached, are moved from wherever they are; a pane
+                // that isn't in any tab has nothing to be removed from
+                if let Some(src_tab) = mux
+                    .resolve_pane_tab(src_pane_id)
+                    .and_then(|(_domain, tab_id)| mux.get_tab(tab_id))
+                {
+                    let had_window = mux.window_containing_tab(src_tab.tab_id()).is_some();
+                    // The focus of the source tab moves to the neighbor
+                    // that takes over the space of the pane
+                    let (_pane, focus) = src_tab
+                        .remove_pane_with_focus_hint(src_pane_id)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "pane {} not found in its containing tab!?",
+                                src_pane_id
+                            )
+                        })?;
+                    log::debug!(
+                        "moved pane {} out of tab {}; focus is now on {:?}",
+                        src_pane_id,
+                        src_tab.tab_id(),
+                        focus.map(|p| p.pane_id())
+                    );
 
-                if src_tab.is_dead() {
-                    mux.remove_tab(src_tab.tab_id());
+                    if had_window && src_tab.is_dead() {
+                        mux.remove_tab(src_tab.tab_id());
+                    }
                 }
 
                 pane
             }
         };
 
-        tab.split_and_insert(pane_index, split_request, Rc::clone(&pane))?;
+        // The index is resolved after moving the pane, as the move
+        // renumbers the panes when it is taken from the same tab
+        let _span = spawntiming::span("insert_split");
+        tab.split_and_insert(find_pane_index()?, split_request, Rc::clone(&pane))?;
         Ok(pane)
     }
 
+    /// Returns where the pane `pane_id` of `tab` and the new pane would
+    /// be if the pane was split as `split_request` describes, without
+    /// splitting it, or None if it cannot be split that way.
+    /// See `Tab::preview_split`.
+    async fn preview_split(
+        &self,
+        tab: TabId,
+        pane_id: PaneId,
+        split_request: SplitRequest,
+    ) -> anyhow::Result<Option<SplitPreview>> {
+        let mux = Mux::get().unwrap();
+        let tab = mux
+            .get_tab(tab)
+            .ok_or_else(|| anyhow::anyhow!("Invalid tab id {}", tab))?;
+        let pane_index = tab
+            .iter_panes_ignoring_zoom()
+            .iter()
+            .find(|p| p.pane.pane_id() == pane_id)
+            .map(|p| p.index)
+            .ok_or_else(|| anyhow::anyhow!("invalid pane id {}", pane_id))?;
+        Ok(tab.preview_split(pane_index, split_request))
+    }
+
+    /// Spawns a pane whose id is `pane_id`.
+    /// The caller allocates the id, normally via `alloc_pane_id`, so
+    /// that a pane can also be created with a specific id, such as that
+    /// of a pane that is being adopted from another process.
     async fn spawn_pane(
         &self,
+        pane_id: PaneId,
         size: TerminalSize,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>>;
+        command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>>;
+
+    /// Decides whether `dir`, the working directory that a command
+    /// spawned into this domain is intended to have, is meaningful to
+    /// this domain.
+    /// `from_domain` is the domain of the pane whose cwd `dir` is, or
+    /// None if `dir` was requested explicitly.
+    /// The default accepts an explicit directory, or the cwd of a pane
+    /// in this same domain; domains that know how to map the paths of
+    /// other domains override this.
+    fn map_cwd(&self, dir: &CommandDir, from_domain: Option<&Arc<dyn Domain>>) -> CwdMapping {
+        map_cwd_within_domain(self.domain_id(), self.domain_name(), dir, from_domain)
+    }
 
     /// Returns false if the `spawn` method will never succeed.
     /// There are some internal placeholder domains that are
@@ -141,6 +562,20 @@ pub trait Domain: Downcast {
         true
     }
 
+    /// Returns how the domain is presented in the launcher.
+    /// Unlike `spawnable`, this has no bearing on whether the domain
+    /// can be spawned into by name.
+    fn launcher_hints(&self) -> LauncherHints {
+        LauncherHints::default()
+    }
+
+    /// Returns the palette overrides that are applied to the panes
+    /// that are spawned into this domain; see
+    /// `Pane::set_palette_overrides`
+    fn palette_overrides(&self) -> Option<Palette> {
+        configuration().domain_palette_overrides(self.domain_name())
+    }
+
     /// Returns the domain id, which is useful for obtaining
     /// a handle on the domain later.
     fn domain_id(&self) -> DomainId;
@@ -149,31 +584,177 @@ pub trait Domain: Downcast {
     /// Should be a short identifier.
     fn domain_name(&self) -> &str;
 
+    /// Returns the kind of config entry that defines the domain, or
+    /// None for internal domains that are not defined by the config
+    fn domain_kind(&self) -> Option<DomainKind> {
+        None
+    }
+
+    /// Describes the pty implementation that backs panes spawned into
+    /// the domain and the workarounds that are active for it, when
+    /// those are worth reporting, such as for ConPTY on Windows
+    fn pty_quirks(&self) -> Option<String> {
+        None
+    }
+
     /// Returns a label describing the domain.
     async fn domain_label(&self) -> String {
         self.domain_name().to_string()
     }
 
     /// Re-attach to any tabs that might be pre-existing in this domain
-    async fn attach(&self, window_id: Option<WindowId>) -> anyhow::Result<()>;
+    async fn attach(&self, window_id: Option<WindowId>) -> DomainResult<()>;
 
     /// Detach all tabs
-    fn detach(&self) -> anyhow::Result<()>;
+    fn detach(&self) -> DomainResult<()>;
 
     /// Indicates the state of the domain
     fn state(&self) -> DomainState;
 
+    /// Returns the number of panes that have been synchronized since
+    /// the domain was attached, and the total number of panes, while
+    /// panes are still being synchronized in the background
+    fn sync_progress(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Returns the policy for showing desktop notifications that
+    /// are emitted by panes in this domain
+    fn notification_handling(&self) -> NotificationHandling {
+        configuration().notification_handling
+    }
+
+    /// Returns the actions that this domain currently offers, beyond
+    /// spawning panes.  This is called each time the entries are
+    /// presented, so the result may depend on the state of the domain.
+    fn palette_entries(&self) -> Vec<PaletteEntry> {
+        vec![]
+    }
+
+    /// Performs the action identified by the `action` field of
+    /// one of the entries returned by `palette_entries`
+    async fn perform_palette_action(&self, action: &str) -> anyhow::Result<()> {
+        bail!(
+            "domain {} has no action named {}",
+            self.domain_name(),
+            action
+        );
+    }
+
+    /// Lists the directory `dir` as the panes of this domain see it,
+    /// returning at most `limit` entries.  This is used to complete
+    /// the working directory of a command that is about to be spawned.
+    async fn list_directory(&self, _dir: &str, _limit: usize) -> anyhow::Result<DirListing> {
+        bail!("domain {} cannot list directories", self.domain_name());
+    }
+
+    /// Runs a short command in this domain without creating a pane,
+    /// and captures its output; see `crate::domainexec`
+    async fn exec(&self, _request: ExecRequest) -> anyhow::Result<ExecOutput> {
+        bail!("domain {} cannot run commands", self.domain_name());
+    }
+
+    /// Decides whether the pane `pane`, which is either one of the
+    /// panes of this domain or is being moved next to one of them, may
+    /// be moved to `destination`.
+    /// It is consulted before anything is changed, so a move that is
+    /// denied leaves the panes where they were.  The default allows
+    /// every move.
+    fn authorize_move(
+        &self,
+        _pane: PaneId,
+        _destination: MoveDestination,
+    ) -> Result<(), MoveDenied> {
+        Ok(())
+    }
+
+    /// Returns what happens to the panes of this domain when a local
+    /// window that contains them is closed
+    fn window_close_policy(&self) -> ClosePolicy {
+        ClosePolicy::Kill
+    }
+
+    /// Returns what happens to the panes of this domain when a local
+    /// tab that contains them is closed
+    fn tab_close_policy(&self) -> ClosePolicy {
+        ClosePolicy::Kill
+    }
+
     /// Called to advise the domain that a local window is closing.
-    /// This allows the domain the opportunity to eg: detach/hide
-    /// its tabs/panes rather than actually killing them off
-    fn local_window_is_closing(&self, _window_id: WindowId) {}
+    /// When `action` is `CloseAction::Detach`, the domain should
+    /// detach/hide its tabs/panes rather than actually killing them off
+    fn local_window_is_closing(&self, _window_id: WindowId, _action: CloseAction) {}
+
+    /// Called to advise the domain that a local tab is closing; the
+    /// counterpart of `local_window_is_closing` for a single tab
+    fn tab_is_closing(&self, _tab_id: TabId, _action: CloseAction) {}
+
+    /// Has the server of this domain, which has all of the panes of
+    /// `scope`, close it once it is confirmed with every client that
+    /// is attached to it, and returns the outcome.
+    /// Returns None if the close is confirmed in this mux, which is
+    /// the default.
+    async fn confirm_close(&self, _scope: CloseScope) -> anyhow::Result<Option<CloseOutcome>> {
+        Ok(None)
+    }
+
+    /// Answers the request `id` of the server of this domain, which
+    /// this mux mirrors; see `Domain::confirm_close`
+    async fn answer_close_confirmation(
+        &self,
+        _id: CloseRequestId,
+        _confirmed: bool,
+    ) -> anyhow::Result<()> {
+        bail!(
+            "domain {} has no server to answer close requests",
+            self.domain_name()
+        );
+    }
 }
 impl_downcast!(Domain);
 
+/// What is done with the panes of a domain when a window or tab that
+/// contains them is closed; the resolved form of a `ClosePolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseAction {
+    Kill,
+    Detach,
+}
+
+/// The answers given by the user for the domains whose `ClosePolicy`
+/// is `Ask`
+pub type CloseDecisions = HashMap<DomainId, CloseAction>;
+
+impl CloseAction {
+    /// Resolves `policy` for `domain_id`.  The mux only closes a window
+    /// or tab once each domain that asks and has panes in it has been
+    /// answered; for the other domains, `Ask` detaches, which leaves
+    /// nothing to undo.
+    pub fn resolve(policy: ClosePolicy, domain_id: DomainId, decisions: &CloseDecisions) -> Self {
+        match policy {
+            ClosePolicy::Kill => Self::Kill,
+            ClosePolicy::Detach => Self::Detach,
+            ClosePolicy::Ask => decisions.get(&domain_id).copied().unwrap_or(Self::Detach),
+        }
+    }
+}
+
+/// The distribution and user of a wsl domain, as computed when the
+/// config had the given generation
+struct WslIdentity {
+    generation: usize,
+    distribution: String,
+    username: Option<String>,
+}
+
 pub struct LocalDomain {
     pty_system: Box<dyn PtySystem>,
     id: DomainId,
     name: String,
+    kind: DomainKind,
+    docker: Option<DockerDomain>,
+    prewarm: RefCell<PrewarmPool>,
+    wsl_identity: RefCell<Option<WslIdentity>>,
 }
 
 impl LocalDomain {
@@ -181,16 +762,32 @@ impl LocalDomain {
         Ok(Self::with_pty_system(name, native_pty_system()))
     }
 
-    fn resolve_exec_domain(&self) -> Option<ExecDomain> {
-        config::configuration()
+    fn with_kind(name: &str, kind: DomainKind) -> Self {
+        let mut domain = Self::with_pty_system(name, native_pty_system());
+        domain.kind = kind;
+        domain
+    }
+
+    /// Only an exec domain is configured by `exec_domains`, even if
+    /// another kind of domain has the same name
+    fn resolve_exec_domain(&self, config: &ConfigHandle) -> Option<ExecDomain> {
+        if self.kind != DomainKind::Exec {
+            return None;
+        }
+        config
             .exec_domains
             .iter()
             .find(|ed| ed.name == self.name)
             .cloned()
     }
 
-    fn resolve_wsl_domain(&self) -> Option<WslDomain> {
-        config::configuration()
+    /// Only a wsl domain is configured by `wsl_domains`, even if
+    /// another kind of domain has the same name
+    fn resolve_wsl_domain(&self, config: &ConfigHandle) -> Option<WslDomain> {
+        if self.kind != DomainKind::Wsl {
+            return None;
+        }
+        config
             .wsl_domains
             .iter()
             .find(|d| d.name == self.name)
@@ -203,15 +800,140 @@ impl LocalDomain {
             pty_system,
             id,
             name: name.to_string(),
+            kind: DomainKind::Local,
+            docker: None,
+            prewarm: RefCell::new(PrewarmPool::default()),
+            wsl_identity: RefCell::new(None),
+        }
+    }
+
+    /// Returns the distribution and user of a wsl domain.  Either may
+    /// be computed by a lua function, in which case the result is kept
+    /// until the config is reloaded.  When the distribution cannot be
+    /// computed, the name of the domain is used.
+    async fn resolve_wsl_identity(
+        &self,
+        config: &ConfigHandle,
+        wsl: &WslDomain,
+    ) -> (String, Option<String>) {
+        if let Some(identity) = self.wsl_identity.borrow().as_ref() {
+            if identity.generation == config.generation() {
+                return (identity.distribution.clone(), identity.username.clone());
+            }
+        }
+
+        let has_func = |value: &Option<ValueOrFunc>| matches!(value, Some(ValueOrFunc::Func(_)));
+        let distros = if has_func(&wsl.distribution) || has_func(&wsl.username) {
+            smol::unblock(WslDistro::load_distro_list)
+                .await
+                .unwrap_or_else(|err| {
+                    log::warn!("Unable to list the WSL distributions: {:#}", err);
+                    vec![]
+                })
+        } else {
+            vec![]
+        };
+
+        let distribution = self
+            .eval_wsl_field("distribution", &wsl.distribution, &distros)
+            .await
+            .unwrap_or_else(|| wsl.name.clone());
+        let username = self
+            .eval_wsl_field("username", &wsl.username, &distros)
+            .await;
+
+        self.wsl_identity.borrow_mut().replace(WslIdentity {
+            generation: config.generation(),
+            distribution: distribution.clone(),
+            username: username.clone(),
+        });
+        (distribution, username)
+    }
+
+    /// Evaluates the `distribution` or `username` of a wsl domain.
+    /// A lua function is passed the name of the domain and the list of
+    /// registered distributions.  If it fails or returns nil, a warning
+    /// is logged and None is returned.
+    async fn eval_wsl_field(
+        &self,
+        field: &str,
+        value: &Option<ValueOrFunc>,
+        distros: &[WslDistro],
+    ) -> Option<String> {
+        let func = match value {
+            None => return None,
+            Some(ValueOrFunc::Value(wezterm_dynamic::Value::String(s))) => return Some(s.clone()),
+            Some(ValueOrFunc::Value(other)) => {
+                log::warn!(
+                    "The {} of wsl domain `{}` must be a string or a function, not {}",
+                    field,
+                    self.name,
+                    other.variant_name()
+                );
+                return None;
+            }
+            Some(ValueOrFunc::Func(func)) => func.clone(),
+        };
+
+        let name = self.name.clone();
+        let distros = distros.to_vec();
+        let result = config::with_lua_config_on_lua_thread(move |lua| async move {
+            let lua = lua.ok_or_else(|| anyhow::anyhow!("missing lua context"))?;
+            let value = config::lua::emit_async_callback(&*lua, (func, (name, distros))).await?;
+            let value: Option<String> =
+                luahelper::from_lua_value_dynamic(value).map_err(anyhow::Error::from)?;
+            Ok(value)
+        })
+        .await;
+
+        match result {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                log::warn!(
+                    "The {} function of wsl domain `{}` returned nil",
+                    field,
+                    self.name
+                );
+                None
+            }
+            Err(err) => {
+                log::warn!(
+                    "Error while calling the {} function of wsl domain `{}`: {:#}",
+                    field,
+                    self.name,
+                    err
+                );
+                None
+            }
         }
     }
 
     pub fn new_wsl(wsl: WslDomain) -> Result<Self, Error> {
-        Self::new(&wsl.name)
+        Ok(Self::with_kind(&wsl.name, DomainKind::Wsl))
     }
 
     pub fn new_exec_domain(exec_domain: ExecDomain) -> anyhow::Result<Self> {
-        Self::new(&exec_domain.name)
+        Ok(Self::with_kind(&exec_domain.name, DomainKind::Exec))
+    }
+
+    pub fn new_docker(docker: DockerDomain) -> anyhow::Result<Self> {
+        let mut domain = Self::with_kind(&docker.name, DomainKind::Docker);
+        domain.docker.replace(docker);
+        Ok(domain)
+    }
+
+    /// If this domain is connected to a serial port, change its settings
+    pub fn set_serial_params(
+        &self,
+        params: &portable_pty::serial::SerialParams,
+    ) -> anyhow::Result<()> {
+        match self
+            .pty_system
+            .downcast_ref::<portable_pty::serial::SerialTty>()
+        {
+            Some(serial) => serial.set_params(params),
+            None => bail!("domain {} is not connected to a serial port", self.name),
+        }
     }
 
     #[cfg(unix)]
@@ -226,8 +948,45 @@ impl LocalDomain {
             .is_some()
     }
 
-    async fn fixup_command(&self, cmd: &mut CommandBuilder) -> anyhow::Result<()> {
-        if let Some(wsl) = self.resolve_wsl_domain() {
+    #[cfg(unix)]
+    fn configure_conpty(&self, _config: &ConfigHandle) {}
+
+    #[cfg(windows)]
+    fn configure_conpty(&self, config: &ConfigHandle) {
+        if self.is_conpty() {
+            portable_pty::win::conpty::set_resize_debounce(std::time::Duration::from_millis(
+                config.conpty_resize_debounce_ms,
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    fn describe_conpty(&self) -> Option<String> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn describe_conpty(&self) -> Option<String> {
+        if self.is_conpty() {
+            Some(portable_pty::win::conpty::describe_conpty())
+        } else {
+            None
+        }
+    }
+
+    async fn fixup_command(
+        &self,
+        config: &ConfigHandle,
+        cmd: &mut CommandBuilder,
+    ) -> anyhow::Result<()> {
+        let _span = spawntiming::span("fixup_command");
+        if let Some(docker) = &self.docker {
+            clear_unsafe_cwd(cmd);
+            let argv = crate::docker::docker_exec_argv(&config.docker_command, docker, cmd, true);
+            cmd.clear_cwd();
+            *cmd.get_argv_mut() = argv;
+        } else if let Some(wsl) = self.resolve_wsl_domain(config) {
+            clear_unsafe_cwd(cmd);
             let mut args: Vec<OsString> = cmd.get_argv().clone();
 
             if args.is_empty() {
@@ -238,13 +997,11 @@ impl LocalDomain {
                 }
             }
 
+            let (distribution, username) = self.resolve_wsl_identity(config, &wsl).await;
             let mut argv: Vec<OsString> = vec![
                 "wsl.exe".into(),
                 "--distribution".into(),
-                wsl.distribution
-                    .as_deref()
-                    .unwrap_or(wsl.name.as_str())
-                    .into(),
+                distribution.into(),
             ];
 
             if let Some(cwd) = cmd.get_cwd() {
@@ -252,7 +1009,7 @@ impl LocalDomain {
                 argv.push(cwd.into());
             }
 
-            if let Some(user) = &wsl.username {
+            if let Some(user) = username {
                 argv.push("--user".into());
                 argv.push(user.into());
             }
@@ -269,7 +1026,8 @@ impl LocalDomain {
 
             cmd.clear_cwd();
             *cmd.get_argv_mut() = argv;
-        } else if let Some(ed) = self.resolve_exec_domain() {
+        } else if let Some(ed) = self.resolve_exec_domain(config) {
+            clear_unsafe_cwd(cmd);
             let mut args = vec![];
             let mut set_environment_variables = HashMap::new();
             for arg in cmd.get_argv() {
@@ -282,36 +1040,68 @@ impl LocalDomain {
             for (k, v) in cmd.iter_full_env_as_str() {
                 set_environment_variables.insert(k.to_string(), v.to_string());
             }
-            let cwd = match cmd.get_cwd() {
-                Some(cwd) => Some(PathBuf::from(cwd)),
-                None => None,
-            };
+            let cwd = cmd
+                .get_cwd()
+                .map(|cwd| CommandDir::new(cwd.to_string_lossy()));
             let spawn_command = SpawnCommand {
-                label: None,
+                label: cmd.get_label().map(|label| label.to_string()),
                 domain: SpawnTabDomain::DomainName(ed.name.clone()),
                 args: if args.is_empty() { None } else { Some(args) },
                 set_environment_variables,
                 cwd,
+                reuse: None,
+                prompt_args: vec![],
+                exit_behavior: cmd
+                    .get_exit_behavior()
+                    .and_then(|behavior| behavior.parse().ok()),
+                window: None,
+                no_domain_rules: false,
             };
 
-            let spawn_command = config::with_lua_config_on_main_thread(|lua| async {
-                let lua = lua.ok_or_else(|| anyhow::anyhow!("missing lua context"))?;
-                let value = config::lua::emit_async_callback(
-                    &*lua,
-                    (ed.fixup_command.clone(), (spawn_command.clone())),
-                )
-                .await?;
-                let cmd: SpawnCommand =
-                    luahelper::from_lua_value_dynamic(value).with_context(|| {
-                        format!(
-                            "interpreting SpawnCommand result from ExecDomain {}",
-                            ed.name
-                        )
-                    })?;
+            // The fixup is evaluated on the lua executor thread so that
+            // a slow callback doesn't block the GUI while we wait for it;
+            // only attaching the resulting tab happens on the main thread.
+            let fixup_command = ed.fixup_command.clone();
+            let name = ed.name.clone();
+            let sandbox_limits = if config.sandbox_exec_domains {
+                Some(config.sandbox_limits())
+            } else {
+                None
+            };
+            let spawn_command = config::with_lua_config_on_lua_thread(move |lua| async move {
+                let lua = lua.ok_or_else(|| DomainError::ConfigUnavailable {
+                    domain: name.clone(),
+                })?;
+                let cmd: SpawnCommand = match sandbox_limits {
+                    Some(limits) => {
+                        let value = config::sandbox::call_exec_domain_callback(
+                            &*lua,
+                            &name,
+                            &fixup_command,
+                            spawn_command,
+                            limits,
+                        )?;
+                        SpawnCommand::from_dynamic(&value, Default::default())
+                            .map_err(anyhow::Error::from)
+                    }
+                    None => {
+                        let value =
+                            config::lua::emit_async_callback(&*lua, (fixup_command, spawn_command))
+                                .await?;
+                        luahelper::from_lua_value_dynamic(value).map_err(anyhow::Error::from)
+                    }
+                }
+                .with_context(|| {
+                    format!("interpreting SpawnCommand result from ExecDomain {}", name)
+                })?;
                 Ok(cmd)
             })
             .await
-            .with_context(|| format!("calling ExecDomain {} function", ed.name))?;
+            .map_err(|err| match DomainError::find(&err) {
+                // Already explains itself, and keeps its code
+                Some(DomainError::ConfigUnavailable { .. }) => err,
+                _ => err.context(format!("calling ExecDomain {} function", ed.name)),
+            })?;
 
             // Reinterpret the SpawnCommand into the builder
 
@@ -327,7 +1117,13 @@ impl LocalDomain {
             }
             cmd.clear_cwd();
             if let Some(cwd) = &spawn_command.cwd {
-                cmd.cwd(cwd);
+                cmd.cwd(&cwd.path);
+            }
+            if let Some(label) = &spawn_command.label {
+                cmd.label(label);
+            }
+            if let Some(exit_behavior) = spawn_command.exit_behavior {
+                cmd.exit_behavior(exit_behavior.as_str());
             }
         } else if let Some(dir) = cmd.get_cwd() {
             // I'm not normally a fan of existence checking, but not checking here
@@ -349,70 +1145,142 @@ impl LocalDomain {
         Ok(())
     }
 
+    /// Returns true if panes in this domain should be spawned
+    /// in their own systemd scope
+    fn use_systemd_scope(&self, config: &ConfigHandle) -> bool {
+        self.resolve_exec_domain(config)
+            .and_then(|ed| ed.use_systemd_scope)
+            .unwrap_or(config.use_systemd_scope)
+    }
+
+    /// Returns whether the default program of this domain runs as a
+    /// login shell, if that was configured
+    fn login_shell(&self, config: &ConfigHandle) -> Option<bool> {
+        self.resolve_exec_domain(config)
+            .and_then(|ed| ed.login_shell)
+            .or(config.login_shell)
+    }
+
+    /// Returns the name of the config field that supplies the program
+    /// to run when no command is specified, if any
+    fn default_prog_field(&self, config: &ConfigHandle) -> Option<String> {
+        if let Some(docker) = &self.docker {
+            return docker
+                .default_prog
+                .as_ref()
+                .map(|_| format!("docker_domains[\"{}\"].default_prog", docker.name));
+        }
+        match self.resolve_wsl_domain(config) {
+            Some(wsl) => wsl
+                .default_prog
+                .as_ref()
+                .map(|_| format!("wsl_domains[\"{}\"].default_prog", wsl.name)),
+            None => config
+                .default_prog
+                .as_ref()
+                .map(|_| "default_prog".to_string()),
+        }
+    }
+
     async fn build_command(
         &self,
+        config: &ConfigHandle,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
-        pane_id: PaneId,
+        command_dir: Option<CommandDir>,
+        pane_id: Option<PaneId>,
     ) -> anyhow::Result<CommandBuilder> {
-        let config = configuration();
-        let mut cmd = match command {
-            Some(mut cmd) => {
-                config.apply_cmd_defaults(&mut cmd, config.default_cwd.as_ref());
-                cmd
-            }
-            None => {
-                let wsl = self.resolve_wsl_domain();
-                config.build_prog(
-                    None,
-                    wsl.as_ref()
-                        .map(|wsl| wsl.default_prog.as_ref())
-                        .unwrap_or(config.default_prog.as_ref()),
-                    wsl.as_ref()
-                        .map(|wsl| wsl.default_cwd.as_ref())
-                        .unwrap_or(config.default_cwd.as_ref()),
-                )?
-            }
+        let _span = spawntiming::span("build_command");
+        let command_dir = resolve_command_dir(command.as_ref(), command_dir);
+        let wsl = self.resolve_wsl_domain(config);
+        let defaults = match (&self.docker, &wsl) {
+            // The default_cwd is a path on the host, which is
+            // meaningless inside a container
+            (Some(docker), _) => SpawnDefaults {
+                default_prog: docker.default_prog.as_ref(),
+                default_cwd: None,
+                explicit_cwd: None,
+                cwd_expansion: CwdExpansion::Verbatim,
+                login_shell: None,
+            },
+            (None, Some(wsl)) => SpawnDefaults {
+                default_prog: wsl.default_prog.as_ref(),
+                default_cwd: wsl.default_cwd.as_ref(),
+                explicit_cwd: config.default_cwd.as_ref(),
+                cwd_expansion: CwdExpansion::Wsl,
+                login_shell: None,
+            },
+            (None, None) => SpawnDefaults {
+                default_prog: config.default_prog.as_ref(),
+                default_cwd: config.default_cwd.as_ref(),
+                explicit_cwd: config.default_cwd.as_ref(),
+                cwd_expansion: CwdExpansion::Local,
+                login_shell: self.login_shell(config),
+            },
         };
+        let mut cmd = resolve_spawn_command(config, command, &defaults)?;
         if let Some(dir) = command_dir {
-            cmd.cwd(dir);
+            // Paths in docker and WSL domains are inside the container
+            // or distribution, whose hostname doesn't match ours.
+            // Otherwise, a path on some other host, such as one reported
+            // by a shell that was started via ssh in the source pane,
+            // must not be confused with a local path.
+            if self.docker.is_some() || wsl.is_some() || dir.is_on_local_host() {
+                cmd.cwd(dir.path);
+            } else {
+                log::debug!(
+                    "not using cwd {} for the command we are spawning, \
+                     as it is on another host",
+                    dir
+                );
+            }
         }
-        cmd.env("WEZTERM_PANE", pane_id.to_string());
-        self.fixup_command(&mut cmd).await?;
+        match pane_id {
+            Some(pane_id) => cmd.env("WEZTERM_PANE", pane_id.to_string()),
+            // A prewarmed shell learns its pane id when it is claimed
+            None => cmd.env_remove("WEZTERM_PANE"),
+        }
+        self.fixup_command(config, &mut cmd).await?;
         Ok(cmd)
     }
-}
 
-#[async_trait(?Send)]
-impl Domain for LocalDomain {
-    async fn spawn_pane(
-        &self,
-        size: TerminalSize,
-        command: Option<CommandBuilder>,
-        command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        let pane_id = alloc_pane_id();
-        let cmd = self.build_command(command, command_dir, pane_id).await?;
-        let pair = self
-            .pty_system
-            .openpty(crate::terminal_size_to_pty_size(size)?)?;
+    /// Returns true if this domain keeps a pool of prewarmed shells.
+    /// Only plain local domains participate: the commands of the other
+    /// kinds either depend on the pane id or run somewhere that the
+    /// handshake cannot reach.
+    fn prewarm_enabled(&self, config: &ConfigHandle) -> bool {
+        config.prewarm_pane_pool_size > 0
+            && self.kind == DomainKind::Local
+            && config.prewarm_pane_domains.contains(&self.name)
+            && !self.use_systemd_scope(config)
+            && !self.resilient_enabled(config)
+            && self
+                .pty_system
+                .downcast_ref::<portable_pty::serial::SerialTty>()
+                .is_none()
+    }
 
-        let command_line = cmd
-            .as_unix_command_line()
-            .unwrap_or_else(|err| format!("error rendering command line: {:?}", err));
-        let command_description = format!(
-            "\"{}\" in domain \"{}\"",
-            if command_line.is_empty() {
-                cmd.get_shell()?
-            } else {
-                command_line
-            },
-            self.name
-        );
-        let child = pair.slave.spawn_command(cmd)?;
-        log::trace!("spawned: {:?}", child);
+    /// Whether new panes are spawned under a supervisor; see
+    /// `crate::supervisor`
+    fn resilient_enabled(&self, config: &ConfigHandle) -> bool {
+        cfg!(unix)
+            && config.resilient_local_panes
+            && self.kind == DomainKind::Local
+            && self
+                .pty_system
+                .downcast_ref::<portable_pty::serial::SerialTty>()
+                .is_none()
+    }
 
-        let writer = pair.master.try_clone_writer()?;
+    /// Builds the pane for a child that was spawned into `master`
+    fn new_local_pane(
+        &self,
+        pane_id: PaneId,
+        size: TerminalSize,
+        child: Box<dyn portable_pty::Child + Send + Sync>,
+        master: Box<dyn portable_pty::MasterPty>,
+        command_description: String,
+    ) -> anyhow::Result<LocalPane> {
+        let writer = master.try_clone_writer()?;
 
         let mut terminal = wezterm_term::Terminal::new(
             size,
@@ -425,53 +1293,570 @@ impl Domain for LocalDomain {
             terminal.enable_conpty_quirks();
         }
 
-        let pane: Rc<dyn Pane> = Rc::new(LocalPane::new(
+        let mut pane = LocalPane::new(
             pane_id,
             terminal,
             child,
-            pair.master,
+            master,
             self.id,
             command_description,
-        ));
-
-        let mux = Mux::get().unwrap();
-        mux.add_pane(&pane)?;
-
+        );
+        if self.docker.is_some() {
+            // The process in the pane is the docker cli; its cwd is
+            // on the host rather than in the container
+            pane.set_cwd_from_process(false);
+        }
         Ok(pane)
     }
 
-    fn domain_id(&self) -> DomainId {
-        self.id
-    }
+    /// Takes over `orphan`, a supervised pane whose owner has gone
+    /// away, as the pane `pane_id` of this domain.  The terminal state
+    /// is recreated from the output that the supervisor replays.
+    #[cfg(unix)]
+    pub fn adopt_pane(
+        &self,
+        pane_id: PaneId,
+        size: TerminalSize,
+        orphan: &crate::supervisor::OrphanedPane,
+    ) -> anyhow::Result<Rc<dyn Pane>> {
+        let (child, master) = orphan.attach(crate::terminal_size_to_pty_size(size)?)?;
+        let pane: Rc<dyn Pane> = Rc::new(self.new_local_pane(
+            pane_id,
+            size,
+            child,
+            master,
+            orphan.entry.command_description.clone(),
+        )?);
+        Mux::get().unwrap().add_pane(&pane)?;
+        Ok(pane)
+    }
+
+    fn prewarm_idle_timeout(config: &ConfigHandle) -> Option<Duration> {
+        match config.prewarm_pane_idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Arranges for the prewarm pool to be refilled once the current
+    /// spawn has completed
+    fn schedule_prewarm_refill(&self) {
+        let mut pool = self.prewarm.borrow_mut();
+        if pool.refill_scheduled {
+            return;
+        }
+        pool.refill_scheduled = true;
+        let domain_id = self.id;
+        promise::spawn::spawn(async move {
+            refill_prewarm_pool(domain_id).await;
+        })
+        .detach();
+    }
+
+    /// Arranges for idle shells to be evicted and replaced when the
+    /// next of them expires
+    fn schedule_prewarm_eviction(&self, config: &ConfigHandle) {
+        let mut pool = self.prewarm.borrow_mut();
+        if pool.eviction_scheduled {
+            return;
+        }
+        let expiry = match pool.next_expiry(Self::prewarm_idle_timeout(config)) {
+            Some(expiry) => expiry,
+            None => return,
+        };
+        pool.eviction_scheduled = true;
+        let domain_id = self.id;
+        promise::spawn::spawn(async move {
+            smol::Timer::after(expiry).await;
+            if let Some(domain) = Mux::get().and_then(|mux| mux.get_domain(domain_id)) {
+                if let Some(local) = domain.downcast_ref::<LocalDomain>() {
+                    local.prewarm.borrow_mut().eviction_scheduled = false;
+                    local.refill_prewarm_pool().await;
+                }
+            }
+        })
+        .detach();
+    }
+
+    async fn spawn_prewarmed_shell(
+        &self,
+        config: &ConfigHandle,
+    ) -> anyhow::Result<Option<PrewarmedShell>> {
+        let cmd = self.build_command(config, None, None, None).await?;
+        if !crate::prewarm::is_pooled_shell(&cmd) {
+            return Ok(None);
+        }
+        let pair = self
+            .pty_system
+            .openpty(crate::terminal_size_to_pty_size(TerminalSize::default())?)?;
+        let child = pair.slave.spawn_command(cmd.clone())?;
+        log::trace!("prewarmed: {:?}", child);
+        Ok(Some(PrewarmedShell::new(&cmd, child, pair.master)))
+    }
+
+    /// Discards idle shells and spawns shells until the pool is full
+    async fn refill_prewarm_pool(&self) {
+        let config = configuration();
+        self.prewarm.borrow_mut().refill_scheduled = false;
+        if !self.prewarm_enabled(&config) {
+            self.prewarm.borrow_mut().clear();
+            return;
+        }
+        self.prewarm
+            .borrow_mut()
+            .evict(Self::prewarm_idle_timeout(&config));
+        while self.prewarm.borrow().len() < config.prewarm_pane_pool_size {
+            match self.spawn_prewarmed_shell(&config).await {
+                Ok(Some(shell)) => self.prewarm.borrow_mut().push(shell),
+                Ok(None) => {
+                    log::debug!(
+                        "not prewarming shells for domain {}: \
+                         its default program is not a POSIX shell",
+                        self.name
+                    );
+                    break;
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to prewarm a shell for domain {}: {:#}",
+                        self.name,
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+        self.schedule_prewarm_eviction(&config);
+    }
+
+    /// Claims a prewarmed shell that can stand in for `cmd`, resizes it
+    /// and tells it its pane id and cwd
+    fn claim_prewarmed_shell(
+        &self,
+        config: &ConfigHandle,
+        cmd: &CommandBuilder,
+        pane_id: PaneId,
+        size: TerminalSize,
+    ) -> anyhow::Result<Option<PrewarmedShell>> {
+        let handshake =
+            match crate::prewarm::handshake(pane_id, cmd.get_cwd().map(|d| d.as_os_str())) {
+                Some(handshake) => handshake,
+                None => return Ok(None),
+            };
+        let mut shell = match self
+            .prewarm
+            .borrow_mut()
+            .claim(cmd, Self::prewarm_idle_timeout(config))
+        {
+            Some(shell) => shell,
+            None => return Ok(None),
+        };
+        shell
+            .master
+            .resize(crate::terminal_size_to_pty_size(size)?)?;
+        shell.master.write_all(handshake.as_bytes())?;
+        log::trace!("pane {} claimed prewarmed shell {:?}", pane_id, shell.child);
+        Ok(Some(shell))
+    }
+}
+
+async fn refill_prewarm_pool(domain_id: DomainId) {
+    if let Some(domain) = Mux::get().and_then(|mux| mux.get_domain(domain_id)) {
+        if let Some(local) = domain.downcast_ref::<LocalDomain>() {
+            local.refill_prewarm_pool().await;
+        }
+    }
+}
+
+/// Returns true if `command` sets its own working directory
+pub fn command_has_cwd(command: Option<&CommandBuilder>) -> bool {
+    command.and_then(|cmd| cmd.get_cwd()).is_some()
+}
+
+/// The working directory of a new pane can be given in two ways, which
+/// take precedence in this order:
+///
+/// 1. a cwd that is set on the `CommandBuilder` itself
+/// 2. the `command_dir` that is passed alongside it, which is the `cwd`
+///    of a `SpawnCommand`, or else the cwd of the current pane
+/// 3. the defaults of the domain, such as `default_cwd`
+///
+/// Returns `command_dir` if it applies, or None if the command sets
+/// its own cwd.  Every domain, and the client end of the spawn PDUs,
+/// resolve the cwd through this function, so that a pane starts in the
+/// same place whichever domain it is spawned into.
+pub fn resolve_command_dir(
+    command: Option<&CommandBuilder>,
+    command_dir: Option<CommandDir>,
+) -> Option<CommandDir> {
+    if command_has_cwd(command) {
+        None
+    } else {
+        command_dir
+    }
+}
+
+/// Escapes the control characters in `s`, such as those of a cwd
+/// that was set by a hostile OSC 7, so that it can be logged or shown
+/// in a prompt without affecting the terminal that displays it
+pub fn escape_control_chars(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Clears the cwd of `cmd` if it contains control characters, as it
+/// is about to become an argument of a wrapper command such as
+/// `wsl.exe --cd`.  The pane then starts in the default directory of
+/// the wrapped environment.
+fn clear_unsafe_cwd(cmd: &mut CommandBuilder) {
+    let unsafe_cwd = cmd
+        .get_cwd()
+        .map(|cwd| cwd.to_string_lossy())
+        .filter(|cwd| cwd.chars().any(char::is_control))
+        .map(|cwd| escape_control_chars(&cwd));
+    if let Some(cwd) = unsafe_cwd {
+        log::warn!("Ignoring cwd \"{}\" as it contains control characters", cwd);
+        cmd.clear_cwd();
+    }
+}
+
+/// The settings that a domain applies to the command of a new pane
+pub struct SpawnDefaults<'a> {
+    /// The program to run when no command is specified; None runs
+    /// the default shell
+    pub default_prog: Option<&'a Vec<String>>,
+    /// The directory to run the default program in
+    pub default_cwd: Option<&'a PathBuf>,
+    /// The directory to run an explicit command in, if it doesn't
+    /// specify one of its own
+    pub explicit_cwd: Option<&'a PathBuf>,
+    /// How the cwd is expanded when it is applied
+    pub cwd_expansion: CwdExpansion,
+    /// Whether the default program runs as a login shell; see
+    /// `CommandBuilder::set_login_shell`.  None leaves it as it is.
+    pub login_shell: Option<bool>,
+}
+
+/// Resolves the command to run in a new pane of a domain.
+/// An explicit `command` is used as-is, other than applying the
+/// config defaults, while None, or a command that carries only a
+/// label, is replaced by the default program of the domain, which
+/// is made a login shell or not according to `login_shell`.
+pub fn resolve_spawn_command(
+    config: &ConfigHandle,
+    command: Option<CommandBuilder>,
+    defaults: &SpawnDefaults,
+) -> anyhow::Result<CommandBuilder> {
+    let label = command
+        .as_ref()
+        .and_then(|cmd| cmd.get_label())
+        .map(|label| label.to_string());
+    let exit_behavior = command
+        .as_ref()
+        .and_then(|cmd| cmd.get_exit_behavior())
+        .map(|behavior| behavior.to_string());
+    // apply_cmd_defaults expands the cwd as a local path; other kinds
+    // of cwd are applied separately below
+    let local = defaults.cwd_expansion == CwdExpansion::Local;
+    let local_cwd = |cwd| if local { cwd } else { None };
+    let (mut cmd, cwd) = match command.filter(|cmd| !carries_only_label(cmd)) {
+        Some(mut cmd) => {
+            config.apply_cmd_defaults(&mut cmd, local_cwd(defaults.explicit_cwd));
+            (cmd, defaults.explicit_cwd)
+        }
+        None => {
+            let mut cmd =
+                config.build_prog(None, defaults.default_prog, local_cwd(defaults.default_cwd))?;
+            if let Some(login) = defaults.login_shell {
+                cmd.set_login_shell(login);
+            }
+            (cmd, defaults.default_cwd)
+        }
+    };
+    if !local {
+        config.apply_default_cwd(&mut cmd, cwd, defaults.cwd_expansion);
+    }
+    if let Some(label) = label {
+        cmd.label(label);
+    }
+    if let Some(exit_behavior) = exit_behavior {
+        cmd.exit_behavior(exit_behavior);
+    }
+    Ok(cmd)
+}
+
+/// Returns true if `cmd` specifies nothing other than a label or an
+/// exit behavior.  Such a builder stands in for the default program of
+/// the domain, so that a label or exit behavior can be given to it.
+fn carries_only_label(cmd: &CommandBuilder) -> bool {
+    cmd.is_default_prog() && cmd.get_cwd().is_none() && cmd.iter_extra_env_as_str().next().is_none()
+}
+
+#[async_trait(?Send)]
+impl Domain for LocalDomain {
+    fn map_cwd(&self, dir: &CommandDir, from_domain: Option<&Arc<dyn Domain>>) -> CwdMapping {
+        // Paths in docker and WSL domains are inside the container
+        // or distribution, whose hostname doesn't match ours
+        if self.kind == DomainKind::Local {
+            match &dir.host {
+                Some(host) if !dir.is_on_local_host() => {
+                    return CwdMapping::Dropped(format!(
+                        "it is on the host `{}`, rather than this one",
+                        host
+                    ));
+                }
+                // The pane, such as that of a unix domain, reported
+                // a path on this host
+                Some(_) => return CwdMapping::Use(dir.clone()),
+                None => {}
+            }
+        }
+        map_cwd_within_domain(self.id, &self.name, dir, from_domain)
+    }
+
+    async fn spawn_pane(
+        &self,
+        pane_id: PaneId,
+        size: TerminalSize,
+        command: Option<CommandBuilder>,
+        command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        if let Some(docker) = &self.docker {
+            crate::docker::ensure_container_running(docker).await?;
+        }
+        let config = configuration();
+        // Kept so that the pane can be respawned after it has exited
+        let (respawn_command, respawn_dir) = (command.clone(), command_dir.clone());
+        let wants_default_prog = match &command {
+            Some(cmd) => carries_only_label(cmd),
+            None => true,
+        };
+        let default_prog_field = if wants_default_prog {
+            self.default_prog_field(&config)
+        } else {
+            None
+        };
+        let mut cmd = self
+            .build_command(&config, command, command_dir, Some(pane_id))
+            .await?;
+        let prewarm_enabled = self.prewarm_enabled(&config);
+        let prewarmed = if wants_default_prog && prewarm_enabled {
+            self.claim_prewarmed_shell(&config, &cmd, pane_id, size)?
+        } else {
+            None
+        };
+        self.configure_conpty(&config);
+
+        let command_line = cmd
+            .as_unix_command_line()
+            .unwrap_or_else(|err| format!("error rendering command line: {:?}", err));
+        let spawn_label = cmd.get_label().map(|label| label.to_string());
+        let exit_behavior = cmd
+            .get_exit_behavior()
+            .and_then(|behavior| behavior.parse().ok())
+            .or_else(|| config.domain_exit_behavior(&self.name));
+        let command_description = escape_control_chars(&match &spawn_label {
+            Some(label) => format!("'{}' in domain \"{}\"", label, self.name),
+            None => format!(
+                "\"{}\" in domain \"{}\"",
+                if command_line.is_empty() {
+                    cmd.get_shell()?
+                } else {
+                    command_line
+                },
+                self.name
+            ),
+        });
+        // The command as it was before it was wrapped in systemd-run,
+        // to fall back to if systemd-run can't be spawned
+        let mut unscoped_cmd = None;
+        let mut systemd_scope = if self.use_systemd_scope(&config) && crate::systemd::is_available()
+        {
+            let unit = crate::systemd::scope_unit_name(pane_id);
+            let argv = crate::systemd::scope_argv(&config, &unit, &cmd)?;
+            unscoped_cmd = Some(cmd.clone());
+            *cmd.get_argv_mut() = argv;
+            Some(unit)
+        } else {
+            None
+        };
+        let (child, master) = match prewarmed {
+            Some(shell) => (shell.child, shell.master),
+            #[cfg(unix)]
+            None if self.resilient_enabled(&config) => crate::supervisor::spawn_supervised(
+                pane_id,
+                &self.name,
+                &command_description,
+                cmd,
+                crate::terminal_size_to_pty_size(size)?,
+                config.resilient_pane_replay_buffer_size,
+            )?,
+            None => {
+                let span = spawntiming::span("openpty");
+                let pair = self
+                    .pty_system
+                    .openpty(crate::terminal_size_to_pty_size(size)?)?;
+                drop(span);
+                let span = spawntiming::span("spawn_command");
+                let spawned = match (pair.slave.spawn_command(cmd), unscoped_cmd) {
+                    (Err(err), Some(unscoped_cmd)) => {
+                        log::warn!(
+                            "Failed to spawn pane {} in systemd scope {}, \
+                             spawning it without a scope: {:#}",
+                            pane_id,
+                            systemd_scope.take().unwrap_or_default(),
+                            err
+                        );
+                        pair.slave.spawn_command(unscoped_cmd)
+                    }
+                    (spawned, _) => spawned,
+                };
+                drop(span);
+                let child = match (spawned, default_prog_field) {
+                    (Ok(child), _) => child,
+                    (Err(err), Some(field)) => {
+                        // This is most likely a problem with the config,
+                        // so report it where config problems are reported
+                        let err = err.context(format!(
+                            "Failed to spawn the program configured by {}",
+                            field
+                        ));
+                        config::show_error(&format!("{:#}", err));
+                        return Err(err.into());
+                    }
+                    (Err(err), None) => return Err(err.into()),
+                };
+                log::trace!("spawned: {:?}", child);
+                (child, pair.master)
+            }
+        };
+        if let Some(conpty) = self.describe_conpty() {
+            log::info!("pane {} uses conpty: {}", pane_id, conpty);
+        }
+
+        let mut pane = self.new_local_pane(pane_id, size, child, master, command_description)?;
+        if let Some(unit) = systemd_scope {
+            pane.set_systemd_scope(unit);
+        }
+        if let Some(label) = spawn_label {
+            pane.set_spawn_label(label);
+        }
+        if let Some(exit_behavior) = exit_behavior {
+            pane.set_exit_behavior(exit_behavior);
+        }
+        pane.set_spawn_command(respawn_command, respawn_dir);
+        if let Some(overrides) = self.palette_overrides() {
+            pane.set_palette_overrides(Some(overrides))?;
+        }
+        let pane: Rc<dyn Pane> = Rc::new(pane);
+
+        let mux = Mux::get().unwrap();
+        let span = spawntiming::span("register_pane");
+        mux.add_pane(&pane)?;
+        drop(span);
+
+        if prewarm_enabled {
+            self.schedule_prewarm_refill();
+        }
+
+        Ok(pane)
+    }
+
+    fn domain_id(&self) -> DomainId {
+        self.id
+    }
 
     fn domain_name(&self) -> &str {
         &self.name
     }
 
+    fn domain_kind(&self) -> Option<DomainKind> {
+        Some(self.kind)
+    }
+
+    fn pty_quirks(&self) -> Option<String> {
+        self.describe_conpty()
+    }
+
+    fn launcher_hints(&self) -> LauncherHints {
+        let config = configuration();
+        if let Some(ed) = self.resolve_exec_domain(&config) {
+            ed.launcher
+        } else if let Some(wsl) = self.resolve_wsl_domain(&config) {
+            wsl.launcher
+        } else {
+            LauncherHints::default()
+        }
+    }
+
     async fn domain_label(&self) -> String {
-        if let Some(ed) = self.resolve_exec_domain() {
+        if let Some(docker) = &self.docker {
+            return crate::docker::container_label(docker).await;
+        }
+        let config = configuration();
+        if let Some(ed) = self.resolve_exec_domain(&config) {
             match &ed.label {
                 Some(ValueOrFunc::Value(wezterm_dynamic::Value::String(s))) => s.to_string(),
                 Some(ValueOrFunc::Func(label_func)) => {
-                    let label = config::with_lua_config_on_main_thread(|lua| async {
-                        let lua = lua.ok_or_else(|| anyhow::anyhow!("missing lua context"))?;
-                        let value = config::lua::emit_async_callback(
-                            &*lua,
-                            (label_func.clone(), (self.name.clone())),
-                        )
-                        .await?;
-                        let label: String =
-                            luahelper::from_lua_value_dynamic(value).with_context(|| {
-                                format!(
-                                    "interpreting SpawnCommand result from ExecDomain {}",
-                                    ed.name
+                    let label_func = label_func.clone();
+                    let name = self.name.clone();
+                    let sandbox_limits = if config.sandbox_exec_domains {
+                        Some(config.sandbox_limits())
+                    } else {
+                        None
+                    };
+                    let label = config::with_lua_config_on_lua_thread(move |lua| async move {
+                        let lua = lua.ok_or_else(|| DomainError::ConfigUnavailable {
+                            domain: name.clone(),
+                        })?;
+                        let label: String = match sandbox_limits {
+                            Some(limits) => {
+                                let value = config::sandbox::call_exec_domain_callback(
+                                    &*lua,
+                                    &name,
+                                    &label_func,
+                                    name.clone(),
+                                    limits,
+                                )?;
+                                String::from_dynamic(&value, Default::default())
+                                    .map_err(anyhow::Error::from)
+                            }
+                            None => {
+                                let value = config::lua::emit_async_callback(
+                                    &*lua,
+                                    (label_func, name.clone()),
                                 )
-                            })?;
+                                .await?;
+                                luahelper::from_lua_value_dynamic(value)
+                                    .map_err(anyhow::Error::from)
+                            }
+                        }
+                        .with_context(|| {
+                            format!("interpreting SpawnCommand result from ExecDomain {}", name)
+                        })?;
                         Ok(label)
                     })
                     .await;
                     match label {
                         Ok(label) => label,
+                        // The failure to load the configuration has
+                        // already been reported
+                        Err(err)
+                            if matches!(
+                                DomainError::find(&err),
+                                Some(DomainError::ConfigUnavailable { .. })
+                            ) =>
+                        {
+                            log::debug!("using the name as the label of {}: {:#}", self.name, err);
+                            self.name.to_string()
+                        }
                         Err(err) => {
                             log::error!(
                                 "Error while calling label function for ExecDomain `{}`: {err:#}",
@@ -483,22 +1868,1037 @@ impl Domain for LocalDomain {
                 }
                 _ => self.name.to_string(),
             }
-        } else if let Some(wsl) = self.resolve_wsl_domain() {
-            wsl.distribution.unwrap_or_else(|| self.name.to_string())
+        } else if let Some(wsl) = self.resolve_wsl_domain(&config) {
+            self.resolve_wsl_identity(&config, &wsl).await.0
         } else {
             self.name.to_string()
         }
     }
 
-    async fn attach(&self, _window_id: Option<WindowId>) -> anyhow::Result<()> {
+    async fn attach(&self, _window_id: Option<WindowId>) -> DomainResult<()> {
         Ok(())
     }
 
-    fn detach(&self) -> anyhow::Result<()> {
-        bail!("detach not implemented for LocalDomain");
+    fn detach(&self) -> DomainResult<()> {
+        Err(anyhow::anyhow!("detach not implemented for LocalDomain").into())
+    }
+
+    fn palette_entries(&self) -> Vec<PaletteEntry> {
+        match &self.docker {
+            Some(_) => vec![PaletteEntry::new(
+                "Restart container",
+                crate::docker::RESTART_ACTION,
+            )],
+            None => vec![],
+        }
+    }
+
+    async fn perform_palette_action(&self, action: &str) -> anyhow::Result<()> {
+        match &self.docker {
+            Some(docker) if action == crate::docker::RESTART_ACTION => {
+                crate::docker::restart_container(docker).await
+            }
+            _ => bail!("domain {} has no action named {}", self.name, action),
+        }
+    }
+
+    async fn list_directory(&self, dir: &str, limit: usize) -> anyhow::Result<DirListing> {
+        let config = configuration();
+        let dir = dir.to_string();
+        if let Some(wsl) = self.resolve_wsl_domain(&config) {
+            let (distribution, username) = self.resolve_wsl_identity(&config, &wsl).await;
+            smol::unblock(move || {
+                crate::dirlist::list_wsl_dir(&distribution, username.as_deref(), &dir, limit)
+            })
+            .await
+        } else if self.kind == DomainKind::Local {
+            smol::unblock(move || crate::dirlist::list_local_dir(&dir, limit)).await
+        } else {
+            bail!("domain {} cannot list directories", self.name);
+        }
+    }
+
+    async fn exec(&self, request: ExecRequest) -> anyhow::Result<ExecOutput> {
+        if self
+            .pty_system
+            .downcast_ref::<portable_pty::serial::SerialTty>()
+            .is_some()
+        {
+            bail!("domain {} cannot run commands", self.name);
+        }
+        let config = configuration();
+        let mut cmd = request.command()?;
+        if let Some(docker) = &self.docker {
+            // Unlike that of a pane, the command has no tty
+            clear_unsafe_cwd(&mut cmd);
+            let argv = crate::docker::docker_exec_argv(&config.docker_command, docker, &cmd, false);
+            cmd.clear_cwd();
+            *cmd.get_argv_mut() = argv;
+        } else {
+            self.fixup_command(&config, &mut cmd).await?;
+        }
+        let timeout = request.timeout;
+        smol::unblock(move || crate::domainexec::run_local(&cmd, timeout)).await
     }
 
     fn state(&self) -> DomainState {
         DomainState::Attached
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::MuxBuilder;
+    use crate::renderable::*;
+    use rangeset::RangeSet;
+    use std::cell::RefMut;
+    use std::ops::Range;
+    use termwiz::surface::SequenceNo;
+    use url::Url;
+    use wezterm_term::color::ColorPalette;
+    use wezterm_term::{KeyCode, KeyModifiers, Line, MouseEvent, StableRowIndex};
+
+    struct FakePane {
+        id: PaneId,
+        domain_id: DomainId,
+        size: RefCell<TerminalSize>,
+    }
+
+    impl FakePane {
+        fn new(domain_id: DomainId, size: TerminalSize) -> Rc<dyn Pane> {
+            Self::with_id(alloc_pane_id(), domain_id, size)
+        }
+
+        fn with_id(id: PaneId, domain_id: DomainId, size: TerminalSize) -> Rc<dyn Pane> {
+            Rc::new(Self {
+                id,
+                domain_id,
+                size: RefCell::new(size),
+            })
+        }
+    }
+
+    impl Pane for FakePane {
+        fn pane_id(&self) -> PaneId {
+            self.id
+        }
+        fn get_cursor_position(&self) -> StableCursorPosition {
+            unimplemented!();
+        }
+        fn get_current_seqno(&self) -> SequenceNo {
+            unimplemented!();
+        }
+        fn get_changed_since(
+            &self,
+            _lines: Range<StableRowIndex>,
+            _: SequenceNo,
+        ) -> RangeSet<StableRowIndex> {
+            unimplemented!();
+        }
+        fn get_lines(&self, _lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>) {
+            unimplemented!();
+        }
+        fn get_dimensions(&self) -> RenderableDimensions {
+            unimplemented!();
+        }
+        fn get_title(&self) -> String {
+            unimplemented!()
+        }
+        fn send_paste(&self, _text: &str) -> anyhow::Result<()> {
+            unimplemented!()
+        }
+        fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>> {
+            Ok(None)
+        }
+        fn writer(&self) -> RefMut<dyn std::io::Write> {
+            unimplemented!()
+        }
+        fn resize(&self, size: TerminalSize) -> anyhow::Result<()> {
+            *self.size.borrow_mut() = size;
+            Ok(())
+        }
+        fn key_down(&self, _key: KeyCode, _mods: KeyModifiers) -> anyhow::Result<()> {
+            unimplemented!()
+        }
+        fn key_up(&self, _: KeyCode, _: KeyModifiers) -> anyhow::Result<()> {
+            unimplemented!()
+        }
+        fn mouse_event(&self, _event: MouseEvent) -> anyhow::Result<()> {
+            unimplemented!()
+        }
+        fn is_dead(&self) -> bool {
+            false
+        }
+        fn palette(&self) -> ColorPalette {
+            unimplemented!()
+        }
+        fn domain_id(&self) -> DomainId {
+            self.domain_id
+        }
+        fn is_mouse_grabbed(&self) -> bool {
+            false
+        }
+        fn is_alt_screen_active(&self) -> bool {
+            false
+        }
+        fn get_current_working_dir(&self) -> Option<Url> {
+            None
+        }
+    }
+
+    const SIZE: TerminalSize = TerminalSize {
+        rows: 24,
+        cols: 80,
+        pixel_width: 800,
+        pixel_height: 600,
+        dpi: 96,
+    };
+
+    fn test_mux() -> (Rc<Mux>, Arc<dyn Domain>) {
+        let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new("local").unwrap());
+        let mux = MuxBuilder::new()
+            .domain(Arc::clone(&domain))
+            .build()
+            .unwrap();
+        (mux, domain)
+    }
+
+    /// Creates a tab holding a single pane, optionally placing it
+    /// in a new window
+    fn make_tab(mux: &Mux, domain: &Arc<dyn Domain>, in_window: bool) -> Rc<Tab> {
+        let tab = Rc::new(Tab::new(&SIZE));
+        tab.assign_pane(&FakePane::new(domain.domain_id(), SIZE));
+        mux.add_tab_and_active_pane(&tab).unwrap();
+        if in_window {
+            let window_id = *mux.new_empty_window(None);
+            mux.add_tab_to_window(&tab, window_id).unwrap();
+        }
+        tab
+    }
+
+    fn move_into_split(domain: &Arc<dyn Domain>, tab: &Rc<Tab>, src_pane_id: PaneId) {
+        let pane_id = tab.get_active_pane().unwrap().pane_id();
+        let moved = smol::block_on(domain.split_pane(
+            SplitSource::MovePane(src_pane_id),
+            tab.tab_id(),
+            pane_id,
+            SplitRequest::default(),
+            src_pane_id,
+        ))
+        .unwrap();
+        assert_eq!(moved.pane_id(), src_pane_id);
+        assert_eq!(tab.count_panes(), 2);
+    }
+
+    #[test]
+    fn move_pane_without_tab() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+
+        // As left by a background attach: known to the mux, but not in any tab
+        let pane = FakePane::new(domain.domain_id(), SIZE);
+        mux.add_pane(&pane).unwrap();
+        assert!(mux.resolve_pane_tab(pane.pane_id()).is_none());
+
+        move_into_split(&domain, &tab, pane.pane_id());
+        let (_domain, _window, tab_id) = mux.resolve_pane_id(pane.pane_id()).unwrap();
+        assert_eq!(tab_id, tab.tab_id());
+    }
+
+    #[test]
+    fn move_pane_from_windowless_tab() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+        let detached = make_tab(&mux, &domain, false);
+        let pane_id = detached.get_active_pane().unwrap().pane_id();
+        assert!(mux.resolve_pane_id(pane_id).is_none());
+
+        move_into_split(&domain, &tab, pane_id);
+        assert!(detached.iter_panes().is_empty());
+        // A windowless tab is not pruned; it belongs to whoever created it
+        assert!(mux.get_tab(detached.tab_id()).is_some());
+    }
+
+    #[test]
+    fn move_pane_from_windowed_tab() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+        let src = make_tab(&mux, &domain, true);
+        let pane_id = src.get_active_pane().unwrap().pane_id();
+
+        move_into_split(&domain, &tab, pane_id);
+        assert!(mux.get_tab(src.tab_id()).is_none());
+    }
+
+    /// A domain whose panes may only be split into its own tabs, and
+    /// into whose tabs no other panes may be moved
+    struct GuardedDomain {
+        id: DomainId,
+    }
+
+    #[async_trait(?Send)]
+    impl Domain for GuardedDomain {
+        async fn spawn_pane(
+            &self,
+            _pane_id: PaneId,
+            _size: TerminalSize,
+            _command: Option<CommandBuilder>,
+            _command_dir: Option<CommandDir>,
+        ) -> DomainResult<Rc<dyn Pane>> {
+            unimplemented!()
+        }
+        fn domain_id(&self) -> DomainId {
+            self.id
+        }
+        fn domain_name(&self) -> &str {
+            "guarded"
+        }
+        async fn attach(&self, _window_id: Option<WindowId>) -> DomainResult<()> {
+            Ok(())
+        }
+        fn detach(&self) -> DomainResult<()> {
+            Ok(())
+        }
+        fn state(&self) -> DomainState {
+            DomainState::Attached
+        }
+        fn authorize_move(
+            &self,
+            pane: PaneId,
+            destination: MoveDestination,
+        ) -> Result<(), MoveDenied> {
+            let mux = Mux::get().unwrap();
+            let ours = mux.get_pane(pane).map(|p| p.domain_id()) == Some(self.id);
+            match destination {
+                MoveDestination::Split { domain_id, .. } if ours && domain_id == self.id => Ok(()),
+                _ => Err(MoveDenied::new("guarded panes stay in guarded tabs")),
+            }
+        }
+    }
+
+    fn guarded_mux() -> (Rc<Mux>, Arc<dyn Domain>, Arc<dyn Domain>) {
+        let (mux, local) = test_mux();
+        let guarded: Arc<dyn Domain> = Arc::new(GuardedDomain {
+            id: alloc_domain_id(),
+        });
+        mux.add_domain(&guarded);
+        (mux, local, guarded)
+    }
+
+    fn assert_denied<T: std::fmt::Debug>(result: Result<T, impl Into<anyhow::Error>>) {
+        let err = result.unwrap_err().into();
+        assert_eq!(
+            DomainError::find(&err).map(|err| err.code()),
+            Some(DomainError::MOVE_DENIED),
+            "{:#}",
+            err
+        );
+    }
+
+    /// Returns the tab that `pane_id` is in, and the panes of that tab
+    fn placement(mux: &Mux, pane_id: PaneId) -> (TabId, Vec<PaneId>) {
+        let (_domain, tab_id) = mux.resolve_pane_tab(pane_id).unwrap();
+        let panes = mux
+            .get_tab(tab_id)
+            .unwrap()
+            .iter_panes()
+            .iter()
+            .map(|p| p.pane.pane_id())
+            .collect();
+        (tab_id, panes)
+    }
+
+    #[test]
+    fn denied_split_moves_change_nothing() {
+        let (mux, local, guarded) = guarded_mux();
+        let local_tab = make_tab(&mux, &local, true);
+        let guarded_tab = make_tab(&mux, &guarded, true);
+        let local_pane = local_tab.get_active_pane().unwrap().pane_id();
+        let guarded_pane = guarded_tab.get_active_pane().unwrap().pane_id();
+        let before = (
+            placement(&mux, local_pane),
+            placement(&mux, guarded_pane),
+            mux.iter_windows().len(),
+        );
+
+        // The domain of the moved pane refuses
+        assert_denied(smol::block_on(local.split_pane(
+            SplitSource::MovePane(guarded_pane),
+            local_tab.tab_id(),
+            local_pane,
+            SplitRequest::default(),
+            guarded_pane,
+        )));
+        // The domain of the destination refuses, although that of the
+        // moved pane allows it
+        assert_denied(smol::block_on(local.split_pane(
+            SplitSource::MovePane(local_pane),
+            guarded_tab.tab_id(),
+            guarded_pane,
+            SplitRequest::default(),
+            local_pane,
+        )));
+
+        let after = (
+            placement(&mux, local_pane),
+            placement(&mux, guarded_pane),
+            mux.iter_windows().len(),
+        );
+        assert_eq!(before, after);
+        assert!(mux.get_tab(local_tab.tab_id()).is_some());
+        assert!(mux.get_tab(guarded_tab.tab_id()).is_some());
+    }
+
+    #[test]
+    fn denied_tab_moves_change_nothing() {
+        let (mux, _local, guarded) = guarded_mux();
+        let tab = make_tab(&mux, &guarded, true);
+        let pane_id = tab.get_active_pane().unwrap().pane_id();
+        let windows = mux.iter_windows();
+
+        assert_denied(smol::block_on(mux.move_pane_to_new_tab(
+            pane_id,
+            None,
+            Some("default".to_string()),
+        )));
+        assert_eq!(mux.iter_windows(), windows);
+        assert_eq!(placement(&mux, pane_id), (tab.tab_id(), vec![pane_id]));
+
+        let detached = make_tab(&mux, &guarded, false);
+        assert_denied(mux.attach_tab_to_window(&detached, windows[0], None));
+        assert!(mux.window_containing_tab(detached.tab_id()).is_none());
+    }
+
+    fn domain_pane_ids(mux: &Mux, domain_id: DomainId) -> Vec<PaneId> {
+        mux.iter_panes_for_domain(domain_id)
+            .iter()
+            .map(|pane| pane.pane_id())
+            .collect()
+    }
+
+    #[test]
+    fn panes_for_domain_follow_moves() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+        let src = make_tab(&mux, &domain, true);
+        let first = tab.get_active_pane().unwrap().pane_id();
+        let second = src.get_active_pane().unwrap().pane_id();
+
+        // A pane of another domain is not included
+        let other = FakePane::new(domain.domain_id() + 1000, SIZE);
+        mux.add_pane(&other).unwrap();
+
+        assert_eq!(
+            domain_pane_ids(&mux, domain.domain_id()),
+            vec![first, second]
+        );
+        move_into_split(&domain, &tab, second);
+        assert_eq!(
+            domain_pane_ids(&mux, domain.domain_id()),
+            vec![first, second]
+        );
+        assert_eq!(
+            domain_pane_ids(&mux, other.domain_id()),
+            vec![other.pane_id()]
+        );
+    }
+
+    #[test]
+    fn panes_for_domain_drop_dead_panes() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+        let pane_id = tab.get_active_pane().unwrap().pane_id();
+
+        mux.remove_pane(pane_id);
+        assert!(domain_pane_ids(&mux, domain.domain_id()).is_empty());
+        assert!(mux.get_pane(pane_id).is_none());
+    }
+
+    fn counts(num_windows: usize, num_tabs: usize, num_panes: usize) -> DomainInventory {
+        DomainInventory {
+            num_windows,
+            num_tabs,
+            num_panes,
+        }
+    }
+
+    #[test]
+    fn domain_inventory_follows_moves() {
+        let (mux, domain) = test_mux();
+        let domain_id = domain.domain_id();
+        let tab = make_tab(&mux, &domain, true);
+        let src = make_tab(&mux, &domain, true);
+        // The panes of a windowless tab count, but the tab doesn't
+        let detached = make_tab(&mux, &domain, false);
+        assert_eq!(mux.domain_inventory(domain_id), counts(2, 2, 3));
+
+        // Moving the only pane of `src` closes it
+        move_into_split(&domain, &tab, src.get_active_pane().unwrap().pane_id());
+        assert_eq!(mux.domain_inventory(domain_id), counts(1, 1, 3));
+
+        let window_id = *mux.new_empty_window(None);
+        mux.add_tab_to_window(&detached, window_id).unwrap();
+        assert_eq!(mux.domain_inventory(domain_id), counts(2, 2, 3));
+
+        // The tab still holds the other pane of the domain
+        let first = tab.iter_panes()[0].pane.pane_id();
+        mux.remove_pane(first);
+        assert_eq!(mux.domain_inventory(domain_id), counts(2, 2, 2));
+
+        mux.remove_tab(tab.tab_id());
+        assert_eq!(mux.domain_inventory(domain_id), counts(1, 1, 1));
+        assert_eq!(mux.domain_inventory(domain_id + 1000), counts(0, 0, 0));
+    }
+
+    #[test]
+    fn kill_panes_in_domain() {
+        let (mux, domain) = test_mux();
+        let tab = make_tab(&mux, &domain, true);
+        let detached = make_tab(&mux, &domain, false);
+        let other = FakePane::new(domain.domain_id() + 1000, SIZE);
+        mux.add_pane(&other).unwrap();
+
+        mux.kill_panes_in_domain(domain.domain_id());
+        assert!(domain_pane_ids(&mux, domain.domain_id()).is_empty());
+        assert!(mux.get_tab(tab.tab_id()).is_none());
+        assert!(detached.iter_panes().is_empty());
+        assert!(mux.get_pane(other.pane_id()).is_some());
+    }
+
+    #[test]
+    fn domain_error_codes() {
+        let err: anyhow::Error = DomainError::DomainNotFound {
+            domain: "foo".to_string(),
+        }
+        .into();
+        let err = DomainError::from(err);
+        assert_eq!(err.code(), DomainError::DOMAIN_NOT_FOUND);
+        assert_eq!(err.to_string(), "domain foo not found");
+
+        // Context is retained, along with the code of the cause
+        let err = anyhow::Error::from(DomainError::DomainDetached {
+            domain: "foo".to_string(),
+        })
+        .context("spawning");
+        let err = DomainError::from(err);
+        assert_eq!(err.code(), DomainError::DOMAIN_DETACHED);
+        assert_eq!(err.kind(), "DomainDetached");
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(err)),
+            "spawning: domain foo is detached"
+        );
+
+        let err = DomainError::from(anyhow::anyhow!("boom"));
+        assert_eq!(err.code(), DomainError::SPAWN_FAILED);
+        assert_eq!(err.to_string(), "boom");
+
+        let err = DomainError::from(SplitError::TooSmall {
+            needed: 3,
+            available: 2,
+        });
+        assert_eq!(err.code(), DomainError::SPLIT_TOO_SMALL);
+
+        let err = DomainError::RemoteError {
+            code: DomainError::TIMEOUT,
+            message: "timed out".to_string(),
+        };
+        assert_eq!(err.kind(), "Timeout");
+    }
+
+    #[test]
+    fn label_only_command() {
+        let mut cmd = CommandBuilder::new_default_prog();
+        cmd.label("deploy script");
+        assert!(carries_only_label(&cmd));
+
+        // The cwd of the mux server's initial pane must be kept
+        cmd.cwd("/tmp");
+        assert!(!carries_only_label(&cmd));
+
+        let mut cmd = CommandBuilder::new("deploy.sh");
+        cmd.label("deploy script");
+        assert!(!carries_only_label(&cmd));
+        assert_eq!(cmd.get_label(), Some("deploy script"));
+    }
+
+    fn command_in(cwd: Option<&str>) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new("true");
+        if let Some(cwd) = cwd {
+            cmd.cwd(cwd);
+        }
+        cmd
+    }
+
+    #[test]
+    fn command_dir_precedence() {
+        // (cwd of the command, command_dir, the command_dir that applies)
+        let matrix = [
+            (Some("/x"), Some("/y"), None),
+            (Some("/x"), None, None),
+            (None, Some("/y"), Some("/y")),
+            (None, None, None),
+        ];
+        for (cwd, dir, expected) in matrix {
+            let cmd = command_in(cwd);
+            assert_eq!(
+                resolve_command_dir(Some(&cmd), dir.map(CommandDir::new)),
+                expected.map(CommandDir::new),
+                "cwd={:?} command_dir={:?}",
+                cwd,
+                dir
+            );
+        }
+        assert_eq!(
+            resolve_command_dir(None, Some(CommandDir::new("/y"))),
+            Some(CommandDir::new("/y"))
+        );
+    }
+
+    /// Returns the directory in which the domain would start `cmd`:
+    /// the cwd of the builder, or the `-w` argument of `docker exec`
+    fn spawned_cwd(
+        domain: &LocalDomain,
+        cmd: Option<CommandBuilder>,
+        dir: Option<&str>,
+    ) -> Option<String> {
+        let config = config::configuration();
+        let cmd =
+            smol::block_on(domain.build_command(&config, cmd, dir.map(CommandDir::new), Some(1)))
+                .unwrap();
+        if domain.docker.is_some() {
+            let argv = cmd.get_argv();
+            argv.iter()
+                .position(|arg| arg == "-w")
+                .map(|idx| argv[idx + 1].to_string_lossy().into_owned())
+        } else {
+            cmd.get_cwd().map(|cwd| cwd.to_string_lossy().into_owned())
+        }
+    }
+
+    #[test]
+    fn spawn_cwd_precedence() {
+        let local = LocalDomain::new("local").unwrap();
+        let docker = LocalDomain::new_docker(DockerDomain {
+            name: "docker".to_string(),
+            container: "box".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        for domain in [&local, &docker] {
+            let name = domain.domain_name();
+            // An explicit cwd on the command wins over command_dir
+            assert_eq!(
+                spawned_cwd(domain, Some(command_in(Some("/x"))), Some("/y")).as_deref(),
+                Some("/x"),
+                "{}",
+                name
+            );
+            // command_dir, such as the cwd of a SpawnCommand, wins over
+            // the defaults of the domain
+            assert_eq!(
+                spawned_cwd(domain, Some(command_in(None)), Some("/y")).as_deref(),
+                Some("/y"),
+                "{}",
+                name
+            );
+            assert_eq!(
+                spawned_cwd(domain, None, Some("/y")).as_deref(),
+                Some("/y"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn spawn_with_broken_config() {
+        let path = std::env::temp_dir().join(format!(
+            "wezterm-mux-broken-config-{}.lua",
+            std::process::id()
+        ));
+        std::fs::write(&path, "this is not lua").unwrap();
+        config::set_config_file_override(&path);
+        config::reload();
+        assert!(config::configuration_result().is_err());
+
+        // Spawning into a domain that doesn't call into lua proceeds
+        let local = LocalDomain::new("local").unwrap();
+        assert_eq!(spawned_cwd(&local, None, Some("/y")).as_deref(), Some("/y"));
+
+        // An exec domain that was configured before the reload failed
+        // can't call its functions, as there is no lua context
+        let exec_domain = ExecDomain {
+            name: "build".to_string(),
+            fixup_command: "fixup-build".to_string(),
+            label: Some(ValueOrFunc::Func("label-build".to_string())),
+            use_systemd_scope: None,
+            login_shell: None,
+            launcher: LauncherHints::default(),
+            palette_overrides: None,
+            exit_behavior: None,
+            max_concurrent_spawns: None,
+            paste_guard: None,
+        };
+        let mut cfg = (*config::configuration()).clone();
+        cfg.exec_domains.push(exec_domain.clone());
+        config::use_this_configuration(cfg);
+        let exec = LocalDomain::new_exec_domain(exec_domain).unwrap();
+        let config = config::configuration();
+        let err = smol::block_on(exec.build_command(&config, None, None, Some(1))).unwrap_err();
+        let err = DomainError::from(err);
+        assert_eq!(err.code(), DomainError::CONFIG_UNAVAILABLE);
+        assert!(
+            err.to_string()
+                .contains("fix the configuration and reload it"),
+            "{}",
+            err
+        );
+        assert_eq!(smol::block_on(exec.domain_label()), "build");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn login_shell_applies_to_default_prog_only() {
+        let config = config::configuration();
+        let resolve =
+            |command: Option<CommandBuilder>, default_prog: Option<&Vec<String>>, login_shell| {
+                let defaults = SpawnDefaults {
+                    default_prog,
+                    default_cwd: None,
+                    explicit_cwd: None,
+                    cwd_expansion: CwdExpansion::Verbatim,
+                    login_shell,
+                };
+                resolve_spawn_command(&config, command, &defaults).unwrap()
+            };
+
+        assert_eq!(resolve(None, None, None).get_login_shell(), None);
+        assert_eq!(
+            resolve(None, None, Some(false)).get_login_shell(),
+            Some(false)
+        );
+        assert_eq!(
+            resolve(None, None, Some(true)).get_login_shell(),
+            Some(true)
+        );
+
+        let zsh = vec!["/bin/zsh".to_string()];
+        assert_eq!(
+            resolve(None, Some(&zsh), Some(true)).get_argv(),
+            &["/bin/zsh", "-l"]
+        );
+        assert_eq!(
+            resolve(None, Some(&zsh), Some(false)).get_argv(),
+            &["/bin/zsh"]
+        );
+
+        // An explicit command is never changed
+        let bash = CommandBuilder::new("bash");
+        assert_eq!(
+            resolve(Some(bash), Some(&zsh), Some(true)).get_argv(),
+            &["bash"]
+        );
+    }
+
+    #[test]
+    fn resolve_spawn_command_applies_domain_defaults() {
+        let config = config::configuration();
+        let zsh = vec!["/bin/zsh".to_string()];
+        let default_cwd = PathBuf::from("~/src");
+        let explicit_cwd = PathBuf::from("~/work");
+        let defaults = SpawnDefaults {
+            default_prog: Some(&zsh),
+            default_cwd: Some(&default_cwd),
+            explicit_cwd: Some(&explicit_cwd),
+            cwd_expansion: CwdExpansion::Verbatim,
+            login_shell: None,
+        };
+        let resolve = |command| resolve_spawn_command(&config, command, &defaults).unwrap();
+        let cwd = |cmd: &CommandBuilder| cmd.get_cwd().map(|cwd| cwd.to_os_string());
+
+        // The default program runs in the default cwd, which is left
+        // for the other host to expand
+        let cmd = resolve(None);
+        assert_eq!(cmd.get_argv(), &["/bin/zsh"]);
+        assert_eq!(cwd(&cmd), Some("~/src".into()));
+
+        // A command that carries only a label stands in for the
+        // default program
+        let mut labelled = CommandBuilder::new_default_prog();
+        labelled.label("build");
+        let cmd = resolve(Some(labelled));
+        assert_eq!(cmd.get_argv(), &["/bin/zsh"]);
+        assert_eq!(cmd.get_label(), Some("build"));
+        assert_eq!(cwd(&cmd), Some("~/src".into()));
+
+        // An explicit command runs in the explicit cwd, unless it has
+        // a cwd of its own
+        let cmd = resolve(Some(CommandBuilder::new("make")));
+        assert_eq!(cmd.get_argv(), &["make"]);
+        assert_eq!(cwd(&cmd), Some("~/work".into()));
+
+        let mut make = CommandBuilder::new("make");
+        make.cwd("/tmp");
+        assert_eq!(cwd(&resolve(Some(make))), Some("/tmp".into()));
+    }
+
+    #[test]
+    fn close_action_follows_the_policy() {
+        let mut decisions = CloseDecisions::new();
+        decisions.insert(1, CloseAction::Kill);
+        for (policy, domain_id, action) in [
+            (ClosePolicy::Kill, 2, CloseAction::Kill),
+            (ClosePolicy::Detach, 1, CloseAction::Detach),
+            (ClosePolicy::Ask, 1, CloseAction::Kill),
+            (ClosePolicy::Ask, 2, CloseAction::Detach),
+        ] {
+            assert_eq!(
+                CloseAction::resolve(policy, domain_id, &decisions),
+                action,
+                "{:?} for domain {}",
+                policy,
+                domain_id
+            );
+        }
+    }
+
+    /// A domain that asks what to do with its panes when a window is
+    /// closed, but detaches them when a tab is closed
+    struct AskingDomain {
+        id: DomainId,
+        closed_tabs: RefCell<Vec<(TabId, CloseAction)>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Domain for AskingDomain {
+        async fn spawn_pane(
+            &self,
+            _pane_id: PaneId,
+            _size: TerminalSize,
+            _command: Option<CommandBuilder>,
+            _command_dir: Option<CommandDir>,
+        ) -> DomainResult<Rc<dyn Pane>> {
+            unimplemented!()
+        }
+        fn domain_id(&self) -> DomainId {
+            self.id
+        }
+        fn domain_name(&self) -> &str {
+            "asking"
+        }
+        async fn attach(&self, _window_id: Option<WindowId>) -> DomainResult<()> {
+            Ok(())
+        }
+        fn detach(&self) -> DomainResult<()> {
+            Ok(())
+        }
+        fn state(&self) -> DomainState {
+            DomainState::Attached
+        }
+        fn window_close_policy(&self) -> ClosePolicy {
+            ClosePolicy::Ask
+        }
+        fn tab_close_policy(&self) -> ClosePolicy {
+            ClosePolicy::Detach
+        }
+        fn tab_is_closing(&self, tab_id: TabId, action: CloseAction) {
+            self.closed_tabs.borrow_mut().push((tab_id, action));
+        }
+    }
+
+    #[test]
+    fn closing_waits_for_asking_domains() {
+        let (mux, local) = test_mux();
+        let asking = Arc::new(AskingDomain {
+            id: alloc_domain_id(),
+            closed_tabs: RefCell::new(vec![]),
+        });
+        let asking_dyn: Arc<dyn Domain> = asking.clone();
+        mux.add_domain(&asking_dyn);
+
+        let local_tab = make_tab(&mux, &local, true);
+        let asking_tab = make_tab(&mux, &asking_dyn, true);
+        let pane_ids = |panes: &[Rc<dyn Pane>]| -> Vec<PaneId> {
+            panes.iter().map(|pane| pane.pane_id()).collect()
+        };
+        let panes: Vec<Rc<dyn Pane>> = [&local_tab, &asking_tab]
+            .iter()
+            .map(|tab| tab.get_active_pane().unwrap())
+            .collect();
+
+        let asking_on_window = mux.domains_asking_on_close(&panes, false);
+        assert_eq!(asking_on_window.len(), 1);
+        assert_eq!(asking_on_window[0].0.domain_id(), asking.id);
+        assert_eq!(pane_ids(&asking_on_window[0].1), pane_ids(&panes[1..]));
+        assert!(mux.domains_asking_on_close(&panes, true).is_empty());
+
+        // The window stays open until the domain has been answered
+        let window_id = mux.window_containing_tab(asking_tab.tab_id()).unwrap();
+        let err = mux
+            .close_window(window_id, &CloseDecisions::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("asking"), "{:#}", err);
+        assert!(mux.get_window(window_id).is_some());
+
+        // The tab policy doesn't ask
+        mux.close_tab(asking_tab.tab_id(), &CloseDecisions::new())
+            .unwrap();
+        assert_eq!(
+            *asking.closed_tabs.borrow(),
+            vec![(asking_tab.tab_id(), CloseAction::Detach)]
+        );
+        assert!(mux.get_tab(asking_tab.tab_id()).is_none());
+    }
+
+    #[test]
+    fn escape_controls() {
+        assert_eq!(
+            escape_control_chars("/tmp/a\nb\x1b]0;pwned\x07"),
+            "/tmp/a\\nb\\u{1b}]0;pwned\\u{7}"
+        );
+        assert_eq!(escape_control_chars("\u{9b}31m"), "\\u{9b}31m");
+        // Spaces and non-ASCII characters are left alone
+        assert_eq!(
+            escape_control_chars("/home/user/my dir/café"),
+            "/home/user/my dir/café"
+        );
+    }
+
+    #[test]
+    fn wrapper_ignores_hostile_cwd() {
+        let docker = LocalDomain::new_docker(DockerDomain {
+            name: "docker".to_string(),
+            container: "box".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let long = format!("/{}", "a/".repeat(4096));
+        for hostile in ["/tmp/a\nb", "/tmp/\x1b]0;pwned\x07", "/tmp/\u{85}"] {
+            assert_eq!(
+                spawned_cwd(&docker, Some(command_in(Some(hostile))), None),
+                None,
+                "{:?}",
+                hostile
+            );
+            assert_eq!(spawned_cwd(&docker, None, Some(hostile)), None);
+        }
+        for fine in ["/tmp/my dir", "/tmp/café", long.as_str()] {
+            assert_eq!(
+                spawned_cwd(&docker, Some(command_in(Some(fine))), None).as_deref(),
+                Some(fine)
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_pane_ids_are_not_reused() {
+        let (mux, domain) = test_mux();
+        let pane = FakePane::new(domain.domain_id(), SIZE);
+        mux.add_pane(&pane).unwrap();
+        assert!(mux.reserve_pane_id(pane.pane_id()).is_err());
+
+        let pane_id = alloc_pane_id() + 100;
+        mux.reserve_pane_id(pane_id).unwrap();
+        assert!(mux.reserve_pane_id(pane_id).is_err());
+        assert!(alloc_pane_id() > pane_id);
+
+        // Adding the pane releases the reservation, but not the id
+        let reserved = FakePane::with_id(pane_id, domain.domain_id(), SIZE);
+        mux.add_pane(&reserved).unwrap();
+        assert!(mux.reserve_pane_id(pane_id).is_err());
+    }
+
+    #[test]
+    fn add_pane_rejects_colliding_id() {
+        let (mux, domain) = test_mux();
+        let pane = FakePane::new(domain.domain_id(), SIZE);
+        mux.add_pane(&pane).unwrap();
+        // The same pane may be added again
+        mux.add_pane(&pane).unwrap();
+
+        let other = FakePane::with_id(pane.pane_id(), domain.domain_id(), SIZE);
+        assert!(mux.add_pane(&other).is_err());
+        let kept = mux.get_pane(pane.pane_id()).unwrap();
+        assert_eq!(
+            Rc::as_ptr(&kept) as *const (),
+            Rc::as_ptr(&pane) as *const ()
+        );
+    }
+
+    #[test]
+    fn spawn_pane_with_used_id_fails() {
+        let (mux, domain) = test_mux();
+        let pane = FakePane::new(domain.domain_id(), SIZE);
+        mux.add_pane(&pane).unwrap();
+        let result =
+            smol::block_on(mux.spawn_pane_with_id(&domain, pane.pane_id(), SIZE, None, None));
+        assert!(result.is_err());
+    }
+
+    fn dir_on(host: Option<&str>) -> CommandDir {
+        CommandDir {
+            host: host.map(str::to_string),
+            path: "/src".to_string(),
+        }
+    }
+
+    #[test]
+    fn map_cwd_within_same_domain_only() {
+        let (_mux, local, guarded) = guarded_mux();
+        // (domain of the pane whose cwd it is, whether it is used)
+        let matrix = [(None, true), (Some(&local), true), (Some(&guarded), false)];
+        for (from, used) in matrix {
+            let dir = dir_on(None);
+            let mapping = map_cwd_within_domain(local.domain_id(), "local", &dir, from);
+            assert_eq!(
+                mapping == CwdMapping::Use(dir),
+                used,
+                "from={:?} -> {:?}",
+                from.map(|from| from.domain_name()),
+                mapping
+            );
+        }
+    }
+
+    #[test]
+    fn local_map_cwd() {
+        let (_mux, local, guarded) = guarded_mux();
+        let docker: Arc<dyn Domain> = Arc::new(
+            LocalDomain::new_docker(DockerDomain {
+                name: "docker".to_string(),
+                container: "box".to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        // (domain, host of the dir, domain of its pane, whether it is used)
+        let matrix = [
+            (&local, None, None, true),
+            (&local, None, Some(&local), true),
+            (&local, None, Some(&guarded), false),
+            // A path on this host is meaningful whichever pane reported it
+            (&local, Some("localhost"), Some(&guarded), true),
+            (&local, Some("elsewhere.invalid"), None, false),
+            (&local, Some("elsewhere.invalid"), Some(&local), false),
+            // The hostname of a container is not that of this host
+            (&docker, Some("a1b2c3"), Some(&docker), true),
+            (&docker, Some("a1b2c3"), Some(&guarded), false),
+            (&docker, None, None, true),
+        ];
+        for (domain, host, from, used) in matrix {
+            let dir = dir_on(host);
+            let mapping = domain.map_cwd(&dir, from);
+            assert_eq!(
+                mapping == CwdMapping::Use(dir),
+                used,
+                "domain={} host={:?} from={:?} -> {:?}",
+                domain.domain_name(),
+                host,
+                from.map(|from| from.domain_name()),
+                mapping
+            );
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Runs short commands in a domain without creating a pane, capturing
+//! their output, for scripts that want to learn something about the
+//! environment of the domain, such as the git branch of a directory in
+//! a wsl distribution; see `Domain::exec`.
+//! The command has no pty: its output is read through pipes, keeping
+//! up to `MAX_OUTPUT` bytes of each stream, and it is killed if it
+//! hasn't finished within its timeout.  Long-running and interactive
+//! commands are not supported.
+use anyhow::Context;
+use portable_pty::{Child, CommandBuilder};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::Stdio;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// The most bytes of each of stdout and stderr that are kept
+pub const MAX_OUTPUT: usize = 1024 * 1024;
+
+/// How long a command may run when no timeout is given
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest timeout that may be given
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the command is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A command to run in a domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecRequest {
+    /// The program and its arguments
+    pub args: Vec<String>,
+    /// The directory to run the command in, as the domain sees it
+    pub cwd: Option<String>,
+    pub timeout: Duration,
+}
+
+impl ExecRequest {
+    pub fn new(args: Vec<String>, cwd: Option<String>, timeout: Option<Duration>) -> Self {
+        Self {
+            args,
+            cwd,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT).min(MAX_TIMEOUT),
+        }
+    }
+
+    /// Returns the command to run, before the domain has adjusted it
+    pub fn command(&self) -> anyhow::Result<CommandBuilder> {
+        anyhow::ensure!(!self.args.is_empty(), "no command to run");
+        let mut cmd = CommandBuilder::from_argv(self.args.iter().map(Into::into).collect());
+        if let Some(cwd) = &self.cwd {
+            cmd.cwd(cwd);
+        }
+        Ok(cmd)
+    }
+}
+
+/// The result of a command that ran to completion
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct ExecOutput {
+    pub success: bool,
+    pub exit_code: u32,
+    pub stdout: String,
+    pub stderr: String,
+    /// True if either stream produced more than `MAX_OUTPUT` bytes,
+    /// the rest of which were discarded
+    pub truncated: bool,
+}
+luahelper::impl_lua_conversion_dynamic!(ExecOutput);
+
+/// Reads `reader` to its end, keeping the first `limit` bytes.
+/// Returns the bytes, and whether any were discarded.
+fn read_capped(mut reader: impl Read, limit: usize) -> (Vec<u8>, bool) {
+    let mut kept = vec![];
+    let mut truncated = false;
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let room = limit - kept.len();
+                if n > room {
+                    truncated = true;
+                }
+                kept.extend_from_slice(&buf[..n.min(room)]);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    (kept, truncated)
+}
+
+fn read_in_background(reader: Box<dyn Read + Send>) -> Receiver<(Vec<u8>, bool)> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        tx.send(read_capped(reader, MAX_OUTPUT)).ok();
+    });
+    rx
+}
+
+/// Waits for `child` to exit and for its output to be read, killing
+/// it if that takes longer than `timeout`
+pub fn capture(
+    mut child: Box<dyn Child + Send>,
+    stdout: Box<dyn Read + Send>,
+    stderr: Box<dyn Read + Send>,
+    timeout: Duration,
+) -> anyhow::Result<ExecOutput> {
+    let deadline = Instant::now() + timeout;
+    let stdout = read_in_background(stdout);
+    let stderr = read_in_background(stderr);
+    let timed_out = || anyhow::anyhow!("the command did not finish within {:?}", timeout);
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.try_wait().ok();
+            return Err(timed_out());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    // A process that the command left running in the background may
+    // hold its output open
+    let collect = |rx: Receiver<(Vec<u8>, bool)>| {
+        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| timed_out())
+    };
+    let (stdout, stdout_truncated) = collect(stdout)?;
+    let (stderr, stderr_truncated) = collect(stderr)?;
+    Ok(ExecOutput {
+        success: status.success(),
+        exit_code: status.exit_code(),
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        truncated: stdout_truncated || stderr_truncated,
+    })
+}
+
+/// Runs `cmd` on this host, with its output captured
+pub fn run_local(cmd: &CommandBuilder, timeout: Duration) -> anyhow::Result<ExecOutput> {
+    #[cfg(windows)]
+    use std::os::windows::process::CommandExt;
+    let argv = cmd.get_argv();
+    let program = argv.first().context("no command to run")?;
+    let mut command = std::process::Command::new(program);
+    command.args(&argv[1..]);
+    command.env_clear();
+    command.envs(cmd.iter_full_env_as_str());
+    if let Some(cwd) = cmd.get_cwd() {
+        command.current_dir(cwd);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(windows)]
+    command.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
+
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("running {}", program.to_string_lossy()))?;
+    let stdout = child.stdout.take().context("missing stdout")?;
+    let stderr = child.stderr.take().context("missing stderr")?;
+    capture(Box::new(child), Box::new(stdout), Box::new(stderr), timeout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capped() {
+        let (kept, truncated) = read_capped(&b"hello world"[..], 5);
+        assert_eq!(kept, b"hello");
+        assert!(truncated);
+        let (kept, truncated) = read_capped(&b"hello"[..], 5);
+        assert_eq!(kept, b"hello");
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn timeout_is_limited() {
+        assert_eq!(
+            ExecRequest::new(vec![], None, None).timeout,
+            DEFAULT_TIMEOUT
+        );
+        let request = ExecRequest::new(vec![], None, Some(Duration::from_secs(3600)));
+        assert_eq!(request.timeout, MAX_TIMEOUT);
+        assert!(request.command().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn captures_output_and_status() {
+        let request = ExecRequest::new(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "echo out; echo err >&2; exit 3".to_string(),
+            ],
+            Some("/".to_string()),
+            None,
+        );
+        let output = run_local(&request.command().unwrap(), request.timeout).unwrap();
+        assert_eq!(
+            output,
+            ExecOutput {
+                success: false,
+                exit_code: 3,
+                stdout: "out\n".to_string(),
+                stderr: "err\n".to_string(),
+                truncated: false,
+            }
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn long_running_commands_time_out() {
+        let request = ExecRequest::new(
+            vec!["sleep".to_string(), "10".to_string()],
+            None,
+            Some(Duration::from_millis(100)),
+        );
+        let err = run_local(&request.command().unwrap(), request.timeout).unwrap_err();
+        assert!(err.to_string().contains("did not finish"), "{:#}", err);
+    }
+}
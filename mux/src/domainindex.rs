@@ -0,0 +1,202 @@
+//! Counts the windows and tabs that hold the panes of each domain, so
+//! that `Mux::domain_inventory` doesn't have to visit every pane.
+//!
+//! A tab reports a layout change whenever its set of panes changes,
+//! and a window is invalidated whenever its set of tabs changes; the
+//! mux passes those on to the index, which revisits only the tabs and
+//! windows that changed the next time it is read.
+use crate::domain::DomainId;
+use crate::pane::{Pane, PaneId};
+use crate::tab::{Tab, TabId};
+use crate::window::{Window, WindowId};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Default)]
+struct DomainCounts {
+    /// The number of tabs in each window that hold panes of the domain
+    windows: HashMap<WindowId, usize>,
+    /// The sum of the tabs in `windows`
+    num_tabs: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct DomainIndex {
+    /// The panes of each indexed tab
+    tab_panes: HashMap<TabId, Vec<PaneId>>,
+    /// The tab that holds each pane of an indexed tab
+    pane_tabs: HashMap<PaneId, TabId>,
+    /// The domains of the registered panes of each indexed tab
+    tab_domains: HashMap<TabId, Vec<DomainId>>,
+    /// The tabs of each indexed window
+    window_tabs: HashMap<WindowId, Vec<TabId>>,
+    /// The window that holds each tab of an indexed window
+    tab_windows: HashMap<TabId, WindowId>,
+    counts: HashMap<DomainId, DomainCounts>,
+    dirty_tabs: HashSet<TabId>,
+    dirty_windows: HashSet<WindowId>,
+}
+
+impl DomainIndex {
+    /// Notes that the panes of a tab may have changed
+    pub fn tab_changed(&mut self, tab_id: TabId) {
+        self.dirty_tabs.insert(tab_id);
+    }
+
+    /// Notes that the tabs of a window may have changed
+    pub fn window_changed(&mut self, window_id: WindowId) {
+        self.dirty_windows.insert(window_id);
+    }
+
+    /// Notes that a pane was registered or unregistered, which changes
+    /// what its tab counts towards
+    pub fn pane_changed(&mut self, pane_id: PaneId) {
+        if let Some(tab_id) = self.pane_tabs.get(&pane_id) {
+            self.dirty_tabs.insert(*tab_id);
+        }
+    }
+
+    pub fn remove_tab(&mut self, tab_id: TabId) {
+        self.set_tab_window(tab_id, None);
+        self.forget_tab_panes(tab_id);
+        self.dirty_tabs.remove(&tab_id);
+    }
+
+    pub fn remove_window(&mut self, window_id: WindowId) {
+        for tab_id in self.window_tabs.remove(&window_id).unwrap_or_default() {
+            if self.tab_windows.get(&tab_id) == Some(&window_id) {
+                self.set_tab_window(tab_id, None);
+            }
+        }
+        self.dirty_windows.remove(&window_id);
+    }
+
+    /// Revisits the tabs and windows that changed since the last call.
+    /// Only the panes in `panes` count towards their domain.
+    pub fn refresh(
+        &mut self,
+        tabs: &HashMap<TabId, Rc<Tab>>,
+        windows: &HashMap<WindowId, Window>,
+        panes: &HashMap<PaneId, Rc<dyn Pane>>,
+    ) {
+        for window_id in std::mem::take(&mut self.dirty_windows) {
+            let window = match windows.get(&window_id) {
+                Some(window) => window,
+                None => {
+                    self.remove_window(window_id);
+                    continue;
+                }
+            };
+            let tab_ids: Vec<TabId> = window.iter().map(|tab| tab.tab_id()).collect();
+            let old = self
+                .window_tabs
+                .insert(window_id, tab_ids.clone())
+                .unwrap_or_default();
+            for tab_id in old {
+                if !tab_ids.contains(&tab_id) && self.tab_windows.get(&tab_id) == Some(&window_id) {
+                    self.set_tab_window(tab_id, None);
+                }
+            }
+            for tab_id in tab_ids {
+                self.set_tab_window(tab_id, Some(window_id));
+            }
+        }
+
+        for tab_id in std::mem::take(&mut self.dirty_tabs) {
+            let tab = match tabs.get(&tab_id) {
+                Some(tab) => tab,
+                None => {
+                    // Not registered (yet); it keeps its place in its
+                    // window, but doesn't count towards any domain
+                    self.forget_tab_panes(tab_id);
+                    continue;
+                }
+            };
+            let mut pane_ids = vec![];
+            let mut domains = vec![];
+            for pos in tab.iter_panes_ignoring_zoom() {
+                let pane_id = pos.pane.pane_id();
+                pane_ids.push(pane_id);
+                if panes.contains_key(&pane_id) {
+                    let domain_id = pos.pane.domain_id();
+                    if !domains.contains(&domain_id) {
+                        domains.push(domain_id);
+                    }
+                }
+            }
+            self.forget_tab_panes(tab_id);
+            for pane_id in &pane_ids {
+                self.pane_tabs.insert(*pane_id, tab_id);
+            }
+            self.tab_panes.insert(tab_id, pane_ids);
+            self.set_tab_domains(tab_id, domains);
+        }
+    }
+
+    /// Returns the number of windows and of tabs in those windows that
+    /// hold panes of `domain_id`, as of the last `refresh`
+    pub fn counts(&self, domain_id: DomainId) -> (usize, usize) {
+        self.counts
+            .get(&domain_id)
+            .map(|counts| (counts.windows.len(), counts.num_tabs))
+            .unwrap_or((0, 0))
+    }
+
+    fn forget_tab_panes(&mut self, tab_id: TabId) {
+        for pane_id in self.tab_panes.remove(&tab_id).unwrap_or_default() {
+            if self.pane_tabs.get(&pane_id) == Some(&tab_id) {
+                self.pane_tabs.remove(&pane_id);
+            }
+        }
+        self.set_tab_domains(tab_id, vec![]);
+    }
+
+    fn set_tab_domains(&mut self, tab_id: TabId, domains: Vec<DomainId>) {
+        let window_id = self.tab_windows.get(&tab_id).copied();
+        let old = self.tab_domains.remove(&tab_id).unwrap_or_default();
+        self.apply(window_id, &old, false);
+        self.apply(window_id, &domains, true);
+        if !domains.is_empty() {
+            self.tab_domains.insert(tab_id, domains);
+        }
+    }
+
+    fn set_tab_window(&mut self, tab_id: TabId, window_id: Option<WindowId>) {
+        let old = match window_id {
+            Some(window_id) => self.tab_windows.insert(tab_id, window_id),
+            None => self.tab_windows.remove(&tab_id),
+        };
+        if old == window_id {
+            return;
+        }
+        let domains = self.tab_domains.get(&tab_id).cloned().unwrap_or_default();
+        self.apply(old, &domains, false);
+        self.apply(window_id, &domains, true);
+    }
+
+    /// Adds or removes a tab in `window_id` to the counts of `domains`
+    fn apply(&mut self, window_id: Option<WindowId>, domains: &[DomainId], add: bool) {
+        let window_id = match window_id {
+            Some(window_id) => window_id,
+            None => return,
+        };
+        for domain_id in domains {
+            let counts = self.counts.entry(*domain_id).or_default();
+            if add {
+                *counts.windows.entry(window_id).or_insert(0) += 1;
+                counts.num_tabs += 1;
+                continue;
+            }
+            if let Some(num_tabs) = counts.windows.get_mut(&window_id) {
+                *num_tabs -= 1;
+                if *num_tabs == 0 {
+                    counts.windows.remove(&window_id);
+                }
+                counts.num_tabs -= 1;
+            }
+            if counts.windows.is_empty() {
+                self.counts.remove(domain_id);
+            }
+        }
+    }
+}
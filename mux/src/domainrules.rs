@@ -0,0 +1,255 @@
+//! Redirects new panes to another domain, following `domain_rules`,
+//! based on the directory that they start in: a tab opened from a
+//! pane in `~/src/legacy-app` can start in the container domain of
+//! that project rather than locally.
+//! Only spawns that use the domain of the current pane, or the default
+//! domain, are redirected; one that names its domain is left alone, as
+//! is one for which `skip_domain_rules_for_next_spawn` was called.
+//! The patterns of the rules are lua patterns, matched with the lua
+//! of the config, as `string.find` would.
+//! The directory is then mapped into the new domain by its
+//! `Domain::map_cwd`, like the cwd of any other spawn, and may be
+//! dropped if it is meaningless there.
+use crate::domain::{command_has_cwd, Domain};
+use crate::pane::{Pane, PaneId};
+use crate::{Mux, MuxNotification};
+use anyhow::anyhow;
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use config::DomainRule;
+use portable_pty::CommandBuilder;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A spawn that a rule sent to another domain
+pub struct DomainRedirect {
+    pub domain: Arc<dyn Domain>,
+    /// The directory that matched
+    pub cwd: CommandDir,
+    pub cwd_pattern: String,
+}
+
+/// Returns true if the lua pattern `pattern` matches `subject`
+pub fn lua_pattern_matches(lua: &mlua::Lua, subject: &str, pattern: &str) -> anyhow::Result<bool> {
+    let string: mlua::Table = lua.globals().get("string")?;
+    let find: mlua::Function = string.get("find")?;
+    let start: mlua::Value = find.call((subject, pattern))?;
+    Ok(!matches!(start, mlua::Value::Nil))
+}
+
+/// Returns the first of `rules` whose pattern `matches` the path
+/// `path`.  A rule whose pattern can't be matched is skipped.
+pub fn first_matching_rule<'a, F>(
+    rules: &'a [DomainRule],
+    path: &str,
+    matches: F,
+) -> Option<&'a DomainRule>
+where
+    F: Fn(&str, &str) -> anyhow::Result<bool>,
+{
+    rules
+        .iter()
+        .find(|rule| match matches(path, &rule.cwd_pattern) {
+            Ok(matched) => matched,
+            Err(err) => {
+                log::warn!(
+                    "domain_rules: cannot match the pattern `{}`: {:#}",
+                    rule.cwd_pattern,
+                    err
+                );
+                false
+            }
+        })
+}
+
+/// Returns the first of the configured `domain_rules` that matches the
+/// path `path`
+fn matching_rule(path: &str) -> Option<DomainRule> {
+    let rules = config::configuration().domain_rules.clone();
+    if rules.is_empty() {
+        return None;
+    }
+    let matched = config::run_immediate_with_lua_config(|lua| {
+        let lua = match lua {
+            Some(lua) => lua,
+            None => return Ok(None),
+        };
+        Ok(first_matching_rule(&rules, path, |path, pattern| {
+            lua_pattern_matches(&lua, path, pattern)
+        })
+        .cloned())
+    });
+    match matched {
+        Ok(rule) => rule,
+        Err(err) => {
+            log::warn!("domain_rules: {:#}", err);
+            None
+        }
+    }
+}
+
+impl Mux {
+    /// Has the next spawn or split ignore `domain_rules`.
+    /// It applies to the spawn or split that is started next, so it
+    /// must be called right before awaiting it.
+    pub fn skip_domain_rules_for_next_spawn(&self) {
+        self.skip_domain_rules.set(true);
+    }
+
+    /// Returns true, and forgets it, if the spawn that is starting
+    /// should ignore `domain_rules`
+    pub(crate) fn take_skip_domain_rules(&self) -> bool {
+        self.skip_domain_rules.replace(false)
+    }
+
+    /// Returns the domain to which `domain_rules` redirect a spawn
+    /// that requested `domain`, from `pane`, of `command` in
+    /// `command_dir`, or None if no rule applies.
+    /// The directory that is matched is `command_dir`, or else the cwd
+    /// of `pane`; a command that sets its own cwd is not redirected.
+    pub(crate) fn domain_rule_redirect(
+        &self,
+        domain: &SpawnTabDomain,
+        command: Option<&CommandBuilder>,
+        command_dir: Option<&CommandDir>,
+        pane: Option<&Rc<dyn Pane>>,
+    ) -> anyhow::Result<Option<DomainRedirect>> {
+        match domain {
+            SpawnTabDomain::CurrentPaneDomain | SpawnTabDomain::DefaultDomain => {}
+            SpawnTabDomain::DomainName(_) | SpawnTabDomain::DomainId(_) => return Ok(None),
+        }
+        if command_has_cwd(command) {
+            return Ok(None);
+        }
+        let cwd = match command_dir {
+            Some(dir) => dir.clone(),
+            None => match pane
+                .and_then(|pane| pane.get_current_working_dir())
+                .and_then(|url| CommandDir::from_url(&url))
+            {
+                Some(dir) => dir,
+                None => return Ok(None),
+            },
+        };
+        let rule = match matching_rule(&cwd.path) {
+            Some(rule) => rule,
+            None => return Ok(None),
+        };
+        let target = self.get_domain_by_name(&rule.domain).ok_or_else(|| {
+            anyhow!(
+                "domain_rules: the domain `{}` of the rule for `{}` was not found",
+                rule.domain,
+                rule.cwd_pattern
+            )
+        })?;
+        Ok(Some(DomainRedirect {
+            domain: target,
+            cwd,
+            cwd_pattern: rule.cwd_pattern,
+        }))
+    }
+
+    /// Returns the domain in which a spawn that requested `domain`,
+    /// from the pane `pane_id`, of `command` in `command_dir`, will
+    /// start, once `domain_rules` are applied unless `skip_rules` is
+    /// true, so that it can be checked before spawning
+    pub fn resolve_spawn_domain_with_rules(
+        &self,
+        pane_id: Option<PaneId>,
+        domain: &SpawnTabDomain,
+        command: Option<&CommandBuilder>,
+        command_dir: Option<&CommandDir>,
+        skip_rules: bool,
+    ) -> anyhow::Result<Arc<dyn Domain>> {
+        if !skip_rules {
+            let pane = pane_id.and_then(|pane_id| self.get_pane(pane_id));
+            if let Some(redirect) =
+                self.domain_rule_redirect(domain, command, command_dir, pane.as_ref())?
+            {
+                return Ok(redirect.domain);
+            }
+        }
+        self.resolve_spawn_tab_domain(pane_id, domain)
+    }
+
+    /// Tells the user that `pane` was spawned into another domain by
+    /// `redirect`, by printing a notice in it and by emitting
+    /// `MuxNotification::SpawnRedirected`
+    pub(crate) fn notify_spawn_redirected(&self, pane: &Rc<dyn Pane>, redirect: DomainRedirect) {
+        let pane_id = pane.pane_id();
+        let domain = redirect.domain.domain_name().to_string();
+        log::info!(
+            "pane {} was spawned in domain `{}`, as {} matches the domain rule `{}`",
+            pane_id,
+            domain,
+            redirect.cwd,
+            redirect.cwd_pattern
+        );
+        let notice = format!(
+            "\x1b[0m\x1b[2mwezterm: started in domain `{}`, \
+             as {} matches the domain rule `{}`\x1b[0m\r\n",
+            domain, redirect.cwd, redirect.cwd_pattern
+        );
+        let mut parser = termwiz::escape::parser::Parser::new();
+        let mut actions = vec![];
+        parser.parse(notice.as_bytes(), |action| actions.push(action));
+        pane.perform_actions(actions);
+        self.notify(MuxNotification::PaneOutput(pane_id));
+        self.notify(MuxNotification::SpawnRedirected {
+            pane_id,
+            domain,
+            cwd: redirect.cwd,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(cwd_pattern: &str, domain: &str) -> DomainRule {
+        DomainRule {
+            cwd_pattern: cwd_pattern.to_string(),
+            domain: domain.to_string(),
+        }
+    }
+
+    #[test]
+    fn lua_patterns() {
+        let lua = mlua::Lua::new();
+        let matches = |path: &str, pattern: &str| lua_pattern_matches(&lua, path, pattern).unwrap();
+        assert!(matches("/home/me/src/legacy-app/lib", "/legacy%-app"));
+        assert!(matches(
+            "/home/me/src/legacy-app",
+            "^/home/me/src/legacy%-app$"
+        ));
+        // An unescaped `-` is a lazy repetition
+        assert!(!matches(
+            "/home/me/src/legacy-app",
+            "^/home/me/src/legacy-app$"
+        ));
+        assert!(!matches("/home/me/src/app", "legacy"));
+        assert!(lua_pattern_matches(&lua, "/x", "[").is_err());
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let rules = vec![
+            rule("[", "broken"),
+            rule("/legacy", "legacy-app-container"),
+            rule("/src/", "devbox"),
+        ];
+        let lua = mlua::Lua::new();
+        let first = |path: &str| {
+            first_matching_rule(&rules, path, |path, pattern| {
+                lua_pattern_matches(&lua, path, pattern)
+            })
+            .map(|rule| rule.domain.as_str())
+        };
+        assert_eq!(
+            first("/home/me/src/legacy-app"),
+            Some("legacy-app-container")
+        );
+        assert_eq!(first("/home/me/src/other"), Some("devbox"));
+        assert_eq!(first("/tmp"), None);
+    }
+}
@@ -0,0 +1,221 @@
+//! Opens the scrollback of a pane in an editor, in a split of its tab,
+//! for the `EditScrollback` key assignment and `wezterm cli
+//! edit-scrollback`.
+//! The lines are written to a temporary file that only the user can
+//! read, and `$VISUAL`, or else `$EDITOR`, is spawned on it.  As the
+//! editor must run where the file is, it is spawned in the domain of the
+//! pane if that domain runs programs on this machine, and otherwise in
+//! the `local` domain.
+//! A pane that is hosted by a multiplexer server has the server do all
+//! of this, so that the editor runs on the remote host, on a file there;
+//! a `local` request copies the lines down and edits them here instead.
+//! The file is removed once the pane of the editor has closed.
+use crate::domain::{Domain, SplitSource};
+use crate::pane::{Pane, PaneId};
+use crate::renderable::RenderableDimensions;
+use crate::scrollback::ScrollbackFormat;
+use crate::tab::{SplitDirection, SplitRequest, SplitSize};
+use crate::{Mux, MuxNotification};
+use anyhow::{anyhow, Context};
+use config::keyassignment::SpawnTabDomain;
+use config::DomainKind;
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::Arc;
+use tempfile::TempPath;
+use wezterm_term::StableRowIndex;
+
+/// The editor that is used when neither `$VISUAL` nor `$EDITOR` is set
+#[cfg(windows)]
+const DEFAULT_EDITOR: &str = "notepad.exe";
+#[cfg(not(windows))]
+const DEFAULT_EDITOR: &str = "vi";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditScrollbackRequest {
+    /// The most lines to edit, counting back from the bottom of the
+    /// screen; all of them if None
+    pub lines: Option<usize>,
+    /// Keep the escape sequences for the attributes of the text,
+    /// rather than just the text
+    pub escapes: bool,
+    /// Edit the lines in this mux, even if the pane is hosted by a
+    /// multiplexer server
+    pub local: bool,
+}
+
+/// Returns the domain in which the editor for `pane` is spawned
+pub fn editor_domain(pane: &Rc<dyn Pane>) -> anyhow::Result<Arc<dyn Domain>> {
+    let mux = Mux::get().ok_or_else(|| anyhow!("the scrollback is edited on the main thread"))?;
+    if let Some(domain) = mux.get_domain(pane.domain_id()) {
+        if domain.domain_kind() == Some(DomainKind::Local) {
+            return Ok(domain);
+        }
+    }
+    mux.get_domain_by_name("local")
+        .ok_or_else(|| anyhow!("the local domain, in which the editor runs, was not found"))
+}
+
+/// Writes the scrollback of the pane `pane_id` to a temporary file, and
+/// opens it in an editor in a split of the tab of the pane, returning
+/// the id of the pane of the editor
+pub async fn edit_here(pane_id: PaneId, request: EditScrollbackRequest) -> anyhow::Result<PaneId> {
+    let mux = Mux::get().ok_or_else(|| anyhow!("the scrollback is edited on the main thread"))?;
+    let pane = mux
+        .get_pane(pane_id)
+        .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+    let domain = editor_domain(&pane)?;
+    let path = write_scrollback(&pane, request).await?;
+    let command = editor_command(
+        choose_editor(std::env::var("VISUAL").ok(), std::env::var("EDITOR").ok()),
+        path.as_os_str().to_os_string(),
+    )?;
+
+    let (editor, _size) = mux
+        .split_pane(
+            pane_id,
+            SplitRequest {
+                direction: SplitDirection::Horizontal,
+                target_is_second: true,
+                top_level: false,
+                size: SplitSize::Percent(50),
+            },
+            SplitSource::Spawn {
+                command: Some(command),
+                command_dir: None,
+            },
+            SpawnTabDomain::DomainId(domain.domain_id()),
+        )
+        .await?;
+    let editor_pane_id = editor.pane_id();
+    remove_when_closed(&mux, editor_pane_id, path);
+    Ok(editor_pane_id)
+}
+
+/// Returns the rows of the last `lines` lines of a pane with `dims`, or
+/// None for all of its rows
+fn edit_range(dims: &RenderableDimensions, lines: Option<usize>) -> Option<Range<StableRowIndex>> {
+    let bottom = dims.physical_top + dims.viewport_rows as StableRowIndex;
+    lines.map(|lines| bottom.saturating_sub(lines as StableRowIndex)..bottom)
+}
+
+async fn write_scrollback(
+    pane: &Rc<dyn Pane>,
+    request: EditScrollbackRequest,
+) -> anyhow::Result<TempPath> {
+    let format = if request.escapes {
+        ScrollbackFormat::Escapes
+    } else {
+        ScrollbackFormat::Text
+    };
+    // The file is created so that only the user can read it
+    let mut file = tempfile::Builder::new()
+        .prefix("wezterm-scrollback-")
+        .suffix(".txt")
+        .tempfile()
+        .context("creating the file for the scrollback")?;
+    let mut range = edit_range(&pane.get_dimensions(), request.lines);
+    let mut header = true;
+    loop {
+        let chunk = pane.render_scrollback(format, range, header).await?;
+        file.write_all(chunk.text.as_bytes())?;
+        match chunk.remaining {
+            Some(remaining) => {
+                range = Some(remaining);
+                header = false;
+            }
+            None => break,
+        }
+    }
+    file.flush()?;
+    Ok(file.into_temp_path())
+}
+
+/// Returns the editor command from `$VISUAL` or `$EDITOR`, whichever
+/// is set first, or the default editor
+fn choose_editor(visual: Option<String>, editor: Option<String>) -> String {
+    visual
+        .into_iter()
+        .chain(editor)
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string())
+}
+
+/// Returns the command that runs `editor`, which may include arguments,
+/// on `path`
+fn editor_command(editor: String, path: OsString) -> anyhow::Result<CommandBuilder> {
+    let mut argv: Vec<OsString> = shell_words::split(&editor)
+        .with_context(|| format!("parsing the editor command `{}`", editor))?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    if argv.is_empty() {
+        anyhow::bail!("the editor command is empty");
+    }
+    argv.push(path);
+    Ok(CommandBuilder::from_argv(argv))
+}
+
+/// Removes the file at `path` once the pane `pane_id` has been removed
+/// from the mux
+fn remove_when_closed(mux: &Mux, pane_id: PaneId, path: TempPath) {
+    let path = RefCell::new(Some(path));
+    mux.subscribe(move |notification| match notification {
+        MuxNotification::PaneRemoved(removed) if removed == pane_id => {
+            if let Some(path) = path.borrow_mut().take() {
+                if let Err(err) = path.close() {
+                    log::warn!("removing the scrollback of pane {}: {:#}", pane_id, err);
+                }
+            }
+            false
+        }
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn editors() {
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(choose_editor(some("code -w"), some("vim")), "code -w");
+        assert_eq!(choose_editor(some(" "), some("vim")), "vim");
+        assert_eq!(choose_editor(None, None), DEFAULT_EDITOR);
+
+        let command = editor_command("code -w".to_string(), "/tmp/x y".into()).unwrap();
+        assert_eq!(
+            command.get_argv(),
+            &vec![
+                OsString::from("code"),
+                OsString::from("-w"),
+                OsString::from("/tmp/x y")
+            ]
+        );
+        assert!(editor_command("".to_string(), "/tmp/x".into()).is_err());
+        assert!(editor_command("vim 'unterminated".to_string(), "/tmp/x".into()).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let dims = RenderableDimensions {
+            cols: 80,
+            viewport_rows: 24,
+            scrollback_rows: 1024,
+            physical_top: 1000,
+            scrollback_top: 0,
+            dpi: 96,
+        };
+        assert_eq!(edit_range(&dims, None), None);
+        assert_eq!(edit_range(&dims, Some(100)), Some(924..1024));
+        // Rows before the scrollback are skipped when the lines are
+        // rendered
+        assert_eq!(edit_range(&dims, Some(5000)), Some(-3976..1024));
+    }
+}
@@ -0,0 +1,296 @@
+//! Searches the scrollback of every pane at once, for `GlobalSearch`
+//! and `wezterm cli search`, using the search of each pane.
+//! Panes that are hosted by a multiplexer server are searched by the
+//! server, which also returns the text around each match, so that the
+//! client doesn't need to fetch their scrollback.
+//! As a pane may have a great deal of scrollback, only its most recent
+//! `max_matches_per_pane` matches are kept, and the panes are searched
+//! one at a time, so that the search can be cancelled between them.
+use crate::pane::{Pane, PaneId, Pattern, SearchResult};
+use crate::tab::TabId;
+use crate::window::WindowId;
+use crate::{Mux, MuxNotification};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use wezterm_term::Line;
+
+/// The number of matches that are kept for each pane by default
+pub const DEFAULT_MAX_MATCHES_PER_PANE: usize = 100;
+/// The most matches that may be kept for each pane
+pub const MAX_MATCHES_PER_PANE: usize = 1000;
+/// The most cells of the line of a match that are kept around it
+const SNIPPET_CELLS: usize = 120;
+/// The cells before the start of a match that are kept, when the
+/// line of the match is too long to keep all of it
+const SNIPPET_LEAD: usize = 30;
+
+/// Selects the panes that are searched
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalSearchOptions {
+    /// Only search the panes of the domain with this name
+    pub domain: Option<String>,
+    /// Only search the panes of the windows of this workspace
+    pub workspace: Option<String>,
+    pub tab_id: Option<TabId>,
+    pub pane_id: Option<PaneId>,
+    /// The most matches that are kept for each pane, which is
+    /// limited to `MAX_MATCHES_PER_PANE`
+    pub max_matches_per_pane: usize,
+}
+
+impl Default for GlobalSearchOptions {
+    fn default() -> Self {
+        Self {
+            domain: None,
+            workspace: None,
+            tab_id: None,
+            pane_id: None,
+            max_matches_per_pane: DEFAULT_MAX_MATCHES_PER_PANE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalSearchMatch {
+    pub pane_id: PaneId,
+    pub result: SearchResult,
+    /// The text of the line of the match, around the match
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalSearchResults {
+    /// The matches, pane by pane, the most recent first within each
+    pub matches: Vec<GlobalSearchMatch>,
+    /// The panes that had more matches than were kept
+    pub truncated: Vec<PaneId>,
+}
+
+impl GlobalSearchResults {
+    fn extend(&mut self, other: GlobalSearchResults) {
+        self.matches.extend(other.matches);
+        self.truncated.extend(other.truncated);
+    }
+}
+
+/// Cancels a search that is in progress; it stops before searching
+/// the next pane
+#[derive(Debug, Clone, Default)]
+pub struct SearchCancellation(Arc<AtomicBool>);
+
+impl SearchCancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the `limit` most recent of `results`, the most recent first,
+/// and whether any were left out
+pub fn newest_matches(mut results: Vec<SearchResult>, limit: usize) -> (Vec<SearchResult>, bool) {
+    results.sort_by(|a, b| (b.start_y, b.start_x).cmp(&(a.start_y, a.start_x)));
+    let truncated = results.len() > limit;
+    results.truncate(limit);
+    (results, truncated)
+}
+
+/// Returns the text of `line` around the match that starts at the
+/// cell `start_x`, without its trailing blanks
+pub fn snippet(line: &Line, start_x: usize) -> String {
+    let len = line.cells().len();
+    let first = if len <= SNIPPET_CELLS {
+        0
+    } else {
+        start_x
+            .saturating_sub(SNIPPET_LEAD)
+            .min(len - SNIPPET_CELLS)
+    };
+    let last = (first + SNIPPET_CELLS).min(len);
+    line.columns_as_str(first..last).trim_end().to_string()
+}
+
+/// Searches `pane` for the default implementation of
+/// `Pane::search_with_snippets`
+pub async fn search_pane<P: Pane + ?Sized>(
+    pane: &P,
+    pattern: Pattern,
+    limit: usize,
+) -> anyhow::Result<GlobalSearchResults> {
+    let pane_id = pane.pane_id();
+    let (results, truncated) = newest_matches(pane.search(pattern).await?, limit);
+    let matches = results
+        .into_iter()
+        .map(|result| {
+            let (_, lines) = pane.get_lines(result.start_y..result.start_y + 1);
+            GlobalSearchMatch {
+                pane_id,
+                result,
+                snippet: lines
+                    .first()
+                    .map(|line| snippet(line, result.start_x))
+                    .unwrap_or_default(),
+            }
+        })
+        .collect();
+    Ok(GlobalSearchResults {
+        matches,
+        truncated: if truncated { vec![pane_id] } else { vec![] },
+    })
+}
+
+impl Mux {
+    /// Returns the panes that `options` selects, in order of their ids
+    fn panes_to_search(&self, options: &GlobalSearchOptions) -> Vec<Rc<dyn Pane>> {
+        let mut panes = self.iter_panes();
+        panes.retain(|pane| {
+            let pane_id = pane.pane_id();
+            if options.pane_id.map_or(false, |id| id != pane_id) {
+                return false;
+            }
+            if let Some(name) = &options.domain {
+                let in_domain = self
+                    .get_domain(pane.domain_id())
+                    .map_or(false, |domain| domain.domain_name() == name);
+                if !in_domain {
+                    return false;
+                }
+            }
+            if options.workspace.is_none() && options.tab_id.is_none() {
+                return true;
+            }
+            let (_domain_id, window_id, tab_id) = match self.resolve_pane_id(pane_id) {
+                Some(found) => found,
+                None => return false,
+            };
+            if options.tab_id.map_or(false, |id| id != tab_id) {
+                return false;
+            }
+            match &options.workspace {
+                Some(workspace) => self
+                    .get_window(window_id)
+                    .map_or(false, |window| window.get_workspace() == workspace),
+                None => true,
+            }
+        });
+        panes.sort_by_key(|pane| pane.pane_id());
+        panes
+    }
+
+    /// Searches the panes that `options` selects for `pattern`.
+    /// A pane that can't be searched is skipped.
+    /// Fails if `cancel` is cancelled before every pane was searched.
+    pub async fn search_all_panes(
+        &self,
+        pattern: Pattern,
+        options: &GlobalSearchOptions,
+        cancel: &SearchCancellation,
+    ) -> anyhow::Result<GlobalSearchResults> {
+        if pattern.is_empty() {
+            anyhow::bail!("the search pattern is empty");
+        }
+        if let Pattern::Regex(re) = &pattern {
+            regex::Regex::new(re)?;
+        }
+        let limit = options.max_matches_per_pane.min(MAX_MATCHES_PER_PANE);
+
+        let mut results = GlobalSearchResults::default();
+        for pane in self.panes_to_search(options) {
+            if cancel.is_cancelled() {
+                anyhow::bail!("the search was cancelled");
+            }
+            match pane.search_with_snippets(pattern.clone(), limit).await {
+                Ok(found) => results.extend(found),
+                Err(err) => log::warn!("searching pane {}: {:#}", pane.pane_id(), err),
+            }
+            // Let the rest of the mux run between panes
+            smol::future::yield_now().await;
+        }
+        Ok(results)
+    }
+
+    /// Makes the pane of `found` the active pane of its tab, that tab
+    /// the active tab of its window, and the workspace of the window
+    /// the active workspace, and scrolls the pane to the match.
+    /// Returns the window of the pane.
+    pub fn jump_to_search_match(&self, found: &GlobalSearchMatch) -> anyhow::Result<WindowId> {
+        let pane_id = found.pane_id;
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        let (_domain_id, window_id, tab_id) = self
+            .resolve_pane_id(pane_id)
+            .ok_or_else(|| anyhow!("pane {} is not in a window", pane_id))?;
+        let tab = self
+            .get_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab {} not found", tab_id))?;
+
+        let workspace = self
+            .get_window(window_id)
+            .map(|window| window.get_workspace().to_string());
+        if let Some(workspace) = workspace {
+            if workspace != self.active_workspace() {
+                self.set_active_workspace(&workspace);
+            }
+        }
+        tab.set_active_pane(&pane);
+        if let Some(mut window) = self.get_window_mut(window_id) {
+            if let Some(idx) = window.idx_by_id(tab_id) {
+                window.save_and_then_set_active(idx);
+            }
+        }
+        self.notify(MuxNotification::ScrollToRow {
+            pane_id,
+            row: found.result.start_y,
+        });
+        Ok(window_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::surface::SEQ_ZERO;
+
+    fn result(start_y: isize, start_x: usize) -> SearchResult {
+        SearchResult {
+            start_y,
+            start_x,
+            end_y: start_y,
+            end_x: start_x + 1,
+            match_id: 0,
+        }
+    }
+
+    #[test]
+    fn newest_first() {
+        let results = vec![result(1, 0), result(5, 2), result(5, 8), result(3, 0)];
+        let (newest, truncated) = newest_matches(results.clone(), 3);
+        assert_eq!(newest, vec![result(5, 8), result(5, 2), result(3, 0)]);
+        assert!(truncated);
+        let (all, truncated) = newest_matches(results, 10);
+        assert_eq!(all.len(), 4);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn snippets() {
+        let short = Line::from_text("error: disk full   ", &Default::default(), SEQ_ZERO, None);
+        assert_eq!(snippet(&short, 0), "error: disk full");
+
+        let text = format!("{}error{}", "a".repeat(200), "b".repeat(200));
+        let long = Line::from_text(&text, &Default::default(), SEQ_ZERO, None);
+        let found = snippet(&long, 200);
+        assert_eq!(found.len(), SNIPPET_CELLS);
+        assert!(found.starts_with(&"a".repeat(SNIPPET_LEAD)));
+        assert!(found[SNIPPET_LEAD..].starts_with("error"));
+        // A match near the end keeps a full snippet
+        assert!(snippet(&long, 400).ends_with("bbb"));
+        assert_eq!(snippet(&long, 400).len(), SNIPPET_CELLS);
+    }
+}
@@ -0,0 +1,348 @@
+//! A record of where the panes have been and what was run in them.
+//! Each pane keeps a bounded history of the working directories that
+//! it reported with OSC 7 and, when the shell marks its input with
+//! OSC 133, of the commands that it ran.  The directories of all of
+//! the panes are also aggregated into a list of recently visited
+//! directories, which the launcher offers with `SpawnInRecentDir`.
+//! A directory is tagged with the name of the domain of the pane that
+//! visited it, so that spawning into it targets the same host.
+//! The GUI and the mux server persist the recent directories in
+//! `RUNTIME_DIR/recent-dirs.json` so that they survive a restart; the
+//! per-pane history and the commands are only kept in memory.
+use crate::pane::PaneId;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// The most entries that the history of a pane holds; older entries
+/// are dropped to make room for new ones
+pub const MAX_HISTORY_PER_PANE: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryEvent {
+    /// The pane changed its working directory to this url
+    WorkingDir(String),
+    /// The pane started running this command
+    Command(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: DateTime<Utc>,
+    pub event: HistoryEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentDir {
+    /// The working directory, as a url
+    pub cwd: String,
+    /// The name of the domain of the panes that visited it
+    pub domain: String,
+    pub last_visited: DateTime<Utc>,
+    /// How many times a pane changed into the directory
+    pub visits: usize,
+}
+
+impl RecentDir {
+    fn is_same_dir(&self, other: &RecentDir) -> bool {
+        self.cwd == other.cwd && self.domain == other.domain
+    }
+}
+
+#[derive(Default)]
+pub struct PaneHistory {
+    by_pane: HashMap<PaneId, VecDeque<HistoryEntry>>,
+    /// Ordered by `last_visited`, most recent first
+    recent: Vec<RecentDir>,
+    /// Where the recent directories are persisted, if they are
+    persist_path: Option<PathBuf>,
+    /// Whether the recent directories changed since they were saved
+    dirty: bool,
+}
+
+impl PaneHistory {
+    fn push(&mut self, pane_id: PaneId, entry: HistoryEntry) {
+        let entries = self.by_pane.entry(pane_id).or_default();
+        if entries.len() == MAX_HISTORY_PER_PANE {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Records that the pane changed its working directory to `cwd`.
+    /// Shells tend to report the directory with every prompt, so a
+    /// report of the directory that the pane is already in is ignored.
+    /// Returns true if the recent directories changed.
+    pub fn record_cwd(
+        &mut self,
+        pane_id: PaneId,
+        domain: &str,
+        cwd: String,
+        now: DateTime<Utc>,
+        max_recent: usize,
+    ) -> bool {
+        let current = self.by_pane.get(&pane_id).and_then(|entries| {
+            entries.iter().rev().find_map(|entry| match &entry.event {
+                HistoryEvent::WorkingDir(cwd) => Some(cwd),
+                HistoryEvent::Command(_) => None,
+            })
+        });
+        if current == Some(&cwd) {
+            return false;
+        }
+        self.push(
+            pane_id,
+            HistoryEntry {
+                time: now,
+                event: HistoryEvent::WorkingDir(cwd.clone()),
+            },
+        );
+
+        let mut dir = RecentDir {
+            cwd,
+            domain: domain.to_string(),
+            last_visited: now,
+            visits: 1,
+        };
+        if let Some(idx) = self.recent.iter().position(|r| r.is_same_dir(&dir)) {
+            dir.visits += self.recent.remove(idx).visits;
+        }
+        self.recent.insert(0, dir);
+        self.recent.truncate(max_recent);
+        self.dirty = true;
+        true
+    }
+
+    /// Records that the pane started running `command`
+    pub fn record_command(&mut self, pane_id: PaneId, command: String, now: DateTime<Utc>) {
+        self.push(
+            pane_id,
+            HistoryEntry {
+                time: now,
+                event: HistoryEvent::Command(command),
+            },
+        );
+    }
+
+    /// Returns the history of the pane, oldest entry first
+    pub fn pane_history(&self, pane_id: PaneId) -> Vec<HistoryEntry> {
+        self.by_pane
+            .get(&pane_id)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        self.by_pane.remove(&pane_id);
+    }
+
+    /// Returns up to `limit` of the recent directories, most recently
+    /// visited first
+    pub fn recent_dirs(&self, limit: Option<usize>) -> Vec<RecentDir> {
+        let limit = limit.unwrap_or(self.recent.len());
+        self.recent.iter().take(limit).cloned().collect()
+    }
+
+    /// Merges `dirs`, such as those persisted by another process, into
+    /// the recent directories.  Where both have the same directory, the
+    /// later visit and the larger count are kept.
+    pub fn merge_recent(&mut self, dirs: Vec<RecentDir>, max_recent: usize) {
+        for dir in dirs {
+            match self.recent.iter_mut().find(|r| r.is_same_dir(&dir)) {
+                Some(existing) => {
+                    existing.last_visited = existing.last_visited.max(dir.last_visited);
+                    existing.visits = existing.visits.max(dir.visits);
+                }
+                None => self.recent.push(dir),
+            }
+        }
+        self.recent
+            .sort_by(|a, b| b.last_visited.cmp(&a.last_visited));
+        self.recent.truncate(max_recent);
+    }
+
+    /// Loads the recent directories that were persisted at `path`, and
+    /// persists them there from now on
+    pub fn persist_to(&mut self, path: PathBuf, max_recent: usize) -> anyhow::Result<()> {
+        let loaded = load_recent_dirs(&path);
+        self.persist_path.replace(path);
+        self.merge_recent(loaded?, max_recent);
+        Ok(())
+    }
+
+    pub fn needs_save(&self) -> bool {
+        self.dirty && self.persist_path.is_some()
+    }
+
+    /// Saves the recent directories if they are persisted and have
+    /// changed since they were last saved.  The file is merged with
+    /// what is already there, as another process may share it.
+    pub fn save(&mut self, max_recent: usize) -> anyhow::Result<()> {
+        if !self.needs_save() {
+            return Ok(());
+        }
+        let path = self.persist_path.clone().unwrap();
+        self.dirty = false;
+        match load_recent_dirs(&path) {
+            Ok(dirs) => self.merge_recent(dirs, max_recent),
+            Err(err) => log::warn!("{:#}; it will be replaced", err),
+        }
+        save_recent_dirs(&path, &self.recent)
+    }
+}
+
+impl Drop for PaneHistory {
+    fn drop(&mut self) {
+        if !self.needs_save() {
+            return;
+        }
+        let max_recent = config::configuration().recent_dirs_max_entries;
+        if let Err(err) = self.save(max_recent) {
+            log::error!("saving recent directories: {:#}", err);
+        }
+    }
+}
+
+/// Where the GUI and the mux server persist the recent directories
+pub fn recent_dirs_path() -> PathBuf {
+    config::RUNTIME_DIR.join("recent-dirs.json")
+}
+
+/// Returns the recent directories persisted at `path`, or none if
+/// nothing has been persisted yet
+fn load_recent_dirs(path: &Path) -> anyhow::Result<Vec<RecentDir>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+    };
+    serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn save_recent_dirs(path: &Path, dirs: &[RecentDir]) -> anyhow::Result<()> {
+    let data = serde_json::to_vec(dirs)?;
+    // Write to a temporary file and rename it into place, so that
+    // a concurrent reader never sees a partial list
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data).with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("renaming {}", tmp.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cwds(history: &PaneHistory) -> Vec<(String, String, usize)> {
+        history
+            .recent_dirs(None)
+            .into_iter()
+            .map(|dir| (dir.domain, dir.cwd, dir.visits))
+            .collect()
+    }
+
+    fn dir(domain: &str, cwd: &str) -> (String, String, usize) {
+        (domain.to_string(), cwd.to_string(), 1)
+    }
+
+    #[test]
+    fn recency() {
+        let now = Utc::now();
+        let mut history = PaneHistory::default();
+        assert!(history.record_cwd(1, "local", "file:///a".to_string(), now, 10));
+        assert!(!history.record_cwd(1, "local", "file:///a".to_string(), now, 10));
+        assert!(history.record_cwd(2, "local", "file:///b".to_string(), now, 10));
+        assert!(history.record_cwd(3, "unix", "file:///b".to_string(), now, 10));
+        assert!(history.record_cwd(1, "local", "file:///b".to_string(), now, 10));
+        assert_eq!(
+            cwds(&history),
+            vec![
+                ("local".to_string(), "file:///b".to_string(), 2),
+                dir("unix", "file:///b"),
+                dir("local", "file:///a"),
+            ]
+        );
+        assert_eq!(history.recent_dirs(Some(1)).len(), 1);
+
+        assert!(history.record_cwd(4, "local", "file:///c".to_string(), now, 2));
+        assert_eq!(
+            cwds(&history),
+            vec![
+                dir("local", "file:///c"),
+                ("local".to_string(), "file:///b".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn pane_history() {
+        let now = Utc::now();
+        let mut history = PaneHistory::default();
+        history.record_cwd(1, "local", "file:///a".to_string(), now, 10);
+        history.record_command(1, "ls".to_string(), now);
+        // Running a command doesn't make the directory new again
+        assert!(!history.record_cwd(1, "local", "file:///a".to_string(), now, 10));
+        let events: Vec<HistoryEvent> = history
+            .pane_history(1)
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                HistoryEvent::WorkingDir("file:///a".to_string()),
+                HistoryEvent::Command("ls".to_string())
+            ]
+        );
+
+        for i in 0..MAX_HISTORY_PER_PANE {
+            history.record_command(1, i.to_string(), now);
+        }
+        let entries = history.pane_history(1);
+        assert_eq!(entries.len(), MAX_HISTORY_PER_PANE);
+        assert_eq!(entries[0].event, HistoryEvent::Command("0".to_string()));
+
+        history.remove_pane(1);
+        assert!(history.pane_history(1).is_empty());
+        // The directory is still recent after the pane has gone
+        assert_eq!(history.recent_dirs(None).len(), 1);
+    }
+
+    #[test]
+    fn persistence() {
+        let now = Utc::now();
+        let earlier = now - chrono::Duration::hours(1);
+        let path = std::env::temp_dir().join(format!(
+            "wezterm-recent-dirs-test-{}.json",
+            std::process::id()
+        ));
+
+        let mut first = PaneHistory::default();
+        first.persist_to(path.clone(), 10).unwrap();
+        let mut second = PaneHistory::default();
+        second.persist_to(path.clone(), 10).unwrap();
+
+        first.record_cwd(1, "local", "file:///a".to_string(), earlier, 10);
+        first.save(10).unwrap();
+        // Saving keeps what another process that shares the file saved
+        second.record_cwd(1, "local", "file:///b".to_string(), now, 10);
+        second.save(10).unwrap();
+
+        let mut third = PaneHistory::default();
+        third.persist_to(path.clone(), 10).unwrap();
+        assert_eq!(
+            cwds(&third),
+            vec![dir("local", "file:///b"), dir("local", "file:///a")]
+        );
+
+        std::fs::write(&path, "garbage").unwrap();
+        let mut fourth = PaneHistory::default();
+        assert!(fourth.persist_to(path.clone(), 10).is_err());
+        // The broken file is replaced on the next save
+        fourth.record_cwd(1, "local", "file:///c".to_string(), now, 10);
+        fourth.save(10).unwrap();
+        assert_eq!(load_recent_dirs(&path).unwrap().len(), 1);
+        std::fs::remove_file(&path).ok();
+    }
+}
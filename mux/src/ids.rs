@@ -0,0 +1,332 @@
+//! Tracks the counters used to allocate pane, tab, window and domain ids.
+//! A mux server can optionally persist these counters to its runtime
+//! directory so that ids remain unique across restarts, and every
+//! process has an instance id that allows clients to detect that
+//! they are talking to a different incarnation of the server.
+use anyhow::Context;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+
+/// How many ids of each kind are reserved in the file ahead of
+/// being allocated
+const RESERVE_BATCH: usize = 1024;
+
+lazy_static! {
+    static ref INSTANCE_ID: String = generate_instance_id();
+    static ref SAVER: Mutex<Option<SyncSender<()>>> = Mutex::new(None);
+}
+
+static PERSISTING: AtomicBool = AtomicBool::new(false);
+
+/// The ceilings most recently recorded in the file, in the same order
+/// as `counters()`
+static RESERVED: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+fn counters() -> [(&'static str, &'static AtomicUsize); 4] {
+    [
+        ("pane", &crate::pane::PANE_ID),
+        ("tab", &crate::tab::TAB_ID),
+        ("window", &crate::window::WIN_ID),
+        ("domain", &crate::domain::DOMAIN_ID),
+    ]
+}
+
+fn random_u64() -> u64 {
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+fn generate_instance_id() -> String {
+    format!("{:x}-{:016x}", std::process::id(), random_u64())
+}
+
+/// Returns an identifier that is unique to this process.
+/// It is reported to clients during the version handshake.
+pub fn instance_id() -> &'static str {
+    INSTANCE_ID.as_str()
+}
+
+fn parse_id_counters(data: &str) -> HashMap<String, usize> {
+    data.lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+fn format_id_counters(values: &HashMap<String, usize>) -> String {
+    let mut names: Vec<&String> = values.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| format!("{}={}\n", name, values[name]))
+        .collect()
+}
+
+fn read_id_counters(path: &Path) -> anyhow::Result<Option<HashMap<String, usize>>> {
+    match std::fs::read_to_string(path) {
+        Ok(data) => Ok(Some(parse_id_counters(&data))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Holds an advisory lock on `file` until it is closed
+fn lock_file(file: &std::fs::File) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::fileapi::LockFileEx;
+        use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+        let mut ov: OVERLAPPED = unsafe { std::mem::zeroed() };
+        if unsafe {
+            LockFileEx(
+                file.as_raw_handle() as _,
+                LOCKFILE_EXCLUSIVE_LOCK,
+                0,
+                !0,
+                !0,
+                &mut ov,
+            )
+        } == 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Reserves the next `RESERVE_BATCH` ids of each of `counters` in the
+/// file at `path`, so that the file only needs to be updated once per
+/// batch rather than once per id, and returns the recorded ceilings.
+/// Other mux servers may share the file, so this holds an advisory
+/// lock while it merges its values with theirs.  Each counter is first
+/// raised to the value in the file, so that a batch never overlaps one
+/// that was reserved by another server, and the file never moves
+/// backwards.
+fn reserve_id_counters(
+    path: &Path,
+    counters: &[(&str, &AtomicUsize)],
+) -> anyhow::Result<Vec<usize>> {
+    let lock_path = path.with_extension("lock");
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("opening {}", lock_path.display()))?;
+    lock_file(&lock).with_context(|| format!("locking {}", lock_path.display()))?;
+
+    let mut values = read_id_counters(path)?.unwrap_or_default();
+    let mut ceilings = vec![];
+    for (name, counter) in counters {
+        let value = values.entry(name.to_string()).or_insert(0);
+        counter.fetch_max(*value, Ordering::Relaxed);
+        let ceiling = counter.load(Ordering::Relaxed) + RESERVE_BATCH;
+        *value = (*value).max(ceiling);
+        ceilings.push(ceiling);
+    }
+
+    // Write to a temporary file and rename it into place so
+    // that a crash can't leave us with a truncated file
+    let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&temp, format_id_counters(&values))
+        .with_context(|| format!("writing {}", temp.display()))?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("renaming {} -> {}", temp.display(), path.display()))?;
+    Ok(ceilings)
+}
+
+/// Arrange for the id counters to be loaded from `path` and for
+/// them to be saved back to it as ids are allocated.
+/// If there is no saved state and `randomize_epoch` is true, the
+/// counters start from a random offset so that ids are unlikely
+/// to collide with those issued by a previous incarnation whose
+/// state was lost.
+/// This must be called before any domains or panes are created.
+pub fn enable_id_persistence(path: PathBuf, randomize_epoch: bool) -> anyhow::Result<()> {
+    match read_id_counters(&path)? {
+        Some(saved) => {
+            for (name, counter) in counters() {
+                if let Some(value) = saved.get(name) {
+                    counter.fetch_max(*value, Ordering::Relaxed);
+                }
+            }
+        }
+        None if randomize_epoch => {
+            // Keep the offset comfortably within the range of
+            // integers that lua can represent exactly
+            let epoch = ((random_u64() % 0x10000) as usize) << 16;
+            for (_name, counter) in counters() {
+                counter.fetch_max(epoch, Ordering::Relaxed);
+            }
+        }
+        None => {}
+    }
+
+    // Reserve the first batch before any ids are handed out
+    let ceilings = reserve_id_counters(&path, &counters())?;
+    for (reserved, ceiling) in RESERVED.iter().zip(ceilings.iter()) {
+        reserved.store(*ceiling, Ordering::Relaxed);
+    }
+
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name("mux-ids".to_string())
+        .spawn(move || {
+            for () in rx {
+                match reserve_id_counters(&path, &counters()) {
+                    Ok(ceilings) => {
+                        for (reserved, ceiling) in RESERVED.iter().zip(ceilings.iter()) {
+                            reserved.store(*ceiling, Ordering::Relaxed);
+                        }
+                    }
+                    Err(err) => log::error!("Failed to save id counters: {:#}", err),
+                }
+            }
+        })
+        .context("spawning mux-ids thread")?;
+    SAVER.lock().unwrap().replace(tx);
+    PERSISTING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Called after an id has been allocated.  If persistence has been
+/// enabled and the counters are getting close to the ids reserved in
+/// the file, asks the saver thread to reserve another batch; the file
+/// is never touched on the allocating thread.
+pub(crate) fn note_id_allocated() {
+    if !PERSISTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let running_low = counters()
+        .iter()
+        .zip(RESERVED.iter())
+        .any(|((_name, counter), reserved)| {
+            counter.load(Ordering::Relaxed) + RESERVE_BATCH / 2 > reserved.load(Ordering::Relaxed)
+        });
+    if running_low {
+        if let Some(saver) = SAVER.lock().unwrap().as_ref() {
+            // A full channel means that a save is already pending
+            saver.try_send(()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_counters() {
+        let mut values = HashMap::new();
+        values.insert("pane".to_string(), 42);
+        values.insert("domain".to_string(), 3);
+        let data = format_id_counters(&values);
+        assert_eq!(data, "domain=3\npane=42\n");
+        assert_eq!(parse_id_counters(&data), values);
+    }
+
+    #[test]
+    fn parse_ignores_garbage() {
+        let parsed = parse_id_counters("pane=10\nbogus\ntab=nope\n window = 7 \n");
+        let mut expected = HashMap::new();
+        expected.insert("pane".to_string(), 10);
+        expected.insert("window".to_string(), 7);
+        assert_eq!(parsed, expected);
+    }
+
+    /// The counters of a mux server, starting at `values`
+    fn allocator(values: [usize; 4]) -> Vec<AtomicUsize> {
+        values
+            .iter()
+            .map(|value| AtomicUsize::new(*value))
+            .collect()
+    }
+
+    fn reserve(path: &Path, allocator: &[AtomicUsize]) -> Vec<usize> {
+        let names = ["pane", "tab", "window", "domain"];
+        let counters: Vec<(&str, &AtomicUsize)> =
+            names.iter().copied().zip(allocator.iter()).collect();
+        reserve_id_counters(path, &counters).unwrap()
+    }
+
+    fn values(allocator: &[AtomicUsize]) -> Vec<usize> {
+        allocator
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn reserve_never_moves_counters_backwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mux-ids");
+
+        let first = allocator([10, 20, 30, 40]);
+        assert_eq!(reserve(&path, &first), vec![1034, 1044, 1054, 1064]);
+        let second = allocator([5, 2000, 30, 1]);
+        assert_eq!(reserve(&path, &second), vec![2058, 3024, 2078, 2088]);
+
+        let saved = read_id_counters(&path).unwrap().unwrap();
+        assert_eq!(saved["pane"], 2058);
+        assert_eq!(saved["tab"], 3024);
+        assert_eq!(saved["window"], 2078);
+        assert_eq!(saved["domain"], 2088);
+
+        // Only the counters and the lock file are left behind
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["mux-ids", "mux-ids.lock"]);
+    }
+
+    #[test]
+    fn allocators_sharing_a_file_do_not_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mux-ids");
+
+        let first = allocator([0; 4]);
+        let first_ceilings = reserve(&path, &first);
+        assert_eq!(first_ceilings, vec![RESERVE_BATCH; 4]);
+
+        // The second server starts after the batch of the first
+        let second = allocator([0; 4]);
+        let second_ceilings = reserve(&path, &second);
+        assert_eq!(values(&second), first_ceilings);
+
+        // When the first runs low, it skips the batch of the second
+        for counter in &first {
+            counter.fetch_add(RESERVE_BATCH - 10, Ordering::Relaxed);
+        }
+        let ceilings = reserve(&path, &first);
+        assert_eq!(values(&first), second_ceilings);
+        assert_eq!(ceilings, vec![3 * RESERVE_BATCH; 4]);
+    }
+
+    #[test]
+    fn instance_id_is_stable() {
+        assert_eq!(instance_id(), instance_id());
+        assert!(instance_id().starts_with(&format!("{:x}-", std::process::id())));
+    }
+}
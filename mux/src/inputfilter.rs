@@ -0,0 +1,225 @@
+//! A chain of filters that see the input that the user sends to a pane
+//! before it reaches the writer of the pane, and that can pass, modify
+//! or reject it.
+//! Filters are registered with `Mux::add_input_filter`; the input lock
+//! of sensitive domains and the `pane-input` lua event are both
+//! implemented as filters.
+//! Filters run on the main thread.  While none are registered, checking
+//! the chain costs a single atomic load.
+use crate::pane::Pane;
+use std::borrow::Cow;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of filters registered across all chains
+static NUM_FILTERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if any input filters are registered.
+/// Callers that have to do extra work to present their input to the
+/// filters, such as encoding a key press, use this to skip it.
+pub fn have_input_filters() -> bool {
+    NUM_FILTERS.load(Ordering::Relaxed) > 0
+}
+
+/// Where the input to a pane came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputSource {
+    /// Typed by the user, or sent by a key assignment
+    Keyboard,
+    /// Pasted by the user; the whole paste is filtered at once
+    Paste,
+    /// Sent by `wezterm cli send-text --no-paste`
+    Cli,
+    /// Pasted by `wezterm cli send-text`
+    CliPaste,
+}
+
+impl InputSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Keyboard => "keyboard",
+            Self::Paste => "paste",
+            Self::Cli | Self::CliPaste => "cli",
+        }
+    }
+}
+
+/// Describes why a filter rejected some input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRejection {
+    /// The name of the filter that rejected the input
+    pub filter: String,
+    pub reason: String,
+    /// If true, the frontend asks the user whether to send the input
+    /// anyway, rather than showing a notification
+    pub confirm: bool,
+}
+
+/// The decision of a filter about some input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputVerdict {
+    /// Deliver the input, subject to the remaining filters
+    Pass,
+    /// Deliver this data in place of the input, subject to the
+    /// remaining filters
+    Replace(Vec<u8>),
+    /// Discard the input and tell the user why
+    Reject(InputRejection),
+    /// Don't deliver the input now; the filter has taken it, and has
+    /// already told the user
+    Hold,
+}
+
+pub trait InputFilter {
+    /// Identifies the filter in rejections, and when it is removed
+    fn name(&self) -> &str;
+
+    fn filter_input(&self, pane: &Rc<dyn Pane>, data: &[u8], source: InputSource) -> InputVerdict;
+}
+
+/// The outcome of running input through the whole chain
+#[derive(Debug, PartialEq, Eq)]
+pub enum FilteredInput<'a> {
+    Deliver(Cow<'a, [u8]>),
+    Rejected(InputRejection),
+    Held,
+}
+
+#[derive(Default)]
+pub struct InputFilterChain {
+    filters: Vec<Rc<dyn InputFilter>>,
+}
+
+impl InputFilterChain {
+    /// Adds `filter` after the existing filters, replacing any filter
+    /// with the same name
+    pub fn push(&mut self, filter: Rc<dyn InputFilter>) {
+        self.remove(filter.name());
+        self.filters.push(filter);
+        NUM_FILTERS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds `filter` ahead of the existing filters, replacing any
+    /// filter with the same name
+    pub fn push_front(&mut self, filter: Rc<dyn InputFilter>) {
+        self.remove(filter.name());
+        self.filters.insert(0, filter);
+        NUM_FILTERS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns true if a filter named `name` was removed
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.filters.iter().position(|f| f.name() == name) {
+            Some(idx) => {
+                self.filters.remove(idx);
+                NUM_FILTERS.fetch_sub(1, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.filters.iter().any(|f| f.name() == name)
+    }
+
+    /// Returns the filters in the order that they run.
+    /// The chain is cloned so that filters can add or remove filters
+    /// while they run.
+    pub fn filters(&self) -> Vec<Rc<dyn InputFilter>> {
+        self.filters.clone()
+    }
+}
+
+impl Drop for InputFilterChain {
+    fn drop(&mut self) {
+        NUM_FILTERS.fetch_sub(self.filters.len(), Ordering::Relaxed);
+    }
+}
+
+/// The outcome of running a key press through the input filters
+#[derive(Debug)]
+pub enum FilteredKey {
+    /// Send the key press to the pane as usual
+    Unchanged,
+    /// Send this data to the pane in place of the key press
+    Replaced(Vec<u8>),
+    /// Discard the key press
+    Discarded,
+}
+
+/// Sends input that has been through the filters to `pane`
+pub fn deliver_input(pane: &Rc<dyn Pane>, data: &[u8], source: InputSource) -> anyhow::Result<()> {
+    match source {
+        InputSource::Paste | InputSource::CliPaste => {
+            pane.send_paste(&String::from_utf8_lossy(data))
+        }
+        InputSource::Keyboard | InputSource::Cli => {
+            pane.writer().write_all(data)?;
+            Ok(())
+        }
+    }
+}
+
+/// Passes `data` through each of `filters` in turn, giving each the
+/// output of the one before it, until one of them rejects it
+pub fn run_filters<'a, F>(
+    filters: &[F],
+    data: &'a [u8],
+    mut apply: impl FnMut(&F, &[u8]) -> InputVerdict,
+) -> FilteredInput<'a> {
+    let mut data = Cow::Borrowed(data);
+    for filter in filters {
+        match apply(filter, &data) {
+            InputVerdict::Pass => {}
+            InputVerdict::Replace(replacement) => data = Cow::Owned(replacement),
+            InputVerdict::Reject(rejection) => return FilteredInput::Rejected(rejection),
+            InputVerdict::Hold => return FilteredInput::Held,
+        }
+    }
+    FilteredInput::Deliver(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rejection(filter: &str) -> InputRejection {
+        InputRejection {
+            filter: filter.to_string(),
+            reason: "no".to_string(),
+            confirm: false,
+        }
+    }
+
+    #[test]
+    fn filters_see_replaced_data() {
+        let upper = |data: &[u8]| InputVerdict::Replace(data.to_ascii_uppercase());
+        let pass = |_: &[u8]| InputVerdict::Pass;
+        let filters: Vec<&dyn Fn(&[u8]) -> InputVerdict> = vec![&pass, &upper, &pass];
+        assert_eq!(
+            run_filters(&filters, b"ls", |f, data| f(data)),
+            FilteredInput::Deliver(Cow::Owned(b"LS".to_vec()))
+        );
+        assert_eq!(
+            run_filters(&filters[..1], b"ls", |f, data| f(data)),
+            FilteredInput::Deliver(Cow::Borrowed(&b"ls"[..]))
+        );
+    }
+
+    #[test]
+    fn rejection_stops_the_chain() {
+        let mut seen = vec![];
+        let filters = ["first", "second", "third"];
+        let result = run_filters(&filters, b"rm -rf /", |name, _| {
+            seen.push(*name);
+            if *name == "second" {
+                InputVerdict::Reject(rejection(name))
+            } else {
+                InputVerdict::Pass
+            }
+        });
+        assert_eq!(result, FilteredInput::Rejected(rejection("second")));
+        assert_eq!(seen, vec!["first", "second"]);
+    }
+}
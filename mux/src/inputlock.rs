@@ -0,0 +1,192 @@
+//! Holds back the input to panes in sensitive domains once they have
+//! gone without input for a while, so that the user doesn't type into
+//! the wrong shell out of habit.
+//! Input from the user is checked by `InputLockFilter`, which the mux
+//! registers as the first input filter while a domain is sensitive or
+//! a pane has been locked explicitly; output from the pane is not
+//! affected.
+//! Input to a locked pane is held, and is sent to it when the pane is
+//! unlocked.
+use crate::inputfilter::{InputFilter, InputSource, InputVerdict};
+use crate::pane::Pane;
+use crate::Mux;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The name of the input filter that holds input to locked panes
+pub const INPUT_LOCK_FILTER: &str = "input-lock";
+
+pub struct InputLockFilter;
+
+impl InputFilter for InputLockFilter {
+    fn name(&self) -> &str {
+        INPUT_LOCK_FILTER
+    }
+
+    fn filter_input(&self, pane: &Rc<dyn Pane>, data: &[u8], source: InputSource) -> InputVerdict {
+        match Mux::get() {
+            Some(mux) if !mux.check_pane_input(pane.pane_id(), data, source) => InputVerdict::Hold,
+            _ => InputVerdict::Pass,
+        }
+    }
+}
+
+/// The most input that is held for a locked pane; anything beyond
+/// this is discarded
+const MAX_HELD_INPUT: usize = 1024 * 1024;
+
+/// Input that was held while a pane was locked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldInput {
+    pub source: InputSource,
+    pub data: Vec<u8>,
+}
+
+/// The input lock state of a pane
+#[derive(Debug, Clone)]
+pub struct PaneInputLock {
+    last_input: Instant,
+    locked: bool,
+    held: Vec<HeldInput>,
+    held_bytes: usize,
+}
+
+impl PaneInputLock {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_input: now,
+            locked: false,
+            held: vec![],
+            held_bytes: 0,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns true if input at `now` would be held, without
+    /// recording it as activity
+    pub fn would_hold_input(&self, lock_after: Option<Duration>, now: Instant) -> bool {
+        self.locked
+            || lock_after
+                .map(|lock_after| now.saturating_duration_since(self.last_input) >= lock_after)
+                .unwrap_or(false)
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    /// Unlocks the pane, returning the input that was held while it
+    /// was locked, in the order that it was sent
+    pub fn unlock(&mut self, now: Instant) -> Vec<HeldInput> {
+        self.locked = false;
+        self.last_input = now;
+        self.held_bytes = 0;
+        std::mem::take(&mut self.held)
+    }
+
+    /// Holds `data` until the pane is unlocked.
+    /// Returns false if too much input is already held, in which case
+    /// `data` is discarded.
+    pub fn hold(&mut self, source: InputSource, data: &[u8]) -> bool {
+        if self.held_bytes + data.len() > MAX_HELD_INPUT {
+            return false;
+        }
+        self.held_bytes += data.len();
+        self.held.push(HeldInput {
+            source,
+            data: data.to_vec(),
+        });
+        true
+    }
+
+    /// Restarts the idle period of an unlocked pane, for when input
+    /// has not been tracked for a while
+    pub fn reset_idle(&mut self, now: Instant) {
+        if !self.locked {
+            self.last_input = now;
+        }
+    }
+
+    /// Called when the user sends input to the pane at `now`.
+    /// `lock_after` is the period without input after which the pane
+    /// locks, if it is in a sensitive domain.
+    /// Returns true if the input can be delivered, recording it, or false
+    /// if the pane is locked and the input must be held.
+    pub fn accept_input(&mut self, lock_after: Option<Duration>, now: Instant) -> bool {
+        if let Some(lock_after) = lock_after {
+            if now.saturating_duration_since(self.last_input) >= lock_after {
+                self.locked = true;
+            }
+        }
+        if self.locked {
+            return false;
+        }
+        self.last_input = now;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locks_after_idle() {
+        let start = Instant::now();
+        let lock_after = Some(Duration::from_secs(60));
+        let mut lock = PaneInputLock::new(start);
+
+        assert!(lock.accept_input(lock_after, start + Duration::from_secs(30)));
+        // Idle time is measured from the most recent input
+        assert!(lock.accept_input(lock_after, start + Duration::from_secs(89)));
+        assert!(!lock.would_hold_input(lock_after, start + Duration::from_secs(148)));
+        assert!(lock.would_hold_input(lock_after, start + Duration::from_secs(149)));
+        assert!(!lock.is_locked());
+        assert!(!lock.accept_input(lock_after, start + Duration::from_secs(150)));
+        assert!(lock.is_locked());
+        // Held input doesn't count as activity
+        assert!(!lock.accept_input(lock_after, start + Duration::from_secs(151)));
+
+        assert!(lock.unlock(start + Duration::from_secs(160)).is_empty());
+        assert!(lock.accept_input(lock_after, start + Duration::from_secs(161)));
+    }
+
+    #[test]
+    fn held_input_is_returned_on_unlock() {
+        let start = Instant::now();
+        let mut lock = PaneInputLock::new(start);
+        lock.lock();
+        assert!(lock.hold(InputSource::Keyboard, b"ls"));
+        assert!(lock.hold(InputSource::Paste, b"echo hi"));
+        assert!(!lock.hold(InputSource::Cli, &vec![b'x'; MAX_HELD_INPUT]));
+        assert_eq!(
+            lock.unlock(start),
+            vec![
+                HeldInput {
+                    source: InputSource::Keyboard,
+                    data: b"ls".to_vec()
+                },
+                HeldInput {
+                    source: InputSource::Paste,
+                    data: b"echo hi".to_vec()
+                },
+            ]
+        );
+        // Held input is only delivered once
+        assert!(lock.unlock(start).is_empty());
+    }
+
+    #[test]
+    fn manual_lock() {
+        let start = Instant::now();
+        let mut lock = PaneInputLock::new(start);
+        lock.lock();
+        // Panes outside of sensitive domains can be locked explicitly
+        assert!(!lock.accept_input(None, start));
+        lock.unlock(start);
+        assert!(lock.accept_input(None, start + Duration::from_secs(3600)));
+    }
+}
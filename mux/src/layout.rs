@@ -0,0 +1,359 @@
+//! A declarative description of windows, tabs and splits, such as the
+//! one that `wezterm start --layout` reads from a file, and the means
+//! to build it in the mux.
+use crate::domain::SplitSource;
+use crate::pane::PaneId;
+use crate::splitplan::{plan_splits, PlannedSplit};
+use crate::tab::{SplitDirection, SplitRequest, SplitSize};
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::Context;
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use portable_pty::CommandBuilder;
+use std::collections::HashMap;
+use std::path::Path;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::TerminalSize;
+
+#[derive(Debug, Clone, Default, PartialEq, FromDynamic, ToDynamic)]
+pub struct LayoutDescription {
+    #[dynamic(default)]
+    pub windows: Vec<LayoutWindow>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, FromDynamic, ToDynamic)]
+pub struct LayoutWindow {
+    /// The workspace of the window; the default workspace is used
+    /// if omitted
+    pub workspace: Option<String>,
+    #[dynamic(default)]
+    pub tabs: Vec<LayoutTab>,
+}
+
+#[derive(Debug, Clone, PartialEq, FromDynamic, ToDynamic)]
+pub struct LayoutTab {
+    pub title: Option<String>,
+    /// The domain of the panes of the tab that don't name their own
+    pub domain: Option<String>,
+    pub root: LayoutNode,
+}
+
+#[derive(Debug, Clone, PartialEq, FromDynamic, ToDynamic)]
+pub enum LayoutNode {
+    Pane(LayoutPane),
+    Split(LayoutSplit),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, FromDynamic, ToDynamic)]
+pub struct LayoutPane {
+    /// The command line; the default program of the domain is used
+    /// if omitted
+    pub args: Option<Vec<String>>,
+    pub cwd: Option<CommandDir>,
+    #[dynamic(default)]
+    pub set_environment_variables: HashMap<String, String>,
+    /// The name of the domain in which to spawn the pane
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, FromDynamic, ToDynamic)]
+pub struct LayoutSplit {
+    pub direction: SplitDirection,
+    /// The share of the region, as a percentage, that goes to `second`
+    #[dynamic(default = "default_split_percent")]
+    pub percent: u8,
+    pub first: Box<LayoutNode>,
+    pub second: Box<LayoutNode>,
+}
+
+fn default_split_percent() -> u8 {
+    50
+}
+
+impl LayoutNode {
+    /// Returns the pane that is spawned first for this node; the rest
+    /// of the node is made by splitting it
+    fn first_pane(&self) -> &LayoutPane {
+        match self {
+            Self::Pane(pane) => pane,
+            Self::Split(split) => split.first.first_pane(),
+        }
+    }
+
+    fn validate(&self, path: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Pane(pane) => {
+                if let Some(args) = &pane.args {
+                    anyhow::ensure!(!args.is_empty(), "{}.args: must not be empty", path);
+                }
+            }
+            Self::Split(split) => {
+                anyhow::ensure!(
+                    split.percent > 0 && split.percent < 100,
+                    "{}.percent: {} is not between 1 and 99",
+                    path,
+                    split.percent
+                );
+                split.first.validate(&format!("{}.first", path))?;
+                split.second.validate(&format!("{}.second", path))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_cwds(&mut self, base: &Path) {
+        match self {
+            Self::Pane(pane) => {
+                if let Some(cwd) = &mut pane.cwd {
+                    if cwd.host.is_none() && Path::new(&cwd.path).is_relative() {
+                        cwd.path = base.join(&cwd.path).to_string_lossy().into_owned();
+                    }
+                }
+            }
+            Self::Split(split) => {
+                split.first.resolve_cwds(base);
+                split.second.resolve_cwds(base);
+            }
+        }
+    }
+}
+
+impl LayoutPane {
+    /// The cwd is returned separately, rather than being set on the
+    /// builder, so that the domain can tell which host it belongs to
+    fn command(&self) -> (Option<CommandBuilder>, Option<CommandDir>) {
+        let mut builder = match &self.args {
+            Some(args) => Some(CommandBuilder::from_argv(
+                args.iter().map(Into::into).collect(),
+            )),
+            None if !self.set_environment_variables.is_empty() => {
+                Some(CommandBuilder::new_default_prog())
+            }
+            None => None,
+        };
+        if let Some(builder) = &mut builder {
+            for (k, v) in &self.set_environment_variables {
+                builder.env(k, v);
+            }
+        }
+        (builder, self.cwd.clone())
+    }
+
+    fn spawn_domain(&self, tab: &LayoutTab) -> SpawnTabDomain {
+        match self.domain.as_ref().or(tab.domain.as_ref()) {
+            Some(name) => SpawnTabDomain::DomainName(name.clone()),
+            None => SpawnTabDomain::DefaultDomain,
+        }
+    }
+}
+
+impl LayoutDescription {
+    /// Checks the parts of the description that can't be expressed in
+    /// its types.  Errors name the field that is at fault.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.windows.is_empty(), "windows: at least one is needed");
+        for (w, window) in self.windows.iter().enumerate() {
+            anyhow::ensure!(
+                !window.tabs.is_empty(),
+                "windows[{}].tabs: at least one is needed",
+                w
+            );
+            for (t, tab) in window.tabs.iter().enumerate() {
+                tab.root
+                    .validate(&format!("windows[{}].tabs[{}].root", w, t))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes relative cwds that aren't on another host relative to `base`
+    pub fn resolve_cwds(&mut self, base: &Path) {
+        for window in &mut self.windows {
+            for tab in &mut window.tabs {
+                tab.root.resolve_cwds(base);
+            }
+        }
+    }
+}
+
+impl Mux {
+    /// Builds the windows, tabs and splits of `layout`.
+    /// The tabs of the first window go into `first_window` if it is
+    /// specified, such as an empty window that was made while a domain
+    /// was attached.
+    /// Windows that don't name a workspace use `default_workspace`.
+    /// Returns the ids of the windows.
+    pub async fn spawn_layout(
+        &self,
+        layout: &LayoutDescription,
+        size: TerminalSize,
+        first_window: Option<WindowId>,
+        default_workspace: &str,
+    ) -> anyhow::Result<Vec<WindowId>> {
+        let mut window_ids = vec![];
+        for (w, window) in layout.windows.iter().enumerate() {
+            let workspace = window
+                .workspace
+                .clone()
+                .unwrap_or_else(|| default_workspace.to_string());
+            let mut window_id = if w == 0 { first_window } else { None };
+            if let Some(window_id) = window_id {
+                window_ids.push(window_id);
+            }
+            for (t, layout_tab) in window.tabs.iter().enumerate() {
+                let path = format!("windows[{}].tabs[{}]", w, t);
+                let first = layout_tab.root.first_pane();
+                let (command, cwd) = first.command();
+                let (tab, pane, new_window_id) = self
+                    .spawn_tab_or_window(
+                        window_id,
+                        first.spawn_domain(layout_tab),
+                        command,
+                        cwd,
+                        size,
+                        None,
+                        workspace.clone(),
+                    )
+                    .await
+                    .with_context(|| format!("spawning {}.root", path))?;
+                if window_id.is_none() {
+                    window_ids.push(new_window_id);
+                    window_id.replace(new_window_id);
+                }
+                if let Some(title) = &layout_tab.title {
+                    tab.set_title(title);
+                }
+                self.spawn_layout_splits(
+                    &layout_tab.root,
+                    layout_tab,
+                    pane.pane_id(),
+                    &format!("{}.root", path),
+                )
+                .await?;
+                tab.set_active_pane(&pane);
+            }
+        }
+        Ok(window_ids)
+    }
+
+    /// Splits `pane_id`, which holds the first pane of `node`, to make
+    /// the rest of `node`
+    async fn spawn_layout_splits(
+        &self,
+        node: &LayoutNode,
+        layout_tab: &LayoutTab,
+        pane_id: PaneId,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        let splits = plan_splits(node, |node| match node {
+            LayoutNode::Pane(_) => None,
+            LayoutNode::Split(split) => Some((split, &*split.first, &*split.second)),
+        });
+        // The pane in each slot, and the path of the node that it fills
+        let mut slots = vec![(pane_id, path.to_string())];
+        for PlannedSplit { slot, split } in splits {
+            let (pane_id, path) = slots[slot].clone();
+            let second = split.second.first_pane();
+            let (command, command_dir) = second.command();
+            let (new_pane, _size) = self
+                .split_pane(
+                    pane_id,
+                    SplitRequest {
+                        direction: split.direction,
+                        target_is_second: true,
+                        top_level: false,
+                        size: SplitSize::Percent(split.percent),
+                    },
+                    SplitSource::Spawn {
+                        command,
+                        command_dir,
+                    },
+                    second.spawn_domain(layout_tab),
+                )
+                .await
+                .with_context(|| format!("spawning {}.second", path))?;
+            slots[slot].1 = format!("{}.first", path);
+            slots.push((new_pane.pane_id(), format!("{}.second", path)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wezterm_dynamic::Value;
+
+    fn parse(value: Value) -> anyhow::Result<LayoutDescription> {
+        let layout = LayoutDescription::from_dynamic(&value, Default::default())?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    fn obj(pairs: Vec<(&str, Value)>) -> Value {
+        Value::Object(
+            pairs
+                .into_iter()
+                .map(|(k, v)| (Value::String(k.to_string()), v))
+                .collect(),
+        )
+    }
+
+    fn one_tab(root: Value) -> Value {
+        let tab = obj(vec![("root", root)]);
+        let window = obj(vec![("tabs", Value::Array(vec![tab].into()))]);
+        obj(vec![("windows", Value::Array(vec![window].into()))])
+    }
+
+    fn pane(cwd: &str) -> Value {
+        obj(vec![(
+            "Pane",
+            obj(vec![("cwd", Value::String(cwd.to_string()))]),
+        )])
+    }
+
+    #[test]
+    fn relative_cwds_resolve_against_base() {
+        let root = obj(vec![(
+            "Split",
+            obj(vec![
+                ("direction", Value::String("Horizontal".to_string())),
+                ("first", pane("src")),
+                ("second", pane("/tmp")),
+            ]),
+        )]);
+        let value = one_tab(root);
+        let mut layout = parse(value).unwrap();
+        layout.resolve_cwds(Path::new("/home/me"));
+
+        match &layout.windows[0].tabs[0].root {
+            LayoutNode::Split(split) => {
+                assert_eq!(split.percent, 50);
+                assert_eq!(
+                    split.first.first_pane().cwd,
+                    Some(CommandDir::new(
+                        Path::new("/home/me").join("src").to_string_lossy()
+                    ))
+                );
+                assert_eq!(split.second.first_pane().cwd, Some(CommandDir::new("/tmp")));
+            }
+            other => panic!("expected a split, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let root = obj(vec![(
+            "Split",
+            obj(vec![
+                ("direction", Value::String("Vertical".to_string())),
+                ("percent", Value::U64(100)),
+                ("first", pane("a")),
+                ("second", pane("b")),
+            ]),
+        )]);
+        let value = one_tab(root);
+        let err = parse(value).unwrap_err().to_string();
+        assert!(err.contains("windows[0].tabs[0].root.percent"), "{}", err);
+    }
+}
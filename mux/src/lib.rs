@@ -1,50 +1,131 @@
 use crate::client::{ClientId, ClientInfo};
+use crate::localpane::LocalPane;
 use crate::pane::{Pane, PaneId};
-use crate::tab::{SplitRequest, Tab, TabId};
+use crate::tab::{SplitPreview, SplitRequest, Tab, TabId};
 use crate::window::{Window, WindowId};
 use anyhow::{anyhow, Context, Error};
-use config::keyassignment::SpawnTabDomain;
-use config::{configuration, ExitBehavior};
-use domain::{Domain, DomainId, DomainState, SplitSource};
+use attach::PendingAttach;
+use bookmark::{Bookmark, PaneBookmarks};
+use closeconfirm::{CloseOutcome, CloseRequestId, PendingClose, PendingCloses};
+use config::keyassignment::{CommandDir, SpawnTabDomain, SpawnWindowTarget};
+use config::{configuration, ClosePolicy, DomainKind, ExitBehavior};
+use domain::{
+    command_has_cwd, CloseAction, CloseDecisions, CwdMapping, Domain, DomainError, DomainId,
+    DomainInfo, DomainInventory, DomainResult, DomainState, MoveDestination, SplitSource,
+};
 use filedescriptor::{socketpair, AsRawSocketDescriptor, FileDescriptor};
+use history::{HistoryEntry, PaneHistory, RecentDir};
+use inputfilter::{
+    FilteredInput, FilteredKey, InputFilter, InputFilterChain, InputRejection, InputSource,
+};
+use inputlock::{HeldInput, InputLockFilter, PaneInputLock, INPUT_LOCK_FILTER};
 #[cfg(unix)]
 use libc::{SOL_SOCKET, SO_RCVBUF, SO_SNDBUF};
 use log::error;
+use marker::{Marker, PaneMarkers};
 use metrics::histogram;
-use percent_encoding::percent_decode_str;
+use outputpause::OutputPause;
+use outputwatch::{OutputMatch, OutputWatcherInfo, OutputWatcherSpec, OutputWatchers, WatcherId};
+use panelink::{LinkGroupId, LinkPolicy, PaneLink, PaneLinks};
+use panepick::{PanePicks, PickId, PickPanesRequest};
 use portable_pty::{CommandBuilder, ExitStatus, PtySize};
-use std::cell::{Ref, RefCell, RefMut};
-use std::collections::HashMap;
+use resources::PaneResourceUsage;
+use share::{ShareId, ShareInfo, ShareLink, ShareListener, ShareRegistry, ShareState, ShareToken};
+use spawnlimit::{SpawnLimiter, SpawnPermit};
+use spawnquota::{
+    DomainSpawn, SpawnLimit, SpawnLimitInfo, SpawnLimitValue, SpawnOrigin, SpawnQuota,
+};
+use spawntiming::{SpawnTimer, SpawnTiming, SpawnTimingLog};
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use termwiz::cell::SemanticType;
 use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Device, Mode};
 use termwiz::escape::{Action, CSI};
 use thiserror::*;
-use wezterm_term::{Clipboard, ClipboardSelection, DownloadHandler, TerminalSize};
+use viewstate::{ClientViewStates, PaneViewState};
+use watchdog::PaneReaderState;
+use wezterm_term::{
+    Alert, Clipboard, ClipboardSelection, DownloadHandler, StableRowIndex, TerminalSize,
+};
 #[cfg(windows)]
 use winapi::um::winsock2::{SOL_SOCKET, SO_RCVBUF, SO_SNDBUF};
+use windowtarget::{WindowTargetResolver, WindowTargets};
 
 pub mod activity;
+pub mod attach;
+pub mod audit;
+pub mod blocking;
+pub mod bookmark;
+pub mod builder;
+pub mod changedir;
 pub mod client;
+pub mod clonetab;
+pub mod closeconfirm;
+pub mod coalesce;
 pub mod connui;
+pub mod dirlist;
+pub mod docker;
 pub mod domain;
+pub mod domainexec;
+mod domainindex;
+pub mod domainrules;
+pub mod editscrollback;
+pub mod globalsearch;
+pub mod history;
+pub mod ids;
+pub mod inputfilter;
+pub mod inputlock;
+pub mod layout;
 pub mod localpane;
+pub mod marker;
+#[cfg(windows)]
+pub mod namedpipe;
+pub mod outputpause;
+pub mod outputwatch;
 pub mod pane;
+pub mod panelink;
+pub mod panepick;
+pub mod pasteguard;
+pub mod prewarm;
+#[cfg(unix)]
+pub mod ptyhandoff;
+pub mod reconnect;
 pub mod renderable;
+pub mod resources;
+pub mod reuse;
+pub mod scan;
+pub mod scrollback;
+pub mod share;
+pub mod spawnlimit;
+pub mod spawnquota;
+pub mod spawntiming;
+pub mod splitplan;
 pub mod ssh;
+pub mod streampane;
+#[cfg(unix)]
+pub mod supervisor;
+pub mod systemd;
 pub mod tab;
 pub mod termwiztermtab;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tmux;
 pub mod tmux_commands;
 mod tmux_pty;
+pub mod viewstate;
+pub mod watchdog;
 pub mod window;
+pub mod windowtarget;
 
 use crate::activity::Activity;
+use crate::domainindex::DomainIndex;
 
 pub const DEFAULT_WORKSPACE: &str = "default";
 
@@ -72,6 +153,144 @@ pub enum MuxNotification {
         name: Option<String>,
         data: Arc<Vec<u8>>,
     },
+    /// Processing the output of the pane panicked; the pane has been
+    /// marked as dead and will no longer process output
+    PaneInternalError {
+        pane_id: PaneId,
+        message: String,
+    },
+    /// Input from the user to the pane was held because the pane
+    /// is locked; the frontend may offer to unlock it
+    PaneInputHeld(PaneId),
+    /// An input filter rejected input from the user to the pane
+    PaneInputRejected {
+        pane_id: PaneId,
+        source: InputSource,
+        rejection: InputRejection,
+        /// The input as the user sent it, before any filter changed it
+        data: Arc<Vec<u8>>,
+    },
+    /// The resource usage of these panes was sampled
+    PaneResourceUsage(Vec<(PaneId, PaneResourceUsage)>),
+    /// The output of the pane was paused or resumed
+    PaneOutputPaused {
+        pane_id: PaneId,
+        paused: bool,
+    },
+    /// The palette overrides of the pane were set or cleared
+    PanePaletteOverridesChanged(PaneId),
+    /// A marker was added to or removed from the pane
+    PaneMarkersChanged(PaneId),
+    /// The domain was asked to attach, but was already attached
+    DomainAlreadyAttached(DomainId),
+    /// More of the panes of the domain were synchronized after it was
+    /// attached; see `Domain::sync_progress`
+    DomainSyncProgress {
+        domain_id: DomainId,
+        synced: usize,
+        total: usize,
+    },
+    /// Some panes were linked together
+    PaneLinksChanged,
+    /// The split tree, pane sizes, zoom state or set of panes of
+    /// the tab changed; see `Tab::layout_generation`
+    TabLayoutChanged(TabId),
+    /// The pane was spawned into a domain to which the cwd that it
+    /// was intended to have is meaningless, so it was started in the
+    /// default directory of the domain instead
+    CwdDropped {
+        pane_id: PaneId,
+        cwd: CommandDir,
+        reason: String,
+    },
+    /// The pane was spawned into `domain`, rather than the domain
+    /// that was requested, because `cwd` matched one of the
+    /// `domain_rules`
+    SpawnRedirected {
+        pane_id: PaneId,
+        domain: String,
+        cwd: CommandDir,
+    },
+    /// Frontends that show the pane should scroll it so that the row
+    /// is at the top of the viewport, such as to show a bookmark
+    ScrollToRow {
+        pane_id: PaneId,
+        row: StableRowIndex,
+    },
+    /// A window was created in a workspace other than the one that
+    /// this client presents; the frontend may let the user know
+    WindowCreatedInWorkspace {
+        domain_id: DomainId,
+        workspace: String,
+    },
+    /// An output watcher of the pane matched a line of its output.
+    /// `owner` is the client that added the watcher, which is the only
+    /// one that is told about the match; if None, the watcher was
+    /// added locally and the lua event is emitted here.
+    OutputMatched {
+        owner: Option<Arc<ClientId>>,
+        matched: OutputMatch,
+    },
+    /// The frontend should let the user choose panes, and report the
+    /// choice with `Mux::finish_pane_pick`
+    PickPanes(PickPanesRequest),
+    /// The frontend should ask the user to confirm the close, and
+    /// report the answer with `Mux::answer_close_confirmation`
+    CloseConfirmationRequested(PendingClose),
+    /// The close request was decided; the frontend should dismiss its
+    /// prompt for it
+    CloseConfirmationResolved {
+        id: CloseRequestId,
+        outcome: CloseOutcome,
+    },
+    /// The share was revoked, or its tab was closed; a client that is
+    /// viewing it is disconnected
+    ShareEnded(ShareId),
+}
+
+impl MuxNotification {
+    /// Returns true if this notification changes the set of windows,
+    /// tabs and panes, or how they relate to each other.
+    /// Subscribers that relay notifications to another party must
+    /// deliver these in order with respect to any reply that follows
+    /// the operation that produced them; the others are advisory and
+    /// may be coalesced or delivered later.
+    pub fn is_sync_critical(&self) -> bool {
+        match self {
+            Self::PaneAdded(_)
+            | Self::PaneRemoved(_)
+            | Self::WindowCreated(_)
+            | Self::WindowRemoved(_)
+            | Self::WindowInvalidated(_)
+            | Self::WindowWorkspaceChanged(_)
+            | Self::ActiveWorkspaceChanged(_)
+            | Self::Empty
+            | Self::PaneLinksChanged => true,
+            Self::PaneOutput(_)
+            | Self::Alert { .. }
+            | Self::AssignClipboard { .. }
+            | Self::SaveToDownloads { .. }
+            | Self::PaneInternalError { .. }
+            | Self::PaneInputHeld(_)
+            | Self::PaneInputRejected { .. }
+            | Self::PaneResourceUsage(_)
+            | Self::PaneOutputPaused { .. }
+            | Self::PanePaletteOverridesChanged(_)
+            | Self::PaneMarkersChanged(_)
+            | Self::TabLayoutChanged(_)
+            | Self::CwdDropped { .. }
+            | Self::SpawnRedirected { .. }
+            | Self::ScrollToRow { .. }
+            | Self::WindowCreatedInWorkspace { .. }
+            | Self::OutputMatched { .. }
+            | Self::PickPanes(_)
+            | Self::CloseConfirmationRequested(_)
+            | Self::CloseConfirmationResolved { .. }
+            | Self::ShareEnded(_)
+            | Self::DomainAlreadyAttached(_)
+            | Self::DomainSyncProgress { .. } => false,
+        }
+    }
 }
 
 static SUB_ID: AtomicUsize = AtomicUsize::new(0);
@@ -79,6 +298,11 @@ static SUB_ID: AtomicUsize = AtomicUsize::new(0);
 pub struct Mux {
     tabs: RefCell<HashMap<TabId, Rc<Tab>>>,
     panes: RefCell<HashMap<PaneId, Rc<dyn Pane>>>,
+    /// The ids of the panes of each domain, so that they can be found
+    /// without visiting every pane
+    panes_by_domain: RefCell<HashMap<DomainId, BTreeSet<PaneId>>>,
+    /// The windows and tabs that hold the panes of each domain
+    domain_index: RefCell<DomainIndex>,
     windows: RefCell<HashMap<WindowId, Window>>,
     default_domain: RefCell<Option<Arc<dyn Domain>>>,
     domains: RefCell<HashMap<DomainId, Arc<dyn Domain>>>,
@@ -88,6 +312,31 @@ pub struct Mux {
     clients: RefCell<HashMap<ClientId, ClientInfo>>,
     identity: RefCell<Option<Arc<ClientId>>>,
     num_panes_by_workspace: RefCell<HashMap<String, usize>>,
+    pane_readers: RefCell<HashMap<PaneId, Arc<PaneReaderState>>>,
+    input_locks: RefCell<HashMap<PaneId, PaneInputLock>>,
+    input_filters: RefCell<InputFilterChain>,
+    pending_attaches: RefCell<HashMap<DomainId, PendingAttach>>,
+    pane_links: RefCell<PaneLinks>,
+    bookmarks: RefCell<PaneBookmarks>,
+    markers: RefCell<PaneMarkers>,
+    output_watchers: RefCell<OutputWatchers>,
+    history: RefCell<PaneHistory>,
+    history_save_scheduled: RefCell<bool>,
+    pane_picks: RefCell<PanePicks>,
+    window_targets: RefCell<WindowTargets>,
+    shares: RefCell<ShareRegistry>,
+    share_listener: RefCell<Option<ShareListener>>,
+    spawn_timings: RefCell<SpawnTimingLog>,
+    spawn_limiter: SpawnLimiter,
+    spawn_quota: RefCell<SpawnQuota>,
+    view_states: RefCell<ClientViewStates>,
+    /// Set by `skip_domain_rules_for_next_spawn`
+    skip_domain_rules: Cell<bool>,
+    pending_closes: RefCell<PendingCloses>,
+    /// Ids that callers chose for panes that are being spawned, and
+    /// that aren't in `panes` yet
+    reserved_pane_ids: RefCell<HashSet<PaneId>>,
+    _config_subscription: config::ConfigSubscription,
 }
 
 const BUFSIZE: usize = 1024 * 1024;
@@ -96,7 +345,14 @@ const BUFSIZE: usize = 1024 * 1024;
 /// the pty in the mux.
 /// It blocks until the mux has finished consuming the data, which provides
 /// some back-pressure so that eg: ctrl-c can remain responsive.
-fn send_actions_to_mux(pane_id: PaneId, dead: &Arc<AtomicBool>, actions: Vec<Action>) {
+/// While the output of the pane is paused, it blocks until it is resumed.
+fn send_actions_to_mux(
+    pane_id: PaneId,
+    dead: &Arc<AtomicBool>,
+    pause: &OutputPause,
+    actions: Vec<Action>,
+) {
+    pause.wait_while_paused();
     let start = Instant::now();
     promise::spawn::block_on(promise::spawn::spawn_into_main_thread({
         let dead = Arc::clone(&dead);
@@ -104,12 +360,21 @@ fn send_actions_to_mux(pane_id: PaneId, dead: &Arc<AtomicBool>, actions: Vec<Act
             let mux = Mux::get().unwrap();
             if let Some(pane) = mux.get_pane(pane_id) {
                 let start = Instant::now();
-                pane.perform_actions(actions);
+                let result = catch_pane_panic(|| pane.perform_actions(actions));
                 histogram!(
                     "send_actions_to_mux.perform_actions.latency",
                     start.elapsed()
                 );
-                mux.notify(MuxNotification::PaneOutput(pane_id));
+                match result {
+                    Ok(()) => {
+                        mux.notify(MuxNotification::PaneOutput(pane_id));
+                        mux.match_output_watchers(&pane);
+                    }
+                    Err(payload) => {
+                        dead.store(true, Ordering::Relaxed);
+                        mux.pane_panicked(pane_id, panic_message(&*payload));
+                    }
+                }
             } else {
                 // Something else removed the pane from
                 // the mux, so signal that we should stop
@@ -122,13 +387,75 @@ fn send_actions_to_mux(pane_id: PaneId, dead: &Arc<AtomicBool>, actions: Vec<Act
     histogram!("send_actions_to_mux.rate", 1.);
 }
 
-fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDescriptor) {
+/// Describes where a domain is defined, for messages about name collisions
+fn describe_domain_kind(domain: &Arc<dyn Domain>) -> String {
+    match domain.domain_kind() {
+        Some(kind) => kind.config_key().to_string(),
+        None => "internal".to_string(),
+    }
+}
+
+thread_local! {
+    static CATCHING_PANE_PANIC: Cell<bool> = Cell::new(false);
+}
+
+/// Calls `f`, catching any panic so that it only takes down the pane
+/// whose output was being processed rather than the whole process
+fn catch_pane_panic<R>(f: impl FnOnce() -> R) -> std::thread::Result<R> {
+    let outer = CATCHING_PANE_PANIC.with(|catching| catching.replace(true));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    CATCHING_PANE_PANIC.with(|catching| catching.set(outer));
+    result
+}
+
+/// Returns true if a panic on the current thread will be caught and
+/// reported against a pane, rather than terminating the process.
+/// Intended to be called from a panic hook.
+pub fn is_catching_pane_panic() -> bool {
+    CATCHING_PANE_PANIC.with(|catching| catching.get())
+}
+
+/// Extracts the message from a panic payload
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// In debug builds, `OSC wezterm-debug-panic ST` deliberately panics the
+/// parser for the pane, so that the handling of panics in pane
+/// output processing can be tested end to end.
+#[cfg(debug_assertions)]
+fn check_debug_panic(action: &Action) {
+    use termwiz::escape::OperatingSystemCommand;
+    if let Action::OperatingSystemCommand(osc) = action {
+        if let OperatingSystemCommand::Unspecified(params) = &**osc {
+            if params.first().map(|p| p.as_slice()) == Some(b"wezterm-debug-panic") {
+                panic!("panic requested via wezterm-debug-panic escape sequence");
+            }
+        }
+    }
+}
+
+fn parse_buffered_data(
+    pane_id: PaneId,
+    dead: &Arc<AtomicBool>,
+    pause: &OutputPause,
+    mut rx: FileDescriptor,
+) {
     let mut buf = vec![0; configuration().mux_output_parser_buffer_size];
     let mut parser = termwiz::escape::parser::Parser::new();
     let mut actions = vec![];
     let mut hold = false;
 
     loop {
+        // Leave the output in the socket while paused, so that the
+        // reader blocks once it is full
+        pause.wait_while_paused();
         match rx.read(&mut buf) {
             Ok(size) if size == 0 => {
                 dead.store(true, Ordering::Relaxed);
@@ -140,6 +467,9 @@ fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDesc
             }
             Ok(size) => {
                 parser.parse(&buf[0..size], |action| {
+                    #[cfg(debug_assertions)]
+                    check_debug_panic(&action);
+
                     let mut flush = false;
                     match &action {
                         Action::CSI(CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
@@ -149,7 +479,12 @@ fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDesc
 
                             // Flush prior actions
                             if !actions.is_empty() {
-                                send_actions_to_mux(pane_id, dead, std::mem::take(&mut actions));
+                                send_actions_to_mux(
+                                    pane_id,
+                                    dead,
+                                    pause,
+                                    std::mem::take(&mut actions),
+                                );
                             }
                         }
                         Action::CSI(CSI::Mode(Mode::ResetDecPrivateMode(
@@ -167,11 +502,11 @@ fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDesc
                     actions.push(action);
 
                     if flush && !actions.is_empty() {
-                        send_actions_to_mux(pane_id, dead, std::mem::take(&mut actions));
+                        send_actions_to_mux(pane_id, dead, pause, std::mem::take(&mut actions));
                     }
                 });
                 if !actions.is_empty() && !hold {
-                    send_actions_to_mux(pane_id, dead, std::mem::take(&mut actions));
+                    send_actions_to_mux(pane_id, dead, pause, std::mem::take(&mut actions));
                 }
 
                 buf.resize(configuration().mux_output_parser_buffer_size, 0);
@@ -198,32 +533,83 @@ fn set_socket_buffer(fd: &mut FileDescriptor, option: i32, size: usize) -> anyho
     }
 }
 
-/// This function is run in a separate thread; its purpose is to perform
-/// blocking reads from the pty (non-blocking reads are not portable to
-/// all platforms and pty/tty types), parse the escape sequences and
-/// relay the actions to the mux thread to apply them to the pane.
-fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<dyn std::io::Read>) {
-    let mut buf = vec![0; BUFSIZE];
-
-    // This is used to signal that an error occurred either in this thread,
-    // or in the main mux thread.  If `true`, this thread will terminate.
+/// Sets up the parser for the output of a pane, and starts the
+/// first reader thread to feed it.
+fn start_pane_reader(
+    pane_id: PaneId,
+    banner: Option<String>,
+    reader: Box<dyn std::io::Read + Send>,
+) -> anyhow::Result<Arc<PaneReaderState>> {
+    // This is used to signal that an error occurred either in the reader,
+    // the parser or in the main mux thread.  If `true`, the reader will
+    // terminate.
     let dead = Arc::new(AtomicBool::new(false));
+    let pause = Arc::new(OutputPause::default());
+    let parsed = Arc::new(AtomicBool::new(false));
 
-    let (mut tx, mut rx) = socketpair().unwrap();
-    set_socket_buffer(&mut tx, SO_SNDBUF, BUFSIZE).unwrap();
-    set_socket_buffer(&mut rx, SO_RCVBUF, BUFSIZE).unwrap();
+    let (mut tx, mut rx) = socketpair()?;
+    set_socket_buffer(&mut tx, SO_SNDBUF, BUFSIZE)?;
+    set_socket_buffer(&mut rx, SO_RCVBUF, BUFSIZE)?;
 
     std::thread::spawn({
         let dead = Arc::clone(&dead);
-        move || parse_buffered_data(pane_id, &dead, rx)
+        let pause = Arc::clone(&pause);
+        let parsed = Arc::clone(&parsed);
+        move || {
+            // A panic while parsing must not leave the pane listed
+            // but inert; catch it and report it against the pane
+            if let Err(payload) =
+                catch_pane_panic(|| parse_buffered_data(pane_id, &dead, &pause, rx))
+            {
+                dead.store(true, Ordering::Relaxed);
+                let message = panic_message(&*payload);
+                promise::spawn::spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    mux.pane_panicked(pane_id, message);
+                })
+                .detach();
+            }
+            // The exit of a process that failed early is reported
+            // once its final output has been applied; see LocalPane
+            parsed.store(true, Ordering::Relaxed);
+            promise::spawn::spawn_into_main_thread(async move {
+                let mux = Mux::get().unwrap();
+                mux.prune_dead_windows();
+            })
+            .detach();
+        }
     });
 
     if let Some(banner) = banner {
         tx.write_all(banner.as_bytes()).ok();
     }
 
-    while !dead.load(Ordering::Relaxed) {
-        match reader.read(&mut buf) {
+    let state = Arc::new(PaneReaderState::new(tx, dead, pause, parsed));
+    state.spawn_reader(pane_id, reader)?;
+    Ok(state)
+}
+
+/// This function is run in a separate thread; its purpose is to perform
+/// blocking reads from the pty (non-blocking reads are not portable to
+/// all platforms and pty/tty types) and relay the data to the parser,
+/// which applies the resulting actions to the pane.
+/// If the watchdog replaces this reader with a new one, this function
+/// returns after its next read without affecting the pane; anything
+/// that it read is discarded rather than forwarded out of order.
+fn read_from_pane_pty(
+    pane_id: PaneId,
+    mut reader: Box<dyn std::io::Read + Send>,
+    mut tx: FileDescriptor,
+    state: Arc<PaneReaderState>,
+    generation: usize,
+) {
+    let mut buf = vec![0; BUFSIZE];
+
+    while !state.dead.load(Ordering::Relaxed) && state.is_current(generation) {
+        state.set_blocked(generation, true);
+        let result = reader.read(&mut buf);
+        state.set_blocked(generation, false);
+        match result {
             Ok(size) if size == 0 => {
                 log::trace!("read_pty EOF: pane_id {}", pane_id);
                 break;
@@ -234,38 +620,69 @@ fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<d
             }
             Ok(size) => {
                 histogram!("read_from_pane_pty.bytes.rate", size as f64);
-                if let Err(err) = tx.write_all(&buf[..size]) {
-                    error!(
-                        "read_pty failed to write to parser: pane {} {:?}",
-                        pane_id, err
-                    );
-                    break;
+                match state.forward(generation, &mut tx, &buf[..size]) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::warn!(
+                            "read_pty: pane {} reader generation {} was superseded; \
+                             discarding {} bytes",
+                            pane_id,
+                            generation,
+                            size
+                        );
+                        break;
+                    }
+                    Err(err) => {
+                        error!(
+                            "read_pty failed to write to parser: pane {} {:?}",
+                            pane_id, err
+                        );
+                        break;
+                    }
                 }
             }
         }
     }
 
-    match configuration().exit_behavior {
-        ExitBehavior::Hold | ExitBehavior::CloseOnCleanExit => {
-            // We don't know if we can unilaterally close
-            // this pane right now, so don't!
-            promise::spawn::spawn_into_main_thread(async move {
-                let mux = Mux::get().unwrap();
+    if !state.is_current(generation) {
+        log::trace!(
+            "read_pty: pane {} reader generation {} was superseded",
+            pane_id,
+            generation
+        );
+        return;
+    }
+
+    // Allow the parser to see EOF once it has consumed what we sent
+    drop(tx);
+    state.output_ended();
+
+    promise::spawn::spawn_into_main_thread(async move {
+        let mux = Mux::get().unwrap();
+        // A local pane closes according to its exit behavior once its
+        // process has been reaped.  It may instead be held open to
+        // report a process that failed early, which waits for the
+        // parser to apply the last of the output, so it must not be
+        // removed here.
+        let is_local = mux
+            .get_pane(pane_id)
+            .map(|pane| pane.downcast_ref::<LocalPane>().is_some())
+            .unwrap_or(false);
+        match configuration().exit_behavior {
+            ExitBehavior::Close if !is_local => {
+                mux.remove_pane(pane_id);
+            }
+            _ => {
+                // We don't know if we can unilaterally close
+                // this pane right now, so don't!
                 log::trace!("checking for dead windows after EOF on pane {}", pane_id);
                 mux.prune_dead_windows();
-            })
-            .detach();
-        }
-        ExitBehavior::Close => {
-            promise::spawn::spawn_into_main_thread(async move {
-                let mux = Mux::get().unwrap();
-                mux.remove_pane(pane_id);
-            })
-            .detach();
+            }
         }
-    }
+    })
+    .detach();
 
-    dead.store(true, Ordering::Relaxed);
+    state.dead.store(true, Ordering::Relaxed);
 }
 
 thread_local! {
@@ -332,9 +749,23 @@ impl Mux {
             );
         }
 
-        Self {
+        let config_subscription = config::subscribe_to_config_reload(|| {
+            // The patterns may have been changed by the new config
+            scan::invalidate_regex_cache();
+            promise::spawn::spawn_into_main_thread(async {
+                if let Some(mux) = Mux::get() {
+                    mux.update_input_lock_filter();
+                }
+            })
+            .detach();
+            true
+        });
+
+        let mux = Self {
             tabs: RefCell::new(HashMap::new()),
             panes: RefCell::new(HashMap::new()),
+            panes_by_domain: RefCell::new(HashMap::new()),
+            domain_index: RefCell::new(DomainIndex::default()),
             windows: RefCell::new(HashMap::new()),
             default_domain: RefCell::new(default_domain),
             domains_by_name: RefCell::new(domains_by_name),
@@ -344,7 +775,31 @@ impl Mux {
             clients: RefCell::new(HashMap::new()),
             identity: RefCell::new(None),
             num_panes_by_workspace: RefCell::new(HashMap::new()),
-        }
+            pane_readers: RefCell::new(HashMap::new()),
+            input_locks: RefCell::new(HashMap::new()),
+            input_filters: RefCell::new(InputFilterChain::default()),
+            pending_attaches: RefCell::new(HashMap::new()),
+            pane_links: RefCell::new(PaneLinks::default()),
+            bookmarks: RefCell::new(PaneBookmarks::default()),
+            markers: RefCell::new(PaneMarkers::default()),
+            output_watchers: RefCell::new(OutputWatchers::default()),
+            history: RefCell::new(PaneHistory::default()),
+            history_save_scheduled: RefCell::new(false),
+            pane_picks: RefCell::new(PanePicks::default()),
+            window_targets: RefCell::new(WindowTargets::default()),
+            shares: RefCell::new(ShareRegistry::default()),
+            share_listener: RefCell::new(None),
+            spawn_timings: RefCell::new(SpawnTimingLog::default()),
+            spawn_limiter: SpawnLimiter::default(),
+            spawn_quota: RefCell::new(SpawnQuota::default()),
+            view_states: RefCell::new(ClientViewStates::default()),
+            skip_domain_rules: Cell::new(false),
+            pending_closes: RefCell::new(PendingCloses::default()),
+            reserved_pane_ids: RefCell::new(HashSet::new()),
+            _config_subscription: config_subscription,
+        };
+        mux.update_input_lock_filter();
+        mux
     }
 
     fn recompute_pane_count(&self) {
@@ -358,12 +813,264 @@ impl Mux {
         *self.num_panes_by_workspace.borrow_mut() = count;
     }
 
+    /// Returns the number of live windows, tabs and panes that
+    /// belong to the specified domain.
+    /// The panes are counted by `panes_by_domain`, and the windows and
+    /// tabs by `domain_index`, which only revisits the tabs and windows
+    /// that changed since it was last asked.
+    pub fn domain_inventory(&self, domain_id: DomainId) -> DomainInventory {
+        let num_panes = match self.panes_by_domain.borrow().get(&domain_id) {
+            Some(panes) => panes.len(),
+            None => return DomainInventory::default(),
+        };
+        let mut index = self.domain_index.borrow_mut();
+        index.refresh(
+            &self.tabs.borrow(),
+            &self.windows.borrow(),
+            &self.panes.borrow(),
+        );
+        let (num_windows, num_tabs) = index.counts(domain_id);
+        DomainInventory {
+            num_windows,
+            num_tabs,
+            num_panes,
+        }
+    }
+
+    /// Returns the sum of the most recent resource usage samples of
+    /// the panes in a domain, or None if none of them have been sampled
+    pub fn domain_resource_usage(&self, domain_id: DomainId) -> Option<PaneResourceUsage> {
+        let usage: Vec<PaneResourceUsage> = self
+            .iter_panes_for_domain(domain_id)
+            .iter()
+            .filter_map(|pane| pane.get_resource_usage())
+            .collect();
+        PaneResourceUsage::sum(usage.iter())
+    }
+
+    /// Returns information about each of the registered domains,
+    /// in the order of `ordered_domains`
+    pub async fn list_domains(&self) -> Vec<DomainInfo> {
+        let domains = self.ordered_domains();
+        let mut result = vec![];
+        for dom in domains {
+            result.push(DomainInfo::new(&dom).await);
+        }
+        result
+    }
+
     pub fn client_had_input(&self, client_id: &ClientId) {
         if let Some(info) = self.clients.borrow_mut().get_mut(client_id) {
             info.update_last_input();
         }
     }
 
+    /// Returns the period without input after which `pane_id` locks,
+    /// if it belongs to a sensitive domain
+    fn pane_lock_after(&self, pane_id: PaneId) -> Option<Duration> {
+        let pane = self.get_pane(pane_id)?;
+        let domain = self.get_domain(pane.domain_id())?;
+        configuration().domain_lock_after(domain.domain_name())
+    }
+
+    /// Called by `InputLockFilter` for input to a pane.
+    /// Returns true if the input can be delivered.
+    /// Returns false if the pane is locked, either explicitly or because
+    /// its domain is sensitive and it went without input for too long;
+    /// `data` is then held until `unlock_pane` is called, and
+    /// `MuxNotification::PaneInputHeld` is emitted so that the frontend
+    /// can offer to unlock the pane.
+    pub fn check_pane_input(&self, pane_id: PaneId, data: &[u8], source: InputSource) -> bool {
+        let lock_after = self.pane_lock_after(pane_id);
+        let now = Instant::now();
+        // Panes that are not managed by the mux, such as overlays,
+        // are never locked
+        let mut locks = self.input_locks.borrow_mut();
+        let lock = match locks.get_mut(&pane_id) {
+            Some(lock) => lock,
+            None => return true,
+        };
+        if lock.accept_input(lock_after, now) {
+            return true;
+        }
+        if !lock.hold(source, data) {
+            log::warn!(
+                "discarding {} input to locked pane {}: too much input is already held",
+                source.as_str(),
+                pane_id
+            );
+        }
+        drop(locks);
+        self.notify(MuxNotification::PaneInputHeld(pane_id));
+        false
+    }
+
+    pub fn is_pane_locked(&self, pane_id: PaneId) -> bool {
+        let lock_after = self.pane_lock_after(pane_id);
+        self.input_locks
+            .borrow()
+            .get(&pane_id)
+            .map(|lock| lock.would_hold_input(lock_after, Instant::now()))
+            .unwrap_or(false)
+    }
+
+    /// Holds input to the pane until `unlock_pane` is called
+    pub fn lock_pane(&self, pane_id: PaneId) {
+        log::debug!("locking pane {}", pane_id);
+        if let Some(lock) = self.input_locks.borrow_mut().get_mut(&pane_id) {
+            lock.lock();
+        }
+        self.update_input_lock_filter();
+    }
+
+    /// Unlocks the pane, and sends it the input that was held while
+    /// it was locked
+    pub fn unlock_pane(&self, pane_id: PaneId) {
+        log::debug!("unlocking pane {}", pane_id);
+        let held = match self.input_locks.borrow_mut().get_mut(&pane_id) {
+            Some(lock) => lock.unlock(Instant::now()),
+            None => vec![],
+        };
+        self.update_input_lock_filter();
+        if held.is_empty() {
+            return;
+        }
+        let pane = match self.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        for HeldInput { source, data } in held {
+            if let Err(err) = self.send_input_to_pane(&pane, &data, source) {
+                log::error!("sending held input to pane {}: {:#}", pane_id, err);
+            }
+        }
+    }
+
+    /// Registers the input lock filter while it can hold input, which
+    /// is while a domain is sensitive or a pane is locked, and removes
+    /// it otherwise so that input to panes stays cheap
+    pub fn update_input_lock_filter(&self) {
+        let needed = configuration().has_sensitive_domains()
+            || self
+                .input_locks
+                .borrow()
+                .values()
+                .any(|lock| lock.is_locked());
+        let mut filters = self.input_filters.borrow_mut();
+        if needed == filters.contains(INPUT_LOCK_FILTER) {
+            return;
+        }
+        if needed {
+            // Input was not recorded while the filter was absent, so
+            // don't let that count as idle time
+            let now = Instant::now();
+            for lock in self.input_locks.borrow_mut().values_mut() {
+                lock.reset_idle(now);
+            }
+            filters.push_front(Rc::new(InputLockFilter));
+        } else {
+            filters.remove(INPUT_LOCK_FILTER);
+        }
+    }
+
+    /// Adds `filter` to the end of the input filter chain, replacing
+    /// any filter with the same name
+    pub fn add_input_filter(&self, filter: Rc<dyn InputFilter>) {
+        self.input_filters.borrow_mut().push(filter);
+    }
+
+    /// Returns true if a filter named `name` was removed
+    pub fn remove_input_filter(&self, name: &str) -> bool {
+        self.input_filters.borrow_mut().remove(name)
+    }
+
+    /// Must be called before delivering input from the user to a pane.
+    /// Runs `data` through the input filters and returns the data that
+    /// should be delivered, or None if a filter discarded it.
+    /// Rejections are reported via `MuxNotification::PaneInputRejected`.
+    pub fn filter_pane_input<'a>(
+        &self,
+        pane: &Rc<dyn Pane>,
+        data: &'a [u8],
+        source: InputSource,
+    ) -> Option<Cow<'a, [u8]>> {
+        if !inputfilter::have_input_filters() {
+            return Some(Cow::Borrowed(data));
+        }
+        let filters = self.input_filters.borrow().filters();
+        match inputfilter::run_filters(&filters, data, |filter, data| {
+            filter.filter_input(pane, data, source)
+        }) {
+            FilteredInput::Deliver(data) => Some(data),
+            FilteredInput::Rejected(rejection) => {
+                log::debug!(
+                    "input filter {} rejected {} input to pane {}: {}",
+                    rejection.filter,
+                    source.as_str(),
+                    pane.pane_id(),
+                    rejection.reason
+                );
+                self.notify(MuxNotification::PaneInputRejected {
+                    pane_id: pane.pane_id(),
+                    source,
+                    rejection,
+                    data: Arc::new(data.to_vec()),
+                });
+                None
+            }
+            FilteredInput::Held => None,
+        }
+    }
+
+    /// Runs `data` through the input filters and sends whatever they
+    /// let through to `pane`, pasting it if `source` is a paste.
+    /// This is the way for scripts and remote clients to send input
+    /// to a pane, so that a locked pane holds it.
+    pub fn send_input_to_pane(
+        &self,
+        pane: &Rc<dyn Pane>,
+        data: &[u8],
+        source: InputSource,
+    ) -> anyhow::Result<()> {
+        match self.filter_pane_input(pane, data, source) {
+            Some(data) => inputfilter::deliver_input(pane, &data, source),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the bytes that pressing `key` would send to `pane` through
+    /// the input filters.
+    /// The key is only encoded when filters are registered, and then
+    /// without the cursor key and newline modes of the pane; when it
+    /// passes unchanged, the pane encodes it itself as usual.
+    pub fn filter_key_down(
+        &self,
+        pane: &Rc<dyn Pane>,
+        key: &termwiz::input::KeyCode,
+        mods: termwiz::input::Modifiers,
+    ) -> FilteredKey {
+        if !inputfilter::have_input_filters() {
+            return FilteredKey::Unchanged;
+        }
+        let modes = termwiz::input::KeyCodeEncodeModes {
+            encoding: pane.get_keyboard_encoding(),
+            application_cursor_keys: false,
+            newline_mode: false,
+        };
+        let encoded = key.encode(mods, modes, true).unwrap_or_default();
+        match self.filter_pane_input(pane, encoded.as_bytes(), InputSource::Keyboard) {
+            Some(Cow::Borrowed(_)) => FilteredKey::Unchanged,
+            Some(Cow::Owned(data)) => FilteredKey::Replaced(data),
+            None => FilteredKey::Discarded,
+        }
+    }
+
+    pub fn record_output_queue_depth(&self, client_id: &ClientId, depth: usize) {
+        if let Some(info) = self.clients.borrow_mut().get_mut(client_id) {
+            info.output_queue_depth = depth;
+        }
+    }
+
     pub fn record_input_for_current_identity(&self) {
         if let Some(ident) = self.identity.borrow().as_ref() {
             self.client_had_input(ident);
@@ -376,6 +1083,30 @@ impl Mux {
         }
     }
 
+    /// Records how `client_id` views `pane_id`, so that the view can be
+    /// restored when the same user reattaches from the same host
+    pub fn record_pane_view_for_client(
+        &self,
+        client_id: &ClientId,
+        pane_id: PaneId,
+        state: PaneViewState,
+    ) {
+        if self.get_pane(pane_id).is_none() {
+            return;
+        }
+        self.view_states
+            .borrow_mut()
+            .record(client_id, pane_id, state, Instant::now());
+    }
+
+    /// Returns the views of its panes that `client_id` last reported,
+    /// including those reported by its earlier connections
+    pub fn pane_views_for_client(&self, client_id: &ClientId) -> HashMap<PaneId, PaneViewState> {
+        self.view_states
+            .borrow_mut()
+            .views(client_id, Instant::now())
+    }
+
     pub fn record_focus_for_client(&self, client_id: &ClientId, pane_id: PaneId) {
         let mut prior = None;
         if let Some(info) = self.clients.borrow_mut().get_mut(client_id) {
@@ -395,9 +1126,530 @@ impl Mux {
         if let Some(pane) = self.get_pane(pane_id) {
             pane.focus_changed(true);
         }
+        self.activate_linked_panes(pane_id);
+    }
+
+    /// Links `panes` together with `policy`, merging any groups with
+    /// the same policy that they already belong to
+    pub fn link_panes(
+        &self,
+        mut panes: Vec<PaneId>,
+        policy: LinkPolicy,
+    ) -> anyhow::Result<LinkGroupId> {
+        panes.sort();
+        panes.dedup();
+        if panes.len() < 2 {
+            anyhow::bail!("at least two panes are needed to make a link");
+        }
+        for &pane_id in &panes {
+            if self.get_pane(pane_id).is_none() {
+                anyhow::bail!("pane {} not found", pane_id);
+            }
+        }
+        let group_id = self.pane_links.borrow_mut().link(&panes, policy);
+        self.notify(MuxNotification::PaneLinksChanged);
+        Ok(group_id)
+    }
+
+    /// Returns the panes that are linked with `pane_id` by `policy`
+    pub fn linked_panes(&self, pane_id: PaneId, policy: LinkPolicy) -> Vec<PaneId> {
+        self.pane_links.borrow().partners(pane_id, policy)
+    }
+
+    /// Returns the link groups that `pane_id` belongs to
+    pub fn pane_links(&self, pane_id: PaneId) -> Vec<PaneLink> {
+        self.pane_links.borrow().links_for_pane(pane_id)
+    }
+
+    /// Records a bookmark of a pane that this mux hosts; see
+    /// `Pane::add_bookmark`
+    pub fn record_bookmark(&self, pane_id: PaneId, bookmark: Bookmark) {
+        self.bookmarks.borrow_mut().add(pane_id, bookmark);
+    }
+
+    /// Returns the bookmarks that were recorded for a pane, marking
+    /// those whose row lies above `scrollback_top` as trimmed
+    pub fn recorded_bookmarks(
+        &self,
+        pane_id: PaneId,
+        scrollback_top: StableRowIndex,
+    ) -> Vec<Bookmark> {
+        self.bookmarks.borrow().list(pane_id, scrollback_top)
+    }
+
+    /// Adds a marker to a pane that this mux hosts, returning it along
+    /// with the id that it was given; see `Pane::add_marker`
+    pub fn record_marker(&self, pane_id: PaneId, marker: Marker) -> anyhow::Result<Marker> {
+        let marker = self.markers.borrow_mut().add(pane_id, marker)?;
+        self.notify(MuxNotification::PaneMarkersChanged(pane_id));
+        Ok(marker)
+    }
+
+    /// Removes a marker from a pane that this mux hosts, returning
+    /// false if the pane has no marker with that id
+    pub fn remove_recorded_marker(&self, pane_id: PaneId, id: &str) -> bool {
+        let removed = self.markers.borrow_mut().remove(pane_id, id);
+        if removed {
+            self.notify(MuxNotification::PaneMarkersChanged(pane_id));
+        }
+        removed
+    }
+
+    /// Returns the markers of a pane, dropping those whose rows all
+    /// lie above `scrollback_top`
+    pub fn recorded_markers(&self, pane_id: PaneId, scrollback_top: StableRowIndex) -> Vec<Marker> {
+        self.markers.borrow_mut().list(pane_id, scrollback_top)
+    }
+
+    /// Adds an output watcher to a pane that this mux hosts, on behalf
+    /// of `owner` if it was added by a client; see
+    /// `Pane::add_output_watcher`
+    pub fn add_output_watcher(
+        &self,
+        pane_id: PaneId,
+        spec: OutputWatcherSpec,
+        owner: Option<Arc<ClientId>>,
+    ) -> anyhow::Result<WatcherId> {
+        self.output_watchers.borrow_mut().add(pane_id, spec, owner)
+    }
+
+    /// Returns true if the watcher was removed
+    pub fn remove_output_watcher(&self, pane_id: PaneId, id: WatcherId) -> bool {
+        self.output_watchers.borrow_mut().remove(pane_id, id)
+    }
+
+    pub fn output_watchers(&self, pane_id: PaneId) -> Vec<OutputWatcherInfo> {
+        self.output_watchers.borrow().list(pane_id)
+    }
+
+    /// Records the working directory that a pane changed to, or the
+    /// command that it started, in its history; see `history`
+    fn record_pane_history(&self, pane_id: PaneId, alert: &Alert) {
+        let pane = match self.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let config = configuration();
+        let now = chrono::Utc::now();
+        match alert {
+            Alert::CurrentWorkingDirectoryChanged => {
+                let (cwd, domain) = match (
+                    pane.get_current_working_dir(),
+                    self.get_domain(pane.domain_id()),
+                ) {
+                    (Some(cwd), Some(domain)) => (cwd, domain),
+                    _ => return,
+                };
+                let changed = self.history.borrow_mut().record_cwd(
+                    pane_id,
+                    domain.domain_name(),
+                    cwd.to_string(),
+                    now,
+                    config.recent_dirs_max_entries,
+                );
+                if changed {
+                    self.schedule_recent_dirs_save();
+                }
+            }
+            Alert::CommandStarted if config.pane_history_commands => {
+                // The output of the command has yet to start, so the
+                // last zone is the input of the command, unless it had
+                // none, such as when Enter is pressed at an empty prompt
+                let zones = pane.get_semantic_zones().unwrap_or_default();
+                let input = zones
+                    .iter()
+                    .rev()
+                    .find(|zone| zone.semantic_type != SemanticType::Output)
+                    .filter(|zone| zone.semantic_type == SemanticType::Input);
+                if let Some(zone) = input {
+                    let command = pane::semantic_zone_text(&*pane, zone).trim().to_string();
+                    if !command.is_empty() {
+                        self.history
+                            .borrow_mut()
+                            .record_command(pane_id, command, now);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Saves the recent directories a little later, so that a burst of
+    /// directory changes is saved at once
+    fn schedule_recent_dirs_save(&self) {
+        if !self.history.borrow().needs_save() || self.history_save_scheduled.replace(true) {
+            return;
+        }
+        promise::spawn::spawn_into_main_thread(async move {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            if let Some(mux) = Mux::get() {
+                mux.history_save_scheduled.replace(false);
+                let max_recent = configuration().recent_dirs_max_entries;
+                if let Err(err) = mux.history.borrow_mut().save(max_recent) {
+                    log::error!("saving recent directories: {:#}", err);
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Loads the recent directories that were saved by an earlier run,
+    /// unless `persist_recent_dirs` is disabled, and saves them as they
+    /// change.  This is enabled by the GUI and the mux server, rather
+    /// than by every mux, so that tests and short lived processes
+    /// don't touch the file.
+    pub fn enable_recent_dir_persistence(&self) {
+        let config = configuration();
+        if !config.persist_recent_dirs {
+            return;
+        }
+        if let Err(err) = self
+            .history
+            .borrow_mut()
+            .persist_to(history::recent_dirs_path(), config.recent_dirs_max_entries)
+        {
+            log::warn!("{:#}; it will be replaced", err);
+        }
+    }
+
+    /// Returns up to `limit` of the recently visited directories, most
+    /// recently visited first
+    pub fn recent_dirs(&self, limit: Option<usize>) -> Vec<RecentDir> {
+        self.history.borrow().recent_dirs(limit)
+    }
+
+    /// Returns the history of a pane that this mux knows about, oldest
+    /// entry first
+    pub fn pane_history(&self, pane_id: PaneId) -> Vec<HistoryEntry> {
+        self.history.borrow().pane_history(pane_id)
+    }
+
+    /// Lets `pick_panes` ask this frontend to show the pane selector
+    pub fn enable_pane_picking(&self) {
+        self.pane_picks.borrow_mut().enable();
+    }
+
+    /// Asks the frontend to let the user choose a pane, or several if
+    /// `multi` is true.  Returns the chosen panes, or None if the user
+    /// cancelled.
+    pub async fn pick_panes(
+        &self,
+        prompt: Option<String>,
+        multi: bool,
+    ) -> anyhow::Result<Option<Vec<PaneId>>> {
+        let (request, rx) = self.pane_picks.borrow_mut().start(prompt, multi)?;
+        self.notify(MuxNotification::PickPanes(request));
+        Ok(rx.recv().await.ok())
+    }
+
+    /// Reports the panes that the user chose for a pick, or None if
+    /// the pick was cancelled
+    pub fn finish_pane_pick(&self, id: PickId, panes: Option<Vec<PaneId>>) {
+        if !self.pane_picks.borrow_mut().finish(id, panes) {
+            log::debug!("pane pick {} is no longer pending", id);
+        }
+    }
+
+    /// Lets the frontend resolve the targets of spawns that refer to
+    /// screens or to the mouse pointer; see `windowtarget`
+    pub fn set_window_target_resolver(&self, resolver: WindowTargetResolver) {
+        self.window_targets.borrow_mut().set_resolver(resolver);
+    }
+
+    /// Returns the window into which a command spawned with `target`
+    /// goes, or None if it goes into a new window
+    pub fn resolve_window_target(
+        &self,
+        target: &SpawnWindowTarget,
+    ) -> anyhow::Result<Option<WindowId>> {
+        self.window_targets
+            .borrow()
+            .resolve(target, |window_id| self.get_window(window_id).is_some())
+    }
+
+    /// Records the TLS server at which viewers redeem shares of tabs
+    pub fn set_share_listener(&self, listener: ShareListener) {
+        self.share_listener.borrow_mut().replace(listener);
+    }
+
+    /// Issues a share of a tab whose token is `secret`; see `share`.
+    /// Returns the share along with the link that the viewer uses to
+    /// redeem it within `lifetime`.
+    pub fn share_tab(
+        &self,
+        tab_id: TabId,
+        secret: String,
+        lifetime: Duration,
+    ) -> anyhow::Result<(ShareInfo, ShareLink)> {
+        let listener = self.share_listener.borrow().clone().ok_or_else(|| {
+            anyhow!(
+                "this mux has no TLS server that accepts shares; \
+                 set `accept_shares = true` in one of its `tls_servers`"
+            )
+        })?;
+        self.get_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab {} not found", tab_id))?;
+        let share =
+            self.shares
+                .borrow_mut()
+                .issue(tab_id, secret.clone(), lifetime, chrono::Utc::now())?;
+        let link = ShareLink {
+            token: ShareToken {
+                secret,
+                fingerprint: listener.fingerprint,
+            },
+            address: listener.address,
+        };
+        Ok((share, link))
+    }
+
+    /// Redeems the share whose token is `secret` on behalf of `viewer`,
+    /// and lets the shared tab know that it is being watched
+    pub fn redeem_share(&self, secret: &str, viewer: String) -> anyhow::Result<ShareInfo> {
+        let share = self
+            .shares
+            .borrow_mut()
+            .redeem(secret, viewer.clone(), chrono::Utc::now())?;
+        self.notify_shared_tab(share.tab_id, format!("{} is viewing this tab", viewer));
+        Ok(share)
+    }
+
+    /// Ends a share, such as when its viewer disconnects, returning
+    /// false if there was no such share
+    pub fn end_share(&self, id: ShareId) -> bool {
+        let share = match self.shares.borrow_mut().end(id) {
+            Some(share) => share,
+            None => return false,
+        };
+        if let ShareState::Viewing { viewer } = share.state {
+            self.notify_shared_tab(share.tab_id, format!("{} stopped viewing this tab", viewer));
+        }
+        true
+    }
+
+    /// Ends a share and disconnects its viewer, returning false if
+    /// there was no such share
+    pub fn revoke_share(&self, id: ShareId) -> bool {
+        let ended = self.end_share(id);
+        if ended {
+            self.notify(MuxNotification::ShareEnded(id));
+        }
+        ended
+    }
+
+    /// Returns the shares that are pending or being viewed
+    pub fn shares(&self) -> Vec<ShareInfo> {
+        self.shares.borrow_mut().list(chrono::Utc::now())
+    }
+
+    /// Checks that spawning a pane into each of `domain_ids` is within
+    /// the spawn limits, which apply to spawns from `origin`, and
+    /// counts the spawns towards the rate of spawns.
+    /// When a spawn is refused, the user is notified in the pane that
+    /// they most recently used, so that a runaway script is noticed.
+    pub fn check_spawn_quota(
+        &self,
+        domain_ids: &[DomainId],
+        origin: SpawnOrigin,
+    ) -> DomainResult<()> {
+        let config = configuration();
+        if origin == SpawnOrigin::Interactive && !config.spawn_limits_apply_to_interactive {
+            return Ok(());
+        }
+
+        let mut domains: Vec<(DomainId, DomainSpawn)> = vec![];
+        for &domain_id in domain_ids {
+            match domains.iter_mut().find(|(id, _)| *id == domain_id) {
+                Some((_, spawn)) => spawn.new_panes += 1,
+                None => domains.push((
+                    domain_id,
+                    DomainSpawn {
+                        domain: self
+                            .get_domain(domain_id)
+                            .map(|domain| domain.domain_name().to_string())
+                            .unwrap_or_else(|| domain_id.to_string()),
+                        panes: self
+                            .panes_by_domain
+                            .borrow()
+                            .get(&domain_id)
+                            .map_or(0, |panes| panes.len()),
+                        new_panes: 1,
+                    },
+                )),
+            }
+        }
+        let domains: Vec<DomainSpawn> = domains.into_iter().map(|(_, spawn)| spawn).collect();
+        let total_panes = self.panes.borrow().len();
+
+        let result =
+            self.spawn_quota
+                .borrow_mut()
+                .check(&config, &domains, total_panes, Instant::now());
+        if let Err(err) = &result {
+            log::warn!("{}", err);
+            let recent_pane = self
+                .iter_clients()
+                .into_iter()
+                .filter_map(|info| Some((info.last_input, info.focused_pane_id?)))
+                .max_by_key(|(last_input, _)| *last_input);
+            if let Some((_, pane_id)) = recent_pane {
+                self.notify(MuxNotification::Alert {
+                    pane_id,
+                    alert: wezterm_term::Alert::ToastNotification {
+                        title: Some("Spawn refused".to_string()),
+                        body: err.to_string(),
+                        focus: false,
+                    },
+                });
+            }
+        }
+        result
+    }
+
+    /// Changes a spawn limit until the mux restarts
+    pub fn set_spawn_limit(&self, limit: SpawnLimit, value: SpawnLimitValue) {
+        self.spawn_quota.borrow_mut().set(limit, value);
+    }
+
+    /// Returns the spawn limits that are in effect
+    pub fn spawn_limits(&self) -> Vec<SpawnLimitInfo> {
+        self.spawn_quota.borrow().list(&configuration())
+    }
+
+    /// Shows a notification in the active pane of a shared tab
+    fn notify_shared_tab(&self, tab_id: TabId, body: String) {
+        if let Some(pane) = self.get_tab(tab_id).and_then(|tab| tab.get_active_pane()) {
+            self.notify(MuxNotification::Alert {
+                pane_id: pane.pane_id(),
+                alert: wezterm_term::Alert::ToastNotification {
+                    title: Some("Shared tab".to_string()),
+                    body,
+                    focus: false,
+                },
+            });
+        }
+    }
+
+    /// Matches the output that the pane has just processed against its
+    /// watchers, sending the responses of those that fired
+    fn match_output_watchers(&self, pane: &Rc<dyn Pane>) {
+        if !self.output_watchers.borrow().is_watching(pane.pane_id()) {
+            return;
+        }
+        let fired = self.output_watchers.borrow_mut().scan(&**pane);
+        for fired in fired {
+            if let Some(response) = &fired.response {
+                if let Err(err) = pane.writer().write_all(response.as_bytes()) {
+                    log::error!(
+                        "sending the response of output watcher {} to pane {}: {:#}",
+                        fired.matched.watcher_id,
+                        pane.pane_id(),
+                        err
+                    );
+                }
+            }
+            self.notify(MuxNotification::OutputMatched {
+                owner: fired.owner,
+                matched: fired.matched,
+            });
+        }
+    }
+
+    /// Remembers the timing of a spawn or split, and passes it to the
+    /// handlers of the `spawn-timing` event, if there are any
+    fn record_spawn_timing(&self, timing: SpawnTiming) {
+        log::trace!(
+            "{} in domain `{}` took {:.1}ms",
+            timing.operation,
+            timing.domain,
+            timing.total_ms
+        );
+        self.spawn_timings.borrow_mut().record(timing.clone());
+
+        let has_handler = config::run_immediate_with_lua_config(|lua| match lua {
+            Some(lua) => Ok(config::lua::has_event_handler(&lua, "spawn-timing")?),
+            None => Ok(false),
+        });
+        if let Ok(true) = has_handler {
+            promise::spawn::spawn(config::with_lua_config_on_main_thread(
+                move |lua| async move {
+                    if let Some(lua) = lua {
+                        let args = lua.pack_multi(timing)?;
+                        if let Err(err) =
+                            config::lua::emit_event(&lua, ("spawn-timing".to_string(), args)).await
+                        {
+                            log::error!("while processing spawn-timing event: {:#}", err);
+                        }
+                    }
+                    Ok(())
+                },
+            ))
+            .detach();
+        }
+    }
+
+    /// Returns the timings of the most recent spawns and splits,
+    /// oldest first
+    pub fn recent_spawn_timings(&self) -> Vec<SpawnTiming> {
+        self.spawn_timings.borrow().recent()
+    }
+
+    /// Waits for the `max_concurrent_spawns` of `domain` to allow
+    /// another spawn into it.  The spawn proceeds while the returned
+    /// permit is held.
+    async fn wait_for_spawn_slot(&self, domain: &Arc<dyn Domain>) -> SpawnPermit {
+        let limit = configuration().domain_max_concurrent_spawns(domain.domain_name());
+        let _span = spawntiming::span("spawn_queue");
+        self.spawn_limiter.acquire(domain.domain_id(), limit).await
+    }
+
+    /// Returns how many spawns into the domain are waiting for one of
+    /// the spawns that are in progress to complete
+    pub fn queued_spawns(&self, domain_id: DomainId) -> usize {
+        self.spawn_limiter.queued(domain_id)
+    }
+
+    /// Names the domain that `domain` refers to, for the timing of a
+    /// spawn or split
+    fn spawn_domain_name(&self, pane_id: Option<PaneId>, domain: &SpawnTabDomain) -> String {
+        self.resolve_spawn_tab_domain(pane_id, domain)
+            .map(|domain| domain.domain_name().to_string())
+            .unwrap_or_else(|_| format!("{:?}", domain))
+    }
+
+    /// Activates the panes that are linked with `pane_id` to be focused
+    /// together, and their tabs, in the windows other than the one that
+    /// contains `pane_id`
+    fn activate_linked_panes(&self, pane_id: PaneId) {
+        let partners = self.linked_panes(pane_id, LinkPolicy::FocusTogether);
+        if partners.is_empty() {
+            return;
+        }
+        let focused_window = self
+            .resolve_pane_id(pane_id)
+            .map(|(_, window_id, _)| window_id);
+        for partner in partners {
+            let (window_id, tab_id) = match self.resolve_pane_id(partner) {
+                Some((_domain_id, window_id, tab_id)) => (window_id, tab_id),
+                None => continue,
+            };
+            if Some(window_id) == focused_window {
+                // Switching tabs here would take the focus away
+                continue;
+            }
+            if let (Some(tab), Some(pane)) = (self.get_tab(tab_id), self.get_pane(partner)) {
+                tab.set_active_pane(&pane);
+            }
+            if let Some(mut window) = self.get_window_mut(window_id) {
+                if let Some(idx) = window.idx_by_id(tab_id) {
+                    window.save_and_then_set_active(idx);
+                }
+            }
+        }
     }
 
     pub fn register_client(&self, client_id: Arc<ClientId>) {
+        audit::log(audit::AuditRecord::new("attach").client(Some(client_id.clone())));
         self.clients
             .borrow_mut()
             .insert((*client_id).clone(), ClientInfo::new(client_id));
@@ -459,6 +1711,15 @@ impl Mux {
             .unwrap_or_else(|| DEFAULT_WORKSPACE.to_string())
     }
 
+    /// Returns the pane that most recently had focus in the
+    /// specified client
+    pub fn focused_pane_id_for_client(&self, ident: &Arc<ClientId>) -> Option<PaneId> {
+        self.clients
+            .borrow()
+            .get(&ident)
+            .and_then(|info| info.focused_pane_id)
+    }
+
     pub fn set_active_workspace_for_client(&self, ident: &Arc<ClientId>, workspace: &str) {
         let mut clients = self.clients.borrow_mut();
         if let Some(info) = clients.get_mut(&ident) {
@@ -494,7 +1755,9 @@ impl Mux {
     }
 
     pub fn unregister_client(&self, client_id: &ClientId) {
+        audit::log(audit::AuditRecord::new("detach").client(Some(Arc::new(client_id.clone()))));
         self.clients.borrow_mut().remove(client_id);
+        self.output_watchers.borrow_mut().remove_owner(client_id);
     }
 
     pub fn subscribe<F>(&self, subscriber: F)
@@ -508,6 +1771,33 @@ impl Mux {
     }
 
     pub fn notify(&self, notification: MuxNotification) {
+        if let MuxNotification::Alert {
+            pane_id,
+            alert: alert @ (Alert::CurrentWorkingDirectoryChanged | Alert::CommandStarted),
+        } = &notification
+        {
+            // The terminal of a local pane is still borrowed while it
+            // raises the alert, so its state is read once it is done
+            let pane_id = *pane_id;
+            let alert = alert.clone();
+            promise::spawn::spawn_into_main_thread(async move {
+                if let Some(mux) = Mux::get() {
+                    mux.record_pane_history(pane_id, &alert);
+                }
+            })
+            .detach();
+        }
+        match &notification {
+            MuxNotification::TabLayoutChanged(tab_id) => {
+                self.domain_index.borrow_mut().tab_changed(*tab_id)
+            }
+            MuxNotification::WindowCreated(window_id)
+            | MuxNotification::WindowRemoved(window_id)
+            | MuxNotification::WindowInvalidated(window_id) => {
+                self.domain_index.borrow_mut().window_changed(*window_id)
+            }
+            _ => {}
+        }
         let mut subscribers = self.subscribers.borrow_mut();
         subscribers.retain(|_, notify| notify(notification.clone()));
     }
@@ -528,10 +1818,95 @@ impl Mux {
         self.domains.borrow().get(&id).cloned()
     }
 
+    /// Change the settings of the serial port that is connected
+    /// to the specified pane
+    pub fn set_serial_params(
+        &self,
+        pane_id: PaneId,
+        params: &portable_pty::serial::SerialParams,
+    ) -> anyhow::Result<()> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+        let domain = self
+            .get_domain(pane.domain_id())
+            .ok_or_else(|| anyhow!("pane {} has no domain", pane_id))?;
+        match domain.downcast_ref::<crate::domain::LocalDomain>() {
+            Some(local) => local.set_serial_params(params),
+            None => anyhow::bail!("pane {} is not connected to a serial port", pane_id),
+        }
+    }
+
     pub fn get_domain_by_name(&self, name: &str) -> Option<Arc<dyn Domain>> {
         self.domains_by_name.borrow().get(name).cloned()
     }
 
+    /// Returns the domain named `name` that is defined by a config entry
+    /// of `kind`, even if the name refers to a different domain
+    pub fn get_domain_by_name_and_kind(
+        &self,
+        name: &str,
+        kind: DomainKind,
+    ) -> Option<Arc<dyn Domain>> {
+        self.domains
+            .borrow()
+            .values()
+            .find(|dom| dom.domain_name() == name && dom.domain_kind() == Some(kind))
+            .cloned()
+    }
+
+    /// Returns the domain that `name` should refer to, and the number
+    /// of domains that share the name.  When several domains share a
+    /// name, the one whose kind ranks highest in `domain_name_priority`
+    /// owns it, with ties going to the domain that was added first.
+    fn resolve_domain_name(&self, name: &str) -> (Option<Arc<dyn Domain>>, usize) {
+        let config = configuration();
+        let rank = |dom: &Arc<dyn Domain>| {
+            dom.domain_kind()
+                .map(|kind| config.domain_name_rank(kind))
+                .unwrap_or(usize::MAX)
+        };
+        let domains = self.domains.borrow();
+        let candidates: Vec<&Arc<dyn Domain>> = domains
+            .values()
+            .filter(|dom| dom.domain_name() == name)
+            .collect();
+        let owner = candidates
+            .iter()
+            .min_by_key(|dom| (rank(dom), dom.domain_id()))
+            .map(|dom| Arc::clone(dom));
+        (owner, candidates.len())
+    }
+
+    /// Returns the id of the domain that owns the name of `domain_id`,
+    /// if that is a different domain that shares the name
+    pub fn domain_shadowed_by(&self, domain_id: DomainId) -> Option<DomainId> {
+        let domain = self.get_domain(domain_id)?;
+        let owner = self.get_domain_by_name(domain.domain_name())?;
+        if owner.domain_id() == domain_id {
+            None
+        } else {
+            Some(owner.domain_id())
+        }
+    }
+
+    /// Re-evaluates which domain each name refers to, so that a change
+    /// to `domain_name_priority` applies to domains that were already
+    /// added
+    pub fn rank_domain_names(&self) {
+        let names: Vec<String> = self
+            .domains
+            .borrow()
+            .values()
+            .map(|dom| dom.domain_name().to_string())
+            .collect();
+        for name in names {
+            if let (Some(owner), _) = self.resolve_domain_name(&name) {
+                self.domains_by_name.borrow_mut().insert(name, owner);
+            }
+        }
+    }
+
     pub fn add_domain(&self, domain: &Arc<dyn Domain>) {
         if self.default_domain.borrow().is_none() {
             *self.default_domain.borrow_mut() = Some(Arc::clone(domain));
@@ -539,9 +1914,64 @@ impl Mux {
         self.domains
             .borrow_mut()
             .insert(domain.domain_id(), Arc::clone(domain));
+
+        let name = domain.domain_name();
+        let (owner, count) = self.resolve_domain_name(name);
+        let owner = owner.unwrap_or_else(|| Arc::clone(domain));
+        let previous = self.get_domain_by_name(name);
+        if let (true, Some(previous)) = (count > 1, previous) {
+            log::warn!(
+                "Domain name collision: {:?} is the name of both domain {} ({}) \
+                 and domain {} ({}); the name now refers to domain {}. \
+                 Rename one of them, or adjust domain_name_priority to choose \
+                 which one the name refers to.",
+                name,
+                domain.domain_id(),
+                describe_domain_kind(domain),
+                previous.domain_id(),
+                describe_domain_kind(&previous),
+                owner.domain_id(),
+            );
+        }
         self.domains_by_name
             .borrow_mut()
-            .insert(domain.domain_name().to_string(), Arc::clone(domain));
+            .insert(name.to_string(), owner);
+    }
+
+    /// Removes a domain that has no panes, such as a discovered domain
+    /// whose container has gone away.  The default domain cannot be
+    /// removed.  If another domain shares the name of the removed
+    /// domain, the name refers to that domain from now on.
+    pub fn remove_domain(&self, domain_id: DomainId) -> anyhow::Result<()> {
+        if self.default_domain().domain_id() == domain_id {
+            anyhow::bail!("domain {} is the default domain", domain_id);
+        }
+        if self
+            .panes_by_domain
+            .borrow()
+            .get(&domain_id)
+            .map_or(false, |panes| !panes.is_empty())
+        {
+            anyhow::bail!("domain {} still has panes", domain_id);
+        }
+        let domain = self
+            .domains
+            .borrow_mut()
+            .remove(&domain_id)
+            .ok_or_else(|| anyhow!("no such domain {}", domain_id))?;
+
+        let name = domain.domain_name();
+        match self.resolve_domain_name(name) {
+            (Some(owner), _) => {
+                self.domains_by_name
+                    .borrow_mut()
+                    .insert(name.to_string(), owner);
+            }
+            (None, _) => {
+                self.domains_by_name.borrow_mut().remove(name);
+            }
+        }
+        Ok(())
     }
 
     pub fn set_mux(mux: &Rc<Mux>) {
@@ -568,14 +1998,32 @@ impl Mux {
         self.panes.borrow().get(&pane_id).map(Rc::clone)
     }
 
+    pub(crate) fn pane_reader_state(&self, pane_id: PaneId) -> Option<Arc<PaneReaderState>> {
+        self.pane_readers.borrow().get(&pane_id).map(Arc::clone)
+    }
+
+    pub(crate) fn pane_reader_states(&self) -> Vec<(PaneId, Arc<PaneReaderState>)> {
+        self.pane_readers
+            .borrow()
+            .iter()
+            .map(|(pane_id, state)| (*pane_id, Arc::clone(state)))
+            .collect()
+    }
+
     pub fn get_tab(&self, tab_id: TabId) -> Option<Rc<Tab>> {
         self.tabs.borrow().get(&tab_id).map(Rc::clone)
     }
 
     pub fn add_pane(&self, pane: &Rc<dyn Pane>) -> Result<(), Error> {
-        if self.panes.borrow().contains_key(&pane.pane_id()) {
-            return Ok(());
+        if let Some(existing) = self.panes.borrow().get(&pane.pane_id()) {
+            // Adding the same pane again is harmless, but another pane
+            // with its id would be unreachable
+            if Rc::as_ptr(existing) as *const () == Rc::as_ptr(pane) as *const () {
+                return Ok(());
+            }
+            anyhow::bail!("pane id {} is already in use", pane.pane_id());
         }
+        self.reserved_pane_ids.borrow_mut().remove(&pane.pane_id());
 
         let clipboard: Arc<dyn Clipboard> = Arc::new(MuxClipboard {
             pane_id: pane.pane_id(),
@@ -589,9 +2037,23 @@ impl Mux {
             .borrow_mut()
             .insert(pane.pane_id(), Rc::clone(pane));
         let pane_id = pane.pane_id();
+        self.panes_by_domain
+            .borrow_mut()
+            .entry(pane.domain_id())
+            .or_default()
+            .insert(pane_id);
+        self.domain_index.borrow_mut().pane_changed(pane_id);
+        // The pane may have been given an id that was chosen elsewhere
+        pane::reserve_pane_id(pane_id);
+        self.input_locks
+            .borrow_mut()
+            .insert(pane_id, PaneInputLock::new(Instant::now()));
         if let Some(reader) = pane.reader()? {
             let banner = self.banner.borrow().clone();
-            thread::spawn(move || read_from_pane_pty(pane_id, banner, reader));
+            let state = start_pane_reader(pane_id, banner, reader)?;
+            self.pane_readers.borrow_mut().insert(pane_id, state);
+            watchdog::start_watchdog();
+            resources::start_resource_sampler();
         }
         self.recompute_pane_count();
         self.notify(MuxNotification::PaneAdded(pane_id));
@@ -600,20 +2062,85 @@ impl Mux {
 
     pub fn add_tab_no_panes(&self, tab: &Rc<Tab>) {
         self.tabs.borrow_mut().insert(tab.tab_id(), Rc::clone(tab));
+        self.domain_index.borrow_mut().tab_changed(tab.tab_id());
         self.recompute_pane_count();
     }
 
     pub fn add_tab_and_active_pane(&self, tab: &Rc<Tab>) -> Result<(), Error> {
         self.tabs.borrow_mut().insert(tab.tab_id(), Rc::clone(tab));
+        self.domain_index.borrow_mut().tab_changed(tab.tab_id());
         let pane = tab
             .get_active_pane()
             .ok_or_else(|| anyhow!("tab MUST have an active pane"))?;
         self.add_pane(&pane)
     }
 
+    /// Claims `pane_id`, which was chosen by the caller rather than by
+    /// `alloc_pane_id`, for a pane that is about to be spawned.
+    /// Fails if a pane with that id exists or is being spawned.
+    pub fn reserve_pane_id(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        if self.panes.borrow().contains_key(&pane_id)
+            || !self.reserved_pane_ids.borrow_mut().insert(pane_id)
+        {
+            anyhow::bail!("pane id {} is already in use", pane_id);
+        }
+        pane::reserve_pane_id(pane_id);
+        Ok(())
+    }
+
+    /// Spawns a pane in `domain` whose id is `pane_id`, which was
+    /// chosen by the caller rather than by `alloc_pane_id`.
+    /// Fails if a pane with that id exists or is being spawned.
+    pub async fn spawn_pane_with_id(
+        &self,
+        domain: &Arc<dyn Domain>,
+        pane_id: PaneId,
+        size: TerminalSize,
+        command: Option<CommandBuilder>,
+        command_dir: Option<CommandDir>,
+    ) -> anyhow::Result<Rc<dyn Pane>> {
+        self.reserve_pane_id(pane_id)?;
+        let result = domain.spawn_pane(pane_id, size, command, command_dir).await;
+        if result.is_err() {
+            self.release_pane_id(pane_id);
+        }
+        Ok(result?)
+    }
+
+    /// Releases a reservation made by `reserve_pane_id` for a pane
+    /// that failed to materialize; `add_pane` releases it otherwise
+    pub(crate) fn release_pane_id(&self, pane_id: PaneId) {
+        self.reserved_pane_ids.borrow_mut().remove(&pane_id);
+    }
+
     fn remove_pane_internal(&self, pane_id: PaneId) {
         log::debug!("removing pane {}", pane_id);
+        if let Some(state) = self.pane_readers.borrow_mut().remove(&pane_id) {
+            state.dead.store(true, Ordering::Relaxed);
+            // Let the parser run to completion
+            state.pause.set_paused(false);
+        }
+        let lock = self.input_locks.borrow_mut().remove(&pane_id);
+        if lock.map(|lock| lock.is_locked()).unwrap_or(false) {
+            self.update_input_lock_filter();
+        }
+        self.pane_links.borrow_mut().remove_pane(pane_id);
+        self.bookmarks.borrow_mut().remove_pane(pane_id);
+        self.markers.borrow_mut().remove_pane(pane_id);
+        self.output_watchers.borrow_mut().remove_pane(pane_id);
+        self.history.borrow_mut().remove_pane(pane_id);
+        self.view_states.borrow_mut().remove_pane(pane_id);
+        self.forget_pane_in_pending_closes(pane_id);
         if let Some(pane) = self.panes.borrow_mut().remove(&pane_id).clone() {
+            let mut panes_by_domain = self.panes_by_domain.borrow_mut();
+            if let Some(panes) = panes_by_domain.get_mut(&pane.domain_id()) {
+                panes.remove(&pane_id);
+                if panes.is_empty() {
+                    panes_by_domain.remove(&pane.domain_id());
+                }
+            }
+            drop(panes_by_domain);
+            self.domain_index.borrow_mut().pane_changed(pane_id);
             log::debug!("killing pane {}", pane_id);
             pane.kill();
             self.recompute_pane_count();
@@ -625,6 +2152,12 @@ impl Mux {
         log::debug!("remove_tab_internal tab {}", tab_id);
 
         let tab = self.tabs.borrow_mut().remove(&tab_id)?;
+        self.domain_index.borrow_mut().remove_tab(tab_id);
+
+        let shares = self.shares.borrow_mut().remove_tab(tab_id);
+        for share in shares {
+            self.notify(MuxNotification::ShareEnded(share.id));
+        }
 
         if let Ok(mut windows) = self.windows.try_borrow_mut() {
             for w in windows.values_mut() {
@@ -644,11 +2177,13 @@ impl Mux {
         Some(tab)
     }
 
-    fn remove_window_internal(&self, window_id: WindowId) {
+    fn remove_window_internal(&self, window_id: WindowId, decisions: &CloseDecisions) {
         log::debug!("remove_window_internal {}", window_id);
         let domains: Vec<Arc<dyn Domain>> = self.domains.borrow().values().cloned().collect();
         for dom in domains {
-            dom.local_window_is_closing(window_id);
+            let action =
+                CloseAction::resolve(dom.window_close_policy(), dom.domain_id(), decisions);
+            dom.local_window_is_closing(window_id, action);
         }
         let window = self.windows.borrow_mut().remove(&window_id);
         if let Some(window) = window {
@@ -665,6 +2200,38 @@ impl Mux {
         self.prune_dead_windows();
     }
 
+    /// Called when processing the output for a pane panicked.
+    /// The pane is marked as dead and a banner explaining what
+    /// happened is rendered into it, leaving the other panes unaffected.
+    fn pane_panicked(&self, pane_id: PaneId, message: String) {
+        log::error!(
+            "internal error while processing output for pane {}: {}",
+            pane_id,
+            message
+        );
+        if let Some(pane) = self.get_pane(pane_id) {
+            pane.mark_internal_error();
+
+            let banner = format!(
+                "\r\n\x1b[0m⚠️  wezterm encountered an internal error while \
+                 processing the output for this pane, which can no longer \
+                 be used:\r\n{}\r\nSee the log for more details.\r\n",
+                message
+            );
+            let mut parser = termwiz::escape::parser::Parser::new();
+            let mut actions = vec![];
+            parser.parse(banner.as_bytes(), |action| actions.push(action));
+            if catch_pane_panic(|| pane.perform_actions(actions)).is_err() {
+                log::error!(
+                    "unable to render the internal error banner in pane {}",
+                    pane_id
+                );
+            }
+            self.notify(MuxNotification::PaneOutput(pane_id));
+        }
+        self.notify(MuxNotification::PaneInternalError { pane_id, message });
+    }
+
     pub fn remove_tab(&self, tab_id: TabId) -> Option<Rc<Tab>> {
         let tab = self.remove_tab_internal(tab_id);
         self.prune_dead_windows();
@@ -712,7 +2279,8 @@ impl Mux {
 
         for window_id in dead_windows {
             log::trace!("window {} is dead", window_id);
-            self.remove_window_internal(window_id);
+            // A dead window has no panes, so there is nothing to decide
+            self.remove_window_internal(window_id, &CloseDecisions::new());
         }
 
         if self.is_empty() {
@@ -723,9 +2291,117 @@ impl Mux {
         }
     }
 
+    /// Closes a window that holds no panes of a domain whose close
+    /// policy is to ask; see `close_window`
     pub fn kill_window(&self, window_id: WindowId) {
-        self.remove_window_internal(window_id);
+        if let Err(err) = self.close_window(window_id, &CloseDecisions::new()) {
+            log::error!("{:#}", err);
+        }
+    }
+
+    /// Closes a window, applying the `window_close_policy` of each domain
+    /// to its panes in the window.  `decisions` holds the answers for the
+    /// domains whose policy is to ask; if any of those that have panes
+    /// in the window is missing, the window is left open and an error
+    /// is returned.
+    pub fn close_window(
+        &self,
+        window_id: WindowId,
+        decisions: &CloseDecisions,
+    ) -> anyhow::Result<()> {
+        let panes: Vec<Rc<dyn Pane>> = match self.get_window(window_id) {
+            Some(window) => window
+                .iter()
+                .flat_map(|tab| tab.iter_panes_ignoring_zoom())
+                .map(|pos| pos.pane)
+                .collect(),
+            None => vec![],
+        };
+        self.check_close_decisions(&format!("window {}", window_id), &panes, false, decisions)?;
+        self.remove_window_internal(window_id, decisions);
         self.prune_dead_windows();
+        Ok(())
+    }
+
+    /// Closes a tab, applying the `tab_close_policy` of each domain
+    /// to its panes in the tab.  `decisions` holds the answers for the
+    /// domains whose policy is to ask; if any of those that have panes
+    /// in the tab is missing, the tab is left open and an error is
+    /// returned.
+    pub fn close_tab(&self, tab_id: TabId, decisions: &CloseDecisions) -> anyhow::Result<Rc<Tab>> {
+        let tab = self
+            .get_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab {} not found", tab_id))?;
+        let panes: Vec<Rc<dyn Pane>> = tab
+            .iter_panes_ignoring_zoom()
+            .into_iter()
+            .map(|pos| pos.pane)
+            .collect();
+        self.check_close_decisions(&format!("tab {}", tab_id), &panes, true, decisions)?;
+        let domains: Vec<Arc<dyn Domain>> = self.domains.borrow().values().cloned().collect();
+        for dom in domains {
+            let action = CloseAction::resolve(dom.tab_close_policy(), dom.domain_id(), decisions);
+            dom.tab_is_closing(tab_id, action);
+        }
+        self.remove_tab(tab_id);
+        Ok(tab)
+    }
+
+    /// Fails if a domain among those of `panes` asks what to do with
+    /// its panes on close, and has no answer in `decisions`
+    fn check_close_decisions(
+        &self,
+        what: &str,
+        panes: &[Rc<dyn Pane>],
+        for_tab: bool,
+        decisions: &CloseDecisions,
+    ) -> anyhow::Result<()> {
+        let unanswered: Vec<String> = self
+            .domains_asking_on_close(panes, for_tab)
+            .into_iter()
+            .filter(|(domain, _)| !decisions.contains_key(&domain.domain_id()))
+            .map(|(domain, _)| domain.domain_name().to_string())
+            .collect();
+        anyhow::ensure!(
+            unanswered.is_empty(),
+            "not closing {}: the domains {} ask whether to kill or detach their panes",
+            what,
+            unanswered.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Returns the domains whose close policy is to ask, along with
+    /// their panes among `panes`.  `for_tab` selects the tab policy
+    /// rather than the window policy.
+    pub fn domains_asking_on_close(
+        &self,
+        panes: &[Rc<dyn Pane>],
+        for_tab: bool,
+    ) -> Vec<(Arc<dyn Domain>, Vec<Rc<dyn Pane>>)> {
+        let mut result: Vec<(Arc<dyn Domain>, Vec<Rc<dyn Pane>>)> = vec![];
+        for pane in panes {
+            let domain = match self.get_domain(pane.domain_id()) {
+                Some(domain) => domain,
+                None => continue,
+            };
+            let policy = if for_tab {
+                domain.tab_close_policy()
+            } else {
+                domain.window_close_policy()
+            };
+            if policy != ClosePolicy::Ask {
+                continue;
+            }
+            match result
+                .iter_mut()
+                .find(|(dom, _)| dom.domain_id() == domain.domain_id())
+            {
+                Some((_, domain_panes)) => domain_panes.push(Rc::clone(pane)),
+                None => result.push((domain, vec![Rc::clone(pane)])),
+            }
+        }
+        result
     }
 
     pub fn get_window(&self, window_id: WindowId) -> Option<Ref<Window>> {
@@ -773,6 +2449,69 @@ impl Mux {
         Ok(())
     }
 
+    /// Adds a tab that was spawned by `Domain::spawn_detached_tab` to
+    /// a window, at position `index` in its tab bar, or after its other
+    /// tabs if `index` is None.  The active tab of the window is not
+    /// changed.
+    pub fn attach_tab_to_window(
+        &self,
+        tab: &Rc<Tab>,
+        window_id: WindowId,
+        index: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let tab_id = tab.tab_id();
+        if self.get_tab(tab_id).is_none() {
+            anyhow::bail!("attach_tab_to_window: tab {} is not in the mux", tab_id);
+        }
+        if let Some(existing) = self.window_containing_tab(tab_id) {
+            anyhow::bail!(
+                "attach_tab_to_window: tab {} is already in window {}",
+                tab_id,
+                existing
+            );
+        }
+        for pos in tab.iter_panes_ignoring_zoom() {
+            self.authorize_move(
+                pos.pane.pane_id(),
+                MoveDestination::Window { tab_id, window_id },
+            )?;
+        }
+        {
+            let mut window = self
+                .get_window_mut(window_id)
+                .ok_or_else(|| anyhow!("attach_tab_to_window: no such window_id {}", window_id))?;
+            match index {
+                Some(index) => {
+                    let index = index.min(window.len());
+                    window.insert(index, tab);
+                }
+                None => window.push(tab),
+            }
+        }
+        self.recompute_pane_count();
+        Ok(())
+    }
+
+    /// Returns the tabs that are not in any window, such as those that
+    /// were spawned by `Domain::spawn_detached_tab` and not yet attached
+    pub fn iter_detached_tabs(&self) -> Vec<Rc<Tab>> {
+        let attached: HashSet<TabId> = self
+            .windows
+            .borrow()
+            .values()
+            .flat_map(|w| w.iter().map(|tab| tab.tab_id()))
+            .collect();
+        let mut tabs: Vec<Rc<Tab>> = self
+            .tabs
+            .borrow()
+            .values()
+            .filter(|tab| !attached.contains(&tab.tab_id()))
+            .cloned()
+            .collect();
+        tabs.sort_by_key(|tab| tab.tab_id());
+        tabs
+    }
+
     pub fn window_containing_tab(&self, tab_id: TabId) -> Option<WindowId> {
         for w in self.windows.borrow().values() {
             for t in w.iter() {
@@ -810,6 +2549,49 @@ impl Mux {
             .collect()
     }
 
+    /// Returns the panes that belong to the domain, ordered by pane id
+    pub fn iter_panes_for_domain(&self, domain_id: DomainId) -> Vec<Rc<dyn Pane>> {
+        let panes = self.panes.borrow();
+        match self.panes_by_domain.borrow().get(&domain_id) {
+            Some(pane_ids) => pane_ids
+                .iter()
+                .filter_map(|pane_id| panes.get(pane_id).map(Rc::clone))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Calls `f` for each of the panes of the domain.
+    /// `f` may add or remove panes; those that it adds are not visited.
+    pub fn for_each_pane_in_domain<F: FnMut(&Rc<dyn Pane>)>(&self, domain_id: DomainId, mut f: F) {
+        for pane in self.iter_panes_for_domain(domain_id) {
+            f(&pane);
+        }
+    }
+
+    /// Removes all of the panes of the domain from their tabs and from
+    /// the mux, then closes the tabs and windows that are left empty
+    pub fn kill_panes_in_domain(&self, domain_id: DomainId) {
+        let pane_ids: Vec<PaneId> = self
+            .iter_panes_for_domain(domain_id)
+            .iter()
+            .map(|pane| pane.pane_id())
+            .collect();
+
+        // Tabs that are not in a window may hold panes too
+        let tabs: Vec<Rc<Tab>> = self.tabs.borrow().values().cloned().collect();
+        for tab in tabs {
+            tab.kill_panes_in_domain(domain_id);
+        }
+
+        log::info!("killing panes of domain {}: {:?}", domain_id, pane_ids);
+        for pane_id in pane_ids {
+            self.remove_pane_internal(pane_id);
+        }
+
+        self.prune_dead_windows();
+    }
+
     pub fn iter_windows_in_workspace(&self, workspace: &str) -> Vec<WindowId> {
         let mut windows: Vec<WindowId> = self
             .windows
@@ -836,44 +2618,40 @@ impl Mux {
         self.domains.borrow().values().cloned().collect()
     }
 
+    /// Returns the registered domains with those named by the
+    /// `domain_order` config option first, in that order, followed by
+    /// the rest in order of domain id
+    pub fn ordered_domains(&self) -> Vec<Arc<dyn Domain>> {
+        let config = configuration();
+        let mut domains = self.iter_domains();
+        domains.sort_by_key(|dom| {
+            (
+                config
+                    .domain_order_rank(dom.domain_name())
+                    .unwrap_or(usize::MAX),
+                dom.domain_id(),
+            )
+        });
+        domains
+    }
+
     pub fn resolve_pane_id(&self, pane_id: PaneId) -> Option<(DomainId, WindowId, TabId)> {
-        let mut ids = None;
-        for tab in self.tabs.borrow().values() {
-            for p in tab.iter_panes() {
-                if p.pane.pane_id() == pane_id {
-                    ids = Some((tab.tab_id(), p.pane.domain_id()));
-                    break;
-                }
-            }
-        }
-        let (tab_id, domain_id) = ids?;
+        let (domain_id, tab_id) = self.resolve_pane_tab(pane_id)?;
         let window_id = self.window_containing_tab(tab_id)?;
         Some((domain_id, window_id, tab_id))
     }
 
-    pub fn domain_was_detached(&self, domain: DomainId) {
-        let mut dead_panes = vec![];
-        for pane in self.panes.borrow().values() {
-            if pane.domain_id() == domain {
-                dead_panes.push(pane.pane_id());
-            }
-        }
-
-        {
-            let mut windows = self.windows.borrow_mut();
-            for (_, win) in windows.iter_mut() {
-                for tab in win.iter() {
-                    tab.kill_panes_in_domain(domain);
+    /// Like `resolve_pane_id`, but also resolves panes whose tab is
+    /// not in any window, such as those of a detached tab
+    pub fn resolve_pane_tab(&self, pane_id: PaneId) -> Option<(DomainId, TabId)> {
+        for tab in self.tabs.borrow().values() {
+            for p in tab.iter_panes() {
+                if p.pane.pane_id() == pane_id {
+                    return Some((p.pane.domain_id(), tab.tab_id()));
                 }
             }
         }
-
-        log::info!("domain detached panes: {:?}", dead_panes);
-        for pane_id in dead_panes {
-            self.remove_pane_internal(pane_id);
-        }
-
-        self.prune_dead_windows();
+        None
     }
 
     pub fn set_banner(&self, banner: Option<String>) {
@@ -897,45 +2675,134 @@ impl Mux {
                 self.get_domain(pane_domain_id)
                     .expect("resolve_pane_id to give valid domain_id")
             }
-            SpawnTabDomain::DomainId(domain_id) => self
-                .get_domain(*domain_id)
-                .ok_or_else(|| anyhow!("domain id {} is invalid", domain_id))?,
-            SpawnTabDomain::DomainName(name) => self
-                .get_domain_by_name(&name)
-                .ok_or_else(|| anyhow!("domain name {} is invalid", name))?,
+            SpawnTabDomain::DomainId(domain_id) => {
+                self.get_domain(*domain_id)
+                    .ok_or_else(|| DomainError::DomainNotFound {
+                        domain: format!("id {}", domain_id),
+                    })?
+            }
+            SpawnTabDomain::DomainName(name) => {
+                self.get_domain_by_name(&name)
+                    .ok_or_else(|| DomainError::DomainNotFound {
+                        domain: name.to_string(),
+                    })?
+            }
         };
         Ok(domain)
     }
 
+    /// Returns the cwd for `command` when it is spawned into `domain`:
+    /// `command_dir`, or else the cwd of `pane`, as mapped by
+    /// `Domain::map_cwd`, or None if `command` sets its own cwd; see
+    /// `domain::resolve_command_dir`.
+    /// The host reported by the pane is retained so that the domain
+    /// can tell whether the path is meaningful to it.
+    /// If the domain dropped the directory, it is returned along with
+    /// the reason, for `notify_cwd_dropped`.
     fn resolve_cwd(
         &self,
-        command_dir: Option<String>,
-        pane: Option<Rc<dyn Pane>>,
-    ) -> Option<String> {
-        command_dir.or_else(|| {
-            match pane {
-                Some(pane) => pane
+        domain: &Arc<dyn Domain>,
+        command: Option<&CommandBuilder>,
+        command_dir: Option<CommandDir>,
+        pane: Option<&Rc<dyn Pane>>,
+    ) -> (Option<CommandDir>, Option<(CommandDir, String)>) {
+        if command_has_cwd(command) {
+            return (None, None);
+        }
+        let (dir, from_domain) = match command_dir {
+            Some(dir) => (dir, None),
+            None => {
+                let pane = match pane {
+                    Some(pane) => pane,
+                    None => return (None, None),
+                };
+                match pane
                     .get_current_working_dir()
-                    .and_then(|url| {
-                        percent_decode_str(url.path())
-                            .decode_utf8()
-                            .ok()
-                            .map(|path| path.into_owned())
-                    })
-                    .map(|path| {
-                        // On Windows the file URI can produce a path like:
-                        // `/C:\Users` which is valid in a file URI, but the leading slash
-                        // is not liked by the windows file APIs, so we strip it off here.
-                        let bytes = path.as_bytes();
-                        if bytes.len() > 2 && bytes[0] == b'/' && bytes[2] == b':' {
-                            path[1..].to_owned()
-                        } else {
-                            path
-                        }
-                    }),
-                None => None,
+                    .and_then(|url| CommandDir::from_url(&url))
+                {
+                    Some(dir) => (dir, self.get_domain(pane.domain_id())),
+                    None => return (None, None),
+                }
             }
-        })
+        };
+        match domain.map_cwd(&dir, from_domain.as_ref()) {
+            CwdMapping::Use(dir) => (Some(dir), None),
+            CwdMapping::Dropped(reason) => (None, Some((dir, reason))),
+        }
+    }
+
+    /// Tells the user that `pane` was not started in `cwd`, for
+    /// `reason`, by printing a notice in it unless the domain is
+    /// listed in `suppress_dropped_cwd_notice`, and by emitting
+    /// `MuxNotification::CwdDropped`
+    fn notify_cwd_dropped(
+        &self,
+        domain: &Arc<dyn Domain>,
+        pane: &Rc<dyn Pane>,
+        cwd: CommandDir,
+        reason: String,
+    ) {
+        let pane_id = pane.pane_id();
+        log::info!(
+            "pane {} in domain `{}` was not started in {}: {}",
+            pane_id,
+            domain.domain_name(),
+            cwd,
+            reason
+        );
+        if !configuration()
+            .suppress_dropped_cwd_notice
+            .iter()
+            .any(|name| name == domain.domain_name())
+        {
+            let notice = format!(
+                "\x1b[0m\x1b[2mwezterm: not starting in {}, as {}\x1b[0m\r\n",
+                cwd, reason
+            );
+            let mut parser = termwiz::escape::parser::Parser::new();
+            let mut actions = vec![];
+            parser.parse(notice.as_bytes(), |action| actions.push(action));
+            pane.perform_actions(actions);
+            self.notify(MuxNotification::PaneOutput(pane_id));
+        }
+        self.notify(MuxNotification::CwdDropped {
+            pane_id,
+            cwd,
+            reason,
+        });
+    }
+
+    /// Runs the command of a pane that is being held open after its
+    /// process exited again, in a new pane that takes the place of the
+    /// held pane in its tab.  The held pane is closed.
+    pub async fn respawn_pane(&self, pane_id: PaneId) -> anyhow::Result<Rc<dyn Pane>> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        let (command, command_dir) = pane
+            .downcast_ref::<LocalPane>()
+            .and_then(LocalPane::get_spawn_command)
+            .ok_or_else(|| anyhow!("pane {} was not spawned by a domain", pane_id))?;
+
+        let (new_pane, _size) = self
+            .split_pane(
+                pane_id,
+                SplitRequest::default(),
+                SplitSource::Spawn {
+                    command,
+                    command_dir,
+                },
+                SpawnTabDomain::CurrentPaneDomain,
+            )
+            .await?;
+        if let Some(tab) = self
+            .resolve_pane_id(new_pane.pane_id())
+            .and_then(|(_domain_id, _window_id, tab_id)| self.get_tab(tab_id))
+        {
+            tab.set_active_pane(&new_pane);
+        }
+        self.remove_pane(pane_id);
+        Ok(new_pane)
     }
 
     pub async fn split_pane(
@@ -946,54 +2813,179 @@ impl Mux {
         source: SplitSource,
         domain: config::keyassignment::SpawnTabDomain,
     ) -> anyhow::Result<(Rc<dyn Pane>, TerminalSize)> {
-        let (_pane_domain_id, window_id, tab_id) = self
+        // Moving a pane into a split doesn't spawn anything to time
+        let timer = match &source {
+            SplitSource::Spawn { .. } => Some((
+                SpawnTimer::start("split"),
+                self.spawn_domain_name(Some(pane_id), &domain),
+            )),
+            SplitSource::MovePane(_) => None,
+        };
+        let result = self.split_pane_impl(pane_id, request, source, domain).await;
+        if let Some((timer, domain_name)) = timer {
+            self.record_spawn_timing(timer.finish(
+                &domain_name,
+                match &result {
+                    Ok((pane, _size)) => Ok(pane.pane_id()),
+                    Err(err) => Err(format!("{:#}", err)),
+                },
+            ));
+        }
+        result
+    }
+
+    /// Returns where the pane and the new pane would be if the pane
+    /// was split as `request` describes, without splitting it.
+    /// The domain that hosts the pane lays out the preview, so that it
+    /// matches the split that the domain would make.
+    pub async fn preview_split(
+        &self,
+        pane_id: PaneId,
+        request: SplitRequest,
+    ) -> anyhow::Result<Option<SplitPreview>> {
+        let (pane_domain_id, _window_id, tab_id) = self
             .resolve_pane_id(pane_id)
             .ok_or_else(|| anyhow!("pane_id {} invalid", pane_id))?;
-
         let domain = self
-            .resolve_spawn_tab_domain(Some(pane_id), &domain)
-            .context("resolve_spawn_tab_domain")?;
+            .get_domain(pane_domain_id)
+            .ok_or_else(|| anyhow!("domain {} of pane {} not found", pane_domain_id, pane_id))?;
+        domain.preview_split(tab_id, pane_id, request).await
+    }
 
-        if domain.state() == DomainState::Detached {
-            domain.attach(Some(window_id)).await?;
+    async fn split_pane_impl(
+        &self,
+        pane_id: PaneId,
+        request: SplitRequest,
+        source: SplitSource,
+        domain: config::keyassignment::SpawnTabDomain,
+    ) -> anyhow::Result<(Rc<dyn Pane>, TerminalSize)> {
+        let skip_domain_rules = self.take_skip_domain_rules();
+        let (_pane_domain_id, window_id, tab_id) = self
+            .resolve_pane_id(pane_id)
+            .ok_or_else(|| anyhow!("pane_id {} invalid", pane_id))?;
+
+        if let SplitSource::MovePane(src_pane_id) = &source {
+            anyhow::ensure!(
+                *src_pane_id != pane_id,
+                "pane {} cannot be moved into a split of itself",
+                pane_id
+            );
         }
 
         let current_pane = self
             .get_pane(pane_id)
             .ok_or_else(|| anyhow!("pane_id {} is invalid", pane_id))?;
+
+        let redirect = match &source {
+            SplitSource::Spawn {
+                command,
+                command_dir,
+            } if !skip_domain_rules => self.domain_rule_redirect(
+                &domain,
+                command.as_ref(),
+                command_dir.as_ref(),
+                Some(&current_pane),
+            )?,
+            _ => None,
+        };
+        let domain = match &redirect {
+            Some(redirect) => Arc::clone(&redirect.domain),
+            None => self
+                .resolve_spawn_tab_domain(Some(pane_id), &domain)
+                .context("resolve_spawn_tab_domain")?,
+        };
+
+        if domain.state() == DomainState::Detached {
+            let _span = spawntiming::span("attach_domain");
+            self.attach_domain(&domain, Some(window_id)).await?;
+        }
+
         let term_config = current_pane.get_config();
 
+        let mut dropped_cwd = None;
         let source = match source {
             SplitSource::Spawn {
                 command,
                 command_dir,
-            } => SplitSource::Spawn {
-                command,
-                command_dir: self.resolve_cwd(command_dir, Some(Rc::clone(&current_pane))),
-            },
+            } => {
+                // The domain decides whether the cwd of the current pane
+                // is meaningful to it; eg: a host path is meaningless
+                // inside a docker container
+                let (command_dir, dropped) =
+                    self.resolve_cwd(&domain, command.as_ref(), command_dir, Some(&current_pane));
+                dropped_cwd = dropped;
+                SplitSource::Spawn {
+                    command,
+                    command_dir,
+                }
+            }
             other => other,
         };
 
-        let pane = domain.split_pane(source, tab_id, pane_id, request).await?;
+        // A moved pane keeps its id
+        let new_pane_id = match &source {
+            SplitSource::Spawn { .. } => pane::alloc_pane_id(),
+            SplitSource::MovePane(src_pane_id) => *src_pane_id,
+        };
+        let permit = match &source {
+            SplitSource::Spawn { .. } => Some(self.wait_for_spawn_slot(&domain).await),
+            SplitSource::MovePane(_) => None,
+        };
+        let span = spawntiming::span("Domain::split_pane");
+        let pane = domain
+            .split_pane(source, tab_id, pane_id, request, new_pane_id)
+            .await?;
+        drop(span);
+        drop(permit);
         if let Some(config) = term_config {
             pane.set_config(config);
         }
+        if let Some(redirect) = redirect {
+            self.notify_spawn_redirected(&pane, redirect);
+        }
+        if let Some((cwd, reason)) = dropped_cwd {
+            self.notify_cwd_dropped(&domain, &pane, cwd, reason);
+        }
 
         // FIXME: clipboard
 
-        let dims = pane.get_dimensions();
-
-        let size = TerminalSize {
-            cols: dims.cols,
-            rows: dims.viewport_rows,
-            pixel_height: 0, // FIXME: split pane pixel dimensions
-            pixel_width: 0,
-            dpi: dims.dpi,
-        };
+        let size = pane.get_size();
 
         Ok((pane, size))
     }
 
+    /// Asks the domain of the pane `pane_id`, and that of the pane that
+    /// it is moved next to if that is another domain, whether the pane
+    /// may be moved to `destination`; see `Domain::authorize_move`.
+    /// This must be called before anything is changed by the move.
+    pub fn authorize_move(
+        &self,
+        pane_id: PaneId,
+        destination: MoveDestination,
+    ) -> Result<(), DomainError> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        let mut domain_ids = vec![pane.domain_id()];
+        if let MoveDestination::Split { domain_id, .. } = destination {
+            if domain_id != pane.domain_id() {
+                domain_ids.push(domain_id);
+            }
+        }
+        for domain_id in domain_ids {
+            if let Some(domain) = self.get_domain(domain_id) {
+                domain
+                    .authorize_move(pane_id, destination)
+                    .map_err(|denied| DomainError::MoveDenied {
+                        domain: domain.domain_name().to_string(),
+                        pane_id,
+                        reason: denied.reason,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn move_pane_to_new_tab(
         &self,
         pane_id: PaneId,
@@ -1007,6 +2999,7 @@ impl Mux {
             Some(t) => t,
             None => anyhow::bail!("Invalid tab id {}", src_tab),
         };
+        self.authorize_move(pane_id, MoveDestination::NewTab { window_id })?;
 
         let window_builder;
         let (window_id, size) = if let Some(window_id) = window_id {
@@ -1046,14 +3039,68 @@ impl Mux {
         window_id: Option<WindowId>,
         domain: SpawnTabDomain,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
+        command_dir: Option<CommandDir>,
         size: TerminalSize,
         current_pane_id: Option<PaneId>,
         workspace_for_new_window: String,
     ) -> anyhow::Result<(Rc<Tab>, Rc<dyn Pane>, WindowId)> {
-        let domain = self
-            .resolve_spawn_tab_domain(current_pane_id, &domain)
-            .context("resolve_spawn_tab_domain")?;
+        let timer = SpawnTimer::start("spawn");
+        let domain_name = self.spawn_domain_name(current_pane_id, &domain);
+        let result = self
+            .spawn_tab_or_window_impl(
+                window_id,
+                domain,
+                command,
+                command_dir,
+                size,
+                current_pane_id,
+                workspace_for_new_window,
+            )
+            .await;
+        self.record_spawn_timing(timer.finish(
+            &domain_name,
+            match &result {
+                Ok((_tab, pane, _window_id)) => Ok(pane.pane_id()),
+                Err(err) => Err(format!("{:#}", err)),
+            },
+        ));
+        result
+    }
+
+    async fn spawn_tab_or_window_impl(
+        &self,
+        window_id: Option<WindowId>,
+        domain: SpawnTabDomain,
+        command: Option<CommandBuilder>,
+        command_dir: Option<CommandDir>,
+        size: TerminalSize,
+        current_pane_id: Option<PaneId>,
+        workspace_for_new_window: String,
+    ) -> anyhow::Result<(Rc<Tab>, Rc<dyn Pane>, WindowId)> {
+        let skip_domain_rules = self.take_skip_domain_rules();
+        let current_pane = match current_pane_id {
+            Some(id) => Some(
+                self.get_pane(id)
+                    .ok_or_else(|| anyhow!("pane_id {} invalid", id))?,
+            ),
+            None => None,
+        };
+        let redirect = if skip_domain_rules {
+            None
+        } else {
+            self.domain_rule_redirect(
+                &domain,
+                command.as_ref(),
+                command_dir.as_ref(),
+                current_pane.as_ref(),
+            )?
+        };
+        let domain = match &redirect {
+            Some(redirect) => Arc::clone(&redirect.domain),
+            None => self
+                .resolve_spawn_tab_domain(current_pane_id, &domain)
+                .context("resolve_spawn_tab_domain")?,
+        };
 
         let window_builder;
         let term_config;
@@ -1080,28 +3127,19 @@ impl Mux {
         };
 
         if domain.state() == DomainState::Detached {
-            domain.attach(Some(window_id)).await?;
+            let _span = spawntiming::span("attach_domain");
+            self.attach_domain(&domain, Some(window_id)).await?;
         }
 
-        let cwd = self.resolve_cwd(
+        let (cwd, dropped_cwd) = self.resolve_cwd(
+            &domain,
+            command.as_ref(),
             command_dir,
-            match current_pane_id {
-                Some(id) => {
-                    // Only use the cwd from the current pane if the domain
-                    // is the same as the one we are spawning into
-                    let (current_domain_id, _, _) = self
-                        .resolve_pane_id(id)
-                        .ok_or_else(|| anyhow!("pane_id {} invalid", id))?;
-                    if current_domain_id == domain.domain_id() {
-                        self.get_pane(id)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            },
+            current_pane.as_ref(),
         );
 
+        let permit = self.wait_for_spawn_slot(&domain).await;
+        let span = spawntiming::span("Domain::spawn");
         let tab = domain
             .spawn(size, command.clone(), cwd.clone(), window_id)
             .await
@@ -1111,6 +3149,8 @@ impl Mux {
                     domain.domain_name()
                 )
             })?;
+        drop(span);
+        drop(permit);
 
         let pane = tab
             .get_active_pane()
@@ -1119,6 +3159,12 @@ impl Mux {
         if let Some(config) = term_config {
             pane.set_config(config);
         }
+        if let Some(redirect) = redirect {
+            self.notify_spawn_redirected(&pane, redirect);
+        }
+        if let Some((cwd, reason)) = dropped_cwd {
+            self.notify_cwd_dropped(&domain, &pane, cwd, reason);
+        }
 
         // FIXME: clipboard?
 
@@ -1156,12 +3202,30 @@ pub enum SessionTerminated {
     WindowClosed,
 }
 
+/// Converts the size of a pane to the winsize of its pty.
+/// The rows and columns must fit, but the pixel dimensions are only
+/// a hint: if either is too large for the winsize, both are reported
+/// as 0, which applications take to mean that they are unknown, rather
+/// than reporting a pixel size that doesn't match the cells.
 pub(crate) fn terminal_size_to_pty_size(size: TerminalSize) -> anyhow::Result<PtySize> {
+    let rows = size
+        .rows
+        .try_into()
+        .with_context(|| format!("{} rows is too many for a pty", size.rows))?;
+    let cols = size
+        .cols
+        .try_into()
+        .with_context(|| format!("{} columns is too many for a pty", size.cols))?;
+    let (pixel_width, pixel_height) =
+        match (size.pixel_width.try_into(), size.pixel_height.try_into()) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => (0, 0),
+        };
     Ok(PtySize {
-        rows: size.rows.try_into()?,
-        cols: size.cols.try_into()?,
-        pixel_height: size.pixel_height.try_into()?,
-        pixel_width: size.pixel_width.try_into()?,
+        rows,
+        cols,
+        pixel_width,
+        pixel_height,
     })
 }
 
@@ -1,21 +1,22 @@
 use crate::domain::DomainId;
 use crate::pane::{CloseReason, Pane, PaneId, Pattern, SearchResult};
 use crate::renderable::*;
+use crate::resources::{PaneResourceUsage, ResourceSample};
 use crate::tmux::{TmuxDomain, TmuxDomainState};
 use crate::{Domain, Mux, MuxNotification};
 use anyhow::Error;
 use async_trait::async_trait;
-use config::keyassignment::ScrollbackEraseMode;
-use config::{configuration, ExitBehavior};
-use portable_pty::{Child, ChildKiller, ExitStatus, MasterPty, PtySize};
-use procinfo::LocalProcessInfo;
+use config::keyassignment::{CommandDir, ScrollbackEraseMode};
+use config::{configuration, ExitBehavior, Palette};
+use portable_pty::{Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty};
+use procinfo::{LocalProcessInfo, ProcessResources};
 use rangeset::RangeSet;
 use smol::channel::{bounded, Receiver, TryRecvError};
 use std::cell::{RefCell, RefMut};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::io::Result as IoResult;
 use std::ops::Range;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use termwiz::escape::DeviceControlMode;
@@ -25,13 +26,105 @@ use url::Url;
 use wezterm_term::color::ColorPalette;
 use wezterm_term::{
     Alert, AlertHandler, CellAttributes, Clipboard, DownloadHandler, KeyCode, KeyModifiers,
-    MouseEvent, SemanticZone, StableRowIndex, Terminal, TerminalConfiguration, TerminalSize,
+    MouseEvent, SemanticZone, StableRowIndex, Terminal, TerminalConfiguration, TerminalModes,
+    TerminalSize,
 };
 
+/// How many lines of trailing output to capture when explaining
+/// a process that exited shortly after it was spawned
+const EARLY_EXIT_OUTPUT_LINES: usize = 10;
+
+/// How long after a process exited early to wait for its final output
+/// to be applied before reporting it anyway; a descendant that holds
+/// the pty open would otherwise delay the report indefinitely
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The outcome of reaping a child process
+#[derive(Debug)]
+struct ChildExit {
+    status: ExitStatus,
+    /// How long the process ran for before it terminated
+    elapsed: Duration,
+}
+
+impl ChildExit {
+    /// Returns true if the process terminated unsuccessfully within
+    /// `threshold` of being spawned.  A zero threshold disables this check.
+    fn is_early_failure(&self, success: bool, threshold: Duration) -> bool {
+        !success && !threshold.is_zero() && self.elapsed < threshold
+    }
+
+    /// Returns true if the report of this exit should wait for the
+    /// parser to apply the final output of the process, which it does
+    /// for no longer than `OUTPUT_DRAIN_TIMEOUT` after the exit
+    fn awaiting_output(&self, spawned: Instant, output_parsed: bool) -> bool {
+        !output_parsed && spawned.elapsed() < self.elapsed + OUTPUT_DRAIN_TIMEOUT
+    }
+
+    /// Describes the exit for the banner of a pane that is being held
+    /// open.  `reason` explains why the pane is held, and the keys that
+    /// are handled by a held pane are listed at the end.
+    fn hold_banner(
+        &self,
+        description: &str,
+        success: bool,
+        reason: &str,
+        can_respawn: bool,
+    ) -> String {
+        let mut banner = if success {
+            format!(
+                "\r\n👍 Process {} completed after {}.\r\n",
+                description,
+                format_runtime(self.elapsed)
+            )
+        } else {
+            format!(
+                "\r\n⚠️  Process {} didn't exit cleanly after {}\r\n{}.\r\n",
+                description,
+                format_runtime(self.elapsed),
+                self.status
+            )
+        };
+        banner.push_str(reason);
+        banner.push_str("\r\n");
+        banner.push_str(hold_hint(can_respawn));
+        banner
+    }
+}
+
+/// Lists the keys that are handled by a pane that is being held open
+fn hold_hint(can_respawn: bool) -> &'static str {
+    if can_respawn {
+        "Press R to run it again, or Q to close this pane.\r\n"
+    } else {
+        "Press Q to close this pane.\r\n"
+    }
+}
+
+/// Formats how long a process ran for, to the nearest second
+fn format_runtime(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs == 0 {
+        return format!("{}ms", elapsed.as_millis());
+    }
+    let (hours, mins, secs) = (secs / 3600, (secs / 60) % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
 #[derive(Debug)]
 enum ProcessState {
     Running {
-        child_waiter: Receiver<IoResult<ExitStatus>>,
+        child_waiter: Receiver<ChildExit>,
+        /// An early failure whose report is waiting for the final
+        /// output of the process
+        pending_exit: Option<ChildExit>,
+        spawned: Instant,
         pid: Option<u32>,
         signaller: Box<dyn ChildKiller>,
         // Whether we've explicitly killed the child
@@ -58,6 +151,25 @@ pub struct LocalPane {
     tmux_domain: RefCell<Option<Arc<TmuxDomainState>>>,
     proc_list: RefCell<Option<CachedProcInfo>>,
     command_description: String,
+    /// The semantic zones as of the sequence number that they were
+    /// computed for.  Zones are derived from the lines that are still
+    /// present, so rows that have been trimmed from the scrollback
+    /// simply drop out of them.
+    semantic_zones: RefCell<Option<(SequenceNo, Vec<SemanticZone>)>>,
+    cwd_from_process: bool,
+    /// The name of the systemd scope unit that contains the process
+    systemd_scope: Option<String>,
+    /// The label of the command that was spawned into the pane
+    spawn_label: Option<String>,
+    /// The most recent sample of the resources used by the process tree
+    resource_sample: RefCell<Option<ResourceSample>>,
+    /// Colors that replace those of the window palette for this pane
+    palette_overrides: RefCell<Option<Palette>>,
+    /// Overrides the global `exit_behavior` for this pane
+    exit_behavior: Option<ExitBehavior>,
+    /// The command and cwd with which the pane was spawned, so that
+    /// it can be run again after it has exited
+    spawn_command: Option<(Option<CommandBuilder>, Option<CommandDir>)>,
 }
 
 #[async_trait(?Send)]
@@ -115,6 +227,10 @@ impl Pane for LocalPane {
         terminal_get_dimensions(&mut self.terminal.borrow_mut())
     }
 
+    fn get_size(&self) -> TerminalSize {
+        self.terminal.borrow().get_size()
+    }
+
     fn copy_user_vars(&self) -> HashMap<String, String> {
         self.terminal.borrow().user_vars().clone()
     }
@@ -138,6 +254,22 @@ impl Pane for LocalPane {
             }
             _ => {}
         }
+        if let Some(unit) = &self.systemd_scope {
+            crate::systemd::stop_scope(unit);
+        }
+    }
+
+    fn mark_internal_error(&self) {
+        let mut proc = self.process.borrow_mut();
+        if let ProcessState::Running { signaller, .. } = &mut *proc {
+            let _ = signaller.kill();
+        }
+        *proc = ProcessState::DeadPendingClose { killed: false };
+        log::debug!(
+            "pane {} had an internal error, state is {:?}",
+            self.pane_id,
+            proc
+        );
     }
 
     fn is_dead(&self) -> bool {
@@ -152,49 +284,106 @@ impl Pane for LocalPane {
         match &mut *proc {
             ProcessState::Running {
                 child_waiter,
+                pending_exit,
+                spawned,
                 killed,
                 ..
             } => {
-                let status = match child_waiter.try_recv() {
-                    Ok(Ok(s)) => Some(s),
-                    Err(TryRecvError::Empty) => None,
-                    _ => Some(ExitStatus::with_exit_code(1)),
+                let deferred = pending_exit.is_some();
+                let exit = match pending_exit.take() {
+                    Some(exit) => Some(exit),
+                    None => match child_waiter.try_recv() {
+                        Ok(exit) => Some(exit),
+                        Err(TryRecvError::Empty) => None,
+                        Err(TryRecvError::Closed) => Some(ChildExit {
+                            status: ExitStatus::with_exit_code(1),
+                            elapsed: spawned.elapsed(),
+                        }),
+                    },
                 };
 
-                if let Some(status) = status {
+                if let Some(exit) = exit {
+                    let config = configuration();
+                    let status = &exit.status;
                     let success = match status.success() {
                         true => true,
-                        false => configuration()
-                            .clean_exit_codes
-                            .contains(&status.exit_code()),
+                        false => config.clean_exit_codes.contains(&status.exit_code()),
                     };
-
-                    match (configuration().exit_behavior, success, killed) {
-                        (ExitBehavior::Close, _, _) => *proc = ProcessState::Dead,
-                        (ExitBehavior::CloseOnCleanExit, false, false) => {
+                    let early_failure = !*killed
+                        && exit.is_early_failure(
+                            success,
+                            Duration::from_millis(config.early_exit_threshold_ms),
+                        );
+
+                    let can_respawn = self.spawn_command.is_some();
+                    match (self.exit_behavior(), success, killed) {
+                        _ if early_failure
+                            && exit.awaiting_output(*spawned, self.output_parsed()) =>
+                        {
+                            // The output that explains the failure may still be
+                            // on its way through the parser, which checks again
+                            // once it is done; in case it never is, check again
+                            // once the wait is over
+                            if !deferred {
+                                promise::spawn::spawn_into_main_thread(async move {
+                                    smol::Timer::after(OUTPUT_DRAIN_TIMEOUT).await;
+                                    if let Some(mux) = Mux::get() {
+                                        mux.prune_dead_windows();
+                                    }
+                                })
+                                .detach();
+                            }
+                            *pending_exit = Some(exit);
+                        }
+                        _ if early_failure => {
+                            let output = self.recent_output(EARLY_EXIT_OUTPUT_LINES);
+                            log::error!(
+                                "Process {} in pane {} exited after {:?}: {}. \
+                                 Final output was:\n{}",
+                                self.command_description,
+                                self.pane_id,
+                                exit.elapsed,
+                                status,
+                                output
+                            );
                             notify = Some(format!(
-                                "\r\n⚠️  Process {} didn't exit cleanly\r\n{}.\r\n{}=\"CloseOnCleanExit\"\r\n",
+                                "\r\n⚠️  Process {} exited {}ms after it was started\r\n\
+                                 {}.\r\n{}\r\n\
+                                 This pane is being held open because of \
+                                 early_exit_threshold_ms.\r\n{}",
                                 self.command_description,
+                                exit.elapsed.as_millis(),
                                 status,
-                                EXIT_BEHAVIOR
+                                if output.is_empty() {
+                                    "The process produced no output.".to_string()
+                                } else {
+                                    format!(
+                                        "Its final output was:\r\n{}",
+                                        output.replace('\n', "\r\n")
+                                    )
+                                },
+                                hold_hint(can_respawn)
+                            ));
+                            *proc = ProcessState::DeadPendingClose { killed: false }
+                        }
+                        (ExitBehavior::Close, _, _) => *proc = ProcessState::Dead,
+                        (ExitBehavior::CloseOnCleanExit, false, false) => {
+                            notify = Some(exit.hold_banner(
+                                &self.command_description,
+                                false,
+                                &format!("{}=\"CloseOnCleanExit\"", EXIT_BEHAVIOR),
+                                can_respawn,
                             ));
                             *proc = ProcessState::DeadPendingClose { killed: false }
                         }
                         (ExitBehavior::CloseOnCleanExit, ..) => *proc = ProcessState::Dead,
                         (ExitBehavior::Hold, success, false) => {
-                            if success {
-                                notify = Some(format!(
-                                    "\r\n👍 Process {} completed.\r\n{}=\"Hold\"\r\n",
-                                    self.command_description, EXIT_BEHAVIOR
-                                ));
-                            } else {
-                                notify = Some(format!(
-                                    "\r\n⚠️  Process {} didn't exit cleanly\r\n{}.\r\n{}=\"Hold\"\r\n",
-                                    self.command_description,
-                                    status,
-                                    EXIT_BEHAVIOR
-                                ));
-                            }
+                            notify = Some(exit.hold_banner(
+                                &self.command_description,
+                                success,
+                                &format!("{}=\"Hold\"", EXIT_BEHAVIOR),
+                                can_respawn,
+                            ));
                             *proc = ProcessState::DeadPendingClose { killed: false }
                         }
                         (ExitBehavior::Hold, _, true) => *proc = ProcessState::Dead,
@@ -266,6 +455,11 @@ impl Pane for LocalPane {
                 self.terminal.borrow_mut().send_paste("detach\n")?;
             }
             return Ok(());
+        } else if self.is_held() {
+            if (mods - KeyModifiers::SHIFT).is_empty() {
+                self.held_key_down(key);
+            }
+            Ok(())
         } else {
             self.terminal.borrow_mut().key_down(key, mods)
         }
@@ -277,12 +471,9 @@ impl Pane for LocalPane {
     }
 
     fn resize(&self, size: TerminalSize) -> Result<(), Error> {
-        self.pty.borrow_mut().resize(PtySize {
-            rows: size.rows.try_into()?,
-            cols: size.cols.try_into()?,
-            pixel_width: size.pixel_width.try_into()?,
-            pixel_height: size.pixel_height.try_into()?,
-        })?;
+        self.pty
+            .borrow_mut()
+            .resize(crate::terminal_size_to_pty_size(size)?)?;
         self.terminal.borrow_mut().resize(size);
         Ok(())
     }
@@ -296,6 +487,10 @@ impl Pane for LocalPane {
         Ok(Some(self.pty.borrow_mut().try_clone_reader()?))
     }
 
+    fn pending_output(&self) -> Option<usize> {
+        self.pty.borrow().pending_output()
+    }
+
     fn send_paste(&self, text: &str) -> Result<(), Error> {
         Mux::get().unwrap().record_input_for_current_identity();
         if self.tmux_domain.borrow().is_some() {
@@ -308,8 +503,12 @@ impl Pane for LocalPane {
     fn get_title(&self) -> String {
         let title = self.terminal.borrow_mut().get_title().to_string();
         // If the title is the default pane title, then try to spice
-        // things up a bit by returning the process basename instead
+        // things up a bit by returning the label of the command or
+        // the process basename instead
         if title == "wezterm" {
+            if let Some(label) = &self.spawn_label {
+                return label.clone();
+            }
             if let Some(proc_name) = self.get_foreground_process_name() {
                 let proc_name = std::path::Path::new(&proc_name);
                 if let Some(name) = proc_name.file_name() {
@@ -364,12 +563,56 @@ impl Pane for LocalPane {
         }
     }
 
+    fn get_modes(&self) -> Option<TerminalModes> {
+        if self.tmux_domain.borrow().is_some() {
+            None
+        } else {
+            Some(self.terminal.borrow().get_modes())
+        }
+    }
+
+    fn get_systemd_scope(&self) -> Option<String> {
+        self.systemd_scope.clone()
+    }
+
+    fn get_spawn_label(&self) -> Option<String> {
+        self.spawn_label.clone()
+    }
+
+    fn set_palette_overrides(&self, overrides: Option<Palette>) -> anyhow::Result<()> {
+        let changed = *self.palette_overrides.borrow() != overrides;
+        if changed {
+            *self.palette_overrides.borrow_mut() = overrides;
+            if let Some(mux) = Mux::get() {
+                mux.notify(MuxNotification::PanePaletteOverridesChanged(self.pane_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_palette_overrides(&self) -> Option<Palette> {
+        self.palette_overrides.borrow().clone()
+    }
+
+    fn get_resource_usage(&self) -> Option<PaneResourceUsage> {
+        self.resource_sample
+            .borrow()
+            .as_ref()
+            .map(|sample| sample.usage)
+    }
+
     fn get_current_working_dir(&self) -> Option<Url> {
         self.terminal
             .borrow()
             .get_current_dir()
             .cloned()
-            .or_else(|| self.divine_current_working_dir())
+            .or_else(|| {
+                if self.cwd_from_process {
+                    self.divine_current_working_dir()
+                } else {
+                    None
+                }
+            })
     }
 
     fn get_foreground_process_info(&self) -> Option<LocalProcessInfo> {
@@ -398,6 +641,12 @@ impl Pane for LocalPane {
     }
 
     fn can_close_without_prompting(&self, _reason: CloseReason) -> bool {
+        if !matches!(&*self.process.borrow(), ProcessState::Running { .. }) {
+            // The pane is being held open after its process exited;
+            // there is nothing running that would be lost
+            return true;
+        }
+
         if let Some(info) = self.divine_process_list(true) {
             log::trace!(
                 "can_close_without_prompting? procs in pane {:#?}",
@@ -468,7 +717,16 @@ impl Pane for LocalPane {
 
     fn get_semantic_zones(&self) -> anyhow::Result<Vec<SemanticZone>> {
         let mut term = self.terminal.borrow_mut();
-        term.get_semantic_zones()
+        let seqno = term.current_seqno();
+        let mut cache = self.semantic_zones.borrow_mut();
+        if let Some((cached_seqno, zones)) = cache.as_ref() {
+            if *cached_seqno == seqno {
+                return Ok(zones.clone());
+            }
+        }
+        let zones = term.get_semantic_zones()?;
+        cache.replace((seqno, zones.clone()));
+        Ok(zones)
     }
 
     async fn search(&self, mut pattern: Pattern) -> anyhow::Result<Vec<SearchResult>> {
@@ -704,7 +962,7 @@ impl wezterm_term::DeviceControlHandler for LocalPaneDCSHandler {
                         let pane = pane.downcast_ref::<LocalPane>().unwrap();
                         pane.tmux_domain.borrow_mut().take();
                     }
-                    mux.domain_was_detached(tmux.domain_id);
+                    mux.kill_panes_in_domain(tmux.domain_id);
                 }
             }
             DeviceControlMode::Data(c) => {
@@ -761,6 +1019,20 @@ impl AlertHandler for LocalPaneNotifHandler {
     }
 }
 
+/// Waits for the child to terminate, which reaps it and prevents
+/// it from lingering as a zombie.  On Windows this waits on the
+/// child process handle.
+fn reap_child(process: &mut (dyn Child + Send), spawned: Instant) -> ChildExit {
+    let status = process.wait().unwrap_or_else(|err| {
+        log::error!("error while waiting for child process: {:#}", err);
+        ExitStatus::with_exit_code(1)
+    });
+    ChildExit {
+        status,
+        elapsed: spawned.elapsed(),
+    }
+}
+
 /// This is a little gross; on some systems, our pipe reader will continue
 /// to be blocked in read even after the child process has died.
 /// We need to wake up and notice that the child terminated in order
@@ -769,21 +1041,22 @@ impl AlertHandler for LocalPaneNotifHandler {
 /// to terminate, and then nudge the muxer to check for dead processes.
 /// Without this, typing `exit` in `cmd.exe` would keep the pane around
 /// until something else triggered the mux to prune dead processes.
+/// The wait happens on that background thread, so the exit status is
+/// recorded as soon as the child terminates and the gui thread never
+/// blocks on it.
 fn split_child(
     mut process: Box<dyn Child + Send>,
-) -> (
-    Receiver<IoResult<ExitStatus>>,
-    Box<dyn ChildKiller>,
-    Option<u32>,
-) {
+    spawned: Instant,
+) -> (Receiver<ChildExit>, Box<dyn ChildKiller>, Option<u32>) {
     let pid = process.process_id();
     let signaller = process.clone_killer();
 
     let (tx, rx) = bounded(1);
 
     std::thread::spawn(move || {
-        let status = process.wait();
-        tx.try_send(status).ok();
+        let exit = reap_child(&mut *process, spawned);
+        log::debug!("child pid {:?} terminated: {:?}", pid, exit);
+        tx.try_send(exit).ok();
         promise::spawn::spawn_into_main_thread(async move {
             let mux = Mux::get().unwrap();
             mux.prune_dead_windows();
@@ -803,7 +1076,8 @@ impl LocalPane {
         domain_id: DomainId,
         command_description: String,
     ) -> Self {
-        let (process, signaller, pid) = split_child(process);
+        let spawned = Instant::now();
+        let (process, signaller, pid) = split_child(process, spawned);
 
         terminal.set_device_control_handler(Box::new(LocalPaneDCSHandler {
             pane_id,
@@ -815,6 +1089,8 @@ impl LocalPane {
             terminal: RefCell::new(terminal),
             process: RefCell::new(ProcessState::Running {
                 child_waiter: process,
+                pending_exit: None,
+                spawned,
                 pid,
                 signaller,
                 killed: false,
@@ -824,7 +1100,212 @@ impl LocalPane {
             tmux_domain: RefCell::new(None),
             proc_list: RefCell::new(None),
             command_description,
+            semantic_zones: RefCell::new(None),
+            cwd_from_process: true,
+            systemd_scope: None,
+            spawn_label: None,
+            resource_sample: RefCell::new(None),
+            palette_overrides: RefCell::new(None),
+            exit_behavior: None,
+            spawn_command: None,
+        }
+    }
+
+    /// Controls whether the cwd of the foreground process is reported
+    /// as the cwd of the pane when the terminal hasn't been told it
+    /// via OSC 7.  That isn't appropriate when the process is a proxy
+    /// for a program running elsewhere, such as `docker exec`.
+    pub fn set_cwd_from_process(&mut self, enable: bool) {
+        self.cwd_from_process = enable;
+    }
+
+    /// Records the systemd scope that the process was spawned into.
+    /// The scope is stopped when the pane is killed, so that any
+    /// processes that outlived the process in the pane are killed too.
+    pub fn set_systemd_scope(&mut self, unit: String) {
+        self.systemd_scope.replace(unit);
+    }
+
+    /// Records the label of the command that was spawned into the pane
+    pub fn set_spawn_label(&mut self, label: String) {
+        self.spawn_label.replace(label);
+    }
+
+    /// Overrides the global `exit_behavior` for this pane
+    pub fn set_exit_behavior(&mut self, exit_behavior: ExitBehavior) {
+        self.exit_behavior.replace(exit_behavior);
+    }
+
+    /// Returns what happens to the pane when its process exits
+    pub fn exit_behavior(&self) -> ExitBehavior {
+        self.exit_behavior
+            .unwrap_or_else(|| configuration().exit_behavior)
+    }
+
+    /// Records the command and cwd that were spawned into the pane, as
+    /// they were passed to the domain, so that the pane can be respawned
+    pub fn set_spawn_command(
+        &mut self,
+        command: Option<CommandBuilder>,
+        command_dir: Option<CommandDir>,
+    ) {
+        self.spawn_command.replace((command, command_dir));
+    }
+
+    /// Returns the command and cwd that were spawned into the pane,
+    /// if it was spawned by a domain
+    pub fn get_spawn_command(&self) -> Option<(Option<CommandBuilder>, Option<CommandDir>)> {
+        self.spawn_command.clone()
+    }
+
+    /// Returns true if the process has exited and the pane is being
+    /// held open
+    pub fn is_held(&self) -> bool {
+        matches!(
+            &*self.process.borrow(),
+            ProcessState::DeadPendingClose { killed: false }
+        )
+    }
+
+    /// Handles a key press in a pane that is being held open:
+    /// R respawns the command and Q closes the pane.
+    /// Other keys are ignored, as there is nothing to send them to.
+    fn held_key_down(&self, key: KeyCode) {
+        let pane_id = self.pane_id;
+        match key {
+            KeyCode::Char('r') | KeyCode::Char('R') if self.spawn_command.is_some() => {
+                promise::spawn::spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    if let Err(err) = mux.respawn_pane(pane_id).await {
+                        log::error!("failed to respawn pane {}: {:#}", pane_id, err);
+                    }
+                })
+                .detach();
+            }
+            KeyCode::Char('q') | KeyCode::Char('Q') => {
+                self.kill();
+                promise::spawn::spawn_into_main_thread(async move {
+                    let mux = Mux::get().unwrap();
+                    mux.prune_dead_windows();
+                })
+                .detach();
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the pid of the process that was spawned into the pane,
+    /// while it is running
+    pub fn root_pid(&self) -> Option<u32> {
+        match &*self.process.borrow() {
+            ProcessState::Running { pid, .. } => *pid,
+            _ => None,
+        }
+    }
+
+    /// Replaces the pty and the process of the pane, for example to
+    /// take over a process that was moved out of another terminal.
+    /// The pane keeps its id, its scrollback and its place in the tab.
+    /// If `reset_parser` is true, an escape sequence that was cut off by
+    /// the switch is abandoned and the terminal is soft-reset before the
+    /// output of the new pty is parsed.
+    /// The previous process is no longer tracked or killed by the pane.
+    pub fn replace_pty(
+        &self,
+        pty: Box<dyn MasterPty>,
+        process: Box<dyn Child + Send>,
+        reset_parser: bool,
+    ) -> anyhow::Result<()> {
+        if crate::pane::paste_in_flight(self.pane_id) {
+            anyhow::bail!(
+                "a paste is still being sent to pane {}; try again once it has completed",
+                self.pane_id
+            );
+        }
+        if self.tmux_domain.borrow().is_some() {
+            anyhow::bail!("pane {} is attached to tmux", self.pane_id);
+        }
+        if !matches!(&*self.process.borrow(), ProcessState::Running { .. }) {
+            anyhow::bail!("the process in pane {} has already exited", self.pane_id);
+        }
+
+        let mux = Mux::get().unwrap();
+        let state = mux.pane_reader_state(self.pane_id).ok_or_else(|| {
+            anyhow::anyhow!("pane {} does not read from a local pty", self.pane_id)
+        })?;
+        let reader = pty.try_clone_reader()?;
+        pty.resize(self.pty.borrow().get_size()?)?;
+        if reset_parser {
+            // CAN abandons a partial escape sequence and DECSTR undoes
+            // the modes that the previous process may have left set
+            state.inject(self.pane_id, b"\x18\x1b[!p")?;
         }
+
+        let spawned = Instant::now();
+        let (child_waiter, signaller, pid) = split_child(process, spawned);
+        *self.pty.borrow_mut() = pty;
+        *self.process.borrow_mut() = ProcessState::Running {
+            child_waiter,
+            pending_exit: None,
+            spawned,
+            pid,
+            signaller,
+            killed: false,
+        };
+        self.proc_list.borrow_mut().take();
+        self.resource_sample.borrow_mut().take();
+
+        let generation = state.spawn_reader(self.pane_id, reader)?;
+        log::info!(
+            "pane {} switched to a new pty; reader generation={} pid={:?}",
+            self.pane_id,
+            generation,
+            pid
+        );
+        mux.notify(MuxNotification::PaneOutput(self.pane_id));
+        Ok(())
+    }
+
+    /// Records the resources used by the process tree of the pane,
+    /// as measured at `now`, and returns the resulting usage
+    pub fn record_resource_sample(
+        &self,
+        resources: ProcessResources,
+        now: Instant,
+    ) -> PaneResourceUsage {
+        let mut sample = self.resource_sample.borrow_mut();
+        let new_sample = ResourceSample::new(sample.as_ref(), resources, now);
+        sample.replace(new_sample);
+        new_sample.usage
+    }
+
+    /// Returns true once the parser has applied the last of the output
+    /// of the pane, or if the pane has no parser
+    fn output_parsed(&self) -> bool {
+        Mux::get()
+            .and_then(|mux| mux.pane_reader_state(self.pane_id))
+            .map(|state| state.parsed.load(Ordering::Relaxed))
+            .unwrap_or(true)
+    }
+
+    /// Returns up to `max_lines` of the most recent non-blank lines
+    /// from the terminal, used to explain why a process died early.
+    fn recent_output(&self, max_lines: usize) -> String {
+        let mut terminal = self.terminal.borrow_mut();
+        let dims = terminal_get_dimensions(&mut terminal);
+        let start = (dims.physical_top - max_lines as StableRowIndex).max(dims.scrollback_top);
+        let end = dims.physical_top + dims.viewport_rows as StableRowIndex;
+        let (_first, lines) = terminal_get_lines(&mut terminal, start..end);
+
+        let mut text: Vec<String> = lines
+            .iter()
+            .map(|line| line.as_str().trim_end().to_string())
+            .collect();
+        while text.last().map(|l| l.is_empty()).unwrap_or(false) {
+            text.pop();
+        }
+        let skip = text.len().saturating_sub(max_lines);
+        text.split_off(skip).join("\n")
     }
 
     fn divine_current_working_dir(&self) -> Option<Url> {
@@ -920,3 +1401,167 @@ impl Drop for LocalPane {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+    use std::io::Read;
+
+    fn spawn(argv: &[&str]) -> (Box<dyn Child + Send>, Box<dyn MasterPty + Send>) {
+        let pair = native_pty_system()
+            .openpty(PtySize::default())
+            .expect("openpty");
+        let child = pair
+            .slave
+            .spawn_command(CommandBuilder::from_argv(
+                argv.iter().map(Into::into).collect(),
+            ))
+            .expect("spawn");
+        (child, pair.master)
+    }
+
+    #[test]
+    fn reap_fast_exiting_command() {
+        let (mut child, _master) = spawn(&["false"]);
+        let exit = reap_child(&mut *child, Instant::now());
+        assert!(!exit.status.success());
+        assert_eq!(exit.status.exit_code(), 1);
+        assert!(exit.is_early_failure(false, Duration::from_secs(60)));
+        assert!(!exit.is_early_failure(false, Duration::ZERO));
+        assert!(!exit.is_early_failure(true, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn early_exit_waits_for_output() {
+        let exit = ChildExit {
+            status: ExitStatus::with_exit_code(1),
+            elapsed: Duration::ZERO,
+        };
+        assert!(exit.awaiting_output(Instant::now(), false));
+        assert!(!exit.awaiting_output(Instant::now(), true));
+        // Don't wait forever for output that may never be parsed
+        let spawned = Instant::now() - OUTPUT_DRAIN_TIMEOUT;
+        assert!(!exit.awaiting_output(spawned, false));
+    }
+
+    #[test]
+    fn hold_banner() {
+        let exit = ChildExit {
+            status: ExitStatus::with_exit_code(0),
+            elapsed: Duration::from_secs(125),
+        };
+        let banner = exit.hold_banner("\"make\"", true, "because", true);
+        assert_eq!(
+            banner,
+            "\r\n👍 Process \"make\" completed after 2m 5s.\r\n\
+             because\r\n\
+             Press R to run it again, or Q to close this pane.\r\n"
+        );
+
+        let exit = ChildExit {
+            status: ExitStatus::with_exit_code(2),
+            elapsed: Duration::from_millis(300),
+        };
+        let banner = exit.hold_banner("\"make\"", false, "because", false);
+        assert!(
+            banner.contains("didn't exit cleanly after 300ms"),
+            "{}",
+            banner
+        );
+        assert!(banner.ends_with("Press Q to close this pane.\r\n"));
+    }
+
+    #[test]
+    fn runtime() {
+        assert_eq!(format_runtime(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_runtime(Duration::from_secs(59)), "59s");
+        assert_eq!(format_runtime(Duration::from_secs(3600 + 61)), "1h 1m 1s");
+    }
+
+    #[test]
+    fn reap_signal_killed_child() {
+        let (mut child, _master) = spawn(&["sleep", "60"]);
+        child.clone_killer().kill().expect("kill");
+        let exit = reap_child(&mut *child, Instant::now());
+        assert!(!exit.status.success());
+        assert!(
+            exit.status.to_string().starts_with("Terminated by"),
+            "{}",
+            exit.status
+        );
+    }
+
+    #[test]
+    fn pty_size_conversion() {
+        let size = TerminalSize {
+            rows: 30,
+            cols: 100,
+            pixel_width: 800,
+            pixel_height: 600,
+            dpi: 96,
+        };
+        assert_eq!(
+            crate::terminal_size_to_pty_size(size).unwrap(),
+            PtySize {
+                rows: 30,
+                cols: 100,
+                pixel_width: 800,
+                pixel_height: 600,
+            }
+        );
+
+        let huge = crate::terminal_size_to_pty_size(TerminalSize {
+            pixel_width: 100_000,
+            ..size
+        })
+        .unwrap();
+        assert_eq!((huge.rows, huge.cols), (30, 100));
+        assert_eq!((huge.pixel_width, huge.pixel_height), (0, 0));
+
+        assert!(crate::terminal_size_to_pty_size(TerminalSize {
+            cols: 100_000,
+            ..size
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn child_sees_resized_winsize() {
+        let pair = native_pty_system()
+            .openpty(PtySize::default())
+            .expect("openpty");
+        let size = TerminalSize {
+            rows: 30,
+            cols: 100,
+            pixel_width: 1000,
+            pixel_height: 750,
+            dpi: 96,
+        };
+        let pty_size = crate::terminal_size_to_pty_size(size).unwrap();
+        pair.master.resize(pty_size).expect("resize");
+        assert_eq!(pair.master.get_size().unwrap(), pty_size);
+
+        let mut child = pair
+            .slave
+            .spawn_command(CommandBuilder::from_argv(vec![
+                "stty".into(),
+                "size".into(),
+            ]))
+            .expect("spawn");
+        drop(pair.slave);
+        let mut reader = pair.master.try_clone_reader().expect("reader");
+        let mut output = vec![];
+        let mut buf = [0u8; 256];
+        // The read fails with EIO rather than returning 0 once the
+        // child has closed the slave
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        child.wait().expect("wait");
+        assert_eq!(String::from_utf8_lossy(&output).trim(), "30 100");
+    }
+}
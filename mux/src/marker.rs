@@ -0,0 +1,224 @@
+//! Highlighted ranges of rows in a pane, which editors and CI
+//! integrations use to draw attention to output, such as that of a
+//! failing test.  A marker only changes how its rows are drawn; the
+//! cells of the pane are left alone.  It is anchored to the stable
+//! indices of its rows, so it scrolls along with them, and it is
+//! dropped once all of its rows have been trimmed from the scrollback.
+//! Markers are kept by the mux that hosts the pane; the server tells
+//! its clients about changes with `Pdu::PaneMarkersChanged`, so that
+//! they can draw the markers without asking for them on every frame.
+use crate::pane::PaneId;
+use config::RgbaColor;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use termwiz::cell::Underline;
+use termwiz::color::ColorAttribute;
+use termwiz::surface::Line;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::StableRowIndex;
+
+/// The most markers that a pane may have at once
+pub const MAX_MARKERS_PER_PANE: usize = 256;
+
+/// How the rows of a marker are drawn.
+/// When none of the fields are set, the rows are underlined.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct MarkerStyle {
+    #[dynamic(default)]
+    pub underline: Option<Underline>,
+    #[dynamic(default)]
+    pub fg: Option<RgbaColor>,
+    #[dynamic(default)]
+    pub bg: Option<RgbaColor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct Marker {
+    /// Identifies the marker so that it can be removed.  Adding a
+    /// marker replaces any other with the same id; if it is empty,
+    /// an id is assigned.
+    #[dynamic(default)]
+    pub id: String,
+    /// The stable index of the first row of the marker
+    pub start_line: StableRowIndex,
+    /// The stable index of the last row of the marker, inclusive
+    pub end_line: StableRowIndex,
+    #[dynamic(default)]
+    pub style: MarkerStyle,
+}
+luahelper::impl_lua_conversion_dynamic!(Marker);
+
+impl Marker {
+    pub fn contains(&self, row: StableRowIndex) -> bool {
+        row >= self.start_line && row <= self.end_line
+    }
+
+    /// Applies the style of the marker to the attributes of the cells
+    /// of `line`, which is a copy of one of its rows that is about to
+    /// be drawn
+    pub fn apply_to_line(&self, line: &mut Line) {
+        let style = &self.style;
+        let underline = match style.underline {
+            Some(underline) => Some(underline),
+            None if style.fg.is_none() && style.bg.is_none() => Some(Underline::Single),
+            None => None,
+        };
+        for cell in line.cells_mut_for_attr_changes_only() {
+            let attrs = cell.attrs_mut();
+            if let Some(underline) = underline {
+                attrs.set_underline(underline);
+            }
+            if let Some(fg) = &style.fg {
+                attrs.set_foreground(ColorAttribute::TrueColorWithDefaultFallback((**fg).into()));
+            }
+            if let Some(bg) = &style.bg {
+                attrs.set_background(ColorAttribute::TrueColorWithDefaultFallback((**bg).into()));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PaneMarkers {
+    by_pane: HashMap<PaneId, Vec<Marker>>,
+    next_id: usize,
+}
+
+impl PaneMarkers {
+    /// Adds `marker` to the pane, replacing any marker with the same
+    /// id, and returns it along with the id that it was given
+    pub fn add(&mut self, pane_id: PaneId, mut marker: Marker) -> anyhow::Result<Marker> {
+        anyhow::ensure!(
+            marker.start_line <= marker.end_line,
+            "the marker starts at row {}, after its end at row {}",
+            marker.start_line,
+            marker.end_line
+        );
+        if marker.id.is_empty() {
+            self.next_id += 1;
+            marker.id = format!("marker-{}", self.next_id);
+        }
+        let markers = self.by_pane.entry(pane_id).or_default();
+        markers.retain(|existing| existing.id != marker.id);
+        anyhow::ensure!(
+            markers.len() < MAX_MARKERS_PER_PANE,
+            "pane {} already has the maximum of {} markers",
+            pane_id,
+            MAX_MARKERS_PER_PANE
+        );
+        markers.push(marker.clone());
+        Ok(marker)
+    }
+
+    /// Removes the marker `id`, returning false if the pane has no
+    /// such marker
+    pub fn remove(&mut self, pane_id: PaneId, id: &str) -> bool {
+        match self.by_pane.get_mut(&pane_id) {
+            Some(markers) => {
+                let len = markers.len();
+                markers.retain(|marker| marker.id != id);
+                markers.len() != len
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the markers of the pane in the order in which they were
+    /// added, after dropping those whose rows all lie above
+    /// `scrollback_top`, the first row that the pane still holds
+    pub fn list(&mut self, pane_id: PaneId, scrollback_top: StableRowIndex) -> Vec<Marker> {
+        match self.by_pane.get_mut(&pane_id) {
+            Some(markers) => {
+                markers.retain(|marker| marker.end_line >= scrollback_top);
+                markers.clone()
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        self.by_pane.remove(&pane_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn marker(id: &str, start_line: StableRowIndex, end_line: StableRowIndex) -> Marker {
+        Marker {
+            id: id.to_string(),
+            start_line,
+            end_line,
+            style: MarkerStyle::default(),
+        }
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut markers = PaneMarkers::default();
+        markers.add(1, marker("a", 10, 12)).unwrap();
+        markers.add(1, marker("b", 20, 20)).unwrap();
+        markers.add(1, marker("a", 30, 31)).unwrap();
+        let assigned = markers.add(1, marker("", 40, 40)).unwrap();
+        assert_eq!(assigned.id, "marker-1");
+        assert!(markers.add(1, marker("bad", 5, 4)).is_err());
+        assert_eq!(
+            markers.list(1, 0),
+            vec![marker("b", 20, 20), marker("a", 30, 31), assigned]
+        );
+
+        assert!(markers.remove(1, "b"));
+        assert!(!markers.remove(1, "b"));
+        assert!(!markers.remove(2, "a"));
+        markers.remove_pane(1);
+        assert_eq!(markers.list(1, 0), vec![]);
+    }
+
+    #[test]
+    fn trimmed_markers_are_dropped() {
+        let mut markers = PaneMarkers::default();
+        markers.add(1, marker("old", 10, 20)).unwrap();
+        markers.add(1, marker("partly", 40, 60)).unwrap();
+        assert_eq!(markers.list(1, 50), vec![marker("partly", 40, 60)]);
+        // Dropped for good, even if asked again with an older top
+        assert_eq!(markers.list(1, 0), vec![marker("partly", 40, 60)]);
+    }
+
+    #[test]
+    fn capped() {
+        let mut markers = PaneMarkers::default();
+        for i in 0..MAX_MARKERS_PER_PANE {
+            markers.add(1, marker(&i.to_string(), 0, 0)).unwrap();
+        }
+        assert!(markers.add(1, marker("more", 0, 0)).is_err());
+        // Replacing an existing marker is still allowed
+        markers.add(1, marker("0", 1, 1)).unwrap();
+        assert!(markers.add(2, marker("other pane", 0, 0)).is_ok());
+    }
+
+    #[test]
+    fn style() {
+        let mut line = Line::from_text("hello", &Default::default(), 0, None);
+        marker("a", 0, 0).apply_to_line(&mut line);
+        assert_eq!(line.cells()[0].attrs().underline(), Underline::Single);
+        assert_eq!(line.as_str(), "hello");
+
+        let mut line = Line::from_text("hello", &Default::default(), 0, None);
+        let bg = RgbaColor::try_from("#ff0000".to_string()).unwrap();
+        Marker {
+            style: MarkerStyle {
+                bg: Some(bg),
+                ..Default::default()
+            },
+            ..marker("b", 0, 0)
+        }
+        .apply_to_line(&mut line);
+        assert_eq!(line.cells()[0].attrs().underline(), Underline::None);
+        assert_eq!(
+            line.cells()[4].attrs().background(),
+            ColorAttribute::TrueColorWithDefaultFallback((*bg).into())
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Windows named pipes, as used for the `cli_listen` endpoint.
+//! The mux protocol is spoken over `smol::Async` streams, which need a
+//! socket on Windows, so each pipe connection is bridged to one end of
+//! a socketpair by a pair of threads.
+use anyhow::Context;
+use filedescriptor::{FileDescriptor, OwnedHandle};
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, AsRawSocket, FromRawHandle};
+use std::path::{Path, PathBuf};
+use std::ptr::{null, null_mut};
+use std::sync::Arc;
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+use winapi::shared::winerror::{ERROR_IO_PENDING, ERROR_PIPE_CONNECTED};
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::fileapi::{CreateFileW, ReadFile, WriteFile, OPEN_EXISTING};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, ImpersonateNamedPipeClient};
+use winapi::um::processthreadsapi::{
+    GetCurrentProcess, GetCurrentThread, OpenProcessToken, OpenThreadToken,
+};
+use winapi::um::securitybaseapi::{GetLengthSid, GetTokenInformation, RevertToSelf};
+use winapi::um::synchapi::{CreateEventW, SetEvent, WaitForMultipleObjects};
+use winapi::um::winbase::{
+    FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, INFINITE, PIPE_ACCESS_DUPLEX,
+    PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES,
+    PIPE_WAIT, SECURITY_IDENTIFICATION, SECURITY_SQOS_PRESENT, WAIT_OBJECT_0,
+};
+use winapi::um::winnt::{TokenUser, GENERIC_READ, GENERIC_WRITE, HANDLE, TOKEN_QUERY, TOKEN_USER};
+use winapi::um::winsock2::{recv, send, shutdown, SD_SEND};
+
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Accepts connections on a named pipe
+pub struct PipeListener {
+    name: PathBuf,
+    wide_name: Vec<u16>,
+    next: Option<OwnedHandle>,
+}
+
+impl PipeListener {
+    /// Creates the first instance of the pipe `name`.
+    /// This fails if another process is already serving that name.
+    pub fn bind(name: &Path) -> anyhow::Result<Self> {
+        let wide_name = wide(name.as_os_str());
+        let first = create_instance(&wide_name, true).with_context(|| {
+            format!(
+                "creating named pipe {}; is it already being served by another process?",
+                name.display()
+            )
+        })?;
+        Ok(Self {
+            name: name.to_path_buf(),
+            wide_name,
+            next: Some(first),
+        })
+    }
+
+    /// Waits for a client to connect to the pipe
+    pub fn accept(&mut self) -> anyhow::Result<PipeStream> {
+        let pipe = match self.next.take() {
+            Some(pipe) => pipe,
+            None => create_instance(&self.wide_name, false)
+                .with_context(|| format!("creating named pipe {}", self.name.display()))?,
+        };
+        let handle = pipe.as_raw_handle() as HANDLE;
+        match overlapped_io(handle, None, |ov| unsafe { ConnectNamedPipe(handle, ov) }) {
+            Ok(_) => {}
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("ConnectNamedPipe {}", self.name.display()))
+            }
+        }
+        Ok(PipeStream { pipe })
+    }
+}
+
+/// The server end of a connected named pipe
+pub struct PipeStream {
+    pipe: OwnedHandle,
+}
+
+impl PipeStream {
+    /// Checks that the client on the other end of the pipe is running
+    /// as the same user as this process
+    pub fn verify_client_is_same_user(&self) -> anyhow::Result<()> {
+        let client = client_token(self.pipe.as_raw_handle() as HANDLE)?;
+        let mut ours = null_mut();
+        if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut ours) } == 0 {
+            return Err(io::Error::last_os_error()).context("OpenProcessToken");
+        }
+        let ours = unsafe { OwnedHandle::from_raw_handle(ours as _) };
+
+        if token_user_sid(&client)? != token_user_sid(&ours)? {
+            anyhow::bail!("named pipe client is running as a different user");
+        }
+        Ok(())
+    }
+
+    /// Returns a socket that is bridged to the pipe
+    pub fn into_socket(self) -> anyhow::Result<FileDescriptor> {
+        bridge(self.pipe)
+    }
+}
+
+/// Connects to the named pipe `name`, returning a socket that is
+/// bridged to it
+pub fn connect(name: &Path) -> anyhow::Result<FileDescriptor> {
+    let wide_name = wide(name.as_os_str());
+    let handle = unsafe {
+        CreateFileW(
+            wide_name.as_ptr(),
+            GENERIC_READ | GENERIC_WRITE,
+            0,
+            null_mut(),
+            OPEN_EXISTING,
+            // The server only needs to identify us, not act as us
+            FILE_FLAG_OVERLAPPED | SECURITY_SQOS_PRESENT | SECURITY_IDENTIFICATION,
+            null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("connecting to {}", name.display()));
+    }
+    bridge(unsafe { OwnedHandle::from_raw_handle(handle as _) })
+}
+
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(std::iter::once(0)).collect()
+}
+
+fn create_instance(wide_name: &[u16], first: bool) -> io::Result<OwnedHandle> {
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let handle = unsafe {
+        CreateNamedPipeW(
+            wide_name.as_ptr(),
+            open_mode,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUF_SIZE as DWORD,
+            BUF_SIZE as DWORD,
+            0,
+            null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle as _) })
+}
+
+fn create_event() -> io::Result<OwnedHandle> {
+    let event = unsafe { CreateEventW(null_mut(), TRUE, FALSE, null()) };
+    if event.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(event as _) })
+}
+
+/// Starts an overlapped operation on `pipe` by calling `op` and waits
+/// for it to complete, returning the number of bytes transferred.
+/// If `stop` is signalled first, the operation is cancelled.
+fn overlapped_io<F>(pipe: HANDLE, stop: Option<HANDLE>, op: F) -> io::Result<usize>
+where
+    F: FnOnce(*mut OVERLAPPED) -> BOOL,
+{
+    let event = create_event()?;
+    let mut ov: OVERLAPPED = unsafe { std::mem::zeroed() };
+    ov.hEvent = event.as_raw_handle() as HANDLE;
+
+    if op(&mut ov) == 0 {
+        let err = unsafe { GetLastError() };
+        if err != ERROR_IO_PENDING {
+            return Err(io::Error::from_raw_os_error(err as i32));
+        }
+        if let Some(stop) = stop {
+            let handles = [ov.hEvent, stop];
+            let res = unsafe { WaitForMultipleObjects(2, handles.as_ptr(), FALSE, INFINITE) };
+            if res != WAIT_OBJECT_0 {
+                unsafe { CancelIoEx(pipe, &mut ov) };
+            }
+        }
+    }
+
+    // The operation must be complete, or cancelled, before `ov` goes away
+    let mut transferred = 0;
+    if unsafe { GetOverlappedResult(pipe, &mut ov, &mut transferred, TRUE) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(transferred as usize)
+}
+
+/// Returns the impersonation token of the client connected to `pipe`
+fn client_token(pipe: HANDLE) -> anyhow::Result<OwnedHandle> {
+    if unsafe { ImpersonateNamedPipeClient(pipe) } == 0 {
+        return Err(io::Error::last_os_error()).context("ImpersonateNamedPipeClient");
+    }
+    let mut token = null_mut();
+    let opened = unsafe { OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, TRUE, &mut token) };
+    let err = io::Error::last_os_error();
+    if unsafe { RevertToSelf() } == 0 {
+        // Carrying on with the identity of the client would be far
+        // worse than stopping here
+        panic!("RevertToSelf failed: {}", io::Error::last_os_error());
+    }
+    if opened == 0 {
+        return Err(err).context("OpenThreadToken");
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(token as _) })
+}
+
+/// Returns the bytes of the SID of the user that `token` belongs to
+fn token_user_sid(token: &OwnedHandle) -> anyhow::Result<Vec<u8>> {
+    let token = token.as_raw_handle() as HANDLE;
+    let mut len = 0;
+    unsafe { GetTokenInformation(token, TokenUser, null_mut(), 0, &mut len) };
+    // u64 keeps the buffer suitably aligned for TOKEN_USER
+    let mut buf = vec![0u64; (len as usize + 7) / 8];
+    if unsafe { GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as _, len, &mut len) } == 0 {
+        return Err(io::Error::last_os_error()).context("GetTokenInformation");
+    }
+    let user = unsafe { &*(buf.as_ptr() as *const TOKEN_USER) };
+    let sid = user.User.Sid;
+    let sid_len = unsafe { GetLengthSid(sid) } as usize;
+    Ok(unsafe { std::slice::from_raw_parts(sid as *const u8, sid_len) }.to_vec())
+}
+
+/// Copies data between `pipe` and a socketpair, returning the other
+/// end of that socketpair.  When either side closes, the other is
+/// shut down so that its reader sees EOF.
+fn bridge(pipe: OwnedHandle) -> anyhow::Result<FileDescriptor> {
+    let (ours, theirs) = filedescriptor::socketpair()?;
+    let ours = Arc::new(ours);
+    let pipe = Arc::new(pipe);
+    let stop = Arc::new(create_event()?);
+
+    {
+        let ours = Arc::clone(&ours);
+        let pipe = Arc::clone(&pipe);
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let handle = pipe.as_raw_handle() as HANDLE;
+            let stop_handle = stop.as_raw_handle() as HANDLE;
+            let sock = ours.as_raw_socket() as _;
+            let mut buf = vec![0u8; BUF_SIZE];
+            'copy: loop {
+                let len = match overlapped_io(handle, Some(stop_handle), |ov| unsafe {
+                    ReadFile(
+                        handle,
+                        buf.as_mut_ptr() as _,
+                        buf.len() as DWORD,
+                        null_mut(),
+                        ov,
+                    )
+                }) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                let mut data = &buf[..len];
+                while !data.is_empty() {
+                    let sent = unsafe { send(sock, data.as_ptr() as _, data.len() as _, 0) };
+                    if sent <= 0 {
+                        break 'copy;
+                    }
+                    data = &data[sent as usize..];
+                }
+            }
+            unsafe {
+                shutdown(sock, SD_SEND);
+                SetEvent(stop_handle);
+            }
+        });
+    }
+
+    std::thread::spawn(move || {
+        let handle = pipe.as_raw_handle() as HANDLE;
+        let stop_handle = stop.as_raw_handle() as HANDLE;
+        let sock = ours.as_raw_socket() as _;
+        let mut buf = vec![0u8; BUF_SIZE];
+        'copy: loop {
+            let len = unsafe { recv(sock, buf.as_mut_ptr() as _, buf.len() as _, 0) };
+            if len <= 0 {
+                break;
+            }
+            let mut data = &buf[..len as usize];
+            while !data.is_empty() {
+                match overlapped_io(handle, Some(stop_handle), |ov| unsafe {
+                    WriteFile(
+                        handle,
+                        data.as_ptr() as _,
+                        data.len() as DWORD,
+                        null_mut(),
+                        ov,
+                    )
+                }) {
+                    Ok(0) | Err(_) => break 'copy,
+                    Ok(written) => data = &data[written..],
+                }
+            }
+        }
+        // Wakes up the pipe reader, which shuts down the socket
+        unsafe { SetEvent(stop_handle) };
+    });
+
+    Ok(theirs)
+}
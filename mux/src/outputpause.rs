@@ -0,0 +1,125 @@
+//! Pauses the processing of the output of a local pane, so that the
+//! user can read output that would otherwise scroll away, without
+//! stopping the process in the pane.
+//! While a pane is paused its parser stops applying actions to the
+//! terminal and stops reading from the socket that the pty reader
+//! writes into.  Once the buffer of that socket is full, the pty reader
+//! blocks, and the kernel then applies backpressure to the process via
+//! the pty.  Resuming applies the output that was buffered meanwhile.
+//! Input to the pane is not affected.
+use crate::pane::PaneId;
+use crate::{Mux, MuxNotification};
+use anyhow::anyhow;
+use std::sync::{Condvar, Mutex};
+
+/// The pause state of the output of a pane, shared between the mux
+/// and the parser thread of the pane
+#[derive(Default)]
+pub struct OutputPause {
+    paused: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl OutputPause {
+    /// Returns true if the state changed
+    pub fn set_paused(&self, paused: bool) -> bool {
+        let mut state = self.paused.lock().unwrap();
+        if *state == paused {
+            return false;
+        }
+        *state = paused;
+        self.cond.notify_all();
+        true
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Blocks the calling thread for as long as the output is paused
+    pub fn wait_while_paused(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused {
+            paused = self.cond.wait(paused).unwrap();
+        }
+    }
+}
+
+impl Mux {
+    /// Pauses or resumes applying the output of the pane to its terminal.
+    /// Panes whose output isn't parsed by this mux, such as those of a
+    /// multiplexer client, are paused wherever their output is parsed;
+    /// the `MuxNotification::PaneOutputPaused` for them arrives once
+    /// that has happened.
+    pub fn set_pane_paused(&self, pane_id: PaneId, paused: bool) -> anyhow::Result<()> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane {} not found", pane_id))?;
+        match self.pane_reader_state(pane_id) {
+            Some(state) => {
+                if state.pause.set_paused(paused) {
+                    log::debug!(
+                        "{} output of pane {}",
+                        if paused { "pausing" } else { "resuming" },
+                        pane_id
+                    );
+                    self.notify(MuxNotification::PaneOutputPaused { pane_id, paused });
+                }
+                Ok(())
+            }
+            None => pane.set_output_paused(paused),
+        }
+    }
+
+    pub fn is_pane_paused(&self, pane_id: PaneId) -> bool {
+        match self.pane_reader_state(pane_id) {
+            Some(state) => state.pause.is_paused(),
+            None => self
+                .get_pane(pane_id)
+                .map(|pane| pane.is_output_paused())
+                .unwrap_or(false),
+        }
+    }
+
+    /// Pauses the output of the pane if it is running, or resumes it
+    /// if it is paused
+    pub fn toggle_pane_paused(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        self.set_pane_paused(pane_id, !self.is_pane_paused(pane_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn resume_releases_waiter() {
+        let pause = Arc::new(OutputPause::default());
+        assert!(pause.set_paused(true));
+        assert!(!pause.set_paused(true));
+        assert!(pause.is_paused());
+
+        let released = Arc::new(AtomicBool::new(false));
+        let waiter = std::thread::spawn({
+            let pause = Arc::clone(&pause);
+            let released = Arc::clone(&released);
+            move || {
+                pause.wait_while_paused();
+                released.store(true, Ordering::SeqCst);
+            }
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!released.load(Ordering::SeqCst));
+
+        assert!(pause.set_paused(false));
+        waiter.join().unwrap();
+        assert!(released.load(Ordering::SeqCst));
+
+        // Not paused, so this returns immediately
+        pause.wait_while_paused();
+    }
+}
@@ -0,0 +1,461 @@
+//! Watchers that look for a regex in the output of a pane, and fire a
+//! lua event when it appears, optionally answering it by sending a
+//! canned response to the pane.
+//! Output is matched a logical line at a time, once the line has been
+//! committed by the cursor moving below it, so that a line that is
+//! still being written is not matched piecemeal.  A watcher that is
+//! meant to answer a prompt that waits for input on the same line can
+//! ask to match the line of the cursor as well.  A watcher fires at
+//! most once per line.
+//! Watchers are kept by the mux that hosts the pane, so the output of
+//! a pane of a multiplexer server is matched by the server, which only
+//! sends the matches to the client that added the watcher.
+//! The lines of a pane without watchers are not looked at; those of a
+//! pane with watchers are checked against a single `RegexSet`.
+use crate::client::ClientId;
+use crate::pane::{Pane, PaneId};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::StableRowIndex;
+
+pub type WatcherId = usize;
+
+/// The most watchers that a pane may have
+pub const MAX_WATCHERS_PER_PANE: usize = 16;
+
+/// Bounds the size of a compiled regex, so that a pathological
+/// pattern is rejected rather than slowing down the output of the pane
+const REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// The most lines that are matched after a batch of output.  When more
+/// than this were committed at once, only the most recent are matched.
+const MAX_LINES_PER_BATCH: StableRowIndex = 1000;
+
+/// What a watcher looks for, and what it does when it finds it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct OutputWatcherSpec {
+    pub regex: String,
+    /// The lua event that is emitted for each match
+    pub event: String,
+    /// If true, the watcher is removed after it first fires
+    #[dynamic(default)]
+    pub once: bool,
+    /// Text that is sent to the pane, as though it was typed, when
+    /// the watcher fires
+    #[dynamic(default)]
+    pub response: Option<String>,
+    /// If true, the line of the cursor is matched as it is written,
+    /// rather than waiting for it to be committed
+    #[dynamic(default)]
+    pub prompt: bool,
+}
+luahelper::impl_lua_conversion_dynamic!(OutputWatcherSpec);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct OutputWatcherInfo {
+    pub id: WatcherId,
+    pub spec: OutputWatcherSpec,
+    /// How many times the watcher has fired
+    pub matches: usize,
+}
+luahelper::impl_lua_conversion_dynamic!(OutputWatcherInfo);
+
+/// A line of output that a watcher matched
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct OutputMatch {
+    pub pane_id: PaneId,
+    pub watcher_id: WatcherId,
+    pub event: String,
+    /// The text that the regex matched
+    pub text: String,
+    /// The capture groups of the regex, starting with the first
+    /// group; groups that didn't participate in the match are None
+    pub captures: Vec<Option<String>>,
+    /// The logical line that contains the match
+    pub line: String,
+    /// The stable index of the first row of the line
+    pub row: StableRowIndex,
+}
+luahelper::impl_lua_conversion_dynamic!(OutputMatch);
+
+/// A watcher that fired, along with what should be done about it
+pub struct FiredWatcher {
+    /// The client that added the watcher, to which the match is sent,
+    /// or None if it was added locally
+    pub owner: Option<Arc<ClientId>>,
+    pub matched: OutputMatch,
+    pub response: Option<String>,
+}
+
+struct Watcher {
+    id: WatcherId,
+    spec: OutputWatcherSpec,
+    regex: Regex,
+    owner: Option<Arc<ClientId>>,
+    matches: usize,
+    /// The first row of the line that the watcher last fired on
+    fired_row: Option<StableRowIndex>,
+}
+
+/// A line of output that is offered to the watchers
+struct OutputLine {
+    row: StableRowIndex,
+    text: String,
+    /// False for the line of the cursor, which may still change
+    committed: bool,
+}
+
+struct PaneWatchers {
+    watchers: Vec<Watcher>,
+    /// The patterns of `watchers`, in the same order
+    set: RegexSet,
+    /// The first row of the first line that hasn't been committed,
+    /// or None until the pane has been scanned
+    scanned_to: Option<StableRowIndex>,
+}
+
+impl PaneWatchers {
+    fn rebuild_set(&mut self) {
+        self.set = RegexSetBuilder::new(self.watchers.iter().map(|w| w.spec.regex.as_str()))
+            .size_limit(REGEX_SIZE_LIMIT * MAX_WATCHERS_PER_PANE)
+            .build()
+            .unwrap_or_else(|_| RegexSet::empty());
+    }
+
+    /// Returns the lines that have been committed since the last scan,
+    /// followed by the line of the cursor
+    fn collect_lines(&mut self, pane: &dyn Pane) -> Vec<OutputLine> {
+        let cursor = pane.get_cursor_position().y;
+        let top = pane.get_dimensions().scrollback_top;
+        // A new watcher only sees output that arrives after it; the
+        // rows may also have moved back, such as after a reset
+        let start = self
+            .scanned_to
+            .unwrap_or(cursor)
+            .min(cursor)
+            .max(top)
+            .max(cursor - MAX_LINES_PER_BATCH);
+
+        let mut lines = vec![];
+        let mut scanned_to = cursor;
+        for line in pane.get_logical_lines(start..cursor + 1) {
+            if line.first_row < start {
+                continue;
+            }
+            let committed = !line.contains_y(cursor);
+            if !committed {
+                scanned_to = scanned_to.min(line.first_row);
+            }
+            lines.push(OutputLine {
+                row: line.first_row,
+                text: line.logical.as_str().trim_end().to_string(),
+                committed,
+            });
+        }
+        self.scanned_to.replace(scanned_to);
+        lines
+    }
+
+    fn match_lines(&mut self, pane_id: PaneId, lines: &[OutputLine]) -> Vec<FiredWatcher> {
+        let mut fired = vec![];
+        for line in lines {
+            for idx in self.set.matches(&line.text).iter() {
+                let watcher = match self.watchers.get_mut(idx) {
+                    Some(watcher) => watcher,
+                    None => continue,
+                };
+                if (!line.committed && !watcher.spec.prompt)
+                    || watcher.fired_row == Some(line.row)
+                    || (watcher.spec.once && watcher.matches > 0)
+                {
+                    continue;
+                }
+                let captures = match watcher.regex.captures(&line.text) {
+                    Some(captures) => captures,
+                    None => continue,
+                };
+                watcher.fired_row.replace(line.row);
+                watcher.matches += 1;
+                fired.push(FiredWatcher {
+                    owner: watcher.owner.clone(),
+                    matched: OutputMatch {
+                        pane_id,
+                        watcher_id: watcher.id,
+                        event: watcher.spec.event.clone(),
+                        text: captures[0].to_string(),
+                        captures: captures
+                            .iter()
+                            .skip(1)
+                            .map(|group| group.map(|m| m.as_str().to_string()))
+                            .collect(),
+                        line: line.text.clone(),
+                        row: line.row,
+                    },
+                    response: watcher.spec.response.clone(),
+                });
+            }
+        }
+
+        let before = self.watchers.len();
+        self.watchers
+            .retain(|watcher| !(watcher.spec.once && watcher.matches > 0));
+        if self.watchers.len() != before {
+            self.rebuild_set();
+        }
+        fired
+    }
+}
+
+#[derive(Default)]
+pub struct OutputWatchers {
+    by_pane: HashMap<PaneId, PaneWatchers>,
+    next_id: WatcherId,
+}
+
+impl OutputWatchers {
+    /// Adds a watcher to the pane, returning its id.
+    /// `owner` is the client that added it, if it was added remotely.
+    pub fn add(
+        &mut self,
+        pane_id: PaneId,
+        spec: OutputWatcherSpec,
+        owner: Option<Arc<ClientId>>,
+    ) -> anyhow::Result<WatcherId> {
+        anyhow::ensure!(!spec.event.is_empty(), "an output watcher needs an event");
+        let regex = RegexBuilder::new(&spec.regex)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()?;
+        let watchers = self.by_pane.entry(pane_id).or_insert_with(|| PaneWatchers {
+            watchers: vec![],
+            set: RegexSet::empty(),
+            scanned_to: None,
+        });
+        if watchers.watchers.len() >= MAX_WATCHERS_PER_PANE {
+            anyhow::bail!(
+                "pane {} already has {} output watchers, which is the most it may have",
+                pane_id,
+                MAX_WATCHERS_PER_PANE
+            );
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        watchers.watchers.push(Watcher {
+            id,
+            spec,
+            regex,
+            owner,
+            matches: 0,
+            fired_row: None,
+        });
+        watchers.rebuild_set();
+        Ok(id)
+    }
+
+    /// Returns true if the watcher was removed
+    pub fn remove(&mut self, pane_id: PaneId, id: WatcherId) -> bool {
+        let watchers = match self.by_pane.get_mut(&pane_id) {
+            Some(watchers) => watchers,
+            None => return false,
+        };
+        let before = watchers.watchers.len();
+        watchers.watchers.retain(|watcher| watcher.id != id);
+        let removed = watchers.watchers.len() != before;
+        if watchers.watchers.is_empty() {
+            self.by_pane.remove(&pane_id);
+        } else if removed {
+            watchers.rebuild_set();
+        }
+        removed
+    }
+
+    /// Returns the watchers of the pane, in the order in which they
+    /// were added
+    pub fn list(&self, pane_id: PaneId) -> Vec<OutputWatcherInfo> {
+        self.by_pane
+            .get(&pane_id)
+            .map(|watchers| {
+                watchers
+                    .watchers
+                    .iter()
+                    .map(|watcher| OutputWatcherInfo {
+                        id: watcher.id,
+                        spec: watcher.spec.clone(),
+                        matches: watcher.matches,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn is_watching(&self, pane_id: PaneId) -> bool {
+        self.by_pane.contains_key(&pane_id)
+    }
+
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        self.by_pane.remove(&pane_id);
+    }
+
+    /// Removes the watchers that were added by a client that has gone
+    /// away, so that they no longer answer prompts on its behalf
+    pub fn remove_owner(&mut self, owner: &ClientId) {
+        for watchers in self.by_pane.values_mut() {
+            let before = watchers.watchers.len();
+            watchers
+                .watchers
+                .retain(|watcher| watcher.owner.as_deref() != Some(owner));
+            if watchers.watchers.len() != before {
+                watchers.rebuild_set();
+            }
+        }
+        self.by_pane
+            .retain(|_, watchers| !watchers.watchers.is_empty());
+    }
+
+    /// Matches the output of the pane that arrived since it was last
+    /// scanned, returning the watchers that fired
+    pub fn scan(&mut self, pane: &dyn Pane) -> Vec<FiredWatcher> {
+        let pane_id = pane.pane_id();
+        let watchers = match self.by_pane.get_mut(&pane_id) {
+            Some(watchers) => watchers,
+            None => return vec![],
+        };
+        let lines = watchers.collect_lines(pane);
+        let fired = watchers.match_lines(pane_id, &lines);
+        if watchers.watchers.is_empty() {
+            self.by_pane.remove(&pane_id);
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec(regex: &str, once: bool, prompt: bool) -> OutputWatcherSpec {
+        OutputWatcherSpec {
+            regex: regex.to_string(),
+            event: "watched".to_string(),
+            once,
+            response: None,
+            prompt,
+        }
+    }
+
+    fn line(row: StableRowIndex, text: &str, committed: bool) -> OutputLine {
+        OutputLine {
+            row,
+            text: text.to_string(),
+            committed,
+        }
+    }
+
+    fn fire(watchers: &mut OutputWatchers, lines: &[OutputLine]) -> Vec<OutputMatch> {
+        watchers
+            .by_pane
+            .get_mut(&1)
+            .map(|w| w.match_lines(1, lines))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|fired| fired.matched)
+            .collect()
+    }
+
+    #[test]
+    fn matches_committed_lines() {
+        let mut watchers = OutputWatchers::default();
+        let id = watchers
+            .add(1, spec(r"error\[E(\d+)\]", false, false), None)
+            .unwrap();
+        let matched = fire(
+            &mut watchers,
+            &[
+                line(0, "Compiling foo", true),
+                line(1, "error[E0502]: cannot borrow", true),
+                line(2, "error[E0499]: cannot", false),
+            ],
+        );
+        assert_eq!(
+            matched,
+            vec![OutputMatch {
+                pane_id: 1,
+                watcher_id: id,
+                event: "watched".to_string(),
+                text: "error[E0502]".to_string(),
+                captures: vec![Some("0502".to_string())],
+                line: "error[E0502]: cannot borrow".to_string(),
+                row: 1,
+            }]
+        );
+
+        // The line of the cursor is matched once it is committed
+        let matched = fire(&mut watchers, &[line(2, "error[E0499]: cannot", true)]);
+        assert_eq!(matched.len(), 1);
+        assert_eq!(watchers.list(1)[0].matches, 2);
+    }
+
+    #[test]
+    fn prompts_fire_once_per_line() {
+        let mut watchers = OutputWatchers::default();
+        watchers
+            .add(1, spec(r"\[y/N\]", false, true), None)
+            .unwrap();
+        assert_eq!(
+            fire(&mut watchers, &[line(5, "Continue? [y/N]", false)]).len(),
+            1
+        );
+        // Neither the next batch of output nor the line being committed
+        // fire it again
+        assert!(fire(&mut watchers, &[line(5, "Continue? [y/N]", false)]).is_empty());
+        assert!(fire(&mut watchers, &[line(5, "Continue? [y/N] y", true)]).is_empty());
+        assert_eq!(
+            fire(&mut watchers, &[line(6, "Really? [y/N]", false)]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn once_and_removal() {
+        let mut watchers = OutputWatchers::default();
+        watchers.add(1, spec("done", true, false), None).unwrap();
+        let kept = watchers.add(1, spec("fail", false, false), None).unwrap();
+        let matched = fire(
+            &mut watchers,
+            &[line(0, "done", true), line(1, "done", true)],
+        );
+        assert_eq!(matched.len(), 1);
+        let listed: Vec<WatcherId> = watchers.list(1).iter().map(|w| w.id).collect();
+        assert_eq!(listed, vec![kept]);
+
+        assert!(!watchers.remove(1, kept + 1));
+        assert!(watchers.remove(1, kept));
+        assert!(!watchers.is_watching(1));
+    }
+
+    #[test]
+    fn limits() {
+        let mut watchers = OutputWatchers::default();
+        assert!(watchers.add(1, spec("(", false, false), None).is_err());
+        for _ in 0..MAX_WATCHERS_PER_PANE {
+            watchers.add(1, spec("a", false, false), None).unwrap();
+        }
+        assert!(watchers.add(1, spec("a", false, false), None).is_err());
+        watchers.add(2, spec("a", false, false), None).unwrap();
+    }
+
+    #[test]
+    fn owners() {
+        let mut watchers = OutputWatchers::default();
+        let client = Arc::new(ClientId::new());
+        watchers
+            .add(1, spec("a", false, false), Some(client.clone()))
+            .unwrap();
+        let local = watchers.add(1, spec("b", false, false), None).unwrap();
+        watchers.remove_owner(&client);
+        let listed: Vec<WatcherId> = watchers.list(1).iter().map(|w| w.id).collect();
+        assert_eq!(listed, vec![local]);
+    }
+}
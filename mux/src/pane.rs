@@ -1,8 +1,18 @@
+use crate::bookmark::Bookmark;
 use crate::domain::DomainId;
+use crate::editscrollback::EditScrollbackRequest;
+use crate::globalsearch::GlobalSearchResults;
+use crate::marker::Marker;
+use crate::outputwatch::{OutputWatcherInfo, OutputWatcherSpec, WatcherId};
 use crate::renderable::*;
+use crate::resources::PaneResourceUsage;
+use crate::scan::{scan_hyperlinks, scan_logical_lines, HyperlinkMatch, ScanMatch};
+use crate::scrollback::{ScrollbackChunk, ScrollbackFormat};
+use crate::viewstate::PaneViewState;
 use crate::Mux;
 use async_trait::async_trait;
 use config::keyassignment::{KeyAssignment, ScrollbackEraseMode};
+use config::Palette;
 use downcast_rs::{impl_downcast, Downcast};
 use rangeset::RangeSet;
 use serde::{Deserialize, Serialize};
@@ -10,6 +20,7 @@ use std::cell::RefMut;
 use std::collections::HashMap;
 use std::ops::Range;
 use std::sync::{Arc, Mutex};
+use termwiz::cell::SemanticType;
 use termwiz::hyperlink::Rule;
 use termwiz::input::KeyboardEncoding;
 use termwiz::surface::{Line, SequenceNo};
@@ -17,14 +28,23 @@ use url::Url;
 use wezterm_term::color::ColorPalette;
 use wezterm_term::{
     Clipboard, DownloadHandler, KeyCode, KeyModifiers, MouseEvent, SemanticZone, StableRowIndex,
-    TerminalConfiguration, TerminalSize,
+    TerminalConfiguration, TerminalModes, TerminalSize,
 };
 
-static PANE_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+pub(crate) static PANE_ID: ::std::sync::atomic::AtomicUsize =
+    ::std::sync::atomic::AtomicUsize::new(0);
 pub type PaneId = usize;
 
 pub fn alloc_pane_id() -> PaneId {
-    PANE_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed)
+    let id = PANE_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+    crate::ids::note_id_allocated();
+    id
+}
+
+/// Ensures that `alloc_pane_id` never returns `pane_id`, which was
+/// chosen elsewhere; for example, to match a pane in another process
+pub fn reserve_pane_id(pane_id: PaneId) {
+    PANE_ID.fetch_max(pane_id + 1, ::std::sync::atomic::Ordering::Relaxed);
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -74,6 +94,28 @@ impl std::ops::DerefMut for Pattern {
     }
 }
 
+/// The foreground process of a pane, as reported by the domain that
+/// hosts the pane, which may be a remote mux
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForegroundProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    /// The path of the executable
+    pub executable: String,
+    pub argv: Vec<String>,
+}
+
+impl From<&procinfo::LocalProcessInfo> for ForegroundProcessInfo {
+    fn from(info: &procinfo::LocalProcessInfo) -> Self {
+        Self {
+            pid: info.pid,
+            name: info.name.clone(),
+            executable: info.executable.to_string_lossy().to_string(),
+            argv: info.argv.clone(),
+        }
+    }
+}
+
 /// Why a close request is being made
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum CloseReason {
@@ -87,12 +129,46 @@ pub enum CloseReason {
 
 const PASTE_CHUNK_SIZE: usize = 1024;
 
+lazy_static::lazy_static! {
+    /// The number of trickled pastes that are still being sent, by pane
+    static ref PASTES_IN_FLIGHT: Mutex<HashMap<PaneId, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Returns true while a large paste is still being trickled into
+/// the pane
+pub fn paste_in_flight(pane_id: PaneId) -> bool {
+    PASTES_IN_FLIGHT.lock().unwrap().contains_key(&pane_id)
+}
+
 struct Paste {
     pane_id: PaneId,
     text: String,
     offset: usize,
 }
 
+impl Paste {
+    fn new(pane_id: PaneId, text: String) -> Self {
+        *PASTES_IN_FLIGHT.lock().unwrap().entry(pane_id).or_insert(0) += 1;
+        Self {
+            pane_id,
+            text,
+            offset: 0,
+        }
+    }
+}
+
+impl Drop for Paste {
+    fn drop(&mut self) {
+        let mut pastes = PASTES_IN_FLIGHT.lock().unwrap();
+        if let Some(count) = pastes.get_mut(&self.pane_id) {
+            *count -= 1;
+            if *count == 0 {
+                pastes.remove(&self.pane_id);
+            }
+        }
+    }
+}
+
 fn paste_next_chunk(paste: &Arc<Mutex<Paste>>) {
     let mut locked = paste.lock().unwrap();
     let mux = Mux::get().unwrap();
@@ -348,9 +424,28 @@ pub trait Pane: Downcast {
     /// Returns render related dimensions
     fn get_dimensions(&self) -> RenderableDimensions;
 
+    /// Returns the size that the pane was most recently resized to,
+    /// including its pixel dimensions when they are known
+    fn get_size(&self) -> TerminalSize {
+        let dims = self.get_dimensions();
+        TerminalSize {
+            rows: dims.viewport_rows,
+            cols: dims.cols,
+            pixel_width: 0,
+            pixel_height: 0,
+            dpi: dims.dpi,
+        }
+    }
+
     fn get_title(&self) -> String;
     fn send_paste(&self, text: &str) -> anyhow::Result<()>;
     fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>>;
+    /// Returns the number of bytes of output that are waiting to be
+    /// consumed via `reader`, if that can be determined.
+    /// This is used by the watchdog to detect a stalled reader.
+    fn pending_output(&self) -> Option<usize> {
+        None
+    }
     fn writer(&self) -> RefMut<dyn std::io::Write>;
     fn resize(&self, size: TerminalSize) -> anyhow::Result<()>;
     /// Called as a hint that the pane is being resized as part of
@@ -365,6 +460,12 @@ pub trait Pane: Downcast {
     fn perform_actions(&self, _actions: Vec<termwiz::escape::Action>) {}
     fn is_dead(&self) -> bool;
     fn kill(&self) {}
+    /// Called when processing the output of this pane panicked.
+    /// The pane should terminate its process but remain visible, as
+    /// though it had exited and was being held open, until it is closed.
+    fn mark_internal_error(&self) {
+        self.kill();
+    }
     fn palette(&self) -> ColorPalette;
     fn domain_id(&self) -> DomainId;
 
@@ -401,6 +502,214 @@ pub trait Pane: Downcast {
         Ok(vec![])
     }
 
+    /// Finds the most recent matches of `pattern`, at most `limit` of
+    /// them, along with the text around each; see `globalsearch`.
+    /// A pane that is hosted by a multiplexer server has the server
+    /// search it.
+    async fn search_with_snippets(
+        &self,
+        pattern: Pattern,
+        limit: usize,
+    ) -> anyhow::Result<GlobalSearchResults> {
+        crate::globalsearch::search_pane(self, pattern, limit).await
+    }
+
+    /// Finds the matches of the regexes in `patterns` in the logical
+    /// lines that intersect `range`; see `scan::scan_logical_lines`.
+    async fn scan(
+        &self,
+        patterns: Vec<String>,
+        range: Range<StableRowIndex>,
+    ) -> anyhow::Result<Vec<ScanMatch>> {
+        scan_logical_lines(&self.get_logical_lines(range), &patterns)
+    }
+
+    /// Finds the implicit hyperlinks that `rules` make in the logical
+    /// lines that intersect `range`; see `scan::scan_hyperlinks`.
+    async fn scan_hyperlinks(
+        &self,
+        rules: &[Rule],
+        range: Range<StableRowIndex>,
+    ) -> anyhow::Result<Vec<HyperlinkMatch>> {
+        Ok(scan_hyperlinks(&self.get_logical_lines(range), rules))
+    }
+
+    /// Returns the foreground process of this pane, asking the mux
+    /// server that hosts it if it is remote, or None if it isn't known
+    async fn query_foreground_process(&self) -> anyhow::Result<Option<ForegroundProcessInfo>> {
+        Ok(self
+            .get_foreground_process_info()
+            .map(|info| ForegroundProcessInfo::from(&info)))
+    }
+
+    /// Tells the mux server that hosts this pane how the user views it,
+    /// so that the view can be restored when the client reattaches.
+    /// Called with the current view whenever the pane is drawn.
+    fn record_view_state(&self, _state: PaneViewState) {}
+
+    /// Returns the view that the user had of this pane before the client
+    /// last detached, the first time this is called after reattaching
+    fn take_restored_view_state(&self) -> Option<PaneViewState> {
+        None
+    }
+
+    /// Changes the working directory of the shell in this pane by
+    /// typing a `cd` command into it, pressing enter if `enter` is true.
+    /// Fails with the reason if the pane doesn't appear to be at a
+    /// shell prompt; see `changedir::change_dir_input`.
+    async fn change_dir(&self, dir: String, enter: bool) -> anyhow::Result<()> {
+        use std::io::Write;
+        let input = crate::changedir::change_dir_input(
+            &config::configuration().change_dir_shells,
+            self.get_foreground_process_name().as_deref(),
+            self.is_alt_screen_active(),
+            &dir,
+            enter,
+        )?;
+        self.writer().write_all(input.as_bytes())?;
+        Ok(())
+    }
+
+    /// Renders up to `SCROLLBACK_CHUNK_ROWS` of the `range` rows of this
+    /// pane, or of all of its rows if `range` is None, in `format`.
+    /// `header` is true for the first chunk of a document.
+    /// The rows that remain are returned with the chunk; see
+    /// `scrollback::save_scrollback`.
+    async fn render_scrollback(
+        &self,
+        format: ScrollbackFormat,
+        range: Option<Range<StableRowIndex>>,
+        header: bool,
+    ) -> anyhow::Result<ScrollbackChunk> {
+        let dims = self.get_dimensions();
+        let bottom = dims.physical_top + dims.viewport_rows as StableRowIndex;
+        // Rows that have been trimmed from the scrollback since the
+        // previous chunk are skipped
+        let range = match range {
+            Some(range) => range.start.max(dims.scrollback_top)..range.end.min(bottom),
+            None => dims.scrollback_top..bottom,
+        };
+        let end = range
+            .end
+            .min(range.start + crate::scrollback::SCROLLBACK_CHUNK_ROWS as StableRowIndex);
+
+        let mut text = if header {
+            crate::scrollback::render_header(format, &self.palette())
+        } else {
+            String::new()
+        };
+        if range.start < end {
+            let (_first_row, lines) = self.get_lines(range.start..end);
+            text.push_str(&crate::scrollback::render_lines(format, &lines));
+        }
+        let remaining = if end < range.end {
+            Some(end..range.end)
+        } else {
+            text.push_str(&crate::scrollback::render_footer(format));
+            None
+        };
+        Ok(ScrollbackChunk { text, remaining })
+    }
+
+    /// Opens the scrollback of this pane in an editor, in a split of its
+    /// tab, returning the id of the pane of the editor.  A pane that is
+    /// hosted by a multiplexer server has the server open it, unless
+    /// the request is `local`; see `editscrollback`.
+    async fn edit_scrollback(&self, request: EditScrollbackRequest) -> anyhow::Result<PaneId> {
+        crate::editscrollback::edit_here(self.pane_id(), request).await
+    }
+
+    /// Bookmarks `row`, or the row of the cursor if None, as `name`,
+    /// replacing any bookmark of the same name.  The bookmarks of panes
+    /// that are hosted by a multiplexer server are kept by the server.
+    async fn add_bookmark(
+        &self,
+        name: String,
+        row: Option<StableRowIndex>,
+    ) -> anyhow::Result<Bookmark> {
+        let dims = self.get_dimensions();
+        let row = row.unwrap_or_else(|| self.get_cursor_position().y);
+        let bottom = dims.physical_top + dims.viewport_rows as StableRowIndex;
+        if row < dims.scrollback_top || row >= bottom {
+            anyhow::bail!(
+                "row {} is outside of the rows {}..{} of the pane",
+                row,
+                dims.scrollback_top,
+                bottom
+            );
+        }
+        let (_first_row, lines) = self.get_lines(row..row + 1);
+        let bookmark = Bookmark {
+            name,
+            row,
+            text: lines
+                .first()
+                .map(|line| line.as_str().trim_end().to_string())
+                .unwrap_or_default(),
+            trimmed: false,
+        };
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        mux.record_bookmark(self.pane_id(), bookmark.clone());
+        Ok(bookmark)
+    }
+
+    /// Returns the bookmarks of this pane, in the order in which they
+    /// were added
+    async fn get_bookmarks(&self) -> anyhow::Result<Vec<Bookmark>> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        Ok(mux.recorded_bookmarks(self.pane_id(), self.get_dimensions().scrollback_top))
+    }
+
+    /// Adds a watcher that looks for a regex in the output of this
+    /// pane.  The output of panes that are hosted by a multiplexer
+    /// server is watched by the server.
+    async fn add_output_watcher(&self, spec: OutputWatcherSpec) -> anyhow::Result<WatcherId> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        mux.add_output_watcher(self.pane_id(), spec, None)
+    }
+
+    async fn remove_output_watcher(&self, id: WatcherId) -> anyhow::Result<()> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        if !mux.remove_output_watcher(self.pane_id(), id) {
+            anyhow::bail!("pane {} has no output watcher {}", self.pane_id(), id);
+        }
+        Ok(())
+    }
+
+    /// Returns the output watchers of this pane, in the order in which
+    /// they were added
+    async fn get_output_watchers(&self) -> anyhow::Result<Vec<OutputWatcherInfo>> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        Ok(mux.output_watchers(self.pane_id()))
+    }
+
+    /// Adds a marker that highlights a range of rows of this pane,
+    /// replacing any marker with the same id, and returns it along
+    /// with the id that it was given.  The markers of panes that are
+    /// hosted by a multiplexer server are kept by the server.
+    async fn add_marker(&self, marker: Marker) -> anyhow::Result<Marker> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        mux.record_marker(self.pane_id(), marker)
+    }
+
+    async fn remove_marker(&self, id: String) -> anyhow::Result<()> {
+        let mux = Mux::get().ok_or_else(|| anyhow::anyhow!("no mux"))?;
+        if !mux.remove_recorded_marker(self.pane_id(), &id) {
+            anyhow::bail!("pane {} has no marker {}", self.pane_id(), id);
+        }
+        Ok(())
+    }
+
+    /// Returns the markers of this pane, in the order in which they
+    /// were added.  This is called when rendering, so it must not
+    /// wait on the multiplexer server.
+    fn get_markers(&self) -> Vec<Marker> {
+        match Mux::get() {
+            Some(mux) => mux.recorded_markers(self.pane_id(), self.get_dimensions().scrollback_top),
+            None => vec![],
+        }
+    }
+
     /// Retrieve the set of semantic zones
     fn get_semantic_zones(&self) -> anyhow::Result<Vec<SemanticZone>> {
         Ok(vec![])
@@ -413,6 +722,12 @@ pub trait Pane: Downcast {
     fn is_mouse_grabbed(&self) -> bool;
     fn is_alt_screen_active(&self) -> bool;
 
+    /// Returns the modes that the application in the pane has set,
+    /// or None if the pane isn't backed by a terminal model
+    fn get_modes(&self) -> Option<TerminalModes> {
+        None
+    }
+
     fn set_clipboard(&self, _clipboard: &Arc<dyn Clipboard>) {}
     fn set_download_handler(&self, _handler: &Arc<dyn DownloadHandler>) {}
     fn set_config(&self, _config: Arc<dyn TerminalConfiguration>) {}
@@ -420,6 +735,47 @@ pub trait Pane: Downcast {
         None
     }
 
+    /// Returns the name of the systemd scope unit that contains
+    /// the processes of this pane, if any
+    fn get_systemd_scope(&self) -> Option<String> {
+        None
+    }
+
+    /// Pauses or resumes applying the output of a pane whose output is
+    /// parsed elsewhere, such as on a multiplexer server.
+    /// The output of panes that read from a local pty is paused by
+    /// the mux; see `Mux::set_pane_paused`.
+    fn set_output_paused(&self, _paused: bool) -> anyhow::Result<()> {
+        anyhow::bail!("the output of this pane cannot be paused")
+    }
+
+    fn is_output_paused(&self) -> bool {
+        false
+    }
+
+    /// Sets the colors that replace those of the palette of the window
+    /// when this pane is rendered, wherever it is shown.
+    /// Passing None restores the palette of the window.
+    fn set_palette_overrides(&self, _overrides: Option<Palette>) -> anyhow::Result<()> {
+        anyhow::bail!("the palette of this pane cannot be overridden")
+    }
+
+    fn get_palette_overrides(&self) -> Option<Palette> {
+        None
+    }
+
+    /// Returns the label of the `SpawnCommand` that created this pane,
+    /// if it had one
+    fn get_spawn_label(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the most recent sample of the resources consumed by
+    /// the processes in this pane, if resource sampling is enabled
+    fn get_resource_usage(&self) -> Option<PaneResourceUsage> {
+        None
+    }
+
     fn get_current_working_dir(&self) -> Option<Url>;
     fn get_foreground_process_name(&self) -> Option<String> {
         None
@@ -434,11 +790,7 @@ pub trait Pane: Downcast {
             self.send_paste(&text)?;
         } else {
             // It's pretty heavy, so we trickle it into the pty
-            let paste = Arc::new(Mutex::new(Paste {
-                pane_id: self.pane_id(),
-                text,
-                offset: 0,
-            }));
+            let paste = Arc::new(Mutex::new(Paste::new(self.pane_id(), text)));
             paste_next_chunk(&paste);
         }
         Ok(())
@@ -446,6 +798,72 @@ pub trait Pane: Downcast {
 }
 impl_downcast!(Pane);
 
+/// Returns the text of a semantic zone of the pane.
+/// Rows of the zone that have been trimmed from the scrollback since the
+/// zone was obtained are omitted, so the text may be truncated at the
+/// start, or empty if the whole zone has been trimmed.
+pub fn semantic_zone_text(pane: &dyn Pane, zone: &SemanticZone) -> String {
+    let rows = zone.start_y..zone.end_y + 1;
+    let mut s = String::new();
+    let mut last_was_wrapped = false;
+
+    for line in pane.get_logical_lines(rows.clone()) {
+        let last_idx = line.physical_lines.len().saturating_sub(1);
+        for (idx, phys) in line.physical_lines.iter().enumerate() {
+            let this_row = line.first_row + idx as StableRowIndex;
+            // get_lines substitutes other rows for rows that have been
+            // trimmed, so we must only consider those within the zone
+            if !rows.contains(&this_row) {
+                continue;
+            }
+            if this_row != zone.start_y && !s.is_empty() && !last_was_wrapped {
+                s.push('\n');
+            }
+            let start = if this_row == zone.start_y {
+                zone.start_x
+            } else {
+                0
+            };
+            let end = if this_row == zone.end_y {
+                zone.end_x + 1
+            } else {
+                usize::max_value()
+            };
+            let col_span = phys.columns_as_str(start..end);
+            if idx == last_idx {
+                s.push_str(col_span.trim_end());
+            } else {
+                s.push_str(&col_span);
+            }
+            last_was_wrapped = phys.last_cell_was_wrapped();
+        }
+    }
+
+    s
+}
+
+/// Given the semantic zones of a pane, returns the Output zone of the
+/// most recently completed command; that is the zone that immediately
+/// precedes the row on which the last prompt starts.
+/// Returns None if the pane has no Prompt zones, which is the case
+/// when shell integration is not in use, or if that command produced
+/// no output.
+pub fn last_command_output_zone(zones: &[SemanticZone]) -> Option<SemanticZone> {
+    let prompt_row = zones
+        .iter()
+        .rev()
+        .find(|zone| zone.semantic_type == SemanticType::Prompt)?
+        .start_y;
+    // Skip over the prompt itself, which may be made up of several
+    // zones, for example when a right prompt is in use
+    let zone = zones.iter().rev().find(|zone| zone.start_y < prompt_row)?;
+    if zone.semantic_type == SemanticType::Output {
+        Some(*zone)
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -971,4 +1389,76 @@ mod test {
             _ => unreachable!(),
         }
     }
+
+    fn zone(
+        start_y: StableRowIndex,
+        start_x: usize,
+        end_y: StableRowIndex,
+        end_x: usize,
+        semantic_type: SemanticType,
+    ) -> SemanticZone {
+        SemanticZone {
+            start_y,
+            start_x,
+            end_y,
+            end_x,
+            semantic_type,
+        }
+    }
+
+    #[test]
+    fn last_command_output() {
+        let output = zone(1, 0, 2, 4, SemanticType::Output);
+        let zones = vec![
+            zone(0, 0, 0, 1, SemanticType::Prompt),
+            zone(0, 2, 0, 5, SemanticType::Input),
+            output,
+            zone(3, 0, 3, 1, SemanticType::Prompt),
+            zone(3, 2, 3, 9, SemanticType::Input),
+            zone(3, 10, 3, 19, SemanticType::Prompt),
+        ];
+        assert_eq!(last_command_output_zone(&zones), Some(output));
+
+        // A command that produced no output
+        let zones = vec![
+            zone(0, 0, 0, 1, SemanticType::Prompt),
+            zone(0, 2, 0, 5, SemanticType::Input),
+            zone(1, 0, 1, 4, SemanticType::Output),
+            zone(2, 0, 2, 1, SemanticType::Prompt),
+            zone(2, 2, 2, 5, SemanticType::Input),
+            zone(3, 0, 3, 1, SemanticType::Prompt),
+        ];
+        assert_eq!(last_command_output_zone(&zones), None);
+
+        // No shell integration
+        let zones = vec![zone(0, 0, 3, 9, SemanticType::Output)];
+        assert_eq!(last_command_output_zone(&zones), None);
+    }
+
+    #[test]
+    fn zone_text() {
+        let text = "$ ls\nfoo bar baz\nqux\n$ ";
+        let lines = physical_lines_from_text(text, 8);
+        let pane = FakePane { lines };
+
+        // "foo bar baz" is wrapped across rows 1 and 2
+        let output = zone(1, 0, 3, 2, SemanticType::Output);
+        assert_eq!(semantic_zone_text(&pane, &output), "foo bar baz\nqux");
+
+        let input = zone(0, 2, 0, 3, SemanticType::Input);
+        assert_eq!(semantic_zone_text(&pane, &input), "ls");
+    }
+
+    #[test]
+    fn paste_tracking() {
+        let pane_id = PaneId::MAX;
+        assert!(!paste_in_flight(pane_id));
+        let first = Paste::new(pane_id, "a".repeat(PASTE_CHUNK_SIZE * 2));
+        let second = Paste::new(pane_id, "b".repeat(PASTE_CHUNK_SIZE * 2));
+        assert!(paste_in_flight(pane_id));
+        drop(first);
+        assert!(paste_in_flight(pane_id));
+        drop(second);
+        assert!(!paste_in_flight(pane_id));
+    }
 }
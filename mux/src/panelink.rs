@@ -0,0 +1,173 @@
+//! Groups of panes that are linked together, wherever they are in the
+//! windows and tabs of the mux.
+//! A group has a policy that says what happens to the other members
+//! when something happens to one of them.  A pane belongs to at most
+//! one group of each policy.
+use crate::pane::PaneId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+pub type LinkGroupId = usize;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, FromDynamic, ToDynamic,
+)]
+pub enum LinkPolicy {
+    /// Focusing one of the panes activates the tabs of the others
+    /// in their windows
+    FocusTogether,
+    /// Closing one of the panes asks whether to close the others
+    CloseTogether,
+}
+luahelper::impl_lua_conversion_dynamic!(LinkPolicy);
+
+impl std::str::FromStr for LinkPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "FocusTogether" | "focus-together" => Ok(Self::FocusTogether),
+            "CloseTogether" | "close-together" => Ok(Self::CloseTogether),
+            _ => anyhow::bail!(
+                "invalid link policy {}; expected focus-together or close-together",
+                s
+            ),
+        }
+    }
+}
+
+/// Describes a group that a pane belongs to, as reported to clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaneLink {
+    pub group_id: LinkGroupId,
+    pub policy: LinkPolicy,
+}
+
+struct LinkGroup {
+    policy: LinkPolicy,
+    panes: Vec<PaneId>,
+}
+
+#[derive(Default)]
+pub struct PaneLinks {
+    groups: HashMap<LinkGroupId, LinkGroup>,
+    next_group_id: LinkGroupId,
+}
+
+impl PaneLinks {
+    fn group_of(&self, pane_id: PaneId, policy: LinkPolicy) -> Option<LinkGroupId> {
+        self.groups
+            .iter()
+            .find(|(_, group)| group.policy == policy && group.panes.contains(&pane_id))
+            .map(|(id, _)| *id)
+    }
+
+    /// Links `panes` with `policy`.  Groups with the same policy that
+    /// already contain any of them are merged into a single group,
+    /// whose id is returned.
+    pub fn link(&mut self, panes: &[PaneId], policy: LinkPolicy) -> LinkGroupId {
+        let mut members = vec![];
+        let mut group_id = None;
+        for &pane_id in panes {
+            match self.group_of(pane_id, policy) {
+                Some(existing) => {
+                    let group = self.groups.remove(&existing).expect("group exists");
+                    members.extend(group.panes);
+                    // Keep the id of the oldest group, so that ids that
+                    // were reported earlier stay valid
+                    group_id = Some(group_id.map_or(existing, |id: LinkGroupId| id.min(existing)));
+                }
+                None => members.push(pane_id),
+            }
+        }
+        members.sort();
+        members.dedup();
+
+        let group_id = group_id.unwrap_or_else(|| {
+            let id = self.next_group_id;
+            self.next_group_id += 1;
+            id
+        });
+        self.groups.insert(
+            group_id,
+            LinkGroup {
+                policy,
+                panes: members,
+            },
+        );
+        group_id
+    }
+
+    /// Removes a pane from its groups, dropping groups that are left
+    /// with fewer than two members
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        for group in self.groups.values_mut() {
+            group.panes.retain(|&p| p != pane_id);
+        }
+        self.groups.retain(|_, group| group.panes.len() > 1);
+    }
+
+    /// Returns the other members of the group of `pane_id` with `policy`
+    pub fn partners(&self, pane_id: PaneId, policy: LinkPolicy) -> Vec<PaneId> {
+        match self.group_of(pane_id, policy) {
+            Some(group_id) => self.groups[&group_id]
+                .panes
+                .iter()
+                .copied()
+                .filter(|&p| p != pane_id)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn links_for_pane(&self, pane_id: PaneId) -> Vec<PaneLink> {
+        let mut links: Vec<PaneLink> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.panes.contains(&pane_id))
+            .map(|(&group_id, group)| PaneLink {
+                group_id,
+                policy: group.policy,
+            })
+            .collect();
+        links.sort_by_key(|link| link.group_id);
+        links
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn linking_merges_groups() {
+        let mut links = PaneLinks::default();
+        let a = links.link(&[1, 2], LinkPolicy::FocusTogether);
+        let b = links.link(&[3, 4], LinkPolicy::FocusTogether);
+        assert_ne!(a, b);
+        links.link(&[1, 2], LinkPolicy::CloseTogether);
+
+        assert_eq!(links.link(&[4, 2], LinkPolicy::FocusTogether), a);
+        assert_eq!(links.partners(1, LinkPolicy::FocusTogether), vec![2, 3, 4]);
+        assert_eq!(links.partners(1, LinkPolicy::CloseTogether), vec![2]);
+        assert_eq!(links.partners(3, LinkPolicy::CloseTogether), vec![]);
+        assert_eq!(links.links_for_pane(2).len(), 2);
+    }
+
+    #[test]
+    fn removing_panes_drops_small_groups() {
+        let mut links = PaneLinks::default();
+        links.link(&[1, 2, 3], LinkPolicy::FocusTogether);
+        links.link(&[1, 2], LinkPolicy::CloseTogether);
+
+        links.remove_pane(1);
+        assert_eq!(links.partners(2, LinkPolicy::FocusTogether), vec![3]);
+        assert!(links
+            .links_for_pane(2)
+            .iter()
+            .all(|l| l.policy == LinkPolicy::FocusTogether));
+
+        links.remove_pane(3);
+        assert!(links.links_for_pane(2).is_empty());
+    }
+}
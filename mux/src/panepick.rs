@@ -0,0 +1,99 @@
+//! Lets scripts ask the user to choose panes, such as the pane that a
+//! command should send its output to.  The request is handed to the
+//! frontend with `MuxNotification::PickPanes`; the frontend shows the
+//! pane selector and reports the choice with `Mux::finish_pane_pick`.
+//! Only a frontend that has enabled picking can serve a pick, so that
+//! a pick fails right away in a headless mux server rather than
+//! waiting forever.
+use crate::pane::PaneId;
+use smol::channel::{bounded, Receiver, Sender};
+use std::collections::HashMap;
+
+pub type PickId = usize;
+
+/// A request to the frontend to let the user choose panes
+#[derive(Debug, Clone, PartialEq)]
+pub struct PickPanesRequest {
+    pub id: PickId,
+    /// Shown along with the pane selector
+    pub prompt: Option<String>,
+    /// Whether several panes may be chosen
+    pub multi: bool,
+}
+
+#[derive(Default)]
+pub struct PanePicks {
+    enabled: bool,
+    next_id: PickId,
+    pending: HashMap<PickId, Sender<Vec<PaneId>>>,
+}
+
+impl PanePicks {
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Starts a pick.  The receiver yields the chosen panes, or fails
+    /// if the pick was cancelled.
+    pub fn start(
+        &mut self,
+        prompt: Option<String>,
+        multi: bool,
+    ) -> anyhow::Result<(PickPanesRequest, Receiver<Vec<PaneId>>)> {
+        anyhow::ensure!(
+            self.enabled,
+            "panes can only be picked in the wezterm GUI; \
+             this mux has no GUI to show the pane selector in"
+        );
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = bounded(1);
+        self.pending.insert(id, tx);
+        Ok((PickPanesRequest { id, prompt, multi }, rx))
+    }
+
+    /// Completes the pick with the chosen `panes`, or cancels it if
+    /// None.  Returns false if the pick is no longer pending.
+    pub fn finish(&mut self, id: PickId, panes: Option<Vec<PaneId>>) -> bool {
+        match self.pending.remove(&id) {
+            Some(tx) => {
+                if let Some(panes) = panes {
+                    tx.try_send(panes).ok();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use smol::future::block_on;
+
+    #[test]
+    fn needs_a_frontend() {
+        let mut picks = PanePicks::default();
+        assert!(picks.start(None, false).is_err());
+    }
+
+    #[test]
+    fn finish_and_cancel() {
+        let mut picks = PanePicks::default();
+        picks.enable();
+        let (first, first_rx) = picks
+            .start(Some("send output to:".to_string()), true)
+            .unwrap();
+        let (second, second_rx) = picks.start(None, false).unwrap();
+        assert_ne!(first.id, second.id);
+        assert!(first.multi);
+
+        assert!(picks.finish(first.id, Some(vec![3, 1])));
+        assert_eq!(block_on(first_rx.recv()).unwrap(), vec![3, 1]);
+        assert!(!picks.finish(first.id, None));
+
+        assert!(picks.finish(second.id, None));
+        assert!(block_on(second_rx.recv()).is_err());
+    }
+}
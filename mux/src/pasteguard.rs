@@ -0,0 +1,214 @@
+//! Holds back a paste into the shell of a pane until the user has
+//! confirmed it, so that a clipboard that happens to hold several
+//! lines isn't run by a shell, line by line, before it can be read.
+//! `paste_guard`, which a domain can override, chooses which pastes
+//! are held; see `PasteGuard`.
+//! A pane is considered to be at a shell when its foreground process is
+//! one of the `change_dir_shells`.  The foreground process is asked of
+//! the domain, so that the panes of a remote mux are guarded too; if it
+//! can't be determined, the paste is sent without asking.
+//! Whether the shell runs as root is judged from the `WEZTERM_USER`
+//! user var if the shell sets it, and otherwise from whether its
+//! prompt ends with `#`.
+use crate::pane::Pane;
+use crate::Mux;
+use config::{ChangeDirShell, PasteGuard};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The user var in which shell integration reports the user that the
+/// shell runs as
+pub const USER_VAR: &str = "WEZTERM_USER";
+
+/// The most lines of a paste that are shown when asking to confirm it
+const MAX_PREVIEW_LINES: usize = 12;
+
+/// Returns true if `text` could be held by `guard`, before anything is
+/// known about the pane that it is pasted into
+pub fn might_hold(guard: PasteGuard, text: &str) -> bool {
+    match guard {
+        PasteGuard::Never => false,
+        PasteGuard::Always => !text.is_empty(),
+        PasteGuard::MultilineOnly | PasteGuard::RootOnly => text.contains(&['\n', '\r'][..]),
+    }
+}
+
+/// Returns true if the shell appears to run as root, judging from the
+/// user vars of its pane and the text of its prompt
+pub fn is_elevated(user_vars: &HashMap<String, String>, prompt: &str) -> bool {
+    match user_vars.get(USER_VAR) {
+        Some(user) => user == "root",
+        None => prompt.trim_end().ends_with('#'),
+    }
+}
+
+/// Returns the reason that pasting `text` into a pane whose foreground
+/// process is `foreground` must be confirmed, or None if it needn't be
+pub fn hold_reason(
+    guard: PasteGuard,
+    shells: &HashMap<String, ChangeDirShell>,
+    foreground: &str,
+    elevated: bool,
+    text: &str,
+) -> Option<String> {
+    if !might_hold(guard, text) {
+        return None;
+    }
+    let shell = crate::changedir::shell_name(foreground);
+    if !shells.contains_key(&shell) {
+        return None;
+    }
+    if guard == PasteGuard::RootOnly && !elevated {
+        return None;
+    }
+    let lines = text.split_inclusive('\n').count();
+    Some(format!(
+        "this paste of {} line{} is about to be sent to {}{}",
+        lines,
+        if lines == 1 { "" } else { "s" },
+        shell,
+        if elevated {
+            ", which appears to be running as root"
+        } else {
+            ""
+        }
+    ))
+}
+
+/// Returns the lines of `text` with their control characters, including
+/// the newline that ends them, escaped, so that exactly what would be
+/// sent can be seen
+pub fn preview(text: &str) -> String {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut preview = String::new();
+    for line in lines.iter().take(MAX_PREVIEW_LINES) {
+        preview.push_str("  ");
+        for c in line.chars() {
+            match c {
+                '\n' => preview.push_str("\\n"),
+                '\r' => preview.push_str("\\r"),
+                '\t' => preview.push_str("\\t"),
+                '\\' => preview.push_str("\\\\"),
+                c if c.is_control() => preview.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                c => preview.push(c),
+            }
+        }
+        preview.push('\n');
+    }
+    if lines.len() > MAX_PREVIEW_LINES {
+        preview.push_str(&format!(
+            "  ... and {} more lines\n",
+            lines.len() - MAX_PREVIEW_LINES
+        ));
+    }
+    preview
+}
+
+/// Returns the text of the line that the cursor of `pane` is on, up
+/// to the cursor, which is the prompt if the shell is waiting for input
+fn prompt_text(pane: &Rc<dyn Pane>) -> String {
+    let cursor = pane.get_cursor_position();
+    let (_, lines) = pane.get_lines(cursor.y..cursor.y + 1);
+    lines
+        .first()
+        .map(|line| line.columns_as_str(0..cursor.x))
+        .unwrap_or_default()
+}
+
+/// Returns the paste guard of `pane`: that of its domain, if the
+/// domain overrides `paste_guard`
+pub fn pane_paste_guard(pane: &Rc<dyn Pane>) -> PasteGuard {
+    let config = config::configuration();
+    Mux::get()
+        .and_then(|mux| mux.get_domain(pane.domain_id()))
+        .and_then(|domain| config.domain_paste_guard(domain.domain_name()))
+        .unwrap_or(config.paste_guard)
+}
+
+/// Returns the reason that pasting `text` into `pane`, whose paste guard
+/// is `guard`, must be confirmed by the user first, or None if it can
+/// be sent right away.
+/// If the foreground process of the pane can't be determined, the
+/// paste is not held.
+pub async fn check_paste(pane: &Rc<dyn Pane>, guard: PasteGuard, text: &str) -> Option<String> {
+    if !might_hold(guard, text) {
+        return None;
+    }
+
+    let foreground = match pane.query_foreground_process().await {
+        Ok(Some(info)) => info,
+        Ok(None) => {
+            log::info!(
+                "paste_guard: the foreground process of pane {} is not known, \
+                 so the paste is sent without confirmation",
+                pane.pane_id()
+            );
+            return None;
+        }
+        Err(err) => {
+            log::warn!(
+                "paste_guard: failed to get the foreground process of pane {}, \
+                 so the paste is sent without confirmation: {:#}",
+                pane.pane_id(),
+                err
+            );
+            return None;
+        }
+    };
+    let elevated = is_elevated(&pane.copy_user_vars(), &prompt_text(pane));
+    hold_reason(
+        guard,
+        &config::configuration().change_dir_shells,
+        &foreground.executable,
+        elevated,
+        text,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guards() {
+        let shells = config::Config::default_config().change_dir_shells;
+        let reason = |guard, foreground, elevated, text| {
+            hold_reason(guard, &shells, foreground, elevated, text).is_some()
+        };
+        assert!(!reason(PasteGuard::Never, "/bin/bash", true, "ls\n"));
+        assert!(reason(PasteGuard::Always, "/bin/bash", false, "ls"));
+        assert!(!reason(PasteGuard::MultilineOnly, "/bin/bash", false, "ls"));
+        assert!(reason(
+            PasteGuard::MultilineOnly,
+            "/bin/bash",
+            false,
+            "ls\n"
+        ));
+        assert!(reason(PasteGuard::MultilineOnly, "-zsh", false, "ls\r"));
+        assert!(!reason(PasteGuard::RootOnly, "/bin/bash", false, "ls\n"));
+        assert!(reason(PasteGuard::RootOnly, "/bin/bash", true, "ls\n"));
+        // Only shells are guarded
+        assert!(!reason(PasteGuard::Always, "/usr/bin/vim", true, "ls\n"));
+    }
+
+    #[test]
+    fn elevation() {
+        let mut vars = HashMap::new();
+        assert!(is_elevated(&vars, "[root@host ~]# "));
+        assert!(!is_elevated(&vars, "user@host:~$ "));
+        // The user var is trusted over the prompt
+        vars.insert(USER_VAR.to_string(), "user".to_string());
+        assert!(!is_elevated(&vars, "[root@host ~]# "));
+        vars.insert(USER_VAR.to_string(), "root".to_string());
+        assert!(is_elevated(&vars, "$ "));
+    }
+
+    #[test]
+    fn previews() {
+        assert_eq!(preview("ls\n"), "  ls\\n\n");
+        assert_eq!(preview("a\tb\r\nc"), "  a\\tb\\r\\n\n  c\n");
+        assert_eq!(preview("\x1b[201~"), "  \\u{1b}[201~\n");
+        let long = "x\n".repeat(MAX_PREVIEW_LINES + 3);
+        assert!(preview(&long).ends_with("  ... and 3 more lines\n"));
+    }
+}
@@ -0,0 +1,208 @@
+//! A pool of default-prog shells that a local domain spawns ahead of
+//! time, so that opening several panes at once doesn't have to wait
+//! for each of their shells to start up in turn.
+//! A pooled shell is spawned in the default cwd and without a
+//! `WEZTERM_PANE` value, because neither is known until a pane claims
+//! it.  The claiming pane writes a handshake line to the input of the
+//! shell that changes to the requested directory, exports
+//! `WEZTERM_PANE` and clears the screen, so only shells that accept
+//! POSIX syntax are pooled.
+use crate::pane::PaneId;
+use portable_pty::{Child, CommandBuilder, MasterPty};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The shells that understand the handshake line
+const POSIX_SHELLS: &[&str] = &["sh", "ash", "bash", "dash", "ksh", "mksh", "zsh"];
+
+/// Returns true if `cmd` runs a shell that can be pooled
+pub fn is_pooled_shell(cmd: &CommandBuilder) -> bool {
+    let program = if cmd.is_default_prog() {
+        match cmd.get_shell() {
+            Ok(shell) => shell,
+            Err(_) => return false,
+        }
+    } else {
+        match cmd.get_argv().first() {
+            Some(program) => program.to_string_lossy().to_string(),
+            None => return false,
+        }
+    };
+    Path::new(&program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| POSIX_SHELLS.contains(&name.trim_start_matches('-')))
+        .unwrap_or(false)
+}
+
+/// Returns the portion of `cmd` that a pooled shell must match in
+/// order to stand in for it: everything other than the cwd, the label
+/// and `WEZTERM_PANE`, which are applied when the shell is claimed
+pub fn pool_key(cmd: &CommandBuilder) -> CommandBuilder {
+    let mut key = cmd.clone();
+    key.clear_cwd();
+    key.clear_label();
+    key.env_remove("WEZTERM_PANE");
+    key
+}
+
+/// Returns the line that is written to a claimed shell.
+/// The leading space keeps it out of the history of shells that are
+/// configured to ignore such lines.
+/// Returns None if `cwd` cannot be expressed in the handshake.
+pub fn handshake(pane_id: PaneId, cwd: Option<&OsStr>) -> Option<String> {
+    let cd = match cwd {
+        Some(cwd) => format!(" cd -- {};", shell_words::quote(cwd.to_str()?)),
+        None => String::new(),
+    };
+    Some(format!(
+        "{} export WEZTERM_PANE={}; printf '\\033[H\\033[2J\\033[3J'\n",
+        cd, pane_id
+    ))
+}
+
+pub struct PrewarmedShell {
+    key: CommandBuilder,
+    pub child: Box<dyn Child + Send + Sync>,
+    pub master: Box<dyn MasterPty>,
+    spawned: Instant,
+}
+
+impl PrewarmedShell {
+    pub fn new(
+        cmd: &CommandBuilder,
+        child: Box<dyn Child + Send + Sync>,
+        master: Box<dyn MasterPty>,
+    ) -> Self {
+        Self {
+            key: pool_key(cmd),
+            child,
+            master,
+            spawned: Instant::now(),
+        }
+    }
+
+    fn expires_in(&self, idle_timeout: Option<Duration>) -> Option<Duration> {
+        idle_timeout.map(|timeout| timeout.saturating_sub(self.spawned.elapsed()))
+    }
+
+    /// Returns false if the shell has exited or has been idle for too long
+    fn is_usable(&mut self, idle_timeout: Option<Duration>) -> bool {
+        if self.expires_in(idle_timeout) == Some(Duration::ZERO) {
+            return false;
+        }
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Kills the shell and reaps it in the background
+    fn discard(self) {
+        let mut child = self.child;
+        if let Err(err) = child.kill() {
+            log::trace!("failed to kill prewarmed shell {:?}: {:#}", child, err);
+        }
+        std::thread::spawn(move || child.wait());
+    }
+}
+
+#[derive(Default)]
+pub struct PrewarmPool {
+    shells: Vec<PrewarmedShell>,
+    /// True while a refill of the pool is pending
+    pub refill_scheduled: bool,
+    /// True while a timer is pending that will evict idle shells
+    pub eviction_scheduled: bool,
+}
+
+impl PrewarmPool {
+    pub fn len(&self) -> usize {
+        self.shells.len()
+    }
+
+    pub fn push(&mut self, shell: PrewarmedShell) {
+        self.shells.push(shell);
+    }
+
+    /// Discards the shells that have exited or been idle for too long
+    pub fn evict(&mut self, idle_timeout: Option<Duration>) {
+        let mut usable = vec![];
+        for mut shell in self.shells.drain(..) {
+            if shell.is_usable(idle_timeout) {
+                usable.push(shell);
+            } else {
+                shell.discard();
+            }
+        }
+        self.shells = usable;
+    }
+
+    /// Discards all of the shells in the pool
+    pub fn clear(&mut self) {
+        for shell in self.shells.drain(..) {
+            shell.discard();
+        }
+    }
+
+    /// Returns the time until the next shell in the pool becomes idle
+    /// for too long
+    pub fn next_expiry(&self, idle_timeout: Option<Duration>) -> Option<Duration> {
+        self.shells
+            .iter()
+            .filter_map(|shell| shell.expires_in(idle_timeout))
+            .min()
+    }
+
+    /// Removes and returns a usable shell that can stand in for `cmd`
+    pub fn claim(
+        &mut self,
+        cmd: &CommandBuilder,
+        idle_timeout: Option<Duration>,
+    ) -> Option<PrewarmedShell> {
+        self.evict(idle_timeout);
+        let key = pool_key(cmd);
+        let idx = self.shells.iter().position(|shell| shell.key == key)?;
+        Some(self.shells.remove(idx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pooled_shells() {
+        let mut cmd = CommandBuilder::new("/usr/bin/zsh");
+        cmd.arg("-l");
+        assert!(is_pooled_shell(&cmd));
+        assert!(is_pooled_shell(&CommandBuilder::new("-bash")));
+        assert!(!is_pooled_shell(&CommandBuilder::new("fish")));
+        assert!(!is_pooled_shell(&CommandBuilder::new("htop")));
+    }
+
+    #[test]
+    fn key_ignores_claim_time_values() {
+        let mut pooled = CommandBuilder::new("bash");
+        pooled.env_remove("WEZTERM_PANE");
+        let mut claimed = pooled.clone();
+        claimed.cwd("/tmp");
+        claimed.label("shell");
+        claimed.env("WEZTERM_PANE", "4");
+        assert_eq!(pool_key(&pooled), pool_key(&claimed));
+
+        claimed.env("EDITOR", "vim");
+        assert_ne!(pool_key(&pooled), pool_key(&claimed));
+    }
+
+    #[test]
+    fn handshake_line() {
+        assert_eq!(
+            handshake(3, Some(OsStr::new("/home/me/my project"))).unwrap(),
+            " cd -- '/home/me/my project'; export WEZTERM_PANE=3; \
+             printf '\\033[H\\033[2J\\033[3J'\n"
+        );
+        assert_eq!(
+            handshake(3, None).unwrap(),
+            " export WEZTERM_PANE=3; printf '\\033[H\\033[2J\\033[3J'\n"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Moves a process that is running in a pty that was created elsewhere
+//! into an existing pane, in the manner of `retty` or `reptyr`.
+//!
+//! `wezterm cli adopt-pty` asks the mux for a handoff socket, connects
+//! to it and passes the master end of the pty with `SCM_RIGHTS`.  The
+//! mux swaps the pty into the pane with `LocalPane::replace_pty` and
+//! writes back either `ok` or the reason that the pty was refused.
+//!
+//! The pane keeps its id, scrollback and place in its tab, but the
+//! terminal state that the adopted process set up in its previous
+//! terminal is not carried over, so continuity of the scrollback is
+//! best-effort.
+use crate::localpane::LocalPane;
+use crate::pane::PaneId;
+use crate::Mux;
+use anyhow::{anyhow, bail, Context};
+use filedescriptor::FileDescriptor;
+use portable_pty::{Child, ChildKiller, ExitStatus};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a handoff socket waits for the pty to be passed to it
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often an adopted process is checked for termination
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static HANDOFF_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The reply written to the handoff socket when the pty was adopted
+pub const HANDOFF_OK: &str = "ok";
+
+/// Options for adopting a pty into a pane
+#[derive(Debug, Clone, Copy)]
+pub struct AdoptPtyOptions {
+    pub pane_id: PaneId,
+    /// The process to track; defaults to the foreground process group
+    /// leader of the pty
+    pub pid: Option<u32>,
+    /// Whether to soft-reset the terminal before parsing output from
+    /// the new pty
+    pub reset_parser: bool,
+}
+
+/// A process that we did not spawn and so cannot wait for; it is
+/// polled until it no longer exists.  Its exit status can't be
+/// known, so it is reported as a successful exit.
+#[derive(Debug, Clone)]
+struct AdoptedChild {
+    pid: u32,
+}
+
+impl AdoptedChild {
+    fn is_alive(&self) -> bool {
+        let res = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+        res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+impl ChildKiller for AdoptedChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        if unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGHUP) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+impl Child for AdoptedChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        if self.is_alive() {
+            Ok(None)
+        } else {
+            Ok(Some(ExitStatus::with_exit_code(0)))
+        }
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        while self.is_alive() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        Ok(ExitStatus::with_exit_code(0))
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.pid)
+    }
+}
+
+impl Mux {
+    /// Replaces the pty of a local pane with `fd`, which must be the
+    /// master end of a pty
+    pub fn adopt_pty(&self, options: AdoptPtyOptions, fd: FileDescriptor) -> anyhow::Result<()> {
+        let pane = self
+            .get_pane(options.pane_id)
+            .ok_or_else(|| anyhow!("no such pane {}", options.pane_id))?;
+        let local = pane
+            .downcast_ref::<LocalPane>()
+            .ok_or_else(|| anyhow!("pane {} is not a local pane", options.pane_id))?;
+        let pty = portable_pty::unix::master_pty_from_fd(fd)?;
+        let pid = match options.pid {
+            Some(pid) => pid,
+            None => pty
+                .process_group_leader()
+                .map(|pid| pid as u32)
+                .ok_or_else(|| {
+                    anyhow!("cannot determine the process that uses the pty; specify its pid")
+                })?,
+        };
+        let child = AdoptedChild { pid };
+        if !child.is_alive() {
+            bail!("process {} does not exist", pid);
+        }
+        local.replace_pty(pty, Box::new(child), options.reset_parser)?;
+        log::info!(
+            "pane {} adopted a pty with process {}",
+            options.pane_id,
+            pid
+        );
+        Ok(())
+    }
+}
+
+/// Creates a socket that accepts a single pty for `options.pane_id`
+/// and returns its path.  The socket is removed once a pty has been
+/// passed to it, or after `HANDOFF_TIMEOUT`.
+pub fn listen_for_pty(options: AdoptPtyOptions) -> anyhow::Result<PathBuf> {
+    let path = config::RUNTIME_DIR.join(format!(
+        "pty-handoff-{}-{}.sock",
+        unsafe { libc::getpid() },
+        HANDOFF_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::remove_file(&path).ok();
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("binding {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+
+    let socket_path = path.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = accept_pty(listener, options) {
+            log::error!("pty handoff for pane {}: {:#}", options.pane_id, err);
+        }
+        std::fs::remove_file(&socket_path).ok();
+    });
+
+    Ok(path)
+}
+
+fn accept_pty(listener: UnixListener, options: AdoptPtyOptions) -> anyhow::Result<()> {
+    let deadline = Instant::now() + HANDOFF_TIMEOUT;
+    let mut stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    bail!("no pty was passed within {:?}", HANDOFF_TIMEOUT);
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => return Err(err).context("accepting pty handoff"),
+        }
+    };
+    drop(listener);
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let result = recv_fd(&stream).and_then(|fd| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        promise::spawn::spawn_into_main_thread(async move {
+            let mux = Mux::get().unwrap();
+            tx.send(mux.adopt_pty(options, fd)).ok();
+        })
+        .detach();
+        rx.recv()
+            .map_err(|_| anyhow!("the mux went away while adopting the pty"))?
+    });
+
+    let reply = match &result {
+        Ok(()) => HANDOFF_OK.to_string(),
+        Err(err) => format!("{:#}", err),
+    };
+    stream.write_all(reply.as_bytes())?;
+    result
+}
+
+/// Passes `fd` to the handoff socket at `path` and returns once the
+/// mux has adopted it
+pub fn hand_off_pty(path: &std::path::Path, fd: RawFd) -> anyhow::Result<()> {
+    let mut stream =
+        UnixStream::connect(path).with_context(|| format!("connecting to {}", path.display()))?;
+    send_fd(&stream, fd)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if reply != HANDOFF_OK {
+        bail!("{}", reply);
+    }
+    Ok(())
+}
+
+/// Sends `fd` over `stream` as `SCM_RIGHTS` ancillary data
+pub fn send_fd(stream: &UnixStream, fd: RawFd) -> anyhow::Result<()> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    // u64 to satisfy the alignment of cmsghdr
+    let mut control = vec![0u64; (space + 7) / 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("sending file descriptor");
+    }
+    Ok(())
+}
+
+/// Receives a file descriptor that was sent with `send_fd`.
+/// Any additional descriptors in the same message are closed.
+pub fn recv_fd(stream: &UnixStream) -> anyhow::Result<FileDescriptor> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(4 * std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u64; (space + 7) / 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let len = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error()).context("receiving file descriptor");
+    }
+
+    let mut received = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    let fd = std::ptr::read_unaligned(data.add(i));
+                    received.push(FileDescriptor::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if received.is_empty() {
+        if len == 0 {
+            bail!("the connection was closed without passing a file descriptor");
+        }
+        bail!("no file descriptor was passed");
+    }
+    Ok(received.remove(0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pass_fd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut pipe = filedescriptor::Pipe::new().unwrap();
+        send_fd(&a, pipe.write.as_raw_fd()).unwrap();
+        let mut write = recv_fd(&b).unwrap();
+        assert_ne!(write.as_raw_fd(), pipe.write.as_raw_fd());
+        drop(pipe.write);
+        write.write_all(b"hello").unwrap();
+        drop(write);
+        let mut text = String::new();
+        pipe.read.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello");
+    }
+
+    #[test]
+    fn recv_without_fd() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        a.write_all(b"x").unwrap();
+        assert!(recv_fd(&b).is_err());
+        drop(a);
+        assert!(recv_fd(&b).is_err());
+    }
+
+    #[test]
+    fn adopted_child_terminates() {
+        let mut process = std::process::Command::new("true").spawn().unwrap();
+        let mut child = AdoptedChild { pid: process.id() };
+        process.wait().unwrap();
+        assert!(child.try_wait().unwrap().is_some());
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,149 @@
+//! Supervision of connections that drop and are made again.
+//! The delay between attempts backs off, and a pane whose connection
+//! was lost counts down to the next attempt, letting the user retry
+//! straight away or give up and close the pane.
+//! This is shared by the multiplexer client and by the ssh domains
+//! that talk to the remote host directly, such as those that are
+//! created by `wezterm ssh`.
+use std::time::{Duration, Instant};
+use termwiz::color::ColorAttribute;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+use termwiz::surface::Change;
+use termwiz::terminal::Terminal;
+
+/// How often the countdown to the next attempt is updated
+const TICK: Duration = Duration::from_secs(1);
+
+/// The delay between successive attempts to reconnect, which doubles
+/// after each failure up to a maximum
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(10))
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            next: base,
+        }
+    }
+
+    /// Returns the delay before the next attempt, and doubles the
+    /// delay before the one after it
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next + self.next).min(self.max);
+        delay
+    }
+
+    /// Starts again from the base delay, once a connection succeeded
+    pub fn reset(&mut self) {
+        self.next = self.base;
+    }
+}
+
+/// What to do once the countdown to the next attempt has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryChoice {
+    Retry,
+    Close,
+}
+
+/// The line that is shown while waiting for the next attempt
+pub fn retry_banner(remaining: Duration) -> String {
+    let secs = (remaining.as_millis() + 999) / 1000;
+    format!(
+        "connection lost, retrying in {}s (press r to retry now, q to close)",
+        secs
+    )
+}
+
+/// Maps a key press during the countdown to what it asks for
+fn choice_for_key(key: &KeyCode) -> Option<RetryChoice> {
+    match key {
+        KeyCode::Char('r') | KeyCode::Char('R') => Some(RetryChoice::Retry),
+        KeyCode::Char('q') | KeyCode::Char('Q') => Some(RetryChoice::Close),
+        _ => None,
+    }
+}
+
+/// Counts down `delay` on `term`, updating the banner in place every
+/// second, and returns once it has elapsed or the user pressed r or q.
+/// `abandoned` is consulted on every tick; if it returns true the pane
+/// has gone away, and the countdown ends with `RetryChoice::Close`.
+pub fn wait_to_retry<T: Terminal>(
+    term: &mut T,
+    delay: Duration,
+    abandoned: impl Fn() -> bool,
+) -> anyhow::Result<RetryChoice> {
+    let deadline = Instant::now() + delay;
+    loop {
+        if abandoned() {
+            return Ok(RetryChoice::Close);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            term.render(&[Change::Text("\r\n".to_string())])?;
+            return Ok(RetryChoice::Retry);
+        }
+        term.render(&[
+            Change::Text("\r".to_string()),
+            Change::ClearToEndOfLine(ColorAttribute::Default),
+            Change::Text(retry_banner(remaining)),
+        ])?;
+        term.flush()?;
+
+        // Wake up on the next whole second of the countdown
+        let tick = match remaining.as_nanos() % TICK.as_nanos() {
+            0 => TICK,
+            nanos => Duration::from_nanos(nanos as u64),
+        };
+        if let Some(InputEvent::Key(KeyEvent { key, .. })) = term.poll_input(Some(tick))? {
+            if let Some(choice) = choice_for_key(&key) {
+                term.render(&[Change::Text("\r\n".to_string())])?;
+                return Ok(choice);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn banner() {
+        assert_eq!(
+            retry_banner(Duration::from_millis(4100)),
+            "connection lost, retrying in 5s (press r to retry now, q to close)"
+        );
+        assert_eq!(
+            retry_banner(Duration::from_secs(2)),
+            "connection lost, retrying in 2s (press r to retry now, q to close)"
+        );
+        assert_eq!(
+            choice_for_key(&KeyCode::Char('q')),
+            Some(RetryChoice::Close)
+        );
+        assert_eq!(choice_for_key(&KeyCode::Enter), None);
+    }
+}
@@ -99,3 +99,39 @@ pub fn terminal_get_dimensions(term: &mut Terminal) -> RenderableDimensions {
         dpi: screen.dpi,
     }
 }
+
+/// Returns a checksum of the text of `lines`, ignoring trailing blanks,
+/// so that a mux client can cheaply check that its copy of the screen
+/// matches that of the server.
+/// This is FNV-1a, whose result doesn't depend on the build.
+pub fn lines_checksum<'a>(lines: impl IntoIterator<Item = &'a Line>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for line in lines {
+        let text = line.as_str();
+        for byte in text.trim_end().bytes().chain(std::iter::once(b'\n')) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        let a = Line::from_text("hello", &Default::default(), SEQ_ZERO, None);
+        let b = Line::from_text("hello   ", &Default::default(), SEQ_ZERO, None);
+        let c = Line::from_text("hellO", &Default::default(), SEQ_ZERO, None);
+        assert_eq!(lines_checksum(&[a.clone()]), lines_checksum(&[b]));
+        assert_ne!(lines_checksum(&[a.clone()]), lines_checksum(&[c]));
+        // The boundaries between lines are significant
+        let empty = Line::from_text("", &Default::default(), SEQ_ZERO, None);
+        assert_ne!(
+            lines_checksum(&[a.clone(), empty.clone()]),
+            lines_checksum(&[empty, a])
+        );
+    }
+}
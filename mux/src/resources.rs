@@ -0,0 +1,259 @@
+//! Samples the memory and CPU usage of the processes that run in
+//! local panes.
+//! Sampling is opt-in via `pane_resource_sample_interval_ms`; the
+//! process trees are walked on a separate thread and the latest sample
+//! is stored on each `LocalPane`.  The mux server forwards the samples
+//! to its clients so that remote panes report them too.
+use crate::localpane::LocalPane;
+use crate::pane::PaneId;
+use crate::{Mux, MuxNotification};
+use config::configuration;
+use procinfo::{LocalProcessInfo, ProcessResources};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// The resource usage of the processes in a pane, or the sum of
+/// that of several panes
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic,
+)]
+pub struct PaneResourceUsage {
+    /// The resident memory of the processes, in bytes
+    pub rss_bytes: u64,
+    /// The CPU usage of the processes between the two most recent
+    /// samples, as a percentage of a single core
+    pub cpu_percent: f64,
+    /// The number of processes
+    pub num_processes: usize,
+}
+luahelper::impl_lua_conversion_dynamic!(PaneResourceUsage);
+
+impl PaneResourceUsage {
+    pub fn accumulate(&mut self, other: &PaneResourceUsage) {
+        self.rss_bytes += other.rss_bytes;
+        self.cpu_percent += other.cpu_percent;
+        self.num_processes += other.num_processes;
+    }
+
+    /// Sums the usage of several panes.
+    /// Returns None if none of them have been sampled.
+    pub fn sum<'a>(usage: impl Iterator<Item = &'a PaneResourceUsage>) -> Option<Self> {
+        let mut total: Option<Self> = None;
+        for usage in usage {
+            total.get_or_insert_with(Self::default).accumulate(usage);
+        }
+        total
+    }
+}
+
+/// Formats a number of bytes for display, such as `2.3 GB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while value >= 1024. && unit + 1 < UNITS.len() {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// A sample of the resources consumed by the process tree of a
+/// local pane
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceSample {
+    pub usage: PaneResourceUsage,
+    cpu_time: Duration,
+    sampled_at: Instant,
+}
+
+impl ResourceSample {
+    /// Computes the usage from `resources`, which was measured at `now`.
+    /// The CPU usage is derived from the CPU time consumed since
+    /// `prior`, so it is reported as zero for the first sample.
+    pub fn new(prior: Option<&ResourceSample>, resources: ProcessResources, now: Instant) -> Self {
+        let cpu_percent = match prior {
+            Some(prior) => {
+                let elapsed = now.saturating_duration_since(prior.sampled_at);
+                if elapsed.is_zero() {
+                    prior.usage.cpu_percent
+                } else {
+                    // Processes that exited since the prior sample take
+                    // their CPU time with them, so the total can shrink
+                    let used = resources.cpu_time.saturating_sub(prior.cpu_time);
+                    100. * used.as_secs_f64() / elapsed.as_secs_f64()
+                }
+            }
+            None => 0.,
+        };
+        Self {
+            usage: PaneResourceUsage {
+                rss_bytes: resources.rss_bytes,
+                cpu_percent,
+                num_processes: resources.num_processes,
+            },
+            cpu_time: resources.cpu_time,
+            sampled_at: now,
+        }
+    }
+}
+
+/// Set while a round of sampling is in progress, so that slow
+/// process tree walks don't pile up
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+
+impl Mux {
+    /// Returns the pids of the processes that were spawned by local panes
+    fn local_pane_pids(&self) -> Vec<(PaneId, u32)> {
+        self.iter_panes()
+            .into_iter()
+            .filter_map(|pane| {
+                let pid = pane.downcast_ref::<LocalPane>()?.root_pid()?;
+                Some((pane.pane_id(), pid))
+            })
+            .collect()
+    }
+
+    /// Walks the process trees of the local panes on another thread,
+    /// records the results on the panes and notifies subscribers
+    pub(crate) async fn sample_pane_resources(&self) {
+        if SAMPLING.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let pids = self.local_pane_pids();
+        let measured = promise::spawn::spawn_into_new_thread(move || {
+            Ok(pids
+                .into_iter()
+                .filter_map(|(pane_id, pid)| {
+                    let info = LocalProcessInfo::with_root_pid(pid)?;
+                    Some((pane_id, info.tree_resources()))
+                })
+                .collect::<Vec<_>>())
+        })
+        .await
+        .unwrap_or_else(|err: anyhow::Error| {
+            log::error!("sampling pane resources: {:#}", err);
+            vec![]
+        });
+        SAMPLING.store(false, Ordering::SeqCst);
+
+        let now = Instant::now();
+        let mut samples = vec![];
+        for (pane_id, resources) in measured {
+            if let Some(pane) = self.get_pane(pane_id) {
+                if let Some(local) = pane.downcast_ref::<LocalPane>() {
+                    samples.push((pane_id, local.record_resource_sample(resources, now)));
+                }
+            }
+        }
+        if !samples.is_empty() {
+            self.notify(MuxNotification::PaneResourceUsage(samples));
+        }
+    }
+}
+
+/// Starts the sampler thread, if it isn't already running and
+/// sampling is enabled.
+/// The thread wakes every `pane_resource_sample_interval_ms` and asks
+/// the mux thread to take a sample.
+pub(crate) fn start_resource_sampler() {
+    if configuration().pane_resource_sample_interval_ms == 0 {
+        return;
+    }
+    static START: Once = Once::new();
+    START.call_once(|| {
+        let result = std::thread::Builder::new()
+            .name("pane-resource-sampler".to_string())
+            .spawn(|| loop {
+                let interval = configuration().pane_resource_sample_interval_ms;
+                if interval == 0 {
+                    // Sampling was disabled by a config reload; check
+                    // occasionally whether it has been enabled again
+                    std::thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+                std::thread::sleep(Duration::from_millis(interval.max(100)));
+                promise::spawn::spawn_into_main_thread(async {
+                    if let Some(mux) = Mux::get() {
+                        mux.sample_pane_resources().await;
+                    }
+                })
+                .detach();
+            });
+        if let Err(err) = result {
+            log::error!("failed to start pane resource sampler thread: {:#}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resources(rss_bytes: u64, cpu_ms: u64) -> ProcessResources {
+        ProcessResources {
+            rss_bytes,
+            cpu_time: Duration::from_millis(cpu_ms),
+            num_processes: 2,
+        }
+    }
+
+    #[test]
+    fn cpu_percent() {
+        let start = Instant::now();
+        let first = ResourceSample::new(None, resources(1024, 500), start);
+        assert_eq!(first.usage.cpu_percent, 0.);
+        assert_eq!(first.usage.rss_bytes, 1024);
+
+        let second = ResourceSample::new(
+            Some(&first),
+            resources(2048, 1500),
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(second.usage.cpu_percent, 50.);
+
+        // A process exited, taking its CPU time with it
+        let third = ResourceSample::new(
+            Some(&second),
+            resources(2048, 1000),
+            start + Duration::from_secs(4),
+        );
+        assert_eq!(third.usage.cpu_percent, 0.);
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(2_469_606_195), "2.3 GB");
+    }
+
+    #[test]
+    fn sum() {
+        assert_eq!(PaneResourceUsage::sum([].iter()), None);
+        let a = PaneResourceUsage {
+            rss_bytes: 100,
+            cpu_percent: 12.5,
+            num_processes: 1,
+        };
+        let b = PaneResourceUsage {
+            rss_bytes: 50,
+            cpu_percent: 100.,
+            num_processes: 3,
+        };
+        assert_eq!(
+            PaneResourceUsage::sum([a, b].iter()),
+            Some(PaneResourceUsage {
+                rss_bytes: 150,
+                cpu_percent: 112.5,
+                num_processes: 4,
+            })
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Allows a request to spawn a command to be satisfied by a pane that
+//! already exists, such as one that is already running the same tool.
+//! The `reuse` predicate of a `SpawnCommand` is evaluated before the
+//! command is dispatched to its domain; if a pane matches, it is
+//! activated instead of spawning the command.
+use crate::pane::Pane;
+use crate::tab::Tab;
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::Context;
+use config::keyassignment::{CommandDir, ReusePredicate};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use url::Url;
+
+/// A `ReusePredicate` whose title regex has been compiled, so that it
+/// can be tested against each pane in turn
+pub struct ReuseMatcher<'a> {
+    predicate: &'a ReusePredicate,
+    title: Option<Regex>,
+}
+
+impl<'a> ReuseMatcher<'a> {
+    /// Fails if the title regex of the predicate is not valid
+    pub fn new(predicate: &'a ReusePredicate) -> anyhow::Result<Self> {
+        let title = match &predicate.title {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid reuse title regex {:?}", pattern))?,
+            ),
+            None => None,
+        };
+        Ok(Self { predicate, title })
+    }
+
+    /// Returns true if a pane with these properties satisfies the predicate
+    pub fn matches(
+        &self,
+        user_vars: &HashMap<String, String>,
+        title: &str,
+        cwd: Option<&Url>,
+        domain_name: &str,
+    ) -> bool {
+        for (key, value) in &self.predicate.user_vars {
+            if user_vars.get(key) != Some(value) {
+                return false;
+            }
+        }
+
+        if let Some(re) = &self.title {
+            if !re.is_match(title) {
+                return false;
+            }
+        }
+
+        if let Some(wanted) = &self.predicate.cwd {
+            let actual = match cwd.and_then(CommandDir::from_url) {
+                Some(actual) => actual,
+                None => return false,
+            };
+            if Path::new(&wanted.path) != Path::new(&actual.path) {
+                return false;
+            }
+            if wanted.host.is_some() && wanted.host != actual.host {
+                return false;
+            }
+        }
+
+        if let Some(domain) = &self.predicate.domain {
+            if domain != domain_name {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl Mux {
+    /// Looks for a pane that satisfies `predicate`, searching `window_id`
+    /// if it is set, or else all of the windows in `workspace`.
+    /// A matching pane is made the active pane of its tab, and that tab
+    /// the active tab of its window.
+    /// Returns None if no pane matches, in which case the caller should
+    /// spawn the command as usual.
+    /// Fails if the title regex of the predicate is not valid, whether
+    /// or not there are any panes to test it against.
+    pub fn reuse_pane(
+        &self,
+        window_id: Option<WindowId>,
+        workspace: &str,
+        predicate: &ReusePredicate,
+    ) -> anyhow::Result<Option<(Rc<Tab>, Rc<dyn Pane>, WindowId)>> {
+        let matcher = ReuseMatcher::new(predicate)?;
+        let window_ids = match window_id {
+            Some(window_id) => vec![window_id],
+            None => self.iter_windows_in_workspace(workspace),
+        };
+
+        for window_id in window_ids {
+            let tabs: Vec<Rc<Tab>> = match self.get_window(window_id) {
+                Some(window) => window.iter().cloned().collect(),
+                None => continue,
+            };
+            for tab in tabs {
+                for pos in tab.iter_panes() {
+                    let pane = pos.pane;
+                    let domain_name = self
+                        .get_domain(pane.domain_id())
+                        .map(|domain| domain.domain_name().to_string())
+                        .unwrap_or_default();
+                    if !matcher.matches(
+                        &pane.copy_user_vars(),
+                        &pane.get_title(),
+                        pane.get_current_working_dir().as_ref(),
+                        &domain_name,
+                    ) {
+                        continue;
+                    }
+
+                    log::debug!(
+                        "reusing pane {} in tab {} for {:?}",
+                        pane.pane_id(),
+                        tab.tab_id(),
+                        predicate
+                    );
+                    tab.set_active_pane(&pane);
+                    if let Some(mut window) = self.get_window_mut(window_id) {
+                        if let Some(idx) = window.idx_by_id(tab.tab_id()) {
+                            window.save_and_then_set_active(idx);
+                        }
+                    }
+                    return Ok(Some((tab, pane, window_id)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::MuxBuilder;
+    use crate::domain::{Domain, LocalDomain};
+    use std::sync::Arc;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn empty_predicate_matches_anything() {
+        let predicate = ReusePredicate::default();
+        let matcher = ReuseMatcher::new(&predicate).unwrap();
+        assert!(matcher.matches(&HashMap::new(), "", None, "local"));
+    }
+
+    #[test]
+    fn user_vars() {
+        let predicate = ReusePredicate {
+            user_vars: vars(&[("app", "lazygit")]),
+            ..Default::default()
+        };
+        let matcher = ReuseMatcher::new(&predicate).unwrap();
+        assert!(matcher.matches(
+            &vars(&[("app", "lazygit"), ("other", "1")]),
+            "",
+            None,
+            "local"
+        ));
+        assert!(!matcher.matches(&vars(&[("app", "htop")]), "", None, "local"));
+        assert!(!matcher.matches(&HashMap::new(), "", None, "local"));
+    }
+
+    #[test]
+    fn title_and_domain() {
+        let predicate = ReusePredicate {
+            title: Some("^lazygit".to_string()),
+            domain: Some("local".to_string()),
+            ..Default::default()
+        };
+        let matcher = ReuseMatcher::new(&predicate).unwrap();
+        let none = HashMap::new();
+        assert!(matcher.matches(&none, "lazygit - repo", None, "local"));
+        assert!(!matcher.matches(&none, "vim lazygit", None, "local"));
+        assert!(!matcher.matches(&none, "lazygit - repo", None, "ssh"));
+
+        let bad = ReusePredicate {
+            title: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(ReuseMatcher::new(&bad).is_err());
+    }
+
+    #[test]
+    fn invalid_title_fails_without_panes() {
+        let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new("local").unwrap());
+        let mux = MuxBuilder::new().domain(domain).build().unwrap();
+        let bad = ReusePredicate {
+            title: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(mux.reuse_pane(None, "default", &bad).is_err());
+    }
+
+    #[test]
+    fn cwd() {
+        let predicate = ReusePredicate {
+            cwd: Some(CommandDir::parse("/home/user/repo")),
+            ..Default::default()
+        };
+        let matcher = ReuseMatcher::new(&predicate).unwrap();
+        let none = HashMap::new();
+        let url = Url::parse("file://host/home/user/repo/").unwrap();
+        assert!(matcher.matches(&none, "", Some(&url), "local"));
+        let other = Url::parse("file://host/home/user").unwrap();
+        assert!(!matcher.matches(&none, "", Some(&other), "local"));
+        assert!(!matcher.matches(&none, "", None, "local"));
+
+        let remote = ReusePredicate {
+            cwd: Some(CommandDir::parse("file://elsewhere/home/user/repo")),
+            ..Default::default()
+        };
+        let matcher = ReuseMatcher::new(&remote).unwrap();
+        assert!(!matcher.matches(&none, "", Some(&url), "local"));
+    }
+}
@@ -0,0 +1,319 @@
+//! Finds the matches of a set of regexes in the logical lines of a pane,
+//! as used by QuickSelect, and the implicit hyperlinks that the
+//! `hyperlink_rules` make in them.
+//! Panes that are hosted by a multiplexer server are scanned by the
+//! server, so that the client doesn't need to fetch their scrollback.
+use crate::pane::{LogicalLine, SearchResult};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use termwiz::hyperlink::{Hyperlink, Rule};
+use wezterm_term::StableRowIndex;
+
+/// Bounds the number of compiled regexes that are kept around
+const MAX_CACHED_REGEXES: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref REGEX_CACHE: Mutex<HashMap<String, Arc<Regex>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ScanMatch {
+    /// Where the match is; results with the same text have the same
+    /// `match_id`
+    pub result: SearchResult,
+    /// The index of the pattern that matched
+    pub pattern: usize,
+    pub text: String,
+}
+
+/// An implicit hyperlink rule, in the form in which it is sent to the
+/// multiplexer server that scans the pane
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperlinkRuleSpec {
+    pub regex: String,
+    pub format: String,
+}
+
+impl From<&Rule> for HyperlinkRuleSpec {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            regex: rule.regex.as_str().to_string(),
+            format: rule.format.clone(),
+        }
+    }
+}
+
+/// Turns rules that were sent to the server back into rules, using the
+/// cache of compiled regexes
+pub fn compile_hyperlink_rules(specs: &[HyperlinkRuleSpec]) -> anyhow::Result<Vec<Rule>> {
+    specs
+        .iter()
+        .map(|spec| {
+            Ok(Rule {
+                regex: (*compile(&spec.regex)?).clone(),
+                format: spec.format.clone(),
+            })
+        })
+        .collect()
+}
+
+/// A run of cells that an implicit hyperlink rule made into a link.
+/// The run ends before `end_x` on row `end_y`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperlinkMatch {
+    pub start_y: StableRowIndex,
+    pub start_x: usize,
+    pub end_y: StableRowIndex,
+    pub end_x: usize,
+    pub link: Arc<Hyperlink>,
+}
+
+/// Returns the implicit hyperlinks that `rules` make in `lines`.
+/// Unlike applying the rules to the rows as they are fetched, this
+/// finds the links that are wrapped across several rows.
+pub fn scan_hyperlinks(lines: &[LogicalLine], rules: &[Rule]) -> Vec<HyperlinkMatch> {
+    let mut matches = vec![];
+    for line in lines {
+        let mut logical = line.logical.clone();
+        logical.invalidate_implicit_hyperlinks(logical.current_seqno());
+        logical.scan_and_create_hyperlinks(rules);
+        if !logical.has_hyperlink() {
+            continue;
+        }
+
+        let cells = logical.cells();
+        let mut idx = 0;
+        while idx < cells.len() {
+            let link = match cells[idx].attrs().hyperlink() {
+                Some(link) if link.is_implicit() => Arc::clone(link),
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+            let start = idx;
+            while idx < cells.len()
+                && cells[idx]
+                    .attrs()
+                    .hyperlink()
+                    .map_or(false, |l| Arc::ptr_eq(l, &link))
+            {
+                idx += 1;
+            }
+            let (start_y, start_x) = line.logical_x_to_physical_coord(start);
+            let (end_y, last_x) = line.logical_x_to_physical_coord(idx - 1);
+            matches.push(HyperlinkMatch {
+                start_y,
+                start_x,
+                end_y,
+                end_x: last_x + 1,
+                link,
+            });
+        }
+    }
+    matches
+}
+
+/// Discards the compiled regexes, so that they are compiled afresh
+/// when they are next used.  Called when the config is reloaded.
+pub fn invalidate_regex_cache() {
+    REGEX_CACHE.lock().unwrap().clear();
+}
+
+fn compile(pattern: &str) -> anyhow::Result<Arc<Regex>> {
+    let mut cache = REGEX_CACHE.lock().unwrap();
+    if let Some(re) = cache.get(pattern) {
+        return Ok(Arc::clone(re));
+    }
+    let re = Arc::new(Regex::new(pattern)?);
+    if cache.len() >= MAX_CACHED_REGEXES {
+        cache.clear();
+    }
+    cache.insert(pattern.to_string(), Arc::clone(&re));
+    Ok(re)
+}
+
+/// Returns the matches of `patterns` in `lines`, ordered by position.
+/// If a pattern has captures, the last capture that matched is used
+/// in place of the whole match.
+/// Where the matches of several patterns overlap, the one that starts
+/// first wins, or the one from the earlier pattern if they start at
+/// the same place.
+pub fn scan_logical_lines(
+    lines: &[LogicalLine],
+    patterns: &[String],
+) -> anyhow::Result<Vec<ScanMatch>> {
+    let regexes = patterns
+        .iter()
+        .map(|p| compile(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut matches = vec![];
+    let mut uniq_matches: HashMap<String, usize> = HashMap::new();
+
+    for line in lines {
+        let mut haystack = String::new();
+        // The cell index of each byte offset of a cell in the haystack
+        let mut coords: Vec<(usize, usize)> = vec![];
+        let mut trailing_spaces = None;
+        let mut end_of_cells = 0;
+        for (cell_idx, cell) in line.logical.visible_cells() {
+            coords.push((haystack.len(), cell_idx));
+            let s = cell.str();
+            if s == " " {
+                // Prune trailing spaces so that `$` works as expected
+                if trailing_spaces.is_none() {
+                    trailing_spaces.replace((haystack.len(), coords.len() - 1));
+                }
+            } else {
+                trailing_spaces.take();
+                end_of_cells = cell_idx + cell.width();
+            }
+            haystack.push_str(s);
+        }
+        if let Some((byte_idx, coord_idx)) = trailing_spaces {
+            haystack.truncate(byte_idx);
+            coords.truncate(coord_idx);
+        }
+        if haystack.is_empty() {
+            continue;
+        }
+
+        let cell_at = |byte_idx: usize| -> usize {
+            match coords.binary_search_by(|(b, _)| b.cmp(&byte_idx)) {
+                Ok(i) => coords[i].1,
+                // Not on a cell boundary; use the cell that contains it
+                Err(i) => coords[i.saturating_sub(1)].1,
+            }
+        };
+
+        let mut line_matches = vec![];
+        for (pattern, re) in regexes.iter().enumerate() {
+            for c in re.captures_iter(&haystack) {
+                // Look for the captures in reverse order, as index==0 is
+                // the whole matched string
+                let m = (0..c.len())
+                    .rev()
+                    .filter_map(|idx| c.get(idx))
+                    .find(|m| !m.as_str().is_empty());
+                if let Some(m) = m {
+                    line_matches.push((m.start(), pattern, m.end()));
+                }
+            }
+        }
+        line_matches.sort();
+
+        let mut covered_until = 0;
+        for (start, pattern, end) in line_matches {
+            if start < covered_until {
+                continue;
+            }
+            covered_until = end;
+
+            let text = haystack[start..end].to_string();
+            let match_id = match uniq_matches.get(&text).copied() {
+                Some(id) => id,
+                None => {
+                    let id = uniq_matches.len();
+                    uniq_matches.insert(text.clone(), id);
+                    id
+                }
+            };
+
+            let start_x = cell_at(start);
+            let end_x = if end >= haystack.len() {
+                end_of_cells
+            } else {
+                cell_at(end)
+            };
+            let (start_y, start_x) = line.logical_x_to_physical_coord(start_x);
+            // The end is exclusive; map its last cell so that a match
+            // that ends at the edge of a row stays on that row
+            let (end_y, last_x) = line.logical_x_to_physical_coord(end_x.saturating_sub(1));
+
+            matches.push(ScanMatch {
+                result: SearchResult {
+                    start_y,
+                    start_x,
+                    end_y,
+                    end_x: last_x + 1,
+                    match_id,
+                },
+                pattern,
+                text,
+            });
+        }
+    }
+
+    matches.sort();
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::surface::SEQ_ZERO;
+    use wezterm_term::Line;
+
+    fn logical(text: &str, first_row: isize) -> LogicalLine {
+        let line = Line::from_text(text, &Default::default(), SEQ_ZERO, None);
+        LogicalLine {
+            physical_lines: vec![line.clone()],
+            logical: line,
+            first_row,
+        }
+    }
+
+    #[test]
+    fn overlapping_patterns() {
+        let lines = vec![logical("see abc123 here", 4)];
+        let patterns = vec!["[0-9]+".to_string(), "[a-z]+([0-9]+)".to_string()];
+        let matches = scan_logical_lines(&lines, &patterns).unwrap();
+        let found: Vec<(&str, usize, usize, isize)> = matches
+            .iter()
+            .map(|m| {
+                (
+                    m.text.as_str(),
+                    m.pattern,
+                    m.result.start_x,
+                    m.result.start_y,
+                )
+            })
+            .collect();
+        assert_eq!(found, vec![("123", 0, 7, 4)]);
+        assert_eq!(matches[0].result.end_x, 10);
+    }
+
+    #[test]
+    fn hyperlink_wrapped_across_rows() {
+        let first = Line::from_text("see http://exa", &Default::default(), SEQ_ZERO, None);
+        let second = Line::from_text("mple.com ok", &Default::default(), SEQ_ZERO, None);
+        let mut logical = first.clone();
+        logical.append_line(second.clone(), SEQ_ZERO);
+        let lines = vec![LogicalLine {
+            physical_lines: vec![first, second],
+            logical,
+            first_row: 10,
+        }];
+        let rules = vec![Rule::new(r"\bhttps?://\S+", "$0").unwrap()];
+
+        let matches = scan_hyperlinks(&lines, &rules);
+        assert_eq!(matches.len(), 1);
+        let m = &matches[0];
+        assert_eq!((m.start_y, m.start_x, m.end_y, m.end_x), (10, 4, 11, 8));
+        assert_eq!(m.link.uri(), "http://example.com");
+        assert!(m.link.is_implicit());
+    }
+
+    #[test]
+    fn repeated_text_shares_match_id() {
+        let lines = vec![logical("ab ab", 0), logical("ab   ", 1)];
+        let matches = scan_logical_lines(&lines, &["ab$|ab".to_string()]).unwrap();
+        assert_eq!(matches.len(), 3);
+        assert!(matches.iter().all(|m| m.result.match_id == 0));
+        assert_eq!(matches[2].result.start_y, 1);
+        assert_eq!(matches[2].result.end_x, 2);
+    }
+}
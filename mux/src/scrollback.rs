@@ -0,0 +1,483 @@
+//! Renders the lines of a pane as plain text, as text with escape
+//! sequences, or as HTML, for `pane:save_scrollback` and
+//! `wezterm cli save-scrollback`.
+//! The lines are rendered a chunk at a time, and panes that are hosted
+//! by a multiplexer server are rendered by the server, so that neither
+//! side holds the rendering of the whole scrollback at once.
+use crate::pane::Pane;
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use termwiz::cell::{Blink, CellAttributes, Intensity, Underline};
+use termwiz::color::{ColorAttribute, ColorSpec};
+use termwiz::escape::csi::Sgr;
+use termwiz::escape::osc::OperatingSystemCommand;
+use termwiz::escape::CSI;
+use termwiz::hyperlink::Hyperlink;
+use termwiz::surface::Line;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::color::ColorPalette;
+use wezterm_term::StableRowIndex;
+
+/// The number of lines that are rendered by each call to
+/// `Pane::render_scrollback`
+pub const SCROLLBACK_CHUNK_ROWS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub enum ScrollbackFormat {
+    /// The text of the lines, without any attributes
+    Text,
+    /// The text of the lines, with SGR escape sequences for their
+    /// attributes and OSC 8 escape sequences for their hyperlinks
+    Escapes,
+    /// A standalone HTML document, whose stylesheet holds the palette
+    /// of the pane
+    Html,
+}
+
+impl Default for ScrollbackFormat {
+    fn default() -> Self {
+        Self::Text
+    }
+}
+
+impl std::str::FromStr for ScrollbackFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "Text" | "text" => Ok(Self::Text),
+            "Escapes" | "escapes" => Ok(Self::Escapes),
+            "Html" | "html" => Ok(Self::Html),
+            _ => anyhow::bail!(
+                "invalid scrollback format {}; expected text, escapes or html",
+                s
+            ),
+        }
+    }
+}
+
+/// A chunk of the rendering of the lines of a pane
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollbackChunk {
+    pub text: String,
+    /// The rows that remain to be rendered, which are passed to the
+    /// next call to `Pane::render_scrollback`, or None if this is the
+    /// last chunk
+    pub remaining: Option<Range<StableRowIndex>>,
+}
+
+/// Returns the text of the visible cells of `line`, including any
+/// trailing whitespace
+pub fn line_as_text(line: &Line) -> String {
+    let mut text = String::new();
+    for (_, cell) in line.visible_cells() {
+        text.push_str(cell.str());
+    }
+    text
+}
+
+/// Returns the rendering of the start of the document, which is only
+/// non-empty for HTML
+pub fn render_header(format: ScrollbackFormat, palette: &ColorPalette) -> String {
+    if format != ScrollbackFormat::Html {
+        return String::new();
+    }
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n:root {\n");
+    let _ = writeln!(html, "  --fg: {};", palette.foreground.to_rgb_string());
+    let _ = writeln!(html, "  --bg: {};", palette.background.to_rgb_string());
+    for (idx, color) in palette.colors.0.iter().enumerate() {
+        let _ = writeln!(html, "  --c{}: {};", idx, color.to_rgb_string());
+    }
+    html.push_str(
+        "}\n\
+         body { margin: 0; background: var(--bg); }\n\
+         pre { margin: 0; padding: 0.5em; color: var(--fg); background: var(--bg); }\n\
+         a { color: inherit; }\n\
+         .w { display: inline-block; width: 2ch; }\n\
+         </style>\n</head>\n<body>\n<pre>",
+    );
+    html
+}
+
+/// Returns the rendering of the end of the document, which is only
+/// non-empty for HTML
+pub fn render_footer(format: ScrollbackFormat) -> String {
+    match format {
+        ScrollbackFormat::Html => "</pre>\n</body>\n</html>\n".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Renders `lines`.
+/// A line whose last cell was wrapped is joined to the line that
+/// follows it, so that each logical line is rendered as a single line;
+/// the trailing blanks of the other lines are omitted.
+/// Each line is rendered from the default attributes, so the chunks
+/// that are rendered separately can be concatenated.
+pub fn render_lines(format: ScrollbackFormat, lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        let wrapped = line.last_cell_was_wrapped();
+        match format {
+            ScrollbackFormat::Text => {
+                let text = line_as_text(line);
+                if wrapped {
+                    out.push_str(&text);
+                } else {
+                    out.push_str(text.trim_end());
+                }
+            }
+            ScrollbackFormat::Escapes => render_line_escapes(line, wrapped, &mut out),
+            ScrollbackFormat::Html => render_line_html(line, wrapped, &mut out),
+        }
+        if !wrapped {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Returns true if `cell` would look the same as the space that
+/// follows the end of the line
+fn is_blank(text: &str, attrs: &CellAttributes) -> bool {
+    text == " "
+        && attrs.background() == ColorAttribute::Default
+        && !attrs.reverse()
+        && attrs.underline() == Underline::None
+        && attrs.hyperlink().is_none()
+}
+
+/// Returns the visible cells of `line`, without the trailing blanks
+/// unless the line is wrapped
+fn cells_to_render(line: &Line, wrapped: bool) -> Vec<(&str, &CellAttributes, usize)> {
+    let mut cells: Vec<_> = line
+        .visible_cells()
+        .map(|(_, cell)| (cell.str(), cell.attrs(), cell.width()))
+        .collect();
+    if !wrapped {
+        while let Some((text, attrs, _)) = cells.last() {
+            if !is_blank(text, attrs) {
+                break;
+            }
+            cells.pop();
+        }
+    }
+    cells
+}
+
+fn color_spec(color: ColorAttribute) -> ColorSpec {
+    match color {
+        ColorAttribute::TrueColorWithPaletteFallback(color, _)
+        | ColorAttribute::TrueColorWithDefaultFallback(color) => ColorSpec::TrueColor(color),
+        ColorAttribute::PaletteIndex(idx) => ColorSpec::PaletteIndex(idx),
+        ColorAttribute::Default => ColorSpec::Default,
+    }
+}
+
+/// Returns the SGR sequence that sets `attrs` from the default attributes
+fn sgr_for(attrs: &CellAttributes) -> String {
+    let mut sgr = String::new();
+    let mut push = |s: Sgr| {
+        let _ = write!(sgr, "{}", CSI::Sgr(s));
+    };
+    if attrs.intensity() != Intensity::Normal {
+        push(Sgr::Intensity(attrs.intensity()));
+    }
+    if attrs.underline() != Underline::None {
+        push(Sgr::Underline(attrs.underline()));
+    }
+    if attrs.underline_color() != ColorAttribute::Default {
+        push(Sgr::UnderlineColor(color_spec(attrs.underline_color())));
+    }
+    if attrs.blink() != Blink::None {
+        push(Sgr::Blink(attrs.blink()));
+    }
+    if attrs.italic() {
+        push(Sgr::Italic(true));
+    }
+    if attrs.reverse() {
+        push(Sgr::Inverse(true));
+    }
+    if attrs.invisible() {
+        push(Sgr::Invisible(true));
+    }
+    if attrs.strikethrough() {
+        push(Sgr::StrikeThrough(true));
+    }
+    if attrs.overline() {
+        push(Sgr::Overline(true));
+    }
+    if attrs.foreground() != ColorAttribute::Default {
+        push(Sgr::Foreground(color_spec(attrs.foreground())));
+    }
+    if attrs.background() != ColorAttribute::Default {
+        push(Sgr::Background(color_spec(attrs.background())));
+    }
+    sgr
+}
+
+fn render_line_escapes(line: &Line, wrapped: bool, out: &mut String) {
+    let mut sgr = String::new();
+    let mut link: Option<&Arc<Hyperlink>> = None;
+    for (text, attrs, _width) in cells_to_render(line, wrapped) {
+        if attrs.hyperlink() != link {
+            link = attrs.hyperlink();
+            let _ = write!(
+                out,
+                "{}",
+                OperatingSystemCommand::SetHyperlink(link.map(|link| (**link).clone()))
+            );
+        }
+        let cell_sgr = sgr_for(attrs);
+        if cell_sgr != sgr {
+            if !sgr.is_empty() {
+                let _ = write!(out, "{}", CSI::Sgr(Sgr::Reset));
+            }
+            out.push_str(&cell_sgr);
+            sgr = cell_sgr;
+        }
+        out.push_str(text);
+    }
+    if !sgr.is_empty() {
+        let _ = write!(out, "{}", CSI::Sgr(Sgr::Reset));
+    }
+    if link.is_some() {
+        let _ = write!(out, "{}", OperatingSystemCommand::SetHyperlink(None));
+    }
+}
+
+fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Returns the CSS value of `color`, or None for the default color.
+/// Palette colors refer to the variables defined by `render_header`.
+fn css_color(color: ColorAttribute) -> Option<String> {
+    match color_spec(color) {
+        ColorSpec::Default => None,
+        ColorSpec::PaletteIndex(idx) => Some(format!("var(--c{})", idx)),
+        ColorSpec::TrueColor(color) => Some(color.to_rgb_string()),
+    }
+}
+
+/// Returns the inline style for `attrs`
+fn css_for(attrs: &CellAttributes) -> String {
+    let mut fg = css_color(attrs.foreground());
+    let mut bg = css_color(attrs.background());
+    if attrs.reverse() {
+        let reversed_fg = bg.unwrap_or_else(|| "var(--bg)".to_string());
+        let reversed_bg = fg.unwrap_or_else(|| "var(--fg)".to_string());
+        fg = Some(reversed_fg);
+        bg = Some(reversed_bg);
+    }
+    if attrs.invisible() {
+        fg = Some("transparent".to_string());
+    }
+
+    let mut css = String::new();
+    if let Some(fg) = fg {
+        let _ = write!(css, "color:{};", fg);
+    }
+    if let Some(bg) = bg {
+        let _ = write!(css, "background:{};", bg);
+    }
+    match attrs.intensity() {
+        Intensity::Normal => {}
+        Intensity::Bold => css.push_str("font-weight:bold;"),
+        Intensity::Half => css.push_str("opacity:0.6;"),
+    }
+    if attrs.italic() {
+        css.push_str("font-style:italic;");
+    }
+    let mut decorations = vec![];
+    if attrs.underline() != Underline::None {
+        decorations.push("underline");
+    }
+    if attrs.strikethrough() {
+        decorations.push("line-through");
+    }
+    if attrs.overline() {
+        decorations.push("overline");
+    }
+    if !decorations.is_empty() {
+        let _ = write!(css, "text-decoration:{};", decorations.join(" "));
+    }
+    css
+}
+
+fn render_line_html(line: &Line, wrapped: bool, out: &mut String) {
+    let mut css = String::new();
+    let mut link: Option<&Arc<Hyperlink>> = None;
+    let close_span = |css: &str, out: &mut String| {
+        if !css.is_empty() {
+            out.push_str("</span>");
+        }
+    };
+
+    for (text, attrs, width) in cells_to_render(line, wrapped) {
+        let cell_css = css_for(attrs);
+        if attrs.hyperlink() != link {
+            close_span(&css, out);
+            css.clear();
+            if link.is_some() {
+                out.push_str("</a>");
+            }
+            link = attrs.hyperlink();
+            if let Some(link) = link {
+                out.push_str("<a href=\"");
+                escape_html(link.uri(), out);
+                out.push_str("\">");
+            }
+        }
+        // A wide cell is given the width of the columns that it spans,
+        // so that the text after it stays aligned whatever the width
+        // of its glyph in the font of the browser
+        if width > 1 {
+            close_span(&css, out);
+            css.clear();
+            out.push_str("<span class=\"w\"");
+            if !cell_css.is_empty() {
+                let _ = write!(out, " style=\"{}\"", cell_css);
+            }
+            out.push('>');
+            escape_html(text, out);
+            out.push_str("</span>");
+            continue;
+        }
+        if cell_css != css {
+            close_span(&css, out);
+            if !cell_css.is_empty() {
+                let _ = write!(out, "<span style=\"{}\">", cell_css);
+            }
+            css = cell_css;
+        }
+        escape_html(text, out);
+    }
+    close_span(&css, out);
+    if link.is_some() {
+        out.push_str("</a>");
+    }
+}
+
+/// Writes the rendering of the `range` rows of `pane`, or all of its
+/// rows, to the file at `path`, replacing it, a chunk at a time
+pub async fn save_scrollback(
+    pane: &Rc<dyn Pane>,
+    path: &Path,
+    format: ScrollbackFormat,
+    range: Option<Range<StableRowIndex>>,
+) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path)
+        .map_err(|err| anyhow::anyhow!("creating {}: {:#}", path.display(), err))?;
+    let mut file = std::io::BufWriter::new(file);
+    let mut range = range;
+    let mut header = true;
+    loop {
+        let chunk = pane.render_scrollback(format, range, header).await?;
+        file.write_all(chunk.text.as_bytes())?;
+        match chunk.remaining {
+            Some(remaining) => {
+                range = Some(remaining);
+                header = false;
+            }
+            None => break,
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::color::AnsiColor;
+
+    fn line(text: &str) -> Line {
+        Line::from_text(text, &CellAttributes::default(), 0, None)
+    }
+
+    fn wrapped(text: &str) -> Line {
+        Line::from_text_with_wrapped_last_col(text, &CellAttributes::default(), 0)
+    }
+
+    #[test]
+    fn text() {
+        let lines = vec![wrapped("hello "), line("world   "), line("  "), line("x")];
+        assert_eq!(
+            render_lines(ScrollbackFormat::Text, &lines),
+            "hello world\n\nx\n"
+        );
+        // The spacer that follows a wide cell is not rendered
+        assert_eq!(
+            render_lines(ScrollbackFormat::Text, &[line("a日b")]),
+            "a日b\n"
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let mut attrs = CellAttributes::default();
+        attrs.set_foreground(AnsiColor::Maroon);
+        attrs.set_hyperlink(Some(Arc::new(Hyperlink::new("https://example.com"))));
+        let mut l = line("a");
+        l.append_line(Line::from_text("b", &attrs, 0, None), 0);
+        l.append_line(line("c   "), 0);
+        assert_eq!(
+            render_lines(ScrollbackFormat::Escapes, &[l]),
+            "a\x1b]8;;https://example.com\x1b\\\x1b[31mb\
+             \x1b]8;;\x1b\\\x1b[0mc\n"
+        );
+    }
+
+    #[test]
+    fn html() {
+        let mut attrs = CellAttributes::default();
+        attrs.set_intensity(Intensity::Bold);
+        attrs.set_foreground(AnsiColor::Maroon);
+        let mut l = line("<&>");
+        l.append_line(Line::from_text("x", &attrs, 0, None), 0);
+        l.append_line(line("日 "), 0);
+        assert_eq!(
+            render_lines(ScrollbackFormat::Html, &[l]),
+            "&lt;&amp;&gt;<span style=\"color:var(--c1);font-weight:bold;\">x</span>\
+             <span class=\"w\">日</span>\n"
+        );
+
+        let mut attrs = CellAttributes::default();
+        attrs.set_hyperlink(Some(Arc::new(Hyperlink::new("https://a.b/?x=1&y=2"))));
+        let lines = vec![
+            Line::from_text_with_wrapped_last_col("ab", &attrs, 0),
+            Line::from_text("c", &attrs, 0, None),
+        ];
+        assert_eq!(
+            render_lines(ScrollbackFormat::Html, &lines),
+            "<a href=\"https://a.b/?x=1&amp;y=2\">ab</a>\
+             <a href=\"https://a.b/?x=1&amp;y=2\">c</a>\n"
+        );
+    }
+
+    #[test]
+    fn html_document() {
+        let palette = ColorPalette::default();
+        let header = render_header(ScrollbackFormat::Html, &palette);
+        assert!(header.contains(&format!("--c1: {};", palette.colors.0[1].to_rgb_string())));
+        assert!(header.ends_with("<pre>"));
+        assert!(render_footer(ScrollbackFormat::Html).starts_with("</pre>"));
+        assert_eq!(render_header(ScrollbackFormat::Text, &palette), "");
+    }
+}
@@ -0,0 +1,280 @@
+//! Shares let someone else watch a single tab, such as a colleague
+//! that is pairing with the user.  `wezterm cli share-tab` issues a
+//! share of a tab along with a token, which a viewer redeems by
+//! connecting with it to a TLS server of this mux that accepts shares;
+//! see `TlsDomainServer::accept_shares`.  The session of the viewer is
+//! then limited to the panes of the tab, and any input that it sends
+//! to them is rejected.
+//! A token may only be redeemed once, and only until it expires.  The
+//! view lasts until the viewer disconnects, the share is revoked or
+//! the tab is closed.
+use crate::tab::TabId;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub type ShareId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShareState {
+    /// The token has yet to be redeemed
+    Pending,
+    /// The token was redeemed by `viewer`, who is watching the tab
+    Viewing { viewer: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub id: ShareId,
+    pub tab_id: TabId,
+    pub created: DateTime<Utc>,
+    /// The token must be redeemed before this time
+    pub expires: DateTime<Utc>,
+    pub state: ShareState,
+}
+
+/// A TLS server of this mux that accepts shares
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareListener {
+    /// The `host:port` at which viewers can reach the server
+    pub address: String,
+    /// The hex encoded SHA-256 digest of the certificate of the server
+    pub fingerprint: String,
+}
+
+/// What a viewer needs to redeem a share.  The token carries the
+/// fingerprint of the certificate of the server, which the viewer
+/// checks in place of a certificate authority, so that the secret is
+/// only ever sent to the server that issued it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareToken {
+    pub secret: String,
+    pub fingerprint: String,
+}
+
+impl std::fmt::Display for ShareToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.secret, self.fingerprint)
+    }
+}
+
+impl FromStr for ShareToken {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        match s.split_once('.') {
+            Some((secret, fingerprint)) if is_hex(secret) && is_hex(fingerprint) => Ok(Self {
+                secret: secret.to_string(),
+                fingerprint: fingerprint.to_ascii_lowercase(),
+            }),
+            _ => anyhow::bail!("`{}` is not a share token", s),
+        }
+    }
+}
+
+/// A token along with the address of the server that issued it,
+/// in the form `TOKEN@host:port` that `wezterm connect --share` accepts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareLink {
+    pub token: ShareToken,
+    pub address: String,
+}
+
+impl std::fmt::Display for ShareLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}@{}", self.token, self.address)
+    }
+}
+
+impl FromStr for ShareLink {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (token, address) = s
+            .split_once('@')
+            .with_context(|| format!("expected TOKEN@host:port, but have `{}`", s))?;
+        anyhow::ensure!(
+            address.rsplit_once(':').map_or(false, |(host, port)| {
+                !host.is_empty() && port.parse::<u16>().is_ok()
+            }),
+            "expected TOKEN@host:port, but have `{}`",
+            s
+        );
+        Ok(Self {
+            token: token.parse()?,
+            address: address.to_string(),
+        })
+    }
+}
+
+struct Share {
+    info: ShareInfo,
+    secret: String,
+}
+
+#[derive(Default)]
+pub struct ShareRegistry {
+    shares: BTreeMap<ShareId, Share>,
+    next_id: ShareId,
+}
+
+/// Compares the secrets in time that doesn't depend on where they differ
+fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl ShareRegistry {
+    /// Issues a share of the tab whose token is `secret`, which must
+    /// be redeemed within `lifetime`
+    pub fn issue(
+        &mut self,
+        tab_id: TabId,
+        secret: String,
+        lifetime: Duration,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<ShareInfo> {
+        let lifetime = chrono::Duration::from_std(lifetime).context("share lifetime")?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let info = ShareInfo {
+            id,
+            tab_id,
+            created: now,
+            expires: now + lifetime,
+            state: ShareState::Pending,
+        };
+        self.shares.insert(
+            id,
+            Share {
+                info: info.clone(),
+                secret,
+            },
+        );
+        Ok(info)
+    }
+
+    /// Drops the pending shares that have expired
+    fn expire(&mut self, now: DateTime<Utc>) {
+        self.shares
+            .retain(|_, share| share.info.state != ShareState::Pending || share.info.expires > now);
+    }
+
+    /// Redeems the pending share whose token is `secret` on behalf
+    /// of `viewer`, after which it can't be redeemed again
+    pub fn redeem(
+        &mut self,
+        secret: &str,
+        viewer: String,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<ShareInfo> {
+        self.expire(now);
+        let share = self
+            .shares
+            .values_mut()
+            .find(|share| {
+                share.info.state == ShareState::Pending && secrets_match(&share.secret, secret)
+            })
+            .context("the share token is invalid, has expired or was already used")?;
+        share.info.state = ShareState::Viewing { viewer };
+        Ok(share.info.clone())
+    }
+
+    /// Removes the share, returning it if it existed
+    pub fn end(&mut self, id: ShareId) -> Option<ShareInfo> {
+        self.shares.remove(&id).map(|share| share.info)
+    }
+
+    /// Removes the shares of a tab that was closed
+    pub fn remove_tab(&mut self, tab_id: TabId) -> Vec<ShareInfo> {
+        let ids: Vec<ShareId> = self
+            .shares
+            .values()
+            .filter(|share| share.info.tab_id == tab_id)
+            .map(|share| share.info.id)
+            .collect();
+        ids.into_iter().filter_map(|id| self.end(id)).collect()
+    }
+
+    /// Returns the shares that are pending or being viewed, in the
+    /// order in which they were issued
+    pub fn list(&mut self, now: DateTime<Utc>) -> Vec<ShareInfo> {
+        self.expire(now);
+        self.shares
+            .values()
+            .map(|share| share.info.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn redeem_once() {
+        let now = Utc::now();
+        let mut shares = ShareRegistry::default();
+        let share = shares.issue(7, "aa11".to_string(), HOUR, now).unwrap();
+        assert!(shares.redeem("aa12", "bob".to_string(), now).is_err());
+
+        let redeemed = shares.redeem("aa11", "bob".to_string(), now).unwrap();
+        assert_eq!(redeemed.id, share.id);
+        assert_eq!(redeemed.tab_id, 7);
+        assert_eq!(
+            redeemed.state,
+            ShareState::Viewing {
+                viewer: "bob".to_string()
+            }
+        );
+        assert!(shares.redeem("aa11", "eve".to_string(), now).is_err());
+
+        assert_eq!(shares.end(share.id), Some(redeemed));
+        assert_eq!(shares.end(share.id), None);
+    }
+
+    #[test]
+    fn expiry() {
+        let now = Utc::now();
+        let later = now + chrono::Duration::hours(2);
+        let mut shares = ShareRegistry::default();
+        shares.issue(1, "aa".to_string(), HOUR, now).unwrap();
+        let viewed = shares.issue(1, "bb".to_string(), HOUR, now).unwrap();
+        shares.redeem("bb", "bob".to_string(), now).unwrap();
+
+        assert!(shares.redeem("aa", "bob".to_string(), later).is_err());
+        // A view that started in time outlives the expiry of its token
+        let listed: Vec<ShareId> = shares.list(later).iter().map(|s| s.id).collect();
+        assert_eq!(listed, vec![viewed.id]);
+    }
+
+    #[test]
+    fn remove_tab() {
+        let now = Utc::now();
+        let mut shares = ShareRegistry::default();
+        shares.issue(1, "aa".to_string(), HOUR, now).unwrap();
+        let other = shares.issue(2, "bb".to_string(), HOUR, now).unwrap();
+        assert_eq!(shares.remove_tab(1).len(), 1);
+        assert_eq!(shares.list(now), vec![other]);
+    }
+
+    #[test]
+    fn links() {
+        let link: ShareLink = "0a1b.C0FFEE@example.com:8080".parse().unwrap();
+        assert_eq!(link.token.secret, "0a1b");
+        assert_eq!(link.token.fingerprint, "c0ffee");
+        assert_eq!(link.address, "example.com:8080");
+        assert_eq!(link.to_string(), "0a1b.c0ffee@example.com:8080");
+
+        assert!("0a1b.c0ffee@example.com".parse::<ShareLink>().is_err());
+        assert!("0a1b@example.com:8080".parse::<ShareLink>().is_err());
+        assert!("xyz.c0ffee@example.com:8080".parse::<ShareLink>().is_err());
+    }
+}
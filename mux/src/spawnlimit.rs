@@ -0,0 +1,195 @@
+//! Limits how many spawns into a domain may be in progress at once,
+//! for domains that set `max_concurrent_spawns`.  The spawns beyond
+//! the limit wait in a queue per domain, and are let through in the
+//! order in which they arrived as earlier spawns complete.
+//! A spawn that is abandoned while it waits, for example because the
+//! request that started it timed out, leaves the queue.
+//! The limiter lives on the mux thread.
+use crate::domain::DomainId;
+use smol::channel::{bounded, Receiver, Sender};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+#[derive(Default)]
+struct DomainQueue {
+    /// How many spawns hold a slot
+    active: usize,
+    /// The limit that the most recent spawn asked for
+    limit: Option<usize>,
+    /// The spawns that are waiting, oldest first.  A slot is handed
+    /// over by sending to the spawn.
+    waiting: VecDeque<Sender<()>>,
+}
+
+impl DomainQueue {
+    fn has_room(&self) -> bool {
+        self.limit.map_or(true, |limit| self.active < limit)
+    }
+
+    /// Hands out free slots to the spawns that have waited longest.
+    /// Spawns that were abandoned are skipped.
+    fn hand_over(&mut self) {
+        while self.has_room() {
+            match self.waiting.pop_front() {
+                Some(tx) => {
+                    if tx.try_send(()).is_ok() {
+                        self.active += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn release(&mut self) {
+        self.active -= 1;
+        self.hand_over();
+    }
+
+    fn queued(&self) -> usize {
+        self.waiting.iter().filter(|tx| !tx.is_closed()).count()
+    }
+}
+
+/// A slot of a domain, which is freed when this is dropped
+pub struct SpawnPermit {
+    queue: Rc<RefCell<DomainQueue>>,
+}
+
+impl Drop for SpawnPermit {
+    fn drop(&mut self) {
+        self.queue.borrow_mut().release();
+    }
+}
+
+/// A spawn that is waiting for a slot
+struct Waiter {
+    queue: Rc<RefCell<DomainQueue>>,
+    rx: Receiver<()>,
+    granted: bool,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // A slot may have been handed over after the spawn gave up
+        // on it, in which case it is passed on
+        if !self.granted && self.rx.try_recv().is_ok() {
+            self.queue.borrow_mut().release();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SpawnLimiter {
+    domains: RefCell<HashMap<DomainId, Rc<RefCell<DomainQueue>>>>,
+}
+
+impl SpawnLimiter {
+    /// Waits until a spawn into `domain_id` may proceed, while at most
+    /// `limit` spawns are in progress; None doesn't limit them, and a
+    /// limit of 0 is treated as 1.  The spawn holds its slot until the
+    /// returned permit is dropped.
+    pub async fn acquire(&self, domain_id: DomainId, limit: Option<usize>) -> SpawnPermit {
+        let queue = Rc::clone(self.domains.borrow_mut().entry(domain_id).or_default());
+        let rx = {
+            let mut q = queue.borrow_mut();
+            q.limit = limit.map(|limit| limit.max(1));
+            // A change to the limit may have made room for others
+            q.hand_over();
+            if q.has_room() && q.queued() == 0 {
+                q.active += 1;
+                None
+            } else {
+                let (tx, rx) = bounded(1);
+                q.waiting.push_back(tx);
+                Some(rx)
+            }
+        };
+        if let Some(rx) = rx {
+            let mut waiter = Waiter {
+                queue: Rc::clone(&queue),
+                rx,
+                granted: false,
+            };
+            // The sender is only dropped once it has been sent to
+            waiter.rx.recv().await.ok();
+            waiter.granted = true;
+        }
+        SpawnPermit { queue }
+    }
+
+    /// Returns how many spawns into `domain_id` are waiting for a slot
+    pub fn queued(&self, domain_id: DomainId) -> usize {
+        self.domains
+            .borrow()
+            .get(&domain_id)
+            .map(|queue| queue.borrow().queued())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use smol::future::{block_on, poll_once};
+
+    #[test]
+    fn fifo() {
+        let limiter = SpawnLimiter::default();
+        let first = block_on(limiter.acquire(1, Some(1)));
+        let mut second = Box::pin(limiter.acquire(1, Some(1)));
+        let mut third = Box::pin(limiter.acquire(1, Some(1)));
+        assert!(block_on(poll_once(&mut second)).is_none());
+        assert!(block_on(poll_once(&mut third)).is_none());
+        assert_eq!(limiter.queued(1), 2);
+
+        // Other domains are not held up
+        drop(block_on(limiter.acquire(2, Some(1))));
+        drop(block_on(limiter.acquire(3, None)));
+
+        drop(first);
+        assert!(block_on(poll_once(&mut third)).is_none());
+        let second = block_on(poll_once(&mut second)).unwrap();
+        assert_eq!(limiter.queued(1), 1);
+        drop(second);
+        let third = block_on(poll_once(&mut third)).unwrap();
+        assert_eq!(limiter.queued(1), 0);
+        drop(third);
+        drop(block_on(limiter.acquire(1, Some(1))));
+    }
+
+    #[test]
+    fn abandoned_spawns_leave_the_queue() {
+        let limiter = SpawnLimiter::default();
+        let first = block_on(limiter.acquire(1, Some(1)));
+        let mut second = Box::pin(limiter.acquire(1, Some(1)));
+        let mut third = Box::pin(limiter.acquire(1, Some(1)));
+        let mut fourth = Box::pin(limiter.acquire(1, Some(1)));
+        assert!(block_on(poll_once(&mut second)).is_none());
+        assert!(block_on(poll_once(&mut third)).is_none());
+        assert!(block_on(poll_once(&mut fourth)).is_none());
+
+        // Gives up before its turn
+        drop(second);
+        assert_eq!(limiter.queued(1), 2);
+
+        // Gives up after the slot was handed to it, but before it
+        // noticed, so the slot passes to the next in line
+        drop(first);
+        drop(third);
+        let fourth = block_on(poll_once(&mut fourth)).unwrap();
+        assert_eq!(limiter.queued(1), 0);
+        drop(fourth);
+    }
+
+    #[test]
+    fn raising_the_limit_lets_spawns_through() {
+        let limiter = SpawnLimiter::default();
+        let _first = block_on(limiter.acquire(1, Some(1)));
+        let mut second = Box::pin(limiter.acquire(1, Some(1)));
+        assert!(block_on(poll_once(&mut second)).is_none());
+        let _third = block_on(limiter.acquire(1, Some(3)));
+        assert!(block_on(poll_once(&mut second)).is_some());
+    }
+}
@@ -0,0 +1,354 @@
+//! Quotas on the panes that scripts create through the mux protocol,
+//! such as with `wezterm cli spawn` and `wezterm cli split-pane`, so
+//! that a script that spawns in a loop can't bury the session in panes.
+//! A spawn is refused with `DomainError::QuotaExceeded` if it would
+//! take the panes of its domain or of the whole mux over their limit,
+//! or if too many panes were spawned within the last second.
+//! Spawns made by the user in the GUI are only limited when
+//! `spawn_limits_apply_to_interactive` is set.
+//! `wezterm cli set-limit` overrides a configured limit until the mux
+//! restarts.  Unlike `spawnlimit`, which queues spawns so that only a
+//! few run at once, a quota refuses a spawn outright.
+use crate::domain::DomainError;
+use config::ConfigHandle;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SpawnLimit {
+    /// The most panes that the mux may have
+    MaxPanes,
+    /// The most panes that a domain may have
+    MaxPanesPerDomain,
+    /// The most panes that may be spawned within a second
+    MaxSpawnsPerSecond,
+}
+
+impl SpawnLimit {
+    pub const ALL: [SpawnLimit; 3] = [
+        SpawnLimit::MaxPanes,
+        SpawnLimit::MaxPanesPerDomain,
+        SpawnLimit::MaxSpawnsPerSecond,
+    ];
+
+    /// The name of the limit for `wezterm cli set-limit`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MaxPanes => "max-panes",
+            Self::MaxPanesPerDomain => "max-panes-per-domain",
+            Self::MaxSpawnsPerSecond => "max-spawns-per-second",
+        }
+    }
+
+    fn configured(self, config: &ConfigHandle) -> Option<usize> {
+        match self {
+            Self::MaxPanes => config.spawn_limit_max_panes,
+            Self::MaxPanesPerDomain => config.spawn_limit_max_panes_per_domain,
+            Self::MaxSpawnsPerSecond => config.spawn_limit_max_spawns_per_second,
+        }
+    }
+}
+
+impl std::fmt::Display for SpawnLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SpawnLimit {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|limit| limit.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|limit| limit.name()).collect();
+                anyhow::anyhow!(
+                    "`{}` is not a limit; expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Where a request to spawn came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnOrigin {
+    /// A client of the mux protocol, such as `wezterm cli`
+    Protocol,
+    /// The user, such as with a key assignment in the GUI
+    Interactive,
+}
+
+/// How a limit is changed at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpawnLimitValue {
+    /// Limit to this many
+    Limit(usize),
+    /// Don't limit
+    Unlimited,
+    /// Go back to the value from the configuration
+    Configured,
+}
+
+impl FromStr for SpawnLimitValue {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(Self::Unlimited),
+            "default" => Ok(Self::Configured),
+            _ => s.parse().map(Self::Limit).map_err(|_| {
+                anyhow::anyhow!("expected a number, `none` or `default`, but have `{}`", s)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpawnLimitInfo {
+    pub limit: SpawnLimit,
+    /// None if there is no limit
+    pub value: Option<usize>,
+    /// Whether the value was set at runtime, rather than configured
+    pub overridden: bool,
+}
+
+/// The panes that a domain has, and how many a spawn would add to it
+#[derive(Debug, Clone)]
+pub struct DomainSpawn {
+    pub domain: String,
+    pub panes: usize,
+    pub new_panes: usize,
+}
+
+#[derive(Default)]
+pub struct SpawnQuota {
+    overrides: HashMap<SpawnLimit, Option<usize>>,
+    /// When the panes that count towards `MaxSpawnsPerSecond` were
+    /// spawned, oldest first
+    recent: VecDeque<Instant>,
+}
+
+impl SpawnQuota {
+    pub fn set(&mut self, limit: SpawnLimit, value: SpawnLimitValue) {
+        match value {
+            SpawnLimitValue::Limit(n) => {
+                self.overrides.insert(limit, Some(n));
+            }
+            SpawnLimitValue::Unlimited => {
+                self.overrides.insert(limit, None);
+            }
+            SpawnLimitValue::Configured => {
+                self.overrides.remove(&limit);
+            }
+        }
+    }
+
+    pub fn value(&self, limit: SpawnLimit, config: &ConfigHandle) -> Option<usize> {
+        match self.overrides.get(&limit) {
+            Some(value) => *value,
+            None => limit.configured(config),
+        }
+    }
+
+    pub fn list(&self, config: &ConfigHandle) -> Vec<SpawnLimitInfo> {
+        SpawnLimit::ALL
+            .iter()
+            .map(|&limit| SpawnLimitInfo {
+                limit,
+                value: self.value(limit, config),
+                overridden: self.overrides.contains_key(&limit),
+            })
+            .collect()
+    }
+
+    /// Checks that the spawns into `domains` may be made while the mux
+    /// has `total_panes` panes.  If they may, they are counted towards
+    /// the rate of spawns as of `now`.
+    pub fn check(
+        &mut self,
+        config: &ConfigHandle,
+        domains: &[DomainSpawn],
+        total_panes: usize,
+        now: Instant,
+    ) -> Result<(), DomainError> {
+        let new_panes: usize = domains.iter().map(|d| d.new_panes).sum();
+        let exceeded =
+            |limit: SpawnLimit, reason: String| DomainError::QuotaExceeded { limit, reason };
+
+        if let Some(max) = self.value(SpawnLimit::MaxPanes, config) {
+            if total_panes + new_panes > max {
+                return Err(exceeded(
+                    SpawnLimit::MaxPanes,
+                    format!(
+                        "there are {} panes, and at most {} are allowed",
+                        total_panes, max
+                    ),
+                ));
+            }
+        }
+
+        if let Some(max) = self.value(SpawnLimit::MaxPanesPerDomain, config) {
+            if let Some(d) = domains.iter().find(|d| d.panes + d.new_panes > max) {
+                return Err(exceeded(
+                    SpawnLimit::MaxPanesPerDomain,
+                    format!(
+                        "domain {} has {} panes, and at most {} are allowed",
+                        d.domain, d.panes, max
+                    ),
+                ));
+            }
+        }
+
+        while let Some(&when) = self.recent.front() {
+            if now.saturating_duration_since(when) < Duration::from_secs(1) {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        if let Some(max) = self.value(SpawnLimit::MaxSpawnsPerSecond, config) {
+            if self.recent.len() + new_panes > max {
+                return Err(exceeded(
+                    SpawnLimit::MaxSpawnsPerSecond,
+                    format!(
+                        "{} panes were spawned within the last second, \
+                         and at most {} are allowed",
+                        self.recent.len(),
+                        max
+                    ),
+                ));
+            }
+        }
+
+        self.recent.extend(std::iter::repeat(now).take(new_panes));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spawn(domain: &str, panes: usize) -> DomainSpawn {
+        DomainSpawn {
+            domain: domain.to_string(),
+            panes,
+            new_panes: 1,
+        }
+    }
+
+    fn exceeded(result: Result<(), DomainError>) -> Option<SpawnLimit> {
+        match result {
+            Ok(()) => None,
+            Err(DomainError::QuotaExceeded { limit, .. }) => Some(limit),
+            Err(err) => panic!("unexpected error {:#}", err),
+        }
+    }
+
+    #[test]
+    fn pane_limits() {
+        let config = config::configuration();
+        let now = Instant::now();
+        let mut quota = SpawnQuota::default();
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 100)], 100, now)),
+            None
+        );
+
+        quota.set(SpawnLimit::MaxPanes, SpawnLimitValue::Limit(10));
+        quota.set(SpawnLimit::MaxPanesPerDomain, SpawnLimitValue::Limit(4));
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 3)], 9, now)),
+            None
+        );
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 3)], 10, now)),
+            Some(SpawnLimit::MaxPanes)
+        );
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 4)], 5, now)),
+            Some(SpawnLimit::MaxPanesPerDomain)
+        );
+        // Each of the domains of a clone is checked
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 0), spawn("b", 4)], 5, now)),
+            Some(SpawnLimit::MaxPanesPerDomain)
+        );
+
+        quota.set(SpawnLimit::MaxPanesPerDomain, SpawnLimitValue::Unlimited);
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 4)], 5, now)),
+            None
+        );
+        quota.set(SpawnLimit::MaxPanes, SpawnLimitValue::Configured);
+        assert_eq!(quota.value(SpawnLimit::MaxPanes, &config), None);
+        assert_eq!(
+            quota.list(&config),
+            vec![
+                SpawnLimitInfo {
+                    limit: SpawnLimit::MaxPanes,
+                    value: None,
+                    overridden: false
+                },
+                SpawnLimitInfo {
+                    limit: SpawnLimit::MaxPanesPerDomain,
+                    value: None,
+                    overridden: true
+                },
+                SpawnLimitInfo {
+                    limit: SpawnLimit::MaxSpawnsPerSecond,
+                    value: None,
+                    overridden: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let config = config::configuration();
+        let now = Instant::now();
+        let mut quota = SpawnQuota::default();
+        quota.set(SpawnLimit::MaxSpawnsPerSecond, SpawnLimitValue::Limit(2));
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 0)], 0, now)),
+            None
+        );
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 1)], 1, now)),
+            None
+        );
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 2)], 2, now)),
+            Some(SpawnLimit::MaxSpawnsPerSecond)
+        );
+        // A refused spawn doesn't count towards the rate
+        let later = now + Duration::from_millis(1000);
+        assert_eq!(
+            exceeded(quota.check(&config, &[spawn("a", 2)], 2, later)),
+            None
+        );
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "max-panes-per-domain".parse::<SpawnLimit>().unwrap(),
+            SpawnLimit::MaxPanesPerDomain
+        );
+        assert!("max_panes".parse::<SpawnLimit>().is_err());
+        assert_eq!(
+            "12".parse::<SpawnLimitValue>().unwrap(),
+            SpawnLimitValue::Limit(12)
+        );
+        assert_eq!(
+            "none".parse::<SpawnLimitValue>().unwrap(),
+            SpawnLimitValue::Unlimited
+        );
+        assert!("-1".parse::<SpawnLimitValue>().is_err());
+    }
+}
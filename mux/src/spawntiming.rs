@@ -0,0 +1,242 @@
+//! Timing of the steps that spawning or splitting a pane goes through,
+//! so that a slow spawn can be explained after the fact.
+//! An operation is timed on the thread that carries it out, which is
+//! the main thread, and the spans that are entered while it is in
+//! progress are attributed to it.  Spawns that overlap, because one
+//! starts while another is awaiting, attribute the spans of both to the
+//! one that started last.
+//! Outside of an operation, entering a span costs a thread-local
+//! lookup, and an operation costs a few timestamps per span.
+//! The mux keeps the timings of the most recent operations; see
+//! `Mux::recent_spawn_timings`.
+use crate::pane::PaneId;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+
+/// How many operations the mux remembers
+pub const MAX_RECORDED: usize = 16;
+
+/// A step of an operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct SpawnSpan {
+    pub name: String,
+    /// When the step started, relative to the start of the operation
+    pub start_ms: f64,
+    pub duration_ms: f64,
+}
+
+/// The timings of a spawn or split
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
+pub struct SpawnTiming {
+    /// Either `spawn` or `split`
+    pub operation: String,
+    pub domain: String,
+    /// The new pane, unless the operation failed
+    pub pane_id: Option<PaneId>,
+    /// Why the operation failed
+    pub error: Option<String>,
+    pub total_ms: f64,
+    /// The steps, in the order in which they started.  A step may
+    /// contain others, such as `build_command` containing
+    /// `fixup_command`.
+    pub spans: Vec<SpawnSpan>,
+}
+luahelper::impl_lua_conversion_dynamic!(SpawnTiming);
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+struct Recording {
+    start: Instant,
+    /// How many spans have been entered, which orders them
+    entered: usize,
+    spans: Vec<(usize, SpawnSpan)>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Recording>> = RefCell::new(None);
+}
+
+/// Times an operation from when it is started until it is finished.
+/// If it is dropped without being finished, for example because the
+/// operation was cancelled, nothing is recorded.
+pub struct SpawnTimer {
+    operation: &'static str,
+    start: Instant,
+    /// The recording of the operation that was in progress when this
+    /// one started, which is resumed once this one is over
+    previous: Option<Option<Recording>>,
+}
+
+impl SpawnTimer {
+    pub fn start(operation: &'static str) -> Self {
+        let start = Instant::now();
+        let previous = CURRENT.with(|current| {
+            current.borrow_mut().replace(Recording {
+                start,
+                entered: 0,
+                spans: vec![],
+            })
+        });
+        Self {
+            operation,
+            start,
+            previous: Some(previous),
+        }
+    }
+
+    /// Ends the operation, which produced `result`
+    pub fn finish(mut self, domain: &str, result: Result<PaneId, String>) -> SpawnTiming {
+        let total = self.start.elapsed();
+        let previous = self.previous.take().unwrap_or(None);
+        let recording =
+            CURRENT.with(|current| std::mem::replace(&mut *current.borrow_mut(), previous));
+        let mut spans = recording.map(|r| r.spans).unwrap_or_default();
+        spans.sort_by_key(|(entered, _)| *entered);
+        let spans = spans.into_iter().map(|(_, span)| span).collect();
+        let (pane_id, error) = match result {
+            Ok(pane_id) => (Some(pane_id), None),
+            Err(err) => (None, Some(err)),
+        };
+        SpawnTiming {
+            operation: self.operation.to_string(),
+            domain: domain.to_string(),
+            pane_id,
+            error,
+            total_ms: millis(total),
+            spans,
+        }
+    }
+}
+
+impl Drop for SpawnTimer {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Times a step of the operation that is in progress on this thread,
+/// until it is dropped
+pub struct Span {
+    name: &'static str,
+    /// The order in which the span was entered, and when
+    start: Option<(usize, Instant)>,
+}
+
+/// Starts timing the step `name`, if an operation is being timed
+pub fn span(name: &'static str) -> Span {
+    let entered = CURRENT.with(|current| {
+        current.borrow_mut().as_mut().map(|recording| {
+            recording.entered += 1;
+            recording.entered
+        })
+    });
+    Span {
+        name,
+        start: entered.map(|entered| (entered, Instant::now())),
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some((entered, start)) = self.start {
+            let duration = start.elapsed();
+            CURRENT.with(|current| {
+                if let Some(recording) = current.borrow_mut().as_mut() {
+                    recording.spans.push((
+                        entered,
+                        SpawnSpan {
+                            name: self.name.to_string(),
+                            start_ms: millis(start.saturating_duration_since(recording.start)),
+                            duration_ms: millis(duration),
+                        },
+                    ));
+                }
+            });
+        }
+    }
+}
+
+/// The timings of the most recent operations, oldest first
+#[derive(Default)]
+pub struct SpawnTimingLog {
+    timings: VecDeque<SpawnTiming>,
+}
+
+impl SpawnTimingLog {
+    pub fn record(&mut self, timing: SpawnTiming) {
+        if self.timings.len() == MAX_RECORDED {
+            self.timings.pop_front();
+        }
+        self.timings.push_back(timing);
+    }
+
+    pub fn recent(&self) -> Vec<SpawnTiming> {
+        self.timings.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spans_are_attributed_to_the_operation() {
+        // Outside of an operation, spans are not recorded
+        drop(span("ignored"));
+
+        let timer = SpawnTimer::start("spawn");
+        {
+            let _outer = span("build_command");
+            drop(span("fixup_command"));
+        }
+        drop(span("openpty"));
+        let timing = timer.finish("local", Ok(3));
+
+        assert_eq!(timing.operation, "spawn");
+        assert_eq!(timing.pane_id, Some(3));
+        let names: Vec<&str> = timing.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["build_command", "fixup_command", "openpty"]);
+        assert!(timing.spans[0].duration_ms >= timing.spans[1].duration_ms);
+
+        // The spans of the previous operation don't carry over
+        let timer = SpawnTimer::start("split");
+        let timing = timer.finish("local", Err("no such domain".to_string()));
+        assert!(timing.spans.is_empty());
+        assert_eq!(timing.error.as_deref(), Some("no such domain"));
+    }
+
+    #[test]
+    fn nested_operations() {
+        let outer = SpawnTimer::start("spawn");
+        drop(span("first"));
+        {
+            let inner = SpawnTimer::start("split");
+            drop(span("inner"));
+            // Dropped without finishing, so nothing is recorded for it
+            drop(inner);
+        }
+        drop(span("second"));
+        let timing = outer.finish("local", Ok(1));
+        let names: Vec<&str> = timing.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn log_keeps_the_most_recent() {
+        let mut log = SpawnTimingLog::default();
+        for pane_id in 0..MAX_RECORDED + 2 {
+            log.record(SpawnTimer::start("spawn").finish("local", Ok(pane_id)));
+        }
+        let recent = log.recent();
+        assert_eq!(recent.len(), MAX_RECORDED);
+        assert_eq!(recent[0].pane_id, Some(2));
+        assert_eq!(recent[MAX_RECORDED - 1].pane_id, Some(MAX_RECORDED + 1));
+    }
+}
@@ -0,0 +1,36 @@
+//! Plans the splits that build up a tree of panes from its first pane,
+//! as is done for a layout file and for the copy of a tab that is
+//! being cloned.
+
+/// A split in a plan made by `plan_splits`.
+/// The pane in `slot` is split to make the second half of `split`.
+/// Slot 0 holds the first pane, and the nth split creates slot n + 1.
+#[derive(Debug, PartialEq)]
+pub struct PlannedSplit<S> {
+    pub slot: usize,
+    pub split: S,
+}
+
+/// Computes the splits that build `root` from its first pane.
+/// `halves` describes the split at a node and returns it along with
+/// the first and second halves of the node, or returns None if the
+/// node is a single pane.
+/// A region is split before either half is subdivided, so that the
+/// pane being split still fills the whole region and the size of the
+/// split is relative to all of it.
+pub fn plan_splits<'a, N, S>(
+    root: &'a N,
+    halves: impl Fn(&'a N) -> Option<(S, &'a N, &'a N)>,
+) -> Vec<PlannedSplit<S>> {
+    let mut splits = vec![];
+    let mut pending = vec![(root, 0)];
+    while let Some((node, slot)) = pending.pop() {
+        if let Some((split, first, second)) = halves(node) {
+            splits.push(PlannedSplit { slot, split });
+            // The first half is planned in full before the second
+            pending.push((second, splits.len()));
+            pending.push((first, slot));
+        }
+    }
+    splits
+}
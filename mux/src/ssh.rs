@@ -1,11 +1,20 @@
 use crate::connui::ConnectionUI;
-use crate::domain::{alloc_domain_id, Domain, DomainId, DomainState};
+use crate::domain::{
+    alloc_domain_id, map_cwd_within_domain, resolve_command_dir, resolve_spawn_command, CwdMapping,
+    Domain, DomainId, DomainResult, DomainState, SpawnDefaults,
+};
+use crate::domainexec::{ExecOutput, ExecRequest};
 use crate::localpane::LocalPane;
-use crate::pane::{alloc_pane_id, Pane, PaneId};
+use crate::pane::{Pane, PaneId};
+use crate::reconnect::{wait_to_retry, Backoff, RetryChoice};
 use crate::Mux;
 use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
-use config::{Shell, SshBackend, SshDomain};
+use config::keyassignment::CommandDir;
+use config::{
+    CwdExpansion, DomainKind, LauncherHints, NotificationHandling, Palette, Shell, SshBackend,
+    SshDomain,
+};
 use filedescriptor::{poll, pollfd, socketpair, AsRawSocketDescriptor, FileDescriptor, POLLIN};
 use portable_pty::cmdbuilder::CommandBuilder;
 use portable_pty::{ChildKiller, ExitStatus, MasterPty, PtySize};
@@ -196,20 +205,40 @@ impl RemoteSshDomain {
         ssh_domain_to_ssh_config(&self.dom)
     }
 
+    /// Returns the session of the domain, starting a new one if there
+    /// is none yet, or if the previous one has ended because its
+    /// connection was lost.  The events of a new session are returned
+    /// with it, so that the caller can carry out the authentication.
+    fn session(&self) -> anyhow::Result<(Session, Option<AsyncReceiver<SessionEvent>>)> {
+        if let Some(session) = self.session.borrow().as_ref() {
+            if !session.is_closed() {
+                return Ok((session.clone(), None));
+            }
+        }
+        let (session, events) = Session::connect(self.ssh_config()?)?;
+        self.session.borrow_mut().replace(session.clone());
+        Ok((session, Some(events)))
+    }
+
     fn build_command(
         &self,
         pane_id: PaneId,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
+        command_dir: Option<CommandDir>,
     ) -> anyhow::Result<(Option<String>, HashMap<String, String>)> {
+        let command_dir = resolve_command_dir(command.as_ref(), command_dir);
         let config = config::configuration();
-        let cmd = match command {
-            Some(mut cmd) => {
-                config.apply_cmd_defaults(&mut cmd, None);
-                cmd
-            }
-            None => config.build_prog(None, self.dom.default_prog.as_ref(), None)?,
+        // The local default_cwd is meaningless on the remote host, so
+        // only the cwd of the domain applies, and it is expanded there
+        let defaults = SpawnDefaults {
+            default_prog: self.dom.default_prog.as_ref(),
+            default_cwd: self.dom.default_cwd.as_ref(),
+            explicit_cwd: None,
+            cwd_expansion: CwdExpansion::Verbatim,
+            // The remote host decides how its shell is started
+            login_shell: None,
         };
+        let mut cmd = resolve_spawn_command(&config, command, &defaults)?;
         let mut env: HashMap<String, String> = cmd
             .iter_extra_env_as_str()
             .map(|(k, v)| (k.to_string(), v.to_string()))
@@ -222,13 +251,13 @@ impl RemoteSshDomain {
         env.insert("WEZTERM_REMOTE_PANE".to_string(), pane_id.to_string());
 
         fn build_env_command(
-            dir: Option<String>,
+            dir: Option<CommandDir>,
             cmd: &CommandBuilder,
             env: &HashMap<String, String>,
         ) -> anyhow::Result<String> {
             // "Soft" chdir: if it doesn't exist then it doesn't matter
             let cd_cmd = if let Some(dir) = dir {
-                format!("cd {};", shell_words::quote(&dir))
+                format!("cd {};", shell_words::quote(&dir.path))
             } else if let Some(dir) = cmd.get_cwd() {
                 let dir = dir.to_str().context("converting cwd to string")?;
                 format!("cd {};", shell_words::quote(&dir))
@@ -267,188 +296,235 @@ impl RemoteSshDomain {
             Ok(cd_cmd + &shell_words::join(env_cmd) + " " + &cmd)
         }
 
-        let command_line = match (cmd.is_default_prog(), self.dom.assume_shell, command_dir) {
-            (_, Shell::Posix, dir) => Some(build_env_command(dir, &cmd, &env)?),
-            (true, _, _) => None,
-            (false, _, _) => Some(cmd.as_unix_command_line()?),
+        let command_line = match (self.dom.assume_shell, command_dir) {
+            (Shell::Posix, dir) => Some(build_env_command(dir, &cmd, &env)?),
+            (_, dir) => {
+                if !cmd.is_default_prog() {
+                    if let Some(dir) = dir {
+                        cmd.cwd(dir.path);
+                    }
+                }
+                if let Some(dir) = cmd.get_cwd() {
+                    log::warn!(
+                        "ssh domain {}: not changing to {:?}, as the remote shell \
+                         is not known to be POSIX; set assume_shell = \"Posix\"",
+                        self.dom.name,
+                        dir
+                    );
+                }
+                if cmd.is_default_prog() {
+                    None
+                } else {
+                    Some(cmd.as_unix_command_line()?)
+                }
+            }
         };
 
         Ok((command_line, env))
     }
 }
 
-/// Carry out the authentication process and create the initial pty.
-fn connect_ssh_session(
-    session: Session,
-    events: smol::channel::Receiver<SessionEvent>,
-    mut stdin_read: FileDescriptor,
-    stdin_tx: Sender<BoxedWriter>,
-    stdout_write: &mut BufWriter<FileDescriptor>,
-    stdout_tx: Sender<BoxedReader>,
-    child_tx: Sender<SshChildProcess>,
-    pty_tx: Sender<SshPty>,
-    size: Arc<Mutex<TerminalSize>>,
-    command_line: Option<String>,
-    env: HashMap<String, String>,
-) -> anyhow::Result<()> {
-    struct StdoutShim<'a> {
-        size: Arc<Mutex<TerminalSize>>,
-        stdout: &'a mut BufWriter<FileDescriptor>,
+/// Returns the command line for `cmd`.  If `shell` is POSIX, it is
+/// prefixed with a "soft" chdir to the cwd of `cmd`; for other shells
+/// the syntax to change directory isn't known, so the cwd is left out.
+fn build_cd_command(cmd: &CommandBuilder, shell: Shell) -> anyhow::Result<String> {
+    let command_line = cmd.as_unix_command_line()?;
+    match (shell, cmd.get_cwd()) {
+        (Shell::Posix, Some(dir)) => {
+            let dir = dir.to_str().context("converting cwd to string")?;
+            Ok(format!("cd {}; {}", shell_words::quote(dir), command_line))
+        }
+        _ => Ok(command_line),
     }
+}
 
-    impl<'a> Write for StdoutShim<'a> {
-        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            self.stdout.write(buf)
-        }
-        fn flush(&mut self) -> std::io::Result<()> {
-            self.stdout.flush()
-        }
+/// The output side of the terminal that talks to the user while a pane
+/// is being connected, rather than showing the output of its command
+struct StdoutShim {
+    size: Arc<Mutex<TerminalSize>>,
+    stdout: BufWriter<FileDescriptor>,
+}
+
+impl Write for StdoutShim {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stdout.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stdout.flush()
     }
+}
 
-    impl<'a> termwiz::render::RenderTty for StdoutShim<'a> {
-        fn get_size_in_cells(&mut self) -> termwiz::Result<(usize, usize)> {
-            let size = *self.size.lock().unwrap();
-            Ok((size.cols as _, size.rows as _))
-        }
+impl termwiz::render::RenderTty for StdoutShim {
+    fn get_size_in_cells(&mut self) -> termwiz::Result<(usize, usize)> {
+        let size = *self.size.lock().unwrap();
+        Ok((size.cols as _, size.rows as _))
     }
+}
 
-    /// a termwiz Terminal for use with the line editor
-    struct TerminalShim<'a> {
-        stdout: &'a mut StdoutShim<'a>,
-        stdin: &'a mut FileDescriptor,
+/// a termwiz Terminal for use with the line editor
+struct TerminalShim {
+    stdout: StdoutShim,
+    stdin: FileDescriptor,
+    size: Arc<Mutex<TerminalSize>>,
+    renderer: TerminfoRenderer,
+    parser: InputParser,
+    input_queue: VecDeque<InputEvent>,
+}
+
+impl TerminalShim {
+    fn new(
+        stdin: FileDescriptor,
+        stdout: BufWriter<FileDescriptor>,
         size: Arc<Mutex<TerminalSize>>,
-        renderer: TerminfoRenderer,
-        parser: InputParser,
-        input_queue: VecDeque<InputEvent>,
-    }
-
-    impl<'a> termwiz::terminal::Terminal for TerminalShim<'a> {
-        fn set_raw_mode(&mut self) -> termwiz::Result<()> {
-            use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Mode, CSI};
-
-            macro_rules! decset {
-                ($variant:ident) => {
-                    write!(
-                        self.stdout,
-                        "{}",
-                        CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
-                            DecPrivateModeCode::$variant
-                        )))
-                    )?;
-                };
-            }
+    ) -> Self {
+        Self {
+            stdout: StdoutShim {
+                stdout,
+                size: Arc::clone(&size),
+            },
+            size,
+            renderer: termwiz_funcs::new_wezterm_terminfo_renderer(),
+            stdin,
+            parser: InputParser::new(),
+            input_queue: VecDeque::new(),
+        }
+    }
 
-            decset!(BracketedPaste);
-            self.flush()?;
+    fn output_line(&mut self, s: &str) -> termwiz::Result<()> {
+        let mut s = s.replace("\n", "\r\n");
+        s.push_str("\r\n");
+        self.render(&[Change::Text(s)])
+    }
+}
 
-            Ok(())
+impl termwiz::terminal::Terminal for TerminalShim {
+    fn set_raw_mode(&mut self) -> termwiz::Result<()> {
+        use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Mode, CSI};
+
+        macro_rules! decset {
+            ($variant:ident) => {
+                write!(
+                    self.stdout,
+                    "{}",
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::$variant
+                    )))
+                )?;
+            };
         }
 
-        fn flush(&mut self) -> termwiz::Result<()> {
-            self.stdout.flush()?;
-            Ok(())
-        }
+        decset!(BracketedPaste);
+        self.flush()?;
 
-        fn set_cooked_mode(&mut self) -> termwiz::Result<()> {
-            Ok(())
-        }
+        Ok(())
+    }
 
-        fn enter_alternate_screen(&mut self) -> termwiz::Result<()> {
-            termwiz::bail!("TerminalShim has no alt screen");
-        }
+    fn flush(&mut self) -> termwiz::Result<()> {
+        self.stdout.flush()?;
+        Ok(())
+    }
 
-        fn exit_alternate_screen(&mut self) -> termwiz::Result<()> {
-            termwiz::bail!("TerminalShim has no alt screen");
-        }
+    fn set_cooked_mode(&mut self) -> termwiz::Result<()> {
+        Ok(())
+    }
 
-        fn get_screen_size(&mut self) -> termwiz::Result<ScreenSize> {
-            let size = *self.size.lock().unwrap();
-            Ok(ScreenSize {
-                cols: size.cols as _,
-                rows: size.rows as _,
-                xpixel: size.pixel_width as _,
-                ypixel: size.pixel_height as _,
-            })
-        }
+    fn enter_alternate_screen(&mut self) -> termwiz::Result<()> {
+        termwiz::bail!("TerminalShim has no alt screen");
+    }
 
-        fn set_screen_size(&mut self, _size: ScreenSize) -> termwiz::Result<()> {
-            termwiz::bail!("TerminalShim cannot set screen size");
-        }
+    fn exit_alternate_screen(&mut self) -> termwiz::Result<()> {
+        termwiz::bail!("TerminalShim has no alt screen");
+    }
 
-        fn render(&mut self, changes: &[Change]) -> termwiz::Result<()> {
-            self.renderer.render_to(changes, self.stdout)?;
-            Ok(())
-        }
+    fn get_screen_size(&mut self) -> termwiz::Result<ScreenSize> {
+        let size = *self.size.lock().unwrap();
+        Ok(ScreenSize {
+            cols: size.cols as _,
+            rows: size.rows as _,
+            xpixel: size.pixel_width as _,
+            ypixel: size.pixel_height as _,
+        })
+    }
 
-        fn poll_input(&mut self, wait: Option<Duration>) -> termwiz::Result<Option<InputEvent>> {
-            if let Some(event) = self.input_queue.pop_front() {
-                return Ok(Some(event));
-            }
+    fn set_screen_size(&mut self, _size: ScreenSize) -> termwiz::Result<()> {
+        termwiz::bail!("TerminalShim cannot set screen size");
+    }
 
-            let deadline = wait.map(|d| Instant::now() + d);
-            let starting_size = *self.size.lock().unwrap();
+    fn render(&mut self, changes: &[Change]) -> termwiz::Result<()> {
+        self.renderer.render_to(changes, &mut self.stdout)?;
+        Ok(())
+    }
 
-            self.stdin.set_non_blocking(true)?;
+    fn poll_input(&mut self, wait: Option<Duration>) -> termwiz::Result<Option<InputEvent>> {
+        if let Some(event) = self.input_queue.pop_front() {
+            return Ok(Some(event));
+        }
 
-            loop {
-                if let Some(deadline) = deadline.as_ref() {
-                    if Instant::now() >= *deadline {
-                        return Ok(None);
-                    }
+        let deadline = wait.map(|d| Instant::now() + d);
+        let starting_size = *self.size.lock().unwrap();
+
+        self.stdin.set_non_blocking(true)?;
+
+        loop {
+            if let Some(deadline) = deadline.as_ref() {
+                if Instant::now() >= *deadline {
+                    return Ok(None);
                 }
-                let mut pfd = [pollfd {
-                    fd: self.stdin.as_socket_descriptor(),
-                    events: POLLIN,
-                    revents: 0,
-                }];
-
-                if let Ok(1) = poll(&mut pfd, Some(Duration::from_millis(200))) {
-                    let mut buf = [0u8; 64];
-                    let n = self.stdin.read(&mut buf)?;
-                    let input_queue = &mut self.input_queue;
-                    self.parser
-                        .parse(&buf[0..n], |evt| input_queue.push_back(evt), n == buf.len());
-                    return Ok(self.input_queue.pop_front());
-                } else {
-                    let size = *self.size.lock().unwrap();
-                    if starting_size != size {
-                        return Ok(Some(InputEvent::Resized {
-                            cols: size.cols as usize,
-                            rows: size.rows as usize,
-                        }));
-                    }
+            }
+            let mut pfd = [pollfd {
+                fd: self.stdin.as_socket_descriptor(),
+                events: POLLIN,
+                revents: 0,
+            }];
+
+            if let Ok(1) = poll(&mut pfd, Some(Duration::from_millis(200))) {
+                let mut buf = [0u8; 64];
+                let n = self.stdin.read(&mut buf)?;
+                if n == 0 {
+                    // The pane has gone away
+                    termwiz::bail!("TerminalShim input was closed");
+                }
+                let input_queue = &mut self.input_queue;
+                self.parser
+                    .parse(&buf[0..n], |evt| input_queue.push_back(evt), n == buf.len());
+                return Ok(self.input_queue.pop_front());
+            } else {
+                let size = *self.size.lock().unwrap();
+                if starting_size != size {
+                    return Ok(Some(InputEvent::Resized {
+                        cols: size.cols as usize,
+                        rows: size.rows as usize,
+                    }));
                 }
             }
         }
-
-        fn waker(&self) -> TerminalWaker {
-            // TODO: TerminalWaker assumes that we're a SystemTerminal but that
-            // isn't the case here.
-            panic!("TerminalShim::waker called!?");
-        }
     }
 
-    let renderer = termwiz_funcs::new_wezterm_terminfo_renderer();
-    let mut shim = TerminalShim {
-        stdout: &mut StdoutShim {
-            stdout: stdout_write,
-            size: Arc::clone(&size),
-        },
-        size: Arc::clone(&size),
-        renderer,
-        stdin: &mut stdin_read,
-        parser: InputParser::new(),
-        input_queue: VecDeque::new(),
-    };
-
-    impl<'a> TerminalShim<'a> {
-        fn output_line(&mut self, s: &str) -> termwiz::Result<()> {
-            let mut s = s.replace("\n", "\r\n");
-            s.push_str("\r\n");
-            self.render(&[Change::Text(s)])
-        }
+    fn waker(&self) -> TerminalWaker {
+        // TODO: TerminalWaker assumes that we're a SystemTerminal but that
+        // isn't the case here.
+        panic!("TerminalShim::waker called!?");
     }
+}
+
+/// The command that runs in a pane, kept so that it can be started
+/// again when the pane is reconnected
+#[derive(Clone)]
+struct RemoteCommand {
+    command_line: Option<String>,
+    env: HashMap<String, String>,
+}
 
+/// Carry out the authentication process of a new session, and then
+/// start the command of the pane.
+/// Returns None if the command was not started, in which case the
+/// reason has been shown to the user.
+fn connect_ssh_session(
+    session: &Session,
+    events: smol::channel::Receiver<SessionEvent>,
+    shim: &mut TerminalShim,
+    command: &RemoteCommand,
+) -> anyhow::Result<Option<(SshPty, SshChildProcess)>> {
     // Process authentication related events
     while let Ok(event) = smol::block_on(events.recv()) {
         match event {
@@ -459,7 +535,7 @@ fn connect_ssh_session(
             }
             SessionEvent::HostVerify(verify) => {
                 shim.output_line(&verify.message)?;
-                let mut editor = LineEditor::new(&mut shim);
+                let mut editor = LineEditor::new(shim);
                 let mut host = PasswordPromptHost::default();
                 host.echo = true;
                 editor.set_prompt("Enter [y/n]> ");
@@ -487,7 +563,7 @@ fn connect_ssh_session(
                     for line in &prompt_lines {
                         shim.output_line(line)?;
                     }
-                    let mut editor = LineEditor::new(&mut shim);
+                    let mut editor = LineEditor::new(shim);
                     let mut host = PasswordPromptHost::default();
                     editor.set_prompt(editor_prompt);
                     host.echo = prompt.echo;
@@ -505,163 +581,292 @@ fn connect_ssh_session(
             SessionEvent::Authenticated => {
                 // Our session has been authenticated: we can now
                 // set up the real pty for the pane
-                match smol::block_on(session.request_pty(
-                    &config::configuration().term,
-                    crate::terminal_size_to_pty_size(*size.lock().unwrap())?,
-                    command_line.as_ref().map(|s| s.as_str()),
-                    Some(env),
-                )) {
-                    Err(err) => {
-                        shim.output_line(&format!("Failed to spawn command: {:#}", err))?;
-                        break;
-                    }
-                    Ok((pty, child)) => {
-                        drop(shim);
-
-                        // Obtain the real stdin/stdout for the pty
-                        let reader = pty.try_clone_reader()?;
-                        let writer = pty.try_clone_writer()?;
-
-                        // And send them to the wrapped reader/writer
-                        stdin_tx
-                            .send(Box::new(writer))
-                            .map_err(|e| anyhow!("{:#}", e))?;
-                        stdout_tx
-                            .send(Box::new(reader))
-                            .map_err(|e| anyhow!("{:#}", e))?;
-
-                        // Likewise, send the real pty and child to
-                        // the wrappers
-                        pty_tx.send(pty)?;
-                        child_tx.send(child)?;
-
-                        // Now when we return, our stdin_read and
-                        // stdout_write will close and that will cause
-                        // the PtyReader and PtyWriter to recv the
-                        // the new reader/writer above and continue.
-                        //
-                        // The pty and child will be picked up when
-                        // they are next polled or resized.
-
-                        return Ok(());
-                    }
-                }
+                return start_command(session, shim, command);
             }
         }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// Starts the command of a pane in a new pty of `session`.
+/// Returns None if that failed, in which case the reason has been
+/// shown to the user.
+fn start_command(
+    session: &Session,
+    shim: &mut TerminalShim,
+    command: &RemoteCommand,
+) -> anyhow::Result<Option<(SshPty, SshChildProcess)>> {
+    match smol::block_on(session.request_pty(
+        &config::configuration().term,
+        crate::terminal_size_to_pty_size(*shim.size.lock().unwrap())?,
+        command.command_line.as_deref(),
+        Some(command.env.clone()),
+    )) {
+        Ok(started) => Ok(Some(started)),
+        Err(err) => {
+            shim.output_line(&format!("Failed to spawn command: {:#}", err))?;
+            Ok(None)
+        }
+    }
+}
+
+/// What is needed to connect a pane again once its connection has
+/// been lost
+#[derive(Clone)]
+struct PaneConnection {
+    pane_id: PaneId,
+    domain_id: DomainId,
+    remote_address: String,
+    command: RemoteCommand,
+    channels: PaneChannels,
+    killer: WrappedSshChildKiller,
+}
+
+impl PaneConnection {
+    /// Gives up on the pane, which is then closed rather than being
+    /// held open by its exit_behavior
+    fn close(self) {
+        self.killer.inner.lock().unwrap().pending_kill = true;
+        promise::spawn::spawn_into_main_thread(async move {
+            let mux = Mux::get().unwrap();
+            if let Some(pane) = mux.get_pane(self.pane_id) {
+                pane.kill();
+            }
+            self.channels.close();
+        })
+        .detach();
+    }
+
+    /// Returns true if the pane has been killed, for example because
+    /// it was closed while it was waiting to be reconnected
+    fn is_abandoned(&self) -> bool {
+        self.killer.inner.lock().unwrap().pending_kill
+    }
+}
+
+/// Runs on a thread of its own once the connection of a pane has been
+/// lost.  The pane counts down to the next attempt to reconnect, at
+/// which the domain is connected again, unless another of its panes
+/// has already done that, and the command of the pane is started
+/// afresh, as the remote processes ended along with the connection.
+fn reconnect_pane(pane: PaneConnection) {
+    if let Err(err) = reconnect_pane_impl(&pane) {
+        log::error!("Failed to reconnect pane {}: {:#}", pane.pane_id, err);
+        pane.close();
+    }
+}
+
+fn reconnect_pane_impl(pane: &PaneConnection) -> anyhow::Result<()> {
+    let (stdin_read, stdout_write) = pane.channels.start_prompt()?;
+    let mut shim = TerminalShim::new(stdin_read, stdout_write, Arc::clone(&pane.channels.size));
+    shim.output_line(&format!(
+        "\r\n*** The connection to {} was lost, and the processes in this pane ended with it ***",
+        pane.remote_address
+    ))?;
+
+    let mut backoff = Backoff::default();
+    loop {
+        match wait_to_retry(&mut shim, backoff.next_delay(), || pane.is_abandoned())? {
+            RetryChoice::Retry => {}
+            RetryChoice::Close => {
+                pane.clone().close();
+                return Ok(());
+            }
+        }
+        shim.output_line(&format!("Reconnecting to {}...", pane.remote_address))?;
+
+        // The domain lives on the main thread
+        let domain_id = pane.domain_id;
+        let session =
+            promise::spawn::block_on(promise::spawn::spawn_into_main_thread(async move {
+                let mux = Mux::get().unwrap();
+                let domain = mux
+                    .get_domain(domain_id)
+                    .ok_or_else(|| anyhow!("domain {} not found", domain_id))?;
+                let domain = domain
+                    .downcast_ref::<RemoteSshDomain>()
+                    .ok_or_else(|| anyhow!("domain {} is not an ssh domain", domain_id))?;
+                domain.session()
+            }));
+        let started = match session {
+            Ok((session, Some(events))) => {
+                connect_ssh_session(&session, events, &mut shim, &pane.command)
+            }
+            Ok((session, None)) => start_command(&session, &mut shim, &pane.command),
+            Err(err) => Err(err),
+        };
+        match started {
+            Ok(Some((pty, child))) => {
+                shim.output_line(&format!(
+                    "*** Reconnected to {}.  The processes that were running in this pane \
+                     ended when the connection was lost, so its command was started again ***",
+                    pane.remote_address
+                ))?;
+                pane.channels.connect(pty, child)?;
+                log::info!("pane {} was reconnected", pane.pane_id);
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(err) => shim.output_line(&format!("Error: {:#}", err))?,
+        }
+    }
 }
 
 #[async_trait(?Send)]
 impl Domain for RemoteSshDomain {
+    fn map_cwd(&self, dir: &CommandDir, from_domain: Option<&Arc<dyn Domain>>) -> CwdMapping {
+        // A path reported by a shell on the remote host, such as one
+        // that was started by running ssh in a local pane, is
+        // meaningful here whichever domain its pane belongs to
+        if dir.host.is_some() {
+            let alias = self
+                .dom
+                .remote_address
+                .split(':')
+                .next()
+                .unwrap_or(&self.dom.remote_address);
+            let hostname = self
+                .ssh_config()
+                .ok()
+                .and_then(|config| config.get("hostname").cloned());
+            if dir.is_on_host(alias) || hostname.map_or(false, |hostname| dir.is_on_host(&hostname))
+            {
+                return CwdMapping::Use(dir.clone());
+            }
+        }
+        map_cwd_within_domain(self.id, &self.name, dir, from_domain)
+    }
+
+    /// The command runs over the session of the domain, which must
+    /// already have been established by spawning a pane into it, as
+    /// authenticating a new session may need to prompt the user
+    async fn exec(&self, request: ExecRequest) -> anyhow::Result<ExecOutput> {
+        let cmd = request.command()?;
+        let session = self
+            .session
+            .borrow()
+            .as_ref()
+            .filter(|session| !session.is_closed())
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "domain {} is not connected; spawn a pane into it first",
+                    self.name
+                )
+            })?;
+        let exec = session
+            .exec(&build_cd_command(&cmd, self.dom.assume_shell)?, None)
+            .await?;
+        let timeout = request.timeout;
+        smol::unblock(move || {
+            // The command gets no input
+            drop(exec.stdin);
+            crate::domainexec::capture(
+                Box::new(exec.child),
+                Box::new(exec.stdout),
+                Box::new(exec.stderr),
+                timeout,
+            )
+        })
+        .await
+    }
+
     async fn spawn_pane(
         &self,
+        pane_id: PaneId,
         size: TerminalSize,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        let pane_id = alloc_pane_id();
-
+        command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        let exit_behavior = command
+            .as_ref()
+            .and_then(|cmd| cmd.get_exit_behavior())
+            .and_then(|behavior| behavior.parse().ok())
+            .or(self.dom.exit_behavior);
+        // Kept so that the pane can be respawned after it has exited
+        let (respawn_command, respawn_dir) = (command.clone(), command_dir.clone());
         let (command_line, env) = self.build_command(pane_id, command, command_dir)?;
-
-        let pty: Box<dyn portable_pty::MasterPty>;
-        let child: Box<dyn portable_pty::Child + Send>;
-        let writer: BoxedWriter;
-
-        let session = self.session.borrow().as_ref().map(|s| s.clone());
-
-        if let Some(session) = session {
-            let (concrete_pty, concrete_child) = session
-                .request_pty(
-                    &config::configuration().term,
-                    crate::terminal_size_to_pty_size(size)?,
-                    command_line.as_ref().map(|s| s.as_str()),
-                    Some(env),
-                )
-                .await?;
-
-            pty = Box::new(concrete_pty);
-            child = Box::new(concrete_child);
-            writer = Box::new(pty.try_clone_writer()?);
-        } else {
-            // We're starting the session
-            let (session, events) = Session::connect(self.ssh_config()?)?;
-            self.session.borrow_mut().replace(session.clone());
-
-            // We get to establish the session!
-            //
-            // Since we want spawn to return the Pane in which
-            // we'll carry out interactive auth, we generate
-            // some shim/wrapper versions of the pty, child
-            // and reader/writer.
-
-            let (stdout_read, stdout_write) = socketpair()?;
-            let (reader_tx, reader_rx) = channel();
-            let (stdin_read, stdin_write) = socketpair()?;
-            let (writer_tx, writer_rx) = channel();
-
-            let pty_reader = PtyReader {
-                reader: Box::new(stdout_read),
-                rx: reader_rx,
-            };
-
-            let pty_writer = PtyWriter {
-                writer: Box::new(stdin_write),
-                rx: writer_rx,
-            };
-            writer = Box::new(pty_writer);
-
-            let (child_tx, child_rx) = channel();
-
-            child = Box::new(WrappedSshChild {
-                status: None,
-                rx: child_rx,
-                exited: None,
-                killer: WrappedSshChildKiller {
-                    inner: Arc::new(Mutex::new(KillerInner {
-                        killer: None,
-                        pending_kill: false,
-                    })),
-                },
-            });
-
-            let (pty_tx, pty_rx) = channel();
-
-            let size = Arc::new(Mutex::new(size));
-
-            pty = Box::new(WrappedSshPty {
-                inner: RefCell::new(WrappedSshPtyInner::Connecting {
-                    size: Arc::clone(&size),
-                    reader: Some(pty_reader),
-                    connected: pty_rx,
-                }),
-            });
-
-            // And with those created, we can now spawn a new thread
-            // to perform the blocking (from its perspective) terminal
-            // UI to carry out any authentication.
-            let mut stdout_write = BufWriter::new(stdout_write);
-            std::thread::spawn(move || {
-                if let Err(err) = connect_ssh_session(
-                    session,
-                    events,
-                    stdin_read,
-                    writer_tx,
-                    &mut stdout_write,
-                    reader_tx,
-                    child_tx,
-                    pty_tx,
-                    size,
-                    command_line,
-                    env,
-                ) {
-                    let _ = write!(stdout_write, "{:#}", err);
-                    log::error!("Failed to connect ssh: {:#}", err);
-                }
-                let _ = stdout_write.flush();
-            });
+        let command = RemoteCommand { command_line, env };
+
+        // The pane holds wrappers around the pty, process, reader and
+        // writer, which are handed the real ones each time the pane is
+        // connected.  That happens either while we carry out the
+        // interactive authentication of a new session in the pane, or
+        // later if its connection is lost and made again.
+        let (channels, wrappers) = PaneChannels::new(size);
+        let killer = WrappedSshChildKiller {
+            inner: Arc::new(Mutex::new(KillerInner {
+                killer: None,
+                pending_kill: false,
+            })),
         };
+        let connection = PaneConnection {
+            pane_id,
+            domain_id: self.id,
+            remote_address: self.dom.remote_address.clone(),
+            command,
+            channels,
+            killer: killer.clone(),
+        };
+        let child: Box<dyn portable_pty::Child + Send> = Box::new(WrappedSshChild {
+            status: None,
+            rx: wrappers.child_rx,
+            exited: None,
+            killer,
+            connection: connection.clone(),
+        });
+        let pty: Box<dyn portable_pty::MasterPty> = Box::new(wrappers.pty);
+        let writer: BoxedWriter = Box::new(wrappers.writer);
+
+        match self.session()? {
+            (session, None) => {
+                // The session is already established, so a failure to
+                // start the command fails the spawn
+                let (concrete_pty, concrete_child) = session
+                    .request_pty(
+                        &config::configuration().term,
+                        crate::terminal_size_to_pty_size(size)?,
+                        connection.command.command_line.as_deref(),
+                        Some(connection.command.env.clone()),
+                    )
+                    .await?;
+                connection.channels.connect(concrete_pty, concrete_child)?;
+            }
+            (session, Some(events)) => {
+                // We get to establish the session!
+                //
+                // Since we want spawn to return the Pane in which
+                // we'll carry out interactive auth, we spawn a new
+                // thread to perform the blocking (from its perspective)
+                // terminal UI, talking to the pane via a socketpair.
+                let (stdin_read, stdout_write) = connection.channels.start_prompt()?;
+                std::thread::spawn(move || {
+                    let mut shim = TerminalShim::new(
+                        stdin_read,
+                        stdout_write,
+                        Arc::clone(&connection.channels.size),
+                    );
+                    let started =
+                        connect_ssh_session(&session, events, &mut shim, &connection.command)
+                            .and_then(|started| match started {
+                                Some((pty, child)) => {
+                                    connection.channels.connect(pty, child).map(|_| true)
+                                }
+                                None => Ok(false),
+                            });
+                    match started {
+                        Ok(true) => {}
+                        Ok(false) => connection.channels.close(),
+                        Err(err) => {
+                            let _ = shim.output_line(&format!("{:#}", err));
+                            log::error!("Failed to connect ssh: {:#}", err);
+                            connection.channels.close();
+                        }
+                    }
+                });
+            }
+        }
 
         // Wrap up the pty etc. in a LocalPane.  That allows for
         // eg: tmux integration to be tunnelled via the remote
@@ -675,14 +880,22 @@ impl Domain for RemoteSshDomain {
             writer,
         );
 
-        let pane: Rc<dyn Pane> = Rc::new(LocalPane::new(
+        let mut pane = LocalPane::new(
             pane_id,
             terminal,
             child,
             pty,
             self.id,
             "RemoteSshDomain".to_string(),
-        ));
+        );
+        if let Some(exit_behavior) = exit_behavior {
+            pane.set_exit_behavior(exit_behavior);
+        }
+        pane.set_spawn_command(respawn_command, respawn_dir);
+        let pane: Rc<dyn Pane> = Rc::new(pane);
+        if let Some(overrides) = self.palette_overrides() {
+            pane.set_palette_overrides(Some(overrides))?;
+        }
         let mux = Mux::get().unwrap();
         mux.add_pane(&pane)?;
 
@@ -697,12 +910,24 @@ impl Domain for RemoteSshDomain {
         &self.name
     }
 
-    async fn attach(&self, _window_id: Option<crate::WindowId>) -> anyhow::Result<()> {
+    fn domain_kind(&self) -> Option<DomainKind> {
+        Some(DomainKind::Ssh)
+    }
+
+    fn launcher_hints(&self) -> LauncherHints {
+        self.dom.launcher
+    }
+
+    fn palette_overrides(&self) -> Option<Palette> {
+        self.dom.palette_overrides.clone()
+    }
+
+    async fn attach(&self, _window_id: Option<crate::WindowId>) -> DomainResult<()> {
         Ok(())
     }
 
-    fn detach(&self) -> anyhow::Result<()> {
-        bail!("detach not implemented for RemoteSshDomain");
+    fn detach(&self) -> DomainResult<()> {
+        Err(anyhow!("detach not implemented for RemoteSshDomain").into())
     }
 
     fn state(&self) -> DomainState {
@@ -711,6 +936,12 @@ impl Domain for RemoteSshDomain {
         // a spawn.
         DomainState::Attached
     }
+
+    fn notification_handling(&self) -> NotificationHandling {
+        self.dom
+            .notifications
+            .unwrap_or_else(|| config::configuration().notification_handling)
+    }
 }
 
 #[derive(Debug)]
@@ -727,21 +958,43 @@ struct WrappedSshChildKiller {
     inner: Arc<Mutex<KillerInner>>,
 }
 
-#[derive(Debug)]
+/// Tells the wrapped process of a pane about the real process that
+/// it stands for
+enum ChildEvent {
+    /// The command of the pane was started
+    Started(SshChildProcess),
+    /// The pane could not be connected, or the user gave up on it
+    Closed,
+}
+
 struct WrappedSshChild {
     status: Option<AsyncReceiver<ExitStatus>>,
-    rx: Receiver<SshChildProcess>,
+    rx: Receiver<ChildEvent>,
     exited: Option<ExitStatus>,
     killer: WrappedSshChildKiller,
+    /// Used to reconnect the pane if its connection is lost
+    connection: PaneConnection,
+}
+
+impl std::fmt::Debug for WrappedSshChild {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("WrappedSshChild")
+            .field("pane_id", &self.connection.pane_id)
+            .field("exited", &self.exited)
+            .finish()
+    }
 }
 
 impl WrappedSshChild {
     fn check_connected(&mut self) {
         if self.status.is_none() {
             match self.rx.try_recv() {
-                Ok(c) => {
+                Ok(ChildEvent::Started(c)) => {
                     self.got_child(c);
                 }
+                Ok(ChildEvent::Closed) => {
+                    self.exited.replace(ExitStatus::with_exit_code(1));
+                }
                 Err(TryRecvError::Empty) => {}
                 Err(err) => {
                     log::error!("WrappedSshChild err: {:#?}", err);
@@ -761,12 +1014,32 @@ impl WrappedSshChild {
         }
 
         let (tx, rx) = bounded(1);
+        let connection = self.connection.clone();
         promise::spawn::spawn_into_main_thread(async move {
-            if let Ok(status) = child.async_wait().await {
-                tx.send(status).await.ok();
-                let mux = Mux::get().unwrap();
-                mux.prune_dead_windows();
+            let status = child
+                .async_wait()
+                .await
+                .unwrap_or_else(|_| ExitStatus::with_exit_code(1));
+            if child.lost_session() {
+                let mut killer = connection.killer.inner.lock().unwrap();
+                if !killer.pending_kill {
+                    // Rather than reporting the exit, the pane waits
+                    // to be connected again; dropping `tx` tells the
+                    // wrapper to expect another process
+                    log::warn!(
+                        "pane {} lost its connection to {}",
+                        connection.pane_id,
+                        connection.remote_address
+                    );
+                    killer.killer.take();
+                    drop(killer);
+                    std::thread::spawn(move || reconnect_pane(connection));
+                    return;
+                }
             }
+            tx.send(status).await.ok();
+            let mux = Mux::get().unwrap();
+            mux.prune_dead_windows();
         })
         .detach();
         self.status.replace(rx);
@@ -788,48 +1061,51 @@ impl portable_pty::Child for WrappedSshChild {
                     Ok(Some(status))
                 }
                 Err(smol::channel::TryRecvError::Empty) => Ok(None),
-                Err(err) => {
-                    log::error!("WrappedSshChild err: {:#?}", err);
-                    let status = ExitStatus::with_exit_code(1);
-                    self.exited.replace(status.clone());
-                    Ok(Some(status))
+                Err(smol::channel::TryRecvError::Closed) => {
+                    // The connection was lost, and the pane is
+                    // being reconnected
+                    self.status.take();
+                    Ok(None)
                 }
             }
         } else {
-            Ok(None)
+            Ok(self.exited.clone())
         }
     }
 
     fn wait(&mut self) -> std::io::Result<portable_pty::ExitStatus> {
-        if let Some(status) = self.exited.as_ref() {
-            return Ok(status.clone());
-        }
+        loop {
+            if let Some(status) = self.exited.as_ref() {
+                return Ok(status.clone());
+            }
 
-        if self.status.is_none() {
-            match smol::block_on(async { self.rx.recv() }) {
-                Ok(c) => {
-                    self.got_child(c);
-                }
-                Err(err) => {
-                    log::error!("WrappedSshChild err: {:#?}", err);
-                    let status = ExitStatus::with_exit_code(1);
-                    self.exited.replace(status.clone());
-                    return Ok(status);
+            if self.status.is_none() {
+                match self.rx.recv() {
+                    Ok(ChildEvent::Started(c)) => {
+                        self.got_child(c);
+                    }
+                    Ok(ChildEvent::Closed) => {
+                        self.exited.replace(ExitStatus::with_exit_code(1));
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("WrappedSshChild err: {:#?}", err);
+                        self.exited.replace(ExitStatus::with_exit_code(1));
+                        continue;
+                    }
                 }
             }
-        }
 
-        let rx = self.status.as_mut().unwrap();
-        match smol::block_on(rx.recv()) {
-            Ok(status) => {
-                self.exited.replace(status.clone());
-                Ok(status)
-            }
-            Err(err) => {
-                log::error!("WrappedSshChild err: {:#?}", err);
-                let status = ExitStatus::with_exit_code(1);
-                self.exited.replace(status.clone());
-                Ok(status)
+            let rx = self.status.as_mut().unwrap();
+            match smol::block_on(rx.recv()) {
+                Ok(status) => {
+                    self.exited.replace(status);
+                }
+                Err(_) => {
+                    // The connection was lost; wait for the process
+                    // that is started when the pane is reconnected
+                    self.status.take();
+                }
             }
         }
     }
@@ -879,20 +1155,127 @@ impl ChildKiller for WrappedSshChildKiller {
 type BoxedReader = Box<(dyn Read + Send + 'static)>;
 type BoxedWriter = Box<(dyn Write + Send + 'static)>;
 
+/// Hands a pane the real pty, process, reader and writer each time
+/// that it is connected, and the ends of a socketpair while it talks
+/// to the user instead, such as during authentication
+#[derive(Clone)]
+struct PaneChannels {
+    reader_tx: Sender<BoxedReader>,
+    writer_tx: Sender<BoxedWriter>,
+    pty_tx: Sender<SshPty>,
+    child_tx: Sender<ChildEvent>,
+    /// The size of the pane, with which the pty is started
+    size: Arc<Mutex<TerminalSize>>,
+}
+
+/// The wrappers that a pane holds in place of its pty, process,
+/// reader and writer
+struct PaneWrappers {
+    pty: WrappedSshPty,
+    writer: PtyWriter,
+    child_rx: Receiver<ChildEvent>,
+}
+
+impl PaneChannels {
+    fn new(size: TerminalSize) -> (Self, PaneWrappers) {
+        let (reader_tx, reader_rx) = channel();
+        let (writer_tx, writer_rx) = channel();
+        let (pty_tx, pty_rx) = channel();
+        let (child_tx, child_rx) = channel();
+        let size = Arc::new(Mutex::new(size));
+
+        // The reader and writer start out with nothing to talk to,
+        // until they are handed either the real ones or a socketpair
+        let reader = PtyReader {
+            reader: Box::new(std::io::empty()),
+            rx: reader_rx,
+        };
+        let writer = PtyWriter {
+            writer: Box::new(std::io::sink()),
+            rx: writer_rx,
+        };
+        let pty = WrappedSshPty {
+            inner: RefCell::new(WrappedSshPtyInner {
+                reader: Some(reader),
+                connected: pty_rx,
+                size: Arc::clone(&size),
+                pty: None,
+            }),
+        };
+
+        (
+            Self {
+                reader_tx,
+                writer_tx,
+                pty_tx,
+                child_tx,
+                size,
+            },
+            PaneWrappers {
+                pty,
+                writer,
+                child_rx,
+            },
+        )
+    }
+
+    /// Hands the pane a newly started pty and its process.
+    /// The reader and writer pick up theirs once the ones they are
+    /// using are done, and the pty and child will be picked up when
+    /// they are next polled or resized.
+    fn connect(&self, pty: SshPty, child: SshChildProcess) -> anyhow::Result<()> {
+        // Obtain the real stdin/stdout for the pty
+        let reader = pty.try_clone_reader()?;
+        let writer = pty.try_clone_writer()?;
+
+        // And send them to the wrapped reader/writer
+        self.writer_tx
+            .send(Box::new(writer))
+            .map_err(|e| anyhow!("{:#}", e))?;
+        self.reader_tx
+            .send(Box::new(reader))
+            .map_err(|e| anyhow!("{:#}", e))?;
+
+        // Likewise, send the real pty and child to the wrappers
+        self.pty_tx.send(pty).map_err(|e| anyhow!("{:#}", e))?;
+        self.child_tx
+            .send(ChildEvent::Started(child))
+            .map_err(|e| anyhow!("{:#}", e))?;
+        Ok(())
+    }
+
+    /// Has the pane show what is written to the returned writer, and
+    /// send its input to the returned reader, until the writer is
+    /// dropped and the pane is handed the real ones by `connect`
+    fn start_prompt(&self) -> anyhow::Result<(FileDescriptor, BufWriter<FileDescriptor>)> {
+        let (stdout_read, stdout_write) = socketpair().context("socketpair")?;
+        let (stdin_read, stdin_write) = socketpair().context("socketpair")?;
+        self.reader_tx
+            .send(Box::new(stdout_read))
+            .map_err(|e| anyhow!("{:#}", e))?;
+        self.writer_tx
+            .send(Box::new(stdin_write))
+            .map_err(|e| anyhow!("{:#}", e))?;
+        Ok((stdin_read, BufWriter::new(stdout_write)))
+    }
+
+    /// Tells the pane that its process has gone for good
+    fn close(&self) {
+        self.child_tx.send(ChildEvent::Closed).ok();
+    }
+}
+
 struct WrappedSshPty {
     inner: RefCell<WrappedSshPtyInner>,
 }
 
-enum WrappedSshPtyInner {
-    Connecting {
-        reader: Option<PtyReader>,
-        connected: Receiver<SshPty>,
-        size: Arc<Mutex<TerminalSize>>,
-    },
-    Connected {
-        reader: Option<PtyReader>,
-        pty: SshPty,
-    },
+struct WrappedSshPtyInner {
+    reader: Option<PtyReader>,
+    /// Receives the real pty each time that the pane is connected
+    connected: Receiver<SshPty>,
+    size: Arc<Mutex<TerminalSize>>,
+    /// The real pty, once the pane has been connected
+    pty: Option<SshPty>,
 }
 
 struct PtyReader {
@@ -925,68 +1308,51 @@ impl std::io::Write for WrappedSshPty {
 
 impl WrappedSshPtyInner {
     fn check_connected(&mut self) -> anyhow::Result<()> {
-        match self {
-            Self::Connecting {
-                reader,
-                connected,
-                size,
-                ..
-            } => {
-                if let Ok(pty) = connected.try_recv() {
-                    let res = pty.resize(crate::terminal_size_to_pty_size(*size.lock().unwrap())?);
-                    *self = Self::Connected {
-                        pty,
-                        reader: reader.take(),
-                    };
-                    res
-                } else {
-                    Ok(())
-                }
-            }
-            _ => Ok(()),
+        let mut res = Ok(());
+        while let Ok(pty) = self.connected.try_recv() {
+            res = pty.resize(crate::terminal_size_to_pty_size(
+                *self.size.lock().unwrap(),
+            )?);
+            self.pty.replace(pty);
         }
+        res
     }
 }
 
 impl portable_pty::MasterPty for WrappedSshPty {
     fn resize(&self, new_size: PtySize) -> anyhow::Result<()> {
         let mut inner = self.inner.borrow_mut();
-        match &mut *inner {
-            WrappedSshPtyInner::Connecting { ref mut size, .. } => {
-                {
-                    let mut size = size.lock().unwrap();
-                    size.cols = new_size.cols as usize;
-                    size.rows = new_size.rows as usize;
-                    size.pixel_height = new_size.pixel_height as usize;
-                    size.pixel_width = new_size.pixel_width as usize;
-                }
-                inner.check_connected()
+        {
+            let mut size = inner.size.lock().unwrap();
+            size.cols = new_size.cols as usize;
+            size.rows = new_size.rows as usize;
+            size.pixel_height = new_size.pixel_height as usize;
+            size.pixel_width = new_size.pixel_width as usize;
+        }
+        inner.check_connected()?;
+        if let Some(pty) = inner.pty.as_ref() {
+            // While the connection is lost, the size is only recorded,
+            // and is applied to the pty that is started on reconnecting
+            if let Err(err) = pty.resize(new_size) {
+                log::debug!("resizing ssh pty: {:#}", err);
             }
-            WrappedSshPtyInner::Connected { pty, .. } => pty.resize(new_size),
         }
+        Ok(())
     }
 
     fn get_size(&self) -> anyhow::Result<PtySize> {
         let mut inner = self.inner.borrow_mut();
-        match &*inner {
-            WrappedSshPtyInner::Connecting { size, .. } => {
-                let size = crate::terminal_size_to_pty_size(*size.lock().unwrap())?;
-                inner.check_connected()?;
-                Ok(size)
-            }
-            WrappedSshPtyInner::Connected { pty, .. } => pty.get_size(),
-        }
+        let size = crate::terminal_size_to_pty_size(*inner.size.lock().unwrap())?;
+        inner.check_connected()?;
+        Ok(size)
     }
 
     fn try_clone_reader(&self) -> anyhow::Result<Box<(dyn Read + Send + 'static)>> {
         let mut inner = self.inner.borrow_mut();
         inner.check_connected()?;
-        match &mut *inner {
-            WrappedSshPtyInner::Connected { ref mut reader, .. }
-            | WrappedSshPtyInner::Connecting { ref mut reader, .. } => match reader.take() {
-                Some(r) => Ok(Box::new(r)),
-                None => anyhow::bail!("reader already taken"),
-            },
+        match inner.reader.take() {
+            Some(r) => Ok(Box::new(r)),
+            None => anyhow::bail!("reader already taken"),
         }
     }
 
@@ -1031,15 +1397,82 @@ impl std::io::Write for PtyWriter {
 
 impl std::io::Read for PtyReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self.reader.read(buf) {
-            Ok(len) if len > 0 => Ok(len),
-            res => match self.rx.recv() {
-                Ok(reader) => {
-                    self.reader = reader;
-                    self.reader.read(buf)
-                }
-                _ => res,
-            },
+        loop {
+            match self.reader.read(buf) {
+                Ok(len) if len > 0 => return Ok(len),
+                res => match self.rx.recv() {
+                    Ok(reader) => {
+                        self.reader = reader;
+                    }
+                    _ => return res,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::LocalDomain;
+
+    #[test]
+    fn cd_only_for_posix_shells() {
+        let mut cmd = CommandBuilder::new("make");
+        cmd.arg("all");
+        assert_eq!(build_cd_command(&cmd, Shell::Posix).unwrap(), "make all");
+
+        cmd.cwd("/src/my project");
+        assert_eq!(
+            build_cd_command(&cmd, Shell::Posix).unwrap(),
+            "cd '/src/my project'; make all"
+        );
+        assert_eq!(build_cd_command(&cmd, Shell::Unknown).unwrap(), "make all");
+    }
+
+    #[test]
+    fn map_cwd_on_the_remote_host() {
+        let ssh: Arc<dyn Domain> = Arc::new(
+            RemoteSshDomain::with_ssh_domain(&SshDomain {
+                name: "devbox".to_string(),
+                remote_address: "devbox:2222".to_string(),
+                ssh_backend: Some(SshBackend::Ssh2),
+                ssh_option: vec![("hostname".to_string(), "build01.example.com".to_string())]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let local: Arc<dyn Domain> = Arc::new(LocalDomain::new("local").unwrap());
+        // (host of the dir, domain of its pane, whether it is used)
+        let matrix = [
+            (None, None, true),
+            (None, Some(&ssh), true),
+            (None, Some(&local), false),
+            // The alias from remote_address, without the port
+            (Some("devbox"), Some(&local), true),
+            // The hostname that the alias resolves to, as reported
+            // by a shell that was started by running ssh locally
+            (Some("build01"), Some(&local), true),
+            (Some("build01.example.com"), None, true),
+            (Some("elsewhere"), Some(&local), false),
+            (Some("elsewhere"), Some(&ssh), true),
+        ];
+        for (host, from, used) in matrix {
+            let dir = CommandDir {
+                host: host.map(str::to_string),
+                path: "/src".to_string(),
+            };
+            let mapping = ssh.map_cwd(&dir, from);
+            assert_eq!(
+                mapping == CwdMapping::Use(dir),
+                used,
+                "host={:?} from={:?} -> {:?}",
+                host,
+                from.map(|from| from.domain_name()),
+                mapping
+            );
         }
     }
 }
@@ -0,0 +1,348 @@
+//! Panes that front something other than a process, such as a serial
+//! bridge, a websocket to a remote shell or the output of a lua
+//! coroutine, without each needing a `Domain` of its own.
+//! A stream pane is a `LocalPane` whose pty is a pair of streams: the
+//! output of the reader is fed to its terminal, and the input to the
+//! pane is written to the writer.  Being a `LocalPane`, it lives in
+//! tabs and splits, and keeps its scrollback, like any other.
+//! As there is no process, the pane doesn't die when the reader reaches
+//! the end of its stream; it stays open until it is marked as dead with
+//! `mark_dead`, after which its domain's `exit_behavior` applies, or
+//! until it is closed.  Closing the pane drops the streams.
+use crate::domain::DomainId;
+use crate::localpane::LocalPane;
+use crate::pane::{alloc_pane_id, Pane, PaneId};
+use crate::tab::Tab;
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::{anyhow, Context};
+use filedescriptor::FileDescriptor;
+use portable_pty::{Child, ChildKiller, ExitStatus, MasterPty, PtySize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use wezterm_term::TerminalSize;
+
+lazy_static::lazy_static! {
+    /// The stream panes that have not yet been marked as dead or
+    /// closed, and how to end them
+    static ref STREAM_PANES: Mutex<HashMap<PaneId, Arc<Ended>>> = Mutex::new(HashMap::new());
+}
+
+/// Describes a stream pane to create
+pub struct StreamPaneSpec {
+    /// The domain that the pane belongs to, which decides, for
+    /// example, where a split of the pane is spawned
+    pub domain_id: DomainId,
+    /// The title of the pane, until the stream sets one
+    pub title: String,
+    /// The size of the pane, if it is placed in a new window
+    pub size: Option<TerminalSize>,
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+}
+
+/// Creates a stream pane in a new tab of `window_id`, or of a new
+/// window in `workspace` if it is None
+pub fn spawn_stream_pane(
+    spec: StreamPaneSpec,
+    window_id: Option<WindowId>,
+    workspace: String,
+) -> anyhow::Result<(Rc<Tab>, Rc<dyn Pane>, WindowId)> {
+    let mux = Mux::get().ok_or_else(|| anyhow!("stream panes are made on the main thread"))?;
+    mux.get_domain(spec.domain_id)
+        .ok_or_else(|| anyhow!("domain {} not found", spec.domain_id))?;
+
+    let window_builder;
+    let (window_id, size) = match window_id {
+        Some(window_id) => {
+            let window = mux
+                .get_window(window_id)
+                .ok_or_else(|| anyhow!("window {} not found", window_id))?;
+            let size = window
+                .get_active()
+                .map(|tab| tab.get_size())
+                .unwrap_or_else(|| config::configuration().initial_size(0));
+            (window_id, size)
+        }
+        None => {
+            window_builder = mux.new_empty_window(Some(workspace));
+            let size = spec
+                .size
+                .unwrap_or_else(|| config::configuration().initial_size(0));
+            (*window_builder, size)
+        }
+    };
+
+    let pane = new_stream_pane(spec, size);
+    let tab = Rc::new(Tab::new(&size));
+    tab.assign_pane(&pane);
+    mux.add_tab_and_active_pane(&tab)?;
+    if let Err(err) = mux.attach_tab_to_window(&tab, window_id, None) {
+        mux.remove_tab(tab.tab_id());
+        return Err(err);
+    }
+    Ok((tab, pane, window_id))
+}
+
+/// Creates a stream pane of `size`, without registering it with the mux
+pub fn new_stream_pane(spec: StreamPaneSpec, size: TerminalSize) -> Rc<dyn Pane> {
+    let pane_id = alloc_pane_id();
+    let ended = Arc::new(Ended::default());
+    STREAM_PANES
+        .lock()
+        .unwrap()
+        .insert(pane_id, Arc::clone(&ended));
+
+    let pty = StreamPty {
+        reader: Mutex::new(Some(spec.reader)),
+        writer: Arc::new(Mutex::new(spec.writer)),
+        size: Mutex::new(PtySize {
+            rows: size.rows as u16,
+            cols: size.cols as u16,
+            pixel_width: size.pixel_width as u16,
+            pixel_height: size.pixel_height as u16,
+        }),
+    };
+    let terminal = wezterm_term::Terminal::new(
+        size,
+        Arc::new(config::TermConfig::new()),
+        "WezTerm",
+        config::wezterm_version(),
+        Box::new(SharedWriter(Arc::clone(&pty.writer))),
+    );
+    let mut pane = LocalPane::new(
+        pane_id,
+        terminal,
+        Box::new(StreamChild { pane_id, ended }),
+        Box::new(pty),
+        spec.domain_id,
+        format!("stream pane `{}`", spec.title),
+    );
+    pane.set_cwd_from_process(false);
+    pane.set_spawn_label(spec.title);
+    Rc::new(pane)
+}
+
+/// Takes ownership of the file descriptors `reader_fd` and `writer_fd`,
+/// which may be the same descriptor, such as that of a socket, and
+/// returns the streams of a stream pane over them.
+/// On Windows, they are handles.
+pub fn streams_from_fds(
+    reader_fd: i64,
+    writer_fd: i64,
+) -> anyhow::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    let reader = owned_fd(reader_fd).context("reader_fd")?;
+    let writer = if writer_fd == reader_fd {
+        reader.try_clone()?
+    } else {
+        owned_fd(writer_fd).context("writer_fd")?
+    };
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+#[cfg(unix)]
+fn owned_fd(fd: i64) -> anyhow::Result<FileDescriptor> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    let fd = RawFd::try_from(fd).map_err(|_| anyhow!("{} is not a file descriptor", fd))?;
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        anyhow::bail!("{} is not an open file descriptor", fd);
+    }
+    // The caller hands the descriptor over to the pane, which closes it
+    Ok(unsafe { FileDescriptor::from_raw_fd(fd) })
+}
+
+#[cfg(windows)]
+fn owned_fd(handle: i64) -> anyhow::Result<FileDescriptor> {
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+    // The caller hands the handle over to the pane, which closes it
+    Ok(unsafe { FileDescriptor::from_raw_handle(handle as usize as RawHandle) })
+}
+
+/// Returns true if `pane_id` is a stream pane that is still alive
+pub fn is_stream_pane(pane_id: PaneId) -> bool {
+    STREAM_PANES.lock().unwrap().contains_key(&pane_id)
+}
+
+/// Marks the stream pane `pane_id` as dead, as though its process had
+/// exited successfully
+pub fn mark_dead(pane_id: PaneId) -> anyhow::Result<()> {
+    let ended = STREAM_PANES
+        .lock()
+        .unwrap()
+        .remove(&pane_id)
+        .ok_or_else(|| anyhow!("pane {} is not a live stream pane", pane_id))?;
+    ended.end();
+    Ok(())
+}
+
+/// Signals the thread that waits for the "process" of a stream pane
+#[derive(Debug, Default)]
+struct Ended {
+    ended: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Ended {
+    fn end(&self) {
+        *self.ended.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    fn is_ended(&self) -> bool {
+        *self.ended.lock().unwrap()
+    }
+
+    fn wait(&self) {
+        let mut ended = self.ended.lock().unwrap();
+        while !*ended {
+            ended = self.cond.wait(ended).unwrap();
+        }
+    }
+}
+
+/// Stands in for the process of a stream pane, which "exits" when
+/// the pane is marked as dead or is killed
+#[derive(Debug, Clone)]
+struct StreamChild {
+    pane_id: PaneId,
+    ended: Arc<Ended>,
+}
+
+impl Child for StreamChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        Ok(if self.ended.is_ended() {
+            Some(ExitStatus::with_exit_code(0))
+        } else {
+            None
+        })
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.ended.wait();
+        Ok(ExitStatus::with_exit_code(0))
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        None
+    }
+}
+
+impl ChildKiller for StreamChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        STREAM_PANES.lock().unwrap().remove(&self.pane_id);
+        self.ended.end();
+        Ok(())
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+/// The writer of a stream pane, shared by the pane and its terminal,
+/// which writes its replies to queries to the same stream
+struct SharedWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+struct StreamPty {
+    /// Taken by the mux when it starts to read from the pane
+    reader: Mutex<Option<Box<dyn Read + Send>>>,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// A stream has no size, but the pane expects to be able to
+    /// get back the size that it set
+    size: Mutex<PtySize>,
+}
+
+impl Write for StreamPty {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl MasterPty for StreamPty {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        *self.size.lock().unwrap() = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self.size.lock().unwrap())
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.reader
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("the stream of a stream pane can only be read once"))
+    }
+
+    fn try_clone_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(SharedWriter(Arc::clone(&self.writer))))
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mark_dead_ends_the_child() {
+        let ended = Arc::new(Ended::default());
+        let pane_id = alloc_pane_id();
+        STREAM_PANES
+            .lock()
+            .unwrap()
+            .insert(pane_id, Arc::clone(&ended));
+        let mut child = StreamChild { pane_id, ended };
+        assert!(is_stream_pane(pane_id));
+        assert!(child.try_wait().unwrap().is_none());
+
+        let waiter = {
+            let mut child = child.clone();
+            std::thread::spawn(move || child.wait().unwrap())
+        };
+        mark_dead(pane_id).unwrap();
+        assert!(waiter.join().unwrap().success());
+        assert!(child.try_wait().unwrap().is_some());
+        assert!(!is_stream_pane(pane_id));
+        assert!(mark_dead(pane_id).is_err());
+    }
+
+    #[test]
+    fn reader_is_taken_once() {
+        let pty = StreamPty {
+            reader: Mutex::new(Some(Box::new(std::io::empty()))),
+            writer: Arc::new(Mutex::new(Box::new(Vec::new()))),
+            size: Mutex::new(PtySize::default()),
+        };
+        assert!(pty.try_clone_reader().is_ok());
+        assert!(pty.try_clone_reader().is_err());
+    }
+}
@@ -0,0 +1,910 @@
+//! Runs the panes of the local domain under supervisor processes when
+//! `resilient_local_panes` is enabled, so that the programs in them
+//! survive a crash of the process that spawned them.
+//!
+//! A supervisor is `wezterm-mux-server --supervise-pane ENTRY`.  It
+//! holds the pty master, keeps a bounded replay buffer of the most
+//! recent output and relays input, output and resizes over a unix
+//! socket.  Each supervisor is described by a registry entry in
+//! `RUNTIME_DIR/supervisors`, next to its socket, so that a later
+//! process can discover the panes whose owner has gone away and adopt
+//! them; the terminal state of an adopted pane is recreated by
+//! replaying the buffer.
+//!
+//! A supervisor exits when its child exits, or when the owner asks it
+//! to during a clean shutdown.
+use crate::domain::LocalDomain;
+use crate::pane::{alloc_pane_id, PaneId};
+use crate::tab::Tab;
+use crate::window::WindowId;
+use crate::Mux;
+use anyhow::{anyhow, Context};
+use lazy_static::lazy_static;
+use portable_pty::{
+    native_pty_system, Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtySize,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use wezterm_term::TerminalSize;
+
+// Frames sent to the supervisor
+const FRAME_ADOPT: u8 = 1;
+const FRAME_INPUT: u8 = 2;
+const FRAME_RESIZE: u8 = 3;
+const FRAME_KILL: u8 = 4;
+const FRAME_SHUTDOWN: u8 = 5;
+// Frames sent by the supervisor
+const FRAME_STARTED: u8 = 16;
+const FRAME_OUTPUT: u8 = 17;
+const FRAME_EXITED: u8 = 18;
+
+/// How long to wait for a new supervisor to start listening
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest payload of a frame.  Larger writes and the replay
+/// buffer are split across several frames, and a peer that announces
+/// a larger frame is treated as broken rather than trusted to size
+/// our allocation.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    /// The connections to the supervisors of the panes of this process,
+    /// so that they can be told to exit during a clean shutdown
+    static ref CONNECTIONS: Mutex<Vec<Weak<Mutex<UnixStream>>>> = Mutex::new(vec![]);
+}
+
+/// Describes a supervised pane; stored as json in the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupervisorEntry {
+    pub pane_id: PaneId,
+    pub domain_name: String,
+    pub command_description: String,
+    /// The pid of the process that the pane currently belongs to
+    pub owner_pid: u32,
+    /// The file name of the executable of the owner.  Only a process
+    /// running the same program adopts the pane, so that a gui doesn't
+    /// take over the panes of a mux server, for example.
+    pub owner_exe: String,
+    pub size: PtySize,
+    pub replay_buffer_size: usize,
+    /// The command to spawn.  The supervisor removes it from the entry
+    /// once the command has been spawned.
+    pub command: Option<CommandBuilder>,
+    pub supervisor_pid: Option<u32>,
+    pub child_pid: Option<u32>,
+}
+
+impl SupervisorEntry {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        // Write to a temporary file and rename it into place, so that
+        // a concurrent reader never sees a partial entry
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data).with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("renaming {}", tmp.display()))
+    }
+}
+
+fn registry_dir() -> PathBuf {
+    config::RUNTIME_DIR.join("supervisors")
+}
+
+fn socket_path(entry_path: &Path) -> PathBuf {
+    entry_path.with_extension("sock")
+}
+
+fn remove_entry(entry_path: &Path) {
+    std::fs::remove_file(socket_path(entry_path)).ok();
+    std::fs::remove_file(entry_path).ok();
+}
+
+fn exe_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            exe.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+}
+
+fn process_is_alive(pid: u32) -> bool {
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn write_frame<W: Write>(w: &mut W, tag: u8, payload: &[u8]) -> std::io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", payload.len()),
+        ));
+    }
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(tag);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    w.write_all(&buf)
+}
+
+/// Returns None at the end of the stream
+fn read_frame<R: Read>(r: &mut R) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+fn parse_u32(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(payload.get(0..4)?.try_into().ok()?))
+}
+
+fn encode_size(size: PtySize) -> Vec<u8> {
+    [size.rows, size.cols, size.pixel_width, size.pixel_height]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+fn decode_size(payload: &[u8]) -> Option<PtySize> {
+    let field = |idx: usize| -> Option<u16> {
+        Some(u16::from_le_bytes(
+            payload.get(idx * 2..idx * 2 + 2)?.try_into().ok()?,
+        ))
+    };
+    Some(PtySize {
+        rows: field(0)?,
+        cols: field(1)?,
+        pixel_width: field(2)?,
+        pixel_height: field(3)?,
+    })
+}
+
+fn send(stream: &Mutex<UnixStream>, tag: u8, payload: &[u8]) -> std::io::Result<()> {
+    write_frame(&mut *stream.lock().unwrap(), tag, payload)
+}
+
+/// Sends as much of `buf` as fits in one input frame
+fn send_input(stream: &Mutex<UnixStream>, buf: &[u8]) -> std::io::Result<usize> {
+    let len = buf.len().min(MAX_FRAME_SIZE);
+    send(stream, FRAME_INPUT, &buf[..len])?;
+    Ok(len)
+}
+
+type ExitState = Arc<(Mutex<Option<ExitStatus>>, Condvar)>;
+
+fn record_exit(exit: &ExitState, status: ExitStatus) {
+    let (lock, cvar) = &**exit;
+    let mut exited = lock.lock().unwrap();
+    if exited.is_none() {
+        exited.replace(status);
+    }
+    cvar.notify_all();
+}
+
+/// The pty of a supervised pane, as seen by its owner
+#[derive(Debug)]
+struct SupervisedPty {
+    stream: Arc<Mutex<UnixStream>>,
+    reader: Mutex<Option<UnixStream>>,
+    exit: ExitState,
+    size: Mutex<PtySize>,
+    child_pid: u32,
+}
+
+/// Decodes the output frames from the supervisor.
+/// The exit status is recorded when the supervisor reports that the
+/// child exited, or when the connection is lost.
+struct SupervisedReader {
+    stream: UnixStream,
+    exit: ExitState,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for SupervisedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.pos < self.pending.len() {
+                let len = buf.len().min(self.pending.len() - self.pos);
+                buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+            match read_frame(&mut self.stream) {
+                Ok(Some((FRAME_OUTPUT, data))) => {
+                    self.pending = data;
+                    self.pos = 0;
+                }
+                Ok(Some((FRAME_EXITED, data))) => {
+                    record_exit(
+                        &self.exit,
+                        ExitStatus::with_exit_code(parse_u32(&data).unwrap_or(1)),
+                    );
+                    return Ok(0);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    record_exit(&self.exit, ExitStatus::with_signal("supervisor lost"));
+                    return Ok(0);
+                }
+                Err(err) => {
+                    record_exit(&self.exit, ExitStatus::with_signal("supervisor lost"));
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+struct SupervisedWriter {
+    stream: Arc<Mutex<UnixStream>>,
+}
+
+impl Write for SupervisedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        send_input(&self.stream, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for SupervisedPty {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        send_input(&self.stream, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MasterPty for SupervisedPty {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        send(&self.stream, FRAME_RESIZE, &encode_size(size))?;
+        *self.size.lock().unwrap() = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> anyhow::Result<PtySize> {
+        Ok(*self.size.lock().unwrap())
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        let stream = self
+            .reader
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow!("the output of a supervised pane can only be read once"))?;
+        Ok(Box::new(SupervisedReader {
+            stream,
+            exit: Arc::clone(&self.exit),
+            pending: vec![],
+            pos: 0,
+        }))
+    }
+
+    fn try_clone_writer(&self) -> anyhow::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(SupervisedWriter {
+            stream: Arc::clone(&self.stream),
+        }))
+    }
+
+    /// The supervisor owns the pty, so the foreground process group
+    /// can't be queried; the child is reported instead, as it is the
+    /// session leader
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        Some(self.child_pid as libc::pid_t)
+    }
+}
+
+#[derive(Debug)]
+struct SupervisedChild {
+    stream: Arc<Mutex<UnixStream>>,
+    exit: ExitState,
+    child_pid: u32,
+}
+
+#[derive(Debug)]
+struct SupervisedChildKiller {
+    stream: Arc<Mutex<UnixStream>>,
+}
+
+impl ChildKiller for SupervisedChildKiller {
+    fn kill(&mut self) -> std::io::Result<()> {
+        send(&self.stream, FRAME_KILL, &[])
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(SupervisedChildKiller {
+            stream: Arc::clone(&self.stream),
+        })
+    }
+}
+
+impl ChildKiller for SupervisedChild {
+    fn kill(&mut self) -> std::io::Result<()> {
+        send(&self.stream, FRAME_KILL, &[])
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        Box::new(SupervisedChildKiller {
+            stream: Arc::clone(&self.stream),
+        })
+    }
+}
+
+impl Child for SupervisedChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        Ok(self.exit.0.lock().unwrap().clone())
+    }
+
+    fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        let (lock, cvar) = &*self.exit;
+        let mut exited = lock.lock().unwrap();
+        loop {
+            if let Some(status) = exited.as_ref() {
+                return Ok(status.clone());
+            }
+            exited = cvar.wait(exited).unwrap();
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.child_pid)
+    }
+}
+
+/// Connects to the supervisor listening at `socket`, making this
+/// process the owner of its pane
+fn connect(
+    socket: &Path,
+    size: PtySize,
+) -> anyhow::Result<(Box<dyn Child + Send + Sync>, Box<dyn MasterPty>)> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("connecting to supervisor {}", socket.display()))?;
+    write_frame(&mut stream, FRAME_ADOPT, &std::process::id().to_le_bytes())?;
+    let child_pid = match read_frame(&mut stream)? {
+        Some((FRAME_STARTED, data)) => {
+            parse_u32(&data).ok_or_else(|| anyhow!("malformed response from supervisor"))?
+        }
+        // The supervisor closes the connection if it already has an owner
+        _ => anyhow::bail!("supervisor {} refused the connection", socket.display()),
+    };
+    write_frame(&mut stream, FRAME_RESIZE, &encode_size(size))?;
+
+    let reader = stream.try_clone()?;
+    let stream = Arc::new(Mutex::new(stream));
+    CONNECTIONS.lock().unwrap().push(Arc::downgrade(&stream));
+
+    let exit: ExitState = Arc::new((Mutex::new(None), Condvar::new()));
+    let child = SupervisedChild {
+        stream: Arc::clone(&stream),
+        exit: Arc::clone(&exit),
+        child_pid,
+    };
+    let pty = SupervisedPty {
+        stream,
+        reader: Mutex::new(Some(reader)),
+        exit,
+        size: Mutex::new(size),
+        child_pid,
+    };
+    Ok((Box::new(child), Box::new(pty)))
+}
+
+/// Spawns `cmd` under a new supervisor and connects to it
+pub(crate) fn spawn_supervised(
+    pane_id: PaneId,
+    domain_name: &str,
+    command_description: &str,
+    cmd: CommandBuilder,
+    size: PtySize,
+    replay_buffer_size: usize,
+) -> anyhow::Result<(Box<dyn Child + Send + Sync>, Box<dyn MasterPty>)> {
+    let dir = registry_dir();
+    config::create_user_owned_dirs(&dir)?;
+    let entry_path = dir.join(format!("{}-{}.json", std::process::id(), pane_id));
+    let socket = socket_path(&entry_path);
+    remove_entry(&entry_path);
+
+    SupervisorEntry {
+        pane_id,
+        domain_name: domain_name.to_string(),
+        command_description: command_description.to_string(),
+        owner_pid: std::process::id(),
+        owner_exe: exe_name(),
+        size,
+        replay_buffer_size,
+        command: Some(cmd),
+        supervisor_pid: None,
+        child_pid: None,
+    }
+    .save(&entry_path)?;
+
+    let exe = std::env::current_exe()?.with_file_name("wezterm-mux-server");
+    let mut supervisor = std::process::Command::new(&exe)
+        .arg("--supervise-pane")
+        .arg(&entry_path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("spawning supervisor {}", exe.display()))?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !socket.exists() {
+        if let Some(status) = supervisor.try_wait()? {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = supervisor.stderr.take() {
+                pipe.read_to_string(&mut stderr).ok();
+            }
+            remove_entry(&entry_path);
+            anyhow::bail!("supervisor exited with {}: {}", status, stderr.trim());
+        }
+        if Instant::now() > deadline {
+            supervisor.kill().ok();
+            remove_entry(&entry_path);
+            anyhow::bail!("timed out waiting for the supervisor to start");
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    drop(supervisor.stderr.take());
+
+    // The supervisor is our child until we exit; reap it when it is done
+    std::thread::spawn(move || supervisor.wait());
+
+    connect(&socket, size)
+}
+
+/// A supervised pane whose owner is no longer running
+#[derive(Debug)]
+pub struct OrphanedPane {
+    pub entry: SupervisorEntry,
+    entry_path: PathBuf,
+}
+
+impl OrphanedPane {
+    pub(crate) fn attach(
+        &self,
+        size: PtySize,
+    ) -> anyhow::Result<(Box<dyn Child + Send + Sync>, Box<dyn MasterPty>)> {
+        connect(&socket_path(&self.entry_path), size)
+    }
+}
+
+/// Scans the registry for supervised panes whose owner has exited.
+/// Entries whose supervisor is gone are removed.
+pub fn find_orphaned_panes() -> Vec<OrphanedPane> {
+    let dir = match std::fs::read_dir(registry_dir()) {
+        Ok(dir) => dir,
+        Err(_) => return vec![],
+    };
+    let exe = exe_name();
+    let mut orphans = vec![];
+    for entry_path in dir.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if entry_path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let entry = match SupervisorEntry::load(&entry_path) {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("{:#}", err);
+                continue;
+            }
+        };
+        match entry.supervisor_pid {
+            Some(pid) if process_is_alive(pid) => {}
+            Some(_) => {
+                remove_entry(&entry_path);
+                continue;
+            }
+            // Still starting up
+            None => continue,
+        }
+        if entry.owner_exe != exe || process_is_alive(entry.owner_pid) {
+            continue;
+        }
+        orphans.push(OrphanedPane { entry, entry_path });
+    }
+    orphans.sort_by_key(|orphan| orphan.entry.pane_id);
+    orphans
+}
+
+/// Tells the supervisors of the panes of this process to kill their
+/// children and exit.  Called during a clean shutdown; if this process
+/// crashes instead, the supervisors keep running until adopted.
+pub fn shutdown_supervisors() {
+    for stream in CONNECTIONS.lock().unwrap().drain(..) {
+        if let Some(stream) = stream.upgrade() {
+            send(&stream, FRAME_SHUTDOWN, &[]).ok();
+        }
+    }
+}
+
+impl Mux {
+    /// Adopts the supervised panes of the local domains whose owner
+    /// crashed, placing each in its own tab of a new window in
+    /// `workspace`.  A pane keeps its id unless that id is already in
+    /// use.  Returns the id of the window, if any panes were adopted.
+    pub async fn adopt_orphaned_panes(
+        &self,
+        size: TerminalSize,
+        workspace: &str,
+    ) -> anyhow::Result<Option<WindowId>> {
+        let mut window_builder = None;
+        for orphan in find_orphaned_panes() {
+            let domain = self.get_domain_by_name(&orphan.entry.domain_name);
+            let local = match domain
+                .as_ref()
+                .and_then(|d| d.downcast_ref::<LocalDomain>())
+            {
+                Some(local) => local,
+                None => {
+                    log::warn!(
+                        "not adopting pane {}: domain {} is not a local domain",
+                        orphan.entry.pane_id,
+                        orphan.entry.domain_name
+                    );
+                    continue;
+                }
+            };
+            let pane_id = match self.reserve_pane_id(orphan.entry.pane_id) {
+                Ok(()) => orphan.entry.pane_id,
+                Err(_) => alloc_pane_id(),
+            };
+            let description = &orphan.entry.command_description;
+            let pane = match local.adopt_pane(pane_id, size, &orphan) {
+                Ok(pane) => pane,
+                Err(err) => {
+                    self.release_pane_id(pane_id);
+                    log::error!("adopting {}: {:#}", description, err);
+                    continue;
+                }
+            };
+            log::info!("adopted {} as pane {}", description, pane_id);
+
+            let tab = Rc::new(Tab::new(&size));
+            tab.assign_pane(&pane);
+            self.add_tab_and_active_pane(&tab)?;
+            let window_id = **window_builder
+                .get_or_insert_with(|| self.new_empty_window(Some(workspace.to_string())));
+            self.add_tab_to_window(&tab, window_id)?;
+        }
+        Ok(window_builder.map(|builder| *builder))
+    }
+}
+
+/// The state of a running supervisor
+struct Supervisor {
+    entry_path: PathBuf,
+    child_pid: u32,
+    replay_limit: usize,
+    replay: Mutex<VecDeque<u8>>,
+    /// The connection to the owner, if any; output is sent to it
+    /// while the replay lock is held, so that nothing is sent twice
+    /// or lost when an owner connects
+    client: Mutex<Option<UnixStream>>,
+    master: Mutex<Box<dyn MasterPty + Send>>,
+    writer: Mutex<Box<dyn Write + Send>>,
+    killer: Mutex<Box<dyn ChildKiller + Send + Sync>>,
+}
+
+impl Supervisor {
+    fn record_output(&self, data: &[u8]) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.extend(data);
+        let excess = replay.len().saturating_sub(self.replay_limit);
+        replay.drain(..excess);
+
+        let mut client = self.client.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if write_frame(stream, FRAME_OUTPUT, data).is_err() {
+                client.take();
+            }
+        }
+    }
+
+    fn set_owner(&self, owner_pid: u32) {
+        let result = SupervisorEntry::load(&self.entry_path).and_then(|mut entry| {
+            entry.owner_pid = owner_pid;
+            entry.save(&self.entry_path)
+        });
+        if let Err(err) = result {
+            log::error!("{:#}", err);
+        }
+    }
+
+    fn serve_client(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(STARTUP_TIMEOUT))?;
+        let owner_pid = match read_frame(&mut stream)? {
+            Some((FRAME_ADOPT, data)) => {
+                parse_u32(&data).ok_or_else(|| anyhow!("malformed adopt frame"))?
+            }
+            _ => anyhow::bail!("expected an adopt frame"),
+        };
+        stream.set_read_timeout(None)?;
+
+        {
+            let replay = self.replay.lock().unwrap();
+            let mut client = self.client.lock().unwrap();
+            if client.is_some() {
+                // Already owned; dropping the stream refuses the connection
+                return Ok(());
+            }
+            self.set_owner(owner_pid);
+            let mut writer = stream.try_clone()?;
+            write_frame(&mut writer, FRAME_STARTED, &self.child_pid.to_le_bytes())?;
+            let replay: Vec<u8> = replay.iter().copied().collect();
+            for chunk in replay.chunks(MAX_FRAME_SIZE) {
+                write_frame(&mut writer, FRAME_OUTPUT, chunk)?;
+            }
+            client.replace(writer);
+        }
+
+        let result = self.relay_input(&mut stream);
+        self.client.lock().unwrap().take();
+        result
+    }
+
+    fn relay_input(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        while let Some((tag, data)) = read_frame(stream)? {
+            match tag {
+                FRAME_INPUT => self.writer.lock().unwrap().write_all(&data)?,
+                FRAME_RESIZE => {
+                    if let Some(size) = decode_size(&data) {
+                        self.master.lock().unwrap().resize(size)?;
+                    }
+                }
+                FRAME_KILL => self.killer.lock().unwrap().kill()?,
+                FRAME_SHUTDOWN => {
+                    self.killer.lock().unwrap().kill().ok();
+                    remove_entry(&self.entry_path);
+                    std::process::exit(0);
+                }
+                _ => {}
+            }
+        }
+        // The owner went away; wait for another one to adopt the pane
+        Ok(())
+    }
+
+    fn finish(&self, status: ExitStatus) -> ! {
+        if let Some(stream) = self.client.lock().unwrap().as_mut() {
+            write_frame(stream, FRAME_EXITED, &status.exit_code().to_le_bytes()).ok();
+        }
+        remove_entry(&self.entry_path);
+        std::process::exit(0);
+    }
+}
+
+/// The main function of a supervisor process; the registry entry at
+/// `entry_path` describes the command to run
+pub fn run_supervisor(entry_path: &Path) -> anyhow::Result<()> {
+    // Leave the session of the owner, so that the supervisor isn't
+    // hung up along with it
+    unsafe {
+        libc::setsid();
+    }
+
+    let mut entry = SupervisorEntry::load(entry_path)?;
+    let cmd = entry
+        .command
+        .take()
+        .ok_or_else(|| anyhow!("{} has no command", entry_path.display()))?;
+    let pair = native_pty_system().openpty(entry.size)?;
+    let mut child = pair.slave.spawn_command(cmd)?;
+    drop(pair.slave);
+    let child_pid = child.process_id().unwrap_or(0);
+
+    entry.supervisor_pid = Some(std::process::id());
+    entry.child_pid = Some(child_pid);
+    entry.save(entry_path)?;
+    let listener = UnixListener::bind(socket_path(entry_path))?;
+
+    let mut reader = pair.master.try_clone_reader()?;
+    let supervisor = Arc::new(Supervisor {
+        entry_path: entry_path.to_path_buf(),
+        child_pid,
+        replay_limit: entry.replay_buffer_size,
+        replay: Mutex::new(VecDeque::new()),
+        client: Mutex::new(None),
+        writer: Mutex::new(pair.master.try_clone_writer()?),
+        killer: Mutex::new(child.clone_killer()),
+        master: Mutex::new(pair.master),
+    });
+
+    let (output_done_tx, output_done_rx) = std::sync::mpsc::channel();
+    {
+        let supervisor = Arc::clone(&supervisor);
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => supervisor.record_output(&buf[..len]),
+                }
+            }
+            output_done_tx.send(()).ok();
+        });
+    }
+
+    {
+        let supervisor = Arc::clone(&supervisor);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().filter_map(|s| s.ok()) {
+                let supervisor = Arc::clone(&supervisor);
+                std::thread::spawn(move || {
+                    if let Err(err) = supervisor.serve_client(stream) {
+                        log::error!("{:#}", err);
+                    }
+                });
+            }
+        });
+    }
+
+    let status = child.wait()?;
+    // Give the output thread a chance to relay the final output
+    output_done_rx.recv_timeout(Duration::from_secs(2)).ok();
+    supervisor.finish(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 640,
+            pixel_height: 480,
+        };
+        let mut buf = vec![];
+        write_frame(&mut buf, FRAME_RESIZE, &encode_size(size)).unwrap();
+        write_frame(&mut buf, FRAME_OUTPUT, b"hello").unwrap();
+
+        let mut reader = buf.as_slice();
+        let (tag, data) = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(tag, FRAME_RESIZE);
+        assert_eq!(decode_size(&data), Some(size));
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            Some((FRAME_OUTPUT, b"hello".to_vec()))
+        );
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut buf = vec![];
+        assert!(write_frame(&mut buf, FRAME_INPUT, &vec![0u8; MAX_FRAME_SIZE + 1]).is_err());
+        assert!(buf.is_empty());
+
+        buf.push(FRAME_OUTPUT);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_frame(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[derive(Debug)]
+    struct NoKiller;
+
+    impl ChildKiller for NoKiller {
+        fn kill(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+            Box::new(NoKiller)
+        }
+    }
+
+    fn read_replay(pty: &dyn MasterPty, len: usize) -> Vec<u8> {
+        let mut reader = pty.try_clone_reader().unwrap();
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn orphan_is_handed_off_and_readopted() {
+        let size = PtySize::default();
+        let dir = tempfile::tempdir().unwrap();
+        let entry_path = dir.path().join("1-1.json");
+        SupervisorEntry {
+            pane_id: 1,
+            domain_name: "local".to_string(),
+            command_description: "test".to_string(),
+            owner_pid: 0,
+            owner_exe: exe_name(),
+            size,
+            replay_buffer_size: 2 * MAX_FRAME_SIZE,
+            command: None,
+            supervisor_pid: None,
+            child_pid: None,
+        }
+        .save(&entry_path)
+        .unwrap();
+
+        let pair = native_pty_system().openpty(size).unwrap();
+        let supervisor = Arc::new(Supervisor {
+            entry_path: entry_path.clone(),
+            child_pid: 42,
+            replay_limit: 2 * MAX_FRAME_SIZE,
+            replay: Mutex::new(VecDeque::new()),
+            client: Mutex::new(None),
+            writer: Mutex::new(pair.master.try_clone_writer().unwrap()),
+            killer: Mutex::new(Box::new(NoKiller)),
+            master: Mutex::new(pair.master),
+        });
+        // More output than fits in a single frame
+        let output: Vec<u8> = (0..MAX_FRAME_SIZE + 100).map(|i| i as u8).collect();
+        supervisor.record_output(&output);
+
+        let socket = socket_path(&entry_path);
+        let listener = UnixListener::bind(&socket).unwrap();
+        {
+            let supervisor = Arc::clone(&supervisor);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().filter_map(|s| s.ok()) {
+                    let supervisor = Arc::clone(&supervisor);
+                    std::thread::spawn(move || supervisor.serve_client(stream));
+                }
+            });
+        }
+
+        let (child, pty) = connect(&socket, size).unwrap();
+        assert_eq!(child.process_id(), Some(42));
+        assert_eq!(read_replay(&*pty, output.len()), output);
+        assert_eq!(
+            SupervisorEntry::load(&entry_path).unwrap().owner_pid,
+            std::process::id()
+        );
+
+        // The pane already has an owner
+        assert!(connect(&socket, size).is_err());
+
+        // The owner goes away, leaving the pane to be adopted again
+        drop(child);
+        drop(pty);
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        let (_child, pty) = loop {
+            match connect(&socket, size) {
+                Ok(adopted) => break adopted,
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => panic!("re-adopting: {:#}", err),
+            }
+        };
+        assert_eq!(read_replay(&*pty, output.len()), output);
+    }
+}
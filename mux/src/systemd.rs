@@ -0,0 +1,186 @@
+//! Support for running the panes of local domains in transient
+//! systemd scopes, so that the whole process tree of a pane can be
+//! resource limited and reliably cleaned up.
+//! The scope is created by spawning the command via
+//! `systemd-run --user --scope`, which places itself in the new scope
+//! and then execs the command, so the pane still directly owns the
+//! process that it spawned.
+use crate::pane::PaneId;
+use config::Config;
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+
+/// Returns the name of the scope unit for a pane.
+/// The process id is included so that multiple wezterm processes
+/// don't compete for the same unit names.
+pub fn scope_unit_name(pane_id: PaneId) -> String {
+    format!("wezterm-pane-{}-{}.scope", std::process::id(), pane_id)
+}
+
+/// Returns the `-p` properties that are applied to the scope of each pane
+fn scope_properties(config: &Config) -> Vec<String> {
+    let mut props = vec![];
+    if let Some(memory_max) = &config.systemd_scope_memory_max {
+        props.push(format!("MemoryMax={}", memory_max));
+    }
+    if let Some(cpu_weight) = config.systemd_scope_cpu_weight {
+        props.push(format!("CPUWeight={}", cpu_weight));
+    }
+    props
+}
+
+#[cfg(target_os = "linux")]
+fn find_in_path(program: &str) -> Option<std::path::PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Checks that `systemd-run` can actually create a scope, by running
+/// `true` in one; the user manager may refuse, for example when it
+/// doesn't delegate the controllers that scopes need.
+#[cfg(target_os = "linux")]
+fn can_create_scope() -> bool {
+    match std::process::Command::new("systemd-run")
+        .args(["--user", "--scope", "--quiet", "--collect", "--", "true"])
+        .stdin(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            log::warn!(
+                "systemd-run is unable to create scopes, so panes will be \
+                 spawned without one: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(err) => {
+            log::warn!(
+                "unable to run systemd-run, so panes will be spawned \
+                 without a scope: {:#}",
+                err
+            );
+            false
+        }
+    }
+}
+
+/// Returns true if the user's systemd instance can be asked to
+/// create scopes: the system was booted with systemd, the user
+/// manager is reachable over its bus, `systemd-run` is installed
+/// and it succeeds in creating a scope.
+#[cfg(target_os = "linux")]
+pub fn is_available() -> bool {
+    lazy_static::lazy_static! {
+        static ref AVAILABLE: bool = {
+            let booted = std::path::Path::new("/run/systemd/system").is_dir();
+            let user_bus = std::env::var_os("XDG_RUNTIME_DIR")
+                .map(|dir| std::path::Path::new(&dir).join("bus").exists())
+                .unwrap_or(false);
+            let available = booted
+                && user_bus
+                && find_in_path("systemd-run").is_some()
+                && can_create_scope();
+            log::trace!("systemd scopes available: {}", available);
+            available
+        };
+    }
+    *AVAILABLE
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_available() -> bool {
+    false
+}
+
+/// Returns the argv that runs `cmd` in a new scope named `unit`.
+/// The default program is run as `$SHELL -l`, as the login shell
+/// convention of prefixing argv0 with `-` can't be passed through
+/// `systemd-run`.
+pub fn scope_argv(
+    config: &Config,
+    unit: &str,
+    cmd: &CommandBuilder,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut argv: Vec<OsString> = vec![
+        "systemd-run".into(),
+        "--user".into(),
+        "--scope".into(),
+        "--quiet".into(),
+        "--collect".into(),
+        "--unit".into(),
+        unit.into(),
+    ];
+    for prop in scope_properties(config) {
+        argv.push("-p".into());
+        argv.push(prop.into());
+    }
+    argv.push("--".into());
+
+    if cmd.is_default_prog() {
+        argv.push(cmd.get_shell()?.into());
+        argv.push("-l".into());
+    } else {
+        argv.extend(cmd.get_argv().iter().cloned());
+    }
+    Ok(argv)
+}
+
+/// Stops the scope named `unit`, killing any processes that remain
+/// in it.  This runs in the background as it doesn't need to
+/// complete before the pane goes away.
+pub fn stop_scope(unit: &str) {
+    let unit = unit.to_string();
+    std::thread::spawn(move || {
+        match std::process::Command::new("systemctl")
+            .args(["--user", "stop", "--no-block", unit.as_str()])
+            .output()
+        {
+            Ok(output) if !output.status.success() => {
+                // The scope may have already gone away by itself
+                log::debug!(
+                    "systemctl --user stop {} failed: {}",
+                    unit,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(_) => {}
+            Err(err) => log::debug!("systemctl --user stop {} failed: {:#}", unit, err),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn argv() {
+        let mut config = Config::default_config();
+        config.systemd_scope_memory_max = Some("4G".to_string());
+        config.systemd_scope_cpu_weight = Some(50);
+
+        let cmd = CommandBuilder::from_argv(vec!["make".into(), "-j4".into()]);
+        assert_eq!(
+            scope_argv(&config, "wezterm-pane-1-2.scope", &cmd).unwrap(),
+            vec![
+                "systemd-run",
+                "--user",
+                "--scope",
+                "--quiet",
+                "--collect",
+                "--unit",
+                "wezterm-pane-1-2.scope",
+                "-p",
+                "MemoryMax=4G",
+                "-p",
+                "CPUWeight=50",
+                "--",
+                "make",
+                "-j4"
+            ]
+        );
+    }
+}
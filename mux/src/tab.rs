@@ -1,21 +1,26 @@
 use crate::domain::DomainId;
+use crate::marker::Marker;
 use crate::pane::*;
-use crate::{Mux, WindowId};
+use crate::panelink::PaneLink;
+use crate::resources::PaneResourceUsage;
+use crate::{Mux, MuxNotification, WindowId};
 use bintree::PathBranch;
-use config::configuration;
 use config::keyassignment::PaneDirection;
+use config::{configuration, Palette};
 use rangeset::range_intersection;
 use serde::{Deserialize, Serialize};
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::convert::TryInto;
 use std::rc::Rc;
 use url::Url;
-use wezterm_term::TerminalSize;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_term::{TerminalModes, TerminalSize};
 
 pub type Tree = bintree::Tree<Rc<dyn Pane>, SplitDirectionAndSize>;
 pub type Cursor = bintree::Cursor<Rc<dyn Pane>, SplitDirectionAndSize>;
 
-static TAB_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+pub(crate) static TAB_ID: ::std::sync::atomic::AtomicUsize =
+    ::std::sync::atomic::AtomicUsize::new(0);
 pub type TabId = usize;
 
 /// A Tab is a container of Panes
@@ -26,6 +31,9 @@ pub struct Tab {
     active: RefCell<usize>,
     zoomed: RefCell<Option<Rc<dyn Pane>>>,
     title: RefCell<String>,
+    layout_generation: Cell<usize>,
+    /// The title produced by the `mux-format-tab-title` event
+    computed_title: RefCell<Option<String>>,
 }
 
 #[derive(Clone)]
@@ -66,7 +74,7 @@ impl std::fmt::Debug for PositionedPane {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, FromDynamic, ToDynamic)]
 pub enum SplitDirection {
     Horizontal,
     Vertical,
@@ -163,6 +171,40 @@ impl SplitDirectionAndSize {
     }
 }
 
+/// A rectangle of cells, relative to the top left corner of a tab
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CellRect {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Where the panes that take part in a split would be; see
+/// `Tab::preview_split`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SplitPreview {
+    /// What would remain of the pane that is split, or of all of the
+    /// panes of the tab for a top level split
+    pub existing: CellRect,
+    /// The new pane
+    pub new: CellRect,
+}
+
+/// Explains why a pane cannot be split
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
+pub enum SplitError {
+    #[error(
+        "not enough space to split the pane: \
+         {needed} cells are needed but only {available} are available"
+    )]
+    TooSmall { needed: usize, available: usize },
+    #[error("cannot split while the tab is zoomed")]
+    Zoomed,
+    #[error("invalid pane index {0}")]
+    InvalidIndex(usize),
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct PositionedSplit {
     /// The topological node index that can be used to reference this split
@@ -194,20 +236,35 @@ fn pane_tree(
     active: Option<&Rc<dyn Pane>>,
     zoomed: Option<&Rc<dyn Pane>>,
     workspace: &str,
+    layout_generation: usize,
+    tab_title: Option<&str>,
 ) -> PaneNode {
     match tree {
         Tree::Empty => PaneNode::Empty,
         Tree::Node { left, right, data } => PaneNode::Split {
             left: Box::new(pane_tree(
-                &*left, tab_id, window_id, active, zoomed, workspace,
+                &*left,
+                tab_id,
+                window_id,
+                active,
+                zoomed,
+                workspace,
+                layout_generation,
+                tab_title,
             )),
             right: Box::new(pane_tree(
-                &*right, tab_id, window_id, active, zoomed, workspace,
+                &*right,
+                tab_id,
+                window_id,
+                active,
+                zoomed,
+                workspace,
+                layout_generation,
+                tab_title,
             )),
             node: data.unwrap(),
         },
         Tree::Leaf(pane) => {
-            let dims = pane.get_dimensions();
             let working_dir = pane.get_current_working_dir();
 
             PaneNode::Leaf(PaneEntry {
@@ -217,15 +274,20 @@ fn pane_tree(
                 title: pane.get_title(),
                 is_active_pane: is_pane(pane, &active),
                 is_zoomed_pane: is_pane(pane, &zoomed),
-                size: TerminalSize {
-                    cols: dims.cols,
-                    rows: dims.viewport_rows,
-                    pixel_height: 0,
-                    pixel_width: 0,
-                    dpi: 0,
-                },
+                size: pane.get_size(),
                 working_dir: working_dir.map(Into::into),
                 workspace: workspace.to_string(),
+                systemd_scope: pane.get_systemd_scope(),
+                spawn_label: pane.get_spawn_label(),
+                resource_usage: pane.get_resource_usage(),
+                modes: pane.get_modes(),
+                links: Mux::get()
+                    .map(|mux| mux.pane_links(pane.pane_id()))
+                    .unwrap_or_default(),
+                layout_generation,
+                tab_title: tab_title.map(str::to_string),
+                palette_overrides: pane.get_palette_overrides(),
+                markers: pane.get_markers(),
             })
         }
     }
@@ -444,6 +506,30 @@ fn apply_sizes_from_splits(tree: &Tree, size: &TerminalSize) {
     }
 }
 
+/// Returns the pane that should take the focus from a pane that was
+/// removed from beside `tree`, which absorbed its space.
+/// `direction` is that of the split that held the removed pane, and
+/// `removed_first` is true if the removed pane was its first side.
+/// Along that direction only the side of `tree` that faced the removed
+/// pane was adjacent to it; across it, the first side is chosen.
+fn neighbor_of_removed(
+    tree: &Tree,
+    direction: SplitDirection,
+    removed_first: bool,
+) -> Option<Rc<dyn Pane>> {
+    match tree {
+        Tree::Empty => None,
+        Tree::Leaf(pane) => Some(Rc::clone(pane)),
+        Tree::Node { left, right, data } => {
+            let facing = match data {
+                Some(data) if data.direction == direction && !removed_first => right,
+                _ => left,
+            };
+            neighbor_of_removed(facing, direction, removed_first)
+        }
+    }
+}
+
 fn cell_dimensions(size: &TerminalSize) -> TerminalSize {
     TerminalSize {
         rows: 1,
@@ -456,13 +542,33 @@ fn cell_dimensions(size: &TerminalSize) -> TerminalSize {
 
 impl Tab {
     pub fn new(size: &TerminalSize) -> Self {
+        let id = TAB_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+        crate::ids::note_id_allocated();
         Self {
-            id: TAB_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed),
+            id,
             pane: RefCell::new(Some(Tree::new())),
             size: RefCell::new(*size),
             active: RefCell::new(0),
             zoomed: RefCell::new(None),
             title: RefCell::new(String::new()),
+            layout_generation: Cell::new(0),
+            computed_title: RefCell::new(None),
+        }
+    }
+
+    /// Returns a counter that changes whenever the split tree, the
+    /// sizes of the panes, the zoom state or the set of panes in this
+    /// tab changes, so that consumers can skip recomputing anything
+    /// that they derived from the layout while it is unchanged.
+    pub fn layout_generation(&self) -> usize {
+        self.layout_generation.get()
+    }
+
+    fn layout_changed(&self) {
+        self.layout_generation
+            .set(self.layout_generation.get().wrapping_add(1));
+        if let Some(mux) = Mux::get() {
+            mux.notify(MuxNotification::TabLayoutChanged(self.id));
         }
     }
 
@@ -474,6 +580,75 @@ impl Tab {
         *self.title.borrow_mut() = title.to_string();
     }
 
+    /// Returns the title that was last computed for this tab by the
+    /// `mux-format-tab-title` event, or that the mux server reported
+    /// for a tab that mirrors one of its tabs
+    pub fn get_computed_title(&self) -> Option<String> {
+        self.computed_title.borrow().clone()
+    }
+
+    pub fn set_computed_title(&self, title: Option<String>) {
+        *self.computed_title.borrow_mut() = title;
+    }
+
+    /// Runs the `mux-format-tab-title` event for this tab and stores
+    /// its result as the computed title.  The title is cleared if no
+    /// handler is registered or if the handler returns nil.
+    pub fn compute_title(&self) {
+        let info = match self.title_info() {
+            Some(info) => info,
+            None => return,
+        };
+        let result = config::run_immediate_with_lua_config(|lua| {
+            let lua = match lua {
+                Some(lua) => lua,
+                None => return Ok(None),
+            };
+            let v =
+                config::lua::emit_sync_callback(&*lua, ("mux-format-tab-title".to_string(), info))?;
+            match v {
+                mlua::Value::Nil => Ok(None),
+                v => Ok(Some(<String as mlua::FromLua>::from_lua(v, &*lua)?)),
+            }
+        });
+        match result {
+            Ok(title) => self.set_computed_title(title),
+            Err(err) => {
+                log::warn!("mux-format-tab-title: {:#}", err);
+                self.set_computed_title(None);
+            }
+        }
+    }
+
+    fn title_info(&self) -> Option<TabTitleInfo> {
+        let mux = Mux::get()?;
+        let window_id = mux.window_containing_tab(self.id)?;
+        let workspace = mux.get_window(window_id)?.get_workspace().to_string();
+        let panes: Vec<TabTitlePaneInfo> = self
+            .iter_panes_ignoring_zoom()
+            .into_iter()
+            .map(|pos| TabTitlePaneInfo {
+                pane_id: pos.pane.pane_id(),
+                title: pos.pane.get_title(),
+                current_working_dir: pos
+                    .pane
+                    .get_current_working_dir()
+                    .map(|url| url.to_string()),
+                foreground_process_name: pos.pane.get_foreground_process_name(),
+                is_active: pos.is_active,
+                is_zoomed: pos.is_zoomed,
+            })
+            .collect();
+        Some(TabTitleInfo {
+            tab_id: self.id,
+            window_id,
+            workspace,
+            title: self.get_title(),
+            active_pane: panes.iter().find(|p| p.is_active).cloned(),
+            panes,
+        })
+    }
+
     /// Called by the multiplexer client when building a local tab to
     /// mirror a remote tab.  The supplied `root` is the information
     /// about our counterpart in the the remote server.
@@ -525,6 +700,7 @@ impl Tab {
         *self.size.borrow_mut() = size;
 
         self.resize(size);
+        self.layout_changed();
 
         log::debug!(
             "sync tab: {:#?} zoomed: {} {:#?}",
@@ -567,6 +743,8 @@ impl Tab {
                 active.as_ref(),
                 zoomed.as_ref(),
                 &workspace,
+                self.layout_generation(),
+                self.computed_title.borrow().as_deref(),
             )
         } else {
             PaneNode::Empty
@@ -622,6 +800,7 @@ impl Tab {
                 self.zoomed.borrow_mut().replace(pane);
             }
         }
+        self.layout_changed();
     }
 
     pub fn contains_pane(&self, pane: PaneId) -> bool {
@@ -680,6 +859,8 @@ impl Tab {
                 }
             }
         }
+        drop(root);
+        self.layout_changed();
     }
 
     pub fn rotate_clockwise(&self) {
@@ -712,6 +893,8 @@ impl Tab {
                 }
             }
         }
+        drop(root);
+        self.layout_changed();
     }
 
     fn iter_panes_impl(&self, respect_zoom_state: bool) -> Vec<PositionedPane> {
@@ -871,7 +1054,7 @@ impl Tab {
         let was_zoomed = self.zoomed.borrow().is_some();
         self.set_zoomed(false);
 
-        {
+        let changed = {
             let mut root = self.pane.borrow_mut();
             let dims = cell_dimensions(&size);
             let (min_x, min_y) = compute_min_size(root.as_mut().unwrap());
@@ -906,10 +1089,14 @@ impl Tab {
                 // And then resize the individual panes to match
                 apply_sizes_from_splits(root.as_mut().unwrap(), &size);
             }
-        }
+            size != current_size
+        };
 
         // And finally restore the zoom, if appropriate
         self.set_zoomed(was_zoomed);
+        if changed {
+            self.layout_changed();
+        }
     }
 
     fn apply_pane_size(&self, pane_size: TerminalSize, cursor: &mut Cursor) {
@@ -960,17 +1147,7 @@ impl Tab {
         fn compute_size(node: &mut Tree) -> Option<TerminalSize> {
             match node {
                 Tree::Empty => None,
-                Tree::Leaf(pane) => {
-                    let dims = pane.get_dimensions();
-                    let size = TerminalSize {
-                        cols: dims.cols,
-                        rows: dims.viewport_rows,
-                        pixel_height: 0,
-                        pixel_width: 0,
-                        dpi: dims.dpi,
-                    };
-                    Some(size)
-                }
+                Tree::Leaf(pane) => Some(pane.get_size()),
                 Tree::Node { left, right, data } => {
                     if let Some(data) = data {
                         if let Some(first) = compute_size(left) {
@@ -987,12 +1164,15 @@ impl Tab {
             }
         }
 
-        let mut root = self.pane.borrow_mut();
-        if let Some(root) = root.as_mut() {
-            if let Some(size) = compute_size(root) {
-                *self.size.borrow_mut() = size;
+        {
+            let mut root = self.pane.borrow_mut();
+            if let Some(root) = root.as_mut() {
+                if let Some(size) = compute_size(root) {
+                    *self.size.borrow_mut() = size;
+                }
             }
         }
+        self.layout_changed();
     }
 
     /// Given split_index, the topological index of a split returned by
@@ -1032,6 +1212,7 @@ impl Tab {
         // Now cursor is looking at the split
         self.adjust_node_at_cursor(&mut cursor, delta);
         self.cascade_size_from_cursor(root, cursor);
+        self.layout_changed();
     }
 
     fn adjust_node_at_cursor(&self, cursor: &mut Cursor, delta: isize) {
@@ -1166,6 +1347,7 @@ impl Tab {
                         if node.direction == split_direction {
                             self.adjust_node_at_cursor(&mut c, delta);
                             self.cascade_size_from_cursor(root, c);
+                            self.layout_changed();
                             return;
                         }
                     }
@@ -1289,18 +1471,21 @@ impl Tab {
     pub fn prune_dead_panes(&self) -> bool {
         !self
             .remove_pane_if(|_, pane| pane.is_dead(), true)
+            .0
             .is_empty()
     }
 
     pub fn kill_pane(&self, pane_id: PaneId) -> bool {
         !self
             .remove_pane_if(|_, pane| pane.pane_id() == pane_id, true)
+            .0
             .is_empty()
     }
 
     pub fn kill_panes_in_domain(&self, domain: DomainId) -> bool {
         !self
             .remove_pane_if(|_, pane| pane.domain_id() == domain, true)
+            .0
             .is_empty()
     }
 
@@ -1308,23 +1493,39 @@ impl Tab {
     /// The pane is still live in the mux; the intent is for the pane to
     /// be added to a different tab.
     pub fn remove_pane(&self, pane_id: PaneId) -> Option<Rc<dyn Pane>> {
-        let panes = self.remove_pane_if(|_, pane| pane.pane_id() == pane_id, false);
-        for pane in panes {
-            return Some(pane);
-        }
-        None
+        self.remove_pane_with_focus_hint(pane_id)
+            .map(|(pane, _focus)| pane)
     }
 
-    fn remove_pane_if<F>(&self, f: F, kill: bool) -> Vec<Rc<dyn Pane>>
+    /// Like `remove_pane`, but also returns the pane that is active in
+    /// the tab after the removal, if any remain.
+    /// When the removed pane was the active one, the focus moves to the
+    /// neighbor that absorbed its space; see `neighbor_of_removed`.
+    pub fn remove_pane_with_focus_hint(
+        &self,
+        pane_id: PaneId,
+    ) -> Option<(Rc<dyn Pane>, Option<Rc<dyn Pane>>)> {
+        let (panes, focus) = self.remove_pane_if(|_, pane| pane.pane_id() == pane_id, false);
+        panes.into_iter().next().map(|pane| (pane, focus))
+    }
+
+    /// Removes the panes for which `f` returns true, returning them
+    /// along with the pane that is active afterwards
+    fn remove_pane_if<F>(&self, f: F, kill: bool) -> (Vec<Rc<dyn Pane>>, Option<Rc<dyn Pane>>)
     where
         F: Fn(usize, &Rc<dyn Pane>) -> bool,
     {
         let mut dead_panes = vec![];
         let zoomed_pane = self.zoomed.borrow().as_ref().map(|p| p.pane_id());
+        let prior = self
+            .iter_panes_ignoring_zoom()
+            .into_iter()
+            .nth(*self.active.borrow())
+            .map(|p| p.pane);
+        let mut focus = prior.clone();
 
         {
             let root_size = *self.size.borrow();
-            let mut active_idx = *self.active.borrow();
             let mut root = self.pane.borrow_mut();
             let mut cursor = root.take().unwrap().cursor();
             let mut pane_index = 0;
@@ -1346,9 +1547,8 @@ impl Tab {
                 if cursor.is_leaf() {
                     let pane = Rc::clone(cursor.leaf_mut().unwrap());
                     if f(pane_index, &pane) {
-                        if pane_index == active_idx {
-                            active_idx = pane_index.saturating_sub(1);
-                        }
+                        let had_focus = focus.as_ref().map(|p| p.pane_id()) == Some(pane.pane_id());
+                        let removed_first = cursor.is_left();
                         if Some(pane.pane_id()) == zoomed_pane {
                             // If we removed the zoomed pane, un-zoom our state!
                             self.zoomed.borrow_mut().take();
@@ -1359,12 +1559,20 @@ impl Tab {
                                 dead_panes.push(dead);
                                 parent = p.unwrap();
                                 cursor = c;
+                                if had_focus {
+                                    focus = neighbor_of_removed(
+                                        cursor.subtree(),
+                                        parent.direction,
+                                        removed_first,
+                                    );
+                                }
                             }
                             Err(c) => {
                                 // We might be the root, for example
                                 if c.is_top() && c.is_leaf() {
                                     root.replace(Tree::Empty);
                                     dead_panes.push(pane);
+                                    focus = None;
                                 } else {
                                     root.replace(c.tree());
                                 }
@@ -1404,7 +1612,25 @@ impl Tab {
                     }
                 }
             }
-            *self.active.borrow_mut() = active_idx;
+        }
+
+        if !dead_panes.is_empty() {
+            let focus_idx = focus.as_ref().and_then(|focus| {
+                self.iter_panes_ignoring_zoom()
+                    .into_iter()
+                    .find(|p| p.pane.pane_id() == focus.pane_id())
+                    .map(|p| p.index)
+            });
+            *self.active.borrow_mut() = focus_idx.unwrap_or(0);
+            if focus_idx.is_none() {
+                focus = self.get_active_pane();
+            }
+            if let Some(focus) = &focus {
+                if prior.map(|p| p.pane_id()) != Some(focus.pane_id()) {
+                    focus.focus_changed(true);
+                }
+            }
+            self.layout_changed();
         }
 
         if !dead_panes.is_empty() && kill {
@@ -1417,7 +1643,7 @@ impl Tab {
             })
             .detach();
         }
-        dead_panes
+        (dead_panes, focus)
     }
 
     pub fn can_close_without_prompting(&self, reason: CloseReason) -> bool {
@@ -1504,6 +1730,7 @@ impl Tab {
             Ok(c) => *self.pane.borrow_mut() = Some(c.tree()),
             Err(_) => panic!("tried to assign root pane to non-empty tree"),
         }
+        self.layout_changed();
     }
 
     fn cell_dimensions(&self) -> TerminalSize {
@@ -1556,6 +1783,7 @@ impl Tab {
             let size = *self.size.borrow();
             apply_sizes_from_splits(root.as_mut().unwrap(), &size);
         }
+        self.layout_changed();
 
         // And update focus
         self.advise_focus_change(Some(pane));
@@ -1566,96 +1794,160 @@ impl Tab {
     /// pane was split in a particular direction.
     /// The intent is to call this prior to spawning the new pane so that
     /// you can create it with the correct size.
-    /// May return None if the specified pane_index is invalid.
+    /// If the tab is zoomed it will be unzoomed, unless `unzoom_on_split`
+    /// is disabled, in which case `SplitError::Zoomed` is returned.
     pub fn compute_split_size(
         &self,
         pane_index: usize,
         request: SplitRequest,
-    ) -> Option<SplitDirectionAndSize> {
+    ) -> Result<SplitDirectionAndSize, SplitError> {
+        // Ensure that we're not zoomed, otherwise we'll end up in
+        // a bogus split state (https://github.com/wez/wezterm/issues/723)
+        if self.zoomed.borrow().is_some() {
+            if !configuration().unzoom_on_split {
+                return Err(SplitError::Zoomed);
+            }
+            self.set_zoomed(false);
+        }
+
+        self.layout_split(pane_index, request)
+            .map(|(_origin, split_info)| split_info)
+    }
+
+    /// Returns where the pane and the new pane would be if the specified
+    /// pane was split in a particular direction, without changing the
+    /// tab, or None if it cannot be split that way.
+    /// This shares its sizing with `compute_split_size`, so that it
+    /// agrees with the split that is eventually made.  A zoomed tab is
+    /// previewed as it would be once it is unzoomed.
+    pub fn preview_split(&self, pane_index: usize, request: SplitRequest) -> Option<SplitPreview> {
+        if self.zoomed.borrow().is_some() && !configuration().unzoom_on_split {
+            return None;
+        }
+        let ((left, top), split_info) = self.layout_split(pane_index, request).ok()?;
+        let first = CellRect {
+            left,
+            top,
+            width: split_info.first.cols,
+            height: split_info.first.rows,
+        };
+        let second = CellRect {
+            left: left + split_info.left_of_second(),
+            top: top + split_info.top_of_second(),
+            width: split_info.second.cols,
+            height: split_info.second.rows,
+        };
+        Some(if request.target_is_second {
+            SplitPreview {
+                existing: first,
+                new: second,
+            }
+        } else {
+            SplitPreview {
+                existing: second,
+                new: first,
+            }
+        })
+    }
+
+    /// Computes the sizes of the split, along with the position of its
+    /// top left corner, from the layout that the tab has when it is
+    /// not zoomed.  The tab is left unchanged.
+    fn layout_split(
+        &self,
+        pane_index: usize,
+        request: SplitRequest,
+    ) -> Result<((usize, usize), SplitDirectionAndSize), SplitError> {
         let cell_dims = self.cell_dimensions();
 
-        fn split_dimension(dim: usize, request: SplitRequest) -> (usize, usize) {
+        fn split_dimension(
+            dim: usize,
+            request: SplitRequest,
+        ) -> Result<(usize, usize), SplitError> {
             let target_size = match request.size {
                 SplitSize::Cells(n) => n,
                 SplitSize::Percent(n) => (dim * (n as usize)) / 100,
             }
             .max(1);
 
-            let remain = dim.saturating_sub(target_size + 1);
+            // We need room for the new pane, the divider and
+            // at least one cell for the pane being split
+            let needed = target_size + 2;
+            if dim < needed {
+                return Err(SplitError::TooSmall {
+                    needed,
+                    available: dim,
+                });
+            }
+
+            let remain = dim - (target_size + 1);
 
             if request.target_is_second {
-                (remain, target_size)
+                Ok((remain, target_size))
             } else {
-                (target_size, remain)
+                Ok((target_size, remain))
             }
         }
 
-        if request.top_level {
-            let size = self.size.borrow().clone();
-
-            let ((width1, width2), (height1, height2)) = match request.direction {
-                SplitDirection::Horizontal => (
-                    split_dimension(size.cols as usize, request),
-                    (size.rows as usize, size.rows as usize),
-                ),
-                SplitDirection::Vertical => (
-                    (size.cols as usize, size.cols as usize),
-                    split_dimension(size.rows as usize, request),
-                ),
-            };
-
-            return Some(SplitDirectionAndSize {
-                direction: request.direction,
+        let make_size =
+            |direction: SplitDirection,
+             (width1, width2): (usize, usize),
+             (height1, height2): (usize, usize)| SplitDirectionAndSize {
+                direction,
                 first: TerminalSize {
-                    rows: height1 as _,
-                    cols: width1 as _,
+                    rows: height1,
+                    cols: width1,
                     pixel_height: cell_dims.pixel_height * height1,
                     pixel_width: cell_dims.pixel_width * width1,
                     dpi: cell_dims.dpi,
                 },
                 second: TerminalSize {
-                    rows: height2 as _,
-                    cols: width2 as _,
+                    rows: height2,
+                    cols: width2,
                     pixel_height: cell_dims.pixel_height * height2,
                     pixel_width: cell_dims.pixel_width * width2,
                     dpi: cell_dims.dpi,
                 },
-            });
-        }
+            };
 
-        // Ensure that we're not zoomed, otherwise we'll end up in
-        // a bogus split state (https://github.com/wez/wezterm/issues/723)
-        self.set_zoomed(false);
+        if request.top_level {
+            let size = self.size.borrow().clone();
 
-        self.iter_panes().iter().nth(pane_index).map(|pos| {
-            let ((width1, width2), (height1, height2)) = match request.direction {
+            let (widths, heights) = match request.direction {
                 SplitDirection::Horizontal => (
-                    split_dimension(pos.width, request),
-                    (pos.height, pos.height),
+                    split_dimension(size.cols as usize, request)?,
+                    (size.rows as usize, size.rows as usize),
+                ),
+                SplitDirection::Vertical => (
+                    (size.cols as usize, size.cols as usize),
+                    split_dimension(size.rows as usize, request)?,
                 ),
-                SplitDirection::Vertical => {
-                    ((pos.width, pos.width), split_dimension(pos.height, request))
-                }
             };
 
-            SplitDirectionAndSize {
-                direction: request.direction,
-                first: TerminalSize {
-                    rows: height1 as _,
-                    cols: width1 as _,
-                    pixel_height: cell_dims.pixel_height * height1,
-                    pixel_width: cell_dims.pixel_width * width1,
-                    dpi: cell_dims.dpi,
-                },
-                second: TerminalSize {
-                    rows: height2 as _,
-                    cols: width2 as _,
-                    pixel_height: cell_dims.pixel_height * height2,
-                    pixel_width: cell_dims.pixel_width * width2,
-                    dpi: cell_dims.dpi,
-                },
-            }
-        })
+            return Ok(((0, 0), make_size(request.direction, widths, heights)));
+        }
+
+        let pos = self
+            .iter_panes_ignoring_zoom()
+            .into_iter()
+            .nth(pane_index)
+            .ok_or(SplitError::InvalidIndex(pane_index))?;
+
+        let (widths, heights) = match request.direction {
+            SplitDirection::Horizontal => (
+                split_dimension(pos.width, request)?,
+                (pos.height, pos.height),
+            ),
+            SplitDirection::Vertical => (
+                (pos.width, pos.width),
+                split_dimension(pos.height, request)?,
+            ),
+        };
+
+        Ok((
+            (pos.left, pos.top),
+            make_size(request.direction, widths, heights),
+        ))
     }
 
     /// Split the pane that has pane_index in the given direction and assign
@@ -1668,16 +1960,21 @@ impl Tab {
         request: SplitRequest,
         pane: Rc<dyn Pane>,
     ) -> anyhow::Result<usize> {
-        if self.zoomed.borrow().is_some() {
-            anyhow::bail!("cannot split while zoomed");
+        let result = self.split_and_insert_impl(pane_index, request, pane);
+        if result.is_ok() {
+            self.layout_changed();
         }
+        result
+    }
 
+    fn split_and_insert_impl(
+        &self,
+        pane_index: usize,
+        request: SplitRequest,
+        pane: Rc<dyn Pane>,
+    ) -> anyhow::Result<usize> {
         {
-            let split_info = self
-                .compute_split_size(pane_index, request)
-                .ok_or_else(|| {
-                    anyhow::anyhow!("invalid pane_index {}; cannot split!", pane_index)
-                })?;
+            let split_info = self.compute_split_size(pane_index, request)?;
 
             let tab_size = *self.size.borrow();
             if split_info.first.rows == 0
@@ -1856,7 +2153,47 @@ pub struct PaneEntry {
     pub is_active_pane: bool,
     pub is_zoomed_pane: bool,
     pub workspace: String,
+    pub systemd_scope: Option<String>,
+    pub spawn_label: Option<String>,
+    pub resource_usage: Option<PaneResourceUsage>,
+    pub modes: Option<TerminalModes>,
+    pub links: Vec<PaneLink>,
+    /// The layout generation of the containing tab; see
+    /// `Tab::layout_generation`
+    pub layout_generation: usize,
+    /// The title computed for the containing tab by the mux server;
+    /// see `Tab::compute_title`
+    pub tab_title: Option<String>,
+    /// See `Pane::get_palette_overrides`
+    pub palette_overrides: Option<Palette>,
+    /// See `Pane::get_markers`
+    pub markers: Vec<Marker>,
+}
+
+/// Describes a tab to the `mux-format-tab-title` event
+#[derive(Debug, Clone, FromDynamic, ToDynamic)]
+pub struct TabTitleInfo {
+    pub tab_id: TabId,
+    pub window_id: WindowId,
+    pub workspace: String,
+    /// The title that was explicitly set on the tab, if any
+    pub title: String,
+    pub active_pane: Option<TabTitlePaneInfo>,
+    pub panes: Vec<TabTitlePaneInfo>,
+}
+luahelper::impl_lua_conversion_dynamic!(TabTitleInfo);
+
+/// Describes a pane of a tab to the `mux-format-tab-title` event
+#[derive(Debug, Clone, FromDynamic, ToDynamic)]
+pub struct TabTitlePaneInfo {
+    pub pane_id: PaneId,
+    pub title: String,
+    pub current_working_dir: Option<String>,
+    pub foreground_process_name: Option<String>,
+    pub is_active: bool,
+    pub is_zoomed: bool,
 }
+luahelper::impl_lua_conversion_dynamic!(TabTitlePaneInfo);
 
 #[derive(Deserialize, Clone, Serialize, PartialEq, Debug)]
 #[serde(try_from = "String", into = "String")]
@@ -2012,15 +2349,33 @@ mod test {
         assert_eq!(80, panes[0].width);
         assert_eq!(24, panes[0].height);
 
-        assert!(tab
-            .compute_split_size(
+        assert_eq!(
+            tab.compute_split_size(
                 1,
                 SplitRequest {
                     direction: SplitDirection::Horizontal,
                     ..Default::default()
                 }
             )
-            .is_none());
+            .err(),
+            Some(SplitError::InvalidIndex(1))
+        );
+
+        assert_eq!(
+            tab.compute_split_size(
+                0,
+                SplitRequest {
+                    direction: SplitDirection::Horizontal,
+                    size: SplitSize::Cells(100),
+                    ..Default::default()
+                }
+            )
+            .err(),
+            Some(SplitError::TooSmall {
+                needed: 102,
+                available: 80
+            })
+        );
 
         let horz_size = tab
             .compute_split_size(
@@ -2190,4 +2545,247 @@ mod test {
         assert_eq!(400, panes[2].pixel_width);
         assert_eq!(600, panes[2].pixel_height);
     }
+
+    #[test]
+    fn layout_generation() {
+        let size = TerminalSize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+            dpi: 96,
+        };
+
+        let tab = Tab::new(&size);
+        let mut generation = tab.layout_generation();
+        let mut assert_changed = |what: &str| {
+            assert_ne!(generation, tab.layout_generation(), "{}", what);
+            generation = tab.layout_generation();
+        };
+
+        tab.assign_pane(&FakePane::new(1, size));
+        assert_changed("assign");
+
+        tab.split_and_insert(
+            0,
+            SplitRequest {
+                direction: SplitDirection::Horizontal,
+                target_is_second: true,
+                ..Default::default()
+            },
+            FakePane::new(2, size),
+        )
+        .unwrap();
+        assert_changed("split");
+
+        tab.resize_split_by(0, 2);
+        assert_changed("resize split");
+
+        tab.resize(TerminalSize {
+            rows: 30,
+            cols: 100,
+            pixel_width: 1000,
+            pixel_height: 750,
+            dpi: 96,
+        });
+        assert_changed("resize");
+
+        tab.toggle_zoom();
+        assert_changed("zoom");
+        tab.toggle_zoom();
+        assert_changed("unzoom");
+
+        tab.rotate_clockwise();
+        assert_changed("rotate");
+
+        tab.remove_pane(2).unwrap();
+        assert_changed("remove");
+
+        // Nothing changes when the size stays the same
+        tab.resize(tab.get_size());
+        assert_eq!(generation, tab.layout_generation());
+    }
+
+    fn split(tab: &Tab, pane_index: usize, request: SplitRequest, id: PaneId) {
+        let size = tab.get_size();
+        tab.split_and_insert(pane_index, request, FakePane::new(id, size))
+            .unwrap();
+    }
+
+    fn split_second(direction: SplitDirection) -> SplitRequest {
+        SplitRequest {
+            direction,
+            target_is_second: true,
+            ..Default::default()
+        }
+    }
+
+    fn rect_of(tab: &Tab, id: PaneId) -> CellRect {
+        let pos = tab
+            .iter_panes_ignoring_zoom()
+            .into_iter()
+            .find(|p| p.pane.pane_id() == id)
+            .unwrap();
+        CellRect {
+            left: pos.left,
+            top: pos.top,
+            width: pos.width,
+            height: pos.height,
+        }
+    }
+
+    #[test]
+    fn split_preview_matches_split() {
+        let size = TerminalSize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+            dpi: 96,
+        };
+        let two_panes = || {
+            let tab = Tab::new(&size);
+            tab.assign_pane(&FakePane::new(1, size));
+            split(&tab, 0, split_second(SplitDirection::Horizontal), 2);
+            tab
+        };
+        let requests = [
+            split_second(SplitDirection::Vertical),
+            SplitRequest {
+                direction: SplitDirection::Horizontal,
+                target_is_second: false,
+                size: SplitSize::Cells(10),
+                top_level: false,
+            },
+            SplitRequest {
+                direction: SplitDirection::Vertical,
+                target_is_second: false,
+                size: SplitSize::Percent(30),
+                top_level: false,
+            },
+        ];
+
+        for request in &requests {
+            let tab = two_panes();
+            let generation = tab.layout_generation();
+            let preview = tab.preview_split(1, *request).unwrap();
+            assert_eq!(generation, tab.layout_generation(), "{:?}", request);
+
+            split(&tab, 1, *request, 3);
+            assert_eq!(preview.new, rect_of(&tab, 3), "{:?}", request);
+            assert_eq!(preview.existing, rect_of(&tab, 2), "{:?}", request);
+        }
+
+        let tab = two_panes();
+        let request = SplitRequest {
+            top_level: true,
+            ..split_second(SplitDirection::Vertical)
+        };
+        let preview = tab.preview_split(0, request).unwrap();
+        split(&tab, 0, request, 3);
+        assert_eq!(preview.new, rect_of(&tab, 3));
+        assert_eq!(
+            preview.existing,
+            CellRect {
+                left: 0,
+                top: 0,
+                width: 80,
+                height: 11
+            }
+        );
+
+        // Zoomed tabs are previewed as they would be after unzooming,
+        // and stay zoomed
+        let tab = two_panes();
+        tab.toggle_zoom();
+        let preview = tab.preview_split(1, requests[0]).unwrap();
+        assert!(tab.iter_panes()[0].is_zoomed);
+        split(&tab, 1, requests[0], 3);
+        assert_eq!(preview.new, rect_of(&tab, 3));
+
+        let tab = two_panes();
+        let too_big = SplitRequest {
+            size: SplitSize::Cells(100),
+            ..requests[0]
+        };
+        assert_eq!(tab.preview_split(1, too_big), None);
+        assert_eq!(tab.preview_split(5, requests[0]), None);
+    }
+
+    /// Activates pane `active`, then removes pane `removed`, and
+    /// returns the id of the pane that has the focus afterwards
+    fn focus_after_removing(tab: &Tab, active: PaneId, removed: PaneId) -> Option<PaneId> {
+        let pane = tab
+            .iter_panes()
+            .into_iter()
+            .find(|p| p.pane.pane_id() == active)
+            .unwrap()
+            .pane;
+        tab.set_active_pane(&pane);
+        let (_pane, focus) = tab.remove_pane_with_focus_hint(removed).unwrap();
+        let focus = focus.map(|p| p.pane_id());
+        assert_eq!(focus, tab.get_active_pane().map(|p| p.pane_id()));
+        focus
+    }
+
+    #[test]
+    fn focus_after_removing_pane() {
+        let size = TerminalSize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+            dpi: 96,
+        };
+
+        // +---+-------+
+        // |   |   2   |
+        // | 1 +---+---+
+        // |   | 3 | 4 |
+        // +---+---+---+
+        let nested = || {
+            let tab = Tab::new(&size);
+            tab.assign_pane(&FakePane::new(1, size));
+            split(&tab, 0, split_second(SplitDirection::Horizontal), 2);
+            split(&tab, 1, split_second(SplitDirection::Vertical), 3);
+            split(&tab, 2, split_second(SplitDirection::Horizontal), 4);
+            tab
+        };
+        for &(removed, expected) in &[(1, 2), (2, 3), (3, 4), (4, 3)] {
+            let tab = nested();
+            assert_eq!(
+                focus_after_removing(&tab, removed, removed),
+                Some(expected),
+                "removing pane {}",
+                removed
+            );
+        }
+
+        // Removing another pane leaves the focus where it was
+        for &removed in &[1, 2, 3] {
+            let tab = nested();
+            assert_eq!(focus_after_removing(&tab, 4, removed), Some(4));
+        }
+
+        // +---+---+---+
+        // | 1 | 2 | 3 |
+        // +---+---+---+
+        // The neighbor is the pane that was beside the removed one,
+        // rather than the first pane of the tab
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(1, size));
+        split(&tab, 0, split_second(SplitDirection::Horizontal), 2);
+        split(
+            &tab,
+            0,
+            SplitRequest {
+                top_level: true,
+                ..split_second(SplitDirection::Horizontal)
+            },
+            3,
+        );
+        assert_eq!(focus_after_removing(&tab, 3, 3), Some(2));
+        assert_eq!(focus_after_removing(&tab, 1, 1), Some(2));
+        assert_eq!(focus_after_removing(&tab, 2, 2), None);
+    }
 }
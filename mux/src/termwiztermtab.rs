@@ -3,15 +3,14 @@
 //! input from the user as part of eg: setting up an ssh
 //! session.
 
-use crate::domain::{alloc_domain_id, Domain, DomainId, DomainState};
+use crate::domain::{alloc_domain_id, Domain, DomainId, DomainResult, DomainState};
 use crate::pane::{alloc_pane_id, CloseReason, Pane, PaneId};
 use crate::renderable::*;
 use crate::tab::Tab;
 use crate::window::WindowId;
 use crate::Mux;
-use anyhow::bail;
 use async_trait::async_trait;
-use config::keyassignment::ScrollbackEraseMode;
+use config::keyassignment::{CommandDir, ScrollbackEraseMode};
 use crossbeam::channel::{unbounded as channel, Receiver, Sender};
 use filedescriptor::{FileDescriptor, Pipe};
 use portable_pty::*;
@@ -46,11 +45,12 @@ impl TermWizTerminalDomain {
 impl Domain for TermWizTerminalDomain {
     async fn spawn_pane(
         &self,
+        _pane_id: PaneId,
         _size: TerminalSize,
         _command: Option<CommandBuilder>,
-        _command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        bail!("cannot spawn panes in a TermWizTerminalPane");
+        _command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        Err(anyhow::anyhow!("cannot spawn panes in a TermWizTerminalPane").into())
     }
 
     fn spawnable(&self) -> bool {
@@ -64,12 +64,12 @@ impl Domain for TermWizTerminalDomain {
     fn domain_name(&self) -> &str {
         "TermWizTerminalDomain"
     }
-    async fn attach(&self, _window_id: Option<WindowId>) -> anyhow::Result<()> {
+    async fn attach(&self, _window_id: Option<WindowId>) -> DomainResult<()> {
         Ok(())
     }
 
-    fn detach(&self) -> anyhow::Result<()> {
-        bail!("detach not implemented for TermWizTerminalDomain");
+    fn detach(&self) -> DomainResult<()> {
+        Err(anyhow::anyhow!("detach not implemented for TermWizTerminalDomain").into())
     }
 
     fn state(&self) -> DomainState {
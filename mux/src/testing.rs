@@ -0,0 +1,71 @@
+//! Helpers for tests that need a running mux.
+//!
+//! The mux is bound to the thread that built it, and most of its work
+//! happens in futures that are scheduled onto that thread, so a test
+//! that makes blocking requests of the mux (for example over a client
+//! connection) has to make them from another thread while the mux
+//! thread keeps running its executor.  `with_test_mux` takes care of
+//! that arrangement.
+use crate::builder::MuxBuilder;
+use crate::domain::{Domain, LocalDomain};
+use crate::Mux;
+use promise::spawn::SimpleExecutor;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Builds a mux whose only domain is a `LocalDomain` named `local`,
+/// and then runs a test against it; see `with_test_mux_from`.
+pub fn with_test_mux<S, F, T>(setup: S) -> T
+where
+    S: FnOnce(&Rc<Mux>) -> F,
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let local: Arc<dyn Domain> = Arc::new(LocalDomain::new("local").unwrap());
+    with_test_mux_from(
+        MuxBuilder::new()
+            .config(config::Config::default_config())
+            .domain(local),
+        setup,
+    )
+}
+
+/// Builds a mux from `builder` and calls `setup` with it on the mux
+/// thread.  `setup` returns the body of the test, which is run on
+/// another thread while the mux thread runs the executor.
+/// Once the body has returned, any panes that it left behind are
+/// killed, and its result is returned.  If the body panicked, the
+/// panic is propagated instead.
+pub fn with_test_mux_from<S, F, T>(builder: MuxBuilder, setup: S) -> T
+where
+    S: FnOnce(&Rc<Mux>) -> F,
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let executor = SimpleExecutor::new();
+    let mux = builder.build().unwrap();
+    let body = setup(&mux);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(body));
+        tx.send(result).unwrap();
+        // Wake up the executor so that the test can finish
+        promise::spawn::spawn_into_main_thread(async {}).detach();
+    });
+
+    let result = loop {
+        executor.tick().unwrap();
+        if let Ok(result) = rx.try_recv() {
+            break result;
+        }
+    };
+    for pane in mux.iter_panes() {
+        pane.kill();
+    }
+    match result {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
@@ -1,9 +1,10 @@
-use crate::domain::{alloc_domain_id, Domain, DomainId, DomainState};
+use crate::domain::{alloc_domain_id, Domain, DomainId, DomainResult, DomainState};
 use crate::pane::{Pane, PaneId};
 use crate::tab::TabId;
 use crate::tmux_commands::{ListAllPanes, TmuxCommand};
 use crate::{Mux, MuxWindowBuilder};
 use async_trait::async_trait;
+use config::keyassignment::CommandDir;
 use filedescriptor::FileDescriptor;
 use portable_pty::CommandBuilder;
 use std::cell::RefCell;
@@ -207,11 +208,12 @@ impl TmuxDomain {
 impl Domain for TmuxDomain {
     async fn spawn_pane(
         &self,
+        _pane_id: PaneId,
         _size: TerminalSize,
         _command: Option<CommandBuilder>,
-        _command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        anyhow::bail!("Spawn_pane not yet implemented for TmuxDomain");
+        _command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        Err(anyhow::anyhow!("Spawn_pane not yet implemented for TmuxDomain").into())
     }
 
     fn domain_id(&self) -> DomainId {
@@ -222,12 +224,12 @@ impl Domain for TmuxDomain {
         "tmux"
     }
 
-    async fn attach(&self, _window_id: Option<crate::WindowId>) -> anyhow::Result<()> {
+    async fn attach(&self, _window_id: Option<crate::WindowId>) -> DomainResult<()> {
         Ok(())
     }
 
-    fn detach(&self) -> anyhow::Result<()> {
-        anyhow::bail!("detach not implemented for TmuxDomain");
+    fn detach(&self) -> DomainResult<()> {
+        Err(anyhow::anyhow!("detach not implemented for TmuxDomain").into())
     }
 
     fn state(&self) -> DomainState {
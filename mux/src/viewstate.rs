@@ -0,0 +1,272 @@
+//! Remembers where each client had scrolled each pane to, and what it
+//! had selected there, so that a client that detaches and reattaches
+//! finds its panes as it left them rather than scrolled to the bottom.
+//! Clients report their view of a pane as it changes.  The views are
+//! kept by the user and host of the client, as the rest of its
+//! `ClientId` changes with each connection.  Only the views of the
+//! `MAX_CLIENTS` most recently active clients are kept, at most
+//! `MAX_PANES_PER_CLIENT` of them each, and a view that hasn't been
+//! reported for `VIEW_EXPIRY` is forgotten.
+//! Rows are identified by their `StableRowIndex`, so a view still shows
+//! the same lines after more output has arrived; a view whose rows have
+//! since been trimmed from the scrollback is clamped to the oldest row.
+use crate::client::ClientId;
+use crate::pane::PaneId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use wezterm_term::StableRowIndex;
+
+/// The most clients whose views are kept
+pub const MAX_CLIENTS: usize = 32;
+/// The most panes whose views are kept for each client
+pub const MAX_PANES_PER_CLIENT: usize = 512;
+/// How long a view is kept after it was last reported
+pub const VIEW_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A selection, by the cells at either end of it, inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ViewSelection {
+    pub start_x: usize,
+    pub start_y: StableRowIndex,
+    pub end_x: usize,
+    pub end_y: StableRowIndex,
+    pub rectangular: bool,
+}
+
+/// How a client views a pane
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaneViewState {
+    /// The top row of the viewport, or None if it is at the bottom
+    /// of the scrollback
+    pub viewport: Option<StableRowIndex>,
+    pub selection: Option<ViewSelection>,
+}
+
+impl PaneViewState {
+    /// Returns true if there is nothing to restore, as a pane is shown
+    /// at the bottom and without a selection when it is first attached
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the view with the rows before `scrollback_top`, which
+    /// have been trimmed from the scrollback, clamped to it.
+    /// A selection that lies wholly within the trimmed rows is dropped.
+    pub fn clamp(self, scrollback_top: StableRowIndex) -> Self {
+        let viewport = self.viewport.map(|top| top.max(scrollback_top));
+        let selection = self.selection.and_then(|mut sel| {
+            if sel.start_y.max(sel.end_y) < scrollback_top {
+                return None;
+            }
+            if sel.start_y < scrollback_top {
+                sel.start_y = scrollback_top;
+                sel.start_x = 0;
+            }
+            if sel.end_y < scrollback_top {
+                sel.end_y = scrollback_top;
+                sel.end_x = 0;
+            }
+            Some(sel)
+        });
+        Self {
+            viewport,
+            selection,
+        }
+    }
+}
+
+/// The key under which the views of `client_id` are kept
+fn client_key(client_id: &ClientId) -> String {
+    format!("{}@{}", client_id.username, client_id.hostname)
+}
+
+struct ClientViews {
+    last_seen: Instant,
+    panes: HashMap<PaneId, (Instant, PaneViewState)>,
+}
+
+#[derive(Default)]
+pub struct ClientViewStates {
+    clients: HashMap<String, ClientViews>,
+}
+
+impl ClientViewStates {
+    /// Records how `client_id` views `pane_id` as of `now`
+    pub fn record(
+        &mut self,
+        client_id: &ClientId,
+        pane_id: PaneId,
+        state: PaneViewState,
+        now: Instant,
+    ) {
+        self.expire(now);
+        let views = self
+            .clients
+            .entry(client_key(client_id))
+            .or_insert_with(|| ClientViews {
+                last_seen: now,
+                panes: HashMap::new(),
+            });
+        views.last_seen = now;
+        if state.is_default() {
+            views.panes.remove(&pane_id);
+            return;
+        }
+        views.panes.insert(pane_id, (now, state));
+
+        if views.panes.len() > MAX_PANES_PER_CLIENT {
+            if let Some(oldest) = views
+                .panes
+                .iter()
+                .min_by_key(|(_, (when, _))| *when)
+                .map(|(&pane_id, _)| pane_id)
+            {
+                views.panes.remove(&oldest);
+            }
+        }
+        if self.clients.len() > MAX_CLIENTS {
+            if let Some(oldest) = self
+                .clients
+                .iter()
+                .min_by_key(|(_, views)| views.last_seen)
+                .map(|(key, _)| key.clone())
+            {
+                self.clients.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns the views of the panes that `client_id`, or an earlier
+    /// connection from the same user and host, reported
+    pub fn views(&mut self, client_id: &ClientId, now: Instant) -> HashMap<PaneId, PaneViewState> {
+        self.expire(now);
+        self.clients
+            .get(&client_key(client_id))
+            .map(|views| {
+                views
+                    .panes
+                    .iter()
+                    .map(|(&pane_id, (_, state))| (pane_id, *state))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_pane(&mut self, pane_id: PaneId) {
+        for views in self.clients.values_mut() {
+            views.panes.remove(&pane_id);
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        for views in self.clients.values_mut() {
+            views
+                .panes
+                .retain(|_, (when, _)| now.saturating_duration_since(*when) < VIEW_EXPIRY);
+        }
+        self.clients.retain(|_, views| !views.panes.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client(username: &str, pid: u32) -> ClientId {
+        ClientId {
+            hostname: "host".to_string(),
+            username: username.to_string(),
+            pid,
+            epoch: 0,
+            id: 0,
+        }
+    }
+
+    fn scrolled(top: StableRowIndex) -> PaneViewState {
+        PaneViewState {
+            viewport: Some(top),
+            selection: None,
+        }
+    }
+
+    #[test]
+    fn kept_across_connections() {
+        let now = Instant::now();
+        let mut states = ClientViewStates::default();
+        states.record(&client("a", 1), 1, scrolled(10), now);
+        states.record(&client("b", 1), 1, scrolled(20), now);
+
+        // A reconnect from the same user and host sees its views
+        let views = states.views(&client("a", 2), now);
+        assert_eq!(views.get(&1), Some(&scrolled(10)));
+        assert_eq!(
+            states.views(&client("b", 3), now).get(&1),
+            Some(&scrolled(20))
+        );
+
+        // Scrolling back to the bottom leaves nothing to restore
+        states.record(&client("a", 2), 1, PaneViewState::default(), now);
+        assert!(states.views(&client("a", 2), now).is_empty());
+
+        states.remove_pane(1);
+        assert!(states.views(&client("b", 3), now).is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let now = Instant::now();
+        let mut states = ClientViewStates::default();
+        for pane_id in 0..=MAX_PANES_PER_CLIENT {
+            let when = now + Duration::from_millis(pane_id as u64);
+            states.record(&client("a", 1), pane_id, scrolled(1), when);
+        }
+        let later = now + Duration::from_secs(1);
+        let views = states.views(&client("a", 1), later);
+        assert_eq!(views.len(), MAX_PANES_PER_CLIENT);
+        // The least recently reported view makes way
+        assert!(!views.contains_key(&0));
+
+        for n in 0..MAX_CLIENTS {
+            states.record(&client(&n.to_string(), 1), 0, scrolled(1), later);
+        }
+        assert!(states.views(&client("a", 1), later).is_empty());
+
+        let expired = later + VIEW_EXPIRY;
+        assert!(states.views(&client("0", 1), expired).is_empty());
+    }
+
+    #[test]
+    fn clamping() {
+        let selection = ViewSelection {
+            start_x: 5,
+            start_y: 40,
+            end_x: 3,
+            end_y: 10,
+            rectangular: false,
+        };
+        let view = PaneViewState {
+            viewport: Some(10),
+            selection: Some(selection),
+        };
+        assert_eq!(view.clamp(0), view);
+        assert_eq!(
+            view.clamp(20),
+            PaneViewState {
+                viewport: Some(20),
+                selection: Some(ViewSelection {
+                    end_x: 0,
+                    end_y: 20,
+                    ..selection
+                }),
+            }
+        );
+        assert_eq!(
+            view.clamp(50),
+            PaneViewState {
+                viewport: Some(50),
+                selection: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Detects panes whose pty reader has stalled.
+//! After a system resume we have seen reads from the pty block
+//! indefinitely, even though the child is alive and the pty has output
+//! waiting to be read, leaving the pane frozen.
+//! The watchdog periodically looks for readers that are blocked while
+//! the pty reports pending output and replaces them with a fresh reader
+//! that is attached to the same master and the same parser.
+use crate::outputpause::OutputPause;
+use crate::pane::PaneId;
+use crate::Mux;
+use anyhow::anyhow;
+use config::configuration;
+use filedescriptor::FileDescriptor;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+
+/// The state shared between the reader thread(s) for a pane and the
+/// watchdog.
+pub(crate) struct PaneReaderState {
+    /// Signals that the pane output has ended, either because of an error
+    /// in the reader or parser, or because the mux removed the pane.
+    pub dead: Arc<AtomicBool>,
+    /// Holds back the parser while the output of the pane is paused
+    pub pause: Arc<OutputPause>,
+    /// Set by the parser once it has applied the last of the output
+    pub parsed: Arc<AtomicBool>,
+    /// The sending side of the parser socket.  A replacement reader
+    /// writes into a clone of this.  It is taken when the output ends
+    /// so that the parser can observe EOF.
+    tx: Mutex<Option<FileDescriptor>>,
+    /// Held by a reader while it checks that it is current and writes
+    /// to the parser, so that a superseded reader can't write after its
+    /// replacement has started
+    forwarding: Mutex<()>,
+    /// Incremented each time a reader is started; only the reader with
+    /// the current generation is responsible for the pane.
+    generation: AtomicUsize,
+    /// While the current reader is blocked in read, the time at which
+    /// it started to block
+    blocked_since: Mutex<Option<Instant>>,
+    /// Set when the watchdog has seen the reader blocked with output
+    /// pending; a reader must be seen in that state on two successive
+    /// checks before it is considered to have stalled.
+    suspect: AtomicBool,
+}
+
+impl PaneReaderState {
+    pub fn new(
+        tx: FileDescriptor,
+        dead: Arc<AtomicBool>,
+        pause: Arc<OutputPause>,
+        parsed: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            dead,
+            pause,
+            parsed,
+            tx: Mutex::new(Some(tx)),
+            forwarding: Mutex::new(()),
+            generation: AtomicUsize::new(0),
+            blocked_since: Mutex::new(None),
+            suspect: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts a thread that reads from `reader` and feeds the parser,
+    /// superseding any previous reader.  Returns the generation of the
+    /// new reader.
+    pub fn spawn_reader(
+        self: &Arc<Self>,
+        pane_id: PaneId,
+        reader: Box<dyn std::io::Read + Send>,
+    ) -> anyhow::Result<usize> {
+        let tx = self
+            .tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| anyhow!("the output of pane {} has already ended", pane_id))?
+            .try_clone()?;
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.blocked_since.lock().unwrap().take();
+        self.suspect.store(false, Ordering::Relaxed);
+        let state = Arc::clone(self);
+        std::thread::spawn(move || {
+            crate::read_from_pane_pty(pane_id, reader, tx, state, generation)
+        });
+        Ok(generation)
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn is_current(&self, generation: usize) -> bool {
+        self.generation() == generation
+    }
+
+    /// Writes `data`, as read by the reader of `generation`, to the
+    /// parser via `tx`.  Returns false, without writing anything, if
+    /// that reader has been superseded.
+    pub fn forward(
+        &self,
+        generation: usize,
+        tx: &mut FileDescriptor,
+        data: &[u8],
+    ) -> std::io::Result<bool> {
+        use std::io::Write;
+        let _forwarding = self.forwarding.lock().unwrap();
+        if !self.is_current(generation) {
+            return Ok(false);
+        }
+        tx.write_all(data)?;
+        Ok(true)
+    }
+
+    /// Called by the reader with `true` before it blocks in read,
+    /// and with `false` once the read has returned
+    pub fn set_blocked(&self, generation: usize, blocked: bool) {
+        if !self.is_current(generation) {
+            return;
+        }
+        *self.blocked_since.lock().unwrap() = if blocked { Some(Instant::now()) } else { None };
+        if !blocked {
+            self.suspect.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Feeds `data` to the parser ahead of any output that is read
+    /// after this point
+    pub fn inject(&self, pane_id: PaneId, data: &[u8]) -> anyhow::Result<()> {
+        use std::io::Write;
+        let mut tx = self
+            .tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| anyhow!("the output of pane {} has already ended", pane_id))?
+            .try_clone()?;
+        tx.write_all(data)?;
+        Ok(())
+    }
+
+    /// Called by the current reader when the pty output has ended
+    pub fn output_ended(&self) {
+        self.tx.lock().unwrap().take();
+    }
+
+    fn blocked_for(&self) -> Option<Duration> {
+        self.blocked_since
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|since| since.elapsed())
+    }
+}
+
+/// What caused a pane reader to be restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReaderRestartTrigger {
+    Watchdog,
+    Nudge,
+}
+
+impl Mux {
+    /// Looks for panes whose reader has been blocked for at least
+    /// `pty_reader_stall_timeout_ms` while the pty has output waiting
+    /// to be read, and restarts their readers.
+    /// Panes that are simply idle have no pending output and are
+    /// never disturbed.
+    pub(crate) fn check_pane_readers(&self) {
+        let timeout = Duration::from_millis(configuration().pty_reader_stall_timeout_ms);
+        if timeout.is_zero() {
+            return;
+        }
+
+        for (pane_id, state) in self.pane_reader_states() {
+            let pane = match self.get_pane(pane_id) {
+                Some(pane) => pane,
+                None => continue,
+            };
+            if pane.is_dead() || state.dead.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let blocked_for = match state.blocked_for() {
+                Some(blocked_for) if blocked_for >= timeout => blocked_for,
+                _ => {
+                    state.suspect.store(false, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            let pending = match pane.pending_output() {
+                Some(pending) if pending > 0 => pending,
+                _ => {
+                    state.suspect.store(false, Ordering::Relaxed);
+                    continue;
+                }
+            };
+
+            // Output may have arrived just before this check and the
+            // reader not yet been scheduled to consume it; only act if
+            // it is still pending on the next check
+            if !state.suspect.swap(true, Ordering::Relaxed) {
+                continue;
+            }
+
+            log::warn!(
+                "pane reader stalled: pane_id={} generation={} blocked_ms={} pending_bytes={}",
+                pane_id,
+                state.generation(),
+                blocked_for.as_millis(),
+                pending
+            );
+            if let Err(err) = self.restart_pane_reader(pane_id, ReaderRestartTrigger::Watchdog) {
+                log::error!("pane reader restart failed: pane_id={} {:#}", pane_id, err);
+            }
+        }
+    }
+
+    /// Replaces the reader for the specified pane with a new reader that
+    /// is attached to the same pty and parser.  The old reader exits
+    /// the next time that its read returns, discarding whatever it read
+    /// rather than racing the new reader to the parser.
+    pub fn restart_pane_reader(
+        &self,
+        pane_id: PaneId,
+        trigger: ReaderRestartTrigger,
+    ) -> anyhow::Result<()> {
+        let pane = self
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+        if pane.is_dead() {
+            anyhow::bail!("pane {} is dead", pane_id);
+        }
+        let state = self
+            .pane_reader_state(pane_id)
+            .ok_or_else(|| anyhow!("pane {} does not read from a local pty", pane_id))?;
+        let reader = pane
+            .reader()?
+            .ok_or_else(|| anyhow!("pane {} does not read from a local pty", pane_id))?;
+        let generation = state.spawn_reader(pane_id, reader)?;
+        log::warn!(
+            "pane reader restarted: pane_id={} generation={} trigger={:?}",
+            pane_id,
+            generation,
+            trigger
+        );
+        Ok(())
+    }
+
+    /// Manually restart the reader for a pane, as requested via
+    /// `wezterm cli nudge-pane`
+    pub fn nudge_pane(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        self.restart_pane_reader(pane_id, ReaderRestartTrigger::Nudge)
+    }
+}
+
+/// Starts the watchdog thread, if it isn't already running.
+/// The thread wakes periodically and asks the mux thread to
+/// check the pane readers.
+pub(crate) fn start_watchdog() {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        let result = std::thread::Builder::new()
+            .name("pane-watchdog".to_string())
+            .spawn(|| loop {
+                let timeout = configuration().pty_reader_stall_timeout_ms;
+                // Check twice per timeout period, as a reader needs to
+                // be seen stalled on two successive checks
+                let interval = Duration::from_millis((timeout / 2).max(1000));
+                std::thread::sleep(interval);
+                if timeout == 0 {
+                    continue;
+                }
+                promise::spawn::spawn_into_main_thread(async {
+                    if let Some(mux) = Mux::get() {
+                        mux.check_pane_readers();
+                    }
+                })
+                .detach();
+            });
+        if let Err(err) = result {
+            log::error!("failed to start pane watchdog thread: {:#}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn superseded_reader_does_not_forward() {
+        let (tx, mut rx) = filedescriptor::socketpair().unwrap();
+        let state = PaneReaderState::new(
+            tx,
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(OutputPause::default()),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let mut old_tx = state
+            .tx
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .try_clone()
+            .unwrap();
+        let old = state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        assert!(state.forward(old, &mut old_tx, b"old").unwrap());
+
+        // The watchdog starts a replacement reader
+        let mut new_tx = old_tx.try_clone().unwrap();
+        let new = state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        assert!(!state.forward(old, &mut old_tx, b"stale").unwrap());
+        assert!(state.forward(new, &mut new_tx, b"new").unwrap());
+
+        drop(old_tx);
+        drop(new_tx);
+        state.output_ended();
+        let mut received = String::new();
+        rx.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "oldnew");
+    }
+}
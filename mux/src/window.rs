@@ -2,7 +2,8 @@ use crate::pane::CloseReason;
 use crate::{Mux, MuxNotification, Tab, TabId};
 use std::rc::Rc;
 
-static WIN_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+pub(crate) static WIN_ID: ::std::sync::atomic::AtomicUsize =
+    ::std::sync::atomic::AtomicUsize::new(0);
 pub type WindowId = usize;
 
 pub struct Window {
@@ -16,8 +17,10 @@ pub struct Window {
 
 impl Window {
     pub fn new(workspace: Option<String>) -> Self {
+        let id = WIN_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+        crate::ids::note_id_allocated();
         Self {
-            id: WIN_ID.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed),
+            id,
             tabs: vec![],
             active: 0,
             last_active: None,
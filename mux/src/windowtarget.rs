@@ -0,0 +1,108 @@
+//! Resolves where a command that is spawned with a `SpawnWindowTarget`
+//! goes, before the spawn itself runs.  An existing window can be
+//! resolved by any mux, but screens and the mouse pointer are only
+//! known to the GUI, which registers a resolver for them with
+//! `Mux::set_window_target_resolver`.  A headless mux server has no
+//! resolver, so such targets fail right away.
+use crate::window::WindowId;
+use config::keyassignment::SpawnWindowTarget;
+
+/// Returns the window that a target refers to, or None if the command
+/// is to be spawned into a new window
+pub type WindowTargetResolver = Box<dyn Fn(&SpawnWindowTarget) -> anyhow::Result<Option<WindowId>>>;
+
+#[derive(Default)]
+pub struct WindowTargets {
+    resolver: Option<WindowTargetResolver>,
+}
+
+impl WindowTargets {
+    pub fn set_resolver(&mut self, resolver: WindowTargetResolver) {
+        self.resolver.replace(resolver);
+    }
+
+    /// Resolves `target`, using `window_exists` to check the id of an
+    /// existing window
+    pub fn resolve(
+        &self,
+        target: &SpawnWindowTarget,
+        window_exists: impl Fn(WindowId) -> bool,
+    ) -> anyhow::Result<Option<WindowId>> {
+        if let SpawnWindowTarget::ExistingWindow(window_id) = target {
+            anyhow::ensure!(window_exists(*window_id), "window {} not found", window_id);
+            return Ok(Some(*window_id));
+        }
+        if let Some(resolver) = &self.resolver {
+            return resolver(target);
+        }
+        let what = match target {
+            SpawnWindowTarget::NewWindowOnScreen(screen) => format!("on screen `{}`", screen),
+            _ => "into the window under the mouse".to_string(),
+        };
+        anyhow::bail!(
+            "spawning {} requires the wezterm GUI, which knows about the \
+             screens and the mouse pointer; this mux has no GUI",
+            what
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn headless() {
+        let targets = WindowTargets::default();
+        let exists = |window_id| window_id == 2;
+        assert_eq!(
+            targets
+                .resolve(&SpawnWindowTarget::ExistingWindow(2), exists)
+                .unwrap(),
+            Some(2)
+        );
+        assert!(targets
+            .resolve(&SpawnWindowTarget::ExistingWindow(3), exists)
+            .is_err());
+
+        let err = targets
+            .resolve(
+                &SpawnWindowTarget::NewWindowOnScreen("DP-2".to_string()),
+                exists,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("on screen `DP-2`"));
+        assert!(targets
+            .resolve(&SpawnWindowTarget::WindowUnderMouse, exists)
+            .is_err());
+    }
+
+    #[test]
+    fn with_resolver() {
+        let mut targets = WindowTargets::default();
+        targets.set_resolver(Box::new(|target| match target {
+            SpawnWindowTarget::WindowUnderMouse => Ok(Some(5)),
+            _ => Ok(None),
+        }));
+        let exists = |_| false;
+        assert_eq!(
+            targets
+                .resolve(&SpawnWindowTarget::WindowUnderMouse, exists)
+                .unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            targets
+                .resolve(
+                    &SpawnWindowTarget::NewWindowOnScreen("0".to_string()),
+                    exists
+                )
+                .unwrap(),
+            None
+        );
+        // Existing windows are checked by the mux, not the resolver
+        assert!(targets
+            .resolve(&SpawnWindowTarget::ExistingWindow(5), exists)
+            .is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::Duration;
 use wezterm_dynamic::{FromDynamic, ToDynamic};
 
 mod linux;
@@ -22,6 +23,25 @@ pub enum LocalProcessStatus {
     Unknown,
 }
 
+/// The resources consumed by one or more processes
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProcessResources {
+    /// The resident set size, in bytes
+    pub rss_bytes: u64,
+    /// The total user and system CPU time consumed since the
+    /// processes started
+    pub cpu_time: Duration,
+    /// The number of processes
+    pub num_processes: usize,
+}
+
+impl ProcessResources {
+    #[cfg(not(any(target_os = "macos", target_os = "linux", windows)))]
+    pub fn with_pid(_pid: u32) -> Option<Self> {
+        None
+    }
+}
+
 #[derive(Debug, Clone, FromDynamic, ToDynamic)]
 pub struct LocalProcessInfo {
     /// The process identifier
@@ -77,6 +97,27 @@ impl LocalProcessInfo {
         names
     }
 
+    /// Sums the resources consumed by this process and all of its
+    /// descendants.  Processes that exit while this is running are
+    /// not counted.
+    pub fn tree_resources(&self) -> ProcessResources {
+        let mut total = ProcessResources::default();
+
+        fn accumulate(item: &LocalProcessInfo, total: &mut ProcessResources) {
+            if let Some(res) = ProcessResources::with_pid(item.pid) {
+                total.rss_bytes += res.rss_bytes;
+                total.cpu_time += res.cpu_time;
+                total.num_processes += res.num_processes;
+            }
+            for proc in item.children.values() {
+                accumulate(proc, total);
+            }
+        }
+
+        accumulate(self, &mut total);
+        total
+    }
+
     #[cfg(not(any(target_os = "macos", target_os = "linux", windows)))]
     pub fn with_root_pid(_pid: u32) -> Option<Self> {
         None
@@ -137,3 +137,34 @@ impl LocalProcessInfo {
         }
     }
 }
+
+impl ProcessResources {
+    pub fn with_pid(pid: u32) -> Option<Self> {
+        let data = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let (_, fields) = data.rsplit_once(')')?;
+        let fields = fields.split_whitespace().collect::<Vec<_>>();
+        // utime and stime are fields 14 and 15 of stat, measured in ticks
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+
+        let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+        let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if ticks_per_second <= 0 || page_size <= 0 {
+            return None;
+        }
+
+        let ticks = utime + stime;
+        let ticks_per_second = ticks_per_second as u64;
+        Some(Self {
+            rss_bytes: resident_pages * page_size as u64,
+            cpu_time: Duration::from_secs(ticks / ticks_per_second)
+                + Duration::from_nanos(
+                    (ticks % ticks_per_second) * 1_000_000_000 / ticks_per_second,
+                ),
+            num_processes: 1,
+        })
+    }
+}
@@ -227,3 +227,37 @@ impl LocalProcessInfo {
         }
     }
 }
+
+impl ProcessResources {
+    pub fn with_pid(pid: u32) -> Option<Self> {
+        let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+        let wanted_size = std::mem::size_of::<libc::proc_taskinfo>() as _;
+        let res = unsafe {
+            libc::proc_pidinfo(
+                pid as _,
+                libc::PROC_PIDTASKINFO,
+                0,
+                &mut info as *mut _ as *mut _,
+                wanted_size,
+            )
+        };
+        if res != wanted_size {
+            return None;
+        }
+
+        // The times are measured in mach absolute time units, which
+        // are not nanoseconds on all hardware
+        let mut timebase: libc::mach_timebase_info = unsafe { std::mem::zeroed() };
+        if unsafe { libc::mach_timebase_info(&mut timebase) } != 0 || timebase.denom == 0 {
+            return None;
+        }
+        let ticks = (info.pti_total_user + info.pti_total_system) as u128;
+        let nanos = ticks * timebase.numer as u128 / timebase.denom as u128;
+
+        Some(Self {
+            rss_bytes: info.pti_resident_size,
+            cpu_time: Duration::from_nanos(nanos as u64),
+            num_processes: 1,
+        })
+    }
+}
@@ -15,6 +15,7 @@ use winapi::shared::ntdef::{FALSE, NT_SUCCESS};
 use winapi::um::handleapi::CloseHandle;
 use winapi::um::memoryapi::ReadProcessMemory;
 use winapi::um::processthreadsapi::{GetCurrentProcessId, GetProcessTimes, OpenProcess};
+use winapi::um::psapi::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
 use winapi::um::shellapi::CommandLineToArgvW;
 use winapi::um::tlhelp32::*;
 use winapi::um::winbase::{LocalFree, QueryFullProcessImageNameW};
@@ -292,6 +293,48 @@ impl ProcHandle {
         Some(buf)
     }
 
+    /// Retrieves the total user and kernel time consumed by the process
+    fn cpu_time(&self) -> Option<Duration> {
+        const fn empty() -> FILETIME {
+            FILETIME {
+                dwLowDateTime: 0,
+                dwHighDateTime: 0,
+            }
+        }
+
+        let mut start = empty();
+        let mut exit = empty();
+        let mut kernel = empty();
+        let mut user = empty();
+
+        let res =
+            unsafe { GetProcessTimes(self.proc, &mut start, &mut exit, &mut kernel, &mut user) };
+        if res == 0 {
+            return None;
+        }
+
+        fn to_u64(time: &FILETIME) -> u64 {
+            (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64
+        }
+
+        // Measured in 100ns units
+        Some(Duration::from_nanos(
+            (to_u64(&kernel) + to_u64(&user)).saturating_mul(100),
+        ))
+    }
+
+    /// Retrieves the working set size of the process, in bytes
+    fn working_set_size(&self) -> Option<u64> {
+        let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as DWORD;
+        counters.cb = size;
+        let res = unsafe { GetProcessMemoryInfo(self.proc, &mut counters, size) };
+        if res == 0 {
+            return None;
+        }
+        Some(counters.WorkingSetSize as u64)
+    }
+
     /// Retrieves the start time of the process
     fn start_time(&self) -> Option<u64> {
         const fn empty() -> FILETIME {
@@ -417,3 +460,14 @@ impl LocalProcessInfo {
         }
     }
 }
+
+impl ProcessResources {
+    pub fn with_pid(pid: u32) -> Option<Self> {
+        let proc = ProcHandle::new(pid)?;
+        Some(Self {
+            rss_bytes: proc.working_set_size()?,
+            cpu_time: proc.cpu_time()?,
+            num_processes: 1,
+        })
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use async_executor::Executor;
 use flume::{bounded, unbounded, Receiver, TryRecvError};
+use std::cell::RefCell;
 use std::future::Future;
 use std::sync::{Arc, Mutex};
 use std::task::{Poll, Waker};
@@ -19,7 +20,57 @@ lazy_static::lazy_static! {
     static ref SCOPED_EXECUTOR: Mutex<Option<Arc<Executor<'static>>>> = Mutex::new(None);
 }
 
-fn schedule_runnable(runnable: Runnable, high_pri: bool) {
+/// Schedulers that take the place of those set by `set_schedulers`
+/// for the futures that are spawned on a thread while they are entered.
+/// A future is bound to the schedulers that were entered when it was
+/// spawned, wherever it is woken up later on.
+/// This allows several executors to share a thread, each of them
+/// running the futures of a different mux, such as a test that runs
+/// both the server and the client of a mux connection.
+pub struct Schedulers {
+    main: ScheduleFunc,
+    low_pri: ScheduleFunc,
+}
+
+thread_local! {
+    static ENTERED_SCHEDULERS: RefCell<Option<Arc<Schedulers>>> = RefCell::new(None);
+}
+
+impl Schedulers {
+    pub fn new(main: ScheduleFunc, low_pri: ScheduleFunc) -> Arc<Self> {
+        Arc::new(Self { main, low_pri })
+    }
+
+    /// Calls `f`, binding the futures that it spawns to these schedulers
+    pub fn enter<T>(self: &Arc<Self>, f: impl FnOnce() -> T) -> T {
+        struct Restore(Option<Arc<Schedulers>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let prior = self.0.take();
+                ENTERED_SCHEDULERS.with(|entered| *entered.borrow_mut() = prior);
+            }
+        }
+
+        let prior =
+            ENTERED_SCHEDULERS.with(|entered| entered.borrow_mut().replace(Arc::clone(self)));
+        let _restore = Restore(prior);
+        f()
+    }
+}
+
+fn entered_schedulers() -> Option<Arc<Schedulers>> {
+    ENTERED_SCHEDULERS.with(|entered| entered.borrow().clone())
+}
+
+fn schedule_runnable(runnable: Runnable, high_pri: bool, schedulers: &Option<Arc<Schedulers>>) {
+    if let Some(schedulers) = schedulers {
+        let func = if high_pri {
+            &schedulers.main
+        } else {
+            &schedulers.low_pri
+        };
+        return func(runnable);
+    }
     let func = if high_pri {
         ON_MAIN_THREAD.lock()
     } else {
@@ -68,8 +119,11 @@ where
     std::thread::spawn(move || {
         // Run the thread
         let res = f();
-        // Pass the result back
-        tx.send(res).unwrap();
+        // Pass the result back.  If the task was dropped, nothing
+        // is waiting for the result and it is dropped here instead.
+        if tx.send(res).is_err() {
+            return;
+        }
         // If someone polled the thread before we got here,
         // they will have populated the waker; extract it
         // and wake up the scheduler so that it will poll
@@ -127,7 +181,10 @@ where
     if let Some(executor) = get_scoped() {
         return executor.spawn(future);
     }
-    let (runnable, task) = async_task::spawn(future, |runnable| schedule_runnable(runnable, true));
+    let schedulers = entered_schedulers();
+    let (runnable, task) = async_task::spawn(future, move |runnable| {
+        schedule_runnable(runnable, true, &schedulers)
+    });
     runnable.schedule();
     task
 }
@@ -146,7 +203,10 @@ where
     if let Some(executor) = get_scoped() {
         return executor.spawn(future);
     }
-    let (runnable, task) = async_task::spawn(future, |runnable| schedule_runnable(runnable, false));
+    let schedulers = entered_schedulers();
+    let (runnable, task) = async_task::spawn(future, move |runnable| {
+        schedule_runnable(runnable, false, &schedulers)
+    });
     runnable.schedule();
     task
 }
@@ -157,8 +217,10 @@ where
     F: Future<Output = R> + 'static,
     R: 'static,
 {
-    let (runnable, task) =
-        async_task::spawn_local(future, |runnable| schedule_runnable(runnable, true));
+    let schedulers = entered_schedulers();
+    let (runnable, task) = async_task::spawn_local(future, move |runnable| {
+        schedule_runnable(runnable, true, &schedulers)
+    });
     runnable.schedule();
     task
 }
@@ -170,8 +232,10 @@ where
     F: Future<Output = R> + 'static,
     R: 'static,
 {
-    let (runnable, task) =
-        async_task::spawn_local(future, |runnable| schedule_runnable(runnable, false));
+    let schedulers = entered_schedulers();
+    let (runnable, task) = async_task::spawn_local(future, move |runnable| {
+        schedule_runnable(runnable, false, &schedulers)
+    });
     runnable.schedule();
     task
 }
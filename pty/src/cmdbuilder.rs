@@ -146,6 +146,24 @@ fn get_base_env() -> BTreeMap<OsString, EnvEntry> {
     env
 }
 
+/// The shells that `CommandBuilder::set_login_shell` knows to start as
+/// login shells by passing `-l`
+const LOGIN_SHELLS: &[&str] = &["bash", "dash", "fish", "ksh", "mksh", "nu", "sh", "zsh"];
+
+/// Returns true if `program` runs one of the shells that accept `-l`,
+/// judging by its basename, so that a path such as
+/// `/opt/homebrew/bin/fish` or a login style `-zsh` are recognized
+pub fn is_shell<S: AsRef<OsStr>>(program: S) -> bool {
+    let program = program.as_ref().to_string_lossy();
+    let basename = program
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or(&program)
+        .trim_start_matches('-');
+    let basename = basename.strip_suffix(".exe").unwrap_or(basename);
+    LOGIN_SHELLS.contains(&basename)
+}
+
 /// `CommandBuilder` is used to prepare a command to be spawned into a pty.
 /// The interface is intentionally similar to that of `std::process::Command`.
 #[derive(Clone, Debug, PartialEq)]
@@ -156,6 +174,17 @@ pub struct CommandBuilder {
     cwd: Option<OsString>,
     #[cfg(unix)]
     pub(crate) umask: Option<libc::mode_t>,
+    /// A name for the command that is shown to the user in place of
+    /// its command line.  It has no effect on the spawned process.
+    label: Option<String>,
+    /// The name of the `exit_behavior` that applies to the pane that
+    /// runs the command, in place of that of its domain.  It is
+    /// interpreted by the mux and has no effect on the spawned process.
+    exit_behavior: Option<String>,
+    /// Whether the shell that is run by a builder made with
+    /// `new_default_prog` is a login shell.  None leaves it to the
+    /// platform, which on unix means that it is one.
+    login_shell: Option<bool>,
 }
 
 impl CommandBuilder {
@@ -168,6 +197,9 @@ impl CommandBuilder {
             cwd: None,
             #[cfg(unix)]
             umask: None,
+            label: None,
+            exit_behavior: None,
+            login_shell: None,
         }
     }
 
@@ -179,6 +211,9 @@ impl CommandBuilder {
             cwd: None,
             #[cfg(unix)]
             umask: None,
+            label: None,
+            exit_behavior: None,
+            login_shell: None,
         }
     }
 
@@ -191,6 +226,9 @@ impl CommandBuilder {
             cwd: None,
             #[cfg(unix)]
             umask: None,
+            label: None,
+            exit_behavior: None,
+            login_shell: None,
         }
     }
 
@@ -257,7 +295,9 @@ impl CommandBuilder {
         self.envs.clear();
     }
 
-    fn get_env<K>(&self, key: K) -> Option<&OsStr>
+    /// Returns the value of an environment variable, as it will be
+    /// set for the command
+    pub fn get_env<K>(&self, key: K) -> Option<&OsStr>
     where
         K: AsRef<OsStr>,
     {
@@ -286,6 +326,76 @@ impl CommandBuilder {
         self.cwd.as_ref()
     }
 
+    /// Sets a name for the command that is shown to the user in place
+    /// of its command line
+    pub fn label<S: Into<String>>(&mut self, label: S) {
+        self.label = Some(label.into());
+    }
+
+    pub fn clear_label(&mut self) {
+        self.label.take();
+    }
+
+    pub fn get_label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Sets the name of the `exit_behavior` for the pane that runs
+    /// the command
+    pub fn exit_behavior<S: Into<String>>(&mut self, behavior: S) {
+        self.exit_behavior = Some(behavior.into());
+    }
+
+    pub fn get_exit_behavior(&self) -> Option<&str> {
+        self.exit_behavior.as_deref()
+    }
+
+    /// Makes the command run as a login shell, or ensures that it
+    /// doesn't.  The default program is started with the `-` prefixed
+    /// argv[0] that shells take to mean a login shell, or without it.
+    /// An explicit command that runs a shell, as determined by
+    /// `is_shell`, is given `-l` if it doesn't already have it, but
+    /// is otherwise left as it is; other commands are never changed.
+    pub fn set_login_shell(&mut self, login: bool) {
+        if self.is_default_prog() {
+            self.login_shell = Some(login);
+        } else if login
+            && is_shell(&self.args[0])
+            && !self.args[1..]
+                .iter()
+                .any(|arg| arg == "-l" || arg == "--login")
+        {
+            self.args.insert(1, "-l".into());
+        }
+    }
+
+    pub fn get_login_shell(&self) -> Option<bool> {
+        self.login_shell
+    }
+
+    /// Describes where the program will be searched for, and whether
+    /// the requested cwd will be substituted, to help explain why a
+    /// program could not be found
+    pub(crate) fn describe_search_environment(&self) -> String {
+        let mut description = format!(
+            "searched PATH={}",
+            self.get_env("PATH")
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "(unset)".to_string())
+        );
+        if let Some(cwd) = &self.cwd {
+            let cwd = std::path::Path::new(cwd);
+            if !cwd.is_dir() {
+                description.push_str(&format!(
+                    "; the requested cwd {} is not a directory, \
+                     so the home directory was used instead",
+                    cwd.display()
+                ));
+            }
+        }
+        description
+    }
+
     /// Iterate over the configured environment. Only includes environment
     /// variables set by the caller via `env`, not variables set in the base
     /// environment.
@@ -397,13 +507,17 @@ impl CommandBuilder {
 
             let mut cmd = std::process::Command::new(&shell);
 
-            // Run the shell as a login shell by prefixing the shell's
-            // basename with `-` and setting that as argv0
-            let basename = shell.rsplit('/').next().unwrap_or(&shell);
-            cmd.arg0(&format!("-{}", basename));
+            if self.login_shell.unwrap_or(true) {
+                // Run the shell as a login shell by prefixing the shell's
+                // basename with `-` and setting that as argv0
+                let basename = shell.rsplit('/').next().unwrap_or(&shell);
+                cmd.arg0(&format!("-{}", basename));
+            }
             cmd
         } else {
-            let resolved = self.search_path(&self.args[0], dir)?;
+            let resolved = self.search_path(&self.args[0], dir).map_err(|err| {
+                anyhow::anyhow!("{:#}; {}", err, self.describe_search_environment())
+            })?;
             let mut cmd = std::process::Command::new(&resolved);
             cmd.arg0(&self.args[0]);
             cmd.args(&self.args[1..]);
@@ -641,6 +755,61 @@ impl CommandBuilder {
 mod tests {
     use super::*;
 
+    #[test]
+    fn detect_shells() {
+        for shell in &[
+            "bash",
+            "/bin/bash",
+            "/usr/local/bin/zsh",
+            "-zsh",
+            "/opt/homebrew/bin/fish",
+            "/home/user/.cargo/bin/nu",
+            "C:\\Program Files\\nu\\bin\\nu.exe",
+        ] {
+            assert!(is_shell(shell), "{} is a shell", shell);
+        }
+        for program in &["vim", "/usr/bin/env", "/bin/bashful", "nushell-ls", ""] {
+            assert!(!is_shell(program), "{} is not a shell", program);
+        }
+    }
+
+    #[test]
+    fn set_login_shell() {
+        let mut cmd = CommandBuilder::new_default_prog();
+        assert_eq!(cmd.get_login_shell(), None);
+        cmd.set_login_shell(false);
+        assert_eq!(cmd.get_login_shell(), Some(false));
+        assert!(cmd.is_default_prog());
+
+        let mut cmd = CommandBuilder::new("/usr/bin/fish");
+        cmd.set_login_shell(true);
+        cmd.set_login_shell(true);
+        assert_eq!(cmd.get_argv(), &["/usr/bin/fish", "-l"]);
+
+        let mut cmd = CommandBuilder::from_argv(vec!["zsh".into(), "--login".into()]);
+        cmd.set_login_shell(true);
+        assert_eq!(cmd.get_argv(), &["zsh", "--login"]);
+
+        let mut cmd = CommandBuilder::new("htop");
+        cmd.set_login_shell(true);
+        assert_eq!(cmd.get_argv(), &["htop"]);
+    }
+
+    #[test]
+    fn describe_search_environment() {
+        let mut cmd = CommandBuilder::new("dummy");
+        cmd.env("PATH", "/wezterm-no-such-dir");
+        assert_eq!(
+            cmd.describe_search_environment(),
+            "searched PATH=/wezterm-no-such-dir"
+        );
+
+        cmd.cwd("/wezterm-no-such-dir");
+        assert!(cmd
+            .describe_search_environment()
+            .ends_with("the home directory was used instead"));
+    }
+
     #[test]
     fn test_env() {
         let mut cmd = CommandBuilder::new("dummy");
@@ -109,6 +109,13 @@ pub trait MasterPty: std::io::Write {
     /// itself, but allows splitting it off into a separate object.
     fn try_clone_writer(&self) -> Result<Box<dyn std::io::Write + Send>, Error>;
 
+    /// Returns the number of bytes of output from the slave that are
+    /// waiting to be read, if that can be determined for this type of tty.
+    /// A reader that remains blocked while output is pending has stalled.
+    fn pending_output(&self) -> Option<usize> {
+        None
+    }
+
     /// If applicable to the type of the tty, return the local process id
     /// of the process group or session leader
     #[cfg(unix)]
@@ -5,12 +5,17 @@
 //! `openpty` method.
 //! On most (all?) systems, attempting to open multiple instances of
 //! the same serial port will fail.
+//! If reconnection is enabled, the port is re-opened when it reappears
+//! after having gone away, which is useful for USB serial adapters that
+//! re-enumerate when the device on the other end is reset.
 use crate::{
     Child, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize, PtySystem,
     SlavePty,
 };
-use anyhow::{ensure, Context};
+use anyhow::{anyhow, bail, ensure, Context};
 use filedescriptor::FileDescriptor;
+#[cfg(feature = "serde_support")]
+use serde_derive::*;
 use serial::{
     BaudRate, CharSize, FlowControl, Parity, PortSettings, SerialPort, StopBits, SystemPort,
 };
@@ -19,7 +24,71 @@ use std::io::{Read, Result as IoResult, Write};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-type Handle = Arc<Mutex<SystemPort>>;
+/// How often to check whether a disconnected device has reappeared
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Port settings that can be changed while the port is open.
+/// Fields that are None are left unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct SerialParams {
+    pub baud: Option<usize>,
+    /// The number of data bits; 5, 6, 7 or 8
+    pub char_size: Option<u8>,
+    /// One of "none", "odd" or "even"
+    pub parity: Option<String>,
+    /// 1 or 2
+    pub stop_bits: Option<u8>,
+    /// One of "none", "software" or "hardware"
+    pub flow_control: Option<String>,
+}
+
+impl SerialParams {
+    /// Apply these params to `settings`, returning an error if any
+    /// of them are invalid.  `settings` is not modified in that case.
+    pub fn apply_to(&self, settings: &PortSettings) -> anyhow::Result<PortSettings> {
+        let mut settings = *settings;
+        if let Some(baud) = self.baud {
+            settings.baud_rate = BaudRate::from_speed(baud);
+        }
+        if let Some(char_size) = self.char_size {
+            settings.char_size = match char_size {
+                5 => CharSize::Bits5,
+                6 => CharSize::Bits6,
+                7 => CharSize::Bits7,
+                8 => CharSize::Bits8,
+                _ => bail!("invalid char_size {}; expected 5, 6, 7 or 8", char_size),
+            };
+        }
+        if let Some(parity) = &self.parity {
+            settings.parity = match parity.to_lowercase().as_str() {
+                "none" => Parity::ParityNone,
+                "odd" => Parity::ParityOdd,
+                "even" => Parity::ParityEven,
+                _ => bail!("invalid parity {:?}; expected none, odd or even", parity),
+            };
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            settings.stop_bits = match stop_bits {
+                1 => StopBits::Stop1,
+                2 => StopBits::Stop2,
+                _ => bail!("invalid stop_bits {}; expected 1 or 2", stop_bits),
+            };
+        }
+        if let Some(flow_control) = &self.flow_control {
+            settings.flow_control = match flow_control.to_lowercase().as_str() {
+                "none" => FlowControl::FlowNone,
+                "software" => FlowControl::FlowSoftware,
+                "hardware" => FlowControl::FlowHardware,
+                _ => bail!(
+                    "invalid flow_control {:?}; expected none, software or hardware",
+                    flow_control
+                ),
+            };
+        }
+        Ok(settings)
+    }
+}
 
 pub struct SerialTty {
     port: OsString,
@@ -28,6 +97,8 @@ pub struct SerialTty {
     parity: Parity,
     stop_bits: StopBits,
     flow_control: FlowControl,
+    reconnect_banner: Option<String>,
+    connection: Mutex<Option<Arc<Connection>>>,
 }
 
 impl SerialTty {
@@ -39,7 +110,35 @@ impl SerialTty {
             parity: Parity::ParityNone,
             stop_bits: StopBits::Stop1,
             flow_control: FlowControl::FlowSoftware,
+            reconnect_banner: None,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Enable re-opening the port when it reappears after having
+    /// gone away.  `banner` is output to the terminal each time
+    /// that a reconnection happens.
+    pub fn enable_reconnect(&mut self, banner: String) {
+        self.reconnect_banner.replace(banner);
+    }
+
+    /// Change the settings of the open port
+    pub fn set_params(&self, params: &SerialParams) -> anyhow::Result<()> {
+        let connection = self
+            .connection
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("serial port {:?} is not open", self.port))?;
+        let mut state = connection.state.lock().unwrap();
+        let settings = params.apply_to(&state.settings)?;
+        if let Some(port) = state.port.as_mut() {
+            port.configure(&settings)?;
         }
+        log::debug!("serial settings: {:#?}", settings);
+        // If we're disconnected, these will be applied on reconnect
+        state.settings = settings;
+        Ok(())
     }
 
     pub fn set_baud_rate(&mut self, baud: BaudRate) {
@@ -63,11 +162,25 @@ impl SerialTty {
     }
 }
 
+fn open_port(name: &OsStr, settings: &PortSettings) -> anyhow::Result<SystemPort> {
+    let mut port =
+        serial::open(name).with_context(|| format!("openpty on serial port {:?}", name))?;
+
+    log::debug!("serial settings: {:#?}", settings);
+    port.configure(settings)?;
+
+    // The timeout needs to be rather short because, at least on Windows,
+    // a read with a long timeout will block a concurrent write from
+    // happening.  In wezterm we tend to have a thread looping on read
+    // while writes happen occasionally from the gui thread, and if we
+    // make this timeout too long we can block the gui thread.
+    port.set_timeout(Duration::from_millis(50))?;
+
+    Ok(port)
+}
+
 impl PtySystem for SerialTty {
     fn openpty(&self, _size: PtySize) -> anyhow::Result<PtyPair> {
-        let mut port = serial::open(&self.port)
-            .with_context(|| format!("openpty on serial port {:?}", self.port))?;
-
         let settings = PortSettings {
             baud_rate: self.baud,
             char_size: self.char_size,
@@ -75,27 +188,96 @@ impl PtySystem for SerialTty {
             stop_bits: self.stop_bits,
             flow_control: self.flow_control,
         };
-        log::debug!("serial settings: {:#?}", settings);
-        port.configure(&settings)?;
-
-        // The timeout needs to be rather short because, at least on Windows,
-        // a read with a long timeout will block a concurrent write from
-        // happening.  In wezterm we tend to have a thread looping on read
-        // while writes happen occasionally from the gui thread, and if we
-        // make this timeout too long we can block the gui thread.
-        port.set_timeout(Duration::from_millis(50))?;
-
-        let port: Handle = Arc::new(Mutex::new(port));
+        let port = open_port(&self.port, &settings)?;
+
+        let connection = Arc::new(Connection {
+            name: self.port.clone(),
+            reconnect_banner: self.reconnect_banner.clone(),
+            state: Mutex::new(PortState {
+                port: Some(port),
+                settings,
+                generation: 0,
+            }),
+        });
+        self.connection
+            .lock()
+            .unwrap()
+            .replace(Arc::clone(&connection));
 
         Ok(PtyPair {
             slave: Box::new(Slave {
-                port: Arc::clone(&port),
+                port: Arc::clone(&connection),
             }),
-            master: Box::new(Master { port }),
+            master: Box::new(Master { port: connection }),
         })
     }
 }
 
+struct PortState {
+    /// None while the device is disconnected
+    port: Option<SystemPort>,
+    settings: PortSettings,
+    /// Incremented each time the port is re-opened
+    generation: usize,
+}
+
+struct Connection {
+    name: OsString,
+    reconnect_banner: Option<String>,
+    state: Mutex<PortState>,
+}
+
+type Handle = Arc<Connection>;
+
+impl Connection {
+    fn can_reconnect(&self) -> bool {
+        self.reconnect_banner.is_some()
+    }
+
+    /// Called when the port of the specified generation has failed.
+    /// Returns the text to output to the terminal to explain that.
+    fn disconnected(&self, generation: usize) -> String {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.port.take();
+        }
+        format!(
+            "\r\n\x1b[1;7m disconnected, waiting for device {} \x1b[0m\r\n",
+            self.name.to_string_lossy()
+        )
+    }
+
+    /// Blocks until the device can be re-opened.
+    /// Returns the reconnection banner.
+    fn wait_for_device(&self) -> String {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.port.is_some() {
+                    // Someone else already re-opened it
+                    break;
+                }
+                match open_port(&self.name, &state.settings) {
+                    Ok(port) => {
+                        log::info!("reconnected to serial port {:?}", self.name);
+                        state.port.replace(port);
+                        state.generation += 1;
+                        break;
+                    }
+                    Err(err) => {
+                        log::trace!("waiting for serial port {:?}: {:#}", self.name, err);
+                    }
+                }
+            }
+            std::thread::sleep(RECONNECT_POLL_INTERVAL);
+        }
+        format!(
+            "\r\n{}\r\n",
+            self.reconnect_banner.as_deref().unwrap_or_default()
+        )
+    }
+}
+
 struct Slave {
     port: Handle,
 }
@@ -140,13 +322,25 @@ impl Child for SerialChild {
         // the CD value itself and just look for an error state.
         // We could potentially also decide to call CD==false the
         // same thing as the "child" completing.
+        // If reconnection is enabled, a device that goes away is
+        // treated as a temporary condition rather than termination.
         loop {
             std::thread::sleep(Duration::from_secs(5));
 
-            let mut port = self.port.lock().unwrap();
-            if let Err(err) = port.read_cd() {
-                log::error!("Error reading carrier detect: {:#}", err);
-                return Ok(ExitStatus::with_exit_code(1));
+            let mut state = self.port.state.lock().unwrap();
+            match state.port.as_mut() {
+                Some(port) => {
+                    if let Err(err) = port.read_cd() {
+                        log::error!("Error reading carrier detect: {:#}", err);
+                        if !self.port.can_reconnect() {
+                            return Ok(ExitStatus::with_exit_code(1));
+                        }
+                    }
+                }
+                None if !self.port.can_reconnect() => {
+                    return Ok(ExitStatus::with_exit_code(1));
+                }
+                None => {}
             }
         }
     }
@@ -188,13 +382,26 @@ struct Master {
     port: Handle,
 }
 
+fn not_connected() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "serial device is disconnected",
+    )
+}
+
 impl Write for Master {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        self.port.lock().unwrap().write(buf)
+        match self.port.state.lock().unwrap().port.as_mut() {
+            Some(port) => port.write(buf),
+            None => Err(not_connected()),
+        }
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.port.lock().unwrap().flush()
+        match self.port.state.lock().unwrap().port.as_mut() {
+            Some(port) => port.flush(),
+            None => Err(not_connected()),
+        }
     }
 }
 
@@ -210,11 +417,12 @@ impl MasterPty for Master {
     }
 
     fn try_clone_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
-        // We rely on the fact that SystemPort implements the traits
-        // that expose the underlying file descriptor, and that direct
-        // reads from that return the raw data that we want
-        let fd = FileDescriptor::dup(&*self.port.lock().unwrap())?;
-        Ok(Box::new(Reader { fd }))
+        Ok(Box::new(Reader {
+            port: Arc::clone(&self.port),
+            fd: None,
+            generation: 0,
+            pending: vec![],
+        }))
     }
 
     fn try_clone_writer(&self) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
@@ -230,12 +438,59 @@ impl MasterPty for Master {
 }
 
 struct Reader {
-    fd: FileDescriptor,
+    port: Handle,
+    /// The fd of the port that we are reading, along with the
+    /// generation of the port that it belongs to
+    fd: Option<FileDescriptor>,
+    generation: usize,
+    /// Text that we generated ourselves to explain a disconnection
+    /// or reconnection, which is yet to be returned to the caller
+    pending: Vec<u8>,
 }
 
-impl Read for Reader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        // On windows, this self.fd.read will block for up to the time we set
+impl Reader {
+    /// Ensure that we are reading from the currently open port.
+    /// Returns false if the port is disconnected.
+    fn update_fd(&mut self) -> Result<bool, std::io::Error> {
+        let state = self.port.state.lock().unwrap();
+        match state.port.as_ref() {
+            Some(port) => {
+                if self.fd.is_none() || self.generation != state.generation {
+                    // We rely on the fact that SystemPort implements the traits
+                    // that expose the underlying file descriptor, and that direct
+                    // reads from that return the raw data that we want
+                    let fd = FileDescriptor::dup(port)
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+                    self.fd.replace(fd);
+                    self.generation = state.generation;
+                }
+                Ok(true)
+            }
+            None => {
+                self.fd.take();
+                Ok(false)
+            }
+        }
+    }
+
+    /// Called when the port fails; if reconnection is possible,
+    /// arranges to explain the situation in the terminal and
+    /// returns Ok, otherwise returns `err`.
+    fn handle_failure(&mut self, err: std::io::Error) -> Result<(), std::io::Error> {
+        if !self.port.can_reconnect() {
+            return Err(err);
+        }
+        log::error!("serial port {:?} failed: {}", self.port.name, err);
+        self.fd.take();
+        self.pending
+            .extend_from_slice(self.port.disconnected(self.generation).as_bytes());
+        Ok(())
+    }
+
+    fn read_port(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let fd = self.fd.as_mut().expect("update_fd to have opened the fd");
+
+        // On windows, this fd.read will block for up to the time we set
         // as the timeout when we set up the port, but on unix it will
         // never block.
         loop {
@@ -246,14 +501,14 @@ impl Read for Reader {
                 // so we must explicitly poll for ourselves here to avoid a
                 // busy loop.
                 let mut poll_array = [pollfd {
-                    fd: self.fd.as_socket_descriptor(),
+                    fd: fd.as_socket_descriptor(),
                     events: POLLIN,
                     revents: 0,
                 }];
                 let _ = poll(&mut poll_array, None);
             }
 
-            match self.fd.read(buf) {
+            match fd.read(buf) {
                 Ok(0) => {
                     if cfg!(windows) {
                         // Read timeout with no data available yet;
@@ -279,3 +534,65 @@ impl Read for Reader {
         }
     }
 }
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = buf.len().min(self.pending.len());
+                buf[..len].copy_from_slice(&self.pending[..len]);
+                self.pending.drain(..len);
+                return Ok(len);
+            }
+
+            if !self.update_fd()? {
+                if !self.port.can_reconnect() {
+                    return Err(not_connected());
+                }
+                let banner = self.port.wait_for_device();
+                self.pending.extend_from_slice(banner.as_bytes());
+                continue;
+            }
+
+            match self.read_port(buf) {
+                Ok(size) => return Ok(size),
+                Err(err) => self.handle_failure(err)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_params() {
+        let settings = PortSettings {
+            baud_rate: BaudRate::Baud9600,
+            char_size: CharSize::Bits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::Stop1,
+            flow_control: FlowControl::FlowSoftware,
+        };
+
+        let params = SerialParams {
+            baud: Some(115200),
+            parity: Some("Even".to_string()),
+            flow_control: Some("none".to_string()),
+            ..Default::default()
+        };
+        let updated = params.apply_to(&settings).unwrap();
+        assert_eq!(updated.baud_rate, BaudRate::Baud115200);
+        assert_eq!(updated.char_size, CharSize::Bits8);
+        assert_eq!(updated.parity, Parity::ParityEven);
+        assert_eq!(updated.stop_bits, StopBits::Stop1);
+        assert_eq!(updated.flow_control, FlowControl::FlowNone);
+
+        let invalid = SerialParams {
+            stop_bits: Some(3),
+            ..Default::default()
+        };
+        assert!(invalid.apply_to(&settings).is_err());
+    }
+}
@@ -66,6 +66,23 @@ impl PtySystem for UnixPtySystem {
     }
 }
 
+/// Wraps the master end of a pty that was opened elsewhere, for example
+/// one that was passed to this process over a unix domain socket.
+/// Fails if `fd` is not the master end of a pty.
+pub fn master_pty_from_fd(fd: FileDescriptor) -> anyhow::Result<Box<dyn MasterPty + Send>> {
+    let raw = fd.as_raw_fd();
+    if unsafe { libc::isatty(raw) } != 1 {
+        bail!("fd {} is not a tty: {:?}", raw, io::Error::last_os_error());
+    }
+    // Only the master end of a pty has a slave name
+    if unsafe { libc::ptsname(raw) }.is_null() {
+        bail!("fd {} is not the master end of a pty", raw);
+    }
+    let master = UnixMasterPty { fd: PtyFd(fd) };
+    cloexec(master.fd.as_raw_fd())?;
+    Ok(Box::new(master))
+}
+
 struct PtyFd(pub FileDescriptor);
 impl std::ops::Deref for PtyFd {
     type Target = FileDescriptor;
@@ -164,6 +181,14 @@ impl PtyFd {
         Ok(())
     }
 
+    fn pending_output(&self) -> Option<usize> {
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.0.as_raw_fd(), libc::FIONREAD as _, &mut pending) } != 0 {
+            return None;
+        }
+        Some(pending as usize)
+    }
+
     fn get_size(&self) -> Result<PtySize, Error> {
         let mut size: winsize = unsafe { mem::zeroed() };
         if unsafe {
@@ -191,6 +216,7 @@ impl PtyFd {
         let configured_umask = builder.umask;
 
         let mut cmd = builder.as_command()?;
+        let program = cmd.get_program().to_owned();
 
         unsafe {
             cmd.stdin(self.as_stdio()?)
@@ -241,7 +267,20 @@ impl PtyFd {
                 })
         };
 
-        let mut child = cmd.spawn()?;
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // The program itself was found by as_command, so this
+                // is most likely a missing #! interpreter
+                bail!(
+                    "Unable to spawn {}: {}; {}",
+                    program.to_string_lossy(),
+                    err,
+                    builder.describe_search_environment()
+                );
+            }
+            Err(err) => return Err(err.into()),
+        };
 
         // Ensure that we close out the slave fds that Child retains;
         // they are not what we need (we need the master side to reference
@@ -315,6 +354,10 @@ impl MasterPty for UnixMasterPty {
         Ok(Box::new(UnixMasterPty { fd }))
     }
 
+    fn pending_output(&self) -> Option<usize> {
+        self.fd.pending_output()
+    }
+
     fn process_group_leader(&self) -> Option<libc::pid_t> {
         match unsafe { libc::tcgetpgrp(self.fd.0.as_raw_fd()) } {
             pid if pid > 0 => Some(pid),
@@ -1,12 +1,106 @@
 use crate::cmdbuilder::CommandBuilder;
-use crate::win::psuedocon::PsuedoCon;
+use crate::win::psuedocon::{self, PsuedoCon};
 use crate::{Child, MasterPty, PtyPair, PtySize, PtySystem, SlavePty};
 use anyhow::Error;
+use bitflags::bitflags;
 use filedescriptor::{FileDescriptor, Pipe};
 use std::io;
+use std::os::windows::io::AsRawHandle;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use winapi::shared::minwindef::DWORD;
+use winapi::um::namedpipeapi::PeekNamedPipe;
 use winapi::um::wincon::COORD;
 
+/// The first build of Windows 10 21H2; earlier builds ship a conpty
+/// that loses track of the screen contents when resized rapidly
+const FIRST_21H2_BUILD: u32 = 19044;
+
+static RESIZE_DEBOUNCE_MS: AtomicU64 = AtomicU64::new(50);
+
+/// Sets the window within which successive resizes of a pseudo console
+/// are coalesced.  A duration of zero disables debouncing for pseudo
+/// consoles that are created after this call; the window itself is
+/// re-read each time a pseudo console is resized.
+pub fn set_resize_debounce(duration: Duration) {
+    RESIZE_DEBOUNCE_MS.store(duration.as_millis() as u64, Ordering::Relaxed);
+}
+
+fn resize_debounce() -> Duration {
+    Duration::from_millis(RESIZE_DEBOUNCE_MS.load(Ordering::Relaxed))
+}
+
+bitflags! {
+    /// Workarounds for conpty bugs that are applied to a pseudo console
+    pub struct ConPtyQuirks: u8 {
+        /// Coalesce rapid resizes, always following up with a final
+        /// settle resize once they stop
+        const DEBOUNCE_RESIZE = 1;
+        /// Nudge the size after the settle resize so that conpty
+        /// repaints the screen, rather than leaving stale content
+        const REPAINT_AFTER_RESIZE = 2;
+        /// conpty from before Windows 10 21H2 can garble the screen or
+        /// get stuck in the alternate screen when resized; use a longer
+        /// debounce window and request a repaint after every resize
+        const LEGACY_CONPTY = 4;
+    }
+}
+
+impl ConPtyQuirks {
+    /// Determine the quirks that apply to the conpty implementation
+    /// in use on this system
+    pub fn detect() -> Self {
+        let mut quirks = Self::empty();
+        if !resize_debounce().is_zero() {
+            quirks |= Self::DEBOUNCE_RESIZE | Self::REPAINT_AFTER_RESIZE;
+        }
+        if !psuedocon::is_sideloaded() {
+            if let Some(build) = psuedocon::build_number() {
+                if build < FIRST_21H2_BUILD {
+                    quirks |= Self::LEGACY_CONPTY | Self::REPAINT_AFTER_RESIZE;
+                }
+            }
+        }
+        quirks
+    }
+
+    /// Returns the names of the quirks in this set, for diagnostic purposes
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (Self::DEBOUNCE_RESIZE, "DEBOUNCE_RESIZE"),
+            (Self::REPAINT_AFTER_RESIZE, "REPAINT_AFTER_RESIZE"),
+            (Self::LEGACY_CONPTY, "LEGACY_CONPTY"),
+        ]
+        .iter()
+        .filter(|(quirk, _)| self.contains(*quirk))
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    fn debounce_window(&self) -> Duration {
+        let window = resize_debounce();
+        if self.contains(Self::LEGACY_CONPTY) {
+            window * 2
+        } else {
+            window
+        }
+    }
+}
+
+/// Describes the conpty implementation in use, for diagnostic purposes
+pub fn describe_conpty() -> String {
+    format!(
+        "build={} sideloaded={} quirks={}",
+        psuedocon::build_number()
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| "unknown".to_string()),
+        psuedocon::is_sideloaded(),
+        ConPtyQuirks::detect().names().join("|")
+    )
+}
+
 #[derive(Default)]
 pub struct ConPtySystem {}
 
@@ -30,6 +124,8 @@ impl PtySystem for ConPtySystem {
                 readable: stdout.read,
                 writable: stdin.write,
                 size,
+                quirks: ConPtyQuirks::detect(),
+                settle_deadline: None,
             })),
         };
 
@@ -48,31 +144,99 @@ struct Inner {
     con: PsuedoCon,
     readable: FileDescriptor,
     writable: FileDescriptor,
+    /// The most recently requested size
     size: PtySize,
+    quirks: ConPtyQuirks,
+    /// When debouncing, the time at which the settle resize is due.
+    /// Some(_) while a settle thread is waiting to apply it.
+    settle_deadline: Option<Instant>,
 }
 
 impl Inner {
-    pub fn resize(
-        &mut self,
-        num_rows: u16,
-        num_cols: u16,
-        pixel_width: u16,
-        pixel_height: u16,
-    ) -> Result<(), Error> {
+    fn apply_size(&self, rows: u16, cols: u16) -> Result<(), Error> {
         self.con.resize(COORD {
-            X: num_cols as i16,
-            Y: num_rows as i16,
-        })?;
-        self.size = PtySize {
-            rows: num_rows,
-            cols: num_cols,
-            pixel_width,
-            pixel_height,
-        };
+            X: cols as i16,
+            Y: rows as i16,
+        })
+    }
+
+    /// Resize the console to the requested size, then ask it to repaint
+    /// by briefly making it one column wider.  conpty only repaints when
+    /// its size actually changes, and growing rather than shrinking
+    /// avoids truncating lines in its buffer.
+    fn apply_size_and_repaint(&self) -> Result<(), Error> {
+        let size = self.size;
+        self.apply_size(size.rows, size.cols)?;
+        if self.quirks.contains(ConPtyQuirks::REPAINT_AFTER_RESIZE) {
+            self.apply_size(size.rows, size.cols.saturating_add(1))?;
+            self.apply_size(size.rows, size.cols)?;
+        }
+        Ok(())
+    }
+
+    pub fn resize(&mut self, size: PtySize, inner: &Arc<Mutex<Inner>>) -> Result<(), Error> {
+        self.size = size;
+
+        if !self.quirks.contains(ConPtyQuirks::DEBOUNCE_RESIZE) {
+            return self.apply_size(size.rows, size.cols);
+        }
+
+        let deadline = Instant::now() + self.quirks.debounce_window();
+        if self.settle_deadline.replace(deadline).is_some() {
+            // A settle thread is already waiting; it will pick up
+            // this size when the window elapses
+            return Ok(());
+        }
+
+        // This is the leading edge of a (potential) series of resizes:
+        // apply it immediately so that a one-off resize isn't delayed
+        if self.quirks.contains(ConPtyQuirks::LEGACY_CONPTY) {
+            self.apply_size_and_repaint()?;
+        } else {
+            self.apply_size(size.rows, size.cols)?;
+        }
+
+        let inner = Arc::clone(inner);
+        if let Err(err) = std::thread::Builder::new()
+            .name("conpty-resize".to_string())
+            .spawn(move || settle_resize(inner))
+        {
+            // Don't leave subsequent resizes waiting for a thread
+            // that will never run
+            self.settle_deadline.take();
+            return Err(err.into());
+        }
         Ok(())
     }
 }
 
+/// Waits until no resize has been requested for the debounce window,
+/// then sends the final settle resize
+fn settle_resize(inner: Arc<Mutex<Inner>>) {
+    loop {
+        let deadline = match inner.lock().unwrap().settle_deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let now = Instant::now();
+        if now < deadline {
+            std::thread::sleep(deadline - now);
+            continue;
+        }
+
+        let mut inner = inner.lock().unwrap();
+        if inner.settle_deadline != Some(deadline) {
+            // Another resize arrived while we were acquiring the lock
+            continue;
+        }
+        inner.settle_deadline.take();
+        if let Err(err) = inner.apply_size_and_repaint() {
+            log::error!("settle resize of pseudo console failed: {:#}", err);
+        }
+        return;
+    }
+}
+
 #[derive(Clone)]
 pub struct ConPtyMasterPty {
     inner: Arc<Mutex<Inner>>,
@@ -85,7 +249,7 @@ pub struct ConPtySlavePty {
 impl MasterPty for ConPtyMasterPty {
     fn resize(&self, size: PtySize) -> anyhow::Result<()> {
         let mut inner = self.inner.lock().unwrap();
-        inner.resize(size.rows, size.cols, size.pixel_width, size.pixel_height)
+        inner.resize(size, &self.inner)
     }
 
     fn get_size(&self) -> Result<PtySize, Error> {
@@ -100,6 +264,26 @@ impl MasterPty for ConPtyMasterPty {
     fn try_clone_writer(&self) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
         Ok(Box::new(self.inner.lock().unwrap().writable.try_clone()?))
     }
+
+    fn pending_output(&self) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        let mut available: DWORD = 0;
+        let ok = unsafe {
+            PeekNamedPipe(
+                inner.readable.as_raw_handle() as _,
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                &mut available,
+                ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            None
+        } else {
+            Some(available as usize)
+        }
+    }
 }
 
 impl io::Write for ConPtyMasterPty {
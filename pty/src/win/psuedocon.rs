@@ -20,7 +20,7 @@ use winapi::um::winbase::{
     CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, STARTF_USESTDHANDLES, STARTUPINFOEXW,
 };
 use winapi::um::wincon::COORD;
-use winapi::um::winnt::HANDLE;
+use winapi::um::winnt::{HANDLE, OSVERSIONINFOW};
 
 pub type HPCON = HANDLE;
 
@@ -39,7 +39,11 @@ shared_library!(ConPtyFuncs,
     pub fn ClosePseudoConsole(hpc: HPCON),
 );
 
-fn load_conpty() -> ConPtyFuncs {
+shared_library!(NtDllFuncs,
+    pub fn RtlGetVersion(info: *mut OSVERSIONINFOW) -> i32,
+);
+
+fn load_conpty() -> (ConPtyFuncs, bool) {
     // If the kernel doesn't export these functions then their system is
     // too old and we cannot run.
     let kernel = ConPtyFuncs::open(Path::new("kernel32.dll")).expect(
@@ -50,14 +54,41 @@ fn load_conpty() -> ConPtyFuncs {
     // alongside the application.  We check for this after checking for kernel
     // support so that we don't try to proceed and do something crazy.
     if let Ok(sideloaded) = ConPtyFuncs::open(Path::new("conpty.dll")) {
-        sideloaded
+        (sideloaded, true)
+    } else {
+        (kernel, false)
+    }
+}
+
+fn windows_build_number() -> Option<u32> {
+    // GetVersionEx lies to applications that are not manifested for
+    // the running version of Windows, so ask ntdll directly
+    let ntdll = NtDllFuncs::open(Path::new("ntdll.dll")).ok()?;
+    let mut info: OSVERSIONINFOW = unsafe { mem::zeroed() };
+    info.dwOSVersionInfoSize = mem::size_of::<OSVERSIONINFOW>() as u32;
+    let status = unsafe { (ntdll.RtlGetVersion)(&mut info) };
+    if status == 0 {
+        Some(info.dwBuildNumber)
     } else {
-        kernel
+        None
     }
 }
 
 lazy_static! {
-    static ref CONPTY: ConPtyFuncs = load_conpty();
+    static ref CONPTY_AND_SIDELOADED: (ConPtyFuncs, bool) = load_conpty();
+    static ref CONPTY: &'static ConPtyFuncs = &CONPTY_AND_SIDELOADED.0;
+    static ref BUILD_NUMBER: Option<u32> = windows_build_number();
+}
+
+/// Returns true if we are using a conpty.dll deployed alongside
+/// the application rather than the one provided by the system
+pub fn is_sideloaded() -> bool {
+    CONPTY_AND_SIDELOADED.1
+}
+
+/// Returns the build number of the running version of Windows
+pub fn build_number() -> Option<u32> {
+    *BUILD_NUMBER
 }
 
 pub struct PsuedoCon {
@@ -148,12 +179,16 @@ impl PsuedoCon {
         };
         if res == 0 {
             let err = IoError::last_os_error();
-            let msg = format!(
+            let mut msg = format!(
                 "CreateProcessW `{:?}` in cwd `{:?}` failed: {}",
                 cmd_os,
                 cwd.as_ref().map(|c| OsString::from_wide(c)),
                 err
             );
+            if err.kind() == std::io::ErrorKind::NotFound {
+                msg.push_str("; ");
+                msg.push_str(&cmd.describe_search_environment());
+            }
             log::error!("{}", msg);
             bail!("{}", msg);
         }
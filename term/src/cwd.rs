@@ -0,0 +1,46 @@
+//! Validation of the working directories that applications report
+//! via OSC 7.  The directory ends up in the command lines of wrapper
+//! commands such as `wsl.exe --cd`, in log messages and prompts, and
+//! is sent to multiplexer clients, so a directory that contains
+//! control characters, either literally or percent-encoded, is not
+//! accepted.  Spaces and other printable characters, including
+//! non-ASCII ones, are fine.
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+/// The longest OSC 7 URL that is accepted.  This leaves room for a
+/// maximal path with every byte of it percent-encoded.
+pub const MAX_CWD_URL_LEN: usize = 16 * 1024;
+
+/// Parses the URL of an OSC 7 working directory, failing if it is
+/// too long, is not a URL, or contains control characters
+pub fn parse_cwd_url(s: &str) -> anyhow::Result<Url> {
+    if s.len() > MAX_CWD_URL_LEN {
+        anyhow::bail!(
+            "working directory URL is {} bytes long, the limit is {}",
+            s.len(),
+            MAX_CWD_URL_LEN
+        );
+    }
+    if s.chars().any(char::is_control) {
+        anyhow::bail!("working directory URL {:?} contains control characters", s);
+    }
+    let url = Url::parse(s)?;
+    if !is_safe_cwd_url(&url) {
+        anyhow::bail!(
+            "working directory URL {:?} contains encoded control characters",
+            s
+        );
+    }
+    Ok(url)
+}
+
+/// Returns true if neither the URL nor its percent-decoded path
+/// contain control characters
+pub fn is_safe_cwd_url(url: &Url) -> bool {
+    if url.as_str().chars().any(char::is_control) {
+        return false;
+    }
+    let path = percent_decode_str(url.path()).decode_utf8_lossy();
+    !path.chars().any(char::is_control)
+}
@@ -25,6 +25,8 @@ use termwiz::surface::SequenceNo;
 pub mod config;
 pub use config::TerminalConfiguration;
 
+pub mod cwd;
+
 pub mod input;
 pub use crate::input::*;
 
@@ -59,6 +59,12 @@ pub enum Alert {
     /// When something bumps the seqno in the terminal model and
     /// the terminal is not focused
     OutputSinceFocusLost,
+    /// The application changed some of its modes.
+    /// Changes made by the same chunk of output are coalesced.
+    ModesChanged(TerminalModes),
+    /// The shell marked the end of the input of a command with OSC 133,
+    /// and the command is about to run
+    CommandStarted,
 }
 
 pub trait AlertHandler {
@@ -151,6 +157,7 @@ impl Terminal {
     /// The output is parsed and applied to the terminal model.
     pub fn advance_bytes<B: AsRef<[u8]>>(&mut self, bytes: B) {
         self.state.increment_seqno();
+        let modes = self.state.get_modes();
         {
             let bytes = bytes.as_ref();
 
@@ -158,17 +165,20 @@ impl Terminal {
 
             self.parser.parse(bytes, |action| performer.perform(action));
         }
+        self.trigger_modes_changed_notif(modes);
         self.trigger_unseen_output_notif();
     }
 
     pub fn perform_actions(&mut self, actions: Vec<termwiz::escape::Action>) {
         self.state.increment_seqno();
+        let modes = self.state.get_modes();
         {
             let mut performer = Performer::new(&mut self.state);
             for action in actions {
                 performer.perform(action);
             }
         }
+        self.trigger_modes_changed_notif(modes);
         self.trigger_unseen_output_notif();
     }
 }
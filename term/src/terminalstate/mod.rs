@@ -22,6 +22,7 @@ use termwiz::input::KeyboardEncoding;
 use termwiz::surface::{CursorShape, CursorVisibility, SequenceNo};
 use url::Url;
 use wezterm_bidi::ParagraphDirectionHint;
+use wezterm_dynamic::{FromDynamic, ToDynamic};
 
 mod image;
 mod iterm;
@@ -52,13 +53,46 @@ pub(crate) enum CharSet {
     DecLineDrawing,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum MouseEncoding {
+#[cfg_attr(feature = "use_serde", derive(Deserialize, Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub enum MouseEncoding {
     X10,
     SGR,
     SgrPixels,
 }
 
+impl Default for MouseEncoding {
+    fn default() -> Self {
+        Self::X10
+    }
+}
+
+/// A snapshot of the modes that the application running in the
+/// terminal has set to change how input is encoded for it, and which
+/// events are reported to it
+#[cfg_attr(feature = "use_serde", derive(Deserialize, Serialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromDynamic, ToDynamic)]
+pub struct TerminalModes {
+    /// DECCKM
+    pub application_cursor_keys: bool,
+    /// DECKPAM, or DECNKM
+    pub application_keypad: bool,
+    /// Mode 2004
+    pub bracketed_paste: bool,
+    /// Mode 1000: report button presses
+    pub mouse_tracking: bool,
+    /// Mode 1002: also report motion while a button is held
+    pub button_event_mouse: bool,
+    /// Mode 1003: report all motion
+    pub any_event_mouse: bool,
+    /// How mouse reports are encoded; set by modes 1006 and 1016
+    pub mouse_encoding: MouseEncoding,
+    /// Mode 1004
+    pub focus_tracking: bool,
+    /// Whether the alternate screen is active
+    pub alt_screen: bool,
+}
+
 impl TabStop {
     fn new(screen_width: usize, tab_width: usize) -> Self {
         let mut tabs = Vec::with_capacity(screen_width);
@@ -700,6 +734,21 @@ impl TerminalState {
         self.screen.is_alt_screen_active()
     }
 
+    /// Returns the modes that the application has set
+    pub fn get_modes(&self) -> TerminalModes {
+        TerminalModes {
+            application_cursor_keys: self.application_cursor_keys,
+            application_keypad: self.application_keypad,
+            bracketed_paste: self.bracketed_paste,
+            mouse_tracking: self.mouse_tracking,
+            button_event_mouse: self.button_event_mouse,
+            any_event_mouse: self.any_event_mouse,
+            mouse_encoding: self.mouse_encoding,
+            focus_tracking: self.focus_tracking,
+            alt_screen: self.screen.is_alt_screen_active(),
+        }
+    }
+
     /// Returns true if the associated application has enabled
     /// bracketed paste mode, which can be helpful to the hosting
     /// GUI application to decide about fragmenting a large paste.
@@ -744,6 +793,15 @@ impl TerminalState {
         !self.focused && self.seqno > self.lost_focus_seqno
     }
 
+    pub(crate) fn trigger_modes_changed_notif(&mut self, before: TerminalModes) {
+        let modes = self.get_modes();
+        if modes != before {
+            if let Some(handler) = self.alert_handler.as_mut() {
+                handler.alert(Alert::ModesChanged(modes));
+            }
+        }
+    }
+
     pub(crate) fn trigger_unseen_output_notif(&mut self) {
         if self.has_unseen_output() {
             if let Some(handler) = self.alert_handler.as_mut() {
@@ -779,6 +837,18 @@ impl TerminalState {
         Ok(())
     }
 
+    /// Returns the size that was most recently passed to `resize`, or
+    /// that the terminal was created with
+    pub fn get_size(&self) -> TerminalSize {
+        TerminalSize {
+            rows: self.screen.physical_rows,
+            cols: self.screen.physical_cols,
+            pixel_width: self.pixel_width,
+            pixel_height: self.pixel_height,
+            dpi: self.dpi,
+        }
+    }
+
     /// Informs the terminal that the viewport of the window has resized to the
     /// specified dimensions.
     /// We need to resize both the primary and alt screens, adjusting
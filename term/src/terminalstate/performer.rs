@@ -20,7 +20,6 @@ use termwiz::escape::{
     Action, ControlCode, DeviceControlMode, Esc, EscCode, OperatingSystemCommand, CSI,
 };
 use termwiz::input::KeyboardEncoding;
-use url::Url;
 use wezterm_bidi::ParagraphDirectionHint;
 
 /// A helper struct for implementing `vtparse::VTActor` while compartmentalizing
@@ -787,6 +786,9 @@ impl<'a> Performer<'a> {
                 FinalTermSemanticPrompt::MarkEndOfInputAndStartOfOutput { .. },
             ) => {
                 self.pen.set_semantic_type(SemanticType::Output);
+                if let Some(handler) = self.alert_handler.as_mut() {
+                    handler.alert(Alert::CommandStarted);
+                }
             }
 
             OperatingSystemCommand::FinalTermSemanticPrompt(
@@ -826,7 +828,15 @@ impl<'a> Performer<'a> {
                 }
             }
             OperatingSystemCommand::CurrentWorkingDirectory(url) => {
-                self.current_dir = Url::parse(&url).ok();
+                match crate::cwd::parse_cwd_url(&url) {
+                    Ok(url) => self.current_dir = Some(url),
+                    Err(err) => {
+                        // Keep the previous directory rather than
+                        // letting a hostile sequence clear it
+                        log::warn!("Ignoring OSC 7: {:#}", err);
+                        return;
+                    }
+                }
                 if let Some(handler) = self.alert_handler.as_mut() {
                     handler.alert(Alert::CurrentWorkingDirectoryChanged);
                 }
@@ -1238,3 +1238,111 @@ fn test_hyperlinks() {
         Compare::TEXT | Compare::ATTRS,
     );
 }
+
+#[test]
+fn test_modes_changed_alert() {
+    struct Alerts(Arc<std::sync::Mutex<Vec<Alert>>>);
+    impl AlertHandler for Alerts {
+        fn alert(&mut self, alert: Alert) {
+            self.0.lock().unwrap().push(alert);
+        }
+    }
+
+    let mut term = TestTerm::new(3, 5, 0);
+    let alerts = Arc::new(std::sync::Mutex::new(vec![]));
+    term.set_notification_handler(Box::new(Alerts(Arc::clone(&alerts))));
+
+    term.print("hello");
+    assert_eq!(alerts.lock().unwrap().len(), 0);
+
+    // Both modes are changed by the same chunk, so are reported together
+    term.print("\x1b[?1049h\x1b[?2004h");
+    let modes = term.get_modes();
+    assert!(modes.alt_screen);
+    assert!(modes.bracketed_paste);
+    assert!(!modes.application_cursor_keys);
+    assert_eq!(
+        alerts.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![Alert::ModesChanged(modes)]
+    );
+
+    // Setting a mode that is already set isn't a change
+    term.set_mode("?2004", true);
+    assert_eq!(alerts.lock().unwrap().len(), 0);
+
+    term.set_mode("?1049", false);
+    assert!(!term.get_modes().alt_screen);
+    assert_eq!(
+        alerts.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![Alert::ModesChanged(term.get_modes())]
+    );
+}
+
+#[test]
+fn test_command_started_alert() {
+    struct Alerts(Arc<std::sync::Mutex<Vec<Alert>>>);
+    impl AlertHandler for Alerts {
+        fn alert(&mut self, alert: Alert) {
+            self.0.lock().unwrap().push(alert);
+        }
+    }
+
+    let mut term = TestTerm::new(3, 10, 0);
+    let alerts = Arc::new(std::sync::Mutex::new(vec![]));
+    term.set_notification_handler(Box::new(Alerts(Arc::clone(&alerts))));
+
+    term.print("\x1b]133;A\x1b\\> \x1b]133;B\x1b\\ls");
+    assert_eq!(alerts.lock().unwrap().len(), 0);
+    term.print("\r\n\x1b]133;C\x1b\\");
+    assert_eq!(
+        alerts.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![Alert::CommandStarted]
+    );
+}
+
+#[test]
+fn test_osc7_hostile_cwd() {
+    let mut term = TestTerm::new(3, 5, 0);
+    let cwd = |term: &TestTerm| term.get_current_dir().map(|url| url.to_string());
+
+    // Spaces and non-ASCII characters are fine
+    term.print("\x1b]7;file://host/tmp/my%20dir/caf%C3%A9\x1b\\");
+    assert_eq!(
+        cwd(&term).as_deref(),
+        Some("file://host/tmp/my%20dir/caf%C3%A9")
+    );
+    term.print("\x1b]7;file://host/tmp/ünïcode dir\x1b\\");
+    let accepted = cwd(&term);
+    assert_eq!(
+        accepted.as_deref(),
+        Some("file://host/tmp/%C3%BCn%C3%AFcode%20dir")
+    );
+
+    // Encoded newlines and escapes, a C1 control and a very long path
+    // are ignored, leaving the previous directory in place
+    for hostile in [
+        "file://host/tmp/%0Aevil".to_string(),
+        "file://host/tmp/a%1B]0;pwned%07".to_string(),
+        "file://host/tmp/%C2%9B31m".to_string(),
+        "file://host/tmp/\u{85}next".to_string(),
+        format!("file://host/{}", "a/".repeat(crate::cwd::MAX_CWD_URL_LEN)),
+    ] {
+        term.print(format!("\x1b]7;{}\x1b\\", hostile));
+        assert_eq!(cwd(&term), accepted);
+    }
+}
+
+#[test]
+fn test_parse_cwd_url() {
+    use crate::cwd::parse_cwd_url;
+    assert!(parse_cwd_url("file://host/tmp/a\nb").is_err());
+    assert!(parse_cwd_url("file://host/tmp/a\x1b]0;x\x07").is_err());
+    assert!(parse_cwd_url("file://host/tmp/a\tb").is_err());
+    assert!(parse_cwd_url("/not/a/url").is_err());
+    assert_eq!(
+        parse_cwd_url("file:///home/user/with space")
+            .unwrap()
+            .path(),
+        "/home/user/with%20space"
+    );
+}
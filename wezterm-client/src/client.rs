@@ -1,5 +1,6 @@
 use crate::domain::{ClientDomain, ClientDomainConfig};
 use crate::pane::ClientPane;
+use crate::proxy::{ProxyCommand, ProxyStderr};
 use crate::UnixStream;
 use anyhow::{anyhow, bail, Context};
 use async_ossl::AsyncSslStream;
@@ -10,11 +11,14 @@ use filedescriptor::FileDescriptor;
 use futures::FutureExt;
 use mux::client::ClientId;
 use mux::connui::ConnectionUI;
-use mux::domain::DomainId;
+use mux::domain::{DomainError, DomainId};
 use mux::pane::PaneId;
+use mux::reconnect::Backoff;
+use mux::share::ShareToken;
 use mux::ssh::ssh_connect_with_ui;
-use mux::Mux;
-use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use mux::{Mux, MuxNotification};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
 use openssl::x509::X509;
 use portable_pty::Child;
 use smol::channel::{bounded, unbounded, Receiver, Sender};
@@ -27,6 +31,7 @@ use std::net::TcpStream;
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use thiserror::Error;
@@ -52,6 +57,14 @@ pub struct Client {
     sender: Sender<ReaderMessage>,
     local_domain_id: Option<DomainId>,
     client_id: ClientId,
+    /// The recent stderr output of the proxy command, if the
+    /// connection uses one
+    proxy_stderr: ProxyStderr,
+    /// The image limits agreed with the server
+    image_limits: Arc<Mutex<ImageLimits>>,
+    /// The configuration of the TLS domain, if it authenticates
+    /// with a token
+    auth_config: Option<TlsDomainClient>,
     pub is_reconnectable: bool,
     pub is_local: bool,
 }
@@ -82,6 +95,15 @@ macro_rules! rpc {
             metrics::counter!("rpc.count", 1, "method" => stringify!($method_name));
             match result {
                 Ok(Pdu::$response_type(res)) => Ok(res),
+                Ok(Pdu::PermissionDeniedResponse(denied)) => Err(DomainError::PermissionDenied {
+                    reason: denied.reason,
+                }
+                .into()),
+                Ok(Pdu::DomainErrorResponse(err)) => Err(DomainError::RemoteError {
+                    code: err.code,
+                    message: err.reason,
+                }
+                .into()),
                 Ok(_) => bail!("unexpected response {:?}", result),
                 Err(err) => Err(err),
             }
@@ -101,6 +123,15 @@ macro_rules! rpc {
             metrics::counter!("rpc.count", 1, "method" => stringify!($method_name));
             match result {
                 Ok(Pdu::$response_type(res)) => Ok(res),
+                Ok(Pdu::PermissionDeniedResponse(denied)) => Err(DomainError::PermissionDenied {
+                    reason: denied.reason,
+                }
+                .into()),
+                Ok(Pdu::DomainErrorResponse(err)) => Err(DomainError::RemoteError {
+                    code: err.code,
+                    message: err.reason,
+                }
+                .into()),
                 Ok(_) => bail!("unexpected response {:?}", result),
                 Err(err) => Err(err),
             }
@@ -220,6 +251,128 @@ fn process_unilateral(
 
             return Ok(());
         }
+        Pdu::PaneResourceUsageUpdate(PaneResourceUsageUpdate { samples }) => {
+            let samples = samples.clone();
+            promise::spawn::spawn_into_main_thread(async move {
+                let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+                let client_domain = mux
+                    .get_domain(local_domain_id)
+                    .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+                let client_domain =
+                    client_domain
+                        .downcast_ref::<ClientDomain>()
+                        .ok_or_else(|| {
+                            anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
+                        })?;
+
+                let mut local_samples = vec![];
+                for (remote_pane_id, usage) in samples {
+                    let local_pane_id = match client_domain.remote_to_local_pane_id(remote_pane_id)
+                    {
+                        Some(id) => id,
+                        // The pane isn't attached to this client
+                        None => continue,
+                    };
+                    if let Some(pane) = mux.get_pane(local_pane_id) {
+                        if let Some(client_pane) = pane.downcast_ref::<ClientPane>() {
+                            client_pane.set_resource_usage(usage);
+                            local_samples.push((local_pane_id, usage));
+                        }
+                    }
+                }
+                if !local_samples.is_empty() {
+                    mux.notify(MuxNotification::PaneResourceUsage(local_samples));
+                }
+
+                anyhow::Result::<()>::Ok(())
+            })
+            .detach();
+
+            return Ok(());
+        }
+        Pdu::WindowCreatedInWorkspace(WindowCreatedInWorkspace { workspace, .. }) => {
+            let workspace = workspace.to_string();
+            promise::spawn::spawn_into_main_thread(async move {
+                promise::spawn::spawn(async move {
+                    let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+                    let client_domain = mux
+                        .get_domain(local_domain_id)
+                        .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+                    let client_domain =
+                        client_domain
+                            .downcast_ref::<ClientDomain>()
+                            .ok_or_else(|| {
+                                anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
+                            })?;
+                    // Mirror the window, so that it is there when the
+                    // user switches to its workspace
+                    client_domain.resync().await?;
+                    mux.notify(MuxNotification::WindowCreatedInWorkspace {
+                        domain_id: local_domain_id,
+                        workspace,
+                    });
+                    anyhow::Result::<()>::Ok(())
+                })
+                .detach();
+            })
+            .detach();
+
+            return Ok(());
+        }
+        Pdu::CloseConfirmationPending(CloseConfirmationPending { request }) => {
+            let request = request.clone();
+            promise::spawn::spawn_into_main_thread(async move {
+                let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+                let client_domain = mux
+                    .get_domain(local_domain_id)
+                    .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+                let client_domain =
+                    client_domain
+                        .downcast_ref::<ClientDomain>()
+                        .ok_or_else(|| {
+                            anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
+                        })?;
+                client_domain.mirror_close_confirmation(request);
+                anyhow::Result::<()>::Ok(())
+            })
+            .detach();
+
+            return Ok(());
+        }
+        Pdu::CloseConfirmationResolved(CloseConfirmationResolved { id, outcome }) => {
+            let id = *id;
+            let outcome = *outcome;
+            promise::spawn::spawn_into_main_thread(async move {
+                let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+                mux.remote_close_resolved(local_domain_id, id, outcome);
+                anyhow::Result::<()>::Ok(())
+            })
+            .detach();
+
+            return Ok(());
+        }
+        Pdu::PaneLinksChanged(PaneLinksChanged {}) => {
+            promise::spawn::spawn_into_main_thread(async move {
+                promise::spawn::spawn(async move {
+                    let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+                    let client_domain = mux
+                        .get_domain(local_domain_id)
+                        .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+                    let client_domain =
+                        client_domain
+                            .downcast_ref::<ClientDomain>()
+                            .ok_or_else(|| {
+                                anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
+                            })?;
+                    // The pane list carries the links
+                    client_domain.resync().await
+                })
+                .detach();
+            })
+            .detach();
+
+            return Ok(());
+        }
         _ => {}
     }
 
@@ -245,11 +398,12 @@ fn client_thread(
     local_domain_id: Option<DomainId>,
     rx: &mut Receiver<ReaderMessage>,
 ) -> anyhow::Result<()> {
-    block_on(client_thread_async(reconnectable, local_domain_id, rx))
+    let stream = reconnectable.take_stream().unwrap();
+    block_on(client_thread_async(stream, local_domain_id, rx))
 }
 
 async fn client_thread_async(
-    reconnectable: &mut Reconnectable,
+    mut stream: Box<dyn AsyncReadAndWrite>,
     local_domain_id: Option<DomainId>,
     rx: &mut Receiver<ReaderMessage>,
 ) -> anyhow::Result<()> {
@@ -277,8 +431,6 @@ async fn client_thread_async(
         map: HashMap::new(),
     };
 
-    let mut stream = reconnectable.take_stream().unwrap();
-
     loop {
         let rx_msg = rx.recv();
         let wait_for_read = stream
@@ -337,6 +489,15 @@ pub fn unix_connect_with_retry(
     target: &UnixTarget,
     just_spawned: bool,
     max_attempts: Option<u64>,
+) -> anyhow::Result<UnixStream> {
+    connect_with_retry(target, just_spawned, max_attempts, &ProxyStderr::default())
+}
+
+fn connect_with_retry(
+    target: &UnixTarget,
+    just_spawned: bool,
+    max_attempts: Option<u64>,
+    proxy_stderr: &ProxyStderr,
 ) -> anyhow::Result<UnixStream> {
     let mut error = None;
 
@@ -351,6 +512,18 @@ pub fn unix_connect_with_retry(
             std::thread::sleep(std::time::Duration::from_millis(iter * 10));
         }
         match target {
+            #[cfg(windows)]
+            UnixTarget::Socket(path) if config::is_pipe_name(path) => {
+                match mux::namedpipe::connect(path) {
+                    Ok(socket) => {
+                        use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+                        return Ok(unsafe {
+                            UnixStream::from_raw_socket(socket.into_raw_socket())
+                        });
+                    }
+                    Err(err) => error = Some(Err(err)),
+                }
+            }
             UnixTarget::Socket(path) => match UnixStream::connect(path) {
                 Ok(stream) => return Ok(stream),
                 Err(err) => {
@@ -358,57 +531,10 @@ pub fn unix_connect_with_retry(
                         Some(Err(err).with_context(|| format!("connecting to {}", path.display())))
                 }
             },
-            UnixTarget::Proxy(argv) => {
-                let mut cmd = std::process::Command::new(&argv[0]);
-                cmd.args(&argv[1..]);
-
-                let (a, b) = filedescriptor::socketpair()?;
-
-                cmd.stdin(b.as_stdio()?);
-                cmd.stdout(b.as_stdio()?);
-                cmd.stderr(std::process::Stdio::inherit());
-                let mut child = cmd
-                    .spawn()
-                    .with_context(|| format!("spawning proxy command {:?}", cmd))?;
-
-                error.take();
-
-                // Grace period to detect whether connection failed
-                for _ in 0..5 {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                    match child.try_wait() {
-                        Ok(Some(status)) => {
-                            error = Some(Err(anyhow!(
-                                "{:?} exited already with status {:?}",
-                                cmd,
-                                status
-                            )));
-                            continue;
-                        }
-                        Ok(None) => {
-                            error.take();
-                        }
-                        Err(err) => {
-                            error =
-                                Some(Err(err).context(format!("spawning proxy command {:?}", cmd)));
-                            continue;
-                        }
-                    }
-                }
-
-                if error.is_none() {
-                    #[cfg(unix)]
-                    unsafe {
-                        use std::os::unix::io::{FromRawFd, IntoRawFd};
-                        return Ok(UnixStream::from_raw_fd(a.into_raw_fd()));
-                    }
-                    #[cfg(windows)]
-                    unsafe {
-                        use std::os::windows::io::{FromRawSocket, IntoRawSocket};
-                        return Ok(UnixStream::from_raw_socket(a.into_raw_socket()));
-                    }
-                }
-            }
+            UnixTarget::Proxy(argv) => match ProxyCommand::new(argv.clone())?.spawn(proxy_stderr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => error = Some(Err(err)),
+            },
         }
     }
 
@@ -438,6 +564,7 @@ struct Reconnectable {
     config: ClientDomainConfig,
     stream: Option<Box<dyn AsyncReadAndWrite>>,
     tls_creds: Option<GetTlsCredsResponse>,
+    proxy_stderr: ProxyStderr,
 }
 
 struct SshStream {
@@ -486,6 +613,17 @@ impl Reconnectable {
             config,
             stream,
             tls_creds: None,
+            proxy_stderr: ProxyStderr::default(),
+        }
+    }
+
+    /// Returns the command whose stdio is used in place of a
+    /// direct connection, if any
+    fn proxy_command(&self) -> Option<&Vec<String>> {
+        match &self.config {
+            ClientDomainConfig::Unix(unix) => unix.proxy_command.as_ref(),
+            ClientDomainConfig::Tls(tls) => tls.proxy_command.as_ref(),
+            ClientDomainConfig::Ssh(_) => None,
         }
     }
 
@@ -515,9 +653,12 @@ impl Reconnectable {
         match &self.config {
             // It doesn't make sense to reconnect to a unix socket; we only
             // get disconnected it it dies, so respawning it would not preserve
-            // the set of tabs and we'd have confusing and inconsistent state
-            ClientDomainConfig::Unix(_) => false,
-            ClientDomainConfig::Tls(_) => true,
+            // the set of tabs and we'd have confusing and inconsistent state.
+            // A proxy command can go away while the server lives on, so
+            // we run it again.
+            ClientDomainConfig::Unix(unix) => unix.proxy_command.is_some(),
+            // A share token can only be redeemed once
+            ClientDomainConfig::Tls(tls) => tls.share_token.is_none(),
             // It *does* make sense to reconnect with an ssh session, but we
             // need to grow some smarts about whether the disconnect was because
             // we sent CTRL-D to close the last session, or whether it was a network
@@ -621,7 +762,7 @@ impl Reconnectable {
 
         let max_attempts = if no_auto_start { Some(1) } else { None };
 
-        let stream = match unix_connect_with_retry(&target, false, max_attempts) {
+        let stream = match connect_with_retry(&target, false, max_attempts, &self.proxy_stderr) {
             Ok(stream) => stream,
             Err(e) => {
                 if no_auto_start || unix_dom.no_serve_automatically || !initial {
@@ -673,7 +814,7 @@ impl Reconnectable {
                     }
                 });
 
-                unix_connect_with_retry(&target, true, None).with_context(|| {
+                connect_with_retry(&target, true, None, &self.proxy_stderr).with_context(|| {
                     format!("(after spawning server) failed to connect to {:?}", target)
                 })?
             }
@@ -693,8 +834,31 @@ impl Reconnectable {
         _initial: bool,
         ui: &mut ConnectionUI,
     ) -> anyhow::Result<()> {
+        if let Some(argv) = &tls_client.proxy_command {
+            // The command takes care of reaching the server, and is
+            // expected to run `wezterm cli proxy` on the other side,
+            // so there is no TLS session to set up
+            ui.output_str(&format!("Running proxy command {:?}\n", argv));
+            let stream = ProxyCommand::new(argv.clone())?.spawn(&self.proxy_stderr)?;
+            ui.output_str("Connected!\n");
+            stream.set_read_timeout(Some(tls_client.read_timeout))?;
+            stream.set_write_timeout(Some(tls_client.write_timeout))?;
+            let stream: Box<dyn AsyncReadAndWrite> = Box::new(Async::new(stream)?);
+            self.stream.replace(stream);
+            return Ok(());
+        }
+
         openssl::init();
 
+        if let Some(token) = &tls_client.share_token {
+            let token: ShareToken = token.parse()?;
+            let cloned_ui = ui.clone();
+            let stream =
+                cloned_ui.run_and_log_error(|| self.try_connect_share(&tls_client, &token, ui))?;
+            self.stream.replace(stream);
+            return Ok(());
+        }
+
         let remote_address = &tls_client.remote_address;
 
         let remote_host_name = remote_address.split(':').next().ok_or_else(|| {
@@ -916,20 +1080,88 @@ impl Reconnectable {
         ui.output_str("TLS Connected!\n");
         Ok(stream)
     }
+
+    /// Connects to the server that issued a share token, without a
+    /// client certificate.  The certificate of the server is trusted
+    /// only if its fingerprint is the one in the token.
+    fn try_connect_share(
+        &mut self,
+        tls_client: &TlsDomainClient,
+        token: &ShareToken,
+        ui: &mut ConnectionUI,
+    ) -> anyhow::Result<Box<dyn AsyncReadAndWrite>> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        // The certificate is issued by the private CA of the server,
+        // so it is checked against the token after the handshake
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+        let connector = connector.configure()?.verify_hostname(false);
+
+        let remote_address = &tls_client.remote_address;
+        let remote_host_name = remote_address
+            .rsplit_once(':')
+            .map_or(remote_address.as_str(), |(host, _port)| host);
+
+        ui.output_str(&format!("Connecting to {} using TLS\n", remote_address));
+        let stream = TcpStream::connect(remote_address)
+            .with_context(|| format!("connecting to {}", remote_address))?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(tls_client.write_timeout))?;
+        stream.set_read_timeout(Some(tls_client.read_timeout))?;
+
+        let stream = connector
+            .connect(remote_host_name, stream)
+            .with_context(|| format!("SslConnector for {}", remote_address))?;
+        let fingerprint: String = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| anyhow!("{} presented no certificate", remote_address))?
+            .digest(MessageDigest::sha256())?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if fingerprint != token.fingerprint {
+            bail!(
+                "the certificate of {} doesn't match the share token, \
+                 which may have been issued by another server",
+                remote_address
+            );
+        }
+        ui.output_str("TLS Connected!\n");
+        Ok(Box::new(Async::new(AsyncSslStream::new(stream))?))
+    }
+}
+
+/// Detaches the domain once the connection of its client has ended
+async fn detach_client(local_domain_id: DomainId, client_id: ClientId) -> anyhow::Result<()> {
+    if let Some(mux) = Mux::get() {
+        let client_domain = mux
+            .get_domain(local_domain_id)
+            .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+        let client_domain = client_domain
+            .downcast_ref::<ClientDomain>()
+            .ok_or_else(|| anyhow!("domain {} is not a ClientDomain instance", local_domain_id))?;
+        // This client may have been abandoned by an attach that
+        // was cancelled, or replaced by a later attach, in which
+        // case the domain no longer belongs to it
+        if client_domain.is_current_client(&client_id) {
+            client_domain.perform_detach();
+        }
+    }
+    Ok(())
 }
 
 impl Client {
     fn new(local_domain_id: Option<DomainId>, mut reconnectable: Reconnectable) -> Self {
         let is_reconnectable = reconnectable.reconnectable();
         let is_local = reconnectable.is_local();
+        let proxy_stderr = reconnectable.proxy_stderr.clone();
         let (sender, mut receiver) = unbounded();
         let client_id = ClientId::new();
+        let thread_client_id = client_id.clone();
 
         thread::spawn(move || {
-            const BASE_INTERVAL: Duration = Duration::from_secs(1);
-            const MAX_INTERVAL: Duration = Duration::from_secs(10);
-
-            let mut backoff = BASE_INTERVAL;
+            let mut backoff = Backoff::default();
             loop {
                 if let Err(e) = client_thread(&mut reconnectable, local_domain_id, &mut receiver) {
                     if !reconnectable.reconnectable() || local_domain_id.is_none() {
@@ -940,8 +1172,11 @@ impl Client {
                     let local_domain_id = local_domain_id.expect("checked above");
 
                     if let Some(ioerr) = e.root_cause().downcast_ref::<std::io::Error>() {
-                        if let std::io::ErrorKind::UnexpectedEof = ioerr.kind() {
-                            // Don't reconnect for a simple EOF
+                        // Don't reconnect for a simple EOF, unless it
+                        // came from a proxy command that exited
+                        if ioerr.kind() == std::io::ErrorKind::UnexpectedEof
+                            && reconnectable.proxy_command().is_none()
+                        {
                             log::error!("server closed connection ({})", e);
                             break;
                         }
@@ -955,17 +1190,18 @@ impl Client {
                     let mut ui = ConnectionUI::new();
                     ui.title("wezterm: Reconnecting...");
 
+                    let mut delay = backoff.next_delay();
                     loop {
                         ui.sleep_with_reason(
                             &format!("client disconnected {}; will reconnect", e),
-                            backoff,
+                            delay,
                         )
                         .ok();
                         let initial = false;
                         let no_auto_start = true; // Don't auto-start on a reconnect
                         match reconnectable.connect(initial, &mut ui, no_auto_start) {
                             Ok(_) => {
-                                backoff = BASE_INTERVAL;
+                                backoff.reset();
                                 log::error!("Reconnected!");
                                 promise::spawn::spawn_into_main_thread(async move {
                                     ClientDomain::reattach(local_domain_id, ui).await.ok();
@@ -974,10 +1210,10 @@ impl Client {
                                 break;
                             }
                             Err(err) => {
-                                backoff = (backoff + backoff).min(MAX_INTERVAL);
+                                delay = backoff.next_delay();
                                 ui.output_str(&format!(
                                     "problem reconnecting: {}; will reconnect in {:?}\n",
-                                    err, backoff
+                                    err, delay
                                 ));
                             }
                         }
@@ -988,24 +1224,10 @@ impl Client {
                 }
             }
 
-            async fn detach(local_domain_id: DomainId) -> anyhow::Result<()> {
-                if let Some(mux) = Mux::get() {
-                    let client_domain = mux
-                        .get_domain(local_domain_id)
-                        .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
-                    let client_domain =
-                        client_domain
-                            .downcast_ref::<ClientDomain>()
-                            .ok_or_else(|| {
-                                anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
-                            })?;
-                    client_domain.perform_detach();
-                }
-                Ok(())
-            }
             if let Some(domain_id) = local_domain_id {
+                let client_id = thread_client_id;
                 promise::spawn::spawn_into_main_thread(async move {
-                    detach(domain_id).await.ok();
+                    detach_client(domain_id, client_id).await.ok();
                 })
                 .detach();
             }
@@ -1014,12 +1236,55 @@ impl Client {
         Self {
             sender,
             local_domain_id,
+            proxy_stderr,
+            image_limits: Arc::new(Mutex::new(ImageLimits::from_config(&configuration()))),
+            auth_config: None,
             is_reconnectable,
             is_local,
             client_id,
         }
     }
 
+    /// Creates a client that talks to a server over `stream`, such as
+    /// a server that runs in the same process.  Rather than by a thread
+    /// of its own, the stream is serviced by a future on the main thread,
+    /// which lets tests drive both ends of the connection from one thread.
+    /// The client doesn't reconnect once the stream is closed.
+    pub fn new_in_process(
+        local_domain_id: Option<DomainId>,
+        stream: Box<dyn AsyncReadAndWrite>,
+    ) -> Self {
+        let (sender, mut receiver) = unbounded();
+        let client_id = ClientId::new();
+        let task_client_id = client_id.clone();
+
+        promise::spawn::spawn(async move {
+            if let Err(e) = client_thread_async(stream, local_domain_id, &mut receiver).await {
+                log::debug!("in-process client ended: {}", e);
+            }
+            if let Some(domain_id) = local_domain_id {
+                detach_client(domain_id, task_client_id).await.ok();
+            }
+        })
+        .detach();
+
+        Self {
+            sender,
+            local_domain_id,
+            proxy_stderr: ProxyStderr::default(),
+            image_limits: Arc::new(Mutex::new(ImageLimits::from_config(&configuration()))),
+            auth_config: None,
+            is_reconnectable: false,
+            is_local: true,
+            client_id,
+        }
+    }
+
+    /// Returns the image limits that apply to this connection
+    pub fn image_limits(&self) -> ImageLimits {
+        *self.image_limits.lock().unwrap()
+    }
+
     pub async fn verify_version_compat(
         &self,
         ui: &ConnectionUI,
@@ -1038,10 +1303,32 @@ impl Client {
                     info.version_string,
                     info.codec_vers
                 );
+                if let Some(tls) = self.auth_config.clone() {
+                    // The token command is run for each connection, so
+                    // that it can hand out a fresh token
+                    if let Some(token) = smol::unblock(move || tls.resolve_auth_token()).await? {
+                        ui.output_str("Authenticating\n");
+                        let role = self.authenticate(Authenticate { token }).await?.role;
+                        log::trace!("authenticated with role {:?}", role);
+                    }
+                }
                 self.set_client_id(SetClientId {
                     client_id: self.client_id.clone(),
                 })
                 .await?;
+                if self.local_domain_id.is_some() {
+                    // The GUI is attaching; what it spawns is at the
+                    // request of the user
+                    self.mark_session_interactive(MarkSessionInteractive {})
+                        .await?;
+                }
+                let limits = self
+                    .negotiate_image_limits(NegotiateImageLimits {
+                        limits: self.image_limits(),
+                    })
+                    .await?
+                    .limits;
+                *self.image_limits.lock().unwrap() = limits;
                 Ok(info)
             }
             Ok(info) => {
@@ -1079,7 +1366,11 @@ impl Client {
                         err
                     )
                 };
+                let msg = self.proxy_stderr.annotate(&msg);
                 ui.output_str(&msg);
+                if err.root_cause().is::<Timeout>() {
+                    return Err(DomainError::Timeout { reason: msg }.into());
+                }
                 bail!("{}", msg);
             }
         }
@@ -1090,10 +1381,26 @@ impl Client {
         self.local_domain_id
     }
 
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
     fn compute_unix_domain(
         prefer_mux: bool,
         class_name: &str,
     ) -> anyhow::Result<config::UnixDomain> {
+        // An explicitly configured control endpoint, as set up by
+        // `cli_listen`, takes precedence over everything else
+        if let Some(path) = std::env::var_os("WEZTERM_CONTROL_ENDPOINT") {
+            if !path.is_empty() {
+                return Ok(config::UnixDomain {
+                    socket_path: Some(path.into()),
+                    no_serve_automatically: true,
+                    ..Default::default()
+                });
+            }
+        }
+
         match std::env::var_os("WEZTERM_UNIX_SOCKET") {
             Some(path) if !path.is_empty() => Ok(config::UnixDomain {
                 socket_path: Some(path.into()),
@@ -1158,7 +1465,11 @@ impl Client {
             Reconnectable::new(ClientDomainConfig::Tls(tls_client.clone()), None);
         let no_auto_start = true;
         reconnectable.connect(true, ui, no_auto_start)?;
-        Ok(Self::new(Some(local_domain_id), reconnectable))
+        let mut client = Self::new(Some(local_domain_id), reconnectable);
+        if tls_client.auth_token.is_some() || tls_client.auth_token_command.is_some() {
+            client.auth_config.replace(tls_client.clone());
+        }
+        Ok(client)
     }
 
     pub fn new_ssh(
@@ -1211,9 +1522,105 @@ impl Client {
         SearchScrollbackResponse
     );
     rpc!(kill_pane, KillPane, UnitResponse);
+    rpc!(set_serial_params, SetSerialParams, UnitResponse);
+    rpc!(nudge_pane, NudgePane, UnitResponse);
+    rpc!(set_pane_output_paused, SetPaneOutputPaused, UnitResponse);
+    rpc!(
+        set_pane_palette_overrides,
+        SetPanePaletteOverrides,
+        UnitResponse
+    );
+    rpc!(cancel_attach, CancelAttach, CancelAttachResponse);
+    rpc!(scan_pane, ScanPane, ScanPaneResponse);
+    rpc!(link_panes, LinkPanes, UnitResponse);
+    rpc!(adopt_pty, AdoptPty, AdoptPtyResponse);
+    rpc!(clone_tab, CloneTab, SpawnResponse);
+    rpc!(
+        get_semantic_zones,
+        GetSemanticZones,
+        GetSemanticZonesResponse
+    );
+    rpc!(
+        get_semantic_zone_text,
+        GetSemanticZoneText,
+        GetSemanticZoneTextResponse
+    );
     rpc!(set_client_id, SetClientId, UnitResponse);
     rpc!(list_clients, GetClientList, GetClientListResponse);
+    rpc!(list_domains, ListDomains, ListDomainsResponse);
     rpc!(set_window_workspace, SetWindowWorkspace, UnitResponse);
     rpc!(set_focused_pane_id, SetFocusedPane, UnitResponse);
     rpc!(get_image_cell, GetImageCell, GetImageCellResponse);
+    rpc!(change_dir, ChangeDir, UnitResponse);
+    rpc!(
+        render_scrollback,
+        RenderScrollback,
+        RenderScrollbackResponse
+    );
+    rpc!(sync_panes, SyncPanes, SyncPanesResponse);
+    rpc!(request_pane_snapshot, RequestPaneSnapshot, UnitResponse);
+    rpc!(verify_pane, VerifyPane, VerifyPaneResponse);
+    rpc!(list_directory, ListDirectory, ListDirectoryResponse);
+    rpc!(add_bookmark, AddBookmark, AddBookmarkResponse);
+    rpc!(get_bookmarks, GetBookmarks, GetBookmarksResponse);
+    rpc!(
+        scroll_to_bookmark,
+        ScrollToBookmark,
+        ScrollToBookmarkResponse
+    );
+    rpc!(get_spawn_timings, GetSpawnTimings, GetSpawnTimingsResponse);
+    rpc!(set_client_workspace, SetClientWorkspace, UnitResponse);
+    rpc!(exec_command, ExecCommand, ExecCommandResponse);
+    rpc!(
+        add_output_watcher,
+        AddOutputWatcher,
+        AddOutputWatcherResponse
+    );
+    rpc!(remove_output_watcher, RemoveOutputWatcher, UnitResponse);
+    rpc!(
+        list_output_watchers,
+        ListOutputWatchers,
+        ListOutputWatchersResponse
+    );
+    rpc!(pick_panes, PickPanes, PickPanesResponse);
+    rpc!(add_marker, AddMarker, AddMarkerResponse);
+    rpc!(remove_marker, RemoveMarker, UnitResponse);
+    rpc!(share_tab, ShareTab, ShareTabResponse);
+    rpc!(list_shares, ListShares, ListSharesResponse);
+    rpc!(revoke_share, RevokeShare, UnitResponse);
+    rpc!(redeem_share, RedeemShare, RedeemShareResponse);
+    rpc!(get_recent_dirs, GetRecentDirs, GetRecentDirsResponse);
+    rpc!(preview_split, PreviewSplit, PreviewSplitResponse);
+    rpc!(
+        mark_session_interactive,
+        MarkSessionInteractive,
+        UnitResponse
+    );
+    rpc!(set_spawn_limit, SetSpawnLimit, UnitResponse);
+    rpc!(get_spawn_limits, GetSpawnLimits, GetSpawnLimitsResponse);
+    rpc!(authenticate, Authenticate, AuthenticateResponse);
+    rpc!(
+        get_foreground_process,
+        GetForegroundProcess,
+        GetForegroundProcessResponse
+    );
+    rpc!(
+        negotiate_image_limits,
+        NegotiateImageLimits,
+        NegotiateImageLimitsResponse
+    );
+    rpc!(set_pane_view_state, SetPaneViewState, UnitResponse);
+    rpc!(edit_scrollback, EditScrollback, SpawnResponse);
+    rpc!(search_panes, SearchPanes, SearchPanesResponse);
+    rpc!(confirm_close, ConfirmClose, ConfirmCloseResponse);
+    rpc!(
+        answer_close_confirmation,
+        AnswerCloseConfirmation,
+        UnitResponse
+    );
+    rpc!(
+        get_pane_view_states,
+        GetPaneViewStates,
+        GetPaneViewStatesResponse
+    );
 }
@@ -2,21 +2,39 @@ use crate::client::Client;
 use crate::pane::ClientPane;
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
-use codec::{ListPanesResponse, SpawnV2, SplitPane};
-use config::keyassignment::SpawnTabDomain;
-use config::{SshDomain, TlsDomainClient, UnixDomain};
+use codec::{
+    AnswerCloseConfirmation, ConfirmClose, ExecCommand, GetCodecVersion, GetPaneViewStates,
+    ListDirectory, ListPanesResponse, PreviewSplit, RedeemShare, SpawnV2, SplitPane, SyncPanes,
+};
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use config::{
+    ClosePolicy, DomainKind, ExitBehavior, LauncherHints, NotificationHandling, Palette, SshDomain,
+    TlsDomainClient, UnixDomain,
+};
+use mux::client::ClientId;
+use mux::closeconfirm::{CloseOutcome, CloseRequestId, CloseScope, PendingClose};
 use mux::connui::{ConnectionUI, ConnectionUIParams};
-use mux::domain::{alloc_domain_id, Domain, DomainId, DomainState, SplitSource};
-use mux::pane::{Pane, PaneId};
-use mux::tab::{SplitRequest, Tab, TabId};
+use mux::dirlist::DirListing;
+use mux::domain::{
+    alloc_domain_id, resolve_command_dir, CloseAction, Domain, DomainError, DomainId, DomainResult,
+    DomainState, MoveDenied, MoveDestination, PaletteEntry, SplitSource,
+};
+use mux::domainexec::{ExecOutput, ExecRequest};
+use mux::pane::{alloc_pane_id, Pane, PaneId};
+use mux::panelink::{LinkGroupId, LinkPolicy};
+use mux::share::ShareToken;
+use mux::spawntiming;
+use mux::tab::{SplitPreview, SplitRequest, Tab, TabId};
+use mux::viewstate::PaneViewState;
 use mux::window::WindowId;
 use mux::{Mux, MuxNotification};
 use portable_pty::CommandBuilder;
 use promise::spawn::spawn_into_new_thread;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use wezterm_term::TerminalSize;
 
 pub struct ClientInner {
@@ -28,9 +46,44 @@ pub struct ClientInner {
     remote_to_local_tab: Mutex<HashMap<TabId, TabId>>,
     remote_to_local_pane: Mutex<HashMap<PaneId, PaneId>>,
     pub focused_remote_pane_id: Mutex<Option<PaneId>>,
+    server_instance_id: Mutex<Option<String>>,
 }
 
 impl ClientInner {
+    /// Records the instance id reported by the server.
+    /// Returns true if it differs from a previously recorded id,
+    /// which means that the server has restarted and that the ids
+    /// we have cached refer to objects from its previous incarnation.
+    fn record_server_instance_id(&self, instance_id: &str) -> bool {
+        let mut current = self.server_instance_id.lock().unwrap();
+        let changed = current
+            .as_deref()
+            .map(|prior| prior != instance_id)
+            .unwrap_or(false);
+        current.replace(instance_id.to_string());
+        changed
+    }
+
+    /// Discard the local panes and id mappings that were associated
+    /// with a previous incarnation of the server, so that we don't
+    /// confuse them with objects that now have the same remote ids.
+    fn forget_previous_instance(&self) {
+        let mux = Mux::get().expect("to be called on main thread");
+        let local_panes: Vec<PaneId> = self
+            .remote_to_local_pane
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_remote, local)| local)
+            .collect();
+        for pane_id in local_panes {
+            mux.remove_pane(pane_id);
+        }
+        self.remote_to_local_tab.lock().unwrap().clear();
+        self.remote_to_local_window.lock().unwrap().clear();
+        self.focused_remote_pane_id.lock().unwrap().take();
+    }
+
     fn remote_to_local_window(&self, remote_window_id: WindowId) -> Option<WindowId> {
         let map = self.remote_to_local_window.lock().unwrap();
         map.get(&remote_window_id).cloned()
@@ -69,7 +122,7 @@ impl ClientInner {
 
         let mux = Mux::get().unwrap();
 
-        for pane in mux.iter_panes() {
+        for pane in mux.iter_panes_for_domain(self.local_domain_id) {
             if let Some(pane) = pane.downcast_ref::<ClientPane>() {
                 if pane.remote_pane_id() == remote_pane_id {
                     let local_pane_id = pane.pane_id();
@@ -110,6 +163,16 @@ impl ClientInner {
         None
     }
 
+    fn local_to_remote_tab_id(&self, local_tab_id: TabId) -> Option<TabId> {
+        let map = self.remote_to_local_tab.lock().unwrap();
+        for (remote, local) in map.iter() {
+            if *local == local_tab_id {
+                return Some(*remote);
+            }
+        }
+        None
+    }
+
     pub fn is_local(&self) -> bool {
         self.client.is_local
     }
@@ -131,6 +194,14 @@ impl ClientDomainConfig {
         }
     }
 
+    pub fn kind(&self) -> DomainKind {
+        match self {
+            ClientDomainConfig::Unix(_) => DomainKind::Unix,
+            ClientDomainConfig::Tls(_) => DomainKind::Tls,
+            ClientDomainConfig::Ssh(_) => DomainKind::Ssh,
+        }
+    }
+
     pub fn local_echo_threshold_ms(&self) -> Option<u64> {
         match self {
             ClientDomainConfig::Unix(unix) => unix.local_echo_threshold_ms,
@@ -153,6 +224,14 @@ impl ClientDomainConfig {
         }
     }
 
+    pub fn notifications(&self) -> Option<NotificationHandling> {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.notifications,
+            ClientDomainConfig::Tls(tls) => tls.notifications,
+            ClientDomainConfig::Ssh(ssh) => ssh.notifications,
+        }
+    }
+
     pub fn connect_automatically(&self) -> bool {
         match self {
             ClientDomainConfig::Unix(unix) => unix.connect_automatically,
@@ -160,6 +239,47 @@ impl ClientDomainConfig {
             ClientDomainConfig::Ssh(ssh) => ssh.connect_automatically,
         }
     }
+
+    pub fn launcher_hints(&self) -> LauncherHints {
+        match self {
+            ClientDomainConfig::Unix(_) | ClientDomainConfig::Tls(_) => LauncherHints::default(),
+            ClientDomainConfig::Ssh(ssh) => ssh.launcher,
+        }
+    }
+
+    pub fn palette_overrides(&self) -> Option<Palette> {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.palette_overrides.clone(),
+            ClientDomainConfig::Tls(tls) => tls.palette_overrides.clone(),
+            ClientDomainConfig::Ssh(ssh) => ssh.palette_overrides.clone(),
+        }
+    }
+
+    pub fn exit_behavior(&self) -> Option<ExitBehavior> {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.exit_behavior,
+            ClientDomainConfig::Tls(tls) => tls.exit_behavior,
+            ClientDomainConfig::Ssh(ssh) => ssh.exit_behavior,
+        }
+    }
+
+    pub fn on_window_close(&self) -> ClosePolicy {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.on_window_close,
+            ClientDomainConfig::Tls(tls) => tls.on_window_close,
+            ClientDomainConfig::Ssh(ssh) => ssh.on_window_close,
+        }
+        .unwrap_or(ClosePolicy::Detach)
+    }
+
+    pub fn on_tab_close(&self) -> ClosePolicy {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.on_tab_close,
+            ClientDomainConfig::Tls(tls) => tls.on_tab_close,
+            ClientDomainConfig::Ssh(ssh) => ssh.on_tab_close,
+        }
+        .unwrap_or(ClosePolicy::Kill)
+    }
 }
 
 impl ClientInner {
@@ -182,6 +302,24 @@ impl ClientInner {
             remote_to_local_tab: Mutex::new(HashMap::new()),
             remote_to_local_pane: Mutex::new(HashMap::new()),
             focused_remote_pane_id: Mutex::new(None),
+            server_instance_id: Mutex::new(None),
+        }
+    }
+}
+
+/// Closes the connection window of an attach that is abandoned before
+/// it completes, such as when it is cancelled, which also dismisses
+/// any prompt that the connection is waiting on
+struct AbandonedAttachGuard {
+    ui: ConnectionUI,
+    armed: bool,
+}
+
+impl Drop for AbandonedAttachGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            self.ui.output_str("Attach was cancelled\n");
+            self.ui.close();
         }
     }
 }
@@ -191,6 +329,28 @@ pub struct ClientDomain {
     label: String,
     inner: RefCell<Option<Arc<ClientInner>>>,
     local_domain_id: DomainId,
+    /// The remote tab to focus when the domain is next attached
+    attach_focus: RefCell<Option<TabId>>,
+    /// See `Domain::sync_progress`
+    sync_progress: RefCell<Option<(usize, usize)>>,
+}
+
+/// The remote ids of the panes of a domain that was just attached,
+/// in the order in which they are synchronized
+#[derive(Default)]
+struct SyncPlan {
+    /// The active pane of the tab that receives focus
+    focused: Vec<PaneId>,
+    /// The other panes that are visible in the active tab of a window
+    visible: Vec<PaneId>,
+    /// All of the other panes
+    background: Vec<PaneId>,
+}
+
+impl SyncPlan {
+    fn total(&self) -> usize {
+        self.focused.len() + self.visible.len() + self.background.len()
+    }
 }
 
 async fn update_remote_workspace(
@@ -202,6 +362,22 @@ async fn update_remote_workspace(
     Ok(())
 }
 
+/// Tells the server which workspace this client presents, so that
+/// the windows of other workspaces don't compete with it for updates.
+/// Clients without an identity, such as `wezterm cli`, present none.
+async fn present_workspace(client: &Client) -> anyhow::Result<()> {
+    let workspace = Mux::get().and_then(|mux| {
+        mux.active_identity()
+            .map(|ident| mux.active_workspace_for_client(&ident))
+    });
+    if let Some(workspace) = workspace {
+        client
+            .set_client_workspace(codec::SetClientWorkspace { workspace })
+            .await?;
+    }
+    Ok(())
+}
+
 fn mux_notify_client_domain(local_domain_id: DomainId, notif: MuxNotification) -> bool {
     let mux = Mux::get().expect("called by mux");
     let domain = match mux.get_domain(local_domain_id) {
@@ -213,8 +389,16 @@ fn mux_notify_client_domain(local_domain_id: DomainId, notif: MuxNotification) -
         None => return false,
     };
     match notif {
-        MuxNotification::ActiveWorkspaceChanged(_client_id) => {
-            // TODO: advice remote host of interesting workspaces
+        MuxNotification::ActiveWorkspaceChanged(client_id) => {
+            if domain.state() == DomainState::Attached
+                && mux.active_identity().as_ref() == Some(&client_id)
+            {
+                promise::spawn::spawn_into_main_thread(async move {
+                    let inner = ClientDomain::get_client_inner_for_domain(local_domain_id)?;
+                    present_workspace(&inner.client).await
+                })
+                .detach();
+            }
         }
         MuxNotification::WindowWorkspaceChanged(window_id) => {
             if let Some(remote_window_id) = domain.local_to_remote_window_id(window_id) {
@@ -239,6 +423,18 @@ fn mux_notify_client_domain(local_domain_id: DomainId, notif: MuxNotification) -
 }
 
 impl ClientDomain {
+    /// Arranges for the panes of this domain in `tab` to be left
+    /// running on the server when they are removed from the mux
+    fn detach_panes_of_tab(&self, tab: &Tab) {
+        for pos in tab.iter_panes_ignoring_zoom() {
+            if pos.pane.domain_id() == self.local_domain_id {
+                if let Some(client_pane) = pos.pane.downcast_ref::<ClientPane>() {
+                    client_pane.ignore_next_kill();
+                }
+            }
+        }
+    }
+
     pub fn new(config: ClientDomainConfig) -> Self {
         let local_domain_id = alloc_domain_id();
         let label = config.label();
@@ -250,6 +446,8 @@ impl ClientDomain {
             label,
             inner: RefCell::new(None),
             local_domain_id,
+            attach_focus: RefCell::new(None),
+            sync_progress: RefCell::new(None),
         }
     }
 
@@ -261,11 +459,20 @@ impl ClientDomain {
         self.config.connect_automatically()
     }
 
+    /// Returns true if `client_id` identifies the client through which
+    /// the domain is currently attached
+    pub fn is_current_client(&self, client_id: &ClientId) -> bool {
+        self.inner()
+            .map(|inner| inner.client.client_id() == client_id)
+            .unwrap_or(false)
+    }
+
     pub fn perform_detach(&self) {
         log::info!("detached domain {}", self.local_domain_id);
         self.inner.borrow_mut().take();
+        self.sync_progress.borrow_mut().take();
         let mux = Mux::get().unwrap();
-        mux.domain_was_detached(self.local_domain_id);
+        mux.kill_panes_in_domain(self.local_domain_id);
     }
 
     pub fn remote_to_local_pane_id(&self, remote_pane_id: TabId) -> Option<TabId> {
@@ -283,6 +490,44 @@ impl ClientDomain {
         inner.local_to_remote_window(local_window_id)
     }
 
+    /// Mirrors the pending close `request` of the server, so that it is
+    /// prompted for here as well.  A request that doesn't involve any
+    /// of the panes that are attached here is ignored.
+    pub fn mirror_close_confirmation(&self, request: PendingClose) {
+        let inner = match self.inner() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let scope = match request.scope {
+            CloseScope::Pane(pane_id) => {
+                inner.remote_to_local_pane_id(pane_id).map(CloseScope::Pane)
+            }
+            CloseScope::Tab(tab_id) => inner.remote_to_local_tab_id(tab_id).map(CloseScope::Tab),
+            CloseScope::Window(window_id) => inner
+                .remote_to_local_window(window_id)
+                .map(CloseScope::Window),
+        };
+        let panes: Vec<PaneId> = request
+            .panes
+            .iter()
+            .filter_map(|&pane_id| inner.remote_to_local_pane_id(pane_id))
+            .collect();
+        let scope = match scope {
+            Some(scope) if !panes.is_empty() => scope,
+            _ => return,
+        };
+        let mux = Mux::get().expect("to be called on main thread");
+        mux.mirror_close_confirmation(
+            self.local_domain_id,
+            PendingClose {
+                id: request.id,
+                scope,
+                panes,
+                message: request.message,
+            },
+        );
+    }
+
     pub fn get_client_inner_for_domain(domain_id: DomainId) -> anyhow::Result<Arc<ClientInner>> {
         let mux = Mux::get().unwrap();
         let domain = mux
@@ -306,6 +551,18 @@ impl ClientDomain {
     pub async fn reattach(domain_id: DomainId, ui: ConnectionUI) -> anyhow::Result<()> {
         let inner = Self::get_client_inner_for_domain(domain_id)?;
 
+        let info = inner.client.verify_version_compat(&ui).await?;
+        if inner.record_server_instance_id(&info.server_instance_id) {
+            log::warn!(
+                "server for domain {} was restarted (instance is now {}); \
+                 discarding panes from its previous instance",
+                domain_id,
+                info.server_instance_id
+            );
+            inner.forget_previous_instance();
+        }
+        present_workspace(&inner.client).await?;
+
         let panes = inner.client.list_panes().await?;
         Self::process_pane_list(inner, panes, None)?;
 
@@ -313,6 +570,115 @@ impl ClientDomain {
         Ok(())
     }
 
+    /// Shows the configuration of the domain and, if it is attached,
+    /// the version of the server in a connection window
+    async fn show_connection_info(&self) -> anyhow::Result<()> {
+        let ui = ConnectionUI::new();
+        ui.title(&format!("wezterm: domain {}", self.config.name()));
+        ui.output_str(&format!("Domain: {}\n", self.config.name()));
+        ui.output_str(&format!("Connection: {}\n", self.label));
+        match self.inner() {
+            Some(inner) => {
+                ui.output_str("State: attached\n");
+                let info = inner.client.get_codec_version(GetCodecVersion {}).await?;
+                ui.output_str(&format!(
+                    "Server version: {} (codec version {})\n",
+                    info.version_string, info.codec_vers
+                ));
+                ui.output_str(&format!("Server instance: {}\n", info.server_instance_id));
+                let mux = Mux::get().unwrap();
+                let inventory = mux.domain_inventory(self.local_domain_id);
+                ui.output_str(&format!(
+                    "Local panes: {} in {} tabs\n",
+                    inventory.num_panes, inventory.num_tabs
+                ));
+            }
+            None => ui.output_str("State: detached\n"),
+        }
+        // Leave the window open until the user dismisses it
+        Ok(())
+    }
+
+    /// Attaches the domain through `client`, which is already connected
+    /// to the server, rather than connecting as the configuration of the
+    /// domain says.  This attaches to a server that runs in the same
+    /// process, such as in tests, so no connection window is shown.
+    pub async fn attach_in_process(
+        &self,
+        client: Client,
+        window_id: Option<WindowId>,
+    ) -> anyhow::Result<()> {
+        let ui = ConnectionUI::new_headless();
+        let result = self.attach_client(client, None, window_id, &ui).await;
+        ui.close();
+        result
+    }
+
+    /// Completes an attach once `client` has connected to the server
+    async fn attach_client(
+        &self,
+        client: Client,
+        share_token: Option<String>,
+        window_id: Option<WindowId>,
+        ui: &ConnectionUI,
+    ) -> anyhow::Result<()> {
+        let domain_id = self.local_domain_id;
+        ui.output_str("Checking server version\n");
+        let info = client.verify_version_compat(ui).await?;
+        if let Some(token) = share_token {
+            // Limits the session to the shared tab
+            let token: ShareToken = token.parse()?;
+            ui.output_str("Redeeming the share token\n");
+            client
+                .redeem_share(RedeemShare {
+                    secret: token.secret,
+                })
+                .await?;
+        }
+        present_workspace(&client).await?;
+
+        ui.output_str("Version check OK!  Requesting pane list...\n");
+        let panes = client.list_panes().await?;
+        ui.output_str(&format!(
+            "Server has {} tabs.  Attaching to local UI...\n",
+            panes.tabs.len()
+        ));
+        let active_tabs = panes.active_tabs.clone();
+        // Fetched ahead of attaching, so that the views are in place
+        // before the panes are first drawn
+        let views = match client.get_pane_view_states(GetPaneViewStates {}).await {
+            Ok(response) => response.states,
+            Err(err) => {
+                log::debug!("the views of the panes can't be restored: {:#}", err);
+                HashMap::new()
+            }
+        };
+        ClientDomain::finish_attach(domain_id, client, panes, window_id)?;
+        let inner = ClientDomain::get_client_inner_for_domain(domain_id)?;
+        ClientDomain::restore_views(&inner, views);
+        inner.record_server_instance_id(&info.server_instance_id);
+
+        // The pane that receives focus is synchronized before
+        // the attach completes, so that it is interactive
+        // straight away, and the others follow in the background
+        let plan = self.plan_sync(&inner, &active_tabs, window_id);
+        ui.output_str("Synchronizing the focused pane...\n");
+        ClientDomain::sync_panes(&inner, &plan.focused).await?;
+        let total = plan.total();
+        let synced = plan.focused.len();
+        ClientDomain::set_sync_progress(domain_id, synced, total);
+        if synced < total {
+            ui.output_str(&format!(
+                "{} of {} panes synchronized; \
+                 the rest will follow in the background\n",
+                synced, total
+            ));
+            promise::spawn::spawn(ClientDomain::sync_in_background(domain_id, inner, plan))
+                .detach();
+        }
+        Ok(())
+    }
+
     pub async fn resync(&self) -> anyhow::Result<()> {
         if let Some(inner) = self.inner.borrow().as_ref() {
             let panes = inner.client.list_panes().await?;
@@ -329,6 +695,9 @@ impl ClientDomain {
         let mux = Mux::get().expect("to be called on main thread");
         log::debug!("ListPanes result {:#?}", panes);
 
+        // The members of each remote link group, by their local pane ids
+        let mut links: HashMap<(LinkGroupId, LinkPolicy), Vec<PaneId>> = HashMap::new();
+
         for tabroot in panes.tabs {
             let root_size = match tabroot.root_size() {
                 Some(size) => size,
@@ -359,9 +728,11 @@ impl ClientDomain {
 
                 log::debug!("tree: {:#?}", tabroot);
                 let mut workspace = None;
+                let mut tab_title = None;
                 tab.sync_with_pane_tree(root_size, tabroot, |entry| {
                     workspace.replace(entry.workspace.clone());
-                    if let Some(pane_id) = inner.remote_to_local_pane_id(entry.pane_id) {
+                    tab_title = entry.tab_title.clone();
+                    let pane = if let Some(pane_id) = inner.remote_to_local_pane_id(entry.pane_id) {
                         match mux.get_pane(pane_id) {
                             Some(pane) => pane,
                             None => {
@@ -371,6 +742,7 @@ impl ClientDomain {
                                 inner.remove_old_pane_mapping(entry.pane_id);
                                 let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
                                     &inner,
+                                    alloc_pane_id(),
                                     entry.tab_id,
                                     entry.pane_id,
                                     entry.size,
@@ -383,6 +755,7 @@ impl ClientDomain {
                     } else {
                         let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
                             &inner,
+                            alloc_pane_id(),
                             entry.tab_id,
                             entry.pane_id,
                             entry.size,
@@ -395,8 +768,24 @@ impl ClientDomain {
                         );
                         mux.add_pane(&pane).expect("failed to add pane to mux");
                         pane
+                    };
+                    if let Some(client_pane) = pane.downcast_ref::<ClientPane>() {
+                        client_pane.set_spawn_label(entry.spawn_label.clone());
+                        client_pane.update_palette_overrides(entry.palette_overrides.clone());
+                        client_pane.set_markers(entry.markers.clone());
+                        if let Some(modes) = entry.modes {
+                            client_pane.set_modes(modes);
+                        }
+                    }
+                    for link in &entry.links {
+                        links
+                            .entry((link.group_id, link.policy))
+                            .or_default()
+                            .push(pane.pane_id());
                     }
+                    pane
                 });
+                tab.set_computed_title(tab_title);
 
                 if let Some(local_window_id) = inner.remote_to_local_window(remote_window_id) {
                     let mut window = mux
@@ -440,6 +829,14 @@ impl ClientDomain {
             }
         }
 
+        for ((_group_id, policy), panes) in links {
+            if panes.len() > 1 {
+                if let Err(err) = mux.link_panes(panes, policy) {
+                    log::error!("failed to mirror remote pane link: {:#}", err);
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -465,6 +862,236 @@ impl ClientDomain {
 
         Ok(())
     }
+
+    /// Hands the views of the remote panes that the user had before the
+    /// domain was last detached to their local panes, to be restored
+    fn restore_views(inner: &ClientInner, views: HashMap<PaneId, PaneViewState>) {
+        let mux = Mux::get().unwrap();
+        for (remote_pane_id, state) in views {
+            let pane = match inner
+                .remote_to_local_pane_id(remote_pane_id)
+                .and_then(|pane_id| mux.get_pane(pane_id))
+            {
+                Some(pane) => pane,
+                None => continue,
+            };
+            if let Some(pane) = pane.downcast_ref::<ClientPane>() {
+                pane.set_restored_view_state(state);
+            }
+        }
+    }
+
+    /// Arranges for the tab with this id on the server to be activated
+    /// and synchronized ahead of the others when the domain is next
+    /// attached
+    pub fn set_attach_focus(&self, remote_tab_id: TabId) {
+        self.attach_focus.borrow_mut().replace(remote_tab_id);
+    }
+
+    /// Activates the tab of each local window that is active in the
+    /// corresponding remote window, and orders the panes of the domain
+    /// for synchronization.  The tab that receives focus is the one
+    /// passed to `set_attach_focus`, or else the active tab of the
+    /// window in which the domain is being attached.
+    fn plan_sync(
+        &self,
+        inner: &ClientInner,
+        active_tabs: &[TabId],
+        window_id: Option<WindowId>,
+    ) -> SyncPlan {
+        let mux = Mux::get().unwrap();
+        let local_tab = |remote_tab_id: TabId| {
+            let tab_id = inner.remote_to_local_tab_id(remote_tab_id)?;
+            let window_id = mux.window_containing_tab(tab_id)?;
+            Some((window_id, tab_id))
+        };
+
+        let mut active: Vec<(WindowId, TabId)> =
+            active_tabs.iter().filter_map(|&id| local_tab(id)).collect();
+        let focus = self
+            .attach_focus
+            .borrow_mut()
+            .take()
+            .and_then(|remote_tab_id| {
+                let focus = local_tab(remote_tab_id);
+                if focus.is_none() {
+                    log::warn!(
+                        "cannot focus tab {}, which is not in domain {}",
+                        remote_tab_id,
+                        self.domain_name()
+                    );
+                }
+                focus
+            })
+            .or_else(|| {
+                active
+                    .iter()
+                    .find(|(window, _)| Some(*window) == window_id)
+                    .copied()
+            });
+        if let Some((focus_window, focus_tab)) = focus {
+            active.retain(|(window, _)| *window != focus_window);
+            active.insert(0, (focus_window, focus_tab));
+        }
+
+        let remote_pane_id = |pane: &Rc<dyn Pane>| {
+            pane.downcast_ref::<ClientPane>()
+                .map(|pane| pane.remote_pane_id)
+        };
+
+        let mut plan = SyncPlan::default();
+        let mut planned = HashSet::new();
+        for (idx, (window_id, tab_id)) in active.iter().enumerate() {
+            let tab = match mux.get_tab(*tab_id) {
+                Some(tab) => tab,
+                None => continue,
+            };
+            if let Some(mut window) = mux.get_window_mut(*window_id) {
+                if let Some(tab_idx) = window.idx_by_id(*tab_id) {
+                    window.set_active_without_saving(tab_idx);
+                }
+            }
+            for pos in tab.iter_panes() {
+                let pane_id = match remote_pane_id(&pos.pane) {
+                    Some(id) if planned.insert(id) => id,
+                    _ => continue,
+                };
+                if idx == 0 && pos.is_active {
+                    plan.focused.push(pane_id);
+                } else {
+                    plan.visible.push(pane_id);
+                }
+            }
+        }
+
+        let mut background: Vec<(PaneId, PaneId)> = mux
+            .iter_panes_for_domain(self.local_domain_id)
+            .into_iter()
+            .filter_map(|pane| Some((pane.pane_id(), remote_pane_id(&pane)?)))
+            .filter(|(_, remote_id)| planned.insert(*remote_id))
+            .collect();
+        background.sort();
+        plan.background = background.into_iter().map(|(_, id)| id).collect();
+
+        plan
+    }
+
+    /// Asks the server to push the state of these panes, skipping any
+    /// that were removed locally in the meantime
+    async fn sync_panes(inner: &ClientInner, remote_pane_ids: &[PaneId]) -> anyhow::Result<()> {
+        let mux = Mux::get().unwrap();
+        let pane_ids: Vec<PaneId> = remote_pane_ids
+            .iter()
+            .copied()
+            .filter(|&id| {
+                inner
+                    .remote_to_local_pane_id(id)
+                    .and_then(|local_id| mux.get_pane(local_id))
+                    .is_some()
+            })
+            .collect();
+        if pane_ids.is_empty() {
+            return Ok(());
+        }
+        let response = inner.client.sync_panes(SyncPanes { pane_ids }).await?;
+        if !response.missing.is_empty() {
+            log::debug!(
+                "remote panes {:?} went away before they were synchronized",
+                response.missing
+            );
+        }
+        Ok(())
+    }
+
+    fn set_sync_progress(domain_id: DomainId, synced: usize, total: usize) {
+        let mux = Mux::get().unwrap();
+        if let Some(domain) = mux.get_domain(domain_id) {
+            if let Some(domain) = domain.downcast_ref::<Self>() {
+                *domain.sync_progress.borrow_mut() = if synced < total {
+                    Some((synced, total))
+                } else {
+                    None
+                };
+            }
+        }
+        mux.notify(MuxNotification::DomainSyncProgress {
+            domain_id,
+            synced,
+            total,
+        });
+    }
+
+    /// Synchronizes the panes that were not synchronized while the
+    /// domain was being attached: the visible ones straight away, and
+    /// the others at the rate set by
+    /// `mux_background_sync_panes_per_second`.
+    /// Gives up if the domain is detached, or attached again, meanwhile;
+    /// panes that were not synchronized are then fetched as usual if
+    /// they are shown.
+    async fn sync_in_background(domain_id: DomainId, inner: Arc<ClientInner>, plan: SyncPlan) {
+        let total = plan.total();
+        let mut synced = plan.focused.len();
+
+        let rate = config::configuration().mux_background_sync_panes_per_second;
+        let (batch_size, interval) = if rate == 0 {
+            (plan.background.len().max(1), Duration::ZERO)
+        } else {
+            (1, Duration::from_secs(1) / rate)
+        };
+        let mut batches = vec![plan.visible];
+        batches.extend(
+            plan.background
+                .chunks(batch_size)
+                .map(|chunk| chunk.to_vec()),
+        );
+
+        for (idx, batch) in batches.into_iter().enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+            if idx > 1 && !interval.is_zero() {
+                smol::Timer::after(interval).await;
+            }
+            let still_attached = Self::get_client_inner_for_domain(domain_id)
+                .map(|current| Arc::ptr_eq(&current, &inner))
+                .unwrap_or(false);
+            if !still_attached {
+                log::debug!(
+                    "domain {} was detached after {} of {} panes were synchronized",
+                    domain_id,
+                    synced,
+                    total
+                );
+                return;
+            }
+            if let Err(err) = Self::sync_panes(&inner, &batch).await {
+                log::error!(
+                    "failed to synchronize panes of domain {}: {:#}",
+                    domain_id,
+                    err
+                );
+                Self::set_sync_progress(domain_id, total, total);
+                return;
+            }
+            synced += batch.len();
+            Self::set_sync_progress(domain_id, synced, total);
+        }
+    }
+
+    /// Applies the `exit_behavior` of the domain to a command that
+    /// doesn't specify its own, so that the server holds or closes
+    /// the pane accordingly
+    fn with_domain_exit_behavior(&self, command: Option<CommandBuilder>) -> Option<CommandBuilder> {
+        let exit_behavior = match self.config.exit_behavior() {
+            Some(exit_behavior) => exit_behavior,
+            None => return command,
+        };
+        let mut command = command.unwrap_or_else(CommandBuilder::new_default_prog);
+        if command.get_exit_behavior().is_none() {
+            command.exit_behavior(exit_behavior.as_str());
+        }
+        Some(command)
+    }
 }
 
 #[async_trait(?Send)]
@@ -477,53 +1104,84 @@ impl Domain for ClientDomain {
         self.config.name()
     }
 
+    fn domain_kind(&self) -> Option<DomainKind> {
+        Some(self.config.kind())
+    }
+
+    fn launcher_hints(&self) -> LauncherHints {
+        self.config.launcher_hints()
+    }
+
+    fn palette_overrides(&self) -> Option<Palette> {
+        self.config.palette_overrides()
+    }
+
     async fn domain_label(&self) -> String {
         self.label.to_string()
     }
 
     async fn spawn_pane(
         &self,
+        _pane_id: PaneId,
         _size: TerminalSize,
         _command: Option<CommandBuilder>,
-        _command_dir: Option<String>,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        anyhow::bail!("spawn_pane not implemented for ClientDomain")
+        _command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        Err(anyhow!("spawn_pane not implemented for ClientDomain").into())
     }
 
     async fn spawn(
         &self,
         size: TerminalSize,
         command: Option<CommandBuilder>,
-        command_dir: Option<String>,
+        command_dir: Option<CommandDir>,
         window: WindowId,
-    ) -> anyhow::Result<Rc<Tab>> {
-        let inner = self
-            .inner()
-            .ok_or_else(|| anyhow!("domain is not attached"))?;
+    ) -> DomainResult<Rc<Tab>> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
 
         let workspace = Mux::get().unwrap().active_workspace();
+        let spawn_label = command
+            .as_ref()
+            .and_then(|cmd| cmd.get_label())
+            .map(|label| label.to_string());
+        let command = self.with_domain_exit_behavior(command);
 
+        let span = spawntiming::span("remote_spawn");
         let result = inner
             .client
             .spawn_v2(SpawnV2 {
                 domain: SpawnTabDomain::DomainId(inner.remote_domain_id),
                 window_id: inner.local_to_remote_window(window),
                 size,
+                command_dir: resolve_command_dir(command.as_ref(), command_dir),
                 command,
-                command_dir,
                 workspace,
+                // The caller has already looked for a pane to reuse
+                reuse: None,
+                // The window was resolved locally
+                window_target: None,
+                no_domain_rules: false,
             })
             .await?;
+        drop(span);
 
         inner.record_remote_to_local_window_mapping(result.window_id, window);
 
-        let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
+        let pane = ClientPane::new(
             &inner,
+            alloc_pane_id(),
             result.tab_id,
             result.pane_id,
             size,
-            "wezterm",
-        ));
+            spawn_label.as_deref().unwrap_or("wezterm"),
+        );
+        pane.set_spawn_label(spawn_label);
+        if let Some(overrides) = self.palette_overrides() {
+            pane.set_palette_overrides(Some(overrides))?;
+        }
+        let pane: Rc<dyn Pane> = Rc::new(pane);
         let tab = Rc::new(Tab::new(&size));
         tab.assign_pane(&pane);
         inner.remove_old_tab_mapping(result.tab_id);
@@ -536,16 +1194,26 @@ impl Domain for ClientDomain {
         Ok(tab)
     }
 
+    async fn spawn_detached_tab(
+        &self,
+        _size: TerminalSize,
+        _command: Option<CommandBuilder>,
+        _command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<Tab>> {
+        Err(anyhow!("ClientDomain cannot spawn a tab that is not in a window").into())
+    }
+
     async fn split_pane(
         &self,
         source: SplitSource,
         tab_id: TabId,
         pane_id: PaneId,
         split_request: SplitRequest,
-    ) -> anyhow::Result<Rc<dyn Pane>> {
-        let inner = self
-            .inner()
-            .ok_or_else(|| anyhow!("domain is not attached"))?;
+        new_pane_id: PaneId,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
 
         let mux = Mux::get().unwrap();
 
@@ -559,33 +1227,103 @@ impl Domain for ClientDomain {
             .downcast_ref::<ClientPane>()
             .ok_or_else(|| anyhow!("pane_id {} is not a ClientPane", pane_id))?;
 
+        // A moved pane is identified to the server by its remote id,
+        // and so must belong to this domain
+        let moved_pane = match &source {
+            SplitSource::MovePane(move_pane_id) => {
+                let moved = mux
+                    .get_pane(*move_pane_id)
+                    .ok_or_else(|| anyhow!("pane_id {} is invalid", move_pane_id))?;
+                if moved.domain_id() != self.local_domain_id {
+                    return Err(anyhow!(
+                        "pane {} cannot be moved into domain {}, which it does not belong to",
+                        move_pane_id,
+                        self.domain_name()
+                    )
+                    .into());
+                }
+                mux.authorize_move(
+                    *move_pane_id,
+                    MoveDestination::Split {
+                        tab_id,
+                        pane_id,
+                        domain_id: local_pane.domain_id(),
+                    },
+                )?;
+                Some(moved)
+            }
+            SplitSource::Spawn { .. } => None,
+        };
+
         let (command, command_dir, move_pane_id) = match source {
             SplitSource::Spawn {
                 command,
                 command_dir,
             } => (command, command_dir, None),
-            SplitSource::MovePane(move_pane_id) => (None, None, Some(move_pane_id)),
+            SplitSource::MovePane(_) => (
+                None,
+                None,
+                moved_pane
+                    .as_ref()
+                    .and_then(|moved| moved.downcast_ref::<ClientPane>())
+                    .map(|moved| moved.remote_pane_id),
+            ),
+        };
+        let spawn_label = command
+            .as_ref()
+            .and_then(|cmd| cmd.get_label())
+            .map(|label| label.to_string());
+        let command = match move_pane_id {
+            Some(_) => command,
+            None => self.with_domain_exit_behavior(command),
         };
 
+        let span = spawntiming::span("remote_split");
         let result = inner
             .client
             .split_pane(SplitPane {
                 domain: SpawnTabDomain::CurrentPaneDomain,
                 pane_id: pane.remote_pane_id,
                 split_request,
+                command_dir: resolve_command_dir(command.as_ref(), command_dir),
                 command,
-                command_dir,
                 move_pane_id,
+                no_domain_rules: false,
             })
             .await?;
-
-        let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
-            &inner,
-            result.tab_id,
-            result.pane_id,
-            result.size,
-            "wezterm",
-        ));
+        drop(span);
+
+        let pane: Rc<dyn Pane> = match moved_pane {
+            Some(moved) => {
+                // The server moved the pane; mirror that locally, rather
+                // than creating a second pane for the same remote pane
+                let (_domain, _window, src_tab) = mux
+                    .resolve_pane_id(moved.pane_id())
+                    .ok_or_else(|| anyhow!("pane {} not found", moved.pane_id()))?;
+                if let Some(src_tab) = mux.get_tab(src_tab) {
+                    src_tab.remove_pane(moved.pane_id());
+                    if src_tab.is_dead() {
+                        mux.remove_tab(src_tab.tab_id());
+                    }
+                }
+                moved
+            }
+            None => {
+                let pane = ClientPane::new(
+                    &inner,
+                    new_pane_id,
+                    result.tab_id,
+                    result.pane_id,
+                    result.size,
+                    spawn_label.as_deref().unwrap_or("wezterm"),
+                );
+                pane.set_spawn_label(spawn_label);
+                if let Some(overrides) = self.palette_overrides() {
+                    pane.set_palette_overrides(Some(overrides))?;
+                }
+                Rc::new(pane)
+            }
+        };
 
         let pane_index = match tab
             .iter_panes()
@@ -593,7 +1331,7 @@ impl Domain for ClientDomain {
             .find(|p| p.pane.pane_id() == pane_id)
         {
             Some(p) => p.index,
-            None => anyhow::bail!("invalid pane id {}", pane_id),
+            None => return Err(anyhow!("invalid pane id {}", pane_id).into()),
         };
 
         tab.split_and_insert(pane_index, split_request, Rc::clone(&pane))
@@ -604,7 +1342,35 @@ impl Domain for ClientDomain {
         Ok(pane)
     }
 
-    async fn attach(&self, window_id: Option<WindowId>) -> anyhow::Result<()> {
+    async fn preview_split(
+        &self,
+        _tab_id: TabId,
+        pane_id: PaneId,
+        split_request: SplitRequest,
+    ) -> anyhow::Result<Option<SplitPreview>> {
+        let inner = self
+            .inner()
+            .ok_or_else(|| anyhow!("domain {} is detached", self.domain_name()))?;
+        let local_pane = Mux::get()
+            .unwrap()
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("pane_id {} is invalid", pane_id))?;
+        let pane = local_pane
+            .downcast_ref::<ClientPane>()
+            .ok_or_else(|| anyhow!("pane_id {} is not a ClientPane", pane_id))?;
+
+        // The server holds the authoritative layout of the tab
+        let response = inner
+            .client
+            .preview_split(PreviewSplit {
+                pane_id: pane.remote_pane_id,
+                split_request,
+            })
+            .await?;
+        Ok(response.preview)
+    }
+
+    async fn attach(&self, window_id: Option<WindowId>) -> DomainResult<()> {
         if self.state() == DomainState::Attached {
             // Already attached
             return Ok(());
@@ -612,6 +1378,10 @@ impl Domain for ClientDomain {
 
         let domain_id = self.local_domain_id;
         let config = self.config.clone();
+        let share_token = match &config {
+            ClientDomainConfig::Tls(tls) => tls.share_token.clone(),
+            _ => None,
+        };
 
         let activity = mux::activity::Activity::new();
         let ui = ConnectionUI::with_params(ConnectionUIParams {
@@ -619,42 +1389,43 @@ impl Domain for ClientDomain {
             ..Default::default()
         });
         ui.title("wezterm: Connecting...");
+        let mut guard = AbandonedAttachGuard {
+            ui: ui.clone(),
+            armed: true,
+        };
 
-        ui.async_run_and_log_error({
-            let ui = ui.clone();
-            async move {
-                let mut cloned_ui = ui.clone();
-                let client = spawn_into_new_thread(move || match &config {
-                    ClientDomainConfig::Unix(unix) => {
-                        let initial = true;
-                        let no_auto_start = false;
-                        Client::new_unix_domain(
-                            Some(domain_id),
-                            unix,
-                            initial,
-                            &mut cloned_ui,
-                            no_auto_start,
-                        )
-                    }
-                    ClientDomainConfig::Tls(tls) => Client::new_tls(domain_id, tls, &mut cloned_ui),
-                    ClientDomainConfig::Ssh(ssh) => Client::new_ssh(domain_id, ssh, &mut cloned_ui),
-                })
-                .await?;
-
-                ui.output_str("Checking server version\n");
-                client.verify_version_compat(&ui).await?;
-
-                ui.output_str("Version check OK!  Requesting pane list...\n");
-                let panes = client.list_panes().await?;
-                ui.output_str(&format!(
-                    "Server has {} tabs.  Attaching to local UI...\n",
-                    panes.tabs.len()
-                ));
-                ClientDomain::finish_attach(domain_id, client, panes, window_id)
-            }
-        })
-        .await
-        .map_err(|e| {
+        let result = ui
+            .async_run_and_log_error({
+                let ui = ui.clone();
+                async move {
+                    let mut cloned_ui = ui.clone();
+                    let client = spawn_into_new_thread(move || match &config {
+                        ClientDomainConfig::Unix(unix) => {
+                            let initial = true;
+                            let no_auto_start = false;
+                            Client::new_unix_domain(
+                                Some(domain_id),
+                                unix,
+                                initial,
+                                &mut cloned_ui,
+                                no_auto_start,
+                            )
+                        }
+                        ClientDomainConfig::Tls(tls) => {
+                            Client::new_tls(domain_id, tls, &mut cloned_ui)
+                        }
+                        ClientDomainConfig::Ssh(ssh) => {
+                            Client::new_ssh(domain_id, ssh, &mut cloned_ui)
+                        }
+                    })
+                    .await?;
+                    self.attach_client(client, share_token, window_id, &ui)
+                        .await
+                }
+            })
+            .await;
+        guard.armed = false;
+        result.map_err(|e| {
             ui.output_str(&format!("Error during attach: {:#}\n", e));
             e
         })?;
@@ -665,7 +1436,36 @@ impl Domain for ClientDomain {
         Ok(())
     }
 
-    fn local_window_is_closing(&self, window_id: WindowId) {
+    fn window_close_policy(&self) -> ClosePolicy {
+        self.config.on_window_close()
+    }
+
+    /// The server keeps the layout of the tabs of this domain, and it
+    /// knows nothing of the tabs of other domains, so a pane can only
+    /// be split into a tab of the domain that it belongs to
+    fn authorize_move(&self, pane: PaneId, destination: MoveDestination) -> Result<(), MoveDenied> {
+        if let MoveDestination::Split { domain_id, .. } = destination {
+            let mux = Mux::get().expect("to be called by mux on mux thread");
+            let moved_domain_id = mux.get_pane(pane).map(|pane| pane.domain_id());
+            if domain_id != self.local_domain_id || moved_domain_id != Some(self.local_domain_id) {
+                return Err(MoveDenied::new(format!(
+                    "the panes of {} can only be split into its own tabs, \
+                     as their layout is kept by its server",
+                    self.domain_name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn tab_close_policy(&self) -> ClosePolicy {
+        self.config.on_tab_close()
+    }
+
+    fn local_window_is_closing(&self, window_id: WindowId, action: CloseAction) {
+        if action != CloseAction::Detach {
+            return;
+        }
         let mux = Mux::get().expect("to be called by mux on mux thread");
         let window = match mux.get_window(window_id) {
             Some(w) => w,
@@ -673,21 +1473,139 @@ impl Domain for ClientDomain {
         };
 
         for tab in window.iter() {
-            for pos in tab.iter_panes_ignoring_zoom() {
-                if pos.pane.domain_id() == self.local_domain_id {
-                    if let Some(client_pane) = pos.pane.downcast_ref::<ClientPane>() {
-                        client_pane.ignore_next_kill();
-                    }
-                }
-            }
+            self.detach_panes_of_tab(tab);
         }
     }
 
-    fn detach(&self) -> anyhow::Result<()> {
+    fn tab_is_closing(&self, tab_id: TabId, action: CloseAction) {
+        if action != CloseAction::Detach {
+            return;
+        }
+        let mux = Mux::get().expect("to be called by mux on mux thread");
+        if let Some(tab) = mux.get_tab(tab_id) {
+            self.detach_panes_of_tab(&tab);
+        }
+    }
+
+    fn detach(&self) -> DomainResult<()> {
         self.perform_detach();
         Ok(())
     }
 
+    fn palette_entries(&self) -> Vec<PaletteEntry> {
+        let mut entries = vec![];
+        if self.state() == DomainState::Attached {
+            entries.push(PaletteEntry::new("Detach", "detach"));
+            entries.push(PaletteEntry::new("Reconnect", "reconnect"));
+        }
+        entries.push(PaletteEntry::new("Show connection info", "connection-info"));
+        entries
+    }
+
+    async fn list_directory(&self, dir: &str, limit: usize) -> anyhow::Result<DirListing> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
+        let response = inner
+            .client
+            .list_directory(ListDirectory {
+                domain: SpawnTabDomain::DomainId(inner.remote_domain_id),
+                path: dir.to_string(),
+                limit,
+            })
+            .await?;
+        Ok(response.listing)
+    }
+
+    async fn exec(&self, request: ExecRequest) -> anyhow::Result<ExecOutput> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
+        let response = inner
+            .client
+            .exec_command(ExecCommand {
+                domain: SpawnTabDomain::DomainId(inner.remote_domain_id),
+                request,
+            })
+            .await?;
+        Ok(response.output)
+    }
+
+    async fn confirm_close(&self, scope: CloseScope) -> anyhow::Result<Option<CloseOutcome>> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
+        let remote_scope = match scope {
+            CloseScope::Pane(pane_id) => Mux::get()
+                .and_then(|mux| mux.get_pane(pane_id))
+                .and_then(|pane| {
+                    pane.downcast_ref::<ClientPane>()
+                        .map(|pane| pane.remote_pane_id())
+                })
+                .map(CloseScope::Pane),
+            CloseScope::Tab(tab_id) => inner.local_to_remote_tab_id(tab_id).map(CloseScope::Tab),
+            CloseScope::Window(window_id) => inner
+                .local_to_remote_window(window_id)
+                .map(CloseScope::Window),
+        }
+        .ok_or_else(|| {
+            anyhow!(
+                "{:?} is not known to the server of domain {}",
+                scope,
+                self.domain_name()
+            )
+        })?;
+        let response = inner
+            .client
+            .confirm_close(ConfirmClose {
+                scope: remote_scope,
+                force: false,
+            })
+            .await?;
+        Ok(Some(response.outcome))
+    }
+
+    async fn answer_close_confirmation(
+        &self,
+        id: CloseRequestId,
+        confirmed: bool,
+    ) -> anyhow::Result<()> {
+        let inner = self.inner().ok_or_else(|| DomainError::DomainDetached {
+            domain: self.domain_name().to_string(),
+        })?;
+        inner
+            .client
+            .answer_close_confirmation(AnswerCloseConfirmation { id, confirmed })
+            .await?;
+        Ok(())
+    }
+
+    async fn perform_palette_action(&self, action: &str) -> anyhow::Result<()> {
+        match action {
+            "detach" => Ok(self.detach()?),
+            "reconnect" => {
+                if self.state() == DomainState::Attached {
+                    self.perform_detach();
+                }
+                let mux = Mux::get().expect("called on the mux thread");
+                let domain = mux
+                    .get_domain(self.local_domain_id)
+                    .ok_or_else(|| anyhow!("domain {} not found", self.config.name()))?;
+                Ok(mux.attach_domain(&domain, None).await?)
+            }
+            "connection-info" => self.show_connection_info().await,
+            _ => bail!(
+                "domain {} has no action named {}",
+                self.config.name(),
+                action
+            ),
+        }
+    }
+
+    fn sync_progress(&self) -> Option<(usize, usize)> {
+        *self.sync_progress.borrow()
+    }
+
     fn state(&self) -> DomainState {
         if self.inner.borrow().is_some() {
             DomainState::Attached
@@ -695,4 +1613,10 @@ impl Domain for ClientDomain {
             DomainState::Detached
         }
     }
+
+    fn notification_handling(&self) -> NotificationHandling {
+        self.config
+            .notifications()
+            .unwrap_or_else(|| config::configuration().notification_handling)
+    }
 }
@@ -4,11 +4,20 @@ use crate::pane::renderable::{hydrate_lines, RenderableInner, RenderableState};
 use anyhow::bail;
 use async_trait::async_trait;
 use codec::*;
-use config::configuration;
+use config::{configuration, ClosePolicy, Palette};
+use mux::bookmark::Bookmark;
 use mux::domain::DomainId;
-use mux::pane::{alloc_pane_id, CloseReason, Pane, PaneId, Pattern, SearchResult};
+use mux::editscrollback::EditScrollbackRequest;
+use mux::globalsearch::{GlobalSearchOptions, GlobalSearchResults};
+use mux::marker::Marker;
+use mux::outputwatch::{OutputWatcherInfo, OutputWatcherSpec, WatcherId};
+use mux::pane::{CloseReason, ForegroundProcessInfo, Pane, PaneId, Pattern, SearchResult};
 use mux::renderable::{RenderableDimensions, StableCursorPosition};
+use mux::resources::PaneResourceUsage;
+use mux::scan::{HyperlinkMatch, HyperlinkRuleSpec, ScanMatch};
+use mux::scrollback::{ScrollbackChunk, ScrollbackFormat};
 use mux::tab::TabId;
+use mux::viewstate::PaneViewState;
 use mux::{Mux, MuxNotification};
 use rangeset::RangeSet;
 use ratelim::RateLimiter;
@@ -17,14 +26,45 @@ use std::collections::HashMap;
 use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
+use termwiz::hyperlink::Rule;
 use termwiz::input::KeyEvent;
 use termwiz::surface::SequenceNo;
 use url::Url;
 use wezterm_term::color::ColorPalette;
 use wezterm_term::{
-    Alert, Clipboard, KeyCode, KeyModifiers, Line, MouseEvent, StableRowIndex, TerminalSize,
+    Alert, Clipboard, KeyCode, KeyModifiers, Line, MouseEvent, SemanticZone, StableRowIndex,
+    TerminalModes, TerminalSize,
 };
 
+/// How long the view of a pane must stay the same before it is reported
+/// to the server, so that scrolling doesn't send a report for each row
+const VIEW_STATE_DELAY: Duration = Duration::from_millis(500);
+
+/// The semantic zones of the remote pane, which are fetched from the
+/// server on demand
+#[derive(Default)]
+struct SemanticZoneState {
+    /// The local seqno of the pane at the time that `zones` was requested
+    seqno: SequenceNo,
+    zones: Option<Vec<SemanticZone>>,
+    fetching: bool,
+}
+
+/// The implicit hyperlinks of the remote pane, which the server finds
+/// in the logical lines around the rows that were asked for, so that
+/// links that are wrapped onto rows that haven't been fetched are found
+#[derive(Default)]
+struct HyperlinkState {
+    /// The local seqno of the pane at the time that `links` was requested
+    seqno: SequenceNo,
+    /// The rules and rows that `links` were found with
+    rules: Vec<HyperlinkRuleSpec>,
+    range: Range<StableRowIndex>,
+    links: Option<Vec<HyperlinkMatch>>,
+    fetching: bool,
+}
+
 pub struct ClientPane {
     client: Arc<ClientInner>,
     local_pane_id: PaneId,
@@ -38,17 +78,40 @@ pub struct ClientPane {
     mouse_grabbed: RefCell<bool>,
     ignore_next_kill: RefCell<bool>,
     user_vars: RefCell<HashMap<String, String>>,
+    semantic_zones: RefCell<SemanticZoneState>,
+    hyperlinks: RefCell<HyperlinkState>,
+    resource_usage: RefCell<Option<PaneResourceUsage>>,
+    spawn_label: RefCell<Option<String>>,
+    /// Whether the server has paused the output of the pane
+    output_paused: RefCell<bool>,
+    /// The modes of the remote pane, as last reported by the server
+    modes: RefCell<Option<TerminalModes>>,
+    /// The palette overrides of the pane, as last reported by the server
+    palette_overrides: RefCell<Option<Palette>>,
+    /// The markers of the pane, as last reported by the server
+    markers: RefCell<Vec<Marker>>,
+    /// The size that was most recently sent to the server, along with
+    /// its pixel dimensions, which aren't part of the dimensions that
+    /// the server reports back
+    size: RefCell<TerminalSize>,
+    /// The view of the pane that was most recently reported to the
+    /// server, or is waiting to be
+    view_state: RefCell<PaneViewState>,
+    view_state_pending: RefCell<bool>,
+    /// The view that the user had before the client last detached,
+    /// until the GUI has restored it
+    restored_view_state: RefCell<Option<PaneViewState>>,
 }
 
 impl ClientPane {
     pub fn new(
         client: &Arc<ClientInner>,
+        local_pane_id: PaneId,
         remote_tab_id: TabId,
         remote_pane_id: PaneId,
         size: TerminalSize,
         title: &str,
     ) -> Self {
-        let local_pane_id = alloc_pane_id();
         let writer = PaneWriter {
             client: Arc::clone(client),
             remote_pane_id,
@@ -96,7 +159,165 @@ impl ClientPane {
             mouse_grabbed: RefCell::new(false),
             ignore_next_kill: RefCell::new(false),
             user_vars: RefCell::new(HashMap::new()),
+            semantic_zones: RefCell::new(SemanticZoneState::default()),
+            hyperlinks: RefCell::new(HyperlinkState::default()),
+            resource_usage: RefCell::new(None),
+            spawn_label: RefCell::new(None),
+            output_paused: RefCell::new(false),
+            modes: RefCell::new(None),
+            palette_overrides: RefCell::new(None),
+            markers: RefCell::new(vec![]),
+            size: RefCell::new(size),
+            view_state: RefCell::new(PaneViewState::default()),
+            view_state_pending: RefCell::new(false),
+            restored_view_state: RefCell::new(None),
+        }
+    }
+
+    /// Records the view of this pane that the user had before the
+    /// client last detached, so that the GUI restores it when it next
+    /// draws the pane
+    pub fn set_restored_view_state(&self, state: PaneViewState) {
+        *self.view_state.borrow_mut() = state;
+        self.restored_view_state.borrow_mut().replace(state);
+    }
+
+    /// Records the palette overrides that the server reported for
+    /// this pane
+    pub fn update_palette_overrides(&self, overrides: Option<Palette>) {
+        if *self.palette_overrides.borrow() == overrides {
+            return;
+        }
+        *self.palette_overrides.borrow_mut() = overrides;
+        if let Some(mux) = Mux::get() {
+            mux.notify(MuxNotification::PanePaletteOverridesChanged(
+                self.local_pane_id,
+            ));
+        }
+    }
+
+    /// Records the markers that the server reported for this pane
+    pub fn set_markers(&self, markers: Vec<Marker>) {
+        if *self.markers.borrow() == markers {
+            return;
         }
+        *self.markers.borrow_mut() = markers;
+        if let Some(mux) = Mux::get() {
+            mux.notify(MuxNotification::PaneMarkersChanged(self.local_pane_id));
+        }
+    }
+
+    /// Records the resource usage that the server sampled for this pane
+    pub fn set_resource_usage(&self, usage: PaneResourceUsage) {
+        self.resource_usage.borrow_mut().replace(usage);
+    }
+
+    /// Records the modes that the server reported for this pane
+    pub fn set_modes(&self, modes: TerminalModes) {
+        self.modes.borrow_mut().replace(modes);
+    }
+
+    /// Records the label of the command that the server spawned
+    /// into this pane
+    pub fn set_spawn_label(&self, label: Option<String>) {
+        *self.spawn_label.borrow_mut() = label;
+    }
+
+    /// Requests the current semantic zones from the server.
+    /// The result is recorded against `seqno`.
+    fn fetch_semantic_zones(&self, seqno: SequenceNo) {
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        let local_pane_id = self.local_pane_id;
+        promise::spawn::spawn(async move {
+            let result = client
+                .client
+                .get_semantic_zones(GetSemanticZones {
+                    pane_id: remote_pane_id,
+                    range: None,
+                })
+                .await;
+            let mux = Mux::get().unwrap();
+            let mut changed = false;
+            if let Some(pane) = mux.get_pane(local_pane_id) {
+                if let Some(pane) = pane.downcast_ref::<ClientPane>() {
+                    let mut state = pane.semantic_zones.borrow_mut();
+                    state.fetching = false;
+                    match result {
+                        Ok(GetSemanticZonesResponse { zones }) => {
+                            changed = state.zones.as_ref() != Some(&zones);
+                            state.zones.replace(zones);
+                            state.seqno = seqno;
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "failed to fetch semantic zones for pane {}: {:#}",
+                                remote_pane_id,
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+            if changed {
+                mux.notify(MuxNotification::PaneOutput(local_pane_id));
+            }
+            anyhow::Result::<()>::Ok(())
+        })
+        .detach();
+    }
+
+    /// Asks the server for the implicit hyperlinks in `range`.
+    /// The result is recorded against `seqno`, `rules` and `range`.
+    fn fetch_hyperlinks(
+        &self,
+        seqno: SequenceNo,
+        rules: Vec<HyperlinkRuleSpec>,
+        range: Range<StableRowIndex>,
+    ) {
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        let local_pane_id = self.local_pane_id;
+        promise::spawn::spawn(async move {
+            let result = client
+                .client
+                .scan_pane(ScanPane {
+                    pane_id: remote_pane_id,
+                    patterns: vec![],
+                    hyperlink_rules: rules.clone(),
+                    range: range.clone(),
+                })
+                .await;
+            let mux = Mux::get().unwrap();
+            let mut changed = false;
+            if let Some(pane) = mux.get_pane(local_pane_id) {
+                if let Some(pane) = pane.downcast_ref::<ClientPane>() {
+                    let mut state = pane.hyperlinks.borrow_mut();
+                    state.fetching = false;
+                    match result {
+                        Ok(ScanPaneResponse { hyperlinks, .. }) => {
+                            changed = state.links.as_ref() != Some(&hyperlinks);
+                            state.links.replace(hyperlinks);
+                            state.seqno = seqno;
+                            state.rules = rules;
+                            state.range = range;
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "failed to scan pane {} for hyperlinks: {:#}",
+                                remote_pane_id,
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+            if changed {
+                mux.notify(MuxNotification::PaneOutput(local_pane_id));
+            }
+            anyhow::Result::<()>::Ok(())
+        })
+        .detach();
     }
 
     pub async fn process_unilateral(&self, pdu: Pdu) -> anyhow::Result<()> {
@@ -149,6 +370,9 @@ impl ClientPane {
                             .borrow_mut()
                             .insert(name.clone(), value.clone());
                     }
+                    Alert::ModesChanged(modes) => {
+                        self.set_modes(*modes);
+                    }
                     _ => {}
                 }
                 mux.notify(MuxNotification::Alert {
@@ -156,6 +380,35 @@ impl ClientPane {
                     alert,
                 });
             }
+            Pdu::PaneOutputPausedChanged(PaneOutputPausedChanged { paused, .. }) => {
+                *self.output_paused.borrow_mut() = paused;
+                let mux = Mux::get().unwrap();
+                mux.notify(MuxNotification::PaneOutputPaused {
+                    pane_id: self.local_pane_id,
+                    paused,
+                });
+            }
+            Pdu::PanePaletteOverridesChanged(PanePaletteOverridesChanged { overrides, .. }) => {
+                self.update_palette_overrides(overrides);
+            }
+            Pdu::PaneMarkersChanged(PaneMarkersChanged { markers, .. }) => {
+                self.set_markers(markers);
+            }
+            Pdu::ScrollPaneToRow(ScrollPaneToRow { row, .. }) => {
+                let mux = Mux::get().unwrap();
+                mux.notify(MuxNotification::ScrollToRow {
+                    pane_id: self.local_pane_id,
+                    row,
+                });
+            }
+            Pdu::OutputWatcherMatched(OutputWatcherMatched { mut matched }) => {
+                matched.pane_id = self.local_pane_id;
+                let mux = Mux::get().unwrap();
+                mux.notify(MuxNotification::OutputMatched {
+                    owner: None,
+                    matched,
+                });
+            }
             Pdu::PaneRemoved(PaneRemoved { pane_id }) => {
                 log::trace!("remote pane {} has been removed", pane_id);
                 self.renderable.borrow().inner.borrow_mut().dead = true;
@@ -173,10 +426,11 @@ impl ClientPane {
 
     /// Arrange to suppress the next Pane::kill call.
     /// This is a bit of a hack that we use when closing a window;
-    /// our Domain::local_window_is_closing impl calls this for each
-    /// ClientPane in the window so that closing a window effectively
-    /// "detaches" the window so that reconnecting later will resume
-    /// from where they left off.
+    /// our Domain::local_window_is_closing and Domain::tab_is_closing
+    /// impls call this for each ClientPane in the window or tab when
+    /// its domain is configured to detach, so that closing a window
+    /// effectively "detaches" the window so that reconnecting later
+    /// will resume from where they left off.
     /// It isn't perfect.
     pub fn ignore_next_kill(&self) {
         *self.ignore_next_kill.borrow_mut() = true;
@@ -196,6 +450,25 @@ impl Pane for ClientPane {
     fn get_dimensions(&self) -> RenderableDimensions {
         self.renderable.borrow().get_dimensions()
     }
+
+    fn get_size(&self) -> TerminalSize {
+        let dims = self.get_dimensions();
+        let size = *self.size.borrow();
+        if size.cols == dims.cols && size.rows == dims.viewport_rows {
+            size
+        } else {
+            // The server resized the pane on behalf of another client,
+            // so the pixel dimensions that we know of no longer apply
+            TerminalSize {
+                rows: dims.viewport_rows,
+                cols: dims.cols,
+                pixel_width: 0,
+                pixel_height: 0,
+                dpi: dims.dpi,
+            }
+        }
+    }
+
     fn get_lines(&self, lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>) {
         self.renderable.borrow().get_lines(lines)
     }
@@ -204,6 +477,75 @@ impl Pane for ClientPane {
         self.renderable.borrow().get_current_seqno()
     }
 
+    /// The rows are fetched one by one, and the rules are applied to
+    /// each of them as they arrive, which misses the links that wrap
+    /// onto another row.  Those are found by the server, which has the
+    /// whole of the logical lines, and are applied here once it has
+    /// returned them.  Until then, or if the pane has changed since,
+    /// the links from the most recent scan are applied, and a
+    /// `PaneOutput` notification is sent if a new scan finds different
+    /// links.
+    fn get_lines_with_hyperlinks_applied(
+        &self,
+        lines: Range<StableRowIndex>,
+        rules: &[Rule],
+    ) -> (StableRowIndex, Vec<Line>) {
+        let (first, mut phys) = self.get_lines(lines.clone());
+
+        let seqno = self.get_current_seqno();
+        let mut state = self.hyperlinks.borrow_mut();
+        let same_rules = state.rules.len() == rules.len()
+            && state.rules.iter().zip(rules.iter()).all(|(spec, rule)| {
+                spec.regex == rule.regex.as_str() && spec.format == rule.format
+            });
+        let covered = state.range.start <= lines.start && state.range.end >= lines.end;
+        if !rules.is_empty()
+            && !state.fetching
+            && (state.links.is_none() || state.seqno != seqno || !same_rules || !covered)
+        {
+            // Scan a viewport's worth of rows either side, so that
+            // scrolling or hovering nearby doesn't need another scan
+            let margin = self.get_dimensions().viewport_rows as StableRowIndex;
+            state.fetching = true;
+            self.fetch_hyperlinks(
+                seqno,
+                rules.iter().map(HyperlinkRuleSpec::from).collect(),
+                lines.start.saturating_sub(margin)..lines.end + margin,
+            );
+        }
+        if !same_rules {
+            return (first, phys);
+        }
+
+        for link in state.links.iter().flatten() {
+            for y in link.start_y.max(first)..=link.end_y {
+                let line = match phys.get_mut((y - first) as usize) {
+                    Some(line) => line,
+                    None => break,
+                };
+                let cells = line.cells_mut_for_attr_changes_only();
+                let start_x = if y == link.start_y { link.start_x } else { 0 };
+                let end_x = if y == link.end_y {
+                    link.end_x.min(cells.len())
+                } else {
+                    cells.len()
+                };
+                for cell in cells.iter_mut().take(end_x).skip(start_x) {
+                    let attrs = cell.attrs_mut();
+                    // Replace the links that the rules made from just
+                    // this row, but not explicit links
+                    if attrs
+                        .hyperlink()
+                        .map_or(true, |existing| existing.is_implicit())
+                    {
+                        attrs.set_hyperlink(Some(Arc::clone(&link.link)));
+                    }
+                }
+            }
+        }
+        (first, phys)
+    }
+
     fn get_changed_since(
         &self,
         lines: Range<StableRowIndex>,
@@ -238,6 +580,7 @@ impl Pane for ClientPane {
                 .send_paste(SendPaste {
                     pane_id: remote_pane_id,
                     data,
+                    from_cli: false,
                 })
                 .await
         })
@@ -287,12 +630,18 @@ impl Pane for ClientPane {
         let cols = size.cols as usize;
         let rows = size.rows as usize;
 
-        if inner.dimensions.cols != cols || inner.dimensions.viewport_rows != rows {
-            inner.dimensions.cols = cols;
-            inner.dimensions.viewport_rows = rows;
+        // The pixel dimensions matter to applications that draw images,
+        // so a change to them alone is sent along too
+        let cells_changed = inner.dimensions.cols != cols || inner.dimensions.viewport_rows != rows;
+        if cells_changed || *self.size.borrow() != size {
+            *self.size.borrow_mut() = size;
+            if cells_changed {
+                inner.dimensions.cols = cols;
+                inner.dimensions.viewport_rows = rows;
 
-            // Invalidate any cached rows on a resize
-            inner.make_all_stale();
+                // Invalidate any cached rows on a resize
+                inner.make_all_stale();
+            }
 
             let client = Arc::clone(&self.client);
             let remote_pane_id = self.remote_pane_id;
@@ -313,6 +662,23 @@ impl Pane for ClientPane {
         Ok(())
     }
 
+    /// The zones are computed by the server, so this returns the zones
+    /// from the most recent fetch, or no zones if there hasn't been one.
+    /// If the pane has changed since then, a new fetch is started, and
+    /// a `PaneOutput` notification is sent if it turns up different
+    /// zones, so that the caller can ask again.
+    fn get_semantic_zones(&self) -> anyhow::Result<Vec<SemanticZone>> {
+        let seqno = self.get_current_seqno();
+        let mut state = self.semantic_zones.borrow_mut();
+        let zones = state.zones.clone().unwrap_or_default();
+        if (state.zones.is_none() || state.seqno != seqno) && !state.fetching {
+            state.fetching = true;
+            drop(state);
+            self.fetch_semantic_zones(seqno);
+        }
+        Ok(zones)
+    }
+
     async fn search(&self, pattern: Pattern) -> anyhow::Result<Vec<SearchResult>> {
         match self
             .client
@@ -328,6 +694,298 @@ impl Pane for ClientPane {
         }
     }
 
+    async fn search_with_snippets(
+        &self,
+        pattern: Pattern,
+        limit: usize,
+    ) -> anyhow::Result<GlobalSearchResults> {
+        // The server has the whole scrollback, and the lines around
+        // the matches, so search it there
+        let mut results = self
+            .client
+            .client
+            .search_panes(SearchPanes {
+                pattern,
+                options: GlobalSearchOptions {
+                    pane_id: Some(self.remote_pane_id),
+                    max_matches_per_pane: limit,
+                    ..GlobalSearchOptions::default()
+                },
+            })
+            .await?
+            .results;
+        for found in &mut results.matches {
+            found.pane_id = self.local_pane_id;
+        }
+        for pane_id in &mut results.truncated {
+            *pane_id = self.local_pane_id;
+        }
+        Ok(results)
+    }
+
+    async fn scan(
+        &self,
+        patterns: Vec<String>,
+        range: Range<StableRowIndex>,
+    ) -> anyhow::Result<Vec<ScanMatch>> {
+        // The server has the whole scrollback, so match it there
+        let ScanPaneResponse { matches, .. } = self
+            .client
+            .client
+            .scan_pane(ScanPane {
+                pane_id: self.remote_pane_id,
+                patterns,
+                hyperlink_rules: vec![],
+                range,
+            })
+            .await?;
+        Ok(matches)
+    }
+
+    async fn scan_hyperlinks(
+        &self,
+        rules: &[Rule],
+        range: Range<StableRowIndex>,
+    ) -> anyhow::Result<Vec<HyperlinkMatch>> {
+        let ScanPaneResponse { hyperlinks, .. } = self
+            .client
+            .client
+            .scan_pane(ScanPane {
+                pane_id: self.remote_pane_id,
+                patterns: vec![],
+                hyperlink_rules: rules.iter().map(HyperlinkRuleSpec::from).collect(),
+                range,
+            })
+            .await?;
+        Ok(hyperlinks)
+    }
+
+    async fn render_scrollback(
+        &self,
+        format: ScrollbackFormat,
+        range: Option<Range<StableRowIndex>>,
+        header: bool,
+    ) -> anyhow::Result<ScrollbackChunk> {
+        // The server holds the whole scrollback, and the palette
+        let RenderScrollbackResponse { chunk } = self
+            .client
+            .client
+            .render_scrollback(RenderScrollback {
+                pane_id: self.remote_pane_id,
+                format,
+                range,
+                header,
+            })
+            .await?;
+        Ok(chunk)
+    }
+
+    async fn query_foreground_process(&self) -> anyhow::Result<Option<ForegroundProcessInfo>> {
+        let response = self
+            .client
+            .client
+            .get_foreground_process(GetForegroundProcess {
+                pane_id: self.remote_pane_id,
+            })
+            .await?;
+        Ok(response.info)
+    }
+
+    fn record_view_state(&self, state: PaneViewState) {
+        if *self.view_state.borrow() == state {
+            return;
+        }
+        *self.view_state.borrow_mut() = state;
+        if self.view_state_pending.replace(true) {
+            return;
+        }
+        let client = Arc::clone(&self.client);
+        let local_pane_id = self.local_pane_id;
+        let remote_pane_id = self.remote_pane_id;
+        promise::spawn::spawn(async move {
+            smol::Timer::after(VIEW_STATE_DELAY).await;
+            let pane = match Mux::get().and_then(|mux| mux.get_pane(local_pane_id)) {
+                Some(pane) => pane,
+                None => return,
+            };
+            let state = match pane.downcast_ref::<ClientPane>() {
+                Some(pane) => {
+                    pane.view_state_pending.replace(false);
+                    *pane.view_state.borrow()
+                }
+                None => return,
+            };
+            if let Err(err) = client
+                .client
+                .set_pane_view_state(SetPaneViewState {
+                    pane_id: remote_pane_id,
+                    state,
+                })
+                .await
+            {
+                log::debug!(
+                    "failed to report the view of pane {}: {:#}",
+                    remote_pane_id,
+                    err
+                );
+            }
+        })
+        .detach();
+    }
+
+    fn take_restored_view_state(&self) -> Option<PaneViewState> {
+        self.restored_view_state.borrow_mut().take()
+    }
+
+    async fn edit_scrollback(&self, request: EditScrollbackRequest) -> anyhow::Result<PaneId> {
+        if request.local {
+            // The lines are copied down, and edited here
+            return mux::editscrollback::edit_here(self.local_pane_id, request).await;
+        }
+        let response = self
+            .client
+            .client
+            .edit_scrollback(EditScrollback {
+                pane_id: self.remote_pane_id,
+                request,
+            })
+            .await?;
+
+        // Attach the pane of the editor that the server split off
+        let mux = Mux::get().unwrap();
+        let local_domain_id = self.client.local_domain_id;
+        let domain = mux
+            .get_domain(local_domain_id)
+            .ok_or_else(|| anyhow::anyhow!("no such domain {}", local_domain_id))?;
+        let domain = domain.downcast_ref::<ClientDomain>().ok_or_else(|| {
+            anyhow::anyhow!("domain {} is not a ClientDomain instance", local_domain_id)
+        })?;
+        domain.resync().await?;
+        domain
+            .remote_to_local_pane_id(response.pane_id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "the pane {} of the editor was not attached",
+                    response.pane_id
+                )
+            })
+    }
+
+    async fn change_dir(&self, dir: String, enter: bool) -> anyhow::Result<()> {
+        // Only the server knows the foreground process of the pane
+        self.client
+            .client
+            .change_dir(ChangeDir {
+                pane_id: self.remote_pane_id,
+                dir,
+                enter,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn add_bookmark(
+        &self,
+        name: String,
+        row: Option<StableRowIndex>,
+    ) -> anyhow::Result<Bookmark> {
+        // The server keeps the bookmarks, so that they are shared by
+        // all of its clients and can be trimmed along with its
+        // scrollback
+        let AddBookmarkResponse { bookmark } = self
+            .client
+            .client
+            .add_bookmark(AddBookmark {
+                pane_id: self.remote_pane_id,
+                name,
+                row,
+            })
+            .await?;
+        Ok(bookmark)
+    }
+
+    async fn get_bookmarks(&self) -> anyhow::Result<Vec<Bookmark>> {
+        let GetBookmarksResponse { bookmarks } = self
+            .client
+            .client
+            .get_bookmarks(GetBookmarks {
+                pane_id: self.remote_pane_id,
+            })
+            .await?;
+        Ok(bookmarks)
+    }
+
+    async fn add_output_watcher(&self, spec: OutputWatcherSpec) -> anyhow::Result<WatcherId> {
+        // The server watches the output, so that only the matches have
+        // to be sent to us
+        let AddOutputWatcherResponse { watcher_id } = self
+            .client
+            .client
+            .add_output_watcher(AddOutputWatcher {
+                pane_id: self.remote_pane_id,
+                spec,
+            })
+            .await?;
+        Ok(watcher_id)
+    }
+
+    async fn remove_output_watcher(&self, watcher_id: WatcherId) -> anyhow::Result<()> {
+        self.client
+            .client
+            .remove_output_watcher(RemoveOutputWatcher {
+                pane_id: self.remote_pane_id,
+                watcher_id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn get_output_watchers(&self) -> anyhow::Result<Vec<OutputWatcherInfo>> {
+        let ListOutputWatchersResponse { watchers } = self
+            .client
+            .client
+            .list_output_watchers(ListOutputWatchers {
+                pane_id: self.remote_pane_id,
+            })
+            .await?;
+        Ok(watchers)
+    }
+
+    async fn add_marker(&self, marker: Marker) -> anyhow::Result<Marker> {
+        // The server tells us about the new marker with a
+        // PaneMarkersChanged, just as it tells its other clients
+        let AddMarkerResponse { marker } = self
+            .client
+            .client
+            .add_marker(AddMarker {
+                pane_id: self.remote_pane_id,
+                marker,
+            })
+            .await?;
+        Ok(marker)
+    }
+
+    async fn remove_marker(&self, id: String) -> anyhow::Result<()> {
+        self.client
+            .client
+            .remove_marker(RemoveMarker {
+                pane_id: self.remote_pane_id,
+                id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn get_markers(&self) -> Vec<Marker> {
+        let scrollback_top = self.get_dimensions().scrollback_top;
+        self.markers
+            .borrow()
+            .iter()
+            .filter(|marker| marker.end_line >= scrollback_top)
+            .cloned()
+            .collect()
+    }
+
     fn key_down(&self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
         let input_serial;
         {
@@ -464,14 +1122,71 @@ impl Pane for ClientPane {
     }
 
     fn is_alt_screen_active(&self) -> bool {
-        // FIXME: retrieve this from the remote
-        false
+        self.modes
+            .borrow()
+            .map(|modes| modes.alt_screen)
+            .unwrap_or(false)
+    }
+
+    fn get_modes(&self) -> Option<TerminalModes> {
+        *self.modes.borrow()
     }
 
     fn get_current_working_dir(&self) -> Option<Url> {
         self.renderable.borrow().inner.borrow().working_dir.clone()
     }
 
+    fn get_resource_usage(&self) -> Option<PaneResourceUsage> {
+        *self.resource_usage.borrow()
+    }
+
+    fn get_spawn_label(&self) -> Option<String> {
+        self.spawn_label.borrow().clone()
+    }
+
+    fn set_output_paused(&self, paused: bool) -> anyhow::Result<()> {
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_pane_output_paused(SetPaneOutputPaused {
+                    pane_id: remote_pane_id,
+                    paused,
+                })
+                .await
+        })
+        .detach();
+        Ok(())
+    }
+
+    fn is_output_paused(&self) -> bool {
+        *self.output_paused.borrow()
+    }
+
+    fn set_palette_overrides(&self, overrides: Option<Palette>) -> anyhow::Result<()> {
+        // Apply them right away, rather than waiting for the server
+        // to report them back
+        self.update_palette_overrides(overrides.clone());
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_pane_palette_overrides(SetPanePaletteOverrides {
+                    pane_id: remote_pane_id,
+                    overrides,
+                })
+                .await
+        })
+        .detach();
+        Ok(())
+    }
+
+    fn get_palette_overrides(&self) -> Option<Palette> {
+        self.palette_overrides.borrow().clone()
+    }
+
     fn focus_changed(&self, focused: bool) {
         if focused {
             self.advise_focus();
@@ -497,11 +1212,16 @@ impl Pane for ClientPane {
     }
 
     fn can_close_without_prompting(&self, reason: CloseReason) -> bool {
-        match reason {
-            CloseReason::Window => true,
-            CloseReason::Tab => false,
-            CloseReason::Pane => false,
-        }
+        // Panes that are detached rather than killed don't need
+        // confirmation, and asking about them is handled separately
+        let domain = Mux::get().and_then(|mux| mux.get_domain(self.client.local_domain_id));
+        let policy = match (reason, domain) {
+            (CloseReason::Window, Some(domain)) => domain.window_close_policy(),
+            (CloseReason::Tab, Some(domain)) => domain.tab_close_policy(),
+            (CloseReason::Window, None) => ClosePolicy::Detach,
+            (CloseReason::Tab, None) | (CloseReason::Pane, _) => ClosePolicy::Kill,
+        };
+        policy != ClosePolicy::Kill
     }
 
     fn copy_user_vars(&self) -> HashMap<String, String> {
@@ -519,6 +1239,7 @@ impl std::io::Write for PaneWriter {
         promise::spawn::block_on(self.client.client.write_to_pane(WriteToPane {
             pane_id: self.remote_pane_id,
             data: data.to_vec(),
+            from_cli: false,
         }))
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
         Ok(data.len())
@@ -5,7 +5,7 @@ use codec::*;
 use config::{configuration, ConfigHandle};
 use lru::LruCache;
 use mux::pane::PaneId;
-use mux::renderable::{RenderableDimensions, StableCursorPosition};
+use mux::renderable::{lines_checksum, RenderableDimensions, StableCursorPosition};
 use mux::Mux;
 use promise::BrokenPromise;
 use rangeset::*;
@@ -52,6 +52,27 @@ impl LineEntry {
     }
 }
 
+/// Describes how we found our copy of a pane to be out of step with
+/// the server
+#[derive(Debug)]
+enum Desync {
+    /// An update was missed, or was applied out of order
+    Gap { expected: u64, received: u64 },
+    /// The checksum of the viewport didn't match that of the server
+    Checksum {
+        expected: u64,
+        actual: u64,
+        push_serial: u64,
+    },
+}
+
+/// A snapshot that was requested to recover from a desync
+struct PendingResync {
+    desync: Desync,
+    seqno: SequenceNo,
+    since: Instant,
+}
+
 pub struct RenderableInner {
     pub client: Arc<ClientInner>,
     remote_pane_id: PaneId,
@@ -68,6 +89,11 @@ pub struct RenderableInner {
     pub title: String,
     pub working_dir: Option<Url>,
     pub seqno: SequenceNo,
+    /// The `push_serial` of the most recent update from the server
+    push_serial: u64,
+    /// Set while waiting for the snapshot that was requested to recover
+    /// from a desync, so that only one is requested at a time
+    resync: Option<PendingResync>,
 
     fetch_limiter: RateLimiter,
 
@@ -114,6 +140,8 @@ impl RenderableInner {
             last_input_rtt: 0,
             input_serial: InputSerial::empty(),
             seqno: SEQ_ZERO,
+            push_serial: 0,
+            resync: None,
         }
     }
 
@@ -312,6 +340,36 @@ impl RenderableInner {
         self.poll_interval = BASE_POLL_INTERVAL;
         self.last_recv_time = now;
 
+        let expected_serial = self.push_serial + 1;
+        self.push_serial = delta.push_serial;
+        if delta.snapshot {
+            if let Some(resync) = self.resync.take() {
+                log::warn!(
+                    "desync recovered: local_pane_id={} remote_pane_id={} {:?} \
+                     detected_seqno={} snapshot_push_serial={} snapshot_seqno={} \
+                     elapsed={:?}",
+                    self.local_pane_id,
+                    self.remote_pane_id,
+                    resync.desync,
+                    resync.seqno,
+                    delta.push_serial,
+                    delta.seqno,
+                    resync.since.elapsed()
+                );
+                metrics::counter!("mux.desync.recovered", 1);
+            }
+            // The server won't tell us which of the lines that we kept
+            // from a prior session have changed, so refresh them as they
+            // are displayed.  The lines that matter right now are in
+            // bonus_lines.
+            self.make_all_stale();
+        } else if delta.push_serial != expected_serial {
+            self.recover_from_desync(Desync::Gap {
+                expected: expected_serial,
+                received: delta.push_serial,
+            });
+        }
+
         let mut dirty = RangeSet::new();
         for r in delta.dirty_lines {
             dirty.add_range(r.clone());
@@ -347,7 +405,10 @@ impl RenderableInner {
         }
         self.dimensions = delta.dimensions;
         self.title = delta.title;
-        self.working_dir = delta.working_dir.map(Into::into);
+        self.working_dir = delta
+            .working_dir
+            .map(Into::<Url>::into)
+            .filter(wezterm_term::cwd::is_safe_cwd_url);
         log::trace!(
             "server says: seqno from {} -> {} for local_pane_id={}",
             self.seqno,
@@ -363,6 +424,10 @@ impl RenderableInner {
             dirty.remove(stable_row);
         }
 
+        if let Some(checksum) = delta.checksum {
+            self.verify_checksum(checksum, delta.push_serial);
+        }
+
         log::trace!(
             "apply_changes_to_surface: Generate PaneOutput event for local={}",
             self.local_pane_id
@@ -420,6 +485,86 @@ impl RenderableInner {
         }
     }
 
+    /// Compares the checksum of the viewport that the server sent with
+    /// that of our copy, which is only possible while we hold all of
+    /// its rows
+    fn verify_checksum(&mut self, expected: u64, push_serial: u64) {
+        let top = self.dimensions.physical_top;
+        let mut lines = vec![];
+        for stable_row in top..top + self.dimensions.viewport_rows as StableRowIndex {
+            match self.lines.peek(&stable_row) {
+                Some(LineEntry::Line(line)) => lines.push(line),
+                entry => {
+                    log::trace!(
+                        "skip checksum for local_pane_id={}: row {} is {:?}",
+                        self.local_pane_id,
+                        stable_row,
+                        entry.map(|e| e.kind())
+                    );
+                    return;
+                }
+            }
+        }
+        let actual = lines_checksum(lines);
+        if actual != expected {
+            self.recover_from_desync(Desync::Checksum {
+                expected,
+                actual,
+                push_serial,
+            });
+        }
+    }
+
+    /// Asks the server for a snapshot of the pane, unless one is
+    /// already on its way
+    fn recover_from_desync(&mut self, desync: Desync) {
+        if self.resync.is_some() {
+            return;
+        }
+        log::warn!(
+            "desync detected: local_pane_id={} remote_pane_id={} {:?} seqno={}; \
+             requesting a snapshot",
+            self.local_pane_id,
+            self.remote_pane_id,
+            desync,
+            self.seqno
+        );
+        self.resync.replace(PendingResync {
+            desync,
+            seqno: self.seqno,
+            since: Instant::now(),
+        });
+
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        let local_pane_id = self.local_pane_id;
+        promise::spawn::spawn(async move {
+            if let Err(err) = client
+                .client
+                .request_pane_snapshot(RequestPaneSnapshot {
+                    pane_id: remote_pane_id,
+                })
+                .await
+            {
+                log::error!(
+                    "failed to request a snapshot of remote pane {}: {:#}",
+                    remote_pane_id,
+                    err
+                );
+                // Let the next desync try again
+                let mux = Mux::get().unwrap();
+                if let Some(pane) = mux.get_pane(local_pane_id) {
+                    if let Some(client_pane) = pane.downcast_ref::<ClientPane>() {
+                        let renderable = client_pane.renderable.borrow_mut();
+                        renderable.inner.borrow_mut().resync.take();
+                    }
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+        .detach();
+    }
+
     pub fn make_all_stale(&mut self) {
         let mut lines = LruCache::unbounded();
         while let Some((stable_row, entry)) = self.lines.pop_lru() {
@@ -643,7 +788,11 @@ pub(crate) async fn hydrate_lines(
     pane_id: PaneId,
     serialized_lines: SerializedLines,
 ) -> Vec<(StableRowIndex, Line)> {
-    let (lines, image_cells) = serialized_lines.extract_data();
+    let (lines, mut image_cells) = serialized_lines.extract_data();
+    // Images beyond the negotiated limits are never requested, and the
+    // cells that reference them are left without an image
+    let limits = client.client.image_limits();
+    image_cells.retain(|im| limits.allows_cell(im));
 
     if image_cells.is_empty() {
         return lines;
@@ -0,0 +1,200 @@
+//! Speaks the mux protocol over the stdio of a command, in place of a
+//! direct connection.  This is typically something like
+//! `kubectl exec -i pod -- wezterm cli proxy`, which tunnels through
+//! a transport that wezterm doesn't know about to `wezterm cli proxy`
+//! on the other side.
+use crate::UnixStream;
+use anyhow::{anyhow, Context};
+use std::io::Read;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How much of the stderr of the command is kept for error messages
+const STDERR_LIMIT: usize = 4096;
+
+/// Collects the most recent stderr output of a proxy command, so that
+/// it can be shown to the user when the connection fails.
+/// Clones share the same buffer, which is cleared each time that a
+/// command is spawned.
+#[derive(Clone, Default, Debug)]
+pub struct ProxyStderr {
+    inner: Arc<Mutex<String>>,
+}
+
+impl ProxyStderr {
+    fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+
+    fn append(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.push_str(&String::from_utf8_lossy(data));
+        if inner.len() > STDERR_LIMIT {
+            let mut start = inner.len() - STDERR_LIMIT;
+            while !inner.is_char_boundary(start) {
+                start += 1;
+            }
+            inner.drain(..start);
+        }
+    }
+
+    /// Returns the captured output, or None if there was none
+    pub fn text(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let text = inner.trim();
+        if text.is_empty() {
+            None
+        } else {
+            Some(text.to_string())
+        }
+    }
+
+    /// Adds the captured output, if any, to `message`
+    pub fn annotate(&self, message: &str) -> String {
+        match self.text() {
+            Some(text) => format!("{}\nThe proxy command reported:\n{}", message, text),
+            None => message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyCommand {
+    argv: Vec<String>,
+}
+
+impl ProxyCommand {
+    pub fn new(argv: Vec<String>) -> anyhow::Result<Self> {
+        if argv.is_empty() {
+            anyhow::bail!("proxy_command must not be empty");
+        }
+        Ok(Self { argv })
+    }
+
+    /// Spawns the command and returns a stream connected to its stdin
+    /// and stdout.  Its stderr is collected into `stderr`, and is
+    /// included in the error if the command exits straight away.
+    pub fn spawn(&self, stderr: &ProxyStderr) -> anyhow::Result<UnixStream> {
+        let mut cmd = std::process::Command::new(&self.argv[0]);
+        cmd.args(&self.argv[1..]);
+
+        let (a, b) = filedescriptor::socketpair()?;
+
+        cmd.stdin(b.as_stdio()?);
+        cmd.stdout(b.as_stdio()?);
+        cmd.stderr(std::process::Stdio::piped());
+        stderr.clear();
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("spawning proxy command {:?}", self.argv))?;
+        // Release our copies of the child's end, so that we see EOF
+        // when the child exits
+        drop(cmd);
+        drop(b);
+
+        let (stderr_done_tx, stderr_done) = channel();
+        if let Some(mut child_stderr) = child.stderr.take() {
+            let stderr = stderr.clone();
+            let argv = self.argv.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Ok(len) = child_stderr.read(&mut buf) {
+                    if len == 0 {
+                        break;
+                    }
+                    let data = &buf[0..len];
+                    log::warn!("{:?} stderr: {}", argv, String::from_utf8_lossy(data));
+                    stderr.append(data);
+                }
+                stderr_done_tx.send(()).ok();
+            });
+        }
+
+        // Grace period to detect whether connection failed
+        for _ in 0..5 {
+            std::thread::sleep(Duration::from_millis(10));
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    // Give the reader a moment to collect what the
+                    // command said on its way out
+                    stderr_done.recv_timeout(Duration::from_millis(500)).ok();
+                    return Err(anyhow!(stderr.annotate(&format!(
+                        "proxy command {:?} exited already with status {}",
+                        self.argv, status
+                    ))));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("spawning proxy command {:?}", self.argv));
+                }
+            }
+        }
+
+        let argv = self.argv.clone();
+        std::thread::spawn(move || match child.wait() {
+            Err(err) => log::error!("waiting on {:?} failed: {:#}", argv, err),
+            Ok(status) if !status.success() => log::error!("{:?}: {}", argv, status),
+            _ => {}
+        });
+
+        #[cfg(unix)]
+        unsafe {
+            use std::os::unix::io::{FromRawFd, IntoRawFd};
+            Ok(UnixStream::from_raw_fd(a.into_raw_fd()))
+        }
+        #[cfg(windows)]
+        unsafe {
+            use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+            Ok(UnixStream::from_raw_socket(a.into_raw_socket()))
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn command(argv: &[&str]) -> ProxyCommand {
+        ProxyCommand::new(argv.iter().map(|s| s.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn loopback() {
+        let stderr = ProxyStderr::default();
+        let mut stream = command(&["cat"]).spawn(&stderr).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(stderr.text(), None);
+    }
+
+    #[test]
+    fn early_exit_reports_stderr() {
+        let stderr = ProxyStderr::default();
+        let err = command(&["sh", "-c", "echo no such pod >&2; exit 3"])
+            .spawn(&stderr)
+            .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("exited already"), "{}", message);
+        assert!(message.contains("no such pod"), "{}", message);
+    }
+
+    #[test]
+    fn empty_command_is_rejected() {
+        assert!(ProxyCommand::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn stderr_keeps_the_tail() {
+        let stderr = ProxyStderr::default();
+        stderr.append(&vec![b'a'; STDERR_LIMIT]);
+        stderr.append(b"the end");
+        let text = stderr.text().unwrap();
+        assert_eq!(text.len(), STDERR_LIMIT);
+        assert!(text.ends_with("the end"));
+    }
+}
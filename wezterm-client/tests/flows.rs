@@ -0,0 +1,121 @@
+//! The flows of a client domain that is attached to a server, run
+//! against both muxes in the same process; see `harness.rs`.
+use crate::harness::Harness;
+use mux::domain::DomainState;
+use mux::tab::{SplitDirection, SplitRequest};
+use wezterm_term::TerminalSize;
+
+#[test]
+fn spawn() {
+    let mut harness = Harness::new();
+    let (_tab_id, pane_id) = harness.spawn_tab();
+    harness.assert_in_sync();
+
+    let server_pane_id = harness.server_pane_id(pane_id);
+    assert_eq!(harness.server_tabs().len(), 1);
+    harness.on_server(|mux| assert!(mux.get_pane(server_pane_id).is_some()));
+}
+
+#[test]
+fn split() {
+    let mut harness = Harness::new();
+    let (_tab_id, pane_id) = harness.spawn_tab();
+    let right = harness.split_pane(pane_id, SplitRequest::default());
+    harness.split_pane(
+        right,
+        SplitRequest {
+            direction: SplitDirection::Vertical,
+            ..Default::default()
+        },
+    );
+    harness.assert_in_sync();
+
+    let tabs = harness.server_tabs();
+    assert_eq!(tabs.len(), 1);
+    assert_eq!(tabs[0].len(), 3);
+}
+
+#[test]
+fn resize() {
+    let mut harness = Harness::new();
+    let (tab_id, pane_id) = harness.spawn_tab();
+    let size = TerminalSize {
+        rows: 40,
+        cols: 100,
+        ..Default::default()
+    };
+    harness.on_client(|mux| mux.get_tab(tab_id).unwrap().resize(size));
+    harness.assert_in_sync();
+
+    let server_pane_id = harness.server_pane_id(pane_id);
+    let server_size =
+        harness.on_server(|mux| mux.get_pane(server_pane_id).unwrap().get_dimensions());
+    assert_eq!(server_size.viewport_rows, 40);
+    assert_eq!(server_size.cols, 100);
+}
+
+fn send_text(harness: &mut Harness) {
+    let (_tab_id, pane_id) = harness.spawn_tab();
+    harness.on_client(|mux| {
+        let pane = mux.get_pane(pane_id).unwrap();
+        pane.send_paste("hello\rworld").unwrap();
+    });
+    harness.assert_in_sync();
+
+    let server_pane_id = harness.server_pane_id(pane_id);
+    assert_eq!(harness.server_text(server_pane_id), "hello\nworld");
+    assert_eq!(harness.client_text(pane_id), "hello\nworld");
+}
+
+#[test]
+fn send_text_without_latency() {
+    send_text(&mut Harness::new());
+}
+
+#[test]
+fn send_text_with_latency() {
+    let mut harness = Harness::new();
+    harness.link().set_latency(5);
+    send_text(&mut harness);
+}
+
+#[test]
+fn detach_and_reattach() {
+    let mut harness = Harness::new();
+    let (_tab_id, pane_id) = harness.spawn_tab();
+    harness.split_pane(pane_id, SplitRequest::default());
+    harness.on_client(|mux| {
+        mux.get_pane(pane_id)
+            .unwrap()
+            .send_paste("still here")
+            .unwrap();
+    });
+    harness.assert_in_sync();
+    let before = harness.server_tabs();
+
+    harness.detach();
+    assert_eq!(harness.domain_state(), DomainState::Detached);
+    assert!(harness.client_tabs().is_empty());
+    // Detaching leaves the panes running on the server
+    assert_eq!(harness.server_tabs(), before);
+
+    harness.attach();
+    assert_eq!(harness.domain_state(), DomainState::Attached);
+    harness.assert_in_sync();
+    assert_eq!(harness.server_tabs(), before);
+}
+
+#[test]
+fn dropped_connection() {
+    let mut harness = Harness::new();
+    let (_tab_id, pane_id) = harness.spawn_tab();
+    harness.assert_in_sync();
+    let before = harness.server_tabs();
+
+    harness.link().sever();
+    harness.settle();
+    assert_eq!(harness.domain_state(), DomainState::Detached);
+    harness.on_client(|mux| assert!(mux.get_pane(pane_id).is_none()));
+    // The server keeps the panes of a client that went away
+    assert_eq!(harness.server_tabs(), before);
+}
@@ -0,0 +1,849 @@
+//! Runs a server mux and a client mux in the same thread, with the
+//! client domain attached to the server over an in-memory link rather
+//! than a socket, so that the protocol can be tested end to end.
+//!
+//! Each mux has a queue of its own for the futures that it spawns; see
+//! `promise::spawn::Schedulers`.  Nothing runs until the test pumps the
+//! queues, one round at a time, so the order in which things happen
+//! only depends on the test.  The link can delay the bytes that cross
+//! it by a number of rounds, or be severed, to check how the client
+//! copes with a slow or broken connection.
+//!
+//! The panes of the server are `EchoPane`s, which show whatever is
+//! written to them as a tty in cooked mode would, without running any
+//! program or thread.
+use async_trait::async_trait;
+use config::keyassignment::{CommandDir, SpawnTabDomain};
+use config::UnixDomain;
+use mux::builder::MuxBuilder;
+use mux::domain::{Domain, DomainId, DomainResult, DomainState};
+use mux::pane::{Pane, PaneId};
+use mux::renderable::*;
+use mux::tab::{SplitRequest, TabId};
+use mux::Mux;
+use portable_pty::CommandBuilder;
+use promise::spawn::{Runnable, ScheduleFunc, Schedulers};
+use rangeset::RangeSet;
+use smol::channel::Receiver;
+use smol::prelude::*;
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use termwiz::surface::{Line, SequenceNo};
+use url::Url;
+use wezterm_client::client::{AsyncReadAndWrite, Client};
+use wezterm_client::domain::{ClientDomain, ClientDomainConfig};
+use wezterm_client::pane::ClientPane;
+use wezterm_mux_server_impl::dispatch::{self, ClientStream};
+use wezterm_term::color::ColorPalette;
+use wezterm_term::{KeyCode, KeyModifiers, MouseEvent, StableRowIndex, Terminal, TerminalSize};
+
+/// The most rounds that the harness pumps while waiting for something
+const MAX_ROUNDS: usize = 10_000;
+
+/// One direction of a `Link`
+#[derive(Debug, Default)]
+struct Pipe {
+    /// Writes that have yet to arrive, along with the round in which
+    /// they do
+    in_flight: VecDeque<(u64, Vec<u8>)>,
+    /// The bytes that have arrived and are waiting to be read
+    arrived: VecDeque<u8>,
+    /// The task that is waiting for bytes to arrive
+    waker: Option<Waker>,
+    /// Set once either end has gone away; the reader sees EOF after
+    /// what is already in the pipe, and writes fail
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&mut self) -> Option<Waker> {
+        self.closed = true;
+        self.waker.take()
+    }
+
+    fn poll_readable(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.arrived.is_empty() || (self.closed && self.in_flight.is_empty()) {
+            Poll::Ready(Ok(()))
+        } else {
+            self.waker.replace(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    /// From the client to the server, and from the server to the client
+    pipes: [Pipe; 2],
+    round: u64,
+    /// How many rounds it takes for a write to arrive
+    latency: u64,
+}
+
+const TO_SERVER: usize = 0;
+const TO_CLIENT: usize = 1;
+
+/// An in-memory connection between the client and the server
+#[derive(Clone, Debug, Default)]
+pub struct Link {
+    state: Arc<Mutex<LinkState>>,
+}
+
+impl Link {
+    /// Returns the ends of the link for the client and for the server
+    fn endpoints(&self) -> (Endpoint, Endpoint) {
+        (
+            Endpoint {
+                state: Arc::clone(&self.state),
+                read: TO_CLIENT,
+                write: TO_SERVER,
+            },
+            Endpoint {
+                state: Arc::clone(&self.state),
+                read: TO_SERVER,
+                write: TO_CLIENT,
+            },
+        )
+    }
+
+    /// Delays the bytes that are written from now on by `rounds`
+    pub fn set_latency(&self, rounds: u64) {
+        self.state.lock().unwrap().latency = rounds;
+    }
+
+    /// Breaks the connection, as if the network had gone away.
+    /// The bytes in flight are lost.
+    pub fn sever(&self) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .pipes
+                .iter_mut()
+                .filter_map(|pipe| {
+                    pipe.in_flight.clear();
+                    pipe.arrived.clear();
+                    pipe.close()
+                })
+                .collect()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Starts the next round, in which the writes whose latency has
+    /// elapsed arrive.  Returns true if any did.
+    fn advance(&self) -> bool {
+        let mut wakers = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            state.round += 1;
+            let round = state.round;
+            for pipe in state.pipes.iter_mut() {
+                let mut arrived = false;
+                while let Some((due, _)) = pipe.in_flight.front() {
+                    if *due > round {
+                        break;
+                    }
+                    let (_, data) = pipe.in_flight.pop_front().unwrap();
+                    pipe.arrived.extend(data);
+                    arrived = true;
+                }
+                if arrived {
+                    wakers.extend(pipe.waker.take());
+                }
+            }
+        }
+        let arrived = !wakers.is_empty();
+        wakers.into_iter().for_each(Waker::wake);
+        arrived
+    }
+
+    fn in_flight(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.pipes.iter().any(|pipe| !pipe.in_flight.is_empty())
+    }
+}
+
+/// One end of a `Link`
+#[derive(Debug)]
+struct Endpoint {
+    state: Arc<Mutex<LinkState>>,
+    read: usize,
+    write: usize,
+}
+
+impl Endpoint {
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.state.lock().unwrap().pipes[self.read].poll_readable(cx)
+    }
+}
+
+impl AsyncRead for Endpoint {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        let pipe = &mut state.pipes[self.read];
+        if pipe.arrived.is_empty() {
+            if pipe.closed && pipe.in_flight.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(pipe.arrived.len());
+        for (dest, byte) in buf.iter_mut().zip(pipe.arrived.drain(..len)) {
+            *dest = byte;
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Endpoint {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            let latency = state.latency;
+            let due = state.round + latency;
+            let pipe = &mut state.pipes[self.write];
+            if pipe.closed {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "the link is closed",
+                )));
+            }
+            if latency == 0 {
+                pipe.arrived.extend(buf);
+                pipe.waker.take()
+            } else {
+                pipe.in_flight.push_back((due, buf.to_vec()));
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let waker = self.state.lock().unwrap().pipes[self.write].close();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock().unwrap();
+            let read = state.pipes[self.read].close();
+            let write = state.pipes[self.write].close();
+            read.into_iter().chain(write).collect()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl ClientStream for Endpoint {
+    fn readable(&self) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + '_>> {
+        Box::pin(smol::future::poll_fn(move |cx| self.poll_readable(cx)))
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncReadAndWrite for Endpoint {
+    async fn wait_for_readable(&self) -> anyhow::Result<()> {
+        Ok(smol::future::poll_fn(|cx| self.poll_readable(cx)).await?)
+    }
+}
+
+/// Tells the terminal of an `EchoPane` about what is written to the
+/// pane, as the tty of a shell that is waiting for input would
+struct EchoWriter {
+    pane_id: PaneId,
+    terminal: Rc<RefCell<Terminal>>,
+}
+
+impl std::io::Write for EchoWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut echo = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            match byte {
+                b'\r' | b'\n' => echo.extend_from_slice(b"\r\n"),
+                byte => echo.push(byte),
+            }
+        }
+        self.terminal.borrow_mut().advance_bytes(echo);
+        if let Some(mux) = Mux::get() {
+            mux.notify(mux::MuxNotification::PaneOutput(self.pane_id));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A pane of the server that echoes its input
+pub struct EchoPane {
+    pane_id: PaneId,
+    terminal: Rc<RefCell<Terminal>>,
+    writer: RefCell<EchoWriter>,
+    dead: RefCell<bool>,
+}
+
+impl EchoPane {
+    fn new(pane_id: PaneId, size: TerminalSize) -> Self {
+        let terminal = Rc::new(RefCell::new(Terminal::new(
+            size,
+            Arc::new(config::TermConfig::new()),
+            "WezTerm",
+            config::wezterm_version(),
+            Box::new(Vec::new()),
+        )));
+        Self {
+            pane_id,
+            writer: RefCell::new(EchoWriter {
+                pane_id,
+                terminal: Rc::clone(&terminal),
+            }),
+            terminal,
+            dead: RefCell::new(false),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Pane for EchoPane {
+    fn pane_id(&self) -> PaneId {
+        self.pane_id
+    }
+
+    fn get_cursor_position(&self) -> StableCursorPosition {
+        terminal_get_cursor_position(&mut self.terminal.borrow_mut())
+    }
+
+    fn get_current_seqno(&self) -> SequenceNo {
+        self.terminal.borrow().current_seqno()
+    }
+
+    fn get_changed_since(
+        &self,
+        lines: Range<StableRowIndex>,
+        seqno: SequenceNo,
+    ) -> RangeSet<StableRowIndex> {
+        terminal_get_dirty_lines(&mut self.terminal.borrow_mut(), lines, seqno)
+    }
+
+    fn get_lines(&self, lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>) {
+        terminal_get_lines(&mut self.terminal.borrow_mut(), lines)
+    }
+
+    fn get_dimensions(&self) -> RenderableDimensions {
+        terminal_get_dimensions(&mut self.terminal.borrow_mut())
+    }
+
+    fn get_title(&self) -> String {
+        self.terminal.borrow_mut().get_title().to_string()
+    }
+
+    fn send_paste(&self, text: &str) -> anyhow::Result<()> {
+        self.writer().write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>> {
+        Ok(None)
+    }
+
+    fn writer(&self) -> RefMut<dyn std::io::Write> {
+        self.writer.borrow_mut()
+    }
+
+    fn resize(&self, size: TerminalSize) -> anyhow::Result<()> {
+        self.terminal.borrow_mut().resize(size);
+        Ok(())
+    }
+
+    fn key_down(&self, _key: KeyCode, _modifiers: KeyModifiers) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn key_up(&self, _key: KeyCode, _modifiers: KeyModifiers) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn mouse_event(&self, _event: MouseEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn kill(&self) {
+        *self.dead.borrow_mut() = true;
+    }
+
+    fn is_dead(&self) -> bool {
+        *self.dead.borrow()
+    }
+
+    fn palette(&self) -> ColorPalette {
+        self.terminal.borrow().palette()
+    }
+
+    fn domain_id(&self) -> DomainId {
+        ECHO_DOMAIN_ID
+    }
+
+    fn is_mouse_grabbed(&self) -> bool {
+        false
+    }
+
+    fn is_alt_screen_active(&self) -> bool {
+        false
+    }
+
+    fn get_current_working_dir(&self) -> Option<Url> {
+        None
+    }
+}
+
+/// `ClientInner` assumes that the default domain of the server has the
+/// first id that the server allocated, as it does in a real server.
+/// The ids are allocated for the whole process, so the domain of the
+/// harness claims that id rather than allocating one.
+const ECHO_DOMAIN_ID: DomainId = 0;
+
+/// The domain of the server, whose panes are `EchoPane`s
+pub struct EchoDomain;
+
+#[async_trait(?Send)]
+impl Domain for EchoDomain {
+    async fn spawn_pane(
+        &self,
+        pane_id: PaneId,
+        size: TerminalSize,
+        _command: Option<CommandBuilder>,
+        _command_dir: Option<CommandDir>,
+    ) -> DomainResult<Rc<dyn Pane>> {
+        let pane: Rc<dyn Pane> = Rc::new(EchoPane::new(pane_id, size));
+        Mux::get().unwrap().add_pane(&pane)?;
+        Ok(pane)
+    }
+
+    fn domain_id(&self) -> DomainId {
+        ECHO_DOMAIN_ID
+    }
+
+    fn domain_name(&self) -> &str {
+        "echo"
+    }
+
+    async fn attach(&self, _window_id: Option<mux::window::WindowId>) -> DomainResult<()> {
+        Ok(())
+    }
+
+    fn detach(&self) -> DomainResult<()> {
+        Err(anyhow::anyhow!("detach not implemented for EchoDomain").into())
+    }
+
+    fn state(&self) -> DomainState {
+        DomainState::Attached
+    }
+}
+
+/// Returns a function that schedules futures onto the returned queue
+fn queue() -> (ScheduleFunc, Receiver<Runnable>) {
+    let (tx, rx) = smol::channel::unbounded();
+    let func: ScheduleFunc = Box::new(move |runnable: Runnable| {
+        if let Err(err) = tx.try_send(runnable) {
+            // The harness is gone, such as when a timer fires after the
+            // test has finished; leak the future rather than drop it on
+            // a thread other than the one that spawned it
+            std::mem::forget(err.into_inner());
+        }
+    });
+    (func, rx)
+}
+
+/// A mux along with the queues of the futures that it spawns
+struct Side {
+    mux: Rc<Mux>,
+    schedulers: Arc<Schedulers>,
+    queue: Receiver<Runnable>,
+    low_pri_queue: Receiver<Runnable>,
+}
+
+impl Side {
+    fn new(make_mux: impl FnOnce() -> Rc<Mux>) -> Self {
+        let (main, queue) = queue();
+        let (low_pri, low_pri_queue) = queue();
+        let schedulers = Schedulers::new(main, low_pri);
+        let mux = schedulers.enter(make_mux);
+        Self {
+            mux,
+            schedulers,
+            queue,
+            low_pri_queue,
+        }
+    }
+
+    /// Calls `f` with this mux as the mux of the thread, binding the
+    /// futures that it spawns to the queues of this side
+    fn enter<T>(&self, f: impl FnOnce() -> T) -> T {
+        Mux::set_mux(&self.mux);
+        self.schedulers.enter(f)
+    }
+
+    /// Runs the futures that are ready at the start of the round, and
+    /// returns how many ran.  Those that they wake up run in the next.
+    fn run_ready(&self) -> usize {
+        let ready = self.queue.len() + self.low_pri_queue.len();
+        let mut ran = 0;
+        while ran < ready {
+            let runnable = match self.queue.try_recv() {
+                Ok(runnable) => runnable,
+                Err(_) => match self.low_pri_queue.try_recv() {
+                    Ok(runnable) => runnable,
+                    Err(_) => break,
+                },
+            };
+            self.enter(|| runnable.run());
+            ran += 1;
+        }
+        ran
+    }
+}
+
+/// What a pane looks like from either side of the link, identified
+/// by its id in the server
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PaneState {
+    pub server_pane_id: PaneId,
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+    pub text: String,
+}
+
+/// Returns the text on the screen of `pane`, without trailing blanks
+pub fn screen_text(pane: &Rc<dyn Pane>) -> String {
+    let dims = pane.get_dimensions();
+    let rows = dims.physical_top..dims.physical_top + dims.viewport_rows as StableRowIndex;
+    let (_first_row, lines) = pane.get_lines(rows);
+    lines
+        .iter()
+        .map(|line| line.as_str().trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// Returns the panes of each tab in the windows of `mux`, ordered so
+/// that those of the server and the client can be compared
+fn tab_states(
+    mux: &Mux,
+    server_pane_id: impl Fn(&Rc<dyn Pane>) -> Option<PaneId>,
+) -> Vec<Vec<PaneState>> {
+    let mut tabs = vec![];
+    for window_id in mux.iter_windows() {
+        let window = match mux.get_window(window_id) {
+            Some(window) => window,
+            None => continue,
+        };
+        for tab in window.iter() {
+            let panes = tab
+                .iter_panes_ignoring_zoom()
+                .into_iter()
+                .filter_map(|pos| {
+                    Some(PaneState {
+                        server_pane_id: server_pane_id(&pos.pane)?,
+                        left: pos.left,
+                        top: pos.top,
+                        width: pos.width,
+                        height: pos.height,
+                        text: screen_text(&pos.pane),
+                    })
+                })
+                .collect();
+            tabs.push(panes);
+        }
+    }
+    tabs.sort();
+    tabs
+}
+
+/// A server mux and a client mux whose client domain is attached to
+/// the server over a `Link`
+pub struct Harness {
+    server: Side,
+    client: Side,
+    domain_id: DomainId,
+    link: Link,
+}
+
+impl Harness {
+    /// Sets up both muxes and attaches the client domain
+    pub fn new() -> Self {
+        let server = Side::new(|| {
+            let domain: Arc<dyn Domain> = Arc::new(EchoDomain);
+            MuxBuilder::new().domain(domain).build().unwrap()
+        });
+        let client = Side::new(|| {
+            // The builder made the server the mux of this thread
+            Mux::shutdown();
+            let mux = Rc::new(Mux::new(None));
+            Mux::set_mux(&mux);
+            mux
+        });
+        let domain_id = client.enter(|| {
+            let domain: Arc<dyn Domain> =
+                Arc::new(ClientDomain::new(ClientDomainConfig::Unix(UnixDomain {
+                    name: "harness".to_string(),
+                    ..Default::default()
+                })));
+            let mux = Mux::get().unwrap();
+            mux.add_domain(&domain);
+            mux.set_default_domain(&domain);
+            domain.domain_id()
+        });
+
+        let mut harness = Self {
+            server,
+            client,
+            domain_id,
+            link: Link::default(),
+        };
+        harness.attach();
+        harness
+    }
+
+    /// The link over which the client domain is currently attached
+    pub fn link(&self) -> &Link {
+        &self.link
+    }
+
+    /// The id of the client domain in the client mux
+    pub fn domain_id(&self) -> DomainId {
+        self.domain_id
+    }
+
+    /// Calls `f` with the server mux
+    pub fn on_server<T>(&self, f: impl FnOnce(&Rc<Mux>) -> T) -> T {
+        self.server.enter(|| f(&self.server.mux))
+    }
+
+    /// Calls `f` with the client mux
+    pub fn on_client<T>(&self, f: impl FnOnce(&Rc<Mux>) -> T) -> T {
+        self.client.enter(|| f(&self.client.mux))
+    }
+
+    /// Connects the client domain to the server over a new link and
+    /// attaches it
+    pub fn attach(&mut self) {
+        self.link = Link::default();
+        let (client_end, server_end) = self.link.endpoints();
+        self.server.enter(|| {
+            promise::spawn::spawn(async move {
+                if let Err(err) = dispatch::process_stream(server_end, false).await {
+                    log::error!("while serving the client: {:#}", err);
+                }
+            })
+            .detach();
+        });
+        let domain_id = self.domain_id;
+        self.run_on_client(async move {
+            let client = Client::new_in_process(Some(domain_id), Box::new(client_end));
+            let domain = Mux::get().unwrap().get_domain(domain_id).unwrap();
+            let domain = domain.downcast_ref::<ClientDomain>().unwrap();
+            domain.attach_in_process(client, None).await
+        })
+        .unwrap();
+        self.settle();
+    }
+
+    /// Detaches the client domain, leaving its panes on the server
+    pub fn detach(&mut self) {
+        let domain_id = self.domain_id;
+        self.on_client(|mux| mux.get_domain(domain_id).unwrap().detach())
+            .unwrap();
+        self.settle();
+    }
+
+    /// Returns the state of the client domain
+    pub fn domain_state(&self) -> DomainState {
+        self.on_client(|mux| mux.get_domain(self.domain_id).unwrap().state())
+    }
+
+    /// Runs one round: the writes whose latency has elapsed arrive, and
+    /// the futures of the server and then of the client that are ready
+    /// run.  Returns false if nothing happened.
+    pub fn pump(&mut self) -> bool {
+        let arrived = self.link.advance();
+        let ran = self.server.run_ready() + self.client.run_ready();
+        arrived || ran > 0
+    }
+
+    /// Pumps until nothing is left to run and nothing is in flight.
+    /// Timers run in real time, so work that waits on them is not
+    /// waited for.
+    pub fn settle(&mut self) {
+        for _ in 0..MAX_ROUNDS {
+            if !self.pump() && !self.link.in_flight() {
+                return;
+            }
+        }
+        panic!("the muxes did not settle within {} rounds", MAX_ROUNDS);
+    }
+
+    fn run_until<T: 'static>(&mut self, rx: Receiver<T>) -> T {
+        for _ in 0..MAX_ROUNDS {
+            if let Ok(result) = rx.try_recv() {
+                return result;
+            }
+            if !self.pump() && !self.link.in_flight() {
+                // Give a timer or another thread a chance to wake it up
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        panic!("the future didn't complete within {} rounds", MAX_ROUNDS);
+    }
+
+    /// Spawns `future` into the client mux and pumps until it completes
+    pub fn run_on_client<T: 'static>(&mut self, future: impl Future<Output = T> + 'static) -> T {
+        let (tx, rx) = smol::channel::bounded(1);
+        self.client.enter(|| {
+            promise::spawn::spawn(async move {
+                tx.try_send(future.await).ok();
+            })
+            .detach();
+        });
+        self.run_until(rx)
+    }
+
+    /// Spawns `future` into the server mux and pumps until it completes
+    pub fn run_on_server<T: 'static>(&mut self, future: impl Future<Output = T> + 'static) -> T {
+        let (tx, rx) = smol::channel::bounded(1);
+        self.server.enter(|| {
+            promise::spawn::spawn(async move {
+                tx.try_send(future.await).ok();
+            })
+            .detach();
+        });
+        self.run_until(rx)
+    }
+
+    /// Spawns a tab into the client domain, as the GUI does for a new
+    /// window, and returns the ids of the tab and pane in the client
+    pub fn spawn_tab(&mut self) -> (TabId, PaneId) {
+        self.run_on_client(async {
+            let mux = Mux::get().unwrap();
+            let (tab, pane, _window_id) = mux
+                .spawn_tab_or_window(
+                    None,
+                    SpawnTabDomain::DefaultDomain,
+                    None,
+                    None,
+                    TerminalSize::default(),
+                    None,
+                    mux.active_workspace(),
+                )
+                .await
+                .unwrap();
+            (tab.tab_id(), pane.pane_id())
+        })
+    }
+
+    /// Splits the pane of the client, and returns the id of the new pane
+    pub fn split_pane(&mut self, pane_id: PaneId, request: SplitRequest) -> PaneId {
+        self.run_on_client(async move {
+            let mux = Mux::get().unwrap();
+            let (pane, _size) = mux
+                .split_pane(
+                    pane_id,
+                    request,
+                    mux::domain::SplitSource::Spawn {
+                        command: None,
+                        command_dir: None,
+                    },
+                    SpawnTabDomain::CurrentPaneDomain,
+                )
+                .await
+                .unwrap();
+            pane.pane_id()
+        })
+    }
+
+    /// Returns the id in the server of a pane of the client
+    pub fn server_pane_id(&self, client_pane_id: PaneId) -> PaneId {
+        self.on_client(|mux| {
+            let pane = mux.get_pane(client_pane_id).unwrap();
+            pane.downcast_ref::<ClientPane>().unwrap().remote_pane_id
+        })
+    }
+
+    /// Returns the text on the screen of a pane of the server
+    pub fn server_text(&self, pane_id: PaneId) -> String {
+        self.on_server(|mux| screen_text(&mux.get_pane(pane_id).unwrap()))
+    }
+
+    /// Returns the text on the screen of a pane of the client, once
+    /// it has fetched the lines that it didn't have
+    pub fn client_text(&mut self, pane_id: PaneId) -> String {
+        self.on_client(|mux| screen_text(&mux.get_pane(pane_id).unwrap()));
+        self.settle();
+        self.on_client(|mux| screen_text(&mux.get_pane(pane_id).unwrap()))
+    }
+
+    /// Returns the tabs of the server
+    pub fn server_tabs(&self) -> Vec<Vec<PaneState>> {
+        self.on_server(|mux| tab_states(mux, |pane| Some(pane.pane_id())))
+    }
+
+    /// Returns the tabs of the client domain
+    pub fn client_tabs(&mut self) -> Vec<Vec<PaneState>> {
+        let server_pane_id = |pane: &Rc<dyn Pane>| {
+            pane.downcast_ref::<ClientPane>()
+                .map(|pane| pane.remote_pane_id)
+        };
+        // The first look starts fetching the lines of the panes
+        self.on_client(|mux| tab_states(mux, server_pane_id));
+        self.settle();
+        self.on_client(|mux| tab_states(mux, server_pane_id))
+    }
+
+    /// Asserts that the client shows the same tabs as the server, with
+    /// the same panes, laid out the same way and showing the same text
+    pub fn assert_in_sync(&mut self) {
+        self.settle();
+        let client = self.client_tabs();
+        let server = self.server_tabs();
+        assert_eq!(client, server);
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.link.sever();
+        Mux::shutdown();
+    }
+}
@@ -1,6 +1,7 @@
 use clap::{Parser, ValueHint};
 use config::{Dimension, GeometryOrigin, SshParameters};
 use std::ffi::OsString;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 pub const DEFAULT_WINDOW_CLASS: &str = "org.wezfurlong.wezterm";
@@ -187,6 +188,14 @@ pub struct StartCommand {
     #[clap(long, verbatim_doc_comment)]
     pub position: Option<GuiPosition>,
 
+    /// Build the windows, tabs and splits described by this layout
+    /// file, which is either JSON or a lua script that returns the
+    /// layout, instead of opening a single pane.
+    /// Relative directories in the layout are relative to the
+    /// current directory.
+    #[clap(long, parse(from_os_str), value_hint=ValueHint::FilePath, conflicts_with_all=&["prog", "cwd"])]
+    pub layout: Option<PathBuf>,
+
     /// Instead of executing your shell, run PROG.
     /// For example: `wezterm start -- bash -l` will spawn bash
     /// as if it were a login shell.
@@ -260,6 +269,19 @@ pub struct SerialCommand {
     #[clap(long = "baud")]
     pub baud: Option<usize>,
 
+    /// If the serial device goes away, for example because a USB
+    /// serial adapter re-enumerated, wait for it to reappear and
+    /// re-open it, rather than closing the pane.
+    /// Consider specifying a stable device name, such as one from
+    /// /dev/serial/by-id, for the port when using this option.
+    #[clap(long = "reconnect")]
+    pub reconnect: bool,
+
+    /// The line that is output to the terminal when a reconnection
+    /// happens.  The default is "*** reconnected to PORT ***".
+    #[clap(long = "reconnect-banner", requires = "reconnect")]
+    pub reconnect_banner: Option<String>,
+
     /// Override the default windowing system class.
     /// The default is "org.wezfurlong.wezterm".
     /// Under X11 and Windows this changes the window class.
@@ -291,7 +313,14 @@ pub struct SerialCommand {
 pub struct ConnectCommand {
     /// Name of the multiplexer domain section from the configuration
     /// to which you'd like to connect
-    pub domain_name: String,
+    #[clap(required_unless_present = "share")]
+    pub domain_name: Option<String>,
+
+    /// Watch the tab that was shared with `wezterm cli share-tab`,
+    /// using the TOKEN@HOST:PORT link that it printed, rather than
+    /// connecting to a domain.  The tab can't be given input.
+    #[clap(long, value_name = "TOKEN@HOST:PORT", conflicts_with_all=&["domain-name", "layout", "prog"])]
+    pub share: Option<String>,
 
     /// Override the default windowing system class.
     /// The default is "org.wezfurlong.wezterm".
@@ -317,6 +346,19 @@ pub struct ConnectCommand {
     #[clap(long, verbatim_doc_comment)]
     pub position: Option<GuiPosition>,
 
+    /// Build the windows, tabs and splits described by this layout
+    /// file in the domain, if it has no panes yet, instead of opening
+    /// a single pane.
+    /// See `wezterm start --layout`.
+    #[clap(long, parse(from_os_str), value_hint=ValueHint::FilePath, conflicts_with = "prog")]
+    pub layout: Option<PathBuf>,
+
+    /// Activate the tab with this id on the server, and make it
+    /// interactive before the other tabs are synchronized.
+    /// The default is the tab that was last active on the server.
+    #[clap(long = "focus", name = "TAB_ID")]
+    pub focus: Option<usize>,
+
     /// Instead of executing your shell, run PROG.
     /// For example: `wezterm start -- bash -l` will spawn bash
     /// as if it were a login shell.
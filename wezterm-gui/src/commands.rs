@@ -712,6 +712,41 @@ static DEFS: &[CommandDef] = &[
         keys: &[],
         args: &[ArgType::ActiveWindow],
     },
+    CommandDef {
+        brief: "Spawn into a domain with a working directory",
+        doc: "Prompts for a domain and a working directory, then spawns a tab there",
+        exp: |exp| exp.push(SpawnInDomainWithCwd),
+        keys: &[],
+        args: &[ArgType::ActiveWindow],
+    },
+    CommandDef {
+        brief: "Spawn in a recent directory",
+        doc: "Shows the recently visited working directories, then spawns a tab in the chosen one",
+        exp: |exp| exp.push(SpawnInRecentDir),
+        keys: &[],
+        args: &[ArgType::ActiveWindow],
+    },
+    CommandDef {
+        brief: "Edit the scrollback",
+        doc: "Opens the scrollback of the active pane in $VISUAL or $EDITOR, in a split of its tab",
+        exp: |exp| exp.push(EditScrollback(EditScrollbackArguments::default())),
+        keys: &[],
+        args: &[ArgType::ActivePane],
+    },
+    CommandDef {
+        brief: "Search all panes",
+        doc: "Searches the scrollback of every pane, and jumps to the chosen match",
+        exp: |exp| exp.push(GlobalSearch(GlobalSearchArguments::default())),
+        keys: &[],
+        args: &[ArgType::ActivePane],
+    },
+    CommandDef {
+        brief: "List the bookmarks of the pane",
+        doc: "Lists the scrollback bookmarks of the active pane, and scrolls to the chosen one",
+        exp: |exp| exp.push(ListBookmarks),
+        keys: &[],
+        args: &[ArgType::ActivePane],
+    },
     CommandDef {
         brief: "Navigate tabs",
         doc: "Shows the tab navigator",
@@ -3,24 +3,87 @@ use crate::termwindow::TermWindowNotif;
 use crate::TermWindow;
 use ::window::*;
 use anyhow::{Context, Error};
+use config::keyassignment::SpawnWindowTarget;
 pub use config::FrontEndSelection;
+use config::{Dimension, GeometryOrigin, NotificationHandling};
+use luahelper::impl_lua_conversion_dynamic;
+use mlua::FromLua;
 use mux::client::ClientId;
+use mux::pane::PaneId;
+use mux::panepick::PickPanesRequest;
 use mux::window::WindowId as MuxWindowId;
 use mux::{Mux, MuxNotification};
+use mux_lua::MuxPane;
 use promise::{Future, Promise};
 use std::cell::RefCell;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wezterm_dynamic::{FromDynamic, ToDynamic};
+use wezterm_gui_subcommands::GuiPosition;
 use wezterm_term::{Alert, ClipboardSelection};
 use wezterm_toast_notification::*;
 
+/// Tracks the number of notifications shown by a pane
+/// in the current one minute period
+struct NotificationLimit {
+    started: Instant,
+    count: u32,
+}
+
+/// A desktop notification requested by a pane, as passed to
+/// and returned from the `pane-notification` event
+#[derive(Debug, Clone, FromDynamic, ToDynamic)]
+struct PaneNotification {
+    title: Option<String>,
+    body: String,
+    #[dynamic(default)]
+    domain_name: String,
+}
+impl_lua_conversion_dynamic!(PaneNotification);
+
+/// Calls the `pane-notification` event, if any, to allow the
+/// notification to be adjusted or suppressed.
+/// Returns None if the notification should not be shown.
+fn call_pane_notification(
+    pane_id: PaneId,
+    notification: PaneNotification,
+) -> Option<PaneNotification> {
+    match config::run_immediate_with_lua_config(|lua| {
+        if let Some(lua) = lua {
+            let v = config::lua::emit_sync_callback(
+                &*lua,
+                (
+                    "pane-notification".to_string(),
+                    (MuxPane(pane_id), notification.clone()),
+                ),
+            )?;
+            match &v {
+                mlua::Value::Nil => Ok(Some(notification.clone())),
+                mlua::Value::Boolean(false) => Ok(None),
+                _ => Ok(Some(PaneNotification::from_lua(v, &*lua)?)),
+            }
+        } else {
+            Ok(Some(notification.clone()))
+        }
+    }) {
+        Ok(result) => result,
+        Err(err) => {
+            log::warn!("pane-notification: {}", err);
+            Some(notification)
+        }
+    }
+}
+
 pub struct GuiFrontEnd {
     connection: Rc<Connection>,
     switching_workspaces: RefCell<bool>,
     spawned_mux_window: RefCell<HashSet<MuxWindowId>>,
     known_windows: RefCell<BTreeMap<Window, MuxWindowId>>,
     client_id: Arc<ClientId>,
+    notification_limits: RefCell<HashMap<PaneId, NotificationLimit>>,
+    window_under_mouse: RefCell<Option<MuxWindowId>>,
 }
 
 impl Drop for GuiFrontEnd {
@@ -42,7 +105,15 @@ impl GuiFrontEnd {
             spawned_mux_window: RefCell::new(HashSet::new()),
             known_windows: RefCell::new(BTreeMap::new()),
             client_id: client_id.clone(),
+            notification_limits: RefCell::new(HashMap::new()),
+            window_under_mouse: RefCell::new(None),
         });
+        mux.enable_pane_picking();
+        let fe = Rc::downgrade(&front_end);
+        mux.set_window_target_resolver(Box::new(move |target| match fe.upgrade() {
+            Some(fe) => fe.resolve_window_target(target),
+            None => anyhow::bail!("the GUI has shut down"),
+        }));
         let fe = Rc::downgrade(&front_end);
         mux.subscribe(move |n| {
             if let Some(fe) = fe.upgrade() {
@@ -59,12 +130,105 @@ impl GuiFrontEnd {
                         })
                         .detach();
                     }
-                    MuxNotification::PaneRemoved(_) => {}
+                    MuxNotification::PaneRemoved(pane_id) => {
+                        fe.notification_limits.borrow_mut().remove(&pane_id);
+                    }
+                    MuxNotification::PaneInternalError { pane_id, message } => {
+                        persistent_toast_notification(
+                            &format!("Internal error in pane {}", pane_id),
+                            &message,
+                        );
+                    }
                     MuxNotification::WindowInvalidated(_) => {}
+                    MuxNotification::DomainAlreadyAttached(domain_id) => {
+                        let mux = Mux::get().expect("mux is calling us");
+                        if let Some(domain) = mux.get_domain(domain_id) {
+                            ToastNotification {
+                                title: "wezterm".to_string(),
+                                message: format!("{} is already attached", domain.domain_name()),
+                                url: None,
+                                timeout: Some(Duration::from_secs(5)),
+                            }
+                            .show();
+                        }
+                    }
+                    MuxNotification::SpawnRedirected { domain, cwd, .. } => {
+                        ToastNotification {
+                            title: "wezterm".to_string(),
+                            message: format!(
+                                "Started in domain {}, as {} matches domain_rules",
+                                domain, cwd
+                            ),
+                            url: None,
+                            timeout: Some(Duration::from_secs(5)),
+                        }
+                        .show();
+                    }
+                    MuxNotification::WindowCreatedInWorkspace {
+                        domain_id,
+                        workspace,
+                    } => {
+                        // The window is mirrored, but is hidden until
+                        // the user switches to its workspace
+                        let mux = Mux::get().expect("mux is calling us");
+                        if let Some(domain) = mux.get_domain(domain_id) {
+                            ToastNotification {
+                                title: "wezterm".to_string(),
+                                message: format!(
+                                    "A window was opened in workspace {} of {}",
+                                    workspace,
+                                    domain.domain_name()
+                                ),
+                                url: None,
+                                timeout: Some(Duration::from_secs(5)),
+                            }
+                            .show();
+                        }
+                    }
+                    MuxNotification::PaneInputRejected {
+                        source, rejection, ..
+                    } => {
+                        // Rejections that ask for confirmation are
+                        // handled via TermWindowNotif
+                        if !rejection.confirm {
+                            ToastNotification {
+                                title: "wezterm".to_string(),
+                                message: format!(
+                                    "{} rejected {} input: {}",
+                                    rejection.filter,
+                                    source.as_str(),
+                                    rejection.reason
+                                ),
+                                url: None,
+                                timeout: Some(Duration::from_secs(5)),
+                            }
+                            .show();
+                        }
+                    }
+                    MuxNotification::PaneInputHeld(_)
+                    | MuxNotification::PaneResourceUsage(_)
+                    | MuxNotification::PaneOutputPaused { .. }
+                    | MuxNotification::PanePaletteOverridesChanged(_)
+                    | MuxNotification::PaneMarkersChanged(_)
+                    | MuxNotification::CwdDropped { .. }
+                    | MuxNotification::ScrollToRow { .. }
+                    | MuxNotification::CloseConfirmationRequested(_)
+                    | MuxNotification::CloseConfirmationResolved { .. }
+                    | MuxNotification::DomainSyncProgress { .. } => {
+                        // Handled via TermWindowNotif; NOP it here.
+                    }
                     MuxNotification::PaneOutput(_) => {}
                     MuxNotification::PaneAdded(_) => {}
+                    MuxNotification::PaneLinksChanged => {}
+                    MuxNotification::TabLayoutChanged(_) => {}
+                    MuxNotification::ShareEnded(_) => {}
+                    // The lua event is emitted by the mux lua module
+                    MuxNotification::OutputMatched { .. } => {}
+                    MuxNotification::PickPanes(request) => {
+                        fe.show_pane_pick(request);
+                    }
                     MuxNotification::Alert {
-                        pane_id: _,
+                        pane_id,
                         alert:
                             Alert::ToastNotification {
                                 title,
@@ -72,12 +236,10 @@ impl GuiFrontEnd {
                                 focus: _,
                             },
                     } => {
-                        let message = if title.is_none() { "" } else { &body };
-                        let title = title.as_ref().unwrap_or(&body);
                         // FIXME: if notification.focus is true, we should do
                         // something here to arrange to focus pane_id when the
                         // notification is clicked
-                        persistent_toast_notification(title, message);
+                        fe.show_pane_notification(pane_id, title, body);
                     }
                     MuxNotification::Alert {
                         pane_id: _,
@@ -94,7 +256,9 @@ impl GuiFrontEnd {
                             | Alert::WindowTitleChanged(_)
                             | Alert::TabTitleChanged(_)
                             | Alert::IconTitleChanged(_)
-                            | Alert::SetUserVar { .. },
+                            | Alert::SetUserVar { .. }
+                            | Alert::ModesChanged(_)
+                            | Alert::CommandStarted,
                     } => {}
                     MuxNotification::Empty => {
                         if mux::activity::Activity::count() == 0 {
@@ -286,10 +450,168 @@ impl GuiFrontEnd {
         }
     }
 
+    /// Shows a desktop notification that was requested by a pane,
+    /// subject to the notification policy of its domain, the
+    /// per-pane rate limit and the `pane-notification` event
+    fn show_pane_notification(&self, pane_id: PaneId, title: Option<String>, body: String) {
+        let mux = Mux::get().expect("called on mux thread");
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let domain = mux.get_domain(pane.domain_id());
+
+        let handling = domain
+            .as_ref()
+            .map(|domain| domain.notification_handling())
+            .unwrap_or_else(|| config::configuration().notification_handling);
+        match handling {
+            NotificationHandling::All => {}
+            NotificationHandling::FocusedPaneOnly => {
+                if mux.focused_pane_id_for_client(&self.client_id) != Some(pane_id) {
+                    log::trace!("suppress notification from unfocused pane {}", pane_id);
+                    return;
+                }
+            }
+            NotificationHandling::None => {
+                log::trace!("suppress notification from pane {}", pane_id);
+                return;
+            }
+        }
+
+        if !self.admit_notification(pane_id) {
+            log::warn!(
+                "suppress notification from pane {}: \
+                 ratelimit_notifications_per_pane_per_minute exceeded",
+                pane_id
+            );
+            return;
+        }
+
+        let notification = PaneNotification {
+            title,
+            body,
+            domain_name: domain
+                .map(|domain| domain.domain_name().to_string())
+                .unwrap_or_default(),
+        };
+        if let Some(PaneNotification { title, body, .. }) =
+            call_pane_notification(pane_id, notification)
+        {
+            let message = if title.is_none() { "" } else { &body };
+            let title = title.as_ref().unwrap_or(&body);
+            persistent_toast_notification(title, message);
+        }
+    }
+
+    /// Returns true if the pane hasn't yet shown its quota of
+    /// notifications for the current minute
+    fn admit_notification(&self, pane_id: PaneId) -> bool {
+        let limit = config::configuration().ratelimit_notifications_per_pane_per_minute;
+        if limit == 0 {
+            return true;
+        }
+        let mut limits = self.notification_limits.borrow_mut();
+        let entry = limits.entry(pane_id).or_insert_with(|| NotificationLimit {
+            started: Instant::now(),
+            count: 0,
+        });
+        if entry.started.elapsed() >= Duration::from_secs(60) {
+            entry.started = Instant::now();
+            entry.count = 0;
+        }
+        if entry.count >= limit {
+            return false;
+        }
+        entry.count += 1;
+        true
+    }
+
     pub fn is_switching_workspace(&self) -> bool {
         *self.switching_workspaces.borrow()
     }
 
+    /// Records that the mouse pointer is over the window, or that it
+    /// left it when `over` is false
+    pub fn set_mouse_over_window(&self, mux_window_id: MuxWindowId, over: bool) {
+        let mut under_mouse = self.window_under_mouse.borrow_mut();
+        if over {
+            under_mouse.replace(mux_window_id);
+        } else if *under_mouse == Some(mux_window_id) {
+            under_mouse.take();
+        }
+    }
+
+    /// Resolves the targets of spawns that refer to screens or to the
+    /// mouse pointer; see `mux::windowtarget`
+    fn resolve_window_target(
+        &self,
+        target: &SpawnWindowTarget,
+    ) -> anyhow::Result<Option<MuxWindowId>> {
+        match target {
+            SpawnWindowTarget::ExistingWindow(window_id) => Ok(Some(*window_id)),
+            SpawnWindowTarget::WindowUnderMouse => {
+                let mux = Mux::get().expect("mux started and running on main thread");
+                Ok((*self.window_under_mouse.borrow())
+                    .filter(|&window_id| mux.get_window(window_id).is_some()))
+            }
+            SpawnWindowTarget::NewWindowOnScreen(name_or_index) => {
+                let screens = self.connection.screens()?;
+                let screen = screens.find(name_or_index).ok_or_else(|| {
+                    let names: Vec<&str> = screens
+                        .ordered()
+                        .iter()
+                        .map(|screen| screen.name.as_str())
+                        .collect();
+                    anyhow::anyhow!(
+                        "there is no screen `{}`; the screens are {}",
+                        name_or_index,
+                        names.join(", ")
+                    )
+                })?;
+                // The next window to be created is placed at the top left
+                // of the screen, as with `wezterm start --position`
+                crate::termwindow::set_window_position(GuiPosition {
+                    x: Dimension::Pixels(0.),
+                    y: Dimension::Pixels(0.),
+                    origin: GeometryOrigin::Named(screen.name.clone()),
+                });
+                Ok(None)
+            }
+        }
+    }
+
+    /// Shows the pane selector for a pick in the window that contains
+    /// the pane that was focused most recently, or cancels the pick if
+    /// there is no window to show it in
+    fn show_pane_pick(&self, request: PickPanesRequest) {
+        let mux = Mux::get().expect("mux started and running on main thread");
+        let focused_window = mux
+            .focused_pane_id_for_client(&self.client_id)
+            .and_then(|pane_id| mux.resolve_pane_id(pane_id))
+            .map(|(_, window_id, _)| window_id);
+        let window = {
+            let windows = self.known_windows.borrow();
+            windows
+                .iter()
+                .find(|(_, &window_id)| Some(window_id) == focused_window)
+                .or_else(|| windows.iter().next())
+                .map(|(window, _)| window.clone())
+        };
+        match window {
+            Some(window) => window.notify(TermWindowNotif::Apply(Box::new(move |term_window| {
+                term_window.show_pane_pick(request);
+            }))),
+            None => {
+                log::warn!(
+                    "cancelling pane pick {}, as there are no windows",
+                    request.id
+                );
+                mux.finish_pane_pick(request.id, None);
+            }
+        }
+    }
+
     pub fn gui_window_for_mux_window(&self, mux_window_id: MuxWindowId) -> Option<GuiWin> {
         let windows = self.known_windows.borrow();
         for (window, v) in windows.iter() {
@@ -0,0 +1,79 @@
+//! Loads the layout file that is passed to `wezterm start --layout`
+//! and `wezterm connect --layout`.
+//! The file is either JSON or a lua script that returns the layout.
+use anyhow::{anyhow, Context};
+use mux::layout::LayoutDescription;
+use std::collections::BTreeMap;
+use std::path::Path;
+use wezterm_dynamic::{FromDynamic, FromDynamicOptions, ToDynamic, UnknownFieldAction, Value};
+
+fn json_to_dynamic(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(*b),
+        serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => Value::U64(u),
+            (None, Some(i), _) => Value::I64(i),
+            (None, None, f) => f.unwrap_or_default().to_dynamic(),
+        },
+        serde_json::Value::String(s) => Value::String(s.to_string()),
+        serde_json::Value::Array(a) => a
+            .iter()
+            .map(json_to_dynamic)
+            .collect::<Vec<_>>()
+            .to_dynamic(),
+        serde_json::Value::Object(o) => Value::Object(
+            o.iter()
+                .map(|(k, v)| (Value::String(k.to_string()), json_to_dynamic(v)))
+                .collect::<BTreeMap<_, _>>()
+                .into(),
+        ),
+    }
+}
+
+/// Parses and checks the layout in `path`.
+/// Relative cwds in the layout are resolved against the cwd of this
+/// process.
+/// Errors say where in the file the problem is: the line for syntax
+/// errors, and the field otherwise.
+pub fn load_layout_file(path: &Path) -> anyhow::Result<LayoutDescription> {
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("reading layout file {}", path.display()))?;
+    // Skip a potential BOM that Windows software may have placed in the file
+    let s = s.trim_start_matches('\u{FEFF}');
+
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => {
+            let json: serde_json::Value =
+                serde_json::from_str(s).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+            json_to_dynamic(&json)
+        }
+        Some("lua") => {
+            let lua = config::lua::make_lua_context(path)?;
+            let value: mlua::Value =
+                smol::block_on(lua.load(s).set_name(path.to_string_lossy())?.eval_async())
+                    .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+            luahelper::lua_value_to_dynamic(value)?
+        }
+        _ => anyhow::bail!(
+            "{}: the name of a layout file must end in .json or .lua",
+            path.display()
+        ),
+    };
+
+    let mut layout = LayoutDescription::from_dynamic(
+        &value,
+        FromDynamicOptions {
+            unknown_fields: UnknownFieldAction::Deny,
+            deprecated_fields: UnknownFieldAction::Warn,
+        },
+    )
+    .map_err(|err| anyhow!("{}: {:#}", path.display(), err))?;
+    layout
+        .validate()
+        .map_err(|err| anyhow!("{}: {:#}", path.display(), err))?;
+
+    let cwd = std::env::current_dir().context("resolving the cwd of this process")?;
+    layout.resolve_cwds(&cwd);
+    Ok(layout)
+}
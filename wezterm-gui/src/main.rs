@@ -4,9 +4,11 @@
 use ::window::*;
 use anyhow::{anyhow, Context};
 use clap::{Parser, ValueHint};
-use config::{ConfigHandle, SshDomain, SshMultiplexing};
+use config::{ClosePolicy, ConfigHandle, DomainKind, SshDomain, SshMultiplexing, TlsDomainClient};
 use mux::activity::Activity;
 use mux::domain::{Domain, LocalDomain};
+use mux::layout::LayoutDescription;
+use mux::share::ShareLink;
 use mux::ssh::RemoteSshDomain;
 use mux::Mux;
 use portable_pty::cmdbuilder::CommandBuilder;
@@ -32,6 +34,7 @@ mod download;
 mod frontend;
 mod glyphcache;
 mod inputmap;
+mod layoutfile;
 mod markdown;
 mod overlay;
 mod quad;
@@ -138,7 +141,7 @@ async fn async_run_ssh(opts: SshCommand) -> anyhow::Result<()> {
 
     let domain: Arc<dyn Domain> = Arc::new(mux::ssh::RemoteSshDomain::with_ssh_domain(&dom)?);
 
-    async_run_with_domain_as_default(domain, cmd).await
+    async_run_with_domain_as_default(domain, cmd, None).await
 }
 
 fn run_ssh(opts: SshCommand) -> anyhow::Result<()> {
@@ -176,6 +179,11 @@ fn run_serial(config: config::ConfigHandle, opts: &SerialCommand) -> anyhow::Res
     if let Some(baud) = opts.baud {
         serial.set_baud_rate(serial::BaudRate::from_speed(baud));
     }
+    if opts.reconnect {
+        serial.enable_reconnect(opts.reconnect_banner.clone().unwrap_or_else(|| {
+            format!("*** reconnected to {} ***", opts.port.to_string_lossy())
+        }));
+    }
 
     let pty_system = Box::new(serial);
     let domain: Arc<dyn Domain> = Arc::new(LocalDomain::with_pty_system("local", pty_system));
@@ -216,6 +224,7 @@ fn client_domains(config: &config::ConfigHandle) -> Vec<ClientDomainConfig> {
 async fn async_run_with_domain_as_default(
     domain: Arc<dyn Domain>,
     cmd: Option<CommandBuilder>,
+    layout: Option<LayoutDescription>,
 ) -> anyhow::Result<()> {
     let mux = Mux::get().unwrap();
     crate::update::load_last_release_info_and_set_banner();
@@ -229,10 +238,13 @@ async fn async_run_with_domain_as_default(
     mux.set_default_domain(&domain);
 
     let is_connecting = true;
-    spawn_tab_in_default_domain_if_mux_is_empty(cmd, is_connecting).await
+    spawn_tab_in_default_domain_if_mux_is_empty(cmd, is_connecting, layout).await
 }
 
-async fn async_run_mux_client(opts: ConnectCommand) -> anyhow::Result<()> {
+async fn async_run_mux_client(
+    opts: ConnectCommand,
+    layout: Option<LayoutDescription>,
+) -> anyhow::Result<()> {
     if let Some(cls) = opts.class.as_ref() {
         crate::set_window_class(cls);
     }
@@ -240,15 +252,29 @@ async fn async_run_mux_client(opts: ConnectCommand) -> anyhow::Result<()> {
         set_window_position(pos.clone());
     }
 
-    let domain = Mux::get()
-        .unwrap()
-        .get_domain_by_name(&opts.domain_name)
-        .ok_or_else(|| {
-            anyhow!(
-                "no multiplexer domain with name `{}` was found in the configuration",
-                opts.domain_name
-            )
-        })?;
+    let domain = match (&opts.share, &opts.domain_name) {
+        (Some(link), _) => share_domain(link)?,
+        (None, Some(domain_name)) => Mux::get()
+            .unwrap()
+            .get_domain_by_name(domain_name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "no multiplexer domain with name `{}` was found in the configuration",
+                    domain_name
+                )
+            })?,
+        (None, None) => anyhow::bail!("either a domain name or --share is required"),
+    };
+
+    if let Some(tab_id) = opts.focus {
+        match domain.downcast_ref::<ClientDomain>() {
+            Some(client) => client.set_attach_focus(tab_id),
+            None => log::warn!(
+                "--focus is ignored, as {} is not a multiplexer domain",
+                domain.domain_name()
+            ),
+        }
+    }
 
     let opts = opts.clone();
     let cmd = if !opts.prog.is_empty() {
@@ -258,15 +284,43 @@ async fn async_run_mux_client(opts: ConnectCommand) -> anyhow::Result<()> {
         None
     };
 
-    async_run_with_domain_as_default(domain, cmd).await
+    async_run_with_domain_as_default(domain, cmd, layout).await
+}
+
+/// Makes a domain through which the tab that was shared with
+/// `wezterm cli share-tab` is watched, given the link that it printed
+fn share_domain(link: &str) -> anyhow::Result<Arc<dyn Domain>> {
+    let link: ShareLink = link.parse()?;
+    let tls = TlsDomainClient {
+        name: format!("share:{}", link.address),
+        remote_address: link.address,
+        share_token: Some(link.token.to_string()),
+        read_timeout: config::default_read_timeout(),
+        write_timeout: config::default_write_timeout(),
+        local_echo_threshold_ms: config::default_local_echo_threshold_ms(),
+        lock_after: config::default_lock_after(),
+        // The viewer can't close the panes of the shared tab
+        on_window_close: Some(ClosePolicy::Detach),
+        on_tab_close: Some(ClosePolicy::Detach),
+        ..Default::default()
+    };
+    let domain: Arc<dyn Domain> = Arc::new(ClientDomain::new(ClientDomainConfig::Tls(tls)));
+    Mux::get().unwrap().add_domain(&domain);
+    Ok(domain)
 }
 
 fn run_mux_client(opts: ConnectCommand) -> anyhow::Result<()> {
+    // Report a broken layout file before any window is opened
+    let layout = opts
+        .layout
+        .as_ref()
+        .map(|path| layoutfile::load_layout_file(path))
+        .transpose()?;
     let activity = Activity::new();
     build_initial_mux(&config::configuration(), None, opts.workspace.as_deref())?;
     let gui = crate::frontend::try_new()?;
     promise::spawn::spawn(async {
-        if let Err(err) = async_run_mux_client(opts).await {
+        if let Err(err) = async_run_mux_client(opts, layout).await {
             terminate_with_error(err);
         }
         drop(activity);
@@ -279,16 +333,14 @@ fn run_mux_client(opts: ConnectCommand) -> anyhow::Result<()> {
 async fn spawn_tab_in_default_domain_if_mux_is_empty(
     cmd: Option<CommandBuilder>,
     is_connecting: bool,
+    layout: Option<LayoutDescription>,
 ) -> anyhow::Result<()> {
     let mux = Mux::get().unwrap();
 
     let domain = mux.default_domain();
 
     if !is_connecting {
-        let have_panes_in_domain = mux
-            .iter_panes()
-            .iter()
-            .any(|p| p.domain_id() == domain.domain_id());
+        let have_panes_in_domain = !mux.iter_panes_for_domain(domain.domain_id()).is_empty();
 
         if have_panes_in_domain {
             return Ok(());
@@ -302,12 +354,9 @@ async fn spawn_tab_in_default_domain_if_mux_is_empty(
         *builder
     };
 
-    domain.attach(Some(window_id)).await?;
+    mux.attach_domain(&domain, Some(window_id)).await?;
 
-    let have_panes_in_domain = mux
-        .iter_panes()
-        .iter()
-        .any(|p| p.domain_id() == domain.domain_id());
+    let have_panes_in_domain = !mux.iter_panes_for_domain(domain.domain_id()).is_empty();
 
     if have_panes_in_domain {
         return Ok(());
@@ -326,6 +375,16 @@ async fn spawn_tab_in_default_domain_if_mux_is_empty(
     });
 
     let dpi = config.dpi.unwrap_or_else(|| ::window::default_dpi()) as u32;
+    if let Some(layout) = layout {
+        mux.spawn_layout(
+            &layout,
+            config.initial_size(dpi),
+            Some(window_id),
+            &mux.active_workspace(),
+        )
+        .await?;
+        return Ok(());
+    }
     let _tab = domain
         .spawn(config.initial_size(dpi), cmd, None, window_id)
         .await?;
@@ -336,7 +395,10 @@ fn update_mux_domains(config: &ConfigHandle) -> anyhow::Result<()> {
     let mux = Mux::get().unwrap();
 
     for client_config in client_domains(&config) {
-        if mux.get_domain_by_name(client_config.name()).is_some() {
+        if mux
+            .get_domain_by_name_and_kind(client_config.name(), client_config.kind())
+            .is_some()
+        {
             continue;
         }
 
@@ -349,7 +411,10 @@ fn update_mux_domains(config: &ConfigHandle) -> anyhow::Result<()> {
             continue;
         }
 
-        if mux.get_domain_by_name(&ssh_dom.name).is_some() {
+        if mux
+            .get_domain_by_name_and_kind(&ssh_dom.name, DomainKind::Ssh)
+            .is_some()
+        {
             continue;
         }
 
@@ -358,7 +423,10 @@ fn update_mux_domains(config: &ConfigHandle) -> anyhow::Result<()> {
     }
 
     for wsl_dom in &config.wsl_domains {
-        if mux.get_domain_by_name(&wsl_dom.name).is_some() {
+        if mux
+            .get_domain_by_name_and_kind(&wsl_dom.name, DomainKind::Wsl)
+            .is_some()
+        {
             continue;
         }
 
@@ -367,7 +435,10 @@ fn update_mux_domains(config: &ConfigHandle) -> anyhow::Result<()> {
     }
 
     for exec_dom in &config.exec_domains {
-        if mux.get_domain_by_name(&exec_dom.name).is_some() {
+        if mux
+            .get_domain_by_name_and_kind(&exec_dom.name, DomainKind::Exec)
+            .is_some()
+        {
             continue;
         }
 
@@ -375,10 +446,33 @@ fn update_mux_domains(config: &ConfigHandle) -> anyhow::Result<()> {
         mux.add_domain(&domain);
     }
 
-    if let Some(name) = &config.default_domain {
-        if let Some(dom) = mux.get_domain_by_name(name) {
-            mux.set_default_domain(&dom);
+    for docker_dom in &config.docker_domains {
+        if mux
+            .get_domain_by_name_and_kind(&docker_dom.name, DomainKind::Docker)
+            .is_some()
+        {
+            continue;
         }
+
+        let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new_docker(docker_dom.clone())?);
+        mux.add_domain(&domain);
+    }
+
+    if config.docker_domain_discovery {
+        promise::spawn::spawn(async {
+            if let Err(err) = mux::docker::discover_docker_domains().await {
+                log::error!("Failed to discover docker containers: {:#}", err);
+            }
+        })
+        .detach();
+    }
+
+    // Apply any change to domain_name_priority before the default
+    // domain is resolved by name
+    mux.rank_domain_names();
+
+    if let Some(dom) = mux.get_domain_by_name(config.effective_default_domain()) {
+        mux.set_default_domain(&dom);
     }
 
     Ok(())
@@ -388,10 +482,12 @@ async fn connect_to_auto_connect_domains() -> anyhow::Result<()> {
     let mux = Mux::get().unwrap();
     let domains = mux.iter_domains();
     for dom in domains {
-        if let Some(dom) = dom.downcast_ref::<ClientDomain>() {
-            if dom.connect_automatically() {
-                dom.attach(None).await?;
-            }
+        let connect_automatically = dom
+            .downcast_ref::<ClientDomain>()
+            .map(|client| client.connect_automatically())
+            .unwrap_or(false);
+        if connect_automatically {
+            mux.attach_domain(&dom, None).await?;
         }
     }
     Ok(())
@@ -400,6 +496,7 @@ async fn connect_to_auto_connect_domains() -> anyhow::Result<()> {
 async fn async_run_terminal_gui(
     cmd: Option<CommandBuilder>,
     opts: StartCommand,
+    layout: Option<LayoutDescription>,
     should_publish: bool,
 ) -> anyhow::Result<()> {
     let unix_socket_path =
@@ -414,6 +511,35 @@ async fn async_run_terminal_gui(
         connect_to_auto_connect_domains().await?;
     }
 
+    #[cfg(unix)]
+    if config::configuration().resilient_local_panes {
+        let mux = Mux::get().unwrap();
+        let config = config::configuration();
+        let dpi = config.dpi.unwrap_or_else(|| ::window::default_dpi()) as u32;
+        if let Err(err) = mux
+            .adopt_orphaned_panes(config.initial_size(dpi), &mux.active_workspace())
+            .await
+        {
+            log::error!("adopting orphaned panes: {:#}", err);
+        }
+    }
+
+    // Build the layout before gui-startup, so that the event handler
+    // can adjust it
+    let have_layout = layout.is_some();
+    if let Some(layout) = layout {
+        let mux = Mux::get().unwrap();
+        let config = config::configuration();
+        let dpi = config.dpi.unwrap_or_else(|| ::window::default_dpi()) as u32;
+        mux.spawn_layout(
+            &layout,
+            config.initial_size(dpi),
+            None,
+            &mux.active_workspace(),
+        )
+        .await?;
+    }
+
     async fn trigger_gui_startup(lua: Option<Rc<mlua::Lua>>) -> anyhow::Result<()> {
         if let Some(lua) = lua {
             let args = lua.pack_multi(())?;
@@ -430,8 +556,12 @@ async fn async_run_terminal_gui(
         persistent_toast_notification("Error", &message);
     }
 
+    if have_layout {
+        return Ok(());
+    }
+
     let is_connecting = false;
-    spawn_tab_in_default_domain_if_mux_is_empty(cmd, is_connecting).await
+    spawn_tab_in_default_domain_if_mux_is_empty(cmd, is_connecting, None).await
 }
 
 #[derive(Debug)]
@@ -443,8 +573,7 @@ enum Publish {
 
 impl Publish {
     pub fn resolve(mux: &Rc<Mux>, config: &ConfigHandle, always_new_process: bool) -> Self {
-        if mux.default_domain().domain_name() != config.default_domain.as_deref().unwrap_or("local")
-        {
+        if mux.default_domain().domain_name() != config.effective_default_domain() {
             return Self::NoConnectNoPublish;
         }
 
@@ -521,6 +650,9 @@ impl Publish {
                                         .as_deref()
                                         .unwrap_or(mux::DEFAULT_WORKSPACE)
                                 ).to_string(),
+                                reuse: None,
+                                window_target: None,
+                                no_domain_rules: false,
                             })
                             .await
                     }));
@@ -579,6 +711,8 @@ fn spawn_mux_server(unix_socket_path: PathBuf, should_publish: bool) -> anyhow::
         std::fs::remove_file(unix_socket_path).ok();
     });
 
+    wezterm_mux_server_impl::local::spawn_cli_listener();
+
     Ok(())
 }
 
@@ -590,6 +724,9 @@ fn setup_mux(
 ) -> anyhow::Result<Rc<Mux>> {
     let mux = Rc::new(mux::Mux::new(Some(local_domain.clone())));
     Mux::set_mux(&mux);
+    mux.enable_recent_dir_persistence();
+    mux_lua::enable_pane_input_event();
+    mux_lua::enable_output_watcher_events();
     let client_id = Arc::new(mux::client::ClientId::new());
     mux.register_client(client_id.clone());
     mux.replace_identity(Some(client_id));
@@ -604,8 +741,7 @@ fn setup_mux(
     crate::update::load_last_release_info_and_set_banner();
     update_mux_domains(config)?;
 
-    let default_name =
-        default_domain_name.unwrap_or(config.default_domain.as_deref().unwrap_or("local"));
+    let default_name = default_domain_name.unwrap_or(config.effective_default_domain());
 
     let domain = mux.get_domain_by_name(default_name).ok_or_else(|| {
         anyhow::anyhow!(
@@ -636,6 +772,12 @@ fn run_terminal_gui(opts: StartCommand) -> anyhow::Result<()> {
     }
 
     let config = config::configuration();
+    // Report a broken layout file before any window is opened
+    let layout = opts
+        .layout
+        .as_ref()
+        .map(|path| layoutfile::load_layout_file(path))
+        .transpose()?;
     let need_builder = !opts.prog.is_empty() || opts.cwd.is_some();
 
     let cmd = if need_builder {
@@ -661,7 +803,7 @@ fn run_terminal_gui(opts: StartCommand) -> anyhow::Result<()> {
     let mut publish = Publish::resolve(
         &mux,
         &config,
-        opts.always_new_process || opts.position.is_some(),
+        opts.always_new_process || opts.position.is_some() || layout.is_some(),
     );
     log::trace!("{:?}", publish);
     if publish.try_spawn(cmd.clone(), &config, opts.workspace.as_deref())? {
@@ -672,7 +814,8 @@ fn run_terminal_gui(opts: StartCommand) -> anyhow::Result<()> {
     let activity = Activity::new();
 
     promise::spawn::spawn(async move {
-        if let Err(err) = async_run_terminal_gui(cmd, opts, publish.should_publish()).await {
+        if let Err(err) = async_run_terminal_gui(cmd, opts, layout, publish.should_publish()).await
+        {
             terminate_with_error(err);
         }
         drop(activity);
@@ -694,8 +837,12 @@ fn fatal_toast_notification(title: &str, message: &str) {
 fn notify_on_panic() {
     let default_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| {
-        if let Some(s) = info.payload().downcast_ref::<&str>() {
-            fatal_toast_notification("Wezterm panic", s);
+        // A panic while processing the output of a pane is recovered
+        // from, and is reported in the pane instead
+        if !mux::is_catching_pane_panic() {
+            if let Some(s) = info.payload().downcast_ref::<&str>() {
+                fatal_toast_notification("Wezterm panic", s);
+            }
         }
         default_hook(info);
     }));
@@ -718,6 +865,8 @@ fn main() {
     if let Err(e) = run() {
         terminate_with_error(e);
     }
+    #[cfg(unix)]
+    mux::supervisor::shutdown_supervisors();
     Mux::shutdown();
     frontend::shutdown();
 }
@@ -727,6 +876,9 @@ fn maybe_show_configuration_error_window() {
         let err = format!("{:#}", err);
         mux::connui::show_configuration_error_message(&err);
     }
+    for warning in config::configuration_warnings() {
+        mux::connui::show_configuration_error_message(&format!("Warning: {}", warning));
+    }
 }
 
 fn run_show_keys(config: config::ConfigHandle, _cmd: &ShowKeysCommand) -> anyhow::Result<()> {
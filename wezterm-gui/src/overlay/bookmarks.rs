@@ -0,0 +1,104 @@
+//! Lists the bookmarks of a pane and prompts for one to jump to
+use super::prompt_args::ChoiceHost;
+use mux::bookmark::Bookmark;
+use mux::pane::PaneId;
+use mux::termwiztermtab::TermWizTerminal;
+use mux::Mux;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+use termwiz::lineedit::LineEditor;
+use termwiz::surface::Change;
+use termwiz::terminal::Terminal;
+
+/// The most characters of the text of a bookmarked row that are shown
+const MAX_TEXT_LEN: usize = 60;
+
+/// Fetches the bookmarks of the pane.  The panes live on the main
+/// thread, and those of a multiplexer server ask the server.
+fn fetch_bookmarks(pane_id: PaneId) -> anyhow::Result<Vec<Bookmark>> {
+    promise::spawn::block_on(promise::spawn::spawn_into_main_thread(async move {
+        let mux = Mux::get().unwrap();
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow::anyhow!("pane {} not found", pane_id))?;
+        pane.get_bookmarks().await
+    }))
+}
+
+fn describe(idx: usize, bookmark: &Bookmark) -> String {
+    if bookmark.trimmed {
+        return format!("{:>3}. {}  (content trimmed)\r\n", idx + 1, bookmark.name);
+    }
+    let text: String = bookmark.text.chars().take(MAX_TEXT_LEN).collect();
+    format!(
+        "{:>3}. {}  row {}: {}\r\n",
+        idx + 1,
+        bookmark.name,
+        bookmark.row,
+        text
+    )
+}
+
+/// Lists the bookmarks of the pane and prompts for one of them, by
+/// number or by name.  Bookmarks whose row has been trimmed from the
+/// scrollback are listed, but cannot be chosen.
+/// Returns the name of the chosen bookmark, or None if the user cancels.
+pub fn choose_bookmark(
+    mut term: TermWizTerminal,
+    pane_id: PaneId,
+) -> anyhow::Result<Option<String>> {
+    let bookmarks = fetch_bookmarks(pane_id)?;
+
+    let title = "Bookmarks";
+    let mut text = format!("{}\r\n", title);
+    if bookmarks.is_empty() {
+        text.push_str("This pane has no bookmarks.  Press Escape to close\r\n");
+        term.render(&[Change::Title(title.to_string()), Change::Text(text)])?;
+        while let Ok(Some(event)) = term.poll_input(None) {
+            if let InputEvent::Key(KeyEvent {
+                key: KeyCode::Escape | KeyCode::Enter,
+                ..
+            }) = event
+            {
+                break;
+            }
+        }
+        return Ok(None);
+    }
+
+    for (idx, bookmark) in bookmarks.iter().enumerate() {
+        text.push_str(&describe(idx, bookmark));
+    }
+    text.push_str("Press Tab to complete, Enter to jump or Escape to cancel\r\n");
+    term.render(&[Change::Title(title.to_string()), Change::Text(text)])?;
+
+    let mut host = ChoiceHost::new(
+        bookmarks
+            .iter()
+            .map(|bookmark| bookmark.name.clone())
+            .collect(),
+    );
+    loop {
+        let mut editor = LineEditor::new(&mut term);
+        editor.set_prompt("Bookmark: ");
+        let line = match editor.read_line(&mut host)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = line.trim();
+        let chosen = line
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|idx| bookmarks.get(idx))
+            .or_else(|| bookmarks.iter().find(|bookmark| bookmark.name == line));
+        let message = match chosen {
+            Some(bookmark) if !bookmark.trimmed => return Ok(Some(bookmark.name.clone())),
+            Some(bookmark) => format!(
+                "bookmark content trimmed: {} is no longer in the scrollback\r\n",
+                bookmark.name
+            ),
+            None => format!("No such bookmark: {}\r\n", line),
+        };
+        term.render(&[Change::Text(message)])?;
+    }
+}
@@ -1,36 +1,50 @@
 use crate::TermWindow;
+use mux::closeconfirm::CloseRequestId;
+use mux::domain::{CloseAction, CloseDecisions, DomainId};
+use mux::inputfilter::{deliver_input, InputSource};
 use mux::pane::PaneId;
 use mux::tab::TabId;
 use mux::termwiztermtab::TermWizTerminal;
 use mux::window::WindowId;
 use mux::Mux;
+use std::sync::Arc;
 use termwiz::cell::AttributeChange;
 use termwiz::color::ColorAttribute;
 use termwiz::input::{InputEvent, KeyCode, KeyEvent, MouseButtons, MouseEvent};
 use termwiz::surface::{Change, CursorVisibility, Position};
 use termwiz::terminal::Terminal;
 
+/// A button of a prompt: its label and the key that activates it
+struct Choice {
+    label: &'static str,
+    key: char,
+}
+
 fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Result<bool> {
+    let choices = [
+        Choice {
+            label: " [Y]es ",
+            key: 'y',
+        },
+        Choice {
+            label: " [N]o ",
+            key: 'n',
+        },
+    ];
+    Ok(run_choice_app(message, &choices, term)? == Some(0))
+}
+
+/// Shows `message` followed by a row of buttons, and returns the index
+/// of the chosen button, or None if the prompt was cancelled
+fn run_choice_app(
+    message: &str,
+    choices: &[Choice],
+    term: &mut TermWizTerminal,
+) -> anyhow::Result<Option<usize>> {
     term.set_raw_mode()?;
 
     let size = term.get_screen_size()?;
 
-    /*
-    use crate::termwindow::ICON_DATA;
-    use image::GenericImageView;
-    use std::sync::Arc;
-    use termwiz::surface::change::ImageData;
-    use termwiz::surface::TextureCoordinate;
-
-        let image_data = Arc::new(ImageData::with_raw_data(ICON_DATA.to_vec()));
-        let decoded_image = image::load_from_memory(ICON_DATA)?;
-        let logo_width_cells = 3usize;
-        let logo_width = logo_width_cells * size.xpixel;
-        let logo_scale = logo_width as f32 / decoded_image.width() as f32;
-        let logo_height = decoded_image.height() as f32 * logo_scale;
-        let logo_height_cells = (logo_height / size.ypixel as f32).ceil() as usize;
-        */
-
     // Render 80% wide, centered
     let text_width = size.cols * 80 / 100;
     let x_pos = size.cols * 10 / 100;
@@ -42,37 +56,29 @@ fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Re
     // Now we want to vertically center the prompt in the view.
     // After the prompt there will be a blank line and then the "buttons",
     // so we add two to the number of rows.
-    let top_row = (size.rows - (message_rows + 2)) / 2;
+    let top_row = size.rows.saturating_sub(message_rows + 2) / 2;
 
     let button_row = top_row + message_rows + 1;
-    let mut active = ActiveButton::None;
-
-    let yes_x = x_pos;
-    let yes_w = 7;
+    let mut active = None;
 
-    let no_x =  yes_x + yes_w + 8 /* spacer */;
-    let no_w = 6;
-
-    #[derive(Copy, Clone, PartialEq, Eq)]
-    enum ActiveButton {
-        None,
-        Yes,
-        No,
+    // The starting column of each button
+    let mut button_x = vec![];
+    let mut x = x_pos;
+    for choice in choices {
+        button_x.push(x);
+        x += choice.label.len() + 8 /* spacer */;
     }
+    let button_at = |x: usize| {
+        choices
+            .iter()
+            .zip(button_x.iter())
+            .position(|(choice, &start)| x >= start && x < start + choice.label.len())
+    };
 
-    let render = |term: &mut TermWizTerminal, active: ActiveButton| -> termwiz::Result<()> {
+    let render = |term: &mut TermWizTerminal, active: Option<usize>| -> termwiz::Result<()> {
         let mut changes = vec![
             Change::ClearScreen(ColorAttribute::Default),
             Change::CursorVisibility(CursorVisibility::Hidden),
-            /*
-            Change::Image(termwiz::surface::change::Image {
-                width: logo_width_cells,
-                height: logo_height_cells,
-                top_left: TextureCoordinate::new_f32(0., 0.),
-                bottom_right: TextureCoordinate::new_f32(1., 1.),
-                image: Arc::clone(&image_data),
-            }),
-            */
         ];
 
         for (y, row) in wrapped.split("\n").enumerate() {
@@ -84,27 +90,18 @@ fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Re
             changes.push(Change::Text(row.to_string()));
         }
 
-        changes.push(Change::CursorPosition {
-            x: Position::Absolute(x_pos),
-            y: Position::Absolute(button_row),
-        });
-
-        if active == ActiveButton::Yes {
-            changes.push(AttributeChange::Reverse(true).into());
-        }
-        changes.push(" [Y]es ".into());
-        if active == ActiveButton::Yes {
-            changes.push(AttributeChange::Reverse(false).into());
-        }
-
-        changes.push("        ".into());
-
-        if active == ActiveButton::No {
-            changes.push(AttributeChange::Reverse(true).into());
-        }
-        changes.push(" [N]o ".into());
-        if active == ActiveButton::No {
-            changes.push(AttributeChange::Reverse(false).into());
+        for (idx, choice) in choices.iter().enumerate() {
+            changes.push(Change::CursorPosition {
+                x: Position::Absolute(button_x[idx]),
+                y: Position::Absolute(button_row),
+            });
+            if active == Some(idx) {
+                changes.push(AttributeChange::Reverse(true).into());
+            }
+            changes.push(choice.label.into());
+            if active == Some(idx) {
+                changes.push(AttributeChange::Reverse(false).into());
+            }
         }
 
         term.render(&changes)?;
@@ -116,20 +113,19 @@ fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Re
     while let Ok(Some(event)) = term.poll_input(None) {
         match event {
             InputEvent::Key(KeyEvent {
-                key: KeyCode::Char('y' | 'Y'),
+                key: KeyCode::Char(c),
                 ..
             }) => {
-                return Ok(true);
+                let c = c.to_ascii_lowercase();
+                if let Some(idx) = choices.iter().position(|choice| choice.key == c) {
+                    return Ok(Some(idx));
+                }
             }
             InputEvent::Key(KeyEvent {
-                key: KeyCode::Char('n' | 'N'),
-                ..
-            })
-            | InputEvent::Key(KeyEvent {
                 key: KeyCode::Escape,
                 ..
             }) => {
-                return Ok(false);
+                return Ok(None);
             }
             InputEvent::Mouse(MouseEvent {
                 x,
@@ -139,23 +135,14 @@ fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Re
             }) => {
                 let x = x as usize;
                 let y = y as usize;
-                if y == button_row && x >= yes_x && x < yes_x + yes_w {
-                    active = ActiveButton::Yes;
-                    if mouse_buttons == MouseButtons::LEFT {
-                        return Ok(true);
-                    }
-                } else if y == button_row && x >= no_x && x < no_x + no_w {
-                    active = ActiveButton::No;
-                    if mouse_buttons == MouseButtons::LEFT {
-                        return Ok(false);
-                    }
-                } else {
-                    active = ActiveButton::None;
+                active = if y == button_row { button_at(x) } else { None };
+                if mouse_buttons == MouseButtons::LEFT && active.is_some() {
+                    return Ok(active);
                 }
 
                 if mouse_buttons != MouseButtons::NONE {
                     // Treat any other mouse button as cancel
-                    return Ok(false);
+                    return Ok(None);
                 }
             }
             _ => {}
@@ -164,23 +151,73 @@ fn run_confirmation_app(message: &str, term: &mut TermWizTerminal) -> anyhow::Re
         render(term, active)?;
     }
 
-    Ok(false)
+    Ok(None)
+}
+
+/// Prompts for the pending close `id` of the mux, and passes the
+/// answer on to the mux, which closes its panes if it is yes.
+/// The prompt may be dismissed before it is answered, when the request
+/// has been decided elsewhere.
+pub fn confirm_pending_close(
+    id: CloseRequestId,
+    message: String,
+    mut term: TermWizTerminal,
+) -> anyhow::Result<()> {
+    let confirmed = run_confirmation_app(&message, &mut term)?;
+    promise::spawn::spawn_into_main_thread(async move {
+        let mux = Mux::get().unwrap();
+        mux.answer_close_confirmation(id, confirmed);
+    })
+    .detach();
+
+    Ok(())
 }
 
-pub fn confirm_close_pane(
+/// Asks whether the panes that are linked to be closed together with
+/// `pane_id` should be closed along with it
+pub fn confirm_close_linked_panes(
     pane_id: PaneId,
+    partners: Vec<(PaneId, String)>,
     mut term: TermWizTerminal,
-    mux_window_id: WindowId,
     window: ::window::Window,
 ) -> anyhow::Result<()> {
-    if run_confirmation_app("🛑 Really kill this pane?", &mut term)? {
+    let titles: Vec<String> = partners
+        .iter()
+        .map(|(_, title)| format!("'{}'", title))
+        .collect();
+    let message = format!(
+        "🔗 This pane is linked with {} other pane{}: {}.  Close them too?",
+        partners.len(),
+        if partners.len() == 1 { "" } else { "s" },
+        titles.join(", ")
+    );
+    let choices = [
+        Choice {
+            label: " [A]ll ",
+            key: 'a',
+        },
+        Choice {
+            label: " [T]his pane ",
+            key: 't',
+        },
+        Choice {
+            label: " [C]ancel ",
+            key: 'c',
+        },
+    ];
+    let to_close: Vec<PaneId> = match run_choice_app(&message, &choices, &mut term)? {
+        Some(0) => std::iter::once(pane_id)
+            .chain(partners.into_iter().map(|(id, _)| id))
+            .collect(),
+        Some(1) => vec![pane_id],
+        _ => vec![],
+    };
+    if !to_close.is_empty() {
         promise::spawn::spawn_into_main_thread(async move {
             let mux = Mux::get().unwrap();
-            let tab = match mux.get_active_tab_for_window(mux_window_id) {
-                Some(tab) => tab,
-                None => return,
-            };
-            tab.kill_pane(pane_id);
+            for pane_id in to_close {
+                mux.remove_pane(pane_id);
+            }
         })
         .detach();
     }
@@ -189,44 +226,55 @@ pub fn confirm_close_pane(
     Ok(())
 }
 
-pub fn confirm_close_tab(
-    tab_id: TabId,
+pub fn confirm_unlock_pane(
+    pane_id: PaneId,
+    domain_name: &str,
     mut term: TermWizTerminal,
-    _mux_window_id: WindowId,
     window: ::window::Window,
 ) -> anyhow::Result<()> {
     if run_confirmation_app(
-        "🛑 Really kill this tab and all contained panes?",
+        &format!(
+            "🔒 This pane in domain `{}` is locked, and the input that you typed \
+             into it is being held.  Unlock it and send that input?",
+            domain_name
+        ),
         &mut term,
     )? {
         promise::spawn::spawn_into_main_thread(async move {
             let mux = Mux::get().unwrap();
-            mux.remove_tab(tab_id);
+            mux.unlock_pane(pane_id);
         })
         .detach();
     }
-    TermWindow::schedule_cancel_overlay(window, tab_id, None);
+    TermWindow::schedule_cancel_overlay_for_pane(window, pane_id);
 
     Ok(())
 }
 
-pub fn confirm_close_window(
+/// Asks whether input that an input filter rejected, and that the
+/// filter wants confirmed, should be sent to the pane anyway
+pub fn confirm_rejected_input(
+    pane_id: PaneId,
+    message: String,
+    source: InputSource,
+    data: Arc<Vec<u8>>,
     mut term: TermWizTerminal,
-    mux_window_id: WindowId,
     window: ::window::Window,
-    tab_id: TabId,
 ) -> anyhow::Result<()> {
-    if run_confirmation_app(
-        "🛑 Really kill this window and all contained tabs and panes?",
-        &mut term,
-    )? {
+    if run_confirmation_app(&message, &mut term)? {
         promise::spawn::spawn_into_main_thread(async move {
             let mux = Mux::get().unwrap();
-            mux.kill_window(mux_window_id);
+            let pane = match mux.get_pane(pane_id) {
+                Some(pane) => pane,
+                None => return,
+            };
+            if let Err(err) = deliver_input(&pane, &data, source) {
+                log::error!("sending confirmed input to pane {}: {:#}", pane_id, err);
+            }
         })
         .detach();
     }
-    TermWindow::schedule_cancel_overlay(window, tab_id, None);
+    TermWindow::schedule_cancel_overlay_for_pane(window, pane_id);
 
     Ok(())
 }
@@ -248,3 +296,62 @@ pub fn confirm_quit_program(
 
     Ok(())
 }
+
+/// The window or tab whose closing is being confirmed
+#[derive(Debug, Clone, Copy)]
+pub enum CloseTarget {
+    Window(WindowId),
+    Tab(TabId),
+}
+
+/// Asks whether the panes of the domains in `domain_ids`, whose close
+/// policy is to ask, should be detached or killed, and then closes
+/// `target` accordingly
+pub fn confirm_close_policy(
+    message: String,
+    domain_ids: Vec<DomainId>,
+    target: CloseTarget,
+    mut term: TermWizTerminal,
+    window: ::window::Window,
+    tab_id: TabId,
+) -> anyhow::Result<()> {
+    let choices = [
+        Choice {
+            label: " [D]etach ",
+            key: 'd',
+        },
+        Choice {
+            label: " [K]ill ",
+            key: 'k',
+        },
+        Choice {
+            label: " [C]ancel ",
+            key: 'c',
+        },
+    ];
+    let action = match run_choice_app(&message, &choices, &mut term)? {
+        Some(0) => Some(CloseAction::Detach),
+        Some(1) => Some(CloseAction::Kill),
+        _ => None,
+    };
+    if let Some(action) = action {
+        promise::spawn::spawn_into_main_thread(async move {
+            let mux = Mux::get().unwrap();
+            let decisions: CloseDecisions = domain_ids
+                .into_iter()
+                .map(|domain_id| (domain_id, action))
+                .collect();
+            let result = match target {
+                CloseTarget::Window(window_id) => mux.close_window(window_id, &decisions),
+                CloseTarget::Tab(tab_id) => mux.close_tab(tab_id, &decisions).map(|_| ()),
+            };
+            if let Err(err) = result {
+                log::error!("{:#}", err);
+            }
+        })
+        .detach();
+    }
+    TermWindow::schedule_cancel_overlay(window, tab_id, None);
+
+    Ok(())
+}
@@ -3,6 +3,7 @@ use crate::termwindow::{TermWindow, TermWindowNotif};
 use config::keyassignment::{
     CopyModeAssignment, KeyAssignment, KeyTable, KeyTableEntry, ScrollbackEraseMode, SelectionMode,
 };
+use config::Palette;
 use mux::domain::DomainId;
 use mux::pane::{Pane, PaneId, Pattern, SearchResult};
 use mux::renderable::*;
@@ -856,6 +857,10 @@ impl Pane for CopyOverlay {
         self.delegate.palette()
     }
 
+    fn get_palette_overrides(&self) -> Option<Palette> {
+        self.delegate.get_palette_overrides()
+    }
+
     fn domain_id(&self) -> DomainId {
         self.delegate.domain_id()
     }
@@ -14,6 +14,7 @@ use termwiz::input::{InputEvent, KeyCode, KeyEvent};
 use termwiz::lineedit::*;
 use termwiz::surface::Change;
 use termwiz::terminal::Terminal;
+use wezterm_term::TerminalModes;
 
 lazy_static::lazy_static! {
     static ref LATEST_LOG_ENTRY: Mutex<Option<DateTime<Local>>> = Mutex::new(None);
@@ -132,10 +133,39 @@ impl LineEditorHost for LuaReplHost {
     }
 }
 
+/// Summarizes the modes of a pane as the names of those that are set
+fn describe_modes(modes: &TerminalModes) -> String {
+    let mut set = vec![];
+    for (name, enabled) in [
+        ("application_cursor_keys", modes.application_cursor_keys),
+        ("application_keypad", modes.application_keypad),
+        ("bracketed_paste", modes.bracketed_paste),
+        ("mouse_tracking", modes.mouse_tracking),
+        ("button_event_mouse", modes.button_event_mouse),
+        ("any_event_mouse", modes.any_event_mouse),
+        ("focus_tracking", modes.focus_tracking),
+        ("alt_screen", modes.alt_screen),
+    ] {
+        if enabled {
+            set.push(name.to_string());
+        }
+    }
+    if modes.mouse_tracking || modes.button_event_mouse || modes.any_event_mouse {
+        set.push(format!("mouse_encoding={:?}", modes.mouse_encoding));
+    }
+    if set.is_empty() {
+        "none".to_string()
+    } else {
+        set.join(" ")
+    }
+}
+
 pub fn show_debug_overlay(
     mut term: TermWizTerminal,
     gui_win: GuiWin,
     opengl_info: String,
+    pane_modes: Option<TerminalModes>,
+    pty_quirks: Option<String>,
 ) -> anyhow::Result<()> {
     term.no_grab_mouse_in_raw_mode();
 
@@ -195,10 +225,24 @@ pub fn show_debug_overlay(
         term.render(&changes)
     }
 
+    let pane_modes = match &pane_modes {
+        Some(modes) => describe_modes(modes),
+        None => "unknown".to_string(),
+    };
+
+    let pty_quirks = pty_quirks.unwrap_or_else(|| "none".to_string());
+
+    let (suppressed_remote, suppressed_local) = mux::coalesce::suppressed_counts();
+
     term.render(&[Change::Text(format!(
         "Debug Overlay\r\n\
          wezterm version: {}\r\n\
          OpenGL version: {opengl_info}\r\n\
+         Pane modes: {pane_modes}\r\n\
+         Pane pty: {pty_quirks}\r\n\
+         Coalesced title/cwd/user var updates: \
+         {suppressed_remote} not sent to mux clients, \
+         {suppressed_local} not dispatched to the GUI\r\n\
          Enter lua statements or expressions and hit Enter.\r\n\
          Press ESC or CTRL-D to exit\r\n",
         config::wezterm_version()
@@ -0,0 +1,366 @@
+//! Searches the scrollback of every pane for the `GlobalSearch` key
+//! assignment, and lists the matches so that the user can jump to one.
+//! The search itself runs on the main thread, where the panes live,
+//! while the overlay waits for it; pressing Escape in the meantime
+//! cancels it.  The list of matches can be narrowed down by fuzzy
+//! matching, as in the launcher.
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use mux::globalsearch::{GlobalSearchMatch, GlobalSearchOptions, SearchCancellation};
+use mux::pane::{PaneId, Pattern};
+use mux::termwiztermtab::TermWizTerminal;
+use mux::Mux;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Duration;
+use termwiz::cell::AttributeChange;
+use termwiz::color::ColorAttribute;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent, Modifiers};
+use termwiz::lineedit::{LineEditor, NopLineEditorHost};
+use termwiz::surface::{Change, Position};
+use termwiz::terminal::Terminal;
+use termwiz_funcs::truncate_right;
+
+const ROW_OVERHEAD: usize = 3;
+
+/// How often the overlay checks for Escape while the search runs
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type SearchOutcome = anyhow::Result<(Vec<GlobalSearchMatch>, Vec<PaneId>, HashMap<PaneId, String>)>;
+
+/// Starts the search on the main thread, returning the receiver of its
+/// outcome, along with the titles of the panes that matched
+fn start_search(
+    pattern: Pattern,
+    options: GlobalSearchOptions,
+    cancel: SearchCancellation,
+) -> Receiver<SearchOutcome> {
+    let (tx, rx) = channel();
+    promise::spawn::spawn_into_main_thread(async move {
+        promise::spawn::spawn(async move {
+            let mux = Mux::get().unwrap();
+            let outcome = mux
+                .search_all_panes(pattern, &options, &cancel)
+                .await
+                .map(|results| {
+                    let titles = results
+                        .matches
+                        .iter()
+                        .filter_map(|found| {
+                            let pane = mux.get_pane(found.pane_id)?;
+                            Some((found.pane_id, pane.get_title()))
+                        })
+                        .collect();
+                    (results.matches, results.truncated, titles)
+                });
+            tx.send(outcome).ok();
+        })
+        .detach();
+    })
+    .detach();
+    rx
+}
+
+/// Waits for the outcome of the search, cancelling it if the user
+/// presses Escape, in which case None is returned
+fn wait_for_search(
+    term: &mut TermWizTerminal,
+    rx: &Receiver<SearchOutcome>,
+    cancel: &SearchCancellation,
+) -> anyhow::Result<Option<SearchOutcome>> {
+    loop {
+        match rx.try_recv() {
+            Ok(outcome) => return Ok(Some(outcome)),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => anyhow::bail!("the search was abandoned"),
+        }
+        if let Some(InputEvent::Key(KeyEvent {
+            key: KeyCode::Escape,
+            ..
+        })) = term.poll_input(Some(POLL_INTERVAL))?
+        {
+            cancel.cancel();
+            return Ok(None);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    label: String,
+    found: GlobalSearchMatch,
+}
+
+struct GlobalSearchState {
+    active_idx: usize,
+    max_items: usize,
+    top_row: usize,
+    entries: Vec<Entry>,
+    filter_term: String,
+    filtered_entries: Vec<Entry>,
+    filtering: bool,
+    truncated: usize,
+}
+
+impl GlobalSearchState {
+    fn update_filter(&mut self) {
+        if self.filter_term.is_empty() {
+            self.filtered_entries = self.entries.clone();
+            return;
+        }
+
+        let matcher = SkimMatcherV2::default();
+        let mut scores: Vec<(usize, i64)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(row_idx, entry)| {
+                let score = matcher.fuzzy_match(&entry.label, &self.filter_term)?;
+                Some((row_idx, score))
+            })
+            .collect();
+        scores.sort_by(|a, b| a.1.cmp(&b.1).reverse());
+
+        self.filtered_entries = scores
+            .into_iter()
+            .map(|(row_idx, _)| self.entries[row_idx].clone())
+            .collect();
+        self.active_idx = 0;
+        self.top_row = 0;
+    }
+
+    fn render(&mut self, term: &mut TermWizTerminal) -> termwiz::Result<()> {
+        let size = term.get_screen_size()?;
+        let max_width = size.cols.saturating_sub(6);
+
+        let mut heading = format!(
+            "{} matches; Enter=jump  Esc=cancel  /=filter",
+            self.entries.len()
+        );
+        if self.truncated > 0 {
+            heading.push_str(&format!(
+                "  (only the newest were kept for {} panes)",
+                self.truncated
+            ));
+        }
+        let mut changes = vec![
+            Change::ClearScreen(ColorAttribute::Default),
+            Change::CursorPosition {
+                x: Position::Absolute(0),
+                y: Position::Absolute(0),
+            },
+            Change::Text(format!("{}\r\n", truncate_right(&heading, max_width))),
+        ];
+
+        for (row_num, (entry_idx, entry)) in self
+            .filtered_entries
+            .iter()
+            .enumerate()
+            .skip(self.top_row)
+            .enumerate()
+        {
+            if row_num > self.max_items {
+                break;
+            }
+            if entry_idx == self.active_idx {
+                changes.push(AttributeChange::Reverse(true).into());
+            }
+            changes.push(Change::Text(format!(
+                "    {} \r\n",
+                truncate_right(&entry.label, max_width)
+            )));
+            if entry_idx == self.active_idx {
+                changes.push(AttributeChange::Reverse(false).into());
+            }
+        }
+
+        if self.filtering || !self.filter_term.is_empty() {
+            changes.append(&mut vec![
+                Change::CursorPosition {
+                    x: Position::Absolute(0),
+                    y: Position::Absolute(0),
+                },
+                Change::ClearToEndOfLine(ColorAttribute::Default),
+                Change::Text(truncate_right(
+                    &format!("Fuzzy matching: {}", self.filter_term),
+                    max_width,
+                )),
+            ]);
+        }
+
+        term.render(&changes)
+    }
+
+    fn move_up(&mut self) {
+        self.active_idx = self.active_idx.saturating_sub(1);
+        if self.active_idx < self.top_row {
+            self.top_row = self.active_idx;
+        }
+    }
+
+    fn move_down(&mut self) {
+        self.active_idx = (self.active_idx + 1).min(self.filtered_entries.len().saturating_sub(1));
+        if self.active_idx + self.top_row > self.max_items {
+            self.top_row = self.active_idx.saturating_sub(self.max_items);
+        }
+    }
+
+    fn run_loop(
+        &mut self,
+        term: &mut TermWizTerminal,
+    ) -> anyhow::Result<Option<GlobalSearchMatch>> {
+        while let Ok(Some(event)) = term.poll_input(None) {
+            match event {
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('j'),
+                    ..
+                }) if !self.filtering => {
+                    self.move_down();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('k'),
+                    ..
+                }) if !self.filtering => {
+                    self.move_up();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('P'),
+                    modifiers: Modifiers::CTRL,
+                })
+                | InputEvent::Key(KeyEvent {
+                    key: KeyCode::UpArrow,
+                    ..
+                }) => {
+                    self.move_up();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('N'),
+                    modifiers: Modifiers::CTRL,
+                })
+                | InputEvent::Key(KeyEvent {
+                    key: KeyCode::DownArrow,
+                    ..
+                }) => {
+                    self.move_down();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('/'),
+                    ..
+                }) if !self.filtering => {
+                    self.filtering = true;
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Backspace,
+                    ..
+                }) => {
+                    if self.filter_term.pop().is_none() {
+                        self.filtering = false;
+                    }
+                    self.update_filter();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char('G'),
+                    modifiers: Modifiers::CTRL,
+                })
+                | InputEvent::Key(KeyEvent {
+                    key: KeyCode::Escape,
+                    ..
+                }) => {
+                    return Ok(None);
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Char(c),
+                    ..
+                }) if self.filtering => {
+                    self.filter_term.push(c);
+                    self.update_filter();
+                }
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::Enter,
+                    ..
+                }) => {
+                    return Ok(self
+                        .filtered_entries
+                        .get(self.active_idx)
+                        .map(|entry| entry.found.clone()));
+                }
+                InputEvent::Resized { rows, .. } => {
+                    self.max_items = rows.saturating_sub(ROW_OVERHEAD);
+                }
+                _ => {}
+            }
+            self.render(term)?;
+        }
+        Ok(None)
+    }
+}
+
+/// Prompts for the text to search for
+fn prompt_for_pattern(term: &mut TermWizTerminal) -> anyhow::Result<Option<Pattern>> {
+    let mut host = NopLineEditorHost::default();
+    let mut editor = LineEditor::new(term);
+    editor.set_prompt("Search all panes for: ");
+    Ok(editor
+        .read_line(&mut host)?
+        .filter(|line| !line.is_empty())
+        .map(Pattern::CaseInSensitiveString))
+}
+
+/// Searches the panes that `options` selects for `pattern`, asking
+/// for it first if it is empty, and lists the matches.
+/// Returns the match that the user picks, or None if they cancel.
+pub fn global_search(
+    mut term: TermWizTerminal,
+    pattern: Pattern,
+    options: GlobalSearchOptions,
+) -> anyhow::Result<Option<GlobalSearchMatch>> {
+    term.render(&[Change::Title("Search all panes".to_string())])?;
+    let pattern = if pattern.is_empty() {
+        match prompt_for_pattern(&mut term)? {
+            Some(pattern) => pattern,
+            None => return Ok(None),
+        }
+    } else {
+        pattern
+    };
+
+    term.set_raw_mode()?;
+    term.render(&[
+        Change::ClearScreen(ColorAttribute::Default),
+        Change::Text("Searching... press Escape to cancel\r\n".to_string()),
+    ])?;
+    let cancel = SearchCancellation::default();
+    let rx = start_search(pattern, options, cancel.clone());
+    let (matches, truncated, titles) = match wait_for_search(&mut term, &rx, &cancel)? {
+        Some(outcome) => outcome?,
+        None => return Ok(None),
+    };
+
+    let entries = matches
+        .into_iter()
+        .map(|found| Entry {
+            label: format!(
+                "{} (pane {}, row {}): {}",
+                titles.get(&found.pane_id).map(String::as_str).unwrap_or(""),
+                found.pane_id,
+                found.result.start_y,
+                found.snippet.trim()
+            ),
+            found,
+        })
+        .collect();
+    let size = term.get_screen_size()?;
+    let mut state = GlobalSearchState {
+        active_idx: 0,
+        max_items: size.rows.saturating_sub(ROW_OVERHEAD),
+        top_row: 0,
+        entries,
+        filter_term: String::new(),
+        filtered_entries: vec![],
+        filtering: false,
+        truncated: truncated.len(),
+    };
+    state.update_filter();
+    state.render(&mut term)?;
+    state.run_loop(&mut term)
+}
@@ -8,11 +8,14 @@
 use crate::inputmap::InputMap;
 use crate::termwindow::TermWindowNotif;
 use config::configuration;
-use config::keyassignment::{KeyAssignment, SpawnCommand, SpawnTabDomain};
+use config::keyassignment::{CommandDir, KeyAssignment, SpawnCommand, SpawnTabDomain};
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
-use mux::domain::{DomainId, DomainState};
+use mlua::FromLua;
+use mux::domain::{DomainId, DomainInfo, DomainState, PaletteEntry};
+use mux::history::RecentDir;
 use mux::pane::PaneId;
+use mux::resources::format_bytes;
 use mux::tab::TabId;
 use mux::termwiztermtab::TermWizTerminal;
 use mux::window::WindowId;
@@ -41,23 +44,90 @@ pub struct LauncherTabEntry {
     pub pane_count: usize,
 }
 
+/// The launcher entries for a domain are grouped together
+/// under a heading derived from its `DomainInfo`
 #[derive(Debug)]
-pub struct LauncherDomainEntry {
-    pub domain_id: DomainId,
-    pub name: String,
-    pub state: DomainState,
-    pub label: String,
+pub struct LauncherDomainSection {
+    pub info: DomainInfo,
+    pub heading: String,
+    pub palette_entries: Vec<PaletteEntry>,
+}
+
+impl LauncherDomainSection {
+    fn new(info: DomainInfo) -> Self {
+        // Resolved here, rather than when collecting the domains, as
+        // format-launcher-domains may have filtered or renamed them
+        let palette_entries = Mux::get()
+            .and_then(|mux| mux.get_domain(info.domain_id))
+            .map(|domain| domain.palette_entries())
+            .unwrap_or_default();
+        let label = if info.name == info.label || info.label == "" {
+            format!("domain `{}`", info.name)
+        } else {
+            format!("domain `{}` - {}", info.name, info.label)
+        };
+        let heading = if info.num_panes == 0 {
+            label
+        } else if let Some(usage) = &info.resource_usage {
+            format!(
+                "{} ({} windows, {} tabs, {} panes, {})",
+                label,
+                info.num_windows,
+                info.num_tabs,
+                info.num_panes,
+                format_bytes(usage.rss_bytes)
+            )
+        } else {
+            format!(
+                "{} ({} windows, {} tabs, {} panes)",
+                label, info.num_windows, info.num_tabs, info.num_panes
+            )
+        };
+        Self {
+            info,
+            heading,
+            palette_entries,
+        }
+    }
+}
+
+/// Gives the `format-launcher-domains` event the opportunity to
+/// filter, reorder or relabel the domains shown in the launcher
+fn call_format_launcher_domains(domains: Vec<DomainInfo>) -> Vec<DomainInfo> {
+    match config::run_immediate_with_lua_config(|lua| {
+        if let Some(lua) = lua {
+            let seq = lua.create_sequence_from(domains.clone().into_iter())?;
+            let v = config::lua::emit_sync_callback(
+                &*lua,
+                ("format-launcher-domains".to_string(), (seq,)),
+            )?;
+            match &v {
+                mlua::Value::Nil => Ok(None),
+                _ => Ok(Some(<Vec<DomainInfo>>::from_lua(v, &*lua)?)),
+            }
+        } else {
+            Ok(None)
+        }
+    }) {
+        Ok(Some(result)) => result,
+        Ok(None) => domains,
+        Err(err) => {
+            log::warn!("format-launcher-domains: {}", err);
+            domains
+        }
+    }
 }
 
 pub struct LauncherArgs {
     flags: LauncherFlags,
-    domains: Vec<LauncherDomainEntry>,
+    domains: Vec<LauncherDomainSection>,
     tabs: Vec<LauncherTabEntry>,
     pane_id: PaneId,
     domain_id_of_current_tab: DomainId,
     title: String,
     active_workspace: String,
     workspaces: Vec<String>,
+    recent_dirs: Vec<RecentDir>,
 }
 
 impl LauncherArgs {
@@ -106,11 +176,30 @@ impl LauncherArgs {
 
         let domains = if flags.contains(LauncherFlags::DOMAINS) {
             let mut domains = mux.iter_domains();
+            domains.retain(|dom| dom.spawnable() && !dom.launcher_hints().hidden);
+            let config = configuration();
             domains.sort_by(|a, b| {
+                use std::cmp::Ordering;
+                // Domains named by domain_order come first, in that order
+                match (
+                    config.domain_order_rank(a.domain_name()),
+                    config.domain_order_rank(b.domain_name()),
+                ) {
+                    (Some(a_rank), Some(b_rank)) => return a_rank.cmp(&b_rank),
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    (None, None) => {}
+                }
+                // Domains with a sort_key come first, in order of the key
+                match (a.launcher_hints().sort_key, b.launcher_hints().sort_key) {
+                    (Some(a_key), Some(b_key)) if a_key != b_key => return a_key.cmp(&b_key),
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    _ => {}
+                }
                 let a_state = a.state();
                 let b_state = b.state();
                 if a_state != b_state {
-                    use std::cmp::Ordering;
                     return if a_state == DomainState::Attached {
                         Ordering::Less
                     } else {
@@ -119,24 +208,27 @@ impl LauncherArgs {
                 }
                 a.domain_id().cmp(&b.domain_id())
             });
-            domains.retain(|dom| dom.spawnable());
-            let mut d = vec![];
+            let mut infos = vec![];
             for dom in domains.into_iter() {
-                let name = dom.domain_name();
-                let label = dom.domain_label().await;
-                let label = if name == label || label == "" {
-                    format!("domain `{}`", name)
-                } else {
-                    format!("domain `{}` - {}", name, label)
-                };
-                d.push(LauncherDomainEntry {
-                    domain_id: dom.domain_id(),
-                    name: name.to_string(),
-                    state: dom.state(),
-                    label,
-                });
+                infos.push(DomainInfo::new(&dom).await);
             }
-            d
+            call_format_launcher_domains(infos)
+                .into_iter()
+                .map(LauncherDomainSection::new)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let recent_dirs = if flags.contains(LauncherFlags::RECENT_DIRS) {
+            // Only offer the directories of domains that can spawn now
+            let mut dirs = mux.recent_dirs(None);
+            dirs.retain(|dir| {
+                mux.get_domain_by_name(&dir.domain).map_or(false, |dom| {
+                    dom.spawnable() && dom.state() == DomainState::Attached
+                })
+            });
+            dirs
         } else {
             vec![]
         };
@@ -145,6 +237,7 @@ impl LauncherArgs {
             flags,
             domains,
             tabs,
+            recent_dirs,
             pane_id,
             domain_id_of_current_tab,
             title: title.to_string(),
@@ -206,6 +299,18 @@ impl LauncherState {
         self.top_row = 0;
     }
 
+    fn push_recent_dir(&mut self, dir: &RecentDir) {
+        let cwd = CommandDir::parse(&dir.cwd);
+        self.entries.push(Entry {
+            label: format!("{} ({})", cwd.path, dir.domain),
+            action: KeyAssignment::SpawnCommandInNewTab(SpawnCommand {
+                domain: SpawnTabDomain::DomainName(dir.domain.clone()),
+                cwd: Some(cwd),
+                ..SpawnCommand::default()
+            }),
+        });
+    }
+
     fn build_entries(&mut self, args: LauncherArgs) {
         let config = configuration();
         // Pull in the user defined entries from the launch_menu
@@ -225,29 +330,49 @@ impl LauncherState {
             }
         }
 
-        for domain in &args.domains {
-            let entry = if domain.state == DomainState::Attached {
-                Entry {
-                    label: format!("New Tab ({})", domain.label),
-                    action: KeyAssignment::SpawnCommandInNewTab(SpawnCommand {
-                        domain: SpawnTabDomain::DomainName(domain.name.to_string()),
-                        ..SpawnCommand::default()
-                    }),
+        for section in &args.domains {
+            let info = &section.info;
+            if info.state == DomainState::Attached {
+                // Preselect the entry that corresponds to the active tab
+                // at the time that the launcher was set up, so that pressing
+                // Enter immediately afterwards spawns a tab in the same domain.
+                if info.domain_id == args.domain_id_of_current_tab {
+                    self.active_idx = self.entries.len();
                 }
+                let spawn = SpawnCommand {
+                    domain: SpawnTabDomain::DomainName(info.name.to_string()),
+                    ..SpawnCommand::default()
+                };
+                self.entries.push(Entry {
+                    label: format!("New Tab ({})", section.heading),
+                    action: KeyAssignment::SpawnCommandInNewTab(spawn),
+                });
             } else {
-                Entry {
-                    label: format!("Attach {}", domain.label),
-                    action: KeyAssignment::AttachDomain(domain.name.to_string()),
-                }
-            };
-
-            // Preselect the entry that corresponds to the active tab
-            // at the time that the launcher was set up, so that pressing
-            // Enter immediately afterwards spawns a tab in the same domain.
-            if domain.domain_id == args.domain_id_of_current_tab {
-                self.active_idx = self.entries.len();
+                self.entries.push(Entry {
+                    label: format!("Attach {}", section.heading),
+                    action: KeyAssignment::AttachDomain(info.name.to_string()),
+                });
+            }
+            for entry in &section.palette_entries {
+                let label = match &entry.icon {
+                    Some(icon) => format!("{} {} ({})", icon, entry.label, section.heading),
+                    None => format!("{} ({})", entry.label, section.heading),
+                };
+                self.entries.push(Entry {
+                    label,
+                    action: KeyAssignment::PerformDomainAction {
+                        domain: info.name.to_string(),
+                        action: entry.action.to_string(),
+                    },
+                });
+            }
+            for dir in args
+                .recent_dirs
+                .iter()
+                .filter(|dir| dir.domain == info.name)
+            {
+                self.push_recent_dir(dir);
             }
-            self.entries.push(entry);
         }
 
         if args.flags.contains(LauncherFlags::WORKSPACES) {
@@ -274,6 +399,18 @@ impl LauncherState {
             });
         }
 
+        // Directories whose domain has no section of its own are
+        // listed after the workspaces
+        for dir in &args.recent_dirs {
+            if !args
+                .domains
+                .iter()
+                .any(|section| section.info.name == dir.domain)
+            {
+                self.push_recent_dir(dir);
+            }
+        }
+
         for tab in &args.tabs {
             self.entries.push(Entry {
                 label: format!("{}. {} panes", tab.title, tab.pane_count),
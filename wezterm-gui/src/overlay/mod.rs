@@ -6,19 +6,28 @@ use std::pin::Pin;
 use std::rc::Rc;
 use wezterm_term::TerminalSize;
 
+pub mod bookmarks;
 pub mod confirm_close_pane;
 pub mod copy;
 pub mod debug;
+pub mod globalsearch;
 pub mod launcher;
+pub mod prompt_args;
 pub mod quickselect;
+pub mod spawn_cwd;
 
+pub use bookmarks::choose_bookmark;
 pub use confirm_close_pane::{
-    confirm_close_pane, confirm_close_tab, confirm_close_window, confirm_quit_program,
+    confirm_close_linked_panes, confirm_close_policy, confirm_pending_close, confirm_quit_program,
+    confirm_rejected_input, confirm_unlock_pane, CloseTarget,
 };
 pub use copy::{CopyModeParams, CopyOverlay};
 pub use debug::show_debug_overlay;
+pub use globalsearch::global_search;
 pub use launcher::{launcher, LauncherArgs, LauncherFlags};
+pub use prompt_args::{prompt_arg_choices, prompt_for_args};
 pub use quickselect::QuickSelectOverlay;
+pub use spawn_cwd::{prompt_for_domain_and_cwd, DomainChoice};
 
 pub fn start_overlay<T, F>(
     term_window: &TermWindow,
@@ -0,0 +1,124 @@
+//! Prompts for the values of the `prompt_args` of a `SpawnCommand`
+//! before it is spawned
+use config::keyassignment::PromptArg;
+use mlua::FromLua;
+use mux::termwiztermtab::TermWizTerminal;
+use std::collections::HashMap;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+use termwiz::lineedit::*;
+use termwiz::surface::Change;
+use termwiz::terminal::Terminal;
+
+/// A line editor host that completes the start of the line to one of
+/// a fixed set of choices, and cancels when Escape is pressed
+pub struct ChoiceHost {
+    history: BasicHistory,
+    choices: Vec<String>,
+}
+
+impl ChoiceHost {
+    pub fn new(choices: Vec<String>) -> Self {
+        Self {
+            history: BasicHistory::default(),
+            choices,
+        }
+    }
+}
+
+impl LineEditorHost for ChoiceHost {
+    fn history(&mut self) -> &mut dyn History {
+        &mut self.history
+    }
+
+    fn complete(&self, line: &str, cursor_position: usize) -> Vec<CompletionCandidate> {
+        let prefix = &line[..cursor_position];
+        self.choices
+            .iter()
+            .filter(|choice| choice.starts_with(prefix))
+            .map(|choice| CompletionCandidate {
+                range: 0..cursor_position,
+                text: choice.clone(),
+            })
+            .collect()
+    }
+
+    fn resolve_action(
+        &mut self,
+        event: &InputEvent,
+        _editor: &mut LineEditor<'_>,
+    ) -> Option<Action> {
+        match event {
+            InputEvent::Key(KeyEvent {
+                key: KeyCode::Escape,
+                ..
+            }) => Some(Action::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the values to offer for completion of `arg`: its `choices`,
+/// followed by those returned by the handler of its `choices_event`.
+/// Must be called on the main thread.
+pub fn prompt_arg_choices(arg: &PromptArg) -> Vec<String> {
+    let mut choices = arg.choices.clone();
+    let event = match &arg.choices_event {
+        Some(event) => event,
+        None => return choices,
+    };
+    match config::run_immediate_with_lua_config(|lua| {
+        if let Some(lua) = lua {
+            let v = config::lua::emit_sync_callback(&*lua, (event.clone(), (arg.name.clone(),)))?;
+            match &v {
+                mlua::Value::Nil => Ok(vec![]),
+                _ => Ok(<Vec<String>>::from_lua(v, &*lua)?),
+            }
+        } else {
+            Ok(vec![])
+        }
+    }) {
+        Ok(more) => choices.extend(more),
+        Err(err) => log::warn!("{}: {:#}", event, err),
+    }
+    choices
+}
+
+/// Prompts for each of `args` in turn, offering the corresponding
+/// `choices` for tab completion.  Parameters for which the default
+/// was accepted are omitted from the result.
+/// Returns None if the user cancels.
+pub fn prompt_for_args(
+    mut term: TermWizTerminal,
+    title: &str,
+    args: Vec<PromptArg>,
+    choices: Vec<Vec<String>>,
+) -> anyhow::Result<Option<HashMap<String, String>>> {
+    term.render(&[
+        Change::Title(title.to_string()),
+        Change::Text(format!(
+            "{}\r\nPress Tab to complete, Enter to accept or Escape to cancel\r\n",
+            title
+        )),
+    ])?;
+
+    let mut values = HashMap::new();
+    for (arg, choices) in args.into_iter().zip(choices) {
+        let mut host = ChoiceHost::new(choices);
+        let prompt = arg.prompt.as_deref().unwrap_or(&arg.name);
+        let mut editor = LineEditor::new(&mut term);
+        editor.set_prompt(&match &arg.default {
+            Some(default) => format!("{} [{}]: ", prompt, default),
+            None => format!("{}: ", prompt),
+        });
+        match editor.read_line(&mut host)? {
+            // An empty line accepts the default, which is applied
+            // by `SpawnCommand::with_params`
+            Some(value) if value.is_empty() && arg.default.is_some() => {}
+            Some(value) => {
+                values.insert(arg.name, value);
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(values))
+}
@@ -1,9 +1,10 @@
 use crate::selection::{SelectionCoordinate, SelectionRange};
+use crate::termwindow::clipboard::filter_paste;
 use crate::termwindow::{TermWindow, TermWindowNotif};
 use config::keyassignment::{ClipboardCopyDestination, QuickSelectArguments, ScrollbackEraseMode};
-use config::ConfigHandle;
+use config::{ConfigHandle, Palette};
 use mux::domain::DomainId;
-use mux::pane::{Pane, PaneId, Pattern, SearchResult};
+use mux::pane::{Pane, PaneId, SearchResult};
 use mux::renderable::*;
 use rangeset::RangeSet;
 use std::cell::{RefCell, RefMut};
@@ -21,6 +22,10 @@ use wezterm_term::{
 };
 use window::WindowOps;
 
+/// How many rows above and below the viewport are matched, unless
+/// `scope_lines` says otherwise
+const DEFAULT_SCOPE_LINES: usize = 1000;
+
 const PATTERNS: [&str; 14] = [
     // markdown_url
     r"\[[^]]*\]\(([^)]+)\)",
@@ -158,10 +163,12 @@ struct MatchResult {
 
 struct QuickSelectRenderable {
     delegate: Rc<dyn Pane>,
-    /// The text that the user entered
-    pattern: Pattern,
+    /// The regexes to match
+    patterns: Vec<String>,
     /// The most recently queried set of matches
     results: Vec<SearchResult>,
+    /// The text of each of `results`
+    texts: Vec<String>,
     by_line: HashMap<StableRowIndex, Vec<MatchResult>>,
     by_label: HashMap<String, usize>,
     selection: String,
@@ -192,44 +199,23 @@ impl QuickSelectOverlay {
 
         let config = term_window.config.clone();
 
-        let mut pattern = "(?m)(".to_string();
-        let mut have_patterns = false;
+        let mut patterns = vec![];
         if !args.patterns.is_empty() {
-            for p in &args.patterns {
-                if have_patterns {
-                    pattern.push('|');
-                }
-                pattern.push_str(p);
-                have_patterns = true;
-            }
+            patterns.extend(args.patterns.iter().cloned());
         } else {
             if !config.disable_default_quick_select_patterns {
-                for p in &PATTERNS {
-                    if have_patterns {
-                        pattern.push('|');
-                    }
-                    pattern.push_str(p);
-                    have_patterns = true;
-                }
-            }
-            for p in &config.quick_select_patterns {
-                if have_patterns {
-                    pattern.push('|');
-                }
-                pattern.push_str(p);
-                have_patterns = true;
+                patterns.extend(PATTERNS.iter().map(|p| p.to_string()));
             }
+            patterns.extend(config.quick_select_patterns.iter().cloned());
         }
-        pattern.push(')');
-
-        let pattern = Pattern::Regex(pattern);
 
         let window = term_window.window.clone().unwrap();
         let mut renderer = QuickSelectRenderable {
             delegate: Rc::clone(pane),
-            pattern,
+            patterns,
             selection: "".to_string(),
             results: vec![],
+            texts: vec![],
             by_line: HashMap::new(),
             by_label: HashMap::new(),
             dirty_results: RangeSet::default(),
@@ -402,6 +388,9 @@ impl Pane for QuickSelectOverlay {
     fn palette(&self) -> ColorPalette {
         self.delegate.palette()
     }
+    fn get_palette_overrides(&self) -> Option<Palette> {
+        self.delegate.get_palette_overrides()
+    }
     fn domain_id(&self) -> DomainId {
         self.delegate.domain_id()
     }
@@ -642,22 +631,31 @@ impl QuickSelectRenderable {
         }
 
         self.results.clear();
+        self.texts.clear();
         self.by_line.clear();
         self.result_pos.take();
 
         let bar_pos = self.compute_search_row();
         self.dirty_results.add(bar_pos);
 
-        if !self.pattern.is_empty() {
+        if !self.patterns.is_empty() {
             let pane: Rc<dyn Pane> = self.delegate.clone();
             let window = self.window.clone();
-            let pattern = self.pattern.clone();
+            let patterns = self.patterns.clone();
+            let range = self.scan_range();
             promise::spawn::spawn(async move {
-                let mut results = pane.search(pattern).await?;
-                results.sort();
+                let matches = match pane.scan(patterns, range).await {
+                    Ok(matches) => matches,
+                    Err(err) => {
+                        log::error!("QuickSelect: {:#}", err);
+                        return Ok(());
+                    }
+                };
+                let (results, texts): (Vec<SearchResult>, Vec<String>) =
+                    matches.into_iter().map(|m| (m.result, m.text)).unzip();
 
                 let pane_id = pane.pane_id();
-                let mut results = Some(results);
+                let mut results = Some((results, texts));
                 window.notify(TermWindowNotif::Apply(Box::new(move |term_window| {
                     let state = term_window.pane_state(pane_id);
                     if let Some(overlay) = state.overlay.as_ref() {
@@ -665,7 +663,9 @@ impl QuickSelectRenderable {
                             overlay.pane.downcast_ref::<QuickSelectOverlay>()
                         {
                             let mut r = search_overlay.renderer.borrow_mut();
-                            r.results = results.take().unwrap();
+                            let (results, texts) = results.take().unwrap();
+                            r.results = results;
+                            r.texts = texts;
                             r.recompute_results();
                             let num_results = r.results.len();
 
@@ -701,6 +701,16 @@ impl QuickSelectRenderable {
         }
     }
 
+    /// Returns the rows to match: those in the viewport, and
+    /// `scope_lines` rows above and below it
+    fn scan_range(&self) -> Range<StableRowIndex> {
+        let scope = self.args.scope_lines.unwrap_or(DEFAULT_SCOPE_LINES) as StableRowIndex;
+        let top = self
+            .viewport
+            .unwrap_or_else(|| self.delegate.get_dimensions().physical_top);
+        top.saturating_sub(scope)..top + self.height as StableRowIndex + scope
+    }
+
     fn clear_selection(&mut self) {
         let pane_id = self.delegate.pane_id();
         self.window
@@ -713,6 +723,9 @@ impl QuickSelectRenderable {
 
     fn select_and_copy_match_number(&mut self, n: usize, paste: bool) {
         let result = self.results[n].clone();
+        // The text of the match comes from the scan, as the lines of
+        // a pane on a multiplexer server may not have been fetched
+        let text = self.texts[n].clone();
 
         let pane_id = self.delegate.pane_id();
         let action = self.args.action.clone();
@@ -738,10 +751,11 @@ impl QuickSelectRenderable {
                         selection.seqno = pane.get_current_seqno();
                     }
 
-                    let text = term_window.selection_text(&pane);
                     if !text.is_empty() {
                         if paste {
-                            let _ = pane.send_paste(&text);
+                            if let Some(text) = filter_paste(&pane, text.clone()) {
+                                let _ = pane.send_paste(&text);
+                            }
                         }
                         if let Some(action) = action {
                             let _ = term_window.perform_key_assignment(&pane, &action);
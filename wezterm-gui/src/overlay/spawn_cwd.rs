@@ -0,0 +1,188 @@
+//! Prompts for a domain and then for a working directory in that
+//! domain, completing the directory against the filesystem that the
+//! panes of the domain see
+use super::prompt_args::ChoiceHost;
+use mux::dirlist::{DirListing, MAX_ENTRIES};
+use mux::domain::DomainId;
+use mux::termwiztermtab::TermWizTerminal;
+use mux::Mux;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+use termwiz::lineedit::*;
+use termwiz::surface::Change;
+use termwiz::terminal::Terminal;
+
+/// How long a completion waits for a listing to arrive.  A listing
+/// that takes longer carries on in the background and is used by
+/// the next completion, so that a slow domain doesn't freeze the
+/// prompt.
+const LISTING_WAIT: Duration = Duration::from_millis(250);
+
+/// A domain that is offered by the prompt
+pub struct DomainChoice {
+    pub domain_id: DomainId,
+    pub name: String,
+}
+
+fn cancel_on_escape(event: &InputEvent) -> Option<Action> {
+    match event {
+        InputEvent::Key(KeyEvent {
+            key: KeyCode::Escape,
+            ..
+        }) => Some(Action::Cancel),
+        _ => None,
+    }
+}
+
+enum Listing {
+    Pending(Receiver<anyhow::Result<DirListing>>),
+    Ready(DirListing),
+    /// The directory could not be listed; it is not tried again
+    Failed,
+}
+
+/// Asks the domain to list `dir`.  Domains live on the main thread,
+/// so the listing happens there and the result is sent back to the
+/// overlay thread.
+fn request_listing(domain_id: DomainId, dir: String) -> Receiver<anyhow::Result<DirListing>> {
+    let (tx, rx) = channel();
+    promise::spawn::spawn_into_main_thread(async move {
+        let mux = Mux::get().unwrap();
+        let result = match mux.get_domain(domain_id) {
+            Some(domain) => domain.list_directory(&dir, MAX_ENTRIES).await,
+            None => Err(anyhow::anyhow!("domain {} not found", domain_id)),
+        };
+        tx.send(result).ok();
+    })
+    .detach();
+    rx
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+struct CwdHost {
+    history: BasicHistory,
+    domain_id: DomainId,
+    /// The listings of the directories that have been completed in,
+    /// keyed by the directory as it was typed
+    listings: RefCell<HashMap<String, Listing>>,
+}
+
+impl CwdHost {
+    /// Calls `func` with the listing of `dir`, if it has arrived
+    fn with_listing<R>(&self, dir: &str, func: impl FnOnce(&DirListing) -> R) -> Option<R> {
+        let mut listings = self.listings.borrow_mut();
+        let listing = listings
+            .entry(dir.to_string())
+            .or_insert_with(|| Listing::Pending(request_listing(self.domain_id, dir.to_string())));
+        if let Listing::Pending(rx) = listing {
+            let next = match rx.recv_timeout(LISTING_WAIT) {
+                Ok(Ok(result)) => Listing::Ready(result),
+                Ok(Err(err)) => {
+                    log::warn!("Unable to list {}: {:#}", dir, err);
+                    Listing::Failed
+                }
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => Listing::Failed,
+            };
+            *listing = next;
+        }
+        match listing {
+            Listing::Ready(result) => Some(func(result)),
+            _ => None,
+        }
+    }
+}
+
+impl LineEditorHost for CwdHost {
+    fn history(&mut self) -> &mut dyn History {
+        &mut self.history
+    }
+
+    /// Completes the last component of the path against the listing
+    /// of the directory that precedes it
+    fn complete(&self, line: &str, cursor_position: usize) -> Vec<CompletionCandidate> {
+        let prefix = &line[..cursor_position];
+        let dir_len = match prefix.rfind(is_separator) {
+            Some(idx) => idx + 1,
+            None => return vec![],
+        };
+        let (dir, name) = prefix.split_at(dir_len);
+        self.with_listing(dir, |listing| {
+            listing
+                .matching(name)
+                .map(|entry| CompletionCandidate {
+                    range: dir_len..cursor_position,
+                    text: entry.to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    fn resolve_action(
+        &mut self,
+        event: &InputEvent,
+        _editor: &mut LineEditor<'_>,
+    ) -> Option<Action> {
+        cancel_on_escape(event)
+    }
+}
+
+/// Prompts for one of `domains`, by number or by name, and then for
+/// a working directory in it.  Returns the chosen domain and the
+/// directory, which is empty if the default of the domain should be
+/// used, or None if the user cancels.
+pub fn prompt_for_domain_and_cwd(
+    mut term: TermWizTerminal,
+    domains: Vec<DomainChoice>,
+) -> anyhow::Result<Option<(DomainId, String)>> {
+    let title = "Spawn into a domain";
+    let mut text = format!("{}\r\n", title);
+    for (idx, domain) in domains.iter().enumerate() {
+        text.push_str(&format!("{:>3}. {}\r\n", idx + 1, domain.name));
+    }
+    text.push_str("Press Tab to complete, Enter to accept or Escape to cancel\r\n");
+    term.render(&[Change::Title(title.to_string()), Change::Text(text)])?;
+
+    let mut host = ChoiceHost::new(domains.iter().map(|domain| domain.name.clone()).collect());
+    let domain = loop {
+        let mut editor = LineEditor::new(&mut term);
+        editor.set_prompt("Domain: ");
+        let line = match editor.read_line(&mut host)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = line.trim();
+        let chosen = line
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|idx| domains.get(idx))
+            .or_else(|| domains.iter().find(|domain| domain.name == line));
+        match chosen {
+            Some(domain) => break domain,
+            None => term.render(&[Change::Text(format!("No such domain: {}\r\n", line))])?,
+        }
+    };
+
+    term.render(&[Change::Text(format!(
+        "Working directory in {}, or empty for its default\r\n",
+        domain.name
+    ))])?;
+    let mut host = CwdHost {
+        history: BasicHistory::default(),
+        domain_id: domain.domain_id,
+        listings: RefCell::new(HashMap::new()),
+    };
+    let mut editor = LineEditor::new(&mut term);
+    editor.set_prompt("Directory: ");
+    Ok(editor
+        .read_line(&mut host)?
+        .map(|cwd| (domain.domain_id, cwd.trim().to_string())))
+}
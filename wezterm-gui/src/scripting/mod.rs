@@ -2,6 +2,8 @@ use crate::frontend::try_front_end;
 use config::lua::get_or_create_sub_module;
 use config::lua::mlua::{self, Lua};
 use mux::window::WindowId as MuxWindowId;
+use mux::Mux;
+use mux_lua::MuxPane;
 
 pub mod guiwin;
 pub mod pane;
@@ -28,5 +30,16 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    window_mod.set(
+        "pick_pane_async",
+        lua.create_async_function(|_, prompt: Option<String>| async move {
+            let mux = Mux::get().ok_or_else(|| mlua::Error::external("no mux"))?;
+            let pane_ids = mux.pick_panes(prompt, false).await.map_err(luaerr)?;
+            Ok(pane_ids
+                .and_then(|pane_ids| pane_ids.into_iter().next())
+                .map(MuxPane))
+        })?,
+    )?;
+
     Ok(())
 }
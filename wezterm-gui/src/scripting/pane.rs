@@ -1,10 +1,18 @@
 //! PaneObject represents a Mux Pane instance in lua code
 use super::luaerr;
 use anyhow::anyhow;
+use config::Palette;
 use mlua::{UserData, UserDataMethods};
+use mux::bookmark::scroll_to_bookmark;
+use mux::inputfilter::InputSource;
 use mux::pane::{Pane, PaneId};
+use mux::panelink::LinkPolicy;
+use mux::scrollback::{line_as_text, save_scrollback};
 use mux::Mux;
+use std::path::Path;
 use std::rc::Rc;
+use wezterm_dynamic::ToDynamic;
+use wezterm_term::StableRowIndex;
 
 #[derive(Clone)]
 pub struct PaneObject {
@@ -47,8 +55,30 @@ impl UserData for PaneObject {
         methods.add_method("get_foreground_process_info", |_, this, _: ()| {
             Ok(this.pane()?.get_foreground_process_info())
         });
+        methods.add_method("get_resource_usage", |_, this, _: ()| {
+            Ok(this.pane()?.get_resource_usage())
+        });
+        methods.add_method("get_spawn_label", |_, this, _: ()| {
+            Ok(this.pane()?.get_spawn_label())
+        });
+        methods.add_method(
+            "set_palette_overrides",
+            |_, this, overrides: Option<Palette>| {
+                this.pane()?
+                    .set_palette_overrides(overrides)
+                    .map_err(luaerr)
+            },
+        );
+        methods.add_method("get_palette_overrides", |_, this, _: ()| {
+            Ok(this.pane()?.get_palette_overrides())
+        });
         methods.add_method("paste", |_, this, text: String| {
-            this.pane()?.send_paste(&text).map_err(luaerr)?;
+            let pane = this.pane()?;
+            match Mux::get() {
+                Some(mux) => mux.send_input_to_pane(&pane, text.as_bytes(), InputSource::Paste),
+                None => pane.send_paste(&text),
+            }
+            .map_err(luaerr)?;
             Ok(())
         });
         methods.add_method("get_cursor_position", |_, this, _: ()| {
@@ -66,6 +96,12 @@ impl UserData for PaneObject {
         methods.add_method("is_alt_screen_active", |_, this, _: ()| {
             Ok(this.pane()?.is_alt_screen_active())
         });
+        methods.add_method("get_modes", |lua, this, _: ()| {
+            match this.pane()?.get_modes() {
+                Some(modes) => luahelper::dynamic_to_lua_value(lua, modes.to_dynamic()),
+                None => Ok(mlua::Value::Nil),
+            }
+        });
 
         // When called with no arguments, returns the lines from the
         // viewport as plain text (no escape sequences).
@@ -81,9 +117,7 @@ impl UserData for PaneObject {
             let (_first_row, lines) = pane.get_lines(top_row..bottom_row);
             let mut text = String::new();
             for line in lines {
-                for (_, cell) in line.visible_cells() {
-                    text.push_str(cell.str());
-                }
+                text.push_str(&line_as_text(&line));
                 let trimmed = text.trim_end().len();
                 text.truncate(trimmed);
                 text.push('\n');
@@ -104,9 +138,7 @@ impl UserData for PaneObject {
                 let lines = pane.get_logical_lines(top_row..bottom_row);
                 let mut text = String::new();
                 for line in lines {
-                    for (_, cell) in line.logical.visible_cells() {
-                        text.push_str(cell.str());
-                    }
+                    text.push_str(&line_as_text(&line.logical));
                     let trimmed = text.trim_end().len();
                     text.truncate(trimmed);
                     text.push('\n');
@@ -117,6 +149,114 @@ impl UserData for PaneObject {
             },
         );
 
+        methods.add_async_method(
+            "change_dir",
+            |_, this, (dir, options): (String, Option<mux_lua::ChangeDirOptions>)| async move {
+                let options = options.unwrap_or_default();
+                this.pane()?
+                    .change_dir(dir, options.enter)
+                    .await
+                    .map_err(luaerr)
+            },
+        );
+
+        methods.add_async_method(
+            "save_scrollback",
+            |_, this, (path, options): (String, Option<mux_lua::SaveScrollbackOptions>)| async move {
+                let options = options.unwrap_or_default();
+                save_scrollback(
+                    &this.pane()?,
+                    Path::new(&path),
+                    options.format,
+                    options.rows(),
+                )
+                .await
+                .map_err(luaerr)
+            },
+        );
+
+        methods.add_async_method(
+            "add_bookmark",
+            |_, this, (name, row): (String, Option<StableRowIndex>)| async move {
+                this.pane()?.add_bookmark(name, row).await.map_err(luaerr)
+            },
+        );
+
+        methods.add_async_method("get_bookmarks", |_, this, _: ()| async move {
+            this.pane()?.get_bookmarks().await.map_err(luaerr)
+        });
+
+        methods.add_async_method("scroll_to_bookmark", |_, this, name: String| async move {
+            scroll_to_bookmark(&this.pane()?, &name)
+                .await
+                .map_err(luaerr)
+        });
+
+        methods.add_method("lock", |_, this, _: ()| {
+            let pane = this.pane()?;
+            if let Some(mux) = Mux::get() {
+                mux.lock_pane(pane.pane_id());
+            }
+            Ok(())
+        });
+
+        methods.add_method("unlock", |_, this, _: ()| {
+            let pane = this.pane()?;
+            if let Some(mux) = Mux::get() {
+                mux.unlock_pane(pane.pane_id());
+            }
+            Ok(())
+        });
+
+        methods.add_method("pause", |_, this, _: ()| {
+            let pane = this.pane()?;
+            if let Some(mux) = Mux::get() {
+                mux.set_pane_paused(pane.pane_id(), true).map_err(luaerr)?;
+            }
+            Ok(())
+        });
+
+        methods.add_method("resume", |_, this, _: ()| {
+            let pane = this.pane()?;
+            if let Some(mux) = Mux::get() {
+                mux.set_pane_paused(pane.pane_id(), false).map_err(luaerr)?;
+            }
+            Ok(())
+        });
+
+        methods.add_method(
+            "link_with",
+            |_, this, (other, policy): (mlua::AnyUserData, Option<LinkPolicy>)| {
+                // Accept either flavor of pane object
+                let other = match other.borrow::<PaneObject>() {
+                    Ok(other) => other.pane,
+                    Err(_) => other.borrow::<mux_lua::MuxPane>()?.0,
+                };
+                let mux = Mux::get()
+                    .ok_or_else(|| anyhow!("must be called on main thread"))
+                    .map_err(luaerr)?;
+                mux.link_panes(
+                    vec![this.pane, other],
+                    policy.unwrap_or(LinkPolicy::FocusTogether),
+                )
+                .map_err(luaerr)
+            },
+        );
+
+        methods.add_method("is_paused", |_, this, _: ()| {
+            let pane = this.pane()?;
+            Ok(Mux::get()
+                .map(|mux| mux.is_pane_paused(pane.pane_id()))
+                .unwrap_or(false))
+        });
+
+        methods.add_method("is_locked", |_, this, _: ()| {
+            let pane = this.pane()?;
+            Ok(Mux::get()
+                .map(|mux| mux.is_pane_locked(pane.pane_id()))
+                .unwrap_or(false))
+        });
+
         methods.add_method("get_domain_name", |_, this, _: ()| {
             let pane = this.pane()?;
             let mut name = None;
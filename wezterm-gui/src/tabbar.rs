@@ -1,6 +1,7 @@
 use crate::termwindow::{PaneInformation, TabInformation, UIItem, UIItemType};
 use config::{ConfigHandle, TabBarColors};
 use mlua::FromLua;
+use mux::Mux;
 use termwiz::cell::{unicode_column_width, Cell, CellAttributes};
 use termwiz::color::ColorSpec;
 use termwiz::escape::csi::Sgr;
@@ -111,7 +112,18 @@ fn compute_tab_title(
         Some(title) => title,
         None => {
             let title = if let Some(pane) = &tab.active_pane {
-                let mut title = pane.title.clone();
+                // Prefer the title that the mux computed for the tab
+                // via the mux-format-tab-title event
+                let computed_title = Mux::get()
+                    .and_then(|mux| mux.get_tab(tab.tab_id))
+                    .and_then(|tab| tab.get_computed_title());
+                let title = computed_title.unwrap_or_else(|| pane.title.clone());
+                let pane_title = if pane.is_paused {
+                    format!("⏸ {}", title)
+                } else {
+                    title
+                };
+                let mut title = pane_title.clone();
                 let classic_spacing = if config.use_fancy_tab_bar { "" } else { " " };
                 if config.show_tab_index_in_tab_bar {
                     title = format!(
@@ -123,7 +135,7 @@ fn compute_tab_title(
                             } else {
                                 1
                             },
-                        pane.title,
+                        pane_title,
                         classic_spacing,
                     );
                 }
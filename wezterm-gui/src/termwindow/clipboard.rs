@@ -1,11 +1,32 @@
+use crate::overlay::{confirm_rejected_input, start_overlay_pane};
 use crate::termwindow::TermWindowNotif;
 use crate::TermWindow;
 use config::keyassignment::{ClipboardCopyDestination, ClipboardPasteSource};
-use mux::pane::Pane;
+use mux::inputfilter::InputSource;
+use mux::pane::{Pane, PaneId};
+use mux::pasteguard;
 use mux::Mux;
+use std::borrow::Cow;
 use std::rc::Rc;
+use std::sync::Arc;
 use window::{Clipboard, WindowOps};
 
+/// Runs text that the user is pasting into `pane` through the input
+/// filters in one piece, rather than in the chunks that it is sent in,
+/// so that a filter that asks for confirmation asks once per paste.
+/// Returns the text to paste, if any.
+pub fn filter_paste(pane: &Rc<dyn Pane>, text: String) -> Option<String> {
+    let replaced =
+        match Mux::get()
+            .unwrap()
+            .filter_pane_input(pane, text.as_bytes(), InputSource::Paste)?
+        {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(data) => Some(String::from_utf8_lossy(&data).into_owned()),
+        };
+    Some(replaced.unwrap_or(text))
+}
+
 impl TermWindow {
     pub fn copy_to_clipboard(&self, clipboard: ClipboardCopyDestination, text: String) {
         let clipboard = match clipboard {
@@ -23,6 +44,59 @@ impl TermWindow {
         }
     }
 
+    /// Pastes `text`, which the input filters have let through, into
+    /// `pane`, unless `paste_guard` holds it until the user confirms it
+    pub fn send_guarded_paste(&mut self, pane: &Rc<dyn Pane>, text: String) -> anyhow::Result<()> {
+        let guard = pasteguard::pane_paste_guard(pane);
+        if !pasteguard::might_hold(guard, &text) {
+            return pane.trickle_paste(text);
+        }
+        let window = self.window.as_ref().unwrap().clone();
+        let pane = Rc::clone(pane);
+        promise::spawn::spawn(async move {
+            match pasteguard::check_paste(&pane, guard, &text).await {
+                None => {
+                    if let Err(err) = pane.trickle_paste(text) {
+                        log::error!("pasting into pane {}: {:#}", pane.pane_id(), err);
+                    }
+                }
+                Some(reason) => {
+                    let pane_id = pane.pane_id();
+                    window.notify(TermWindowNotif::Apply(Box::new(move |myself| {
+                        myself.confirm_guarded_paste(pane_id, reason, text);
+                    })));
+                }
+            }
+        })
+        .detach();
+        Ok(())
+    }
+
+    /// Asks whether the paste that `paste_guard` held should be sent,
+    /// showing exactly what would be sent
+    fn confirm_guarded_paste(&mut self, pane_id: PaneId, reason: String, text: String) {
+        if self.pane_state(pane_id).overlay.is_some() {
+            return;
+        }
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let message = format!(
+            "✋ Paste guard: {}.  Send it?\n\n{}",
+            reason,
+            pasteguard::preview(&text)
+        );
+        let data = Arc::new(text.into_bytes());
+        let window = self.window.clone().unwrap();
+        let (overlay, future) = start_overlay_pane(self, &pane, move |pane_id, term| {
+            confirm_rejected_input(pane_id, message, InputSource::Paste, data, term, window)
+        });
+        self.assign_overlay_for_pane(pane_id, overlay);
+        promise::spawn::spawn(future).detach();
+    }
+
     pub fn paste_from_clipboard(&mut self, pane: &Rc<dyn Pane>, clipboard: ClipboardPasteSource) {
         let pane_id = pane.pane_id();
         log::trace!(
@@ -49,7 +123,9 @@ impl TermWindow {
                             mux.get_pane(pane_id)
                         })
                     {
-                        pane.trickle_paste(clip).ok();
+                        if let Some(clip) = filter_paste(&pane, clip) {
+                            myself.send_guarded_paste(&pane, clip).ok();
+                        }
                     }
                 })));
             }
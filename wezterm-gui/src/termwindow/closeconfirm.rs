@@ -0,0 +1,100 @@
+//! Shows the prompts for the pending closes of the mux, in the window
+//! that holds what is to be closed.  The prompt is an overlay of the
+//! pane, or of the tab, that is to be closed, or of the active tab of a
+//! window that is to be closed.  Answering it passes the answer to the
+//! mux, and it is dismissed once the mux reports that the request was
+//! decided, whether here, by another client or by a timeout.
+use crate::overlay::{confirm_pending_close, start_overlay, start_overlay_pane};
+use crate::termwindow::TermWindow;
+use mux::closeconfirm::{CloseRequestId, CloseScope, PendingClose};
+use mux::pane::PaneId;
+use mux::tab::TabId;
+use mux::Mux;
+
+/// The overlay that shows the prompt for a pending close
+#[derive(Debug, Clone, Copy)]
+pub enum ClosePrompt {
+    Pane { pane_id: PaneId, overlay_id: PaneId },
+    Tab { tab_id: TabId, overlay_id: PaneId },
+}
+
+impl TermWindow {
+    /// Has the mux close `scope`, once it has been confirmed if any of
+    /// its panes are running something
+    pub(crate) fn request_close(&self, scope: CloseScope) {
+        promise::spawn::spawn(async move {
+            let mux = Mux::get().unwrap();
+            if let Err(err) = mux.request_close(scope).await {
+                log::error!("closing {:?}: {:#}", scope, err);
+            }
+        })
+        .detach();
+    }
+
+    pub fn show_close_confirmation(&mut self, request: PendingClose) {
+        let mux = Mux::get().unwrap();
+        let id = request.id;
+        let message = request.message;
+        let prompt = match request.scope {
+            CloseScope::Pane(pane_id) => {
+                let pane = match mux.get_pane(pane_id) {
+                    Some(pane) => pane,
+                    None => return,
+                };
+                let (overlay, future) = start_overlay_pane(self, &pane, move |_pane_id, term| {
+                    confirm_pending_close(id, message, term)
+                });
+                let overlay_id = overlay.pane_id();
+                self.assign_overlay_for_pane(pane_id, overlay);
+                promise::spawn::spawn(future).detach();
+                ClosePrompt::Pane {
+                    pane_id,
+                    overlay_id,
+                }
+            }
+            CloseScope::Tab(_) | CloseScope::Window(_) => {
+                let tab = match request.scope {
+                    CloseScope::Tab(tab_id) => mux.get_tab(tab_id),
+                    _ => mux.get_active_tab_for_window(self.mux_window_id),
+                };
+                let tab = match tab {
+                    Some(tab) => tab,
+                    None => return,
+                };
+                let tab_id = tab.tab_id();
+                let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
+                    confirm_pending_close(id, message, term)
+                });
+                let overlay_id = overlay.pane_id();
+                self.assign_overlay(tab_id, overlay);
+                promise::spawn::spawn(future).detach();
+                ClosePrompt::Tab { tab_id, overlay_id }
+            }
+        };
+        self.close_prompts.insert(id, prompt);
+    }
+
+    /// Dismisses the prompt for the pending close `id`, if this window
+    /// shows it and it is still showing
+    pub fn dismiss_close_confirmation(&mut self, id: CloseRequestId) {
+        match self.close_prompts.remove(&id) {
+            Some(ClosePrompt::Pane {
+                pane_id,
+                overlay_id,
+            }) => {
+                let current = self
+                    .pane_state(pane_id)
+                    .overlay
+                    .as_ref()
+                    .map(|overlay| overlay.pane.pane_id());
+                if current == Some(overlay_id) {
+                    self.cancel_overlay_for_pane(pane_id);
+                }
+            }
+            Some(ClosePrompt::Tab { tab_id, overlay_id }) => {
+                self.cancel_overlay_for_tab(tab_id, Some(overlay_id));
+            }
+            None => {}
+        }
+    }
+}
@@ -2,7 +2,9 @@ use crate::termwindow::InputMap;
 use ::window::{DeadKeyStatus, KeyCode, KeyEvent, Modifiers, RawKeyEvent, WindowOps};
 use anyhow::Context;
 use config::keyassignment::KeyTableEntry;
+use mux::inputfilter::FilteredKey;
 use mux::pane::Pane;
+use mux::Mux;
 use smol::Timer;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -188,6 +190,17 @@ enum OnlyKeyBindings {
 }
 
 impl super::TermWindow {
+    /// Runs the bytes that pressing `key` would send to `pane` through
+    /// the input filters; see `Mux::filter_key_down`
+    pub fn filter_key_down(
+        &self,
+        pane: &Rc<dyn Pane>,
+        key: &::termwiz::input::KeyCode,
+        mods: ::termwiz::input::Modifiers,
+    ) -> FilteredKey {
+        Mux::get().unwrap().filter_key_down(pane, key, mods)
+    }
+
     fn encode_win32_input(&self, pane: &Rc<dyn Pane>, key: &KeyEvent) -> Option<String> {
         if !self.config.allow_win32_input_mode
             || pane.get_keyboard_encoding() != KeyboardEncoding::Win32
@@ -336,10 +349,22 @@ impl super::TermWindow {
                         return false;
                     }
 
-                    let res = if is_down {
-                        pane.key_down(term_key, tw_raw_modifiers)
+                    let filtered = if is_down && !term_key.is_modifier() {
+                        self.filter_key_down(&pane, &term_key, tw_raw_modifiers)
                     } else {
-                        pane.key_up(term_key, tw_raw_modifiers)
+                        FilteredKey::Unchanged
+                    };
+
+                    let res = match filtered {
+                        FilteredKey::Discarded => return true,
+                        FilteredKey::Replaced(data) => pane
+                            .writer()
+                            .write_all(&data)
+                            .context("sending filtered key input"),
+                        FilteredKey::Unchanged if is_down => {
+                            pane.key_down(term_key, tw_raw_modifiers)
+                        }
+                        FilteredKey::Unchanged => pane.key_up(term_key, tw_raw_modifiers),
                     };
 
                     if res.is_ok() {
@@ -531,6 +556,10 @@ impl super::TermWindow {
             );
         }
 
+        if self.split_preview_key_event(&window_key) {
+            return;
+        }
+
         let modifiers = window_mods_to_termwiz_mods(window_key.modifiers);
 
         if self.process_key(
@@ -584,7 +613,19 @@ impl super::TermWindow {
                     return;
                 }
 
-                let res = if let Some(encoded) = self.encode_win32_input(&pane, &window_key) {
+                let filtered = if window_key.key_is_down && !key.is_modifier() {
+                    self.filter_key_down(&pane, &key, modifiers)
+                } else {
+                    FilteredKey::Unchanged
+                };
+
+                let res = if let FilteredKey::Discarded = filtered {
+                    return;
+                } else if let FilteredKey::Replaced(data) = filtered {
+                    pane.writer()
+                        .write_all(&data)
+                        .context("sending filtered key input")
+                } else if let Some(encoded) = self.encode_win32_input(&pane, &window_key) {
                     if self.config.debug_key_events {
                         log::info!("Encoded input as {:?}", encoded);
                     }
@@ -625,7 +666,11 @@ impl super::TermWindow {
                 if self.config.debug_key_events {
                     log::info!("send to pane string={:?}", s);
                 }
-                pane.writer().write_all(s.as_bytes()).ok();
+                let data = match self.filter_keyboard_input(&pane, s.as_bytes()) {
+                    Some(data) => data,
+                    None => return,
+                };
+                pane.writer().write_all(&data).ok();
                 self.maybe_scroll_to_bottom_for_input(&pane);
                 context.invalidate();
             }
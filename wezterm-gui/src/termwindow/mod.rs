@@ -7,9 +7,9 @@ use crate::frontend::{front_end, try_front_end};
 use crate::glium::texture::SrgbTexture2d;
 use crate::inputmap::InputMap;
 use crate::overlay::{
-    confirm_close_pane, confirm_close_tab, confirm_close_window, confirm_quit_program, launcher,
-    start_overlay, start_overlay_pane, CopyModeParams, CopyOverlay, LauncherArgs, LauncherFlags,
-    QuickSelectOverlay,
+    confirm_close_linked_panes, confirm_close_policy, confirm_quit_program, confirm_rejected_input,
+    confirm_unlock_pane, launcher, start_overlay, start_overlay_pane, CloseTarget, CopyModeParams,
+    CopyOverlay, LauncherArgs, LauncherFlags, QuickSelectOverlay,
 };
 use crate::scripting::guiwin::GuiWin;
 use crate::scripting::pane::PaneObject;
@@ -26,15 +26,24 @@ use ::wezterm_term::input::{ClickPosition, MouseButton as TMB};
 use ::window::*;
 use anyhow::{anyhow, ensure, Context};
 use config::keyassignment::{
-    ClipboardCopyDestination, ClipboardPasteSource, KeyAssignment, PaneDirection, Pattern,
-    QuickSelectArguments, RotationDirection, SpawnCommand, SplitSize,
+    ClipboardCopyDestination, ClipboardPasteSource, CommandDir, GlobalSearchArguments,
+    KeyAssignment, PaneSelectArguments, Pattern, QuickSelectArguments, RotationDirection,
+    SpawnCommand, SplitPaneTarget,
 };
 use config::{
     configuration, AudibleBell, ConfigHandle, Dimension, DimensionContext, TermConfig,
     WindowCloseConfirmation,
 };
 use mlua::{FromLua, UserData, UserDataFields};
+use mux::closeconfirm::{CloseRequestId, CloseScope};
+use mux::coalesce::{self, UpdateCoalescer, UpdateKey};
+use mux::domain::{CloseDecisions, Domain, DomainId};
+use mux::editscrollback::EditScrollbackRequest;
+use mux::globalsearch::{GlobalSearchOptions, DEFAULT_MAX_MATCHES_PER_PANE};
+use mux::inputfilter::{FilteredKey, InputRejection, InputSource};
 use mux::pane::{CloseReason, Pane, PaneId, Pattern as MuxPattern};
+use mux::panelink::LinkPolicy;
+use mux::panepick::PickPanesRequest;
 use mux::renderable::RenderableDimensions;
 use mux::tab::{
     PositionedPane, PositionedSplit, SplitDirection, SplitRequest, SplitSize as MuxSplitSize, Tab,
@@ -44,6 +53,7 @@ use mux::window::WindowId as MuxWindowId;
 use mux::{Mux, MuxNotification};
 use smol::channel::Sender;
 use smol::Timer;
+use std::borrow::Cow;
 use std::cell::{RefCell, RefMut};
 use std::collections::HashMap;
 use std::ops::Add;
@@ -62,6 +72,7 @@ use wezterm_term::{Alert, StableRowIndex, TerminalConfiguration, TerminalSize};
 pub mod background;
 pub mod box_model;
 pub mod clipboard;
+mod closeconfirm;
 mod keyevent;
 pub mod modal;
 mod mouseevent;
@@ -71,6 +82,7 @@ mod render;
 pub mod resize;
 mod selection;
 pub mod spawn;
+mod splitpreview;
 use prevcursor::PrevCursorPos;
 use spawn::SpawnWhere;
 
@@ -230,6 +242,13 @@ impl UserData for TabInformation {
                 .ok_or_else(|| mlua::Error::external(format!("tab {} not found", this.tab_id)))?;
             Ok(tab.get_title())
         });
+        fields.add_field_method_get("computed_title", |_, this| {
+            let mux = Mux::get().expect("event to run on main thread");
+            let tab = mux
+                .get_tab(this.tab_id)
+                .ok_or_else(|| mlua::Error::external(format!("tab {} not found", this.tab_id)))?;
+            Ok(tab.get_computed_title())
+        });
         fields.add_field_method_get("window_title", |_, this| {
             let mux = Mux::get().expect("event to run on main thread");
             let window = mux.get_window(this.window_id).ok_or_else(|| {
@@ -248,6 +267,7 @@ pub struct PaneInformation {
     pub is_active: bool,
     pub is_zoomed: bool,
     pub has_unseen_output: bool,
+    pub is_paused: bool,
     pub left: usize,
     pub top: usize,
     pub width: usize,
@@ -265,6 +285,7 @@ impl UserData for PaneInformation {
         fields.add_field_method_get("is_active", |_, this| Ok(this.is_active));
         fields.add_field_method_get("is_zoomed", |_, this| Ok(this.is_zoomed));
         fields.add_field_method_get("has_unseen_output", |_, this| Ok(this.has_unseen_output));
+        fields.add_field_method_get("is_paused", |_, this| Ok(this.is_paused));
         fields.add_field_method_get("left", |_, this| Ok(this.left));
         fields.add_field_method_get("top", |_, this| Ok(this.top));
         fields.add_field_method_get("width", |_, this| Ok(this.width));
@@ -312,6 +333,11 @@ impl UserData for PaneInformation {
                 None => Ok("".to_string()),
             }
         });
+        fields.add_field_method_get("resource_usage", |_, this| {
+            Ok(Mux::get()
+                .and_then(|mux| mux.get_pane(this.pane_id))
+                .and_then(|pane| pane.get_resource_usage()))
+        });
     }
 }
 
@@ -359,6 +385,11 @@ pub struct TermWindow {
     /// If is_some, the LEADER modifier is active until the specified instant.
     leader_is_down: Option<std::time::Instant>,
     dead_key_status: DeadKeyStatus,
+    /// The split of a `SplitPanePreview` whose key is held
+    pending_split: Option<splitpreview::PendingSplit>,
+    /// The prompts that this window shows for the pending closes of
+    /// the mux
+    close_prompts: HashMap<CloseRequestId, closeconfirm::ClosePrompt>,
     key_table_state: KeyTableState,
     show_tab_bar: bool,
     show_scroll_bar: bool,
@@ -396,6 +427,9 @@ pub struct TermWindow {
         RefCell<LruCache<ShapeCacheKey, anyhow::Result<Rc<Vec<ShapedInfo<SrgbTexture2d>>>>>>,
 
     last_status_call: Instant,
+    /// Title, cwd and user var changes that have yet to refresh
+    /// the title and tab bar
+    title_updates: UpdateCoalescer<(PaneId, UpdateKey)>,
     cursor_blink_state: RefCell<ColorEase>,
     blink_state: RefCell<ColorEase>,
     rapid_blink_state: RefCell<ColorEase>,
@@ -433,6 +467,10 @@ impl TermWindow {
 
     fn close_requested(&mut self, window: &Window) {
         let mux = Mux::get().unwrap();
+        if self.prompt_for_close_policy(CloseTarget::Window(self.mux_window_id)) {
+            // The prompt closes the window once it has been answered
+            return;
+        }
         match self.config.window_close_confirmation {
             WindowCloseConfirmation::NeverPrompt => {
                 // Immediately kill the tabs and allow the window to close
@@ -441,15 +479,12 @@ impl TermWindow {
                 front_end().forget_known_window(window);
             }
             WindowCloseConfirmation::AlwaysPrompt => {
-                let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
-                    Some(tab) => tab,
-                    None => {
-                        mux.kill_window(self.mux_window_id);
-                        window.close();
-                        front_end().forget_known_window(window);
-                        return;
-                    }
-                };
+                if mux.get_active_tab_for_window(self.mux_window_id).is_none() {
+                    mux.kill_window(self.mux_window_id);
+                    window.close();
+                    front_end().forget_known_window(window);
+                    return;
+                }
 
                 let mux_window_id = self.mux_window_id;
 
@@ -462,15 +497,9 @@ impl TermWindow {
                     front_end().forget_known_window(window);
                     return;
                 }
-                let window = self.window.clone().unwrap();
-                let (overlay, future) = start_overlay(self, &tab, move |tab_id, term| {
-                    confirm_close_window(term, mux_window_id, window, tab_id)
-                });
-                self.assign_overlay(tab.tab_id(), overlay);
-                promise::spawn::spawn(future).detach();
-
-                // Don't close right now; let the close happen from
-                // the confirmation overlay
+                // Don't close right now; the mux closes the window
+                // once the close has been confirmed
+                self.request_close(CloseScope::Window(mux_window_id));
             }
         }
     }
@@ -481,6 +510,8 @@ impl TermWindow {
         self.load_os_parameters();
 
         if self.focused.is_none() {
+            // The key up that would make the split may never arrive
+            self.cancel_split_preview();
             self.last_mouse_click = None;
             self.current_mouse_buttons.clear();
             self.current_mouse_capture = None;
@@ -651,6 +682,8 @@ impl TermWindow {
             input_map: InputMap::new(&config),
             leader_is_down: None,
             dead_key_status: DeadKeyStatus::None,
+            pending_split: None,
+            close_prompts: HashMap::new(),
             show_tab_bar,
             show_scroll_bar: config.enable_scroll_bar,
             tab_bar: TabBarState::default(),
@@ -673,6 +706,7 @@ impl TermWindow {
                 65536,
             )),
             last_status_call: Instant::now(),
+            title_updates: UpdateCoalescer::default(),
             cursor_blink_state: RefCell::new(ColorEase::new(
                 config.cursor_blink_rate,
                 config.cursor_blink_ease_in,
@@ -854,7 +888,9 @@ impl TermWindow {
                     })
                     .collect::<Vec<_>>()
                     .join(" ");
-                pane.trickle_paste(paths)?;
+                if let Some(paths) = clipboard::filter_paste(&pane, paths) {
+                    self.send_guarded_paste(&pane, paths)?;
+                }
                 Ok(true)
             }
             WindowEvent::DraggedFile(_) => Ok(true),
@@ -948,17 +984,22 @@ impl TermWindow {
                 self.cancel_overlay_for_tab(tab_id, pane_id);
             }
             TermWindowNotif::MuxNotification(n) => match n {
+                MuxNotification::Alert {
+                    alert: Alert::OutputSinceFocusLost,
+                    ..
+                } => {
+                    self.update_title();
+                }
                 MuxNotification::Alert {
                     alert:
-                        Alert::OutputSinceFocusLost
-                        | Alert::CurrentWorkingDirectoryChanged
+                        alert @ (Alert::CurrentWorkingDirectoryChanged
                         | Alert::WindowTitleChanged(_)
                         | Alert::TabTitleChanged(_)
                         | Alert::IconTitleChanged(_)
-                        | Alert::SetUserVar { .. },
-                    ..
+                        | Alert::SetUserVar { .. }),
+                    pane_id,
                 } => {
-                    self.update_title();
+                    self.coalesce_title_update(pane_id, alert);
                 }
                 MuxNotification::Alert {
                     alert: Alert::PaletteChanged,
@@ -985,9 +1026,15 @@ impl TermWindow {
                     window.invalidate();
                 }
                 MuxNotification::Alert {
-                    alert: Alert::ToastNotification { .. },
+                    alert: Alert::ToastNotification { .. } | Alert::CommandStarted,
                     ..
                 } => {}
+                MuxNotification::Alert {
+                    alert: Alert::ModesChanged(_),
+                    pane_id,
+                } => {
+                    self.emit_window_event("pane-mode-changed", Some(pane_id));
+                }
                 MuxNotification::PaneOutput(pane_id) => {
                     self.mux_pane_output_event(pane_id);
                 }
@@ -1003,11 +1050,67 @@ impl TermWindow {
                 MuxNotification::SaveToDownloads { .. } => {
                     // Handled by frontend
                 }
+                MuxNotification::PaneInputHeld(pane_id) => {
+                    self.offer_to_unlock_pane(pane_id);
+                }
+                MuxNotification::PaneInputRejected {
+                    pane_id,
+                    source,
+                    rejection,
+                    data,
+                } => {
+                    self.confirm_rejected_input(pane_id, source, rejection, data);
+                }
+                MuxNotification::PaneResourceUsage(_) => {
+                    // The tab bar may be showing the usage
+                    self.update_title();
+                }
+                MuxNotification::PaneOutputPaused { .. } => {
+                    // The tab bar shows whether the pane is paused
+                    self.update_title();
+                    window.invalidate();
+                }
+                MuxNotification::PanePaletteOverridesChanged(pane_id)
+                | MuxNotification::PaneMarkersChanged(pane_id) => {
+                    self.mux_pane_output_event(pane_id);
+                }
+                MuxNotification::CwdDropped {
+                    pane_id,
+                    cwd,
+                    reason,
+                } => {
+                    self.emit_cwd_dropped(pane_id, cwd, reason);
+                }
+                MuxNotification::ScrollToRow { pane_id, row } => {
+                    let mux = Mux::get().unwrap();
+                    if let Some(pane) = mux.get_pane(pane_id) {
+                        self.set_viewport(pane_id, Some(row), pane.get_dimensions());
+                    }
+                }
+                MuxNotification::DomainSyncProgress { .. } => {
+                    // The status area may be showing the progress
+                    self.emit_status_event();
+                }
+                MuxNotification::CloseConfirmationRequested(request) => {
+                    self.show_close_confirmation(request);
+                }
+                MuxNotification::CloseConfirmationResolved { id, .. } => {
+                    self.dismiss_close_confirmation(id);
+                }
                 MuxNotification::PaneAdded(_)
                 | MuxNotification::PaneRemoved(_)
+                | MuxNotification::PaneInternalError { .. }
                 | MuxNotification::WindowWorkspaceChanged(_)
                 | MuxNotification::ActiveWorkspaceChanged(_)
                 | MuxNotification::Empty
+                | MuxNotification::DomainAlreadyAttached(_)
+                | MuxNotification::PaneLinksChanged
+                | MuxNotification::TabLayoutChanged(_)
+                | MuxNotification::WindowCreatedInWorkspace { .. }
+                | MuxNotification::OutputMatched { .. }
+                | MuxNotification::PickPanes(_)
+                | MuxNotification::ShareEnded(_)
+                | MuxNotification::SpawnRedirected { .. }
                 | MuxNotification::WindowCreated(_) => {}
             },
             TermWindowNotif::EmitStatusUpdate => {
@@ -1119,6 +1222,9 @@ impl TermWindow {
 
     fn mux_pane_output_event(&mut self, pane_id: PaneId) {
         metrics::histogram!("mux.pane_output_event.rate", 1.);
+        // The zones of a remote pane can arrive after its output, and
+        // are announced by this same notification, so look them up again
+        self.semantic_zones.remove(&pane_id);
         if self.is_pane_visible(pane_id) {
             if let Some(ref win) = self.window {
                 win.invalidate();
@@ -1167,6 +1273,54 @@ impl TermWindow {
                 }
                 let _ = pane_id;
             }
+            MuxNotification::PaneInputRejected {
+                pane_id,
+                ref rejection,
+                ..
+            } => {
+                // Other rejections are shown as a notification by the frontend
+                if !rejection.confirm {
+                    return true;
+                }
+                let mux = Mux::get().expect("mux is calling us");
+                match mux.resolve_pane_id(pane_id) {
+                    Some((_domain_id, window_id, _tab_id)) if window_id == mux_window_id => {}
+                    _ => return true,
+                }
+            }
+            MuxNotification::PaneInputHeld(pane_id)
+            | MuxNotification::CwdDropped { pane_id, .. }
+            | MuxNotification::ScrollToRow { pane_id, .. }
+            | MuxNotification::PaneOutputPaused { pane_id, .. }
+            | MuxNotification::PanePaletteOverridesChanged(pane_id)
+            | MuxNotification::PaneMarkersChanged(pane_id)
+            | MuxNotification::Alert {
+                pane_id,
+                alert: Alert::ModesChanged(_),
+            } => {
+                let mux = Mux::get().expect("mux is calling us");
+                match mux.resolve_pane_id(pane_id) {
+                    Some((_domain_id, window_id, _tab_id)) if window_id == mux_window_id => {}
+                    _ => return true,
+                }
+            }
+            MuxNotification::DomainSyncProgress { .. } => {}
+            MuxNotification::CloseConfirmationRequested(ref request) => {
+                let mux = Mux::get().expect("mux is calling us");
+                if mux.close_scope_window(request.scope) != Some(mux_window_id) {
+                    return true;
+                }
+            }
+            MuxNotification::CloseConfirmationResolved { .. } => {}
+            MuxNotification::PaneResourceUsage(samples) => {
+                let mux = Mux::get().expect("mux is calling us");
+                if !samples.iter().any(|(pane_id, _)| {
+                    matches!(mux.resolve_pane_id(*pane_id),
+                        Some((_domain_id, window_id, _tab_id)) if window_id == mux_window_id)
+                }) {
+                    return true;
+                }
+            }
             MuxNotification::PaneAdded(_pane_id) => {
                 // If some other client spawns a pane inside this window, this
                 // gives us an opportunity to attach it to the clipboard.
@@ -1183,15 +1337,25 @@ impl TermWindow {
                 alert:
                     Alert::SetUserVar { .. }
                     | Alert::ToastNotification { .. }
-                    | Alert::PaletteChanged { .. },
+                    | Alert::PaletteChanged { .. }
+                    | Alert::CommandStarted,
                 ..
             }
             | MuxNotification::AssignClipboard { .. }
             | MuxNotification::SaveToDownloads { .. }
             | MuxNotification::PaneRemoved(_)
+            | MuxNotification::PaneInternalError { .. }
             | MuxNotification::WindowCreated(_)
             | MuxNotification::ActiveWorkspaceChanged(_)
             | MuxNotification::Empty
+            | MuxNotification::DomainAlreadyAttached(_)
+            | MuxNotification::PaneLinksChanged
+            | MuxNotification::TabLayoutChanged(_)
+            | MuxNotification::WindowCreatedInWorkspace { .. }
+            | MuxNotification::OutputMatched { .. }
+            | MuxNotification::PickPanes(_)
+            | MuxNotification::ShareEnded(_)
+            | MuxNotification::SpawnRedirected { .. }
             | MuxNotification::WindowWorkspaceChanged(_) => return true,
         }
 
@@ -1474,6 +1638,16 @@ impl TermWindow {
         }
     }
 
+    /// Shows the pane selector for a pick requested with
+    /// `Mux::pick_panes`, in place of any other modal
+    pub fn show_pane_pick(&mut self, request: PickPanesRequest) {
+        let modal = crate::termwindow::paneselect::PaneSelector::pick(self, request);
+        self.modal.borrow_mut().replace(Rc::new(modal));
+        if let Some(window) = self.window.as_ref() {
+            window.invalidate();
+        }
+    }
+
     pub fn cancel_modal(&self) {
         self.modal.borrow_mut().take();
         if let Some(window) = self.window.as_ref() {
@@ -1669,6 +1843,49 @@ impl TermWindow {
         self.schedule_next_status_update();
     }
 
+    /// Refreshes the title and tab bar in response to a title, cwd or
+    /// user var change, at most once per coalescing window, so that
+    /// a prompt that changes the title on every keystroke doesn't
+    /// invoke `format-tab-title` just as often.
+    fn coalesce_title_update(&mut self, pane_id: PaneId, alert: Alert) {
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        if let Some((key, value)) = UpdateKey::for_alert(&alert, &*pane) {
+            self.title_updates.push((pane_id, key), value, alert);
+        }
+        self.flush_title_updates();
+    }
+
+    fn flush_title_updates(&mut self) {
+        if !self.title_updates.has_pending() {
+            return;
+        }
+        let window = coalesce::coalesce_window();
+        let now = Instant::now();
+        if self.title_updates.is_due(now, window) {
+            let changed = !self.title_updates.flush(now).is_empty();
+            coalesce::SUPPRESSED_LOCAL
+                .fetch_add(self.title_updates.take_suppressed(), Ordering::Relaxed);
+            if changed {
+                self.update_title();
+            }
+        } else if self.title_updates.schedule_flush() {
+            if let Some(win) = self.window.clone() {
+                let target = self.title_updates.next_flush(window).unwrap_or(now);
+                promise::spawn::spawn(async move {
+                    Timer::at(target).await;
+                    win.notify(TermWindowNotif::Apply(Box::new(|term_window| {
+                        term_window.flush_title_updates();
+                    })));
+                })
+                .detach();
+            }
+        }
+    }
+
     fn schedule_next_status_update(&mut self) {
         if let Some(window) = self.window.as_ref() {
             let now = Instant::now();
@@ -1817,14 +2034,98 @@ impl TermWindow {
         let gui_win = GuiWin::new(self);
 
         let opengl_info = self.opengl_info.as_deref().unwrap_or("Unknown").to_string();
+        let pane = tab.get_active_pane();
+        let pane_modes = pane.as_ref().and_then(|pane| pane.get_modes());
+        let pty_quirks = pane
+            .and_then(|pane| mux.get_domain(pane.domain_id()))
+            .and_then(|domain| domain.pty_quirks());
 
         let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
-            crate::overlay::show_debug_overlay(term, gui_win, opengl_info)
+            crate::overlay::show_debug_overlay(term, gui_win, opengl_info, pane_modes, pty_quirks)
         });
         self.assign_overlay(tab.tab_id(), overlay);
         promise::spawn::spawn(future).detach();
     }
 
+    /// Lists the bookmarks of the active pane, and scrolls it to the
+    /// one that the user picks
+    fn show_bookmarks(&mut self) {
+        let pane = match self.get_active_pane_no_overlay() {
+            Some(pane) => pane,
+            None => return,
+        };
+        let (overlay, future) = start_overlay_pane(self, &pane, move |pane_id, term| {
+            crate::overlay::choose_bookmark(term, pane_id)
+        });
+        self.assign_overlay_for_pane(pane.pane_id(), overlay);
+        promise::spawn::spawn(async move {
+            let name = match future.await {
+                Ok(Some(name)) => name,
+                Ok(None) => return,
+                Err(err) => {
+                    log::error!("Failed to list bookmarks: {:#}", err);
+                    return;
+                }
+            };
+            if let Err(err) = mux::bookmark::scroll_to_bookmark(&pane, &name).await {
+                log::error!("{:#}", err);
+            }
+        })
+        .detach();
+    }
+
+    /// Searches the scrollback of every pane, and jumps to the match
+    /// that the user picks, in whichever window it is
+    fn show_global_search(&mut self, args: &GlobalSearchArguments, pane: &Rc<dyn Pane>) {
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
+            Some(tab) => tab,
+            None => return,
+        };
+        let pattern = self.resolve_search_pattern(args.pattern.clone(), pane);
+        let options = GlobalSearchOptions {
+            domain: args.domain.clone(),
+            workspace: if args.current_workspace_only {
+                Some(mux.active_workspace())
+            } else {
+                None
+            },
+            max_matches_per_pane: args
+                .max_matches_per_pane
+                .unwrap_or(DEFAULT_MAX_MATCHES_PER_PANE),
+            ..Default::default()
+        };
+        let mux_window_id = self.mux_window_id;
+
+        let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
+            crate::overlay::global_search(term, pattern, options)
+        });
+        self.assign_overlay(tab.tab_id(), overlay);
+        promise::spawn::spawn(async move {
+            let found = match future.await {
+                Ok(Some(found)) => found,
+                Ok(None) => return,
+                Err(err) => {
+                    log::error!("GlobalSearch: {:#}", err);
+                    return;
+                }
+            };
+            let mux = Mux::get().unwrap();
+            match mux.jump_to_search_match(&found) {
+                Ok(window_id) if window_id != mux_window_id => {
+                    if let Some(gui_win) =
+                        try_front_end().and_then(|fe| fe.gui_window_for_mux_window(window_id))
+                    {
+                        gui_win.window.show();
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => log::error!("GlobalSearch: {:#}", err),
+            }
+        })
+        .detach();
+    }
+
     fn show_tab_navigator(&mut self) {
         self.show_launcher_impl("Tab Navigator", LauncherFlags::TABS);
     }
@@ -2149,14 +2450,22 @@ impl TermWindow {
             ActivateTab(n) => {
                 self.activate_tab(*n)?;
             }
-            SendString(s) => pane.writer().write_all(s.as_bytes())?,
+            SendString(s) => {
+                if let Some(data) = self.filter_keyboard_input(&pane, s.as_bytes()) {
+                    pane.writer().write_all(&data)?;
+                }
+            }
             SendKey(key) => {
                 use keyevent::{window_mods_to_termwiz_mods, Key};
                 let mods = window_mods_to_termwiz_mods(key.mods);
                 if let Key::Code(key) = self.win_key_code_to_termwiz_key_code(
                     &key.key.resolve(self.config.key_map_preference),
                 ) {
-                    pane.key_down(key, mods)?;
+                    match self.filter_key_down(&pane, &key, mods) {
+                        FilteredKey::Unchanged => pane.key_down(key, mods)?,
+                        FilteredKey::Replaced(data) => pane.writer().write_all(&data)?,
+                        FilteredKey::Discarded => {}
+                    }
                 }
             }
             Hide => {
@@ -2186,6 +2495,12 @@ impl TermWindow {
             ShowLauncherArgs(args) => {
                 self.show_launcher_impl(args.title.as_deref().unwrap_or("Launcher"), args.flags)
             }
+            SpawnInDomainWithCwd => self.spawn_in_domain_with_cwd(),
+            SpawnInRecentDir => self.show_launcher_impl(
+                "Recent directories",
+                LauncherFlags::FUZZY | LauncherFlags::RECENT_DIRS,
+            ),
+            ListBookmarks => self.show_bookmarks(),
             HideApplication => {
                 let con = Connection::get().expect("call on gui thread");
                 con.hide_application();
@@ -2456,12 +2771,10 @@ impl TermWindow {
                     let domain = mux
                         .get_domain_by_name(&domain)
                         .ok_or_else(|| anyhow!("{} is not a valid domain name", domain))?;
-                    domain.attach(Some(window)).await?;
+                    mux.attach_domain(&domain, Some(window)).await?;
 
-                    let have_panes_in_domain = mux
-                        .iter_panes()
-                        .iter()
-                        .any(|p| p.domain_id() == domain.domain_id());
+                    let have_panes_in_domain =
+                        !mux.iter_panes_for_domain(domain.domain_id()).is_empty();
 
                     if !have_panes_in_domain {
                         let config = config::configuration();
@@ -2474,6 +2787,62 @@ impl TermWindow {
                 })
                 .detach();
             }
+            CancelAttach(domain) => {
+                let mux = Mux::get().unwrap();
+                let domain = mux
+                    .get_domain_by_name(domain)
+                    .ok_or_else(|| anyhow!("{} is not a valid domain name", domain))?;
+                if !mux.cancel_attach(domain.domain_id()) {
+                    log::info!("domain {} is not being attached", domain.domain_name());
+                }
+            }
+            RefreshDockerDomains => {
+                promise::spawn::spawn(async {
+                    if let Err(err) = mux::docker::discover_docker_domains().await {
+                        log::error!("Failed to discover docker containers: {:#}", err);
+                    }
+                })
+                .detach();
+            }
+            PerformDomainAction { domain, action } => {
+                let domain_name = domain.to_string();
+                let action = action.to_string();
+                promise::spawn::spawn(async move {
+                    let mux = Mux::get().unwrap();
+                    let result = match mux.get_domain_by_name(&domain_name) {
+                        Some(domain) => domain.perform_palette_action(&action).await,
+                        None => Err(anyhow!("{} is not a valid domain name", domain_name)),
+                    };
+                    if let Err(err) = result {
+                        log::error!("{} action {}: {:#}", domain_name, action, err);
+                    }
+                })
+                .detach();
+            }
+            LockPane => {
+                let mux = Mux::get().unwrap();
+                mux.lock_pane(pane.pane_id());
+            }
+            TogglePanePause => {
+                let mux = Mux::get().unwrap();
+                if let Err(err) = mux.toggle_pane_paused(pane.pane_id()) {
+                    log::error!("TogglePanePause: {:#}", err);
+                }
+            }
+            CloneTab => {
+                let mux = Mux::get().unwrap();
+                let tab_id = match mux.get_active_tab_for_window(self.mux_window_id) {
+                    Some(tab) => tab.tab_id(),
+                    None => return Ok(()),
+                };
+                promise::spawn::spawn(async move {
+                    let mux = Mux::get().unwrap();
+                    if let Err(err) = mux.clone_tab(tab_id, false).await {
+                        log::error!("Failed to clone tab {}: {:#}", tab_id, err);
+                    }
+                })
+                .detach();
+            }
             CopyMode(_) => {
                 // NOP here; handled by the overlay directly
             }
@@ -2490,39 +2859,72 @@ impl TermWindow {
             }
             SplitPane(split) => {
                 log::trace!("SplitPane {:?}", split);
-                self.spawn_command(
-                    &split.command,
-                    SpawnWhere::SplitPane(SplitRequest {
-                        direction: match split.direction {
-                            PaneDirection::Down | PaneDirection::Up => SplitDirection::Vertical,
-                            PaneDirection::Left | PaneDirection::Right => {
-                                SplitDirection::Horizontal
-                            }
-                            PaneDirection::Next | PaneDirection::Prev => {
-                                log::error!(
-                                    "Invalid direction {:?} for SplitPane",
-                                    split.direction
+                let request = match splitpreview::split_request(split) {
+                    Some(request) => request,
+                    None => {
+                        log::error!("Invalid direction {:?} for SplitPane", split.direction);
+                        return Ok(());
+                    }
+                };
+                match split.target {
+                    SplitPaneTarget::ActivePane => {
+                        self.spawn_command(&split.command, SpawnWhere::SplitPane(request));
+                    }
+                    SplitPaneTarget::Selection => {
+                        let command = split.command.clone();
+                        let modal = crate::termwindow::paneselect::PaneSelector::then(
+                            self,
+                            &PaneSelectArguments::default(),
+                            move |term_window, selected| {
+                                term_window.spawn_command(
+                                    &command,
+                                    SpawnWhere::SplitPaneById(selected.pane_id(), request),
                                 );
-                                return Ok(());
-                            }
-                        },
-                        target_is_second: match split.direction {
-                            PaneDirection::Down | PaneDirection::Right => true,
-                            PaneDirection::Up | PaneDirection::Left => false,
-                            PaneDirection::Next | PaneDirection::Prev => unreachable!(),
-                        },
-                        size: match split.size {
-                            SplitSize::Percent(n) => MuxSplitSize::Percent(n),
-                            SplitSize::Cells(n) => MuxSplitSize::Cells(n),
-                        },
-                        top_level: split.top_level,
-                    }),
-                );
+                                Ok(())
+                            },
+                        );
+                        self.modal.borrow_mut().replace(Rc::new(modal));
+                    }
+                    SplitPaneTarget::MoveSelection => {
+                        let target_pane_id = pane.pane_id();
+                        let modal = crate::termwindow::paneselect::PaneSelector::then(
+                            self,
+                            &PaneSelectArguments::default(),
+                            move |term_window, selected| {
+                                term_window.move_pane_into_split(
+                                    selected.pane_id(),
+                                    target_pane_id,
+                                    request,
+                                );
+                                Ok(())
+                            },
+                        );
+                        self.modal.borrow_mut().replace(Rc::new(modal));
+                    }
+                }
+            }
+            SplitPanePreview(split) => {
+                self.start_split_preview(split, pane)?;
             }
             PaneSelect(args) => {
                 let modal = crate::termwindow::paneselect::PaneSelector::new(self, args);
                 self.modal.borrow_mut().replace(Rc::new(modal));
             }
+            EditScrollback(args) => {
+                let pane = Rc::clone(pane);
+                let request = EditScrollbackRequest {
+                    lines: args.lines,
+                    escapes: args.escapes,
+                    local: args.local,
+                };
+                promise::spawn::spawn(async move {
+                    if let Err(err) = pane.edit_scrollback(request).await {
+                        log::error!("EditScrollback: {:#}", err);
+                    }
+                })
+                .detach();
+            }
+            GlobalSearch(args) => self.show_global_search(args, pane),
         };
         Ok(())
     }
@@ -2574,6 +2976,165 @@ impl TermWindow {
             .detach();
         }
     }
+
+    /// Called when input to a locked pane in this window was held.
+    /// Emits the `pane-input-locked` event, and unless its handler
+    /// returns false, asks the user whether to unlock the pane.
+    fn offer_to_unlock_pane(&mut self, pane_id: PaneId) {
+        if self.pane_state(pane_id).overlay.is_some() {
+            // Already offering; the input went to the overlay
+            return;
+        }
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let gui_win = GuiWin::new(self);
+        let pane_obj = PaneObject::new(&pane);
+        let window = match self.window.clone() {
+            Some(window) => window,
+            None => return,
+        };
+
+        async fn pane_input_locked(
+            lua: Option<Rc<mlua::Lua>>,
+            gui_win: GuiWin,
+            pane_obj: PaneObject,
+        ) -> anyhow::Result<bool> {
+            match lua {
+                Some(lua) => {
+                    let args = lua.pack_multi((gui_win, pane_obj))?;
+                    config::lua::emit_event(&lua, ("pane-input-locked".to_string(), args))
+                        .await
+                        .map_err(|e| {
+                            log::error!("while processing pane-input-locked event: {:#}", e);
+                            e
+                        })
+                }
+                None => Ok(true),
+            }
+        }
+
+        promise::spawn::spawn(async move {
+            let default_action = config::with_lua_config_on_main_thread(move |lua| {
+                pane_input_locked(lua, gui_win, pane_obj)
+            })
+            .await
+            .unwrap_or(true);
+            if default_action {
+                window.notify(TermWindowNotif::Apply(Box::new(move |term_window| {
+                    term_window.show_unlock_pane_confirmation(pane_id);
+                })));
+            }
+        })
+        .detach();
+    }
+
+    fn emit_cwd_dropped(&mut self, pane_id: PaneId, cwd: CommandDir, reason: String) {
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let gui_win = GuiWin::new(self);
+        let pane_obj = PaneObject::new(&pane);
+
+        async fn cwd_dropped(
+            lua: Option<Rc<mlua::Lua>>,
+            gui_win: GuiWin,
+            pane_obj: PaneObject,
+            cwd: String,
+            reason: String,
+        ) -> anyhow::Result<()> {
+            if let Some(lua) = lua {
+                let args = lua.pack_multi((gui_win, pane_obj, cwd, reason))?;
+                if let Err(err) =
+                    config::lua::emit_event(&lua, ("cwd-dropped".to_string(), args)).await
+                {
+                    log::error!("while processing cwd-dropped event: {:#}", err);
+                }
+            }
+            Ok(())
+        }
+
+        let cwd = cwd.to_string();
+        promise::spawn::spawn(config::with_lua_config_on_main_thread(move |lua| {
+            cwd_dropped(lua, gui_win, pane_obj, cwd, reason)
+        }))
+        .detach();
+    }
+
+    fn show_unlock_pane_confirmation(&mut self, pane_id: PaneId) {
+        if self.pane_state(pane_id).overlay.is_some() {
+            return;
+        }
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let domain_name = mux
+            .get_domain(pane.domain_id())
+            .map(|domain| domain.domain_name().to_string())
+            .unwrap_or_default();
+        let window = self.window.clone().unwrap();
+        let (overlay, future) = start_overlay_pane(self, &pane, move |pane_id, term| {
+            confirm_unlock_pane(pane_id, &domain_name, term, window)
+        });
+        self.assign_overlay_for_pane(pane_id, overlay);
+        promise::spawn::spawn(future).detach();
+    }
+
+    /// Runs text typed by the user through the input filters, and
+    /// returns the data to send to `pane`, if any.
+    /// If the pane is locked the input is held until it is unlocked,
+    /// and the user will be offered the chance to unlock the pane.
+    pub fn filter_keyboard_input<'a>(
+        &self,
+        pane: &Rc<dyn Pane>,
+        data: &'a [u8],
+    ) -> Option<Cow<'a, [u8]>> {
+        Mux::get()
+            .unwrap()
+            .filter_pane_input(pane, data, InputSource::Keyboard)
+    }
+
+    /// Asks whether input that a filter rejected should be sent anyway,
+    /// when the filter wants that confirmed
+    fn confirm_rejected_input(
+        &mut self,
+        pane_id: PaneId,
+        source: InputSource,
+        rejection: InputRejection,
+        data: Arc<Vec<u8>>,
+    ) {
+        if self.pane_state(pane_id).overlay.is_some() {
+            return;
+        }
+        let mux = Mux::get().unwrap();
+        let pane = match mux.get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let message = format!(
+            "✋ {} input was rejected by {}: {}.  Send it anyway?",
+            match source {
+                InputSource::Keyboard => "Keyboard",
+                InputSource::Paste => "Pasted",
+                InputSource::Cli | InputSource::CliPaste => "CLI",
+            },
+            rejection.filter,
+            rejection.reason
+        );
+        let window = self.window.clone().unwrap();
+        let (overlay, future) = start_overlay_pane(self, &pane, move |pane_id, term| {
+            confirm_rejected_input(pane_id, message, source, data, term, window)
+        });
+        self.assign_overlay_for_pane(pane_id, overlay);
+        promise::spawn::spawn(future).detach();
+    }
+
     fn close_current_pane(&mut self, confirm: bool) {
         let mux_window_id = self.mux_window_id;
         let mux = Mux::get().unwrap();
@@ -2587,13 +3148,20 @@ impl TermWindow {
         };
 
         let pane_id = pane.pane_id();
-        if confirm && !pane.can_close_without_prompting(CloseReason::Pane) {
+        let partners: Vec<(PaneId, String)> = mux
+            .linked_panes(pane_id, LinkPolicy::CloseTogether)
+            .into_iter()
+            .filter_map(|id| mux.get_pane(id).map(|p| (id, p.get_title())))
+            .collect();
+        if confirm && !partners.is_empty() {
             let window = self.window.clone().unwrap();
             let (overlay, future) = start_overlay_pane(self, &pane, move |pane_id, term| {
-                confirm_close_pane(pane_id, term, mux_window_id, window)
+                confirm_close_linked_panes(pane_id, partners, term, window)
             });
             self.assign_overlay_for_pane(pane_id, overlay);
             promise::spawn::spawn(future).detach();
+        } else if confirm && !pane.can_close_without_prompting(CloseReason::Pane) {
+            self.request_close(CloseScope::Pane(pane_id));
         } else {
             mux.remove_pane(pane_id);
         }
@@ -2614,19 +3182,20 @@ impl TermWindow {
         drop(mux_window);
 
         let tab_id = tab.tab_id();
-        if confirm && !tab.can_close_without_prompting(CloseReason::Tab) {
+        let asks = self.close_policy_prompt_needed(CloseTarget::Tab(tab_id));
+        if asks || (confirm && !tab.can_close_without_prompting(CloseReason::Tab)) {
             if self.activate_tab(tab_idx as isize).is_err() {
                 return;
             }
-
-            let window = self.window.clone().unwrap();
-            let (overlay, future) = start_overlay(self, &tab, move |tab_id, term| {
-                confirm_close_tab(tab_id, term, mux_window_id, window)
-            });
-            self.assign_overlay(tab_id, overlay);
-            promise::spawn::spawn(future).detach();
+            if asks {
+                self.prompt_for_close_policy(CloseTarget::Tab(tab_id));
+                return;
+            }
+            self.request_close(CloseScope::Tab(tab_id));
         } else {
-            mux.remove_tab(tab_id);
+            if let Err(err) = mux.close_tab(tab_id, &CloseDecisions::new()) {
+                log::error!("{:#}", err);
+            }
         }
     }
 
@@ -2637,29 +3206,111 @@ impl TermWindow {
             None => return,
         };
         let tab_id = tab.tab_id();
-        let mux_window_id = self.mux_window_id;
+        if self.prompt_for_close_policy(CloseTarget::Tab(tab_id)) {
+            return;
+        }
         if confirm && !tab.can_close_without_prompting(CloseReason::Tab) {
-            let window = self.window.clone().unwrap();
-            let (overlay, future) = start_overlay(self, &tab, move |tab_id, term| {
-                confirm_close_tab(tab_id, term, mux_window_id, window)
-            });
-            self.assign_overlay(tab_id, overlay);
-            promise::spawn::spawn(future).detach();
+            self.request_close(CloseScope::Tab(tab_id));
         } else {
-            mux.remove_tab(tab_id);
+            if let Err(err) = mux.close_tab(tab_id, &CloseDecisions::new()) {
+                log::error!("{:#}", err);
+            }
         }
     }
 
     fn close_tab_idx(&mut self, idx: usize) -> anyhow::Result<()> {
         let mux = Mux::get().unwrap();
+        let tab_id = match mux.get_window(self.mux_window_id) {
+            Some(win) => win.get_by_idx(idx).map(|tab| tab.tab_id()),
+            None => None,
+        };
+        if let Some(tab_id) = tab_id {
+            if self.close_policy_prompt_needed(CloseTarget::Tab(tab_id)) {
+                self.activate_tab(idx as isize)?;
+                self.prompt_for_close_policy(CloseTarget::Tab(tab_id));
+                return Ok(());
+            }
+        }
         if let Some(mut win) = mux.get_window_mut(self.mux_window_id) {
             let tab = win.remove_by_idx(idx);
             drop(win);
-            mux.remove_tab(tab.tab_id());
+            if let Err(err) = mux.close_tab(tab.tab_id(), &CloseDecisions::new()) {
+                log::error!("{:#}", err);
+            }
         }
         self.activate_tab_relative(0, true)
     }
 
+    /// Returns the domains whose close policy is to ask, and which have
+    /// panes in `target`, along with those panes
+    fn domains_asking_on_close(
+        &self,
+        target: CloseTarget,
+    ) -> Vec<(Arc<dyn Domain>, Vec<Rc<dyn Pane>>)> {
+        let mux = Mux::get().unwrap();
+        let tabs: Vec<Rc<Tab>> = match target {
+            CloseTarget::Window(window_id) => match mux.get_window(window_id) {
+                Some(win) => win.iter().cloned().collect(),
+                None => vec![],
+            },
+            CloseTarget::Tab(tab_id) => mux.get_tab(tab_id).into_iter().collect(),
+        };
+        let panes: Vec<Rc<dyn Pane>> = tabs
+            .iter()
+            .flat_map(|tab| tab.iter_panes_ignoring_zoom())
+            .map(|pos| pos.pane)
+            .collect();
+        mux.domains_asking_on_close(&panes, matches!(target, CloseTarget::Tab(_)))
+    }
+
+    fn close_policy_prompt_needed(&self, target: CloseTarget) -> bool {
+        !self.domains_asking_on_close(target).is_empty()
+    }
+
+    /// If closing `target` affects panes of a domain whose close policy
+    /// is to ask, shows a prompt that lists those panes and asks whether
+    /// to detach or kill them, and returns true.
+    /// The prompt performs the close once it has been answered.
+    fn prompt_for_close_policy(&mut self, target: CloseTarget) -> bool {
+        let asking = self.domains_asking_on_close(target);
+        if asking.is_empty() {
+            return false;
+        }
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
+            Some(tab) => tab,
+            None => return false,
+        };
+
+        let mut message = match target {
+            CloseTarget::Window(_) => "Closing this window affects these panes:\n".to_string(),
+            CloseTarget::Tab(_) => "Closing this tab affects these panes:\n".to_string(),
+        };
+        for (domain, panes) in &asking {
+            for pane in panes {
+                message.push_str(&format!(
+                    "  • {} (pane {} in domain `{}`)\n",
+                    pane.get_title(),
+                    pane.pane_id(),
+                    domain.domain_name()
+                ));
+            }
+        }
+        message.push_str("\nDetach them, so that you can reattach to them later, or kill them?");
+        let domain_ids: Vec<DomainId> = asking
+            .iter()
+            .map(|(domain, _)| domain.domain_id())
+            .collect();
+
+        let window = self.window.clone().unwrap();
+        let (overlay, future) = start_overlay(self, &tab, move |tab_id, term| {
+            confirm_close_policy(message, domain_ids, target, term, window, tab_id)
+        });
+        self.assign_overlay(tab.tab_id(), overlay);
+        promise::spawn::spawn(future).detach();
+        true
+    }
+
     pub fn pane_state(&self, pane_id: PaneId) -> RefMut<PaneState> {
         RefMut::map(self.pane_state.borrow_mut(), |state| {
             state.entry(pane_id).or_insert_with(PaneState::default)
@@ -2788,6 +3439,9 @@ impl TermWindow {
             is_active: pos.is_active,
             is_zoomed: pos.is_zoomed,
             has_unseen_output: pos.pane.has_unseen_output(),
+            is_paused: Mux::get()
+                .map(|mux| mux.is_pane_paused(pos.pane.pane_id()))
+                .unwrap_or(false),
             left: pos.left,
             top: pos.top,
             width: pos.width,
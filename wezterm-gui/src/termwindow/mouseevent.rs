@@ -1,3 +1,4 @@
+use crate::frontend::try_front_end;
 use crate::tabbar::TabBarItem;
 use crate::termwindow::keyevent::window_mods_to_termwiz_mods;
 use crate::termwindow::{MouseCapture, PositionedSplit, ScrollHit, UIItem, UIItemType, TMB};
@@ -54,6 +55,9 @@ impl super::TermWindow {
 
     pub fn mouse_event_impl(&mut self, event: MouseEvent, context: &dyn WindowOps) {
         log::trace!("{:?}", event);
+        if let Some(fe) = try_front_end() {
+            fe.set_mouse_over_window(self.mux_window_id, true);
+        }
         let pane = match self.get_active_pane_or_overlay() {
             Some(pane) => pane,
             None => return,
@@ -225,6 +229,9 @@ impl super::TermWindow {
 
     pub fn mouse_leave_impl(&mut self, context: &dyn WindowOps) {
         self.current_mouse_event = None;
+        if let Some(fe) = try_front_end() {
+            fe.set_mouse_over_window(self.mux_window_id, false);
+        }
         self.update_title();
         context.invalidate();
     }
@@ -832,6 +839,7 @@ impl super::TermWindow {
 
         if allow_action
             && !(self.config.swallow_mouse_click_on_pane_focus && is_click_to_focus_pane)
+            && !Mux::get().unwrap().is_pane_locked(pane.pane_id())
         {
             pane.mouse_event(mouse_event).ok();
         }
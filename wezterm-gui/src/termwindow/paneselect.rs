@@ -7,21 +7,90 @@ use crate::termwindow::render::{
 use crate::termwindow::DimensionContext;
 use crate::utilsprites::RenderMetrics;
 use crate::TermWindow;
+use ::window::RectF;
 use config::keyassignment::{KeyAssignment, PaneSelectArguments, PaneSelectMode};
-use config::Dimension;
+use config::{Dimension, RgbaColor};
+use mux::pane::{Pane, PaneId};
+use mux::panepick::{PickId, PickPanesRequest};
 use mux::Mux;
 use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use wezterm_font::LoadedFont;
 use wezterm_term::{KeyCode, KeyModifiers, MouseEvent};
 
+/// Called with the selected pane in place of the action of the mode
+pub type PaneSelectAction = Box<dyn FnOnce(&mut TermWindow, Rc<dyn Pane>) -> anyhow::Result<()>>;
+
+/// A pick that was requested with `Mux::pick_panes`.
+/// It is reported as cancelled if the selector goes away before the
+/// panes were chosen.
+struct PanePick {
+    id: PickId,
+    prompt: Option<String>,
+    multi: bool,
+    /// The indices of the panes that were chosen so far, when several
+    /// may be chosen
+    chosen: Vec<usize>,
+    finished: bool,
+}
+
+impl PanePick {
+    fn finish(&mut self, panes: Vec<PaneId>) {
+        self.finished = true;
+        Mux::get().unwrap().finish_pane_pick(self.id, Some(panes));
+    }
+}
+
+impl Drop for PanePick {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Some(mux) = Mux::get() {
+                mux.finish_pane_pick(self.id, None);
+            }
+        }
+    }
+}
+
 pub struct PaneSelector {
     element: RefCell<Option<Vec<ComputedElement>>>,
     labels: RefCell<Vec<String>>,
     selection: RefCell<String>,
     alphabet: String,
     mode: PaneSelectMode,
+    then: RefCell<Option<PaneSelectAction>>,
+    pick: RefCell<Option<PanePick>>,
 }
 
 impl PaneSelector {
+    /// Shows the selector and then performs the second stage of a
+    /// two-stage assignment with the selected pane.
+    /// The mode of `args` is ignored.
+    pub fn then(
+        term_window: &mut TermWindow,
+        args: &PaneSelectArguments,
+        action: impl FnOnce(&mut TermWindow, Rc<dyn Pane>) -> anyhow::Result<()> + 'static,
+    ) -> Self {
+        let selector = Self::new(term_window, args);
+        selector.then.borrow_mut().replace(Box::new(action));
+        selector
+    }
+
+    /// Shows the selector for a pick that was requested with
+    /// `Mux::pick_panes`, reporting the chosen panes rather than
+    /// acting on them.  When several panes may be chosen, typing the
+    /// label of a pane toggles it, and Enter completes the pick.
+    pub fn pick(term_window: &mut TermWindow, request: PickPanesRequest) -> Self {
+        let selector = Self::new(term_window, &PaneSelectArguments::default());
+        selector.pick.borrow_mut().replace(PanePick {
+            id: request.id,
+            prompt: request.prompt,
+            multi: request.multi,
+            chosen: vec![],
+            finished: false,
+        });
+        selector
+    }
+
     pub fn new(term_window: &mut TermWindow, args: &PaneSelectArguments) -> Self {
         let alphabet = if args.alphabet.is_empty() {
             term_window.config.quick_select_alphabet.clone()
@@ -34,12 +103,81 @@ impl PaneSelector {
             selection: RefCell::new(String::new()),
             alphabet,
             mode: args.mode,
+            then: RefCell::new(None),
+            pick: RefCell::new(None),
         }
     }
 
+    fn label_element(font: &Rc<LoadedFont>, text: String, fg: RgbaColor, bg: RgbaColor) -> Element {
+        Element::new(font, ElementContent::Text(text))
+            .colors(ElementColors {
+                border: BorderColor::new(bg.to_linear().into()),
+                bg: bg.to_linear().into(),
+                text: fg.to_linear().into(),
+            })
+            .padding(BoxDimension {
+                left: Dimension::Cells(0.25),
+                right: Dimension::Cells(0.25),
+                top: Dimension::Cells(0.),
+                bottom: Dimension::Cells(0.),
+            })
+            .border(BoxDimension::new(Dimension::Pixels(1.)))
+            .border_corners(Some(Corners {
+                top_left: SizedPoly {
+                    width: Dimension::Cells(0.25),
+                    height: Dimension::Cells(0.25),
+                    poly: TOP_LEFT_ROUNDED_CORNER,
+                },
+                top_right: SizedPoly {
+                    width: Dimension::Cells(0.25),
+                    height: Dimension::Cells(0.25),
+                    poly: TOP_RIGHT_ROUNDED_CORNER,
+                },
+                bottom_left: SizedPoly {
+                    width: Dimension::Cells(0.25),
+                    height: Dimension::Cells(0.25),
+                    poly: BOTTOM_LEFT_ROUNDED_CORNER,
+                },
+                bottom_right: SizedPoly {
+                    width: Dimension::Cells(0.25),
+                    height: Dimension::Cells(0.25),
+                    poly: BOTTOM_RIGHT_ROUNDED_CORNER,
+                },
+            }))
+    }
+
+    fn compute_within(
+        term_window: &TermWindow,
+        metrics: &RenderMetrics,
+        bounds: RectF,
+        element: &Element,
+    ) -> anyhow::Result<ComputedElement> {
+        let dimensions = term_window.dimensions;
+        term_window.compute_element(
+            &LayoutContext {
+                height: DimensionContext {
+                    dpi: dimensions.dpi as f32,
+                    pixel_max: dimensions.pixel_height as f32,
+                    pixel_cell: metrics.cell_size.height as f32,
+                },
+                width: DimensionContext {
+                    dpi: dimensions.dpi as f32,
+                    pixel_max: dimensions.pixel_width as f32,
+                    pixel_cell: metrics.cell_size.width as f32,
+                },
+                bounds,
+                metrics,
+                gl_state: term_window.render_state.as_ref().unwrap(),
+                zindex: 100,
+            },
+            element,
+        )
+    }
+
     fn compute(
         term_window: &mut TermWindow,
         alphabet: &str,
+        pick: Option<&PanePick>,
     ) -> anyhow::Result<(Vec<ComputedElement>, Vec<String>)> {
         let font = term_window
             .fonts
@@ -59,81 +197,50 @@ impl PaneSelector {
         let panes = term_window.get_panes_to_render();
         let labels =
             crate::overlay::quickselect::compute_labels_for_alphabet(alphabet, panes.len());
+        let fg = term_window.config.pane_select_fg_color;
+        let bg = term_window.config.pane_select_bg_color;
+        let cell_width = term_window.render_metrics.cell_size.width as f32;
+        let cell_height = term_window.render_metrics.cell_size.height as f32;
 
         let mut elements = vec![];
         for pos in panes {
             let caption = labels[pos.index].clone();
-            let element = Element::new(&font, ElementContent::Text(caption))
-                .colors(ElementColors {
-                    border: BorderColor::new(
-                        term_window.config.pane_select_bg_color.to_linear().into(),
-                    ),
-                    bg: term_window.config.pane_select_bg_color.to_linear().into(),
-                    text: term_window.config.pane_select_fg_color.to_linear().into(),
-                })
-                .padding(BoxDimension {
-                    left: Dimension::Cells(0.25),
-                    right: Dimension::Cells(0.25),
-                    top: Dimension::Cells(0.),
-                    bottom: Dimension::Cells(0.),
-                })
-                .border(BoxDimension::new(Dimension::Pixels(1.)))
-                .border_corners(Some(Corners {
-                    top_left: SizedPoly {
-                        width: Dimension::Cells(0.25),
-                        height: Dimension::Cells(0.25),
-                        poly: TOP_LEFT_ROUNDED_CORNER,
-                    },
-                    top_right: SizedPoly {
-                        width: Dimension::Cells(0.25),
-                        height: Dimension::Cells(0.25),
-                        poly: TOP_RIGHT_ROUNDED_CORNER,
-                    },
-                    bottom_left: SizedPoly {
-                        width: Dimension::Cells(0.25),
-                        height: Dimension::Cells(0.25),
-                        poly: BOTTOM_LEFT_ROUNDED_CORNER,
-                    },
-                    bottom_right: SizedPoly {
-                        width: Dimension::Cells(0.25),
-                        height: Dimension::Cells(0.25),
-                        poly: BOTTOM_RIGHT_ROUNDED_CORNER,
-                    },
-                }));
-
-            let dimensions = term_window.dimensions;
+            // The panes that were chosen for a pick are shown inverted
+            let element = if pick.map_or(false, |pick| pick.chosen.contains(&pos.index)) {
+                Self::label_element(&font, caption, bg, fg)
+            } else {
+                Self::label_element(&font, caption, fg, bg)
+            };
             let pane_dims = pos.pane.get_dimensions();
+            let bounds = euclid::rect(
+                padding_left + ((pos.left as f32 + pane_dims.cols as f32 / 2.) * cell_width),
+                top_pixel_y
+                    + ((pos.top as f32 + pane_dims.viewport_rows as f32 / 2.) * cell_height),
+                pane_dims.cols as f32 * cell_width,
+                pane_dims.viewport_rows as f32 * cell_height,
+            );
+            elements.push(Self::compute_within(
+                term_window,
+                &metrics,
+                bounds,
+                &element,
+            )?);
+        }
 
-            let computed = term_window.compute_element(
-                &LayoutContext {
-                    height: DimensionContext {
-                        dpi: dimensions.dpi as f32,
-                        pixel_max: dimensions.pixel_height as f32,
-                        pixel_cell: metrics.cell_size.height as f32,
-                    },
-                    width: DimensionContext {
-                        dpi: dimensions.dpi as f32,
-                        pixel_max: dimensions.pixel_width as f32,
-                        pixel_cell: metrics.cell_size.width as f32,
-                    },
-                    bounds: euclid::rect(
-                        padding_left
-                            + ((pos.left as f32 + pane_dims.cols as f32 / 2.)
-                                * term_window.render_metrics.cell_size.width as f32),
-                        top_pixel_y
-                            + ((pos.top as f32 + pane_dims.viewport_rows as f32 / 2.)
-                                * term_window.render_metrics.cell_size.height as f32),
-                        pane_dims.cols as f32 * term_window.render_metrics.cell_size.width as f32,
-                        pane_dims.viewport_rows as f32
-                            * term_window.render_metrics.cell_size.height as f32,
-                    ),
-                    metrics: &metrics,
-                    gl_state: term_window.render_state.as_ref().unwrap(),
-                    zindex: 100,
-                },
+        if let Some(prompt) = pick.and_then(|pick| pick.prompt.as_ref()) {
+            let element = Self::label_element(&font, prompt.clone(), fg, bg);
+            let bounds = euclid::rect(
+                padding_left,
+                top_pixel_y,
+                term_window.dimensions.pixel_width as f32 - padding_left,
+                metrics.cell_size.height as f32 * 2.,
+            );
+            elements.push(Self::compute_within(
+                term_window,
+                &metrics,
+                bounds,
                 &element,
-            )?;
-            elements.push(computed);
+            )?);
         }
 
         Ok((elements, labels))
@@ -155,6 +262,41 @@ impl PaneSelector {
         if term_window.tab_state(tab_id).overlay.is_none() {
             let panes = tab.iter_panes();
 
+            if let Some(pick) = self.pick.borrow_mut().as_mut() {
+                if pick.multi {
+                    match pick.chosen.iter().position(|&idx| idx == pane_index) {
+                        Some(idx) => {
+                            pick.chosen.remove(idx);
+                        }
+                        None => pick.chosen.push(pane_index),
+                    }
+                    self.selection.borrow_mut().clear();
+                    self.element.borrow_mut().take();
+                    if let Some(window) = term_window.window.as_ref() {
+                        window.invalidate();
+                    }
+                    return Ok(());
+                }
+                if let Some(pos) = panes.iter().find(|p| p.index == pane_index) {
+                    pick.finish(vec![pos.pane.pane_id()]);
+                }
+                term_window.cancel_modal();
+                return Ok(());
+            }
+
+            let then = self.then.borrow_mut().take();
+            if let Some(action) = then {
+                let pane = panes
+                    .into_iter()
+                    .find(|p| p.index == pane_index)
+                    .map(|p| p.pane);
+                term_window.cancel_modal();
+                return match pane {
+                    Some(pane) => action(term_window, pane),
+                    None => Ok(()),
+                };
+            }
+
             match self.mode {
                 PaneSelectMode::Activate => {
                     if panes.iter().position(|p| p.index == pane_index).is_some() {
@@ -170,6 +312,31 @@ impl PaneSelector {
         term_window.cancel_modal();
         Ok(())
     }
+
+    /// Reports the panes that were chosen for a pick of several panes,
+    /// unless none were chosen yet
+    fn finish_multi_pick(&self, term_window: &mut TermWindow) -> anyhow::Result<()> {
+        let mut pick = self.pick.borrow_mut();
+        let pick = match pick.as_mut() {
+            Some(pick) if pick.multi && !pick.chosen.is_empty() => pick,
+            _ => return Ok(()),
+        };
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(term_window.mux_window_id) {
+            Some(tab) => tab,
+            None => return Ok(()),
+        };
+        let panes = tab.iter_panes();
+        let pane_ids = pick
+            .chosen
+            .iter()
+            .filter_map(|&idx| panes.iter().find(|p| p.index == idx))
+            .map(|p| p.pane.pane_id())
+            .collect();
+        pick.finish(pane_ids);
+        term_window.cancel_modal();
+        Ok(())
+    }
 }
 
 impl Modal for PaneSelector {
@@ -197,15 +364,21 @@ impl Modal for PaneSelector {
             }
             (KeyCode::Char(c), KeyModifiers::NONE) | (KeyCode::Char(c), KeyModifiers::SHIFT) => {
                 // Type to add to the selection
-                let mut selection = self.selection.borrow_mut();
-                selection.push(c);
+                let pane_index = {
+                    let mut selection = self.selection.borrow_mut();
+                    selection.push(c);
+                    self.labels.borrow().iter().position(|s| s == &*selection)
+                };
 
                 // and if we have a complete match, activate that pane
-                if let Some(pane_index) = self.labels.borrow().iter().position(|s| s == &*selection)
-                {
+                if let Some(pane_index) = pane_index {
                     return self.perform_selection(pane_index, term_window);
                 }
             }
+            (KeyCode::Enter, KeyModifiers::NONE) => {
+                // Enter completes a pick of several panes
+                return self.finish_multi_pick(term_window);
+            }
             (KeyCode::Backspace, KeyModifiers::NONE) => {
                 // Backspace to edit the selection
                 let mut selection = self.selection.borrow_mut();
@@ -226,7 +399,8 @@ impl Modal for PaneSelector {
         term_window: &mut TermWindow,
     ) -> anyhow::Result<Ref<[ComputedElement]>> {
         if self.element.borrow().is_none() {
-            let (element, labels) = Self::compute(term_window, &self.alphabet)?;
+            let (element, labels) =
+                Self::compute(term_window, &self.alphabet, self.pick.borrow().as_ref())?;
             self.element.borrow_mut().replace(element);
             *self.labels.borrow_mut() = labels;
         }
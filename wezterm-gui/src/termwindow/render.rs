@@ -1043,6 +1043,9 @@ impl super::TermWindow {
         num_panes: usize,
     ) -> anyhow::Result<()> {
         self.check_for_dirty_lines_and_invalidate_selection(&pos.pane);
+        // After the check, so that a restored selection isn't cleared
+        // because its rows have yet to be fetched
+        self.sync_view_state(&pos.pane);
         /*
         let zone = {
             let dims = pos.pane.get_dimensions();
@@ -1062,7 +1065,7 @@ impl super::TermWindow {
         let global_cursor_fg = self.palette().cursor_fg;
         let global_cursor_bg = self.palette().cursor_bg;
         let config = &self.config;
-        let palette = pos.pane.palette();
+        let palette = pane_palette(&*pos.pane);
 
         let (padding_left, padding_top) = self.padding_left_top();
 
@@ -1086,7 +1089,7 @@ impl super::TermWindow {
         }
 
         let current_viewport = self.get_viewport(pos.pane.pane_id());
-        let (stable_top, lines);
+        let (stable_top, mut lines);
         let dims = pos.pane.get_dimensions();
 
         {
@@ -1108,6 +1111,18 @@ impl super::TermWindow {
             lines = vp_lines;
         }
 
+        // Markers only change how their rows are drawn, so apply them
+        // to our copies of the lines
+        let markers = pos.pane.get_markers();
+        if !markers.is_empty() {
+            for (line_idx, line) in lines.iter_mut().enumerate() {
+                let stable_row = stable_top + line_idx as StableRowIndex;
+                for marker in markers.iter().filter(|marker| marker.contains(stable_row)) {
+                    marker.apply_to_line(line);
+                }
+            }
+        }
+
         let gl_state = self.render_state.as_ref().unwrap();
         let layer = gl_state.layer_for_zindex(0)?;
         let vbs = layer.vb.borrow();
@@ -1629,7 +1644,7 @@ impl super::TermWindow {
                 let background = if panes.len() == 1 {
                     // If we're the only pane, use the pane's palette
                     // to draw the padding background
-                    panes[0].pane.palette().background
+                    pane_palette(&*panes[0].pane).background
                 } else {
                     self.palette().background
                 }
@@ -1674,6 +1689,7 @@ impl super::TermWindow {
                 self.paint_split_opengl(split, &pane)?;
             }
         }
+        self.paint_split_preview()?;
 
         if self.show_tab_bar {
             self.paint_tab_bar()?;
@@ -2857,6 +2873,16 @@ impl super::TermWindow {
     }
 }
 
+/// Returns the palette used to render `pane`; that of the pane,
+/// with the palette overrides of the pane applied
+fn pane_palette(pane: &dyn Pane) -> ColorPalette {
+    let mut palette = pane.palette();
+    if let Some(overrides) = pane.get_palette_overrides() {
+        overrides.apply_to(&mut palette);
+    }
+    palette
+}
+
 pub fn rgbcolor_to_window_color(color: RgbColor) -> LinearRgba {
     rgbcolor_alpha_to_window_color(color, 1.0)
 }
@@ -1,6 +1,7 @@
 use crate::selection::{Selection, SelectionCoordinate, SelectionMode, SelectionRange, SelectionX};
 use ::window::WindowOps;
 use mux::pane::{Pane, PaneId};
+use mux::viewstate::{PaneViewState, ViewSelection};
 use std::cell::RefMut;
 use std::rc::Rc;
 use termwiz::surface::Line;
@@ -282,4 +283,51 @@ impl super::TermWindow {
         self.selection(pane.pane_id()).seqno = pane.get_current_seqno();
         self.window.as_ref().unwrap().invalidate();
     }
+
+    /// Restores the view of `pane` that the user had before the client
+    /// last detached, if there is one, and then tells the pane about the
+    /// current view, so that it can be restored in turn
+    pub fn sync_view_state(&mut self, pane: &Rc<dyn Pane>) {
+        let pane_id = pane.pane_id();
+        if let Some(state) = pane.take_restored_view_state() {
+            let dims = pane.get_dimensions();
+            let state = state.clamp(dims.scrollback_top);
+            self.set_viewport(pane_id, state.viewport, dims);
+            if let Some(sel) = state.selection {
+                let start = SelectionCoordinate::x_y(sel.start_x, sel.start_y);
+                let mut selection = self.selection(pane_id);
+                selection.origin = Some(start);
+                selection.range = Some(SelectionRange {
+                    start,
+                    end: SelectionCoordinate::x_y(sel.end_x, sel.end_y),
+                });
+                selection.rectangular = sel.rectangular;
+                selection.seqno = pane.get_current_seqno();
+            }
+        }
+
+        let viewport = self.get_viewport(pane_id);
+        let selection = {
+            let selection = self.selection(pane_id);
+            let rectangular = selection.rectangular;
+            selection.range.map(|range| ViewSelection {
+                start_x: selection_x(range.start.x),
+                start_y: range.start.y,
+                end_x: selection_x(range.end.x),
+                end_y: range.end.y,
+                rectangular,
+            })
+        };
+        pane.record_view_state(PaneViewState {
+            viewport,
+            selection,
+        });
+    }
+}
+
+fn selection_x(x: SelectionX) -> usize {
+    match x {
+        SelectionX::Cell(x) => x,
+        SelectionX::BeforeZero => 0,
+    }
 }
@@ -1,24 +1,41 @@
+use crate::frontend::try_front_end;
+use crate::overlay::{
+    prompt_arg_choices, prompt_for_args, prompt_for_domain_and_cwd, start_overlay, DomainChoice,
+};
 use crate::termwindow::MuxWindowId;
 use anyhow::{anyhow, bail, Context};
-use config::keyassignment::{SpawnCommand, SpawnTabDomain};
+use config::keyassignment::{CommandDir, SpawnCommand, SpawnTabDomain};
 use config::TermConfig;
 use mux::activity::Activity;
-use mux::domain::SplitSource;
+use mux::domain::{DomainState, SplitSource};
+use mux::pane::PaneId;
+use mux::spawnquota::SpawnOrigin;
 use mux::tab::SplitRequest;
 use mux::Mux;
 use portable_pty::CommandBuilder;
 use std::sync::Arc;
 use wezterm_term::TerminalSize;
+use wezterm_toast_notification::persistent_toast_notification;
+use window::WindowOps;
 
 #[derive(Copy, Debug, Clone, Eq, PartialEq)]
 pub enum SpawnWhere {
     NewWindow,
     NewTab,
+    /// Split the active pane
     SplitPane(SplitRequest),
+    /// Split a specific pane, such as one chosen by the pane selector
+    SplitPaneById(PaneId, SplitRequest),
+}
+
+impl SpawnWhere {
+    fn is_split(&self) -> bool {
+        matches!(self, Self::SplitPane(_) | Self::SplitPaneById(..))
+    }
 }
 
 impl super::TermWindow {
-    pub fn spawn_command(&self, spawn: &SpawnCommand, spawn_where: SpawnWhere) {
+    pub fn spawn_command(&mut self, spawn: &SpawnCommand, spawn_where: SpawnWhere) {
         let size = if spawn_where == SpawnWhere::NewWindow {
             self.config.initial_size(self.dimensions.dpi as u32)
         } else {
@@ -26,7 +43,113 @@ impl super::TermWindow {
         };
         let term_config = Arc::new(TermConfig::with_config(self.config.clone()));
 
-        Self::spawn_command_impl(spawn, spawn_where, size, self.mux_window_id, term_config)
+        if spawn.prompt_args.is_empty() {
+            Self::spawn_command_impl(spawn, spawn_where, size, self.mux_window_id, term_config)
+        } else {
+            self.prompt_then_spawn(spawn, spawn_where, size, term_config)
+        }
+    }
+
+    /// Prompts for the values of the `prompt_args` of `spawn` in an
+    /// overlay on the active tab, then spawns it with those values
+    fn prompt_then_spawn(
+        &mut self,
+        spawn: &SpawnCommand,
+        spawn_where: SpawnWhere,
+        size: TerminalSize,
+        term_config: Arc<TermConfig>,
+    ) {
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
+            Some(tab) => tab,
+            None => return,
+        };
+
+        let args = spawn.prompt_args.clone();
+        let choices = args.iter().map(prompt_arg_choices).collect();
+        let title = match &spawn.label {
+            Some(label) => format!("Parameters for {}", label),
+            None => "Parameters for the command".to_string(),
+        };
+        let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
+            prompt_for_args(term, &title, args, choices)
+        });
+        self.assign_overlay(tab.tab_id(), overlay);
+
+        let spawn = spawn.clone();
+        let src_window_id = self.mux_window_id;
+        promise::spawn::spawn(async move {
+            let params = match future.await {
+                Ok(Some(params)) => params,
+                Ok(None) => return,
+                Err(err) => {
+                    log::error!("Failed to prompt for parameters: {:#}", err);
+                    return;
+                }
+            };
+            match spawn.with_params(&params) {
+                Ok(spawn) => {
+                    Self::spawn_command_impl(&spawn, spawn_where, size, src_window_id, term_config)
+                }
+                Err(err) => log::error!("Failed to spawn: {:#}", err),
+            }
+        })
+        .detach();
+    }
+
+    /// Prompts in an overlay on the active tab for an attached domain
+    /// and then for a working directory in it, which is completed
+    /// against the filesystem of that domain, then spawns a new tab
+    /// into the domain in that directory
+    pub fn spawn_in_domain_with_cwd(&mut self) {
+        let mux = Mux::get().unwrap();
+        let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
+            Some(tab) => tab,
+            None => return,
+        };
+
+        let domains = mux
+            .ordered_domains()
+            .into_iter()
+            .filter(|domain| {
+                domain.spawnable()
+                    && domain.state() == DomainState::Attached
+                    && !domain.launcher_hints().hidden
+            })
+            .map(|domain| DomainChoice {
+                domain_id: domain.domain_id(),
+                name: domain.domain_name().to_string(),
+            })
+            .collect();
+        let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
+            prompt_for_domain_and_cwd(term, domains)
+        });
+        self.assign_overlay(tab.tab_id(), overlay);
+
+        let size = self.terminal_size;
+        let term_config = Arc::new(TermConfig::with_config(self.config.clone()));
+        let src_window_id = self.mux_window_id;
+        promise::spawn::spawn(async move {
+            let (domain_id, cwd) = match future.await {
+                Ok(Some(choice)) => choice,
+                Ok(None) => return,
+                Err(err) => {
+                    log::error!("Failed to prompt for a domain: {:#}", err);
+                    return;
+                }
+            };
+            let spawn = SpawnCommand {
+                domain: SpawnTabDomain::DomainId(domain_id),
+                cwd: if cwd.is_empty() {
+                    None
+                } else {
+                    Some(CommandDir::new(cwd))
+                },
+                ..Default::default()
+            };
+            Self::spawn_command_impl(&spawn, SpawnWhere::NewTab, size, src_window_id, term_config)
+        })
+        .detach();
     }
 
     fn spawn_command_impl(
@@ -44,6 +167,11 @@ impl super::TermWindow {
                     .await
             {
                 log::error!("Failed to spawn: {:#}", err);
+                if spawn_where.is_split() {
+                    // Splits are usually triggered by a key assignment,
+                    // so make it clear why nothing happened
+                    persistent_toast_notification("Unable to split pane", &format!("{:#}", err));
+                }
             }
         })
         .detach();
@@ -65,24 +193,31 @@ impl super::TermWindow {
             None
         };
 
-        let cwd = if let Some(cwd) = spawn.cwd.as_ref() {
-            Some(cwd.to_str().map(|s| s.to_owned()).ok_or_else(|| {
-                anyhow!(
-                    "Domain::spawn requires that the cwd be unicode in {:?}",
-                    cwd
-                )
-            })?)
-        } else {
-            None
-        };
+        // The cwd is passed to the domain separately from the command,
+        // so that the domain can tell which host it belongs to
+        let cwd = spawn.cwd.clone();
 
         let cmd_builder = if let Some(args) = spawn.args {
             let mut builder = CommandBuilder::from_argv(args.iter().map(Into::into).collect());
             for (k, v) in spawn.set_environment_variables.iter() {
                 builder.env(k, v);
             }
-            if let Some(cwd) = spawn.cwd {
-                builder.cwd(cwd);
+            if let Some(label) = &spawn.label {
+                builder.label(label);
+            }
+            if let Some(exit_behavior) = spawn.exit_behavior {
+                builder.exit_behavior(exit_behavior.as_str());
+            }
+            Some(builder)
+        } else if spawn.label.is_some() || spawn.exit_behavior.is_some() {
+            // The domain runs its default program in place of a
+            // builder that has nothing but a label or exit behavior
+            let mut builder = CommandBuilder::new_default_prog();
+            if let Some(label) = &spawn.label {
+                builder.label(label);
+            }
+            if let Some(exit_behavior) = spawn.exit_behavior {
+                builder.exit_behavior(exit_behavior.as_str());
             }
             Some(builder)
         } else {
@@ -91,6 +226,39 @@ impl super::TermWindow {
 
         let workspace = mux.active_workspace().clone();
 
+        // A split always creates a new pane alongside the current one
+        let reuse = if spawn_where.is_split() {
+            None
+        } else {
+            spawn.reuse.as_ref()
+        };
+        if let Some(reuse) = reuse {
+            if let Some((_tab, pane, window_id)) = mux.reuse_pane(None, &workspace, reuse)? {
+                log::trace!("reused pane {} instead of spawning", pane.pane_id());
+                if window_id != src_window_id {
+                    if let Some(gui_win) =
+                        try_front_end().and_then(|fe| fe.gui_window_for_mux_window(window_id))
+                    {
+                        gui_win.window.show();
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        let target_pane_id = match spawn_where {
+            SpawnWhere::SplitPaneById(pane_id, _) => Some(pane_id),
+            _ => current_pane_id,
+        };
+        let domain = mux.resolve_spawn_domain_with_rules(
+            target_pane_id,
+            &spawn.domain,
+            cmd_builder.as_ref(),
+            cwd.as_ref(),
+            spawn.no_domain_rules,
+        )?;
+        mux.check_spawn_quota(&[domain.domain_id()], SpawnOrigin::Interactive)?;
+
         match spawn_where {
             SpawnWhere::SplitPane(direction) => {
                 if let Some(tab) = mux.get_active_tab_for_window(src_window_id) {
@@ -99,6 +267,9 @@ impl super::TermWindow {
                         .ok_or_else(|| anyhow!("tab to have a pane"))?;
 
                     log::trace!("doing split_pane");
+                    if spawn.no_domain_rules {
+                        mux.skip_domain_rules_for_next_spawn();
+                    }
                     let (pane, _size) = mux
                         .split_pane(
                             // tab.tab_id(),
@@ -117,13 +288,37 @@ impl super::TermWindow {
                     bail!("there is no active tab while splitting pane!?");
                 }
             }
+            SpawnWhere::SplitPaneById(pane_id, direction) => {
+                log::trace!("doing split_pane of pane {}", pane_id);
+                if spawn.no_domain_rules {
+                    mux.skip_domain_rules_for_next_spawn();
+                }
+                let (pane, _size) = mux
+                    .split_pane(
+                        pane_id,
+                        direction,
+                        SplitSource::Spawn {
+                            command: cmd_builder,
+                            command_dir: cwd,
+                        },
+                        spawn.domain,
+                    )
+                    .await
+                    .context("split_pane")?;
+                pane.set_config(term_config);
+            }
             _ => {
+                let window_id = match (spawn_where, &spawn.window) {
+                    (SpawnWhere::NewWindow, Some(target)) => mux.resolve_window_target(target)?,
+                    (SpawnWhere::NewWindow, None) => None,
+                    _ => Some(src_window_id),
+                };
+                if spawn.no_domain_rules {
+                    mux.skip_domain_rules_for_next_spawn();
+                }
                 let (_tab, pane, window_id) = mux
                     .spawn_tab_or_window(
-                        match spawn_where {
-                            SpawnWhere::NewWindow => None,
-                            _ => Some(src_window_id),
-                        },
+                        window_id,
                         spawn.domain,
                         cmd_builder,
                         cwd,
@@ -148,6 +343,34 @@ impl super::TermWindow {
         Ok(())
     }
 
+    /// Moves `pane_id` into a new split of `target_pane_id`
+    pub fn move_pane_into_split(
+        &self,
+        pane_id: PaneId,
+        target_pane_id: PaneId,
+        request: SplitRequest,
+    ) {
+        if pane_id == target_pane_id {
+            return;
+        }
+        promise::spawn::spawn(async move {
+            let mux = Mux::get().unwrap();
+            if let Err(err) = mux
+                .split_pane(
+                    target_pane_id,
+                    request,
+                    SplitSource::MovePane(pane_id),
+                    SpawnTabDomain::CurrentPaneDomain,
+                )
+                .await
+            {
+                log::error!("Failed to move pane {}: {:#}", pane_id, err);
+                persistent_toast_notification("Unable to move pane", &format!("{:#}", err));
+            }
+        })
+        .detach();
+    }
+
     pub fn spawn_tab(&mut self, domain: &SpawnTabDomain) {
         self.spawn_command(
             &SpawnCommand {
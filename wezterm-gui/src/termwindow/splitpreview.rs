@@ -0,0 +1,203 @@
+//! `SplitPanePreview` splits the active pane like `SplitPane`, but only
+//! once its key is released.  While the key is held for longer than
+//! `split_preview_hold_delay_ms`, the space that the new pane would
+//! take is outlined, so that the split can be judged before it is made;
+//! pressing Escape or moving the focus away cancels it.
+//! The outline is laid out by the domain that hosts the pane, with the
+//! same sizing as the split itself, so that the two agree even for the
+//! tabs of a remote mux.
+use crate::termwindow::{TermWindow, TermWindowNotif};
+use ::window::{KeyCode, KeyEvent};
+use config::keyassignment::{KeyAssignment, PaneDirection, SplitPane, SplitPaneTarget, SplitSize};
+use mux::pane::{Pane, PaneId};
+use mux::tab::{SplitDirection, SplitPreview, SplitRequest, SplitSize as MuxSplitSize};
+use mux::Mux;
+use smol::Timer;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Returns the request that splits a pane as `split` describes, or
+/// None if its direction is not one that a pane can be split in
+pub fn split_request(split: &SplitPane) -> Option<SplitRequest> {
+    let (direction, target_is_second) = match split.direction {
+        PaneDirection::Down => (SplitDirection::Vertical, true),
+        PaneDirection::Up => (SplitDirection::Vertical, false),
+        PaneDirection::Right => (SplitDirection::Horizontal, true),
+        PaneDirection::Left => (SplitDirection::Horizontal, false),
+        PaneDirection::Next | PaneDirection::Prev => return None,
+    };
+    Some(SplitRequest {
+        direction,
+        target_is_second,
+        size: match split.size {
+            SplitSize::Percent(n) => MuxSplitSize::Percent(n),
+            SplitSize::Cells(n) => MuxSplitSize::Cells(n),
+        },
+        top_level: split.top_level,
+    })
+}
+
+pub struct PendingSplit {
+    split: SplitPane,
+    pane_id: PaneId,
+    /// Identifies this hold, so that a lookup that completes after it
+    /// has ended doesn't show its outline on a later one
+    started: Instant,
+    /// Set once the hold delay has passed and the domain has laid out
+    /// the split
+    preview: Option<SplitPreview>,
+}
+
+impl TermWindow {
+    pub fn start_split_preview(
+        &mut self,
+        split: &SplitPane,
+        pane: &Rc<dyn Pane>,
+    ) -> anyhow::Result<()> {
+        if self.pending_split.is_some() {
+            // The key is repeating while it is held
+            return Ok(());
+        }
+        let delay = self.config.split_preview_hold_delay_ms;
+        let request = match split_request(split) {
+            Some(request) if delay > 0 && split.target == SplitPaneTarget::ActivePane => request,
+            _ => {
+                return self.perform_key_assignment(pane, &KeyAssignment::SplitPane(split.clone()))
+            }
+        };
+
+        let started = Instant::now();
+        let pane_id = pane.pane_id();
+        self.pending_split.replace(PendingSplit {
+            split: split.clone(),
+            pane_id,
+            started,
+            preview: None,
+        });
+
+        let window = match self.window.clone() {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        promise::spawn::spawn(async move {
+            Timer::after(Duration::from_millis(delay)).await;
+            let mux = Mux::get().unwrap();
+            let preview = match mux.preview_split(pane_id, request).await {
+                Ok(Some(preview)) => preview,
+                Ok(None) => return,
+                Err(err) => {
+                    log::error!("previewing split of pane {}: {:#}", pane_id, err);
+                    return;
+                }
+            };
+            window.notify(TermWindowNotif::Apply(Box::new(move |tw| {
+                if let Some(pending) = tw.pending_split.as_mut() {
+                    if pending.started == started {
+                        pending.preview.replace(preview);
+                        if let Some(window) = tw.window.as_ref() {
+                            window.invalidate();
+                        }
+                    }
+                }
+            })));
+        })
+        .detach();
+        Ok(())
+    }
+
+    /// Handles a key event while a split is pending.
+    /// Returns true if the event was consumed.
+    pub fn split_preview_key_event(&mut self, key: &KeyEvent) -> bool {
+        if self.pending_split.is_none() {
+            return false;
+        }
+        if key.key_is_down {
+            if key.key == KeyCode::Char('\u{1b}') {
+                self.cancel_split_preview();
+                return true;
+            }
+            return false;
+        }
+        if key.key.is_modifier() {
+            // The modifiers of the binding may be let go of first
+            return false;
+        }
+        self.commit_split_preview();
+        true
+    }
+
+    pub fn cancel_split_preview(&mut self) {
+        if let Some(pending) = self.pending_split.take() {
+            if pending.preview.is_some() {
+                if let Some(window) = self.window.as_ref() {
+                    window.invalidate();
+                }
+            }
+        }
+    }
+
+    fn commit_split_preview(&mut self) {
+        let pending = match self.pending_split.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        if let Some(window) = self.window.as_ref() {
+            window.invalidate();
+        }
+        // The split is made in the active pane; if that is no longer
+        // the pane that was previewed, it isn't what the user intended
+        let pane = match self.get_active_pane_or_overlay() {
+            Some(pane) if pane.pane_id() == pending.pane_id => pane,
+            _ => return,
+        };
+        if let Err(err) =
+            self.perform_key_assignment(&pane, &KeyAssignment::SplitPane(pending.split))
+        {
+            log::error!("SplitPanePreview: {:#}", err);
+        }
+    }
+
+    /// Shades the space that the pending split gives to the new pane
+    pub fn paint_split_preview(&mut self) -> anyhow::Result<()> {
+        let rect = match self
+            .pending_split
+            .as_ref()
+            .and_then(|pending| pending.preview)
+        {
+            Some(preview) => preview.new,
+            None => return Ok(()),
+        };
+        let pane = match self.get_active_pane_or_overlay() {
+            Some(pane) => pane,
+            None => return Ok(()),
+        };
+
+        let gl_state = self.render_state.as_ref().unwrap();
+        let layer = gl_state.layer_for_zindex(0)?;
+        let vb = &layer.vb.borrow()[2];
+        let mut vb_mut = vb.current_vb_mut();
+        let mut quads = vb.map(&mut vb_mut);
+        let color = pane.palette().split.to_linear().mul_alpha(0.35);
+        let cell_width = self.render_metrics.cell_size.width as f32;
+        let cell_height = self.render_metrics.cell_size.height as f32;
+
+        let first_row_offset = if self.show_tab_bar && !self.config.tab_bar_at_bottom {
+            self.tab_bar_pixel_height()?
+        } else {
+            0.
+        } + self.get_os_border().top.get() as f32;
+        let (padding_left, padding_top) = self.padding_left_top();
+
+        self.filled_rectangle(
+            &mut quads,
+            euclid::rect(
+                rect.left as f32 * cell_width + padding_left,
+                rect.top as f32 * cell_height + first_row_offset + padding_top,
+                rect.width as f32 * cell_width,
+                rect.height as f32 * cell_height,
+            ),
+            color,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+//! Builds the C ABI of `mux::blocking::ffi` into a shared library.
+//! See `wezterm_mux.h` for the exported functions.
+pub use mux::blocking::ffi::*;
@@ -0,0 +1,28 @@
+/* C interface to the synchronous wrappers in mux::blocking.
+ *
+ * Requests and results are JSON strings.  Each function returns a newly
+ * allocated string holding either {"ok": RESULT} or {"error": "MESSAGE"},
+ * which must be released by passing it to wezterm_mux_free_string.
+ *
+ * These functions block until the mux thread has handled the request or
+ * timeout_ms has elapsed, and fail if called from the mux thread. */
+#ifndef WEZTERM_MUX_H
+#define WEZTERM_MUX_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+char *wezterm_mux_spawn_tab(const char *request, uint64_t timeout_ms);
+char *wezterm_mux_split_pane(const char *request, uint64_t timeout_ms);
+char *wezterm_mux_send_text(uint64_t pane_id, const char *text, uint64_t timeout_ms);
+char *wezterm_mux_list(uint64_t timeout_ms);
+void wezterm_mux_free_string(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,125 @@
+//! Token authentication for the clients of a TLS server that sets
+//! `require_auth_token`.  Until a client sends an `Authenticate`
+//! request with a token that is accepted, its session refuses to do
+//! anything else.
+//! The `mux-authenticate` event decides whether a token is accepted,
+//! and with which role; if there is no handler, or it returns nil,
+//! the token is looked up in the `auth_tokens` of the server.
+//! A failed attempt is recorded in the audit log, and the connection
+//! is closed after `AUTH_FAILURE_DELAY`, so that guessing tokens is slow.
+use config::lua::mlua;
+use config::{AuthRole, TlsAuthToken};
+use std::time::Duration;
+
+/// How long the server waits before it closes the connection of a
+/// client whose token was refused
+pub const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(2);
+
+/// What is known about a client before it authenticates
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    /// The address that the client connected from
+    pub address: Option<String>,
+    /// The CN of the certificate of the client, if it presented one
+    pub cert_cn: Option<String>,
+    /// The `bind_address` of the server that the client connected to
+    pub server: String,
+}
+
+/// How the clients of a TLS server are authenticated
+#[derive(Debug, Clone, Default)]
+pub struct TokenAuth {
+    pub tokens: Vec<TlsAuthToken>,
+    pub peer: PeerInfo,
+}
+
+impl TokenAuth {
+    /// Returns the role of the client if `token` is accepted
+    pub fn authenticate(&self, token: &str) -> Result<AuthRole, String> {
+        match call_mux_authenticate(token, &self.peer) {
+            Ok(Verdict::Accepted(role)) => return Ok(role),
+            Ok(Verdict::Refused) => return Err("the token was refused".to_string()),
+            Ok(Verdict::Undecided) => {}
+            Err(err) => {
+                // Fail closed, as the handler may have been written
+                // to refuse this token
+                log::warn!("mux-authenticate: {:#}", err);
+                return Err("the token could not be checked".to_string());
+            }
+        }
+        self.tokens
+            .iter()
+            .find(|t| !t.token.is_empty() && tokens_match(&t.token, token))
+            .map(|t| t.role)
+            .ok_or_else(|| "the token was refused".to_string())
+    }
+}
+
+enum Verdict {
+    /// There is no handler, or it returned nil
+    Undecided,
+    /// The handler returned false
+    Refused,
+    /// The handler returned the name of a role
+    Accepted(AuthRole),
+}
+
+/// Gives the `mux-authenticate` event the opportunity to decide
+/// whether `token` is accepted
+fn call_mux_authenticate(token: &str, peer: &PeerInfo) -> anyhow::Result<Verdict> {
+    config::run_immediate_with_lua_config(|lua| {
+        let lua = match lua {
+            Some(lua) => lua,
+            None => return Ok(Verdict::Undecided),
+        };
+        let info = lua.create_table()?;
+        info.set("peer_address", peer.address.clone())?;
+        info.set("cert_cn", peer.cert_cn.clone())?;
+        info.set("server_address", peer.server.clone())?;
+        let v = config::lua::emit_sync_callback(
+            &*lua,
+            ("mux-authenticate".to_string(), (token.to_string(), info)),
+        )?;
+        match v {
+            mlua::Value::Nil => Ok(Verdict::Undecided),
+            mlua::Value::Boolean(false) => Ok(Verdict::Refused),
+            mlua::Value::String(s) => match s.to_str()? {
+                "Full" => Ok(Verdict::Accepted(AuthRole::Full)),
+                "ReadOnly" => Ok(Verdict::Accepted(AuthRole::ReadOnly)),
+                other => anyhow::bail!("`{}` is not a role; expected Full or ReadOnly", other),
+            },
+            other => anyhow::bail!(
+                "expected a role, false or nil, but the handler returned a {}",
+                other.type_name()
+            ),
+        }
+    })
+}
+
+/// Compares the tokens in time that doesn't depend on where they
+/// differ, so that the position of a difference can't be timed
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    let expected = expected.as_bytes();
+    let actual = actual.as_bytes();
+    if expected.len() != actual.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(actual.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compare() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cret", "s3creT"));
+        assert!(!tokens_match("s3cret", "s3cret2"));
+        assert!(!tokens_match("s3cret", ""));
+    }
+}
@@ -1,12 +1,16 @@
-use crate::sessionhandler::{PduSender, SessionHandler};
+use crate::auth::{TokenAuth, AUTH_FAILURE_DELAY};
+use crate::sessionhandler::{OutputQueue, PduSender, RequestBarrier, SessionHandler};
 use crate::UnixStream;
-use anyhow::Context;
+use anyhow::Context as _;
 use async_ossl::AsyncSslStream;
-use codec::{DecodedPdu, Pdu};
-use futures::FutureExt;
+use codec::Pdu;
 use mux::{Mux, MuxNotification};
 use smol::prelude::*;
 use smol::Async;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use wezterm_term::Alert;
 
 #[cfg(unix)]
 pub trait AsRawDesc: std::os::unix::io::AsRawFd {}
@@ -16,57 +20,252 @@ pub trait AsRawDesc: std::os::windows::io::AsRawSocket {}
 impl AsRawDesc for UnixStream {}
 impl AsRawDesc for AsyncSslStream {}
 
+/// The connection to a client.  While the server waits for the
+/// client to send a request, it also waits for notifications from
+/// the mux, so it needs to be able to wait for the stream to become
+/// readable without reading from it.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug {
+    fn readable(&self) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + '_>>;
+}
+
+impl<T> ClientStream for Async<T>
+where
+    T: std::io::Read,
+    T: std::io::Write,
+    T: std::fmt::Debug,
+    T: Unpin,
+{
+    fn readable(&self) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + '_>> {
+        Box::pin(Async::readable(self))
+    }
+}
+
+/// Wraps the stream of a client to count the bytes that are
+/// transferred in each direction
+#[derive(Debug)]
+struct CountingStream<S> {
+    inner: S,
+    received: u64,
+    sent: u64,
+}
+
+impl<S> CountingStream<S> {
+    /// Returns the (received, sent) byte counts since the last call
+    fn take_counts(&mut self) -> (u64, u64) {
+        let counts = (self.received, self.sent);
+        self.received = 0;
+        self.sent = 0;
+        counts
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.received += *n as u64;
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.sent += *n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
 #[derive(Debug)]
 enum Item {
     Notif(MuxNotification),
-    WritePdu(DecodedPdu),
+    /// The next PDU of the output queue is due to be written
+    WritePdu,
     Readable,
+    /// The request barrier was released
+    Resume,
+}
+
+/// Serves the client connected via `stream`.
+/// `is_remote` should be true if the transport can be reached from
+/// other hosts, so that the client's access to domains is restricted.
+pub async fn process<T>(stream: T, is_remote: bool) -> anyhow::Result<()>
+where
+    T: 'static,
+    T: std::io::Read,
+    T: std::io::Write,
+    T: AsRawDesc,
+    T: std::fmt::Debug,
+    T: Unpin,
+{
+    let stream = smol::Async::new(stream)?;
+    process_async(stream, is_remote).await
+}
+
+/// Serves a client on another host that connected with a share token,
+/// whose session is limited to the shared tab once it redeems the token
+pub async fn process_shared<T>(stream: T) -> anyhow::Result<()>
+where
+    T: 'static,
+    T: std::io::Read,
+    T: std::io::Write,
+    T: AsRawDesc,
+    T: std::fmt::Debug,
+    T: Unpin,
+{
+    let stream = smol::Async::new(stream)?;
+    serve(stream, true, true, None).await
 }
 
-pub async fn process<T>(stream: T) -> anyhow::Result<()>
+/// Serves a client on another host that connected to a TLS server
+/// which requires a token, and which must authenticate before its
+/// session does anything else; see `crate::auth`.
+/// If `shared` is true, the client may instead redeem a share token.
+pub async fn process_authenticated<T>(
+    stream: T,
+    auth: TokenAuth,
+    shared: bool,
+) -> anyhow::Result<()>
 where
     T: 'static,
     T: std::io::Read,
     T: std::io::Write,
     T: AsRawDesc,
     T: std::fmt::Debug,
+    T: Unpin,
 {
     let stream = smol::Async::new(stream)?;
-    process_async(stream).await
+    serve(stream, true, shared, Some(auth)).await
 }
 
-pub async fn process_async<T>(mut stream: Async<T>) -> anyhow::Result<()>
+pub async fn process_async<T>(stream: Async<T>, is_remote: bool) -> anyhow::Result<()>
 where
     T: 'static,
     T: std::io::Read,
     T: std::io::Write,
     T: std::fmt::Debug,
+    T: Unpin,
+{
+    serve(stream, is_remote, false, None).await
+}
+
+/// Serves the client connected via `stream`, which needn't be backed
+/// by a descriptor, such as an in-memory connection to a client that
+/// runs in the same process
+pub async fn process_stream<S>(stream: S, is_remote: bool) -> anyhow::Result<()>
+where
+    S: ClientStream + 'static,
+{
+    serve(stream, is_remote, false, None).await
+}
+
+async fn serve<S>(
+    stream: S,
+    is_remote: bool,
+    shared: bool,
+    auth: Option<TokenAuth>,
+) -> anyhow::Result<()>
+where
+    S: ClientStream + 'static,
 {
     log::trace!("process_async called");
 
+    let mut stream = CountingStream {
+        inner: stream,
+        received: 0,
+        sent: 0,
+    };
+
+    // Responses, notifications that change the structure of the mux
+    // and barrier releases share a channel, so that they are written
+    // to the client in the order in which they happened.  Advisory
+    // notifications, such as pane output, use a separate channel that
+    // is only drained when there is nothing more important to do.
     let (item_tx, item_rx) = smol::channel::unbounded::<Item>();
+    let (advisory_tx, advisory_rx) = smol::channel::unbounded::<Item>();
+
+    let output_queue = Arc::new(OutputQueue::new(
+        config::configuration().mux_client_output_queue_limit,
+    ));
 
     let pdu_sender = PduSender::new({
         let item_tx = item_tx.clone();
+        let output_queue = Arc::clone(&output_queue);
         move |pdu| {
+            // The channel only carries the position of the PDU, so
+            // that it is written in order with the notifications
+            output_queue.push(pdu);
             item_tx
-                .try_send(Item::WritePdu(pdu))
+                .try_send(Item::WritePdu)
                 .map_err(|e| anyhow::anyhow!("{:?}", e))
         }
     });
-    let mut handler = SessionHandler::new(pdu_sender);
+    let barrier = RequestBarrier::new({
+        let item_tx = item_tx.clone();
+        move || {
+            item_tx.try_send(Item::Resume).ok();
+        }
+    });
+    let mut handler =
+        SessionHandler::new(pdu_sender, Arc::clone(&output_queue), barrier, is_remote);
+    if shared {
+        handler.restrict_to_share();
+    }
+    if let Some(auth) = auth {
+        handler.require_auth(auth);
+    }
 
     {
         let mux = Mux::get().expect("to be running on gui thread");
         let tx = item_tx.clone();
-        mux.subscribe(move |n| tx.try_send(Item::Notif(n)).is_ok());
+        mux.subscribe(move |n| {
+            let tx = if n.is_sync_critical() {
+                &tx
+            } else {
+                &advisory_tx
+            };
+            tx.try_send(Item::Notif(n)).is_ok()
+        });
     }
 
     loop {
+        let (received, sent) = stream.take_counts();
+        handler.record_transfer(received, sent);
+
         let rx_msg = item_rx.recv();
-        let wait_for_read = stream.readable().map(|_| Ok(Item::Readable));
+        let advisory_msg = advisory_rx.recv();
+        // A client whose output is backed up isn't read from until it
+        // has caught up; writing its output wakes this loop again
+        let accepts_requests = output_queue.accepts_requests();
+        let wait_for_read = async {
+            if !accepts_requests {
+                smol::future::pending::<()>().await;
+            }
+            stream.inner.readable().await.ok();
+            Ok(Item::Readable)
+        };
 
-        match smol::future::or(rx_msg, wait_for_read).await {
+        // `or` prefers its first future when both are ready
+        match smol::future::or(rx_msg, smol::future::or(advisory_msg, wait_for_read)).await {
             Ok(Item::Readable) => {
                 let decoded = match Pdu::decode_async(&mut stream, None).await {
                     Ok(data) => data,
@@ -81,10 +280,38 @@ where
                     }
                 };
                 handler.process_one(decoded);
+                if handler.auth_failed() {
+                    // Slow down the guessing of tokens, then write the
+                    // refusal and hang up
+                    smol::Timer::after(AUTH_FAILURE_DELAY).await;
+                    while let Some(decoded) = output_queue.pop() {
+                        decoded
+                            .pdu
+                            .encode_async(&mut stream, decoded.serial)
+                            .await?;
+                    }
+                    stream.flush().await.ok();
+                    return Ok(());
+                }
             }
-            Ok(Item::WritePdu(decoded)) => {
+            Ok(Item::Notif(n)) if !handler.shows_notification(&n) => {}
+            Ok(Item::Resume) => {
+                handler.resume_deferred();
+            }
+            Ok(Item::WritePdu) => {
+                let decoded = match output_queue.pop() {
+                    Some(decoded) => decoded,
+                    None => continue,
+                };
+                handler.output_written();
                 match decoded.pdu.encode_async(&mut stream, decoded.serial).await {
-                    Ok(()) => {}
+                    Ok(bytes) => {
+                        if let Pdu::GetPaneRenderChangesResponse(response) = &decoded.pdu {
+                            if response.snapshot {
+                                handler.snapshot_written(response.pane_id, bytes);
+                            }
+                        }
+                    }
                     Err(err) => {
                         if let Some(err) = err.root_cause().downcast_ref::<std::io::Error>() {
                             if err.kind() == std::io::ErrorKind::BrokenPipe {
@@ -111,11 +338,26 @@ where
             }
             Ok(Item::Notif(MuxNotification::PaneAdded(_pane_id))) => {}
             Ok(Item::Notif(MuxNotification::PaneRemoved(pane_id))) => {
+                handler.forget_pane(pane_id);
+                crate::imagecache::forget_pane(pane_id);
+                crate::sessionhandler::forget_verify_requests(pane_id);
                 Pdu::PaneRemoved(codec::PaneRemoved { pane_id })
                     .encode_async(&mut stream, 0)
                     .await?;
                 stream.flush().await.context("flushing PDU to client")?;
             }
+            Ok(Item::Notif(MuxNotification::Alert {
+                pane_id,
+                alert: alert @ Alert::ToastNotification { .. },
+            })) => {
+                // Forward notifications right away, rather than waiting
+                // for the next (possibly deferred) push of the pane
+                // content, so that the client can show them promptly
+                Pdu::NotifyAlert(codec::NotifyAlert { pane_id, alert })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
             Ok(Item::Notif(MuxNotification::Alert { pane_id, alert })) => {
                 {
                     let per_pane = handler.per_pane(pane_id);
@@ -125,6 +367,81 @@ where
                 handler.schedule_pane_push(pane_id);
             }
             Ok(Item::Notif(MuxNotification::SaveToDownloads { .. })) => {}
+            Ok(Item::Notif(MuxNotification::PaneInternalError { .. })) => {}
+            Ok(Item::Notif(MuxNotification::PaneInputHeld(_))) => {}
+            Ok(Item::Notif(MuxNotification::PaneInputRejected { .. })) => {}
+            Ok(Item::Notif(MuxNotification::PaneResourceUsage(samples))) => {
+                Pdu::PaneResourceUsageUpdate(codec::PaneResourceUsageUpdate { samples })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            Ok(Item::Notif(MuxNotification::PaneOutputPaused { pane_id, paused })) => {
+                Pdu::PaneOutputPausedChanged(codec::PaneOutputPausedChanged { pane_id, paused })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            // Picks are served by the frontend of this process
+            Ok(Item::Notif(MuxNotification::PickPanes(_))) => {}
+            Ok(Item::Notif(MuxNotification::CloseConfirmationRequested(request))) => {
+                Pdu::CloseConfirmationPending(codec::CloseConfirmationPending { request })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            Ok(Item::Notif(MuxNotification::CloseConfirmationResolved { id, outcome })) => {
+                Pdu::CloseConfirmationResolved(codec::CloseConfirmationResolved { id, outcome })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            Ok(Item::Notif(MuxNotification::ShareEnded(id))) => {
+                if handler.viewed_share() == Some(id) {
+                    log::info!("share {} ended; disconnecting its viewer", id);
+                    return Ok(());
+                }
+            }
+            Ok(Item::Notif(MuxNotification::OutputMatched { owner, matched })) => {
+                if let Some(pdu) = handler.output_matched(owner.as_ref(), matched) {
+                    pdu.encode_async(&mut stream, 0).await?;
+                    stream.flush().await.context("flushing PDU to client")?;
+                }
+            }
+            Ok(Item::Notif(MuxNotification::ScrollToRow { pane_id, row })) => {
+                Pdu::ScrollPaneToRow(codec::ScrollPaneToRow { pane_id, row })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            Ok(Item::Notif(MuxNotification::PanePaletteOverridesChanged(pane_id))) => {
+                let overrides = {
+                    let mux = Mux::get().expect("to be running on gui thread");
+                    mux.get_pane(pane_id)
+                        .map(|pane| pane.get_palette_overrides())
+                };
+                if let Some(overrides) = overrides {
+                    Pdu::PanePaletteOverridesChanged(codec::PanePaletteOverridesChanged {
+                        pane_id,
+                        overrides,
+                    })
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                    stream.flush().await.context("flushing PDU to client")?;
+                }
+            }
+            Ok(Item::Notif(MuxNotification::PaneMarkersChanged(pane_id))) => {
+                let markers = {
+                    let mux = Mux::get().expect("to be running on gui thread");
+                    mux.get_pane(pane_id).map(|pane| pane.get_markers())
+                };
+                if let Some(markers) = markers {
+                    Pdu::PaneMarkersChanged(codec::PaneMarkersChanged { pane_id, markers })
+                        .encode_async(&mut stream, 0)
+                        .await?;
+                    stream.flush().await.context("flushing PDU to client")?;
+                }
+            }
             Ok(Item::Notif(MuxNotification::AssignClipboard {
                 pane_id,
                 selection,
@@ -140,7 +457,12 @@ where
                 stream.flush().await.context("flushing PDU to client")?;
             }
             Ok(Item::Notif(MuxNotification::WindowRemoved(_window_id))) => {}
-            Ok(Item::Notif(MuxNotification::WindowCreated(_window_id))) => {}
+            Ok(Item::Notif(MuxNotification::WindowCreated(window_id))) => {
+                if let Some(pdu) = handler.window_created(window_id) {
+                    pdu.encode_async(&mut stream, 0).await?;
+                    stream.flush().await.context("flushing PDU to client")?;
+                }
+            }
             Ok(Item::Notif(MuxNotification::WindowInvalidated(_window_id))) => {}
             Ok(Item::Notif(MuxNotification::WindowWorkspaceChanged(window_id))) => {
                 let workspace = {
@@ -159,6 +481,21 @@ where
                 }
             }
             Ok(Item::Notif(MuxNotification::ActiveWorkspaceChanged(_))) => {}
+            Ok(Item::Notif(MuxNotification::DomainAlreadyAttached(_))) => {}
+            Ok(Item::Notif(MuxNotification::DomainSyncProgress { .. })) => {}
+            Ok(Item::Notif(MuxNotification::PaneLinksChanged)) => {
+                // The links are reported in the pane list; have the
+                // client fetch it again
+                Pdu::PaneLinksChanged(codec::PaneLinksChanged {})
+                    .encode_async(&mut stream, 0)
+                    .await?;
+                stream.flush().await.context("flushing PDU to client")?;
+            }
+            Ok(Item::Notif(MuxNotification::TabLayoutChanged(_))) => {}
+            // The notice is relayed as output of the pane
+            Ok(Item::Notif(MuxNotification::CwdDropped { .. })) => {}
+            Ok(Item::Notif(MuxNotification::SpawnRedirected { .. })) => {}
+            Ok(Item::Notif(MuxNotification::WindowCreatedInWorkspace { .. })) => {}
             Ok(Item::Notif(MuxNotification::Empty)) => {}
             Err(err) => {
                 log::error!("process_async Err {}", err);
@@ -167,3 +504,455 @@ where
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::auth::{PeerInfo, TokenAuth};
+    use codec::{
+        Authenticate, DecodedPdu, GetLines, GetPaneRenderChanges, GetPaneRenderChangesResponse,
+        KillPane, ListPanes, Pdu, RedeemShare, ShareTab, SpawnV2, WriteToPane,
+    };
+    use config::keyassignment::SpawnTabDomain;
+    use config::{AuthRole, TlsAuthToken};
+    use mux::share::{ShareLink, ShareListener};
+    use mux::testing::with_test_mux;
+    use portable_pty::CommandBuilder;
+    use std::collections::HashSet;
+    use std::ops::Range;
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+    use wezterm_term::TerminalSize;
+
+    const ITERATIONS: usize = 500;
+
+    struct Client {
+        stream: UnixStream,
+        serial: u64,
+    }
+
+    impl Client {
+        /// Sends all of `requests` before reading any of the responses,
+        /// which are returned in the order in which they arrived
+        fn pipeline(&mut self, requests: Vec<Pdu>) -> Vec<(u64, Pdu)> {
+            let mut pending = HashSet::new();
+            for pdu in requests {
+                self.serial += 1;
+                pdu.encode(&mut self.stream, self.serial).unwrap();
+                pending.insert(self.serial);
+            }
+            let mut responses = vec![];
+            while !pending.is_empty() {
+                let DecodedPdu { pdu, serial } = Pdu::decode(&mut self.stream).unwrap();
+                // Skip any unilateral notifications
+                if pending.remove(&serial) {
+                    responses.push((serial, pdu));
+                }
+            }
+            responses
+        }
+
+        fn request(&mut self, pdu: Pdu) -> Pdu {
+            self.pipeline(vec![pdu]).pop().unwrap().1
+        }
+
+        /// Sends `pdu` and returns its response, along with the pane
+        /// changes that were pushed ahead of it
+        fn request_with_changes(&mut self, pdu: Pdu) -> (Pdu, Vec<GetPaneRenderChangesResponse>) {
+            self.serial += 1;
+            pdu.encode(&mut self.stream, self.serial).unwrap();
+            let mut changes = vec![];
+            loop {
+                let DecodedPdu { pdu, serial } = Pdu::decode(&mut self.stream).unwrap();
+                if serial == self.serial {
+                    return (pdu, changes);
+                }
+                if let Pdu::GetPaneRenderChangesResponse(response) = pdu {
+                    changes.push(response);
+                }
+            }
+        }
+    }
+
+    fn lines_text(client: &mut Client, pane_id: mux::pane::PaneId, rows: Range<isize>) -> String {
+        match client.request(Pdu::GetLines(GetLines {
+            pane_id,
+            lines: vec![rows],
+        })) {
+            Pdu::GetLinesResponse(response) => response
+                .lines
+                .extract_data()
+                .0
+                .into_iter()
+                .map(|(_, line)| line.as_str().into_owned())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    fn screen_text(client: &mut Client, pane_id: mux::pane::PaneId, rows: usize) -> String {
+        lines_text(client, pane_id, 0..rows as isize)
+    }
+
+    fn stress(mut client: Client) {
+        let size = TerminalSize::default();
+        for iteration in 0..ITERATIONS {
+            // Ask for the pane list immediately after the spawn; it must
+            // be answered after the spawn and must include the new tab
+            let mut responses = client.pipeline(vec![
+                Pdu::SpawnV2(SpawnV2 {
+                    domain: SpawnTabDomain::DomainName("local".to_string()),
+                    window_id: None,
+                    command: Some(CommandBuilder::new("cat")),
+                    command_dir: None,
+                    size,
+                    workspace: "default".to_string(),
+                    reuse: None,
+                    window_target: None,
+                }),
+                Pdu::ListPanes(ListPanes {}),
+            ]);
+            assert_eq!(responses.len(), 2);
+            let (list_serial, list) = responses.pop().unwrap();
+            let (spawn_serial, spawned) = responses.pop().unwrap();
+            assert!(spawn_serial < list_serial, "responses out of order");
+            let spawned = match spawned {
+                Pdu::SpawnResponse(spawned) => spawned,
+                pdu => panic!("unexpected response {:?}", pdu),
+            };
+            match list {
+                Pdu::ListPanesResponse(list) => {
+                    assert!(
+                        list.tabs.iter().any(|tab| tab.window_and_tab_ids()
+                            == Some((spawned.window_id, spawned.tab_id))),
+                        "iteration {}: tab {} missing from {:?}",
+                        iteration,
+                        spawned.tab_id,
+                        list.tabs
+                    )
+                }
+                pdu => panic!("unexpected response {:?}", pdu),
+            }
+
+            let text = format!("stress-{}", iteration);
+            match client.request(Pdu::WriteToPane(WriteToPane {
+                pane_id: spawned.pane_id,
+                data: format!("{}\n", text).into_bytes(),
+                from_cli: false,
+            })) {
+                Pdu::UnitResponse(_) => {}
+                pdu => panic!("unexpected response {:?}", pdu),
+            }
+
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let screen = screen_text(&mut client, spawned.pane_id, size.rows);
+                if screen.contains(&text) {
+                    break;
+                }
+                assert!(
+                    Instant::now() < deadline,
+                    "iteration {}: lost write; screen is {:?}",
+                    iteration,
+                    screen
+                );
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            client.request(Pdu::KillPane(KillPane {
+                pane_id: spawned.pane_id,
+            }));
+        }
+    }
+
+    fn spawn_cat(client: &mut Client) -> codec::SpawnResponse {
+        match client.request(Pdu::SpawnV2(SpawnV2 {
+            domain: SpawnTabDomain::DomainName("local".to_string()),
+            window_id: None,
+            command: Some(CommandBuilder::new("cat")),
+            command_dir: None,
+            size: TerminalSize::default(),
+            workspace: "default".to_string(),
+            reuse: None,
+            window_target: None,
+        })) {
+            Pdu::SpawnResponse(spawned) => spawned,
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    fn is_denied(pdu: &Pdu) -> bool {
+        matches!(pdu, Pdu::PermissionDeniedResponse(_))
+    }
+
+    fn share(mut owner: Client, mut viewer: Client) {
+        let shared = spawn_cat(&mut owner);
+        let other = spawn_cat(&mut owner);
+        let link: ShareLink = match owner.request(Pdu::ShareTab(ShareTab {
+            tab_id: shared.tab_id,
+            expires: Duration::from_secs(60),
+        })) {
+            Pdu::ShareTabResponse(response) => response.link.parse().unwrap(),
+            pdu => panic!("unexpected response {:?}", pdu),
+        };
+        assert_eq!(link.address, "example.com:8080");
+        assert_eq!(link.token.fingerprint, "c0ffee");
+
+        // Nothing can be seen before the token is redeemed
+        assert!(is_denied(&viewer.request(Pdu::ListPanes(ListPanes {}))));
+        assert!(is_denied(&owner.request(Pdu::RedeemShare(RedeemShare {
+            secret: link.token.secret.clone(),
+        }))));
+        match viewer.request(Pdu::RedeemShare(RedeemShare {
+            secret: link.token.secret.clone(),
+        })) {
+            Pdu::RedeemShareResponse(response) => assert_eq!(response.tab_id, shared.tab_id),
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+
+        match viewer.request(Pdu::ListPanes(ListPanes {})) {
+            Pdu::ListPanesResponse(list) => {
+                let tabs: Vec<_> = list
+                    .tabs
+                    .iter()
+                    .filter_map(|tab| tab.window_and_tab_ids())
+                    .map(|(_window_id, tab_id)| tab_id)
+                    .collect();
+                assert_eq!(tabs, vec![shared.tab_id]);
+                assert_eq!(list.active_tabs, vec![shared.tab_id]);
+            }
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+        screen_text(&mut viewer, shared.pane_id, 1);
+        assert!(is_denied(&viewer.request(Pdu::GetLines(GetLines {
+            pane_id: other.pane_id,
+            lines: vec![0..1],
+        }))));
+        assert!(is_denied(&viewer.request(Pdu::WriteToPane(WriteToPane {
+            pane_id: shared.pane_id,
+            data: b"typed\n".to_vec(),
+            from_cli: false,
+        }))));
+        assert!(is_denied(&viewer.request(Pdu::KillPane(KillPane {
+            pane_id: shared.pane_id,
+        }))));
+
+        for pane_id in [shared.pane_id, other.pane_id] {
+            owner.request(Pdu::KillPane(KillPane { pane_id }));
+        }
+    }
+
+    #[test]
+    fn shared_session_is_limited_to_the_tab() {
+        with_test_mux(|mux| {
+            mux.set_share_listener(ShareListener {
+                address: "example.com:8080".to_string(),
+                fingerprint: "c0ffee".to_string(),
+            });
+
+            let (owner_server, owner) = UnixStream::pair().unwrap();
+            promise::spawn::spawn(
+                async move { crate::dispatch::process(owner_server, false).await },
+            )
+            .detach();
+            let (viewer_server, viewer) = UnixStream::pair().unwrap();
+            promise::spawn::spawn(
+                async move { crate::dispatch::process_shared(viewer_server).await },
+            )
+            .detach();
+
+            move || {
+                share(
+                    Client {
+                        stream: owner,
+                        serial: 0,
+                    },
+                    Client {
+                        stream: viewer,
+                        serial: 0,
+                    },
+                )
+            }
+        });
+    }
+
+    fn authenticate(client: &mut Client, token: &str) -> Result<AuthRole, Pdu> {
+        match client.request(Pdu::Authenticate(Authenticate {
+            token: token.to_string(),
+        })) {
+            Pdu::AuthenticateResponse(response) => Ok(response.role),
+            pdu => Err(pdu),
+        }
+    }
+
+    fn token_auth(mut full: Client, mut read_only: Client, mut guesser: Client) {
+        // Nothing can be done before authenticating
+        assert!(is_denied(&full.request(Pdu::ListPanes(ListPanes {}))));
+        assert_eq!(authenticate(&mut full, "full-token"), Ok(AuthRole::Full));
+        let spawned = spawn_cat(&mut full);
+
+        assert_eq!(
+            authenticate(&mut read_only, "watch-token"),
+            Ok(AuthRole::ReadOnly)
+        );
+        assert!(matches!(
+            read_only.request(Pdu::ListPanes(ListPanes {})),
+            Pdu::ListPanesResponse(_)
+        ));
+        screen_text(&mut read_only, spawned.pane_id, 1);
+        assert!(is_denied(&read_only.request(Pdu::WriteToPane(
+            WriteToPane {
+                pane_id: spawned.pane_id,
+                data: b"typed\n".to_vec(),
+                from_cli: false,
+            }
+        ))));
+        assert!(is_denied(&read_only.request(Pdu::KillPane(KillPane {
+            pane_id: spawned.pane_id,
+        }))));
+
+        // A wrong token is refused after a delay, and the connection
+        // is closed
+        let start = Instant::now();
+        match authenticate(&mut guesser, "full-tokeN") {
+            Err(pdu) => assert!(is_denied(&pdu)),
+            Ok(role) => panic!("a wrong token was accepted with role {:?}", role),
+        }
+        assert!(start.elapsed() >= crate::auth::AUTH_FAILURE_DELAY);
+        assert!(Pdu::decode(&mut guesser.stream).is_err());
+
+        full.request(Pdu::KillPane(KillPane {
+            pane_id: spawned.pane_id,
+        }));
+    }
+
+    #[test]
+    fn token_auth_roles() {
+        let auth = TokenAuth {
+            tokens: vec![
+                TlsAuthToken {
+                    token: "full-token".to_string(),
+                    role: AuthRole::Full,
+                },
+                TlsAuthToken {
+                    token: "watch-token".to_string(),
+                    role: AuthRole::ReadOnly,
+                },
+            ],
+            peer: PeerInfo {
+                address: Some("192.0.2.1:4000".to_string()),
+                cert_cn: None,
+                server: "0.0.0.0:8080".to_string(),
+            },
+        };
+
+        with_test_mux(move |_mux| {
+            let mut clients = vec![];
+            for _ in 0..3 {
+                let (server, client) = UnixStream::pair().unwrap();
+                let auth = auth.clone();
+                promise::spawn::spawn(async move {
+                    crate::dispatch::process_authenticated(server, auth, false).await
+                })
+                .detach();
+                clients.push(Client {
+                    stream: client,
+                    serial: 0,
+                });
+            }
+
+            move || {
+                let guesser = clients.pop().unwrap();
+                let read_only = clients.pop().unwrap();
+                let full = clients.pop().unwrap();
+                token_auth(full, read_only, guesser)
+            }
+        });
+    }
+
+    #[test]
+    fn spawn_write_read_is_ordered() {
+        with_test_mux(|_mux| {
+            let (server, stream) = UnixStream::pair().unwrap();
+            promise::spawn::spawn(async move { crate::dispatch::process(server, false).await })
+                .detach();
+
+            move || stress(Client { stream, serial: 0 })
+        });
+    }
+
+    fn snapshot(mut writer: Client, mut attacher: Client) {
+        const LINES: usize = 300;
+        let spawned = spawn_cat(&mut writer);
+        let data: String = (0..LINES).map(|i| format!("line-{}\n", i)).collect();
+        writer.request(Pdu::WriteToPane(WriteToPane {
+            pane_id: spawned.pane_id,
+            data: data.into_bytes(),
+            from_cli: false,
+        }));
+        // The input is echoed and then repeated by cat
+        let last = format!("line-{}", LINES - 1);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while lines_text(&mut writer, spawned.pane_id, 0..2 * LINES as isize)
+            .matches(&last)
+            .count()
+            < 2
+        {
+            assert!(Instant::now() < deadline, "output never arrived");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        // A client that attaches now is sent only the screen and the
+        // most recent scrollback
+        let (_, changes) =
+            attacher.request_with_changes(Pdu::GetPaneRenderChanges(GetPaneRenderChanges {
+                pane_id: spawned.pane_id,
+            }));
+        let snapshot = changes
+            .into_iter()
+            .find(|changes| changes.pane_id == spawned.pane_id)
+            .expect("no changes were pushed");
+        assert!(snapshot.snapshot);
+        assert!(snapshot.dirty_lines.is_empty());
+        let dims = snapshot.dimensions;
+        let scrollback = config::configuration().mux_snapshot_scrollback_lines;
+        let first_row = dims.physical_top - scrollback as isize;
+        assert!(first_row > dims.scrollback_top);
+        let (lines, _) = snapshot.bonus_lines.extract_data();
+        assert!(lines.iter().all(|(row, _)| *row >= first_row));
+        // The cursor's row is sent as well
+        assert!(lines.len() <= scrollback + dims.viewport_rows + 1);
+
+        // Older lines are fetched when they are needed
+        assert_eq!(
+            lines_text(&mut attacher, spawned.pane_id, 0..1).trim_end(),
+            "line-0"
+        );
+
+        writer.request(Pdu::KillPane(KillPane {
+            pane_id: spawned.pane_id,
+        }));
+    }
+
+    #[test]
+    fn attach_sends_a_snapshot() {
+        with_test_mux(|_mux| {
+            let mut clients = vec![];
+            for _ in 0..2 {
+                let (server, client) = UnixStream::pair().unwrap();
+                promise::spawn::spawn(async move { crate::dispatch::process(server, false).await })
+                    .detach();
+                clients.push(Client {
+                    stream: client,
+                    serial: 0,
+                });
+            }
+
+            move || {
+                let attacher = clients.pop().unwrap();
+                let writer = clients.pop().unwrap();
+                snapshot(writer, attacher)
+            }
+        });
+    }
+}
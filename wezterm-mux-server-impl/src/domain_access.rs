@@ -0,0 +1,218 @@
+//! Restricts the domains that remote clients of the server can see
+//! and spawn into.
+//! A session is remote if it arrived over TLS or was relayed by
+//! `wezterm cli proxy` on behalf of a client on another host.
+//! Local sessions, such as those of `wezterm cli`, and the lua
+//! configuration of the server itself are not restricted.
+use config::lua::mlua;
+use mux::domain::{Domain, DomainError, DomainInfo};
+use std::sync::Arc;
+
+/// Gives the `remote-domain-access` event the opportunity to decide
+/// whether remote clients can use the domain named `name`.
+/// Returns None if there is no handler, or if it didn't return a boolean.
+fn call_remote_domain_access(name: &str) -> Option<bool> {
+    match config::run_immediate_with_lua_config(|lua| {
+        if let Some(lua) = lua {
+            let v = config::lua::emit_sync_callback(
+                &*lua,
+                ("remote-domain-access".to_string(), (name.to_string(),)),
+            )?;
+            match v {
+                mlua::Value::Boolean(allowed) => Ok(Some(allowed)),
+                _ => Ok(None),
+            }
+        } else {
+            Ok(None)
+        }
+    }) {
+        Ok(allowed) => allowed,
+        Err(err) => {
+            log::warn!("remote-domain-access: {:#}", err);
+            // Fail closed, as the handler may have been written
+            // to exclude this domain
+            Some(false)
+        }
+    }
+}
+
+/// Returns true if remote clients can see and spawn into the
+/// domain named `name`
+pub fn is_remote_accessible(name: &str) -> bool {
+    if let Some(allowed) = call_remote_domain_access(name) {
+        return allowed;
+    }
+    match &config::configuration().remote_spawnable_domains {
+        Some(names) => names.iter().any(|n| n == name),
+        None => true,
+    }
+}
+
+/// Removes the domains that remote clients can't use
+pub fn filter_remote_domains(domains: Vec<DomainInfo>) -> Vec<DomainInfo> {
+    domains
+        .into_iter()
+        .filter(|info| is_remote_accessible(&info.name))
+        .collect()
+}
+
+/// Returns an error if remote clients can't spawn into `domain`.
+/// The `DomainError::PermissionDenied` is sent to the client as a
+/// `PermissionDeniedResponse`.
+pub fn check_remote_spawn(domain: &Arc<dyn Domain>) -> anyhow::Result<()> {
+    let name = domain.domain_name();
+    if is_remote_accessible(name) {
+        Ok(())
+    } else {
+        log::warn!("refused a request from a remote client to spawn into domain {name}");
+        Err(DomainError::PermissionDenied {
+            reason: format!("domain {} is not available to remote clients", name),
+        }
+        .into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use codec::{DecodedPdu, ListDomains, Pdu, SpawnV2};
+    use config::keyassignment::{SpawnTabDomain, SpawnWindowTarget};
+    use mux::builder::MuxBuilder;
+    use mux::domain::{Domain, LocalDomain};
+    use mux::testing::with_test_mux_from;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use wezterm_term::TerminalSize;
+
+    /// Sets up a mux with the domains `allowed` and `secret`, of which
+    /// only `allowed` is available to remote clients, and then sends
+    /// each of `requests` to a session over a loopback connection.
+    /// Returns the responses.
+    fn run_session(is_remote: bool, requests: Vec<Pdu>) -> Vec<Pdu> {
+        let mut config = config::Config::default_config();
+        config.remote_spawnable_domains = Some(vec!["allowed".to_string()]);
+        let allowed: Arc<dyn Domain> = Arc::new(LocalDomain::new("allowed").unwrap());
+        let secret: Arc<dyn Domain> = Arc::new(LocalDomain::new("secret").unwrap());
+        let builder = MuxBuilder::new()
+            .config(config)
+            .domain(allowed)
+            .domain(secret);
+
+        with_test_mux_from(builder, move |_mux| {
+            let (server, mut client) = UnixStream::pair().unwrap();
+            promise::spawn::spawn(async move { crate::dispatch::process(server, is_remote).await })
+                .detach();
+
+            move || {
+                let mut responses = vec![];
+                for (idx, pdu) in requests.into_iter().enumerate() {
+                    let serial = idx as u64 + 1;
+                    pdu.encode(&mut client, serial).unwrap();
+                    loop {
+                        let DecodedPdu { pdu, serial: s } = Pdu::decode(&mut client).unwrap();
+                        // Skip any unilateral notifications
+                        if s == serial {
+                            responses.push(pdu);
+                            break;
+                        }
+                    }
+                }
+                responses
+            }
+        })
+    }
+
+    fn domain_names(pdu: &Pdu) -> Vec<String> {
+        match pdu {
+            Pdu::ListDomainsResponse(response) => response
+                .domains
+                .iter()
+                .map(|info| info.name.clone())
+                .collect(),
+            _ => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn remote_listing_is_filtered() {
+        let responses = run_session(true, vec![Pdu::ListDomains(ListDomains)]);
+        assert_eq!(domain_names(&responses[0]), vec!["allowed"]);
+    }
+
+    #[test]
+    fn local_listing_is_not_filtered() {
+        let responses = run_session(false, vec![Pdu::ListDomains(ListDomains)]);
+        assert_eq!(domain_names(&responses[0]), vec!["allowed", "secret"]);
+    }
+
+    #[test]
+    fn remote_spawn_is_rejected() {
+        let responses = run_session(
+            true,
+            vec![Pdu::SpawnV2(SpawnV2 {
+                domain: SpawnTabDomain::DomainName("secret".to_string()),
+                window_id: None,
+                command: None,
+                command_dir: None,
+                size: TerminalSize::default(),
+                workspace: "default".to_string(),
+                reuse: None,
+                window_target: None,
+            })],
+        );
+        match &responses[0] {
+            Pdu::PermissionDeniedResponse(denied) => assert_eq!(
+                denied.reason,
+                "domain secret is not available to remote clients"
+            ),
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn screen_target_needs_a_gui() {
+        let responses = run_session(
+            false,
+            vec![Pdu::SpawnV2(SpawnV2 {
+                domain: SpawnTabDomain::DomainName("allowed".to_string()),
+                window_id: None,
+                command: None,
+                command_dir: None,
+                size: TerminalSize::default(),
+                workspace: "default".to_string(),
+                reuse: None,
+                window_target: Some(SpawnWindowTarget::NewWindowOnScreen("DP-2".to_string())),
+            })],
+        );
+        match &responses[0] {
+            Pdu::ErrorResponse(err) => assert_eq!(
+                err.reason,
+                "Error: spawning on screen `DP-2` requires the wezterm GUI, which knows \
+                 about the screens and the mouse pointer; this mux has no GUI"
+            ),
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn unknown_domain_is_a_domain_error() {
+        let responses = run_session(
+            false,
+            vec![Pdu::SpawnV2(SpawnV2 {
+                domain: SpawnTabDomain::DomainName("missing".to_string()),
+                window_id: None,
+                command: None,
+                command_dir: None,
+                size: TerminalSize::default(),
+                workspace: "default".to_string(),
+                reuse: None,
+                window_target: None,
+            })],
+        );
+        match &responses[0] {
+            Pdu::DomainErrorResponse(err) => {
+                assert_eq!(err.code, mux::domain::DomainError::DOMAIN_NOT_FOUND)
+            }
+            pdu => panic!("unexpected response {:?}", pdu),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Holds the image data referenced by the lines that have been sent
+//! to clients, keyed by the hash of that data, so that a client's
+//! request for an image can be answered even after the line that
+//! referenced it has changed, and without searching the pane for it.
+//! Each pane has its own cache, bounded by `mux_image_cache_size`
+//! bytes, from which the least recently used images are evicted.
+use lru::LruCache;
+use mux::pane::PaneId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use termwiz::image::ImageData;
+use wezterm_term::{Line, StableRowIndex};
+
+lazy_static::lazy_static! {
+    static ref CACHES: Mutex<HashMap<PaneId, PaneImageCache>> = Mutex::new(HashMap::new());
+}
+
+struct PaneImageCache {
+    images: LruCache<[u8; 32], Arc<ImageData>>,
+    bytes: usize,
+}
+
+impl PaneImageCache {
+    fn new() -> Self {
+        Self {
+            images: LruCache::unbounded(),
+            bytes: 0,
+        }
+    }
+
+    fn insert(&mut self, data: &Arc<ImageData>, capacity: usize) {
+        let hash = data.hash();
+        // Looking it up also marks it as recently used
+        if self.images.get(&hash).is_some() {
+            return;
+        }
+        let len = data.len();
+        if len > capacity {
+            return;
+        }
+        self.images.put(hash, Arc::clone(data));
+        self.bytes += len;
+        while self.bytes > capacity {
+            match self.images.pop_lru() {
+                Some((_, evicted)) => self.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn get(&mut self, hash: &[u8; 32]) -> Option<Arc<ImageData>> {
+        self.images.get(hash).map(Arc::clone)
+    }
+}
+
+/// Records the images referenced by `lines`, which are about to be
+/// sent to a client
+pub fn remember_images(pane_id: PaneId, lines: &[(StableRowIndex, Line)]) {
+    let capacity = config::configuration().mux_image_cache_size;
+    if capacity == 0 {
+        return;
+    }
+    let mut caches = CACHES.lock().unwrap();
+    for (_, line) in lines {
+        for cell in line.cells() {
+            if let Some(images) = cell.attrs().images() {
+                for im in images {
+                    caches
+                        .entry(pane_id)
+                        .or_insert_with(PaneImageCache::new)
+                        .insert(im.image_data(), capacity);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the image data with the specified hash, if it was
+/// referenced by lines of the pane that were recently sent
+pub fn lookup(pane_id: PaneId, hash: &[u8; 32]) -> Option<Arc<ImageData>> {
+    CACHES
+        .lock()
+        .unwrap()
+        .get_mut(&pane_id)
+        .and_then(|cache| cache.get(hash))
+}
+
+/// Discards the images of a pane that has been removed
+pub fn forget_pane(pane_id: PaneId) {
+    CACHES.lock().unwrap().remove(&pane_id);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::image::ImageDataType;
+
+    fn image(pixels: u32, value: u8) -> Arc<ImageData> {
+        Arc::new(ImageData::with_data(ImageDataType::new_single_frame(
+            pixels,
+            1,
+            vec![value; pixels as usize * 4],
+        )))
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = PaneImageCache::new();
+        let a = image(1, 1);
+        let b = image(1, 2);
+        let c = image(1, 3);
+
+        cache.insert(&a, 8);
+        cache.insert(&b, 8);
+        // Using `a` makes `b` the least recently used
+        assert!(cache.get(&a.hash()).is_some());
+        cache.insert(&c, 8);
+
+        assert!(cache.get(&a.hash()).is_some());
+        assert!(cache.get(&b.hash()).is_none());
+        assert!(cache.get(&c.hash()).is_some());
+        assert_eq!(cache.bytes, 8);
+    }
+
+    #[test]
+    fn skips_images_larger_than_the_cache() {
+        let mut cache = PaneImageCache::new();
+        let small = image(1, 1);
+        cache.insert(&small, 8);
+        cache.insert(&image(4, 1), 8);
+        assert!(cache.get(&small.hash()).is_some());
+        assert_eq!(cache.bytes, 4);
+    }
+}
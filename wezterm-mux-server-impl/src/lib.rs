@@ -3,8 +3,12 @@ use std::os::unix::net::{UnixListener, UnixStream};
 #[cfg(windows)]
 use uds_windows::{UnixListener, UnixStream};
 
+pub mod auth;
 pub mod dispatch;
+pub mod domain_access;
+pub mod imagecache;
 pub mod local;
+pub mod metrics;
 pub mod pki;
 pub mod sessionhandler;
 
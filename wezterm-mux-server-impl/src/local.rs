@@ -1,15 +1,25 @@
-use crate::UnixListener;
+use crate::{UnixListener, UnixStream};
 use anyhow::{anyhow, Context as _};
-use config::{create_user_owned_dirs, UnixDomain};
+use config::{create_user_owned_dirs, CliListen, UnixDomain};
 use promise::spawn::spawn_into_main_thread;
+#[cfg(unix)]
+use std::path::Path;
 
 pub struct LocalListener {
     listener: UnixListener,
+    /// When true, connections from processes that are not running
+    /// as the same user as this process are rejected
+    #[cfg(unix)]
+    verify_peer: bool,
 }
 
 impl LocalListener {
     pub fn new(listener: UnixListener) -> Self {
-        Self { listener }
+        Self {
+            listener,
+            #[cfg(unix)]
+            verify_peer: false,
+        }
     }
 
     pub fn with_domain(unix_dom: &UnixDomain) -> anyhow::Result<Self> {
@@ -17,17 +27,52 @@ impl LocalListener {
         Ok(Self::new(listener))
     }
 
+    /// Listen on the fixed endpoint configured by `cli_listen`.
+    /// Unlike the default socket, that path may be somewhere that is
+    /// reachable by other users, so each peer is verified as it connects.
+    #[cfg(unix)]
+    pub fn with_cli_listen(sock_path: &Path) -> anyhow::Result<Self> {
+        // Another instance may already be serving this endpoint;
+        // only replace the socket if it is stale.
+        if UnixStream::connect(sock_path).is_ok() {
+            anyhow::bail!(
+                "{} is already being served by another process",
+                sock_path.display()
+            );
+        }
+        match std::fs::remove_file(sock_path) {
+            Ok(_) => {}
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::NotFound => {}
+                _ => return Err(err).context(format!("Unable to remove {}", sock_path.display())),
+            },
+        }
+
+        let listener = UnixListener::bind(sock_path)
+            .with_context(|| format!("Failed to bind to {}", sock_path.display()))?;
+
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(sock_path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("setting permissions of {}", sock_path.display()))?;
+
+        Ok(Self {
+            listener,
+            verify_peer: true,
+        })
+    }
+
     pub fn run(&mut self) {
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    spawn_into_main_thread(async move {
-                        crate::dispatch::process(stream).await.map_err(|e| {
-                            log::error!("{:#}", e);
-                            e
-                        })
-                    })
-                    .detach();
+                    #[cfg(unix)]
+                    if self.verify_peer {
+                        if let Err(err) = verify_peer_is_same_user(&stream) {
+                            log::error!("rejecting connection: {:#}", err);
+                            continue;
+                        }
+                    }
+                    serve_local(stream);
                 }
                 Err(err) => {
                     log::error!("accept failed: {}", err);
@@ -38,6 +83,73 @@ impl LocalListener {
     }
 }
 
+fn serve_local(stream: UnixStream) {
+    spawn_into_main_thread(async move {
+        // Clients on other hosts only reach this socket via
+        // `wezterm cli proxy`, which marks the session as remote
+        let is_remote = false;
+        crate::dispatch::process(stream, is_remote)
+            .await
+            .map_err(|e| {
+                log::error!("{:#}", e);
+                e
+            })
+    })
+    .detach();
+}
+
+/// Starts listening on the `cli_listen` endpoint, if one is configured.
+/// Failing to set it up is logged rather than treated as fatal, as the
+/// default socket remains available.
+pub fn spawn_cli_listener() {
+    let config = config::configuration();
+    if let Some(cli_listen) = &config.cli_listen {
+        if let Err(err) = spawn_cli_listener_for(cli_listen) {
+            log::error!("cli_listen: {:#}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn spawn_cli_listener_for(cli_listen: &CliListen) -> anyhow::Result<()> {
+    let socket_path = cli_listen.endpoint()?;
+    let mut listener = LocalListener::with_cli_listen(&socket_path)?;
+    std::thread::spawn(move || {
+        listener.run();
+        std::fs::remove_file(socket_path).ok();
+    });
+    Ok(())
+}
+
+/// On Windows the endpoint is a named pipe, which, unlike an AF_UNIX
+/// socket, can tell us who the client is running as
+#[cfg(windows)]
+fn spawn_cli_listener_for(cli_listen: &CliListen) -> anyhow::Result<()> {
+    use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+
+    let mut listener = mux::namedpipe::PipeListener::bind(&cli_listen.endpoint()?)?;
+    std::thread::spawn(move || loop {
+        let pipe = match listener.accept() {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                log::error!("accept failed: {:#}", err);
+                return;
+            }
+        };
+        if let Err(err) = pipe.verify_client_is_same_user() {
+            log::error!("rejecting connection: {:#}", err);
+            continue;
+        }
+        match pipe.into_socket() {
+            Ok(socket) => {
+                serve_local(unsafe { UnixStream::from_raw_socket(socket.into_raw_socket()) })
+            }
+            Err(err) => log::error!("cli_listen: {:#}", err),
+        }
+    });
+    Ok(())
+}
+
 /// Take care when setting up the listener socket;
 /// we need to be sure that the directory that we create it in
 /// is owned by the user and has appropriate file permissions
@@ -92,3 +204,63 @@ fn safely_create_sock_path(unix_dom: &UnixDomain) -> anyhow::Result<UnixListener
 
     Ok(listener)
 }
+
+/// Checks that the process on the other end of `stream` is running
+/// as the same user as this process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn verify_peer_is_same_user(stream: &UnixStream) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut _,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error()).context("getsockopt SO_PEERCRED");
+    }
+    check_peer_uid(cred.uid)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn verify_peer_is_same_user(stream: &UnixStream) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("getpeereid");
+    }
+    check_peer_uid(uid)
+}
+
+#[cfg(unix)]
+fn check_peer_uid(uid: libc::uid_t) -> anyhow::Result<()> {
+    let my_uid = unsafe { libc::geteuid() };
+    if uid != my_uid {
+        anyhow::bail!("peer uid {} doesn't match our uid {}", uid, my_uid);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peer_check() {
+        let (a, b) = UnixStream::pair().unwrap();
+        verify_peer_is_same_user(&a).unwrap();
+        verify_peer_is_same_user(&b).unwrap();
+
+        let my_uid = unsafe { libc::geteuid() };
+        check_peer_uid(my_uid).unwrap();
+        assert!(check_peer_uid(my_uid.wrapping_add(1)).is_err());
+    }
+}
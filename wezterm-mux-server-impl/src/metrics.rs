@@ -0,0 +1,534 @@
+//! Exposes the state of the mux in the Prometheus text format over
+//! plain HTTP, for operating a shared mux server.
+//!
+//! Counters and histograms are recorded by the mux and protocol layers
+//! via the `metrics` crate.  Those calls cost next to nothing unless a
+//! recorder has been installed, which only happens when the listener is
+//! enabled.  Gauges that describe the current state of the mux, such as
+//! the number of panes, are computed from the mux each time the endpoint
+//! is scraped.
+use anyhow::Context;
+use metrics::{GaugeValue, Key, Recorder, Unit};
+use mux::Mux;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The upper bounds of the buckets used for latency histograms, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+struct Histogram {
+    /// The number of observations that were <= each of LATENCY_BUCKETS
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.,
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    counters: HashMap<Key, u64>,
+    gauges: HashMap<Key, f64>,
+    histograms: HashMap<Key, Histogram>,
+}
+
+/// Accumulates the values recorded via the `metrics` crate
+struct Registry {
+    inner: Mutex<Inner>,
+}
+
+impl Recorder for Registry {
+    fn register_counter(
+        &self,
+        _key: &Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn register_gauge(&self, _key: &Key, _unit: Option<Unit>, _description: Option<&'static str>) {}
+
+    fn register_histogram(
+        &self,
+        _key: &Key,
+        _unit: Option<Unit>,
+        _description: Option<&'static str>,
+    ) {
+    }
+
+    fn increment_counter(&self, key: &Key, value: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.counters.entry(key.clone()).or_insert(0) += value;
+    }
+
+    fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        let mut inner = self.inner.lock().unwrap();
+        let gauge = inner.gauges.entry(key.clone()).or_insert(0.);
+        *gauge = match value {
+            GaugeValue::Absolute(value) => value,
+            GaugeValue::Increment(value) => *gauge + value,
+            GaugeValue::Decrement(value) => *gauge - value,
+        };
+    }
+
+    fn record_histogram(&self, key: &Key, value: f64) {
+        // The `.rate` histograms duplicate their `.size` counterparts
+        // for the benefit of the GUI's throughput display
+        if key.name().ends_with(".rate") {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .histograms
+            .entry(key.clone())
+            .or_insert_with(Histogram::new)
+            .record(value);
+    }
+}
+
+/// Converts a metric name such as `mux.pdu.latency` into the form
+/// used by Prometheus: `wezterm_mux_pdu_latency`
+fn metric_name(name: &str, suffix: &str) -> String {
+    let mut result = "wezterm_".to_string();
+    for c in name.chars() {
+        result.push(if c.is_ascii_alphanumeric() { c } else { '_' });
+    }
+    result.push_str(suffix);
+    result
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels<'a>(labels: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let labels: Vec<String> = labels
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn key_labels(key: &Key) -> String {
+    format_labels(key.labels().map(|label| (label.key(), label.value())))
+}
+
+/// Accumulates the text exposition format, grouping the samples
+/// of each metric beneath its `# TYPE` line
+#[derive(Default)]
+struct Exposition {
+    metrics: BTreeMap<String, (&'static str, Vec<String>)>,
+}
+
+impl Exposition {
+    fn sample(&mut self, name: &str, kind: &'static str, sample: String) {
+        self.metrics
+            .entry(name.to_string())
+            .or_insert_with(|| (kind, vec![]))
+            .1
+            .push(sample);
+    }
+
+    fn gauge(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let sample = format!(
+            "{}{} {}",
+            name,
+            format_labels(labels.iter().map(|(k, v)| (*k, *v))),
+            value
+        );
+        self.sample(name, "gauge", sample);
+    }
+
+    fn render(self) -> String {
+        let mut output = String::new();
+        for (name, (kind, mut samples)) in self.metrics {
+            samples.sort();
+            writeln!(output, "# TYPE {} {}", name, kind).ok();
+            for sample in samples {
+                writeln!(output, "{}", sample).ok();
+            }
+        }
+        output
+    }
+}
+
+impl Registry {
+    fn render(&self, exposition: &mut Exposition) {
+        let inner = self.inner.lock().unwrap();
+
+        for (key, value) in &inner.counters {
+            let name = metric_name(key.name(), "_total");
+            let sample = format!("{}{} {}", name, key_labels(key), value);
+            exposition.sample(&name, "counter", sample);
+        }
+
+        for (key, value) in &inner.gauges {
+            let name = metric_name(key.name(), "");
+            let sample = format!("{}{} {}", name, key_labels(key), value);
+            exposition.sample(&name, "gauge", sample);
+        }
+
+        for (key, histogram) in &inner.histograms {
+            let labels: Vec<(&str, &str)> = key
+                .labels()
+                .map(|label| (label.key(), label.value()))
+                .collect();
+
+            if key.name().ends_with(".size") {
+                // Sizes don't fit the latency buckets; report them
+                // as a summary without quantiles
+                let name = metric_name(key.name(), "_bytes");
+                let labels = format_labels(labels.iter().copied());
+                exposition.sample(
+                    &name,
+                    "summary",
+                    format!("{}_sum{} {}", name, labels, histogram.sum),
+                );
+                exposition.sample(
+                    &name,
+                    "summary",
+                    format!("{}_count{} {}", name, labels, histogram.count),
+                );
+                continue;
+            }
+
+            // Everything else is a duration measured in seconds
+            let name = metric_name(key.name(), "_seconds");
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let bound = bound.to_string();
+                let mut bucket_labels = labels.clone();
+                bucket_labels.push(("le", &bound));
+                exposition.sample(
+                    &name,
+                    "histogram",
+                    format!(
+                        "{}_bucket{} {}",
+                        name,
+                        format_labels(bucket_labels.into_iter()),
+                        count
+                    ),
+                );
+            }
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le", "+Inf"));
+            exposition.sample(
+                &name,
+                "histogram",
+                format!(
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(bucket_labels.into_iter()),
+                    histogram.count
+                ),
+            );
+            let labels = format_labels(labels.into_iter());
+            exposition.sample(
+                &name,
+                "histogram",
+                format!("{}_sum{} {}", name, labels, histogram.sum),
+            );
+            exposition.sample(
+                &name,
+                "histogram",
+                format!("{}_count{} {}", name, labels, histogram.count),
+            );
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry = Registry {
+        inner: Mutex::new(Inner::default()),
+    };
+}
+
+/// Computes the gauges that describe the current state of the mux.
+/// Must be called on the mux thread.
+fn mux_gauges(exposition: &mut Exposition) {
+    let mux = match Mux::get() {
+        Some(mux) => mux,
+        None => return,
+    };
+
+    for domain in mux.iter_domains() {
+        let inventory = mux.domain_inventory(domain.domain_id());
+        let labels = [("domain", domain.domain_name())];
+        exposition.gauge(
+            "wezterm_mux_domain_windows",
+            &labels,
+            inventory.num_windows as f64,
+        );
+        exposition.gauge(
+            "wezterm_mux_domain_tabs",
+            &labels,
+            inventory.num_tabs as f64,
+        );
+        exposition.gauge(
+            "wezterm_mux_domain_panes",
+            &labels,
+            inventory.num_panes as f64,
+        );
+    }
+
+    exposition.gauge("wezterm_mux_clients", &[], mux.iter_clients().len() as f64);
+
+    let mut scrollback_rows = 0;
+    let mut scrollback_bytes = 0;
+    for pane in mux.iter_panes() {
+        let dims = pane.get_dimensions();
+        scrollback_rows += dims.scrollback_rows;
+        // An estimate: lines that hold less text than the width of
+        // the pane use less memory than this
+        scrollback_bytes +=
+            dims.scrollback_rows * dims.cols * std::mem::size_of::<termwiz::cell::Cell>();
+    }
+    exposition.gauge("wezterm_mux_scrollback_lines", &[], scrollback_rows as f64);
+    exposition.gauge(
+        "wezterm_mux_scrollback_estimated_bytes",
+        &[],
+        scrollback_bytes as f64,
+    );
+}
+
+/// Produces the text exposition of all of the metrics.
+/// Blocks until the mux thread has computed its gauges, so this
+/// must not be called on the mux thread.
+fn scrape() -> String {
+    let mut exposition = smol::block_on(promise::spawn::spawn_into_main_thread(async {
+        let mut exposition = Exposition::default();
+        mux_gauges(&mut exposition);
+        exposition
+    }));
+    REGISTRY.render(&mut exposition);
+    exposition.render()
+}
+
+/// How long a client has to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most that is read of the request line and headers
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Reads from a stream until `deadline`, after which reads fail, so
+/// that a client that trickles its request can't hold the listener
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self
+            .deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| std::io::Error::new(ErrorKind::TimedOut, "request timed out"))?;
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn serve_request(stream: TcpStream, timeout: Duration) -> anyhow::Result<()> {
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(
+        DeadlineReader {
+            stream: stream.try_clone()?,
+            deadline: Instant::now() + timeout,
+        }
+        .take(MAX_REQUEST_BYTES),
+    );
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Consume the headers; we have no use for them
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            if reader.get_ref().limit() == 0 {
+                anyhow::bail!("request exceeds {} bytes", MAX_REQUEST_BYTES);
+            }
+            break;
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut stream = stream;
+    let mut words = request_line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = scrape();
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        }
+        _ => {
+            stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Installs the metrics recorder and starts serving the metrics
+/// on `bind_address` at the `/metrics` path.
+/// Returns the address that was bound, which is useful when
+/// binding to port 0.
+pub fn spawn_metrics_listener(bind_address: &str) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind_address)
+        .with_context(|| format!("binding metrics listener to {}", bind_address))?;
+    let addr = listener.local_addr()?;
+
+    metrics::set_recorder(&*REGISTRY)
+        .map_err(|e| anyhow::anyhow!("Failed to set metrics recorder: {}", e))?;
+
+    // Requests are served one at a time on this thread; each is bounded
+    // by REQUEST_TIMEOUT, so a client that stalls only delays the others
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = serve_request(stream, REQUEST_TIMEOUT) {
+                        log::debug!("serving metrics: {:#}", err);
+                    }
+                }
+                Err(err) => {
+                    log::error!("metrics listener accept failed: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+
+    log::info!("serving metrics on http://{}/metrics", addr);
+    Ok(addr)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use config::keyassignment::SpawnTabDomain;
+    use mux::testing::with_test_mux;
+    use portable_pty::CommandBuilder;
+    use wezterm_term::TerminalSize;
+
+    #[test]
+    fn scrape_pane_gauge() {
+        let response = with_test_mux(|_mux| {
+            let addr = spawn_metrics_listener("127.0.0.1:0").unwrap();
+            let (tx, rx) = std::sync::mpsc::channel();
+            promise::spawn::spawn(async move {
+                let mux = Mux::get().unwrap();
+                mux.spawn_tab_or_window(
+                    None,
+                    SpawnTabDomain::DefaultDomain,
+                    Some(CommandBuilder::from_argv(vec!["sleep".into(), "10".into()])),
+                    None,
+                    TerminalSize::default(),
+                    None,
+                    mux.active_workspace(),
+                )
+                .await
+                .unwrap();
+                tx.send(()).unwrap();
+            })
+            .detach();
+
+            // Scrape from another thread, as the scrape needs the
+            // mux thread to compute the gauges
+            move || {
+                rx.recv().unwrap();
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            }
+        });
+
+        assert!(response.starts_with("HTTP/1.0 200 OK"), "{}", response);
+        assert!(
+            response.contains("wezterm_mux_domain_panes{domain=\"local\"} 1\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("wezterm_mux_domain_tabs{domain=\"local\"} 1\n"),
+            "{}",
+            response
+        );
+    }
+
+    /// Accepts a connection from a client that is set up by `client`,
+    /// and serves it with a short timeout
+    fn serve_one(client: impl FnOnce(&mut TcpStream)) -> (anyhow::Result<()>, Duration) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client(&mut stream);
+        let (server, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        let result = serve_request(server, Duration::from_millis(200));
+        (result, start.elapsed())
+    }
+
+    #[test]
+    fn idle_client_times_out() {
+        let (result, elapsed) = serve_one(|_| {});
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        // As is a client that stops partway through its headers
+        let (result, elapsed) = serve_one(|stream| {
+            stream
+                .write_all(b"GET /metrics HTTP/1.0\r\nX-Slow:")
+                .unwrap();
+        });
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn oversized_request_is_rejected() {
+        let (result, _) = serve_one(|stream| {
+            stream.write_all(b"GET /metrics HTTP/1.0\r\n").unwrap();
+            for _ in 0..1000 {
+                stream.write_all(b"X-Padding: 0123456789\r\n").unwrap();
+            }
+            stream.write_all(b"\r\n").unwrap();
+        });
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{:#}", err);
+    }
+}
@@ -1,21 +1,69 @@
+use crate::auth::TokenAuth;
+use crate::domain_access::{check_remote_spawn, filter_remote_domains};
+use crate::imagecache;
 use crate::PKI;
 use anyhow::{anyhow, Context};
 use codec::*;
+use config::keyassignment::SpawnTabDomain;
+use config::AuthRole;
+use mux::audit::{self, AuditRecord};
+use mux::bookmark::scroll_to_bookmark;
 use mux::client::ClientId;
-use mux::domain::SplitSource;
+use mux::closeconfirm::{CloseOutcome, CloseScope};
+use mux::coalesce::{self, UpdateCoalescer, UpdateKey};
+use mux::domain::{Domain, DomainError, DomainId, SplitSource};
+use mux::domainexec::ExecRequest;
+use mux::editscrollback::{self, EditScrollbackRequest};
+use mux::globalsearch::{GlobalSearchOptions, SearchCancellation};
+use mux::inputfilter::{FilteredKey, InputSource};
+use mux::outputwatch::OutputMatch;
 use mux::pane::{Pane, PaneId};
-use mux::renderable::{RenderableDimensions, StableCursorPosition};
+use mux::renderable::{lines_checksum, RenderableDimensions, StableCursorPosition};
+use mux::share::ShareId;
+use mux::spawnquota::SpawnOrigin;
 use mux::tab::TabId;
-use mux::Mux;
+use mux::window::WindowId;
+use mux::{Mux, MuxNotification};
 use promise::spawn::spawn_into_main_thread;
-use std::collections::HashMap;
+use rangeset::RangeSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use termwiz::surface::SequenceNo;
 use url::Url;
 use wezterm_term::terminal::Alert;
-use wezterm_term::StableRowIndex;
+use wezterm_term::{Line, StableRowIndex};
+
+lazy_static::lazy_static! {
+    /// Counts the verifications of each pane that were requested with
+    /// `VerifyPane`, so that each session can tell when its client is
+    /// due a checksum
+    static ref VERIFY_REQUESTS: Mutex<HashMap<PaneId, usize>> = Mutex::new(HashMap::new());
+}
+
+fn verify_generation(pane_id: PaneId) -> usize {
+    VERIFY_REQUESTS
+        .lock()
+        .unwrap()
+        .get(&pane_id)
+        .copied()
+        .unwrap_or(0)
+}
+
+pub fn forget_verify_requests(pane_id: PaneId) {
+    VERIFY_REQUESTS.lock().unwrap().remove(&pane_id);
+}
+
+/// How long the output of a pane must be quiet before its clients are
+/// sent a checksum of the screen, or None if checksums are disabled
+fn checksum_interval() -> Option<Duration> {
+    match config::configuration().mux_pane_checksum_interval {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
 
 #[derive(Clone)]
 pub struct PduSender {
@@ -35,6 +83,170 @@ impl PduSender {
     }
 }
 
+/// Holds back the requests that a client sends while one of its
+/// requests that returns new ids (spawn, split, move, clone) is being
+/// processed.  The held requests are processed once the response to
+/// that request has been queued, so that they observe its effects and
+/// their responses follow it on the connection.
+#[derive(Clone)]
+pub struct RequestBarrier {
+    raised: Arc<AtomicBool>,
+    resume: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl RequestBarrier {
+    /// `resume` is called whenever the barrier is released; it should
+    /// arrange for `SessionHandler::resume_deferred` to be called after
+    /// any output that was queued before it.
+    pub fn new<T>(resume: T) -> Self
+    where
+        T: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            raised: Arc::new(AtomicBool::new(false)),
+            resume: Arc::new(resume),
+        }
+    }
+
+    fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    fn raise(&self) -> BarrierGuard {
+        self.raised.store(true, Ordering::SeqCst);
+        BarrierGuard(self.clone())
+    }
+}
+
+/// Releases the barrier when the response has been sent, or when the
+/// request is dropped without one
+struct BarrierGuard(RequestBarrier);
+
+impl BarrierGuard {
+    fn release(&self) {
+        if self.0.raised.swap(false, Ordering::SeqCst) {
+            (self.0.resume)();
+        }
+    }
+}
+
+impl Drop for BarrierGuard {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Returns true if the response to `pdu` carries ids that the client
+/// may refer to in the requests that follow it
+fn allocates_ids(pdu: &Pdu) -> bool {
+    match pdu {
+        Pdu::SpawnV2(_) | Pdu::SplitPane(_) | Pdu::MovePaneToNewTab(_) | Pdu::CloneTab(_) => true,
+        _ => false,
+    }
+}
+
+/// Holds the PDUs that have been queued for a client but not yet
+/// written to it; it is the only path from the session to the writer.
+/// The queue is bounded: when a client is slow to consume its output
+/// and the queue is full, pane updates for that client are deferred and
+/// later coalesced into a single update that reflects the latest state,
+/// and no further requests are read from the client until the queue
+/// and the requests that it has yet to be answered have drained.
+/// This never blocks the pty reader or the delivery of output to other
+/// clients.
+pub struct OutputQueue {
+    pdus: Mutex<VecDeque<DecodedPdu>>,
+    limit: usize,
+    /// Requests that were read from the client but not yet answered
+    unanswered: AtomicUsize,
+    deferred: Mutex<HashSet<PaneId>>,
+}
+
+impl OutputQueue {
+    /// A `limit` of 0 is treated as 1, as no requests would ever be
+    /// read from the client otherwise
+    pub fn new(limit: usize) -> Self {
+        Self {
+            pdus: Mutex::new(VecDeque::new()),
+            limit: limit.max(1),
+            unanswered: AtomicUsize::new(0),
+            deferred: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.pdus.lock().unwrap().len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.depth() >= self.limit
+    }
+
+    /// Returns true if another request may be read from the client,
+    /// which is the case while its responses, along with the PDUs that
+    /// are already queued, would fit in the queue
+    pub fn accepts_requests(&self) -> bool {
+        self.depth() + self.unanswered.load(Ordering::Relaxed) < self.limit
+    }
+
+    /// Queues `pdu` to be written to the client
+    pub fn push(&self, pdu: DecodedPdu) {
+        self.pdus.lock().unwrap().push_back(pdu);
+    }
+
+    /// Takes the next PDU to be written to the client
+    pub fn pop(&self) -> Option<DecodedPdu> {
+        self.pdus.lock().unwrap().pop_front()
+    }
+
+    /// Counts a request as unanswered until the returned guard is
+    /// answered or dropped
+    fn start_request(self: &Arc<Self>) -> UnansweredGuard {
+        self.unanswered.fetch_add(1, Ordering::Relaxed);
+        UnansweredGuard {
+            queue: Arc::clone(self),
+            answered: AtomicBool::new(false),
+        }
+    }
+
+    /// Called when a queued PDU has been written to the client.
+    /// Returns the set of panes whose updates were deferred, if
+    /// there is now room in the queue to send them.
+    pub fn written(&self) -> Vec<PaneId> {
+        if self.is_full() {
+            vec![]
+        } else {
+            self.deferred.lock().unwrap().drain().collect()
+        }
+    }
+
+    /// Defer sending an update for the pane until the queue drains
+    pub fn defer(&self, pane_id: PaneId) {
+        self.deferred.lock().unwrap().insert(pane_id);
+    }
+}
+
+/// Counts a request of the client as unanswered until its response has
+/// been queued, or the request is dropped without one
+struct UnansweredGuard {
+    queue: Arc<OutputQueue>,
+    answered: AtomicBool,
+}
+
+impl UnansweredGuard {
+    fn answer(&self) {
+        if !self.answered.swap(true, Ordering::SeqCst) {
+            self.queue.unanswered.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for UnansweredGuard {
+    fn drop(&mut self) {
+        self.answer();
+    }
+}
+
 #[derive(Default, Debug)]
 pub(crate) struct PerPane {
     cursor_position: StableCursorPosition,
@@ -43,16 +255,75 @@ pub(crate) struct PerPane {
     dimensions: RenderableDimensions,
     mouse_grabbed: bool,
     sent_initial_palette: bool,
+    sent_snapshot: bool,
+    /// The snapshot that is queued for the client, which is logged
+    /// once it has been written and its size is known
+    pending_snapshot: Option<SnapshotStats>,
     seqno: SequenceNo,
     config_generation: usize,
     pub(crate) notifications: Vec<Alert>,
+    /// Title, cwd and user var alerts that are held back until the
+    /// coalescing window has elapsed
+    updates: UpdateCoalescer<UpdateKey>,
+    /// The title or cwd changed since the window was last flushed
+    metadata_changed: bool,
+    /// Counts the responses sent to the client
+    push_serial: u64,
+    /// Changes were sent since the client was last sent a checksum
+    unverified: bool,
+    /// When the client is due a checksum, if that is scheduled
+    checksum_due: Option<Instant>,
+    /// The value of `verify_generation` when the client was last sent
+    /// a checksum
+    verify_generation: usize,
 }
 
 impl PerPane {
+    /// Returns true if the next response should carry a checksum,
+    /// because one is due or because `VerifyPane` was used
+    fn verify_due(&mut self, pane_id: PaneId, now: Instant) -> bool {
+        let generation = verify_generation(pane_id);
+        let due = self.checksum_due.map_or(false, |due| due <= now);
+        if due || generation != self.verify_generation {
+            self.verify_generation = generation;
+            self.checksum_due = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns a response that changes nothing, but carries a checksum
+    /// of the viewport against which the client verifies its copy
+    fn checksum_only(&mut self, pane: &Rc<dyn Pane>) -> GetPaneRenderChangesResponse {
+        let dims = self.dimensions;
+        let (_, lines) = pane
+            .get_lines(dims.physical_top..dims.physical_top + dims.viewport_rows as StableRowIndex);
+        self.push_serial += 1;
+        self.unverified = false;
+        GetPaneRenderChangesResponse {
+            pane_id: pane.pane_id(),
+            mouse_grabbed: self.mouse_grabbed,
+            cursor_position: self.cursor_position,
+            dimensions: dims,
+            dirty_lines: vec![],
+            title: self.title.clone(),
+            working_dir: self.working_dir.clone().map(Into::into),
+            bonus_lines: Vec::<(StableRowIndex, Line)>::new().into(),
+            input_serial: None,
+            seqno: self.seqno,
+            snapshot: false,
+            push_serial: self.push_serial,
+            checksum: Some(lines_checksum(&lines)),
+        }
+    }
+
     fn compute_changes(
         &mut self,
         pane: &Rc<dyn Pane>,
         force_with_input_serial: Option<InputSerial>,
+        metadata_due: bool,
+        verify: bool,
     ) -> Option<GetPaneRenderChangesResponse> {
         let mut changed = false;
         let mouse_grabbed = pane.is_mouse_grabbed();
@@ -70,33 +341,55 @@ impl PerPane {
             changed = true;
         }
 
+        // A change to just the title or cwd waits for the coalescing
+        // window; see maybe_push_pane_changes
         let title = pane.get_title();
-        if title != self.title {
-            changed = true;
-        }
-
-        let working_dir = pane.get_current_working_dir();
-        if working_dir != self.working_dir {
-            changed = true;
+        // A directory with control characters isn't sent to clients
+        let working_dir = pane
+            .get_current_working_dir()
+            .filter(wezterm_term::cwd::is_safe_cwd_url);
+        if title != self.title || working_dir != self.working_dir {
+            self.metadata_changed = true;
+            if metadata_due {
+                changed = true;
+            }
         }
 
-        let mut all_dirty_lines = pane.get_changed_since(
-            0..dims.physical_top + dims.viewport_rows as StableRowIndex,
-            self.seqno,
-        );
-        if !all_dirty_lines.is_empty() {
+        // The first response is a snapshot of the screen and the most
+        // recent scrollback.  Rather than listing the entire history as
+        // dirty, we leave the client to fetch older lines with GetLines
+        // if and when it scrolls back to them; that is the only way in
+        // which they are sent.
+        let snapshot = !self.sent_snapshot;
+        let mut all_dirty_lines = if snapshot {
+            RangeSet::new()
+        } else {
+            pane.get_changed_since(
+                0..dims.physical_top + dims.viewport_rows as StableRowIndex,
+                self.seqno,
+            )
+        };
+        if snapshot || !all_dirty_lines.is_empty() {
             changed = true;
         }
 
         if !changed && !force_with_input_serial.is_some() {
+            if verify {
+                return Some(self.checksum_only(pane));
+            }
             return None;
         }
 
         // Figure out what we're going to send as dirty lines vs bonus lines
-        let viewport_range =
-            dims.physical_top..dims.physical_top + dims.viewport_rows as StableRowIndex;
+        let first_row = if snapshot {
+            let scrollback = config::configuration().mux_snapshot_scrollback_lines;
+            (dims.physical_top - scrollback as StableRowIndex).max(dims.scrollback_top)
+        } else {
+            dims.physical_top
+        };
+        let bonus_range = first_row..dims.physical_top + dims.viewport_rows as StableRowIndex;
 
-        let (first_line, lines) = pane.get_lines(viewport_range);
+        let (first_line, lines) = pane.get_lines(bonus_range);
         let mut bonus_lines = lines
             .into_iter()
             .enumerate()
@@ -106,6 +399,16 @@ impl PerPane {
                 (stable_row, line)
             })
             .collect::<Vec<_>>();
+        let checksum = if verify {
+            Some(lines_checksum(
+                bonus_lines
+                    .iter()
+                    .filter(|(stable_row, _)| *stable_row >= dims.physical_top)
+                    .map(|(_, line)| line),
+            ))
+        } else {
+            None
+        };
 
         // Always send the cursor's row, as that tends to the busiest and we don't
         // have a sequencing concept for our idea of the remote state.
@@ -118,9 +421,13 @@ impl PerPane {
         self.dimensions = dims;
         self.mouse_grabbed = mouse_grabbed;
         self.seqno = pane.get_current_seqno();
+        self.push_serial += 1;
+        self.unverified = true;
 
+        let num_bonus_lines = bonus_lines.len();
+        imagecache::remember_images(pane.pane_id(), &bonus_lines);
         let bonus_lines = bonus_lines.into();
-        Some(GetPaneRenderChangesResponse {
+        let response = GetPaneRenderChangesResponse {
             pane_id: pane.pane_id(),
             mouse_grabbed,
             dirty_lines: all_dirty_lines.iter().cloned().collect(),
@@ -131,23 +438,98 @@ impl PerPane {
             working_dir: working_dir.map(Into::into),
             input_serial: force_with_input_serial,
             seqno: self.seqno,
-        })
+            snapshot,
+            push_serial: self.push_serial,
+            checksum,
+        };
+
+        if snapshot {
+            self.sent_snapshot = true;
+            self.pending_snapshot = Some(SnapshotStats {
+                num_lines: num_bonus_lines,
+                deferred: (first_row - dims.scrollback_top).max(0) as usize,
+            });
+        }
+        Some(response)
+    }
+}
+
+/// Describes a snapshot that was sent in place of the full history
+#[derive(Debug, Clone, Copy)]
+struct SnapshotStats {
+    /// The number of lines in the snapshot
+    num_lines: usize,
+    /// The number of older lines, which the client fetches using
+    /// `GetLines` only if it scrolls back to them
+    deferred: usize,
+}
+
+impl SnapshotStats {
+    /// Estimates the size that sending the entire history would have
+    /// taken, given that the snapshot was encoded in `bytes`
+    fn full_history_bytes(&self, bytes: usize) -> usize {
+        if self.num_lines == 0 {
+            return bytes;
+        }
+        bytes + bytes * self.deferred / self.num_lines
+    }
+
+    /// Logs the size of the snapshot, which was encoded in `bytes`,
+    /// along with the size that the entire history would have taken
+    fn record(&self, pane_id: PaneId, bytes: usize) {
+        log::debug!(
+            "resync pane {}: snapshot of {} lines in {} bytes rather than \
+             about {} bytes for the full history; {} older lines are \
+             fetched on demand",
+            pane_id,
+            self.num_lines,
+            bytes,
+            self.full_history_bytes(bytes),
+            self.deferred
+        );
+        metrics::counter!("mux.resync.snapshot_bytes", bytes as u64);
+        metrics::counter!("mux.resync.deferred_lines", self.deferred as u64);
     }
 }
 
 fn maybe_push_pane_changes(
     pane: &Rc<dyn Pane>,
     sender: PduSender,
-    per_pane: Arc<Mutex<PerPane>>,
+    per_pane_handle: Arc<Mutex<PerPane>>,
 ) -> anyhow::Result<()> {
-    let mut per_pane = per_pane.lock().unwrap();
-    if let Some(resp) = per_pane.compute_changes(pane, None) {
+    let window = coalesce::coalesce_window();
+    let now = Instant::now();
+    let mut per_pane = per_pane_handle.lock().unwrap();
+    let metadata_due = per_pane.updates.is_due(now, window);
+    let verify = per_pane.verify_due(pane.pane_id(), now);
+    if let Some(resp) = per_pane.compute_changes(pane, None, metadata_due, verify) {
         sender.send(DecodedPdu {
             pdu: Pdu::GetPaneRenderChangesResponse(resp),
             serial: 0,
         })?;
     }
 
+    // Once the output has been quiet for a while, send a checksum so
+    // that the client can detect that its copy has gone out of step
+    if per_pane.unverified && per_pane.checksum_due.is_none() {
+        if let Some(interval) = checksum_interval() {
+            let due = now + interval;
+            per_pane.checksum_due.replace(due);
+            let pane_id = pane.pane_id();
+            let per_pane = Arc::clone(&per_pane_handle);
+            let sender = sender.clone();
+            spawn_into_main_thread(async move {
+                smol::Timer::at(due).await;
+                let mux = Mux::get().unwrap();
+                if let Some(pane) = mux.get_pane(pane_id) {
+                    maybe_push_pane_changes(&pane, sender, per_pane)?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+            .detach();
+        }
+    }
+
     let config = config::configuration();
     if per_pane.config_generation != config.generation() {
         per_pane.config_generation = config.generation();
@@ -163,7 +545,12 @@ fn maybe_push_pane_changes(
         per_pane.notifications.push(Alert::PaletteChanged);
         per_pane.sent_initial_palette = true;
     }
-    for alert in per_pane.notifications.drain(..) {
+    let notifications: Vec<Alert> = per_pane.notifications.drain(..).collect();
+    for alert in notifications {
+        if let Some((key, value)) = UpdateKey::for_alert(&alert, &**pane) {
+            per_pane.updates.push(key, value, alert);
+            continue;
+        }
         match alert {
             Alert::PaletteChanged => {
                 sender.send(DecodedPdu {
@@ -185,13 +572,88 @@ fn maybe_push_pane_changes(
             }
         }
     }
+
+    if per_pane.metadata_changed || per_pane.updates.has_pending() {
+        if metadata_due {
+            per_pane.metadata_changed = false;
+            for alert in per_pane.updates.flush(now) {
+                sender.send(DecodedPdu {
+                    pdu: Pdu::NotifyAlert(NotifyAlert {
+                        pane_id: pane.pane_id(),
+                        alert,
+                    }),
+                    serial: 0,
+                })?;
+            }
+        } else if per_pane.updates.schedule_flush() {
+            let due = per_pane.updates.next_flush(window).unwrap_or(now);
+            let pane_id = pane.pane_id();
+            let per_pane = Arc::clone(&per_pane_handle);
+            spawn_into_main_thread(async move {
+                smol::Timer::at(due).await;
+                let mux = Mux::get().unwrap();
+                if let Some(pane) = mux.get_pane(pane_id) {
+                    maybe_push_pane_changes(&pane, sender, per_pane)?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+            .detach();
+        }
+    }
+    coalesce::SUPPRESSED_REMOTE.fetch_add(per_pane.updates.take_suppressed(), Ordering::Relaxed);
     Ok(())
 }
 
 pub struct SessionHandler {
     to_write_tx: PduSender,
+    output_queue: Arc<OutputQueue>,
     per_pane: HashMap<TabId, Arc<Mutex<PerPane>>>,
     client_id: Option<Arc<ClientId>>,
+    /// Identifies the client in metrics
+    client_label: Option<String>,
+    /// True if the client is on another host, in which case its
+    /// access to domains is restricted
+    is_remote: bool,
+    /// True if the client is the GUI, whose spawns are made by the user
+    /// and so are exempt from the spawn limits unless configured
+    /// otherwise; see `mux::spawnquota`
+    is_interactive: bool,
+    barrier: RequestBarrier,
+    /// Requests that arrived while the barrier was raised
+    deferred: VecDeque<DecodedPdu>,
+    /// The image limits agreed with the client, if it proposed any
+    image_limits: Option<ImageLimits>,
+    /// The workspace that the client presents, if it told us
+    workspace: Option<String>,
+    /// Panes in windows of other workspaces than the one that the
+    /// client presents, whose changes are pushed once it presents theirs
+    background_panes: HashSet<PaneId>,
+    /// Limits the session of a client that connected with a share
+    /// token to the shared tab
+    share: Option<ShareScope>,
+    auth: AuthState,
+}
+
+/// Whether the client of a TLS server that requires a token has
+/// authenticated; see `crate::auth`
+#[derive(Debug, Clone)]
+enum AuthState {
+    NotRequired,
+    /// The client has yet to send its token
+    Pending(TokenAuth),
+    Authenticated(AuthRole),
+    /// The token was refused, and the connection is being closed
+    Failed,
+}
+
+/// What the session of a client that connected with a share token
+/// may see; see `mux::share`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShareScope {
+    /// The client has yet to redeem its token
+    Unredeemed,
+    /// The client redeemed the share and may watch its tab
+    Tab { share_id: ShareId, tab_id: TabId },
 }
 
 impl Drop for SessionHandler {
@@ -200,15 +662,272 @@ impl Drop for SessionHandler {
             let mux = Mux::get().unwrap();
             mux.unregister_client(&client_id);
         }
+        if let Some(ShareScope::Tab { share_id, .. }) = self.share {
+            let mux = Mux::get().unwrap();
+            mux.end_share(share_id);
+        }
     }
 }
 
 impl SessionHandler {
-    pub fn new(to_write_tx: PduSender) -> Self {
+    pub fn new(
+        to_write_tx: PduSender,
+        output_queue: Arc<OutputQueue>,
+        barrier: RequestBarrier,
+        is_remote: bool,
+    ) -> Self {
         Self {
             to_write_tx,
+            output_queue,
             per_pane: HashMap::new(),
             client_id: None,
+            client_label: None,
+            is_remote,
+            is_interactive: false,
+            barrier,
+            deferred: VecDeque::new(),
+            image_limits: None,
+            workspace: None,
+            background_panes: HashSet::new(),
+            share: None,
+            auth: AuthState::NotRequired,
+        }
+    }
+
+    /// Requires the client to authenticate with a token before it
+    /// can do anything else
+    pub fn require_auth(&mut self, auth: TokenAuth) {
+        self.auth = AuthState::Pending(auth);
+    }
+
+    /// Returns true if the client's token was refused, in which case
+    /// the connection is to be closed
+    pub fn auth_failed(&self) -> bool {
+        matches!(self.auth, AuthState::Failed)
+    }
+
+    /// Limits the session to a shared tab, which the client must
+    /// redeem its share token for before it can see anything
+    pub fn restrict_to_share(&mut self) {
+        self.share.replace(ShareScope::Unredeemed);
+    }
+
+    fn spawn_origin(&self) -> SpawnOrigin {
+        if self.is_interactive {
+            SpawnOrigin::Interactive
+        } else {
+            SpawnOrigin::Protocol
+        }
+    }
+
+    /// Returns the share that the client is viewing, if any
+    pub fn viewed_share(&self) -> Option<ShareId> {
+        match self.share {
+            Some(ShareScope::Tab { share_id, .. }) => Some(share_id),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the client may see the pane, which is only the
+    /// case for the panes of the shared tab if the session is limited
+    /// to one
+    fn can_see_pane(&self, pane_id: PaneId) -> bool {
+        match self.share {
+            None => true,
+            Some(ShareScope::Unredeemed) => false,
+            Some(ShareScope::Tab { tab_id, .. }) => Mux::get()
+                .and_then(|mux| mux.resolve_pane_id(pane_id))
+                .map_or(false, |(_domain_id, _window_id, pane_tab_id)| {
+                    pane_tab_id == tab_id
+                }),
+        }
+    }
+
+    /// Returns true if the client should be told of the notification.
+    /// A client that is limited to a shared tab is only told of
+    /// changes to its panes.
+    pub fn shows_notification(&self, notification: &MuxNotification) -> bool {
+        if matches!(self.auth, AuthState::Pending(_) | AuthState::Failed) {
+            return false;
+        }
+        if self.share.is_none() {
+            return true;
+        }
+        match notification {
+            MuxNotification::PaneOutput(pane_id)
+            | MuxNotification::PaneOutputPaused { pane_id, .. }
+            | MuxNotification::PanePaletteOverridesChanged(pane_id)
+            | MuxNotification::PaneMarkersChanged(pane_id)
+            | MuxNotification::ScrollToRow { pane_id, .. }
+            | MuxNotification::Alert { pane_id, .. } => self.can_see_pane(*pane_id),
+            // The pane is no longer in any tab, so its removal is
+            // relayed if the client knew of it
+            MuxNotification::PaneRemoved(pane_id) => self.per_pane.contains_key(pane_id),
+            MuxNotification::ShareEnded(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Rejects requests that a client whose session is limited to a
+    /// shared tab may not make, which are any that change the mux or
+    /// involve panes outside of the tab
+    fn check_share_scope(&self, pdu: &Pdu) -> Result<(), String> {
+        let scope = match self.share {
+            Some(scope) => scope,
+            None => return Ok(()),
+        };
+        let pane_id = match (scope, pdu) {
+            (_, Pdu::Ping(_))
+            | (_, Pdu::GetCodecVersion(_))
+            | (_, Pdu::SetClientId(_))
+            | (_, Pdu::NegotiateImageLimits(_))
+            | (_, Pdu::MarkSessionInteractive(_))
+            | (ShareScope::Unredeemed, Pdu::RedeemShare(_))
+            | (ShareScope::Unredeemed, Pdu::Authenticate(_))
+            | (ShareScope::Tab { .. }, Pdu::ListPanes(_))
+            | (ShareScope::Tab { .. }, Pdu::SetClientWorkspace(_)) => return Ok(()),
+            (ShareScope::Unredeemed, _) => {
+                return Err("the share token must be redeemed first".to_string())
+            }
+            (_, Pdu::GetPaneRenderChanges(GetPaneRenderChanges { pane_id, .. }))
+            | (_, Pdu::GetLines(GetLines { pane_id, .. }))
+            | (_, Pdu::GetImageCell(GetImageCell { pane_id, .. }))
+            | (_, Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, .. }))
+            | (
+                _,
+                Pdu::SearchPanes(SearchPanes {
+                    options:
+                        GlobalSearchOptions {
+                            pane_id: Some(pane_id),
+                            ..
+                        },
+                    ..
+                }),
+            )
+            | (_, Pdu::GetSemanticZones(GetSemanticZones { pane_id, .. }))
+            | (_, Pdu::GetSemanticZoneText(GetSemanticZoneText { pane_id, .. }))
+            | (_, Pdu::RequestPaneSnapshot(RequestPaneSnapshot { pane_id }))
+            | (_, Pdu::VerifyPane(VerifyPane { pane_id }))
+            | (_, Pdu::SetFocusedPane(SetFocusedPane { pane_id })) => *pane_id,
+            (_, Pdu::SyncPanes(SyncPanes { pane_ids })) => {
+                return match pane_ids.iter().find(|&&id| !self.can_see_pane(id)) {
+                    Some(pane_id) => Err(format!("pane {} is not in the shared tab", pane_id)),
+                    None => Ok(()),
+                };
+            }
+            (_, pdu) => {
+                return Err(format!(
+                    "{} is not allowed while viewing a shared tab",
+                    pdu.pdu_name()
+                ))
+            }
+        };
+        if self.can_see_pane(pane_id) {
+            Ok(())
+        } else {
+            Err(format!("pane {} is not in the shared tab", pane_id))
+        }
+    }
+
+    /// Rejects requests that a client may not make before it has
+    /// authenticated, or that change the mux if its role is `ReadOnly`
+    fn check_auth_scope(&self, pdu: &Pdu) -> Result<(), String> {
+        match (&self.auth, pdu) {
+            (AuthState::NotRequired, _) | (AuthState::Authenticated(AuthRole::Full), _) => Ok(()),
+            (_, Pdu::Ping(_)) | (_, Pdu::GetCodecVersion(_)) | (_, Pdu::Authenticate(_)) => Ok(()),
+            // A share token is as good as an authentication token,
+            // and the share scope limits the session until it is redeemed
+            (AuthState::Pending(_), _) if self.share == Some(ShareScope::Unredeemed) => Ok(()),
+            (AuthState::Pending(_), _) | (AuthState::Failed, _) => {
+                Err("the client must authenticate with a token first".to_string())
+            }
+            (AuthState::Authenticated(AuthRole::ReadOnly), pdu) => match pdu {
+                Pdu::SetClientId(_)
+                | Pdu::NegotiateImageLimits(_)
+                | Pdu::MarkSessionInteractive(_)
+                | Pdu::ListPanes(_)
+                | Pdu::ListDomains(_)
+                | Pdu::SetClientWorkspace(_)
+                | Pdu::GetPaneRenderChanges(_)
+                | Pdu::GetLines(_)
+                | Pdu::GetImageCell(_)
+                | Pdu::SearchScrollbackRequest(_)
+                | Pdu::SearchPanes(_)
+                | Pdu::GetSemanticZones(_)
+                | Pdu::GetSemanticZoneText(_)
+                | Pdu::RequestPaneSnapshot(_)
+                | Pdu::VerifyPane(_)
+                | Pdu::SetFocusedPane(_)
+                | Pdu::SetPaneViewState(_)
+                | Pdu::GetPaneViewStates(_)
+                | Pdu::SyncPanes(_) => Ok(()),
+                pdu => Err(format!(
+                    "{} is not allowed for a client with the ReadOnly role",
+                    pdu.pdu_name()
+                )),
+            },
+        }
+    }
+
+    /// Processes the requests that were held back by the barrier,
+    /// stopping if one of them raises it again
+    pub fn resume_deferred(&mut self) {
+        while !self.barrier.is_raised() {
+            match self.deferred.pop_front() {
+                Some(decoded) => self.process_one(decoded),
+                None => break,
+            }
+        }
+    }
+
+    /// Accounts for the bytes that were transferred to and from
+    /// the client since this was last called
+    pub fn record_transfer(&self, received: u64, sent: u64) {
+        if metrics::try_recorder().is_none() {
+            return;
+        }
+        if let Some(client) = &self.client_label {
+            if received > 0 {
+                metrics::counter!(
+                    "mux.client.bytes_received",
+                    received,
+                    "client" => client.clone()
+                );
+            }
+            if sent > 0 {
+                metrics::counter!(
+                    "mux.client.bytes_sent",
+                    sent,
+                    "client" => client.clone()
+                );
+            }
+        }
+    }
+
+    fn record_output_queue_depth(&self) {
+        if let Some(client_id) = &self.client_id {
+            if let Some(mux) = Mux::get() {
+                mux.record_output_queue_depth(client_id, self.output_queue.depth());
+            }
+        }
+    }
+
+    /// Called by the dispatcher each time that it has written a
+    /// queued PDU to the client
+    pub fn output_written(&mut self) {
+        for pane_id in self.output_queue.written() {
+            self.schedule_pane_push(pane_id);
+        }
+        self.record_output_queue_depth();
+    }
+
+    /// Called once a snapshot of `pane_id` has been written to the
+    /// client as a PDU of `bytes` bytes
+    pub fn snapshot_written(&mut self, pane_id: PaneId, bytes: usize) {
+        if let Some(per_pane) = self.per_pane.get(&pane_id) {
+            if let Some(stats) = per_pane.lock().unwrap().pending_snapshot.take() {
+                stats.record(pane_id, bytes);
+            }
         }
     }
 
@@ -220,7 +939,80 @@ impl SessionHandler {
         )
     }
 
+    /// Returns the workspace of the window, if the client presents
+    /// another one
+    fn other_workspace(&self, window_id: WindowId) -> Option<String> {
+        let presented = self.workspace.as_ref()?;
+        let mux = Mux::get()?;
+        let window = mux.get_window(window_id)?;
+        let workspace = window.get_workspace();
+        if workspace == presented {
+            None
+        } else {
+            Some(workspace.to_string())
+        }
+    }
+
+    fn is_background_pane(&self, pane_id: PaneId) -> bool {
+        // The shared tab is shown whichever workspace it is in
+        if self.share.is_some() {
+            return false;
+        }
+        Mux::get()
+            .and_then(|mux| mux.resolve_pane_id(pane_id))
+            .and_then(|(_domain_id, window_id, _tab_id)| self.other_workspace(window_id))
+            .is_some()
+    }
+
+    /// Called by the dispatcher when a window was created.  The client
+    /// learns of windows in the workspace that it presents when their
+    /// panes are pushed; clients that present another workspace are
+    /// told of the window with the returned PDU instead.
+    pub fn window_created(&self, window_id: WindowId) -> Option<Pdu> {
+        self.other_workspace(window_id).map(|workspace| {
+            Pdu::WindowCreatedInWorkspace(WindowCreatedInWorkspace {
+                window_id,
+                workspace,
+            })
+        })
+    }
+
+    /// Called by the dispatcher when an output watcher matched.  Only
+    /// the client that added the watcher is told of the match.
+    pub fn output_matched(
+        &self,
+        owner: Option<&Arc<ClientId>>,
+        matched: OutputMatch,
+    ) -> Option<Pdu> {
+        match (owner, &self.client_id) {
+            (Some(owner), Some(client_id)) if owner == client_id => {
+                Some(Pdu::OutputWatcherMatched(OutputWatcherMatched { matched }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Called by the dispatcher when a pane was removed
+    pub fn forget_pane(&mut self, pane_id: PaneId) {
+        self.background_panes.remove(&pane_id);
+    }
+
     pub fn schedule_pane_push(&mut self, pane_id: PaneId) {
+        if !self.can_see_pane(pane_id) {
+            return;
+        }
+        if self.is_background_pane(pane_id) {
+            self.background_panes.insert(pane_id);
+            return;
+        }
+        if self.output_queue.is_full() {
+            // The client isn't keeping up; rather than queue another
+            // update, remember that this pane needs one and send a
+            // single coalesced update once the queue has drained.
+            self.output_queue.defer(pane_id);
+            self.record_output_queue_depth();
+            return;
+        }
         let sender = self.to_write_tx.clone();
         let per_pane = self.per_pane(pane_id);
         spawn_into_main_thread(async move {
@@ -235,9 +1027,21 @@ impl SessionHandler {
     }
 
     pub fn process_one(&mut self, decoded: DecodedPdu) {
+        if self.barrier.is_raised() {
+            self.deferred.push_back(decoded);
+            return;
+        }
+
         let start = Instant::now();
         let sender = self.to_write_tx.clone();
         let serial = decoded.serial;
+        let pdu_name = decoded.pdu.pdu_name();
+        let barrier = if allocates_ids(&decoded.pdu) {
+            Some(self.barrier.raise())
+        } else {
+            None
+        };
+        let unanswered = self.output_queue.start_request();
 
         if let Some(client_id) = &self.client_id {
             Mux::get().unwrap().client_had_input(client_id);
@@ -246,14 +1050,40 @@ impl SessionHandler {
         let send_response = move |result: anyhow::Result<Pdu>| {
             let pdu = match result {
                 Ok(pdu) => pdu,
-                Err(err) => Pdu::ErrorResponse(ErrorResponse {
-                    reason: format!("Error: {}", err),
-                }),
+                Err(err) => match DomainError::find(&err) {
+                    Some(DomainError::PermissionDenied { reason }) => {
+                        Pdu::PermissionDeniedResponse(PermissionDeniedResponse {
+                            reason: reason.clone(),
+                        })
+                    }
+                    Some(domain_err) => Pdu::DomainErrorResponse(DomainErrorResponse {
+                        code: domain_err.code(),
+                        reason: format!("{:#}", err),
+                    }),
+                    None => Pdu::ErrorResponse(ErrorResponse {
+                        reason: format!("Error: {}", err),
+                    }),
+                },
             };
-            log::trace!("{} processing time {:?}", serial, start.elapsed());
+            let elapsed = start.elapsed();
+            log::trace!("{} processing time {:?}", serial, elapsed);
+            metrics::histogram!("mux.pdu.latency", elapsed, "pdu" => pdu_name);
+            unanswered.answer();
             sender.send(DecodedPdu { pdu, serial }).ok();
+            if let Some(barrier) = &barrier {
+                barrier.release();
+            }
         };
 
+        if let Err(reason) = self.check_auth_scope(&decoded.pdu) {
+            send_response(Err(DomainError::PermissionDenied { reason }.into()));
+            return;
+        }
+        if let Err(reason) = self.check_share_scope(&decoded.pdu) {
+            send_response(Err(DomainError::PermissionDenied { reason }.into()));
+            return;
+        }
+
         fn catch<F, SND>(f: F, send_response: SND)
         where
             F: FnOnce() -> anyhow::Result<Pdu>,
@@ -262,6 +1092,16 @@ impl SessionHandler {
             send_response(f());
         }
 
+        /// Runs `f` and records its outcome in the audit log
+        fn audited<F>(record: AuditRecord, f: F) -> anyhow::Result<Pdu>
+        where
+            F: FnOnce() -> anyhow::Result<Pdu>,
+        {
+            let result = f();
+            audit::log(record.outcome(&result));
+            result
+        }
+
         match decoded.pdu {
             Pdu::Ping(Ping {}) => send_response(Ok(Pdu::Pong(Pong {}))),
             Pdu::SetWindowWorkspace(SetWindowWorkspace {
@@ -285,6 +1125,10 @@ impl SessionHandler {
             }
             Pdu::SetClientId(SetClientId { client_id }) => {
                 let client_id = Arc::new(client_id);
+                self.client_label.replace(format!(
+                    "{}@{}:{}",
+                    client_id.username, client_id.hostname, client_id.pid
+                ));
                 self.client_id.replace(client_id.clone());
                 spawn_into_main_thread(async move {
                     let mux = Mux::get().unwrap();
@@ -293,6 +1137,22 @@ impl SessionHandler {
                 .detach();
                 send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
             }
+            Pdu::SetClientWorkspace(SetClientWorkspace { workspace }) => {
+                self.workspace.replace(workspace.clone());
+                // Catch up on the panes of the newly presented workspace;
+                // those of other workspaces go back into the background
+                for pane_id in std::mem::take(&mut self.background_panes) {
+                    self.schedule_pane_push(pane_id);
+                }
+                if let Some(client_id) = self.client_id.clone() {
+                    spawn_into_main_thread(async move {
+                        let mux = Mux::get().unwrap();
+                        mux.set_active_workspace_for_client(&client_id, &workspace);
+                    })
+                    .detach();
+                }
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
             Pdu::SetFocusedPane(SetFocusedPane { pane_id }) => {
                 let client_id = self.client_id.clone();
                 spawn_into_main_thread(async move {
@@ -303,6 +1163,36 @@ impl SessionHandler {
                 .detach();
                 send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
             }
+            Pdu::SetPaneViewState(SetPaneViewState { pane_id, state }) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    if let Some(client_id) = client_id {
+                        let mux = Mux::get().unwrap();
+                        mux.record_pane_view_for_client(&client_id, pane_id, state);
+                    }
+                })
+                .detach();
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::GetPaneViewStates(GetPaneViewStates {}) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let states = match client_id {
+                                Some(client_id) => mux.pane_views_for_client(&client_id),
+                                None => HashMap::new(),
+                            };
+                            Ok(Pdu::GetPaneViewStatesResponse(GetPaneViewStatesResponse {
+                                states,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
             Pdu::GetClientList(GetClientList) => {
                 spawn_into_main_thread(async move {
                     catch(
@@ -318,20 +1208,1033 @@ impl SessionHandler {
                 })
                 .detach();
             }
-            Pdu::ListPanes(ListPanes {}) => {
+            Pdu::ListDomains(ListDomains) => {
+                let is_remote = self.is_remote;
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let mux = Mux::get().unwrap();
+                        let mut domains = mux.list_domains().await;
+                        if is_remote {
+                            domains = filter_remote_domains(domains);
+                        }
+                        send_response(Ok(Pdu::ListDomainsResponse(ListDomainsResponse {
+                            domains,
+                        })));
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+            Pdu::ListPanes(ListPanes {}) => {
+                let shared_tab = match self.share {
+                    Some(ShareScope::Tab { tab_id, .. }) => Some(tab_id),
+                    _ => None,
+                };
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let mut tabs = vec![];
+                            let mut active_tabs = vec![];
+                            let is_listed =
+                                |tab_id: TabId| shared_tab.map_or(true, |shared| shared == tab_id);
+                            for window_id in mux.iter_windows().into_iter() {
+                                let window = mux.get_window(window_id).unwrap();
+                                for tab in window.iter() {
+                                    if !is_listed(tab.tab_id()) {
+                                        continue;
+                                    }
+                                    tab.compute_title();
+                                    tabs.push(tab.codec_pane_tree());
+                                }
+                                if let Some(tab) = window.get_active() {
+                                    if is_listed(tab.tab_id()) {
+                                        active_tabs.push(tab.tab_id());
+                                    }
+                                }
+                            }
+                            if let Some(tab_id) = shared_tab {
+                                // The shared tab is active as far as
+                                // the viewer is concerned
+                                if !active_tabs.contains(&tab_id) && !tabs.is_empty() {
+                                    active_tabs.push(tab_id);
+                                }
+                            }
+                            log::trace!("ListPanes {:#?}", tabs);
+                            Ok(Pdu::ListPanesResponse(ListPanesResponse {
+                                tabs,
+                                active_tabs,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::WriteToPane(WriteToPane {
+                pane_id,
+                data,
+                from_cli,
+            }) => {
+                let client_id = self.client_id.clone();
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let record = AuditRecord::new("write")
+                                .client(client_id)
+                                .pane(pane_id)
+                                .input(&data, audit::record_input());
+                            audited(record, move || {
+                                let mux = Mux::get().unwrap();
+                                let pane = mux
+                                    .get_pane(pane_id)
+                                    .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                                let source = if from_cli {
+                                    InputSource::Cli
+                                } else {
+                                    InputSource::Keyboard
+                                };
+                                mux.send_input_to_pane(&pane, &data, source)?;
+                                maybe_push_pane_changes(&pane, sender, per_pane)?;
+                                Ok(Pdu::UnitResponse(UnitResponse {}))
+                            })
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::KillPane(KillPane { pane_id }) => {
+                let client_id = self.client_id.clone();
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let record = AuditRecord::new("kill").client(client_id).pane(pane_id);
+                            audited(record, move || {
+                                let mux = Mux::get().unwrap();
+                                let pane = mux
+                                    .get_pane(pane_id)
+                                    .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                                pane.kill();
+                                mux.remove_pane(pane_id);
+                                maybe_push_pane_changes(&pane, sender, per_pane)?;
+                                Ok(Pdu::UnitResponse(UnitResponse {}))
+                            })
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SetSerialParams(SetSerialParams { pane_id, params }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.set_serial_params(pane_id, &params)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::GetSemanticZones(GetSemanticZones { pane_id, range }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            let mut zones = pane.get_semantic_zones()?;
+                            if let Some(range) = range {
+                                zones.retain(|zone| {
+                                    zone.start_y < range.end && zone.end_y >= range.start
+                                });
+                            }
+                            Ok(Pdu::GetSemanticZonesResponse(GetSemanticZonesResponse {
+                                zones,
+                            }))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::GetSemanticZoneText(GetSemanticZoneText { pane_id, zone }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            let text = mux::pane::semantic_zone_text(&*pane, &zone);
+                            Ok(Pdu::GetSemanticZoneTextResponse(
+                                GetSemanticZoneTextResponse { text },
+                            ))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::NudgePane(NudgePane { pane_id }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.nudge_pane(pane_id)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::AdoptPty(AdoptPty {
+                pane_id,
+                pid,
+                reset_parser,
+            }) => {
+                let is_remote = self.is_remote;
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            if is_remote {
+                                return Err(DomainError::PermissionDenied {
+                                    reason: "a pty can only be adopted from the local host"
+                                        .to_string(),
+                                }
+                                .into());
+                            }
+                            adopt_pty(pane_id, pid, reset_parser)
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SetPaneOutputPaused(SetPaneOutputPaused { pane_id, paused }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.set_pane_paused(pane_id, paused)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SetPanePaletteOverrides(SetPanePaletteOverrides { pane_id, overrides }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            pane.set_palette_overrides(overrides)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::CancelAttach(CancelAttach { domain_name }) => {
+                let is_remote = self.is_remote;
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let domain = mux.get_domain_by_name(&domain_name).ok_or_else(|| {
+                                DomainError::DomainNotFound {
+                                    domain: domain_name.clone(),
+                                }
+                            })?;
+                            if is_remote {
+                                check_remote_spawn(&domain)?;
+                            }
+                            let cancelled = mux.cancel_attach(domain.domain_id());
+                            Ok(Pdu::CancelAttachResponse(CancelAttachResponse {
+                                cancelled,
+                            }))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SendPaste(SendPaste {
+                pane_id,
+                data,
+                from_cli,
+            }) => {
+                let client_id = self.client_id.clone();
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let record = AuditRecord::new("paste")
+                                .client(client_id)
+                                .pane(pane_id)
+                                .input(data.as_bytes(), audit::record_input());
+                            audited(record, move || {
+                                let mux = Mux::get().unwrap();
+                                let pane = mux
+                                    .get_pane(pane_id)
+                                    .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                                let source = if from_cli {
+                                    InputSource::CliPaste
+                                } else {
+                                    InputSource::Paste
+                                };
+                                mux.send_input_to_pane(&pane, data.as_bytes(), source)?;
+                                maybe_push_pane_changes(&pane, sender, per_pane)?;
+                                Ok(Pdu::UnitResponse(UnitResponse {}))
+                            })
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, pattern }) => {
+                use mux::pane::Pattern;
+
+                async fn do_search(pane_id: TabId, pattern: Pattern) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+
+                    pane.search(pattern).await.map(|results| {
+                        Pdu::SearchScrollbackResponse(SearchScrollbackResponse { results })
+                    })
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_search(pane_id, pattern).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::SearchPanes(SearchPanes { pattern, options }) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let mux = Mux::get().unwrap();
+                        let _identity = mux.with_identity(client_id);
+                        let result = mux
+                            .search_all_panes(pattern, &options, &SearchCancellation::default())
+                            .await
+                            .map(|results| {
+                                Pdu::SearchPanesResponse(SearchPanesResponse { results })
+                            });
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::ConfirmClose(ConfirmClose { scope, force }) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let mux = Mux::get().unwrap();
+                        let _identity = mux.with_identity(client_id.clone());
+                        let result = if force {
+                            mux.close_scope(scope).map(|()| CloseOutcome::Closed)
+                        } else {
+                            mux.request_close(scope).await
+                        };
+                        if !matches!(result, Ok(outcome) if outcome != CloseOutcome::Closed) {
+                            let record = match scope {
+                                CloseScope::Pane(pane_id) => AuditRecord::new("kill").pane(pane_id),
+                                CloseScope::Tab(tab_id) => AuditRecord::new("kill").tab(tab_id),
+                                CloseScope::Window(window_id) => {
+                                    AuditRecord::new("kill").window(window_id)
+                                }
+                            };
+                            audit::log(record.client(client_id).outcome(&result));
+                        }
+                        send_response(result.map(|outcome| {
+                            Pdu::ConfirmCloseResponse(ConfirmCloseResponse { outcome })
+                        }));
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::AnswerCloseConfirmation(AnswerCloseConfirmation { id, confirmed }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.answer_close_confirmation(id, confirmed);
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+
+            Pdu::ScanPane(ScanPane {
+                pane_id,
+                patterns,
+                hyperlink_rules,
+                range,
+            }) => {
+                async fn do_scan(
+                    pane_id: PaneId,
+                    patterns: Vec<String>,
+                    hyperlink_rules: Vec<mux::scan::HyperlinkRuleSpec>,
+                    range: std::ops::Range<StableRowIndex>,
+                ) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+
+                    let matches = if patterns.is_empty() {
+                        vec![]
+                    } else {
+                        pane.scan(patterns, range.clone()).await?
+                    };
+                    let hyperlinks = if hyperlink_rules.is_empty() {
+                        vec![]
+                    } else {
+                        let rules = mux::scan::compile_hyperlink_rules(&hyperlink_rules)?;
+                        pane.scan_hyperlinks(&rules, range).await?
+                    };
+                    Ok(Pdu::ScanPaneResponse(ScanPaneResponse {
+                        matches,
+                        hyperlinks,
+                    }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_scan(pane_id, patterns, hyperlink_rules, range).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::RenderScrollback(RenderScrollback {
+                pane_id,
+                format,
+                range,
+                header,
+            }) => {
+                async fn do_render(
+                    pane_id: PaneId,
+                    format: mux::scrollback::ScrollbackFormat,
+                    range: Option<std::ops::Range<StableRowIndex>>,
+                    header: bool,
+                ) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+
+                    let chunk = pane.render_scrollback(format, range, header).await?;
+                    Ok(Pdu::RenderScrollbackResponse(RenderScrollbackResponse {
+                        chunk,
+                    }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_render(pane_id, format, range, header).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::ChangeDir(ChangeDir {
+                pane_id,
+                dir,
+                enter,
+            }) => {
+                async fn do_change_dir(
+                    pane_id: PaneId,
+                    dir: String,
+                    enter: bool,
+                ) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                    pane.change_dir(dir, enter).await?;
+                    Ok(Pdu::UnitResponse(UnitResponse {}))
+                }
+
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let record = AuditRecord::new("change_dir")
+                            .client(client_id)
+                            .pane(pane_id)
+                            .input(dir.as_bytes(), audit::record_input());
+                        let result = do_change_dir(pane_id, dir, enter).await;
+                        audit::log(record.outcome(&result));
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::LinkPanes(LinkPanes { pane_ids, policy }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.link_panes(pane_ids, policy)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SetPaneZoomed(SetPaneZoomed {
+                containing_tab_id,
+                pane_id,
+                zoomed,
+            }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            let tab = mux
+                                .get_tab(containing_tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
+                            tab.set_active_pane(&pane);
+                            tab.set_zoomed(zoomed);
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::Resize(Resize {
+                containing_tab_id,
+                pane_id,
+                size,
+            }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            pane.resize(size)?;
+                            let tab = mux
+                                .get_tab(containing_tab_id)
+                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
+                            tab.rebuild_splits_sizes_from_contained_panes();
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SendKeyDown(SendKeyDown {
+                pane_id,
+                event,
+                input_serial,
+            }) => {
+                let client_id = self.client_id.clone();
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let record = AuditRecord::new("key").client(client_id).pane(pane_id);
+                            audited(record, move || {
+                                let mux = Mux::get().unwrap();
+                                let pane = mux
+                                    .get_pane(pane_id)
+                                    .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                                match mux.filter_key_down(&pane, &event.key, event.modifiers) {
+                                    FilteredKey::Unchanged => {
+                                        pane.key_down(event.key, event.modifiers)?
+                                    }
+                                    FilteredKey::Replaced(data) => {
+                                        pane.writer().write_all(&data)?
+                                    }
+                                    FilteredKey::Discarded => {}
+                                }
+
+                                // For a key press, we want to always send back the
+                                // cursor position so that the predictive echo doesn't
+                                // leave the cursor in the wrong place
+                                let mut per_pane = per_pane.lock().unwrap();
+                                if let Some(resp) = per_pane.compute_changes(
+                                    &pane,
+                                    Some(input_serial),
+                                    false,
+                                    false,
+                                ) {
+                                    sender.send(DecodedPdu {
+                                        pdu: Pdu::GetPaneRenderChangesResponse(resp),
+                                        serial: 0,
+                                    })?;
+                                }
+                                Ok(Pdu::UnitResponse(UnitResponse {}))
+                            })
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+            Pdu::SendMouseEvent(SendMouseEvent { pane_id, event }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            pane.mouse_event(event)?;
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SpawnV2(spawn) => {
+                let client_id = self.client_id.clone();
+                let is_remote = self.is_remote;
+                let origin = self.spawn_origin();
+                spawn_into_main_thread(async move {
+                    schedule_domain_spawn_v2(spawn, send_response, client_id, is_remote, origin);
+                })
+                .detach();
+            }
+
+            Pdu::SplitPane(split) => {
+                let client_id = self.client_id.clone();
+                let is_remote = self.is_remote;
+                let origin = self.spawn_origin();
+                spawn_into_main_thread(async move {
+                    schedule_split_pane(split, send_response, client_id, is_remote, origin);
+                })
+                .detach();
+            }
+
+            Pdu::EditScrollback(EditScrollback { pane_id, request }) => {
+                let client_id = self.client_id.clone();
+                let is_remote = self.is_remote;
+                let origin = self.spawn_origin();
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result =
+                            edit_scrollback(pane_id, request, client_id, is_remote, origin).await;
+                        record_spawn_result(&result);
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::NegotiateImageLimits(NegotiateImageLimits { limits }) => {
+                let limits = ImageLimits::from_config(&config::configuration()).intersect(&limits);
+                log::trace!("image limits for session: {:?}", limits);
+                self.image_limits.replace(limits);
+                send_response(Ok(Pdu::NegotiateImageLimitsResponse(
+                    NegotiateImageLimitsResponse { limits },
+                )));
+            }
+
+            Pdu::MarkSessionRemote(MarkSessionRemote {}) => {
+                // There is deliberately no way to revert this
+                log::trace!("session marked as remote");
+                self.is_remote = true;
+            }
+
+            Pdu::MarkSessionInteractive(MarkSessionInteractive {}) => {
+                log::trace!("session marked as interactive");
+                self.is_interactive = true;
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})));
+            }
+
+            Pdu::SetSpawnLimit(SetSpawnLimit { limit, value }) => {
+                let is_remote = self.is_remote;
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            if is_remote {
+                                return Err(DomainError::PermissionDenied {
+                                    reason: "the spawn limits can only be changed \
+                                             from the local host"
+                                        .to_string(),
+                                }
+                                .into());
+                            }
+                            let mux = Mux::get().unwrap();
+                            mux.set_spawn_limit(limit, value);
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::GetSpawnLimits(GetSpawnLimits {}) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            Ok(Pdu::GetSpawnLimitsResponse(GetSpawnLimitsResponse {
+                                limits: mux.spawn_limits(),
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::MovePaneToNewTab(request) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    schedule_move_pane(request, send_response, client_id);
+                })
+                .detach();
+            }
+
+            Pdu::CloneTab(request) => {
+                let client_id = self.client_id.clone();
+                let is_remote = self.is_remote;
+                let origin = self.spawn_origin();
+                spawn_into_main_thread(async move {
+                    schedule_clone_tab(request, send_response, client_id, is_remote, origin);
+                })
+                .detach();
+            }
+
+            Pdu::GetPaneRenderChanges(GetPaneRenderChanges { pane_id, .. }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let is_alive = match mux.get_pane(pane_id) {
+                                Some(pane) => {
+                                    maybe_push_pane_changes(&pane, sender, per_pane)?;
+                                    true
+                                }
+                                None => false,
+                            };
+                            Ok(Pdu::LivenessResponse(LivenessResponse {
+                                pane_id,
+                                is_alive,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SyncPanes(SyncPanes { pane_ids }) => {
+                let sender = self.to_write_tx.clone();
+                let per_panes: Vec<_> = pane_ids
+                    .into_iter()
+                    .map(|pane_id| (pane_id, self.per_pane(pane_id)))
+                    .collect();
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let mut missing = vec![];
+                            // The changes are pushed in the order that
+                            // the client asked for, ahead of the response
+                            for (pane_id, per_pane) in per_panes {
+                                match mux.get_pane(pane_id) {
+                                    Some(pane) => {
+                                        maybe_push_pane_changes(&pane, sender.clone(), per_pane)?
+                                    }
+                                    None => missing.push(pane_id),
+                                }
+                            }
+                            Ok(Pdu::SyncPanesResponse(SyncPanesResponse { missing }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::RequestPaneSnapshot(RequestPaneSnapshot { pane_id }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            log::info!("client requested a snapshot of pane {}", pane_id);
+                            per_pane.lock().unwrap().sent_snapshot = false;
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::VerifyPane(VerifyPane { pane_id }) => {
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            *VERIFY_REQUESTS.lock().unwrap().entry(pane_id).or_insert(0) += 1;
+                            let dims = pane.get_dimensions();
+                            let (_, lines) = pane.get_lines(
+                                dims.physical_top
+                                    ..dims.physical_top + dims.viewport_rows as StableRowIndex,
+                            );
+                            // Each session sends its client a checksum
+                            // along with its next update
+                            mux.notify(MuxNotification::PaneOutput(pane_id));
+                            Ok(Pdu::VerifyPaneResponse(VerifyPaneResponse {
+                                checksum: lines_checksum(&lines),
+                                seqno: pane.get_current_seqno(),
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::ListDirectory(ListDirectory {
+                domain,
+                path,
+                limit,
+            }) => {
+                let is_remote = self.is_remote;
+                async fn do_list(
+                    domain: SpawnTabDomain,
+                    path: String,
+                    limit: usize,
+                    is_remote: bool,
+                ) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let domain = mux.resolve_spawn_tab_domain(None, &domain)?;
+                    if is_remote {
+                        check_remote_spawn(&domain)?;
+                    }
+                    let listing = domain
+                        .list_directory(&path, limit.min(mux::dirlist::MAX_ENTRIES))
+                        .await?;
+                    Ok(Pdu::ListDirectoryResponse(ListDirectoryResponse {
+                        listing,
+                    }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_list(domain, path, limit, is_remote).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::ExecCommand(ExecCommand { domain, request }) => {
+                let is_remote = self.is_remote;
+                async fn do_exec(
+                    domain: Arc<dyn Domain>,
+                    request: ExecRequest,
+                    is_remote: bool,
+                ) -> anyhow::Result<Pdu> {
+                    // Running a command is as good as spawning one
+                    if is_remote {
+                        check_remote_spawn(&domain)?;
+                    }
+                    let output = domain.exec(request).await?;
+                    Ok(Pdu::ExecCommandResponse(ExecCommandResponse { output }))
+                }
+
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let mut record = AuditRecord::new("exec")
+                            .client(client_id)
+                            .input(request.args.join(" ").as_bytes(), audit::record_input());
+                        let mux = Mux::get().unwrap();
+                        let result = match mux.resolve_spawn_tab_domain(None, &domain) {
+                            Ok(domain) => {
+                                record = record.domain(domain.domain_name());
+                                do_exec(domain, request, is_remote).await
+                            }
+                            Err(err) => Err(err),
+                        };
+                        audit::log(record.outcome(&result));
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::AddBookmark(AddBookmark { pane_id, name, row }) => {
+                async fn do_add(
+                    pane_id: PaneId,
+                    name: String,
+                    row: Option<StableRowIndex>,
+                ) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                    let bookmark = pane.add_bookmark(name, row).await?;
+                    Ok(Pdu::AddBookmarkResponse(AddBookmarkResponse { bookmark }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_add(pane_id, name, row).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::GetBookmarks(GetBookmarks { pane_id }) => {
+                async fn do_get(pane_id: PaneId) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                    let bookmarks = pane.get_bookmarks().await?;
+                    Ok(Pdu::GetBookmarksResponse(GetBookmarksResponse {
+                        bookmarks,
+                    }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_get(pane_id).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::ScrollToBookmark(ScrollToBookmark { pane_id, name }) => {
+                async fn do_scroll(pane_id: PaneId, name: String) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux
+                        .get_pane(pane_id)
+                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                    let row = scroll_to_bookmark(&pane, &name).await?;
+                    Ok(Pdu::ScrollToBookmarkResponse(ScrollToBookmarkResponse {
+                        row,
+                    }))
+                }
+
+                spawn_into_main_thread(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_scroll(pane_id, name).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::AddOutputWatcher(AddOutputWatcher { pane_id, spec }) => {
+                let client_id = self.client_id.clone();
+                spawn_into_main_thread(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_pane(pane_id)
+                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+                            // The matches are sent to this client, rather
+                            // than emitting the event here
+                            let watcher_id = mux.add_output_watcher(pane_id, spec, client_id)?;
+                            Ok(Pdu::AddOutputWatcherResponse(AddOutputWatcherResponse {
+                                watcher_id,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::RemoveOutputWatcher(RemoveOutputWatcher {
+                pane_id,
+                watcher_id,
+            }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let mut tabs = vec![];
-                            for window_id in mux.iter_windows().into_iter() {
-                                let window = mux.get_window(window_id).unwrap();
-                                for tab in window.iter() {
-                                    tabs.push(tab.codec_pane_tree());
-                                }
+                            if !mux.remove_output_watcher(pane_id, watcher_id) {
+                                anyhow::bail!(
+                                    "pane {} has no output watcher {}",
+                                    pane_id,
+                                    watcher_id
+                                );
                             }
-                            log::trace!("ListPanes {:#?}", tabs);
-                            Ok(Pdu::ListPanesResponse(ListPanesResponse { tabs }))
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
                         send_response,
                     )
@@ -339,57 +2242,48 @@ impl SessionHandler {
                 .detach();
             }
 
-            Pdu::WriteToPane(WriteToPane { pane_id, data }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
+            Pdu::ListOutputWatchers(ListOutputWatchers { pane_id }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
+                            mux.get_pane(pane_id)
                                 .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.writer().write_all(&data)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let watchers = mux.output_watchers(pane_id);
+                            Ok(Pdu::ListOutputWatchersResponse(
+                                ListOutputWatchersResponse { watchers },
+                            ))
                         },
                         send_response,
-                    );
+                    )
                 })
                 .detach();
             }
-            Pdu::KillPane(KillPane { pane_id }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
+
+            Pdu::AddMarker(AddMarker { pane_id, marker }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
+                            mux.get_pane(pane_id)
                                 .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.kill();
-                            mux.remove_pane(pane_id);
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let marker = mux.record_marker(pane_id, marker)?;
+                            Ok(Pdu::AddMarkerResponse(AddMarkerResponse { marker }))
                         },
                         send_response,
-                    );
+                    )
                 })
                 .detach();
             }
-            Pdu::SendPaste(SendPaste { pane_id, data }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
+
+            Pdu::RemoveMarker(RemoveMarker { pane_id, id }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.send_paste(&data)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            if !mux.remove_recorded_marker(pane_id, &id) {
+                                anyhow::bail!("pane {} has no marker {}", pane_id, id);
+                            }
                             Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
                         send_response,
@@ -398,48 +2292,35 @@ impl SessionHandler {
                 .detach();
             }
 
-            Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, pattern }) => {
-                use mux::pane::Pattern;
-
-                async fn do_search(pane_id: TabId, pattern: Pattern) -> anyhow::Result<Pdu> {
-                    let mux = Mux::get().unwrap();
-                    let pane = mux
-                        .get_pane(pane_id)
-                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-
-                    pane.search(pattern).await.map(|results| {
-                        Pdu::SearchScrollbackResponse(SearchScrollbackResponse { results })
-                    })
-                }
-
+            Pdu::ShareTab(ShareTab { tab_id, expires }) => {
                 spawn_into_main_thread(async move {
-                    promise::spawn::spawn(async move {
-                        let result = do_search(pane_id, pattern).await;
-                        send_response(result);
-                    })
-                    .detach();
+                    catch(
+                        move || {
+                            let mut secret = [0u8; 32];
+                            openssl::rand::rand_bytes(&mut secret)
+                                .context("generating share token")?;
+                            let secret = secret.iter().map(|b| format!("{:02x}", b)).collect();
+                            let mux = Mux::get().unwrap();
+                            let (share, link) = mux.share_tab(tab_id, secret, expires)?;
+                            Ok(Pdu::ShareTabResponse(ShareTabResponse {
+                                share,
+                                link: link.to_string(),
+                            }))
+                        },
+                        send_response,
+                    )
                 })
                 .detach();
             }
 
-            Pdu::SetPaneZoomed(SetPaneZoomed {
-                containing_tab_id,
-                pane_id,
-                zoomed,
-            }) => {
+            Pdu::ListShares(ListShares {}) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            let tab = mux
-                                .get_tab(containing_tab_id)
-                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
-                            tab.set_active_pane(&pane);
-                            tab.set_zoomed(zoomed);
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            Ok(Pdu::ListSharesResponse(ListSharesResponse {
+                                shares: mux.shares(),
+                            }))
                         },
                         send_response,
                     )
@@ -447,24 +2328,14 @@ impl SessionHandler {
                 .detach();
             }
 
-            Pdu::Resize(Resize {
-                containing_tab_id,
-                pane_id,
-                size,
-            }) => {
+            Pdu::GetRecentDirs(GetRecentDirs { limit }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.resize(size)?;
-                            let tab = mux
-                                .get_tab(containing_tab_id)
-                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
-                            tab.rebuild_splits_sizes_from_contained_panes();
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            Ok(Pdu::GetRecentDirsResponse(GetRecentDirsResponse {
+                                dirs: mux.recent_dirs(limit),
+                            }))
                         },
                         send_response,
                     )
@@ -472,52 +2343,29 @@ impl SessionHandler {
                 .detach();
             }
 
-            Pdu::SendKeyDown(SendKeyDown {
+            Pdu::PreviewSplit(PreviewSplit {
                 pane_id,
-                event,
-                input_serial,
+                split_request,
             }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
                 spawn_into_main_thread(async move {
-                    catch(
-                        move || {
-                            let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.key_down(event.key, event.modifiers)?;
-
-                            // For a key press, we want to always send back the
-                            // cursor position so that the predictive echo doesn't
-                            // leave the cursor in the wrong place
-                            let mut per_pane = per_pane.lock().unwrap();
-                            if let Some(resp) = per_pane.compute_changes(&pane, Some(input_serial))
-                            {
-                                sender.send(DecodedPdu {
-                                    pdu: Pdu::GetPaneRenderChangesResponse(resp),
-                                    serial: 0,
-                                })?;
-                            }
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
-                        },
-                        send_response,
-                    )
+                    let mux = Mux::get().unwrap();
+                    let result = mux
+                        .preview_split(pane_id, split_request)
+                        .await
+                        .map(|preview| Pdu::PreviewSplitResponse(PreviewSplitResponse { preview }));
+                    send_response(result);
                 })
                 .detach();
             }
-            Pdu::SendMouseEvent(SendMouseEvent { pane_id, event }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
+
+            Pdu::RevokeShare(RevokeShare { id }) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.mouse_event(event)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            if !mux.revoke_share(id) {
+                                anyhow::bail!("there is no share {}", id);
+                            }
                             Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
                         send_response,
@@ -526,47 +2374,144 @@ impl SessionHandler {
                 .detach();
             }
 
-            Pdu::SpawnV2(spawn) => {
-                let client_id = self.client_id.clone();
-                spawn_into_main_thread(async move {
-                    schedule_domain_spawn_v2(spawn, send_response, client_id);
-                })
-                .detach();
+            Pdu::RedeemShare(RedeemShare { secret }) => {
+                // The scope of the session changes before any further
+                // requests are processed
+                let result = if self.share.is_none() {
+                    Err(DomainError::PermissionDenied {
+                        reason: "shares can only be redeemed by connecting to a TLS server \
+                                 that accepts them"
+                            .to_string(),
+                    }
+                    .into())
+                } else {
+                    let viewer = self
+                        .client_label
+                        .clone()
+                        .unwrap_or_else(|| "someone".to_string());
+                    Mux::get().unwrap().redeem_share(&secret, viewer)
+                };
+                match result {
+                    Ok(share) => {
+                        self.share.replace(ShareScope::Tab {
+                            share_id: share.id,
+                            tab_id: share.tab_id,
+                        });
+                        if let AuthState::Pending(_) = self.auth {
+                            self.auth = AuthState::Authenticated(AuthRole::ReadOnly);
+                        }
+                        send_response(Ok(Pdu::RedeemShareResponse(RedeemShareResponse {
+                            tab_id: share.tab_id,
+                        })))
+                    }
+                    Err(err) => send_response(Err(err)),
+                }
             }
 
-            Pdu::SplitPane(split) => {
-                let client_id = self.client_id.clone();
+            Pdu::Authenticate(Authenticate { token }) => {
+                // The state of the session changes before any further
+                // requests are processed
+                let auth = match &self.auth {
+                    AuthState::Pending(auth) => auth.clone(),
+                    AuthState::Authenticated(role) => {
+                        send_response(Ok(Pdu::AuthenticateResponse(AuthenticateResponse {
+                            role: *role,
+                        })));
+                        return;
+                    }
+                    AuthState::Failed => {
+                        send_response(Err(DomainError::PermissionDenied {
+                            reason: "the token was refused".to_string(),
+                        }
+                        .into()));
+                        return;
+                    }
+                    AuthState::NotRequired => {
+                        // A client that connects through a proxy or the
+                        // unix socket has nothing to authenticate
+                        send_response(Ok(Pdu::AuthenticateResponse(AuthenticateResponse {
+                            role: AuthRole::Full,
+                        })));
+                        return;
+                    }
+                };
+                let result = auth.authenticate(&token);
+                if audit::enabled() {
+                    let mut record = AuditRecord::new("authenticate")
+                        .client(self.client_id.clone())
+                        .outcome(&result.clone().map_err(|reason| anyhow!(reason)));
+                    if let Some(address) = &auth.peer.address {
+                        record = record.peer(address);
+                    }
+                    audit::log(record);
+                }
+                match result {
+                    Ok(role) => {
+                        log::info!(
+                            "client at {} authenticated with role {:?}",
+                            auth.peer.address.as_deref().unwrap_or("?"),
+                            role
+                        );
+                        self.auth = AuthState::Authenticated(role);
+                        if self.share == Some(ShareScope::Unredeemed) {
+                            // The token grants more than a share would
+                            self.share = None;
+                        }
+                        send_response(Ok(Pdu::AuthenticateResponse(AuthenticateResponse { role })));
+                    }
+                    Err(reason) => {
+                        log::warn!(
+                            "refused the token of the client at {}: {}",
+                            auth.peer.address.as_deref().unwrap_or("?"),
+                            reason
+                        );
+                        self.auth = AuthState::Failed;
+                        send_response(Err(DomainError::PermissionDenied { reason }.into()));
+                    }
+                }
+            }
+
+            Pdu::GetForegroundProcess(GetForegroundProcess { pane_id }) => {
                 spawn_into_main_thread(async move {
-                    schedule_split_pane(split, send_response, client_id);
+                    promise::spawn::spawn(async move {
+                        let mux = Mux::get().unwrap();
+                        let result = match mux.get_pane(pane_id) {
+                            Some(pane) => pane.query_foreground_process().await.map(|info| {
+                                Pdu::GetForegroundProcessResponse(GetForegroundProcessResponse {
+                                    info,
+                                })
+                            }),
+                            None => Err(anyhow!("no such pane {}", pane_id)),
+                        };
+                        send_response(result);
+                    })
+                    .detach();
                 })
                 .detach();
             }
 
-            Pdu::MovePaneToNewTab(request) => {
-                let client_id = self.client_id.clone();
+            Pdu::PickPanes(PickPanes { prompt, multi }) => {
                 spawn_into_main_thread(async move {
-                    schedule_move_pane(request, send_response, client_id);
+                    promise::spawn::spawn(async move {
+                        let mux = Mux::get().unwrap();
+                        let result = mux
+                            .pick_panes(prompt, multi)
+                            .await
+                            .map(|pane_ids| Pdu::PickPanesResponse(PickPanesResponse { pane_ids }));
+                        send_response(result);
+                    })
+                    .detach();
                 })
                 .detach();
             }
 
-            Pdu::GetPaneRenderChanges(GetPaneRenderChanges { pane_id, .. }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
+            Pdu::GetSpawnTimings(GetSpawnTimings {}) => {
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let is_alive = match mux.get_pane(pane_id) {
-                                Some(pane) => {
-                                    maybe_push_pane_changes(&pane, sender, per_pane)?;
-                                    true
-                                }
-                                None => false,
-                            };
-                            Ok(Pdu::LivenessResponse(LivenessResponse {
-                                pane_id,
-                                is_alive,
+                            Ok(Pdu::GetSpawnTimingsResponse(GetSpawnTimingsResponse {
+                                timings: mux.recent_spawn_timings(),
                             }))
                         },
                         send_response,
@@ -592,6 +2537,7 @@ impl SessionHandler {
                                     lines_and_indices.push((stable_row, line));
                                 }
                             }
+                            imagecache::remember_images(pane_id, &lines_and_indices);
                             Ok(Pdu::GetLinesResponse(GetLinesResponse {
                                 pane_id,
                                 lines: lines_and_indices.into(),
@@ -609,29 +2555,39 @@ impl SessionHandler {
                 cell_idx,
                 data_hash,
             }) => {
+                let limits = self
+                    .image_limits
+                    .unwrap_or_else(|| ImageLimits::from_config(&config::configuration()));
                 spawn_into_main_thread(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let mut data = None;
+                            let mut data = imagecache::lookup(pane_id, &data_hash);
 
                             let pane = mux
                                 .get_pane(pane_id)
                                 .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
 
-                            let (_, lines) = pane.get_lines(line_idx..line_idx + 1);
-                            'found_data: for line in lines {
-                                if let Some(cell) = line.cells().get(cell_idx) {
-                                    if let Some(images) = cell.attrs().images() {
-                                        for im in images {
-                                            if im.image_data().hash() == data_hash {
-                                                data.replace(im.image_data().clone());
-                                                break 'found_data;
+                            if data.is_none() {
+                                let (_, lines) = pane.get_lines(line_idx..line_idx + 1);
+                                'found_data: for line in lines {
+                                    if let Some(cell) = line.cells().get(cell_idx) {
+                                        if let Some(images) = cell.attrs().images() {
+                                            for im in images {
+                                                if im.image_data().hash() == data_hash {
+                                                    data.replace(im.image_data().clone());
+                                                    break 'found_data;
+                                                }
                                             }
                                         }
                                     }
                                 }
                             }
+
+                            // The client shouldn't ask for an image beyond the
+                            // limits, but it may have been unable to tell the
+                            // dimensions of an image that was still encoded
+                            let data = data.filter(|data| limits.allows_data(data));
                             Ok(Pdu::GetImageCellResponse(GetImageCellResponse {
                                 pane_id,
                                 data,
@@ -653,6 +2609,7 @@ impl SessionHandler {
                             executable_path,
                             config_file_path: std::env::var_os("WEZTERM_CONFIG_FILE")
                                 .map(Into::into),
+                            server_instance_id: mux::ids::instance_id().to_string(),
                         })))
                     }
                 }
@@ -686,11 +2643,54 @@ impl SessionHandler {
             | Pdu::GetLinesResponse { .. }
             | Pdu::GetCodecVersionResponse { .. }
             | Pdu::WindowWorkspaceChanged { .. }
+            | Pdu::WindowCreatedInWorkspace { .. }
             | Pdu::GetTlsCredsResponse { .. }
             | Pdu::GetClientListResponse { .. }
+            | Pdu::ListDomainsResponse { .. }
+            | Pdu::GetSemanticZonesResponse { .. }
+            | Pdu::GetSemanticZoneTextResponse { .. }
             | Pdu::PaneRemoved { .. }
             | Pdu::GetImageCellResponse { .. }
             | Pdu::MovePaneToNewTabResponse { .. }
+            | Pdu::PermissionDeniedResponse { .. }
+            | Pdu::DomainErrorResponse { .. }
+            | Pdu::PaneResourceUsageUpdate { .. }
+            | Pdu::PaneOutputPausedChanged { .. }
+            | Pdu::PanePaletteOverridesChanged { .. }
+            | Pdu::CancelAttachResponse { .. }
+            | Pdu::ScanPaneResponse { .. }
+            | Pdu::RenderScrollbackResponse { .. }
+            | Pdu::SyncPanesResponse { .. }
+            | Pdu::VerifyPaneResponse { .. }
+            | Pdu::ListDirectoryResponse { .. }
+            | Pdu::AddBookmarkResponse { .. }
+            | Pdu::GetBookmarksResponse { .. }
+            | Pdu::ScrollToBookmarkResponse { .. }
+            | Pdu::GetSpawnTimingsResponse { .. }
+            | Pdu::ExecCommandResponse { .. }
+            | Pdu::AddOutputWatcherResponse { .. }
+            | Pdu::ListOutputWatchersResponse { .. }
+            | Pdu::OutputWatcherMatched { .. }
+            | Pdu::PickPanesResponse { .. }
+            | Pdu::AddMarkerResponse { .. }
+            | Pdu::ShareTabResponse { .. }
+            | Pdu::ListSharesResponse { .. }
+            | Pdu::RedeemShareResponse { .. }
+            | Pdu::GetRecentDirsResponse { .. }
+            | Pdu::PreviewSplitResponse { .. }
+            | Pdu::GetSpawnLimitsResponse { .. }
+            | Pdu::AuthenticateResponse { .. }
+            | Pdu::GetForegroundProcessResponse { .. }
+            | Pdu::GetPaneViewStatesResponse { .. }
+            | Pdu::SearchPanesResponse { .. }
+            | Pdu::ConfirmCloseResponse { .. }
+            | Pdu::CloseConfirmationPending { .. }
+            | Pdu::CloseConfirmationResolved { .. }
+            | Pdu::PaneMarkersChanged { .. }
+            | Pdu::ScrollPaneToRow { .. }
+            | Pdu::PaneLinksChanged { .. }
+            | Pdu::AdoptPtyResponse { .. }
+            | Pdu::NegotiateImageLimitsResponse { .. }
             | Pdu::ErrorResponse { .. } => {
                 send_response(Err(anyhow!("expected a request, got {:?}", decoded.pdu)))
             }
@@ -698,6 +2698,31 @@ impl SessionHandler {
     }
 }
 
+#[cfg(unix)]
+fn adopt_pty(pane_id: PaneId, pid: Option<u32>, reset_parser: bool) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    mux.get_pane(pane_id)
+        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+    // Refuse early, rather than after the caller has handed over the pty
+    if mux::pane::paste_in_flight(pane_id) {
+        anyhow::bail!(
+            "a paste is still being sent to pane {}; try again once it has completed",
+            pane_id
+        );
+    }
+    let socket_path = mux::ptyhandoff::listen_for_pty(mux::ptyhandoff::AdoptPtyOptions {
+        pane_id,
+        pid,
+        reset_parser,
+    })?;
+    Ok(Pdu::AdoptPtyResponse(AdoptPtyResponse { socket_path }))
+}
+
+#[cfg(not(unix))]
+fn adopt_pty(_pane_id: PaneId, _pid: Option<u32>, _reset_parser: bool) -> anyhow::Result<Pdu> {
+    anyhow::bail!("adopting a pty is only supported on unix systems")
+}
+
 // Dancing around a little bit here; we can't directly spawn_into_main_thread the domain_spawn
 // function below because the compiler thinks that all of its locals then need to be Send.
 // We need to shimmy through this helper to break that aspect of the compiler flow
@@ -706,22 +2731,120 @@ fn schedule_domain_spawn_v2<SND>(
     spawn: SpawnV2,
     send_response: SND,
     client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
 ) where
     SND: Fn(anyhow::Result<Pdu>) + 'static,
 {
-    promise::spawn::spawn(async move { send_response(domain_spawn_v2(spawn, client_id).await) })
-        .detach();
+    promise::spawn::spawn(async move {
+        let domain = spawn.domain.clone();
+        let result = domain_spawn_v2(spawn, client_id.clone(), is_remote, origin).await;
+        record_spawn_result(&result);
+        audit_spawn("spawn", client_id, &domain, &result);
+        send_response(result)
+    })
+    .detach();
 }
 
-fn schedule_split_pane<SND>(split: SplitPane, send_response: SND, client_id: Option<Arc<ClientId>>)
-where
+fn schedule_split_pane<SND>(
+    split: SplitPane,
+    send_response: SND,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) where
     SND: Fn(anyhow::Result<Pdu>) + 'static,
 {
-    promise::spawn::spawn(async move { send_response(split_pane(split, client_id).await) })
-        .detach();
+    promise::spawn::spawn(async move {
+        let is_spawn = split.move_pane_id.is_none();
+        let domain = split.domain.clone();
+        let result = split_pane(split, client_id.clone(), is_remote, origin).await;
+        if is_spawn {
+            record_spawn_result(&result);
+        }
+        let operation = if is_spawn { "split" } else { "move-pane" };
+        audit_spawn(operation, client_id, &domain, &result);
+        send_response(result)
+    })
+    .detach();
+}
+
+async fn edit_scrollback(
+    pane_id: PaneId,
+    request: EditScrollbackRequest,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+    let pane = mux
+        .get_pane(pane_id)
+        .ok_or_else(|| anyhow!("pane_id {} invalid", pane_id))?;
+    let domain = editscrollback::editor_domain(&pane)?;
+    if is_remote {
+        check_remote_spawn(&domain)?;
+    }
+    mux.check_spawn_quota(&[domain.domain_id()], origin)?;
+
+    let editor_pane_id = pane.edit_scrollback(request).await?;
+    let editor = mux
+        .get_pane(editor_pane_id)
+        .ok_or_else(|| anyhow!("the pane of the editor went away"))?;
+    let (_domain_id, window_id, tab_id) = mux
+        .resolve_pane_id(editor_pane_id)
+        .ok_or_else(|| anyhow!("pane_id {} invalid", editor_pane_id))?;
+    Ok(Pdu::SpawnResponse(SpawnResponse {
+        pane_id: editor_pane_id,
+        tab_id,
+        window_id,
+        size: editor.get_size(),
+    }))
+}
+
+fn record_spawn_result(result: &anyhow::Result<Pdu>) {
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics::counter!("mux.spawn", 1, "result" => outcome);
+}
+
+/// Records a spawn, split or move in the audit log, along with the ids
+/// of the resulting pane
+fn audit_spawn(
+    operation: &'static str,
+    client_id: Option<Arc<ClientId>>,
+    domain: &SpawnTabDomain,
+    result: &anyhow::Result<Pdu>,
+) {
+    if !audit::enabled() {
+        return;
+    }
+    let mux = Mux::get().unwrap();
+    let mut record = AuditRecord::new(operation)
+        .client(client_id)
+        .outcome(result);
+    if let Ok(Pdu::SpawnResponse(resp)) = result {
+        record = record
+            .pane(resp.pane_id)
+            .tab(resp.tab_id)
+            .window(resp.window_id);
+        if let Some(dom) = mux
+            .get_pane(resp.pane_id)
+            .and_then(|pane| mux.get_domain(pane.domain_id()))
+        {
+            record = record.domain(dom.domain_name());
+        }
+    } else if let SpawnTabDomain::DomainName(name) = domain {
+        record = record.domain(name);
+    }
+    audit::log(record);
 }
 
-async fn split_pane(split: SplitPane, client_id: Option<Arc<ClientId>>) -> anyhow::Result<Pdu> {
+async fn split_pane(
+    split: SplitPane,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) -> anyhow::Result<Pdu> {
     let mux = Mux::get().unwrap();
     let _identity = mux.with_identity(client_id);
 
@@ -732,12 +2855,26 @@ async fn split_pane(split: SplitPane, client_id: Option<Arc<ClientId>>) -> anyho
     let source = if let Some(move_pane_id) = split.move_pane_id {
         SplitSource::MovePane(move_pane_id)
     } else {
+        let domain = mux.resolve_spawn_domain_with_rules(
+            Some(split.pane_id),
+            &split.domain,
+            split.command.as_ref(),
+            split.command_dir.as_ref(),
+            split.no_domain_rules,
+        )?;
+        if is_remote {
+            check_remote_spawn(&domain)?;
+        }
+        mux.check_spawn_quota(&[domain.domain_id()], origin)?;
         SplitSource::Spawn {
             command: split.command,
             command_dir: split.command_dir,
         }
     };
 
+    if split.no_domain_rules {
+        mux.skip_domain_rules_for_next_spawn();
+    }
     let (pane, size) = mux
         .split_pane(split.pane_id, split.split_request, source, split.domain)
         .await?;
@@ -750,13 +2887,50 @@ async fn split_pane(split: SplitPane, client_id: Option<Arc<ClientId>>) -> anyho
     }))
 }
 
-async fn domain_spawn_v2(spawn: SpawnV2, client_id: Option<Arc<ClientId>>) -> anyhow::Result<Pdu> {
+async fn domain_spawn_v2(
+    spawn: SpawnV2,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) -> anyhow::Result<Pdu> {
     let mux = Mux::get().unwrap();
     let _identity = mux.with_identity(client_id);
 
+    let domain = mux.resolve_spawn_domain_with_rules(
+        None,
+        &spawn.domain,
+        spawn.command.as_ref(),
+        spawn.command_dir.as_ref(),
+        spawn.no_domain_rules,
+    )?;
+    if is_remote {
+        check_remote_spawn(&domain)?;
+    }
+
+    let window_id = match &spawn.window_target {
+        Some(target) => mux.resolve_window_target(target)?,
+        None => spawn.window_id,
+    };
+
+    if let Some(reuse) = &spawn.reuse {
+        if let Some((tab, pane, window_id)) = mux.reuse_pane(window_id, &spawn.workspace, reuse)? {
+            return Ok(Pdu::SpawnResponse(SpawnResponse {
+                pane_id: pane.pane_id(),
+                tab_id: tab.tab_id(),
+                window_id,
+                size: tab.get_size(),
+            }));
+        }
+    }
+    // Reusing a pane doesn't spawn one, so only a new pane counts
+    mux.check_spawn_quota(&[domain.domain_id()], origin)?;
+
+    if spawn.no_domain_rules {
+        mux.skip_domain_rules_for_next_spawn();
+    }
     let (tab, pane, window_id) = mux
         .spawn_tab_or_window(
-            spawn.window_id,
+            window_id,
             spawn.domain,
             spawn.command,
             spawn.command_dir,
@@ -774,6 +2948,61 @@ async fn domain_spawn_v2(spawn: SpawnV2, client_id: Option<Arc<ClientId>>) -> an
     }))
 }
 
+fn schedule_clone_tab<SND>(
+    request: CloneTab,
+    send_response: SND,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) where
+    SND: Fn(anyhow::Result<Pdu>) + 'static,
+{
+    promise::spawn::spawn(async move {
+        let result = clone_tab(request, client_id, is_remote, origin).await;
+        record_spawn_result(&result);
+        send_response(result)
+    })
+    .detach();
+}
+
+async fn clone_tab(
+    request: CloneTab,
+    client_id: Option<Arc<ClientId>>,
+    is_remote: bool,
+    origin: SpawnOrigin,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+
+    let tab = mux
+        .get_tab(request.tab_id)
+        .ok_or_else(|| anyhow!("tab {} not found", request.tab_id))?;
+    // The clone spawns into the domain of each of the panes
+    let domain_ids: Vec<DomainId> = tab
+        .iter_panes_ignoring_zoom()
+        .into_iter()
+        .map(|pos| pos.pane.domain_id())
+        .collect();
+    if is_remote {
+        for &domain_id in &domain_ids {
+            let domain = mux
+                .get_domain(domain_id)
+                .ok_or_else(|| anyhow!("domain {} not found", domain_id))?;
+            check_remote_spawn(&domain)?;
+        }
+    }
+    mux.check_spawn_quota(&domain_ids, origin)?;
+
+    let (tab, pane, window_id) = mux.clone_tab(request.tab_id, request.same_commands).await?;
+
+    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
+        pane_id: pane.pane_id(),
+        tab_id: tab.tab_id(),
+        window_id,
+        size: tab.get_size(),
+    }))
+}
+
 fn schedule_move_pane<SND>(
     request: MovePaneToNewTab,
     send_response: SND,
@@ -805,3 +3034,92 @@ async fn move_pane(
         window_id,
     }))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push(queue: &OutputQueue) {
+        queue.push(DecodedPdu {
+            serial: 0,
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+        });
+    }
+
+    #[test]
+    fn slow_client_coalesces_deferred_updates() {
+        let queue = OutputQueue::new(2);
+
+        push(&queue);
+        assert!(!queue.is_full());
+        push(&queue);
+        assert!(queue.is_full());
+
+        // A slow client: further output for the same panes is
+        // deferred and coalesced rather than queued
+        queue.defer(1);
+        queue.defer(2);
+        queue.defer(1);
+        assert_eq!(queue.depth(), 2);
+
+        assert!(queue.pop().is_some());
+        let mut deferred = queue.written();
+        deferred.sort();
+        assert_eq!(deferred, vec![1, 2]);
+        assert_eq!(queue.depth(), 1);
+
+        // Nothing left to coalesce
+        assert!(queue.pop().is_some());
+        assert!(queue.written().is_empty());
+        assert_eq!(queue.depth(), 0);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn deferred_updates_wait_for_room() {
+        let queue = OutputQueue::new(1);
+        push(&queue);
+        push(&queue);
+        queue.defer(7);
+
+        // Still at the limit after the first write
+        queue.pop();
+        assert!(queue.written().is_empty());
+        queue.pop();
+        assert_eq!(queue.written(), vec![7]);
+    }
+
+    #[test]
+    fn unanswered_requests_hold_back_reading() {
+        let queue = Arc::new(OutputQueue::new(2));
+        assert!(queue.accepts_requests());
+
+        let first = queue.start_request();
+        let second = queue.start_request();
+        assert!(!queue.accepts_requests());
+
+        // Answering moves the request into the queue, which is still
+        // full until the response has been written
+        first.answer();
+        push(&queue);
+        assert!(!queue.accepts_requests());
+        queue.pop();
+        assert!(!queue.accepts_requests());
+
+        // A request that is dropped without an answer counts as done,
+        // and answering twice counts once
+        drop(second);
+        first.answer();
+        assert!(queue.accepts_requests());
+    }
+
+    #[test]
+    fn zero_limit_still_reads_requests() {
+        let queue = Arc::new(OutputQueue::new(0));
+        assert!(queue.accepts_requests());
+        let request = queue.start_request();
+        assert!(!queue.accepts_requests());
+        request.answer();
+        assert!(queue.accepts_requests());
+    }
+}
@@ -56,6 +56,12 @@ struct Opt {
     /// as if it were a login shell.
     #[clap(parse(from_os_str), value_hint=ValueHint::CommandWithArguments, multiple_values=true)]
     prog: Vec<OsString>,
+
+    /// Run as the supervisor of a resilient local pane, as described
+    /// by the registry entry at this path.  Used internally.
+    #[cfg(unix)]
+    #[clap(long, hide = true, parse(from_os_str))]
+    supervise_pane: Option<std::path::PathBuf>,
 }
 
 fn main() {
@@ -73,6 +79,15 @@ fn run() -> anyhow::Result<()> {
     let _saver = umask::UmaskSaver::new();
 
     let opts = Opt::parse();
+    #[cfg(unix)]
+    if let Some(entry_path) = &opts.supervise_pane {
+        // Errors are reported to the process that started us via stderr
+        if let Err(err) = mux::supervisor::run_supervisor(entry_path) {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     config::common_init(
         opts.config_file.as_ref(),
         &opts.config_override,
@@ -175,9 +190,19 @@ fn run() -> anyhow::Result<()> {
         None
     };
 
+    if config.mux_persist_ids {
+        mux::ids::enable_id_persistence(
+            config::RUNTIME_DIR.join("mux-ids"),
+            config.mux_randomize_id_epoch,
+        )?;
+    }
+
     let domain: Arc<dyn Domain> = Arc::new(LocalDomain::new("local")?);
     let mux = Rc::new(mux::Mux::new(Some(domain.clone())));
     Mux::set_mux(&mux);
+    mux.enable_recent_dir_persistence();
+    mux_lua::enable_pane_input_event();
+    mux_lua::enable_output_watcher_events();
 
     let executor = promise::spawn::SimpleExecutor::new();
 
@@ -222,10 +247,7 @@ async fn async_run(cmd: Option<CommandBuilder>) -> anyhow::Result<()> {
         }
     }
 
-    let have_panes_in_domain = mux
-        .iter_panes()
-        .iter()
-        .any(|p| p.domain_id() == domain.domain_id());
+    let have_panes_in_domain = !mux.iter_panes_for_domain(domain.domain_id()).is_empty();
 
     if !have_panes_in_domain {
         let window_id = mux.new_empty_window(None);
@@ -261,5 +283,11 @@ pub fn spawn_listener() -> anyhow::Result<()> {
         ossl::spawn_tls_listener(tls_server)?;
     }
 
+    wezterm_mux_server_impl::local::spawn_cli_listener();
+
+    if let Some(address) = &config.metrics_bind_address {
+        wezterm_mux_server_impl::metrics::spawn_metrics_listener(address)?;
+    }
+
     Ok(())
 }
@@ -1,24 +1,32 @@
 use anyhow::{anyhow, Context, Error};
 use async_ossl::AsyncSslStream;
 use config::TlsDomainServer;
+use mux::share::ShareListener;
+use mux::Mux;
+use openssl::hash::MessageDigest;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
 use openssl::x509::X509;
 use promise::spawn::spawn_into_main_thread;
 use std::net::TcpListener;
 use std::path::Path;
 use std::sync::Arc;
+use wezterm_mux_server_impl::auth::{PeerInfo, TokenAuth};
 use wezterm_mux_server_impl::PKI;
 
 struct OpenSSLNetListener {
     acceptor: Arc<SslAcceptor>,
     listener: TcpListener,
+    /// Decides whether peers without a certificate may connect, and
+    /// whether peers must authenticate with a token
+    tls_server: TlsDomainServer,
 }
 
 impl OpenSSLNetListener {
-    pub fn new(listener: TcpListener, acceptor: SslAcceptor) -> Self {
+    pub fn new(listener: TcpListener, acceptor: SslAcceptor, tls_server: TlsDomainServer) -> Self {
         Self {
             listener,
             acceptor: Arc::new(acceptor),
+            tls_server,
         }
     }
 
@@ -31,7 +39,8 @@ impl OpenSSLNetListener {
     ///   user running this mux server instance, or must match
     ///   a special encoded prefix set up by a proprietary PKI
     ///   infrastructure in an environment used by the author.
-    fn verify_peer_cert<T>(stream: &SslStream<T>) -> anyhow::Result<()> {
+    /// Returns the CN.
+    fn verify_peer_cert<T>(stream: &SslStream<T>) -> anyhow::Result<String> {
         let cert = stream
             .ssl()
             .peer_certificate()
@@ -51,7 +60,7 @@ impl OpenSSLNetListener {
                 cn_str,
                 wanted_unix_name
             );
-            Ok(())
+            Ok(cn_str)
         } else {
             // Some environments that are used by the author of this
             // program encode the CN in the form `user:unixname/DATA`
@@ -62,7 +71,7 @@ impl OpenSSLNetListener {
                     cn_str,
                     wanted_unix_name
                 );
-                Ok(())
+                Ok(cn_str)
             } else {
                 anyhow::bail!("CN `{}` did not match $USER `{}`", cn_str, wanted_unix_name);
             }
@@ -74,21 +83,63 @@ impl OpenSSLNetListener {
             match stream {
                 Ok(stream) => {
                     stream.set_nodelay(true).ok();
+                    let peer_address = stream.peer_addr().ok().map(|addr| addr.to_string());
                     let acceptor = self.acceptor.clone();
 
                     match acceptor.accept(stream) {
                         Ok(stream) => {
-                            if let Err(err) = Self::verify_peer_cert(&stream) {
-                                log::error!("problem with peer cert: {}", err);
-                                break;
-                            }
+                            // A peer without a certificate can only have
+                            // got this far if shares are accepted, or if
+                            // it can authenticate with a token instead
+                            let cert_cn = if stream.ssl().peer_certificate().is_some() {
+                                match Self::verify_peer_cert(&stream) {
+                                    Ok(cn) => Some(cn),
+                                    Err(err) => {
+                                        log::error!("problem with peer cert: {}", err);
+                                        break;
+                                    }
+                                }
+                            } else {
+                                None
+                            };
+                            let shared = self.tls_server.accept_shares && cert_cn.is_none();
+                            let needs_token = self.tls_server.require_auth_token
+                                && (cert_cn.is_some() || !self.tls_server.require_client_cert);
+                            let auth = if needs_token {
+                                Some(TokenAuth {
+                                    tokens: self.tls_server.auth_tokens.clone(),
+                                    peer: PeerInfo {
+                                        address: peer_address,
+                                        cert_cn,
+                                        server: self.tls_server.bind_address.clone(),
+                                    },
+                                })
+                            } else {
+                                None
+                            };
                             spawn_into_main_thread(async move {
                                 log::error!("Making new AsyncSslStream");
-                                wezterm_mux_server_impl::dispatch::process(AsyncSslStream::new(
-                                    stream,
-                                ))
-                                .await
-                                .map_err(|e| {
+                                let stream = AsyncSslStream::new(stream);
+                                let result = match auth {
+                                    Some(auth) => {
+                                        wezterm_mux_server_impl::dispatch::process_authenticated(
+                                            stream, auth, shared,
+                                        )
+                                        .await
+                                    }
+                                    None if shared => {
+                                        wezterm_mux_server_impl::dispatch::process_shared(stream)
+                                            .await
+                                    }
+                                    None => {
+                                        let is_remote = true;
+                                        wezterm_mux_server_impl::dispatch::process(
+                                            stream, is_remote,
+                                        )
+                                        .await
+                                    }
+                                };
+                                result.map_err(|e| {
                                     log::error!("process: {:?}", e);
                                     e
                                 })
@@ -110,6 +161,12 @@ impl OpenSSLNetListener {
 }
 
 pub fn spawn_tls_listener(tls_server: &TlsDomainServer) -> Result<(), Error> {
+    anyhow::ensure!(
+        tls_server.require_client_cert || tls_server.require_auth_token,
+        "the TLS server on {} sets require_client_cert = false, which requires \
+         require_auth_token = true so that clients still have to authenticate",
+        tls_server.bind_address
+    );
     openssl::init();
 
     let mut acceptor = SslAcceptor::mozilla_modern(SslMethod::tls())?;
@@ -166,7 +223,17 @@ pub fn spawn_tls_listener(tls_server: &TlsDomainServer) -> Result<(), Error> {
         .cert_store_mut()
         .add_cert(load_cert(&PKI.ca_pem())?)?;
 
-    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    if tls_server.accept_shares || !tls_server.require_client_cert {
+        // Certificates that are presented are still verified, but
+        // peers without one are let in to redeem a share token or
+        // to authenticate with a token
+        acceptor.set_verify(SslVerifyMode::PEER);
+    } else {
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    if tls_server.accept_shares {
+        register_share_listener(tls_server, &load_cert(&cert_file)?)?;
+    }
 
     let acceptor = acceptor.build();
 
@@ -180,9 +247,42 @@ pub fn spawn_tls_listener(tls_server: &TlsDomainServer) -> Result<(), Error> {
             )
         })?,
         acceptor,
+        tls_server.clone(),
     );
     std::thread::spawn(move || {
         net_listener.run();
     });
     Ok(())
 }
+
+/// Tells the mux where viewers can redeem shares of tabs, and the
+/// fingerprint of the certificate that they should expect
+fn register_share_listener(tls_server: &TlsDomainServer, cert: &X509) -> anyhow::Result<()> {
+    let fingerprint = cert
+        .digest(MessageDigest::sha256())?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let (host, port) = tls_server
+        .bind_address
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("expected bind_address to have the form host:port"))?;
+    // Viewers can't connect to a wildcard address
+    let host = match host {
+        "" | "0.0.0.0" | "[::]" => config::local_hostname()
+            .ok_or_else(|| anyhow!("cannot determine the hostname to put in share links"))?,
+        host => host,
+    };
+    let listener = ShareListener {
+        address: format!("{}:{}", host, port),
+        fingerprint,
+    };
+    spawn_into_main_thread(async move {
+        if let Some(mux) = Mux::get() {
+            mux.set_share_listener(listener);
+        }
+    })
+    .detach();
+    Ok(())
+}
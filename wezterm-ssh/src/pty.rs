@@ -85,9 +85,21 @@ pub struct SshChildProcess {
     pub(crate) tx: Option<SessionSender>,
     pub(crate) exit: Receiver<ExitStatus>,
     pub(crate) exited: Option<ExitStatus>,
+    /// Set if the exit status never arrived because the channel went
+    /// away without one
+    pub(crate) lost: bool,
 }
 
 impl SshChildProcess {
+    /// Returns true if waiting for the process ended because the
+    /// session that runs it has ended, for example because the
+    /// connection to the remote host was lost, rather than because
+    /// the process exited.  The process is then reported as having
+    /// exited with status 1.
+    pub fn lost_session(&self) -> bool {
+        self.lost && self.tx.as_ref().map(|tx| tx.tx.is_closed()).unwrap_or(true)
+    }
+
     pub async fn async_wait(&mut self) -> std::io::Result<ExitStatus> {
         if let Some(status) = self.exited.as_ref() {
             return Ok(status.clone());
@@ -100,6 +112,7 @@ impl SshChildProcess {
             Err(_) => {
                 let status = ExitStatus::with_exit_code(1);
                 self.exited.replace(status.clone());
+                self.lost = true;
                 Ok(status)
             }
         }
@@ -120,6 +133,7 @@ impl portable_pty::Child for SshChildProcess {
             Err(TryRecvError::Closed) => {
                 let status = ExitStatus::with_exit_code(1);
                 self.exited.replace(status.clone());
+                self.lost = true;
                 Ok(Some(status))
             }
         }
@@ -137,6 +151,7 @@ impl portable_pty::Child for SshChildProcess {
             Err(_) => {
                 let status = ExitStatus::with_exit_code(1);
                 self.exited.replace(status.clone());
+                self.lost = true;
                 Ok(status)
             }
         }
@@ -270,6 +285,7 @@ impl crate::sessioninner::SessionInner {
             tx: None,
             exit: exit_rx,
             exited: None,
+            lost: false,
         };
 
         let info = ChannelInfo {
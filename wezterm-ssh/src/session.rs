@@ -153,6 +153,13 @@ impl Session {
         Ok(exec)
     }
 
+    /// Returns true once the session has ended, for example because
+    /// the connection to the remote host was lost.  A session that has
+    /// ended cannot be used again; connect a new one instead.
+    pub fn is_closed(&self) -> bool {
+        self.tx.tx.is_closed()
+    }
+
     /// Creates a new reference to the sftp channel for filesystem operations
     ///
     /// ### Note
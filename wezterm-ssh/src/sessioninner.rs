@@ -792,6 +792,7 @@ impl SessionInner {
             tx: None,
             exit: exit_rx,
             exited: None,
+            lost: false,
         };
 
         let result = ExecResult {
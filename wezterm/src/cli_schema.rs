@@ -0,0 +1,187 @@
+//! The versioned JSON output of the `wezterm cli` subcommands that
+//! accept `--format json`.
+//! Each kind of item that is printed is a struct of its own, which
+//! declares the version of its schema and lists its fields, rather
+//! than serializing internal types, so that those can change without
+//! breaking scripts.  Every item carries a `schema_version` field.
+//! Adding a field doesn't change the version.  Removing, renaming or
+//! changing the type of a field bumps `SCHEMA_VERSION`, and the old
+//! shape remains available through `--schema-version` for at least a
+//! release, by raising `SCHEMA_VERSION` while leaving
+//! `MIN_SCHEMA_VERSION` alone and implementing `to_older_version`.
+use serde::{Serialize, Serializer};
+use std::io::Write;
+
+/// An item of the JSON output of a subcommand
+pub trait CliJsonItem: Serialize {
+    /// The subcommand that prints items of this type
+    const COMMAND: &'static str;
+    /// The version of the schema that the item is serialized as
+    const SCHEMA_VERSION: u32;
+    /// The oldest version that may still be requested
+    const MIN_SCHEMA_VERSION: u32 = Self::SCHEMA_VERSION;
+    /// The fields of the item and their types, as they are in
+    /// `SCHEMA_VERSION`.  The fields of nested objects are named with
+    /// a `.`, and those of the objects in an array with `[].`.
+    /// A type that ends with `?` may also be null.
+    const FIELDS: &'static [(&'static str, &'static str)];
+
+    /// Returns the item as it was serialized by `version`, which is
+    /// at least `MIN_SCHEMA_VERSION` and older than `SCHEMA_VERSION`
+    fn to_older_version(&self, version: u32) -> anyhow::Result<serde_json::Value> {
+        anyhow::bail!("{} has no schema version {}", Self::COMMAND, version)
+    }
+}
+
+/// An item along with the version of its schema
+#[derive(Serialize)]
+struct Versioned<T: Serialize> {
+    schema_version: u32,
+    #[serde(flatten)]
+    item: T,
+}
+
+/// Returns the version to print the items of `T` as, which is the
+/// current one unless an older one was `requested`
+pub fn resolve_version<T: CliJsonItem>(requested: Option<u32>) -> anyhow::Result<u32> {
+    match requested {
+        None => Ok(T::SCHEMA_VERSION),
+        Some(version) if (T::MIN_SCHEMA_VERSION..=T::SCHEMA_VERSION).contains(&version) => {
+            Ok(version)
+        }
+        Some(version) => anyhow::bail!(
+            "`wezterm cli {}` doesn't support schema version {}; \
+             it supports versions {} through {}",
+            T::COMMAND,
+            version,
+            T::MIN_SCHEMA_VERSION,
+            T::SCHEMA_VERSION
+        ),
+    }
+}
+
+/// Writes `items` to `out` as a pretty printed JSON array, in the
+/// `requested` version of their schema
+pub fn write_json<T: CliJsonItem>(
+    out: impl Write,
+    items: &[T],
+    requested: Option<u32>,
+) -> anyhow::Result<()> {
+    let schema_version = resolve_version::<T>(requested)?;
+    let mut writer = serde_json::Serializer::pretty(out);
+    if schema_version == T::SCHEMA_VERSION {
+        writer.collect_seq(items.iter().map(|item| Versioned {
+            schema_version,
+            item,
+        }))?;
+    } else {
+        let items = items
+            .iter()
+            .map(|item| {
+                Ok(Versioned {
+                    schema_version,
+                    item: item.to_older_version(schema_version)?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        writer.collect_seq(items)?;
+    }
+    Ok(())
+}
+
+/// The description of the output of a subcommand that is printed by
+/// `wezterm cli schema`
+#[derive(Serialize)]
+pub struct CliSchema {
+    command: &'static str,
+    schema_version: u32,
+    min_schema_version: u32,
+    fields: Vec<CliSchemaField>,
+}
+
+#[derive(Serialize)]
+struct CliSchemaField {
+    name: &'static str,
+    #[serde(rename = "type")]
+    field_type: &'static str,
+}
+
+impl CliSchema {
+    pub fn of<T: CliJsonItem>() -> Self {
+        Self {
+            command: T::COMMAND,
+            schema_version: T::SCHEMA_VERSION,
+            min_schema_version: T::MIN_SCHEMA_VERSION,
+            fields: std::iter::once(("schema_version", "integer"))
+                .chain(T::FIELDS.iter().copied())
+                .map(|(name, field_type)| CliSchemaField { name, field_type })
+                .collect(),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        self.command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Item {
+        name: &'static str,
+    }
+
+    impl CliJsonItem for Item {
+        const COMMAND: &'static str = "test";
+        const SCHEMA_VERSION: u32 = 2;
+        const MIN_SCHEMA_VERSION: u32 = 1;
+        const FIELDS: &'static [(&'static str, &'static str)] = &[("name", "string")];
+
+        fn to_older_version(&self, _version: u32) -> anyhow::Result<serde_json::Value> {
+            Ok(serde_json::json!({ "title": self.name }))
+        }
+    }
+
+    fn render(requested: Option<u32>) -> anyhow::Result<serde_json::Value> {
+        let mut out = vec![];
+        write_json(&mut out, &[Item { name: "a" }], requested)?;
+        Ok(serde_json::from_slice(&out)?)
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(
+            render(None).unwrap(),
+            serde_json::json!([{"schema_version": 2, "name": "a"}])
+        );
+        assert_eq!(
+            render(Some(1)).unwrap(),
+            serde_json::json!([{"schema_version": 1, "title": "a"}])
+        );
+        let err = render(Some(3)).unwrap_err();
+        assert!(
+            err.to_string().contains("versions 1 through 2"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn schema() {
+        let schema = serde_json::to_value(CliSchema::of::<Item>()).unwrap();
+        assert_eq!(
+            schema,
+            serde_json::json!({
+                "command": "test",
+                "schema_version": 2,
+                "min_schema_version": 1,
+                "fields": [
+                    {"name": "schema_version", "type": "integer"},
+                    {"name": "name", "type": "string"},
+                ],
+            })
+        );
+    }
+}
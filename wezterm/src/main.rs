@@ -2,25 +2,39 @@ use anyhow::{anyhow, Context};
 use chrono::{DateTime, Utc};
 use clap::{Parser, ValueHint};
 use clap_complete::{generate as generate_completion, Shell};
-use config::keyassignment::SpawnTabDomain;
-use config::wezterm_version;
+use cli_schema::{CliJsonItem, CliSchema};
+use config::keyassignment::{CommandDir, ReusePredicate, SpawnTabDomain, SpawnWindowTarget};
+use config::{wezterm_version, RgbaColor};
 use mux::activity::Activity;
+use mux::closeconfirm::{CloseOutcome, CloseScope};
+use mux::domain::DomainError;
+use mux::domainexec::ExecRequest;
+use mux::globalsearch::{GlobalSearchMatch, GlobalSearchOptions, DEFAULT_MAX_MATCHES_PER_PANE};
+use mux::history::RecentDir;
+use mux::marker::{Marker, MarkerStyle};
+use mux::outputwatch::WatcherId;
 use mux::pane::PaneId;
-use mux::tab::{SplitDirection, SplitRequest, SplitSize};
+use mux::share::{ShareId, ShareInfo, ShareState};
+use mux::spawnquota::{SpawnLimit, SpawnLimitInfo, SpawnLimitValue};
+use mux::tab::{SplitDirection, SplitRequest, SplitSize, TabId};
 use mux::window::WindowId;
 use mux::Mux;
 use portable_pty::cmdbuilder::CommandBuilder;
-use serde::Serializer as _;
+use serde::{Serialize as _, Serializer as _};
+use std::convert::TryFrom;
 use std::ffi::OsString;
 use std::io::{Read, Write};
 use std::rc::Rc;
+use std::time::Duration;
 use tabout::{tabulate_output, Alignment, Column};
+use termwiz::cell::Underline;
 use umask::UmaskSaver;
 use wezterm_client::client::{unix_connect_with_retry, Client};
 use wezterm_gui_subcommands::*;
-use wezterm_term::TerminalSize;
+use wezterm_term::{StableRowIndex, TerminalSize};
 
 mod asciicast;
+mod cli_schema;
 
 //    let message = "; ❤ 😍🤢\n\x1b[91;mw00t\n\x1b[37;104;m bleet\x1b[0;m.";
 
@@ -155,6 +169,12 @@ struct CliOutputFormat {
     /// "table" and "json" are possible formats.
     #[clap(long = "format", default_value = "table")]
     format: CliOutputFormatKind,
+
+    /// The version of the schema of the JSON output, which defaults
+    /// to the current one.  Older versions remain available for a
+    /// while after the schema changes; see `wezterm cli schema`.
+    #[clap(long = "schema-version")]
+    schema_version: Option<u32>,
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -165,6 +185,18 @@ enum CliSubCommand {
     #[clap(name = "list-clients", about = "list clients")]
     ListClients(CliOutputFormat),
 
+    #[clap(name = "list-domains", about = "list domains")]
+    ListDomains(CliOutputFormat),
+
+    /// Cancel the attach of a domain that is in progress, such as one
+    /// that is waiting for a server that doesn't respond.
+    /// The domain is left detached.
+    #[clap(name = "cancel-attach")]
+    CancelAttach {
+        /// The name of the domain
+        domain_name: String,
+    },
+
     #[clap(name = "proxy", about = "start rpc proxy pipe")]
     Proxy,
 
@@ -201,6 +233,58 @@ enum CliSubCommand {
         workspace: Option<String>,
     },
 
+    #[clap(
+        name = "clone-tab",
+        rename_all = "kebab",
+        about = "Create a new tab with the same split layout as a tab.
+Outputs the pane-id of the active pane of the new tab on success"
+    )]
+    CloneTab {
+        /// Specify the tab that should be cloned.
+        /// The default is to use the tab that contains the pane
+        /// specified by --pane-id.
+        #[clap(long)]
+        tab_id: Option<TabId>,
+
+        /// Specify the current pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long, conflicts_with = "tab-id")]
+        pane_id: Option<PaneId>,
+
+        /// Run the same command lines as the panes of the tab,
+        /// rather than a fresh shell, in the panes that are
+        /// running something other than their shell.
+        #[clap(long)]
+        same_commands: bool,
+    },
+
+    #[clap(
+        name = "kill-tab",
+        rename_all = "kebab",
+        about = "Kill a tab and all of its panes.
+If any of its panes are running something, the attached clients are
+asked to confirm it first; exits with a non-zero status if the tab
+was not killed"
+    )]
+    KillTab {
+        /// Specify the tab that should be killed.
+        /// The default is to use the tab that contains the pane
+        /// specified by --pane-id.
+        #[clap(long)]
+        tab_id: Option<TabId>,
+
+        /// Specify the current pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long, conflicts_with = "tab-id")]
+        pane_id: Option<PaneId>,
+
+        /// Kill the tab without asking for confirmation
+        #[clap(long)]
+        force: bool,
+    },
+
     #[clap(
         name = "split-pane",
         rename_all = "kebab",
@@ -252,7 +336,8 @@ Outputs the pane-id for the newly created pane on success"
         percent: Option<u8>,
 
         /// Specify the current working directory for the initially
-        /// spawned program
+        /// spawned program.  A `file://host/path` URL can be used
+        /// to name a directory on the host of the pane.
         #[clap(long, parse(from_os_str), value_hint=ValueHint::DirPath)]
         cwd: Option<OsString>,
 
@@ -261,6 +346,11 @@ Outputs the pane-id for the newly created pane on success"
         #[clap(long, conflicts_with_all=&["cwd", "prog"])]
         move_pane_id: Option<PaneId>,
 
+        /// Spawn in the domain of the pane even if the working
+        /// directory matches one of the `domain_rules`
+        #[clap(long)]
+        no_domain_rules: bool,
+
         /// Instead of executing your shell, run PROG.
         /// For example: `wezterm cli split-pane -- bash -l` will spawn bash
         /// as if it were a login shell.
@@ -296,8 +386,22 @@ Outputs the pane-id for the newly created pane on success"
         #[clap(long = "new-window", conflicts_with = "window-id")]
         new_window: bool,
 
+        /// When creating a new window, place it on the screen with
+        /// this name, or with this index when the screens are numbered
+        /// from 0, from left to right.
+        /// Only the wezterm GUI can resolve screens.
+        #[clap(long = "screen", requires = "new-window")]
+        screen: Option<String>,
+
+        /// Spawn into the window that the mouse pointer is over, or into
+        /// a new window if it isn't over any.
+        /// Only the wezterm GUI can resolve the mouse pointer.
+        #[clap(long = "under-mouse", conflicts_with_all=&["window-id", "new-window"])]
+        under_mouse: bool,
+
         /// Specify the current working directory for the initially
-        /// spawned program
+        /// spawned program.  A `file://host/path` URL can be used
+        /// to name a directory on the host of the pane.
         #[clap(long = "cwd", parse(from_os_str), value_hint=ValueHint::DirPath)]
         cwd: Option<OsString>,
 
@@ -306,6 +410,53 @@ Outputs the pane-id for the newly created pane on success"
         #[clap(long = "workspace")]
         workspace: Option<String>,
 
+        /// Rather than spawning, activate an existing pane that has
+        /// the user var KEY set to VALUE, and output its pane-id.
+        /// May be repeated to require several user vars.
+        /// If no pane matches, the command is spawned as usual.
+        #[clap(
+            long = "reuse",
+            value_name = "KEY=VALUE",
+            parse(try_from_str = name_equals_value),
+            number_of_values = 1)]
+        reuse: Vec<(String, String)>,
+
+        /// Rather than spawning, activate an existing pane whose
+        /// title matches this regex
+        #[clap(long = "reuse-title")]
+        reuse_title: Option<String>,
+
+        /// Rather than spawning, activate an existing pane whose
+        /// current working directory is this directory
+        #[clap(long = "reuse-cwd")]
+        reuse_cwd: Option<String>,
+
+        /// Rather than spawning, activate an existing pane that
+        /// belongs to the domain with this name
+        #[clap(long = "reuse-domain")]
+        reuse_domain: Option<String>,
+
+        /// Spawn the entry of the `launch_menu` that has this label,
+        /// rather than PROG
+        #[clap(long = "launch-menu", conflicts_with = "prog")]
+        launch_menu: Option<String>,
+
+        /// Supply the value of a parameter of the `--launch-menu`
+        /// entry.  May be repeated; each parameter that has no
+        /// default value must be supplied.
+        #[clap(
+            long = "param",
+            value_name = "NAME=VALUE",
+            requires = "launch-menu",
+            parse(try_from_str = name_equals_value),
+            number_of_values = 1)]
+        param: Vec<(String, String)>,
+
+        /// Spawn in the requested domain even if the working directory
+        /// matches one of the `domain_rules`
+        #[clap(long = "no-domain-rules")]
+        no_domain_rules: bool,
+
         /// Instead of executing your shell, run PROG.
         /// For example: `wezterm cli spawn -- bash -l` will spawn bash
         /// as if it were a login shell.
@@ -331,6 +482,479 @@ Outputs the pane-id for the newly created pane on success"
         /// The text to send. If omitted, will read the text from stdin.
         text: Option<String>,
     },
+
+    /// Print the output of the most recently completed command in a pane.
+    /// This requires that the shell marks up its prompts using the
+    /// OSC 133 semantic prompt escape sequences; see
+    /// <https://wezfurlong.org/wezterm/shell-integration.html>
+    #[clap(name = "get-last-command-output", rename_all = "kebab")]
+    GetLastCommandOutput {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+    },
+
+    /// Save the scrollback and screen of a pane, or some of its rows,
+    /// as text, as text with escape sequences, or as HTML.
+    /// The lines are rendered by the mux server a chunk at a time.
+    #[clap(name = "save-scrollback", rename_all = "kebab")]
+    SaveScrollback {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The format to save; text, escapes or html
+        #[clap(long, default_value = "text")]
+        format: mux::scrollback::ScrollbackFormat,
+
+        /// The first row to save, as a stable row index.
+        /// The default is the first row of the scrollback.
+        #[clap(long)]
+        first_row: Option<StableRowIndex>,
+
+        /// The last row to save, as a stable row index.
+        /// The default is the last row of the screen.
+        #[clap(long)]
+        last_row: Option<StableRowIndex>,
+
+        /// The file to write; it is replaced if it exists.
+        /// If omitted, the output is written to stdout.
+        #[clap(long, parse(from_os_str))]
+        out: Option<std::path::PathBuf>,
+    },
+
+    /// Open the scrollback of a pane in an editor, in a split of its tab,
+    /// and print the id of the pane of the editor.
+    /// The editor is $VISUAL, or else $EDITOR, as set for the mux server.
+    #[clap(name = "edit-scrollback", rename_all = "kebab")]
+    EditScrollback {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The most lines to edit, counting back from the bottom of the
+        /// screen.  The default is all of the scrollback.
+        #[clap(long)]
+        lines: Option<usize>,
+
+        /// Keep the escape sequences for the attributes of the text
+        #[clap(long)]
+        escapes: bool,
+    },
+
+    /// Restart the reader for a pane whose output has stopped updating
+    /// even though its program is still running.
+    /// This is normally done automatically by the mux when it detects
+    /// that the reader has stalled.
+    #[clap(name = "nudge-pane", rename_all = "kebab")]
+    NudgePane {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+    },
+
+    /// Have the clients of a pane compare their copy of its screen
+    /// against that of the mux server, and fetch a fresh snapshot if
+    /// it differs.  The outcome is logged by each client.
+    #[clap(name = "verify-pane", rename_all = "kebab")]
+    VerifyPane {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+    },
+
+    /// Change the working directory of the shell in a pane, by typing
+    /// a `cd` command into it.
+    /// This is refused, with the reason, unless the foreground process
+    /// of the pane is one of the shells in `change_dir_shells` and the
+    /// pane isn't showing the alternate screen.
+    #[clap(name = "change-dir", rename_all = "kebab")]
+    ChangeDir {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The directory.  It is interpreted by the shell, so a
+        /// relative path is relative to the current directory of
+        /// the shell, and the directory may be on a remote host.
+        #[clap(long)]
+        dir: String,
+
+        /// Type the command without pressing enter
+        #[clap(long)]
+        no_enter: bool,
+    },
+
+    /// Move a process that runs in a pty created elsewhere into a pane,
+    /// replacing the process that currently runs there.
+    /// The master end of the pty is passed either as a file descriptor
+    /// that this command inherited, or via a unix socket that sends it
+    /// with SCM_RIGHTS, as tools that detach processes from their
+    /// terminal can do.
+    /// The pane keeps its id, scrollback and position, but the
+    /// continuity of the scrollback is best-effort.
+    /// This is an expert-level command that is only available on unix.
+    #[clap(name = "adopt-pty", rename_all = "kebab")]
+    AdoptPty {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// A file descriptor of this process that is the master
+        /// end of the pty
+        #[clap(long, conflicts_with = "socket", required_unless_present = "socket")]
+        fd: Option<i32>,
+
+        /// The path to a unix socket that passes the master end
+        /// of the pty when it is connected to
+        #[clap(long, parse(from_os_str), value_hint=ValueHint::FilePath)]
+        socket: Option<std::path::PathBuf>,
+
+        /// The pid of the process to track in the pane.  The pane
+        /// closes when it exits.  The default is the foreground
+        /// process of the pty.
+        #[clap(long)]
+        pid: Option<u32>,
+
+        /// Don't reset the terminal modes before the output of the
+        /// process is shown
+        #[clap(long)]
+        no_reset: bool,
+    },
+
+    /// Change the settings of the serial port connected to a pane,
+    /// such as one opened via `wezterm serial`.
+    /// Settings that are not specified are left unchanged.
+    #[clap(name = "set-serial-params", rename_all = "kebab")]
+    SetSerialParams {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// Set the baud rate
+        #[clap(long)]
+        baud: Option<usize>,
+
+        /// Set the number of data bits; 5, 6, 7 or 8
+        #[clap(long)]
+        char_size: Option<u8>,
+
+        /// Set the parity; none, odd or even
+        #[clap(long)]
+        parity: Option<String>,
+
+        /// Set the number of stop bits; 1 or 2
+        #[clap(long)]
+        stop_bits: Option<u8>,
+
+        /// Set the flow control; none, software or hardware
+        #[clap(long)]
+        flow_control: Option<String>,
+    },
+
+    /// Link panes together, wherever they are, so that they are
+    /// focused or closed together.
+    /// Linking a pane with panes that are already linked with the
+    /// same policy adds it to their group.
+    #[clap(name = "link-panes", rename_all = "kebab")]
+    LinkPanes {
+        /// Specify the current pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// What the panes do together; focus-together or close-together
+        #[clap(long, default_value = "focus-together")]
+        policy: mux::panelink::LinkPolicy,
+
+        /// The panes to link with the current pane
+        #[clap(required = true)]
+        others: Vec<PaneId>,
+    },
+
+    /// Bookmark a row of the scrollback of a pane under a name, so
+    /// that the pane can later be scrolled back to it.
+    /// A bookmark with the same name is replaced.
+    #[clap(name = "add-bookmark", rename_all = "kebab")]
+    AddBookmark {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The row to bookmark, as a stable row index.
+        /// The default is the row of the cursor.
+        #[clap(long)]
+        row: Option<StableRowIndex>,
+
+        /// The name of the bookmark
+        name: String,
+    },
+
+    /// Highlight a range of rows of a pane, such as the output of a
+    /// failing test, and print the id of the marker.
+    /// The rows are underlined unless a style is given.
+    /// A marker with the same id is replaced.
+    #[clap(name = "add-marker", rename_all = "kebab")]
+    AddMarker {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The id of the marker.
+        /// The default is to assign one.
+        #[clap(long)]
+        id: Option<String>,
+
+        /// The first row to highlight, as a stable row index
+        #[clap(long)]
+        start_line: StableRowIndex,
+
+        /// The last row to highlight, as a stable row index.
+        /// The default is the first row.
+        #[clap(long)]
+        end_line: Option<StableRowIndex>,
+
+        /// How to underline the rows: none, single, double, curly,
+        /// dotted or dashed
+        #[clap(long, parse(try_from_str = parse_underline))]
+        underline: Option<Underline>,
+
+        /// The color of the text of the rows
+        #[clap(long, parse(try_from_str = parse_color))]
+        fg: Option<RgbaColor>,
+
+        /// The background color of the rows
+        #[clap(long, parse(try_from_str = parse_color))]
+        bg: Option<RgbaColor>,
+    },
+
+    /// Remove a marker that was added by `add-marker` or
+    /// `pane:add_marker` in lua
+    #[clap(name = "remove-marker", rename_all = "kebab")]
+    RemoveMarker {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// The id of the marker
+        id: String,
+    },
+
+    /// List the bookmarks of a pane, or scroll it to one of them.
+    /// The bookmarks whose row has been trimmed from the scrollback
+    /// are listed as such, and cannot be jumped to.
+    #[clap(name = "bookmarks", rename_all = "kebab")]
+    Bookmarks {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// List the bookmarks; this is the default
+        #[clap(long, conflicts_with = "jump")]
+        list: bool,
+
+        /// Scroll the pane to the named bookmark
+        #[clap(long)]
+        jump: Option<String>,
+    },
+
+    /// List the output watchers of a pane, which are added by
+    /// `pane:add_output_watcher` in lua, or remove one of them
+    #[clap(name = "output-watchers", rename_all = "kebab")]
+    OutputWatchers {
+        /// Specify the target pane.
+        /// The default is to use the current pane based on the
+        /// environment variable WEZTERM_PANE.
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// Remove the watcher with this id, rather than listing them
+        #[clap(long)]
+        remove: Option<WatcherId>,
+
+        #[clap(flatten)]
+        format: CliOutputFormat,
+    },
+
+    /// Ask the user to choose a pane in the wezterm GUI, and print
+    /// its id.  Exits with status 1 if the user cancelled.
+    #[clap(name = "pick-pane", rename_all = "kebab")]
+    PickPane {
+        /// The text to show along with the pane selector
+        #[clap(long)]
+        prompt: Option<String>,
+
+        /// Let the user choose several panes, printing their ids
+        /// one per line
+        #[clap(long)]
+        multi: bool,
+    },
+
+    /// Share a tab with someone on another host, who can watch it
+    /// but not type into it, and print the TOKEN@HOST:PORT link that
+    /// they pass to `wezterm connect --share`.
+    /// The link can be used once, before it expires.  The mux server
+    /// must have a TLS server with `accept_shares = true`.
+    #[clap(name = "share-tab", rename_all = "kebab")]
+    ShareTab {
+        /// The tab to share
+        #[clap(long)]
+        tab_id: TabId,
+
+        /// How long the link may be used for, such as 90s, 30m, 1h or 2d
+        #[clap(long, default_value = "1h", parse(try_from_str = parse_duration))]
+        expires: Duration,
+    },
+
+    /// List the shares of tabs that are waiting for their viewer,
+    /// or being viewed
+    #[clap(name = "list-shares", rename_all = "kebab")]
+    ListShares(CliOutputFormat),
+
+    /// Revoke a share made by `share-tab`, disconnecting its viewer
+    #[clap(name = "revoke-share", rename_all = "kebab")]
+    RevokeShare {
+        /// The id of the share, as listed by `list-shares`
+        id: ShareId,
+    },
+
+    /// List the working directories that panes have recently visited,
+    /// most recent first, along with the domain of each
+    #[clap(name = "recent-dirs", rename_all = "kebab")]
+    RecentDirs {
+        /// List at most this many directories
+        #[clap(long)]
+        limit: Option<usize>,
+
+        #[clap(flatten)]
+        format: CliOutputFormat,
+    },
+
+    /// Search the scrollback of every pane for a pattern, and list the
+    /// matches, pane by pane, the most recent first within each pane.
+    /// Only the most recent matches of each pane are kept.
+    #[clap(name = "search", rename_all = "kebab")]
+    Search {
+        /// The text to search for
+        #[clap(long)]
+        pattern: String,
+
+        /// Treat the pattern as a regular expression
+        #[clap(long)]
+        regex: bool,
+
+        /// Match the case of the pattern; the default is to ignore
+        /// case.  A regular expression always matches case, unless
+        /// it starts with `(?i)`.
+        #[clap(long)]
+        case_sensitive: bool,
+
+        /// Only search the panes of the domain with this name
+        #[clap(long)]
+        domain: Option<String>,
+
+        /// Only search the panes of the windows of this workspace
+        #[clap(long)]
+        workspace: Option<String>,
+
+        /// Only search the panes of this tab
+        #[clap(long)]
+        tab_id: Option<TabId>,
+
+        /// Only search this pane
+        #[clap(long)]
+        pane_id: Option<PaneId>,
+
+        /// Keep at most this many matches for each pane; the default
+        /// is 100, and at most 1000 are kept
+        #[clap(long)]
+        max_matches_per_pane: Option<usize>,
+
+        #[clap(flatten)]
+        format: CliOutputFormat,
+    },
+
+    /// Show how long the steps of the most recent spawns and splits
+    /// took, oldest first
+    #[clap(name = "last-spawn-timings", rename_all = "kebab")]
+    LastSpawnTimings(CliOutputFormat),
+
+    /// Run a short command in a domain without creating a pane, and
+    /// print its output.  The exit status of the command becomes that
+    /// of `wezterm cli`.  A command that is still running when the
+    /// timeout expires is killed.
+    #[clap(name = "exec", trailing_var_arg = true, rename_all = "kebab")]
+    Exec {
+        /// The name of the domain to run the command in.
+        /// The default is the default domain of the server.
+        #[clap(long)]
+        domain: Option<String>,
+
+        /// The directory to run the command in, as the domain sees it
+        #[clap(long)]
+        cwd: Option<String>,
+
+        /// How many seconds the command may run for, up to 60
+        #[clap(long, default_value = "10")]
+        timeout: f64,
+
+        /// The command to run, and its arguments
+        #[clap(required = true, multiple_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Change a limit on the panes that scripts may spawn through
+    /// `wezterm cli`, until the mux restarts.  The limits are
+    /// max-panes, max-panes-per-domain and max-spawns-per-second.
+    #[clap(name = "set-limit", rename_all = "kebab")]
+    SetLimit {
+        /// The limit to change
+        name: SpawnLimit,
+
+        /// The new value of the limit: a number, `none` to remove the
+        /// limit, or `default` to use the value from the configuration
+        value: SpawnLimitValue,
+    },
+
+    /// List the limits on the panes that scripts may spawn through
+    /// `wezterm cli`
+    #[clap(name = "list-limits", rename_all = "kebab")]
+    ListLimits(CliOutputFormat),
+
+    /// Describe the JSON output of the subcommands that accept
+    /// `--format json`: the current version of its schema, the oldest
+    /// version that may still be requested with `--schema-version`,
+    /// and its fields
+    #[clap(name = "schema")]
+    Schema {
+        /// Only describe the output of this subcommand
+        command: Option<String>,
+    },
 }
 
 use termwiz::escape::osc::{
@@ -426,15 +1050,22 @@ impl SetCwdCommand {
     }
 }
 
-fn canon_cwd(cwd: Option<OsString>) -> anyhow::Result<Option<String>> {
+/// Resolves the `--cwd` argument of the spawn and split-pane commands.
+/// A `file://host/path` URL names a directory on a specific host and
+/// is passed through as-is; anything else is a local path.
+fn canon_cwd(cwd: Option<OsString>) -> anyhow::Result<Option<CommandDir>> {
     match cwd {
         None => Ok(None),
-        Some(cwd) => Ok(Some(
-            std::fs::canonicalize(cwd)?
-                .to_str()
-                .ok_or_else(|| anyhow!("path is not representable as String"))?
-                .to_string(),
-        )),
+        Some(cwd) => {
+            if let Some(url) = cwd.to_str().filter(|s| s.starts_with("file://")) {
+                return Ok(Some(CommandDir::parse(url)));
+            }
+            Ok(Some(CommandDir::new(
+                std::fs::canonicalize(cwd)?
+                    .to_str()
+                    .ok_or_else(|| anyhow!("path is not representable as String"))?,
+            )))
+        }
     }
 }
 
@@ -542,6 +1173,44 @@ fn delegate_to_gui(saver: UmaskSaver) -> anyhow::Result<()> {
     }
 }
 
+fn parse_underline(arg: &str) -> Result<Underline, String> {
+    match arg {
+        "none" => Ok(Underline::None),
+        "single" => Ok(Underline::Single),
+        "double" => Ok(Underline::Double),
+        "curly" => Ok(Underline::Curly),
+        "dotted" => Ok(Underline::Dotted),
+        "dashed" => Ok(Underline::Dashed),
+        _ => Err(format!(
+            "unknown underline {}; expected none, single, double, curly, dotted or dashed",
+            arg
+        )),
+    }
+}
+
+fn parse_color(arg: &str) -> Result<RgbaColor, String> {
+    RgbaColor::try_from(arg.to_string()).map_err(|err| format!("{:#}", err))
+}
+
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {}; expected s, m, h or d", unit)),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(Duration::from_secs(number * secs)),
+        _ => Err(format!(
+            "expected a positive number followed by s, m, h or d, but have {}",
+            arg
+        )),
+    }
+}
+
 async fn resolve_pane_id(client: &Client, pane_id: Option<PaneId>) -> anyhow::Result<PaneId> {
     let pane_id: PaneId = match pane_id {
         Some(p) => p,
@@ -569,11 +1238,157 @@ async fn resolve_pane_id(client: &Client, pane_id: Option<PaneId>) -> anyhow::Re
     Ok(pane_id)
 }
 
-#[derive(serde::Serialize)]
-struct CliListResultPtySize {
-    rows: usize,
-    cols: usize,
+/// Returns `tab_id`, or else the tab that contains the pane that
+/// `resolve_pane_id` picks for `pane_id`
+async fn resolve_tab_id(
+    client: &Client,
+    tab_id: Option<TabId>,
+    pane_id: Option<PaneId>,
+) -> anyhow::Result<TabId> {
+    if let Some(tab_id) = tab_id {
+        return Ok(tab_id);
+    }
+    let pane_id = resolve_pane_id(client, pane_id).await?;
+    let panes = client.list_panes().await?;
+    for tabroot in panes.tabs {
+        let mut cursor = tabroot.into_tree().cursor();
+
+        loop {
+            if let Some(entry) = cursor.leaf_mut() {
+                if entry.pane_id == pane_id {
+                    return Ok(entry.tab_id);
+                }
+            }
+            match cursor.preorder_next() {
+                Ok(c) => cursor = c,
+                Err(_) => break,
+            }
+        }
+    }
+    Err(anyhow!("unable to resolve tab for pane {}", pane_id))
 }
+
+#[cfg(unix)]
+async fn adopt_pty(
+    client: &Client,
+    pane_id: PaneId,
+    fd: Option<i32>,
+    socket: Option<std::path::PathBuf>,
+    pid: Option<u32>,
+    reset_parser: bool,
+) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Obtain the pty before asking the mux to wait for it
+    let received = match &socket {
+        Some(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .with_context(|| format!("connecting to {}", path.display()))?;
+            Some(mux::ptyhandoff::recv_fd(&stream)?)
+        }
+        None => None,
+    };
+    let fd = match (&received, fd) {
+        (Some(received), _) => received.as_raw_fd(),
+        (None, Some(fd)) => fd,
+        (None, None) => anyhow::bail!("one of --fd or --socket is required"),
+    };
+
+    let response = client
+        .adopt_pty(codec::AdoptPty {
+            pane_id,
+            pid,
+            reset_parser,
+        })
+        .await?;
+    mux::ptyhandoff::hand_off_pty(&response.socket_path, fd)
+}
+
+#[cfg(not(unix))]
+async fn adopt_pty(
+    _client: &Client,
+    _pane_id: PaneId,
+    _fd: Option<i32>,
+    _socket: Option<std::path::PathBuf>,
+    _pid: Option<u32>,
+    _reset_parser: bool,
+) -> anyhow::Result<()> {
+    anyhow::bail!("adopt-pty is only supported on unix systems")
+}
+
+#[derive(serde::Serialize)]
+struct CliListResultPtySize {
+    rows: usize,
+    cols: usize,
+}
+
+#[derive(serde::Serialize)]
+struct CliResourceUsage {
+    rss_bytes: u64,
+    cpu_percent: f64,
+    num_processes: usize,
+}
+
+impl From<mux::resources::PaneResourceUsage> for CliResourceUsage {
+    fn from(usage: mux::resources::PaneResourceUsage) -> CliResourceUsage {
+        CliResourceUsage {
+            rss_bytes: usage.rss_bytes,
+            cpu_percent: usage.cpu_percent,
+            num_processes: usage.num_processes,
+        }
+    }
+}
+#[derive(serde::Serialize)]
+struct CliPaneModes {
+    application_cursor_keys: bool,
+    application_keypad: bool,
+    bracketed_paste: bool,
+    mouse_tracking: bool,
+    button_event_mouse: bool,
+    any_event_mouse: bool,
+    mouse_encoding: &'static str,
+    focus_tracking: bool,
+    alt_screen: bool,
+}
+
+impl From<wezterm_term::TerminalModes> for CliPaneModes {
+    fn from(modes: wezterm_term::TerminalModes) -> CliPaneModes {
+        CliPaneModes {
+            application_cursor_keys: modes.application_cursor_keys,
+            application_keypad: modes.application_keypad,
+            bracketed_paste: modes.bracketed_paste,
+            mouse_tracking: modes.mouse_tracking,
+            button_event_mouse: modes.button_event_mouse,
+            any_event_mouse: modes.any_event_mouse,
+            mouse_encoding: match modes.mouse_encoding {
+                wezterm_term::MouseEncoding::X10 => "X10",
+                wezterm_term::MouseEncoding::SGR => "SGR",
+                wezterm_term::MouseEncoding::SgrPixels => "SgrPixels",
+            },
+            focus_tracking: modes.focus_tracking,
+            alt_screen: modes.alt_screen,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CliPaneLink {
+    group_id: mux::panelink::LinkGroupId,
+    policy: &'static str,
+}
+
+impl From<mux::panelink::PaneLink> for CliPaneLink {
+    fn from(link: mux::panelink::PaneLink) -> CliPaneLink {
+        CliPaneLink {
+            group_id: link.group_id,
+            policy: match link.policy {
+                mux::panelink::LinkPolicy::FocusTogether => "FocusTogether",
+                mux::panelink::LinkPolicy::CloseTogether => "CloseTogether",
+            },
+        }
+    }
+}
+
 // This will be serialized to JSON via the 'List' command.
 // As such it is intended to be a stable output format,
 // Thus we need to be careful about both the fields and their types,
@@ -587,6 +1402,13 @@ struct CliListResultItem {
     size: CliListResultPtySize,
     title: String,
     cwd: String,
+    systemd_scope: Option<String>,
+    spawn_label: Option<String>,
+    resource_usage: Option<CliResourceUsage>,
+    modes: Option<CliPaneModes>,
+    links: Vec<CliPaneLink>,
+    layout_generation: usize,
+    tab_title: Option<String>,
 }
 
 impl From<mux::tab::PaneEntry> for CliListResultItem {
@@ -599,6 +1421,13 @@ impl From<mux::tab::PaneEntry> for CliListResultItem {
             title,
             working_dir,
             size: TerminalSize { rows, cols, .. },
+            systemd_scope,
+            spawn_label,
+            resource_usage,
+            modes,
+            links,
+            layout_generation,
+            tab_title,
             ..
         } = pane;
 
@@ -614,10 +1443,53 @@ impl From<mux::tab::PaneEntry> for CliListResultItem {
                 .map(|url| url.url.as_str())
                 .unwrap_or("")
                 .to_string(),
+            systemd_scope,
+            spawn_label,
+            resource_usage: resource_usage.map(CliResourceUsage::from),
+            modes: modes.map(CliPaneModes::from),
+            links: links.into_iter().map(CliPaneLink::from).collect(),
+            layout_generation,
+            tab_title,
         }
     }
 }
 
+impl CliJsonItem for CliListResultItem {
+    const COMMAND: &'static str = "list";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("window_id", "integer"),
+        ("tab_id", "integer"),
+        ("pane_id", "integer"),
+        ("workspace", "string"),
+        ("size.rows", "integer"),
+        ("size.cols", "integer"),
+        ("title", "string"),
+        ("cwd", "string"),
+        ("systemd_scope", "string?"),
+        ("spawn_label", "string?"),
+        ("resource_usage", "object?"),
+        ("resource_usage.rss_bytes", "integer"),
+        ("resource_usage.cpu_percent", "number"),
+        ("resource_usage.num_processes", "integer"),
+        ("modes", "object?"),
+        ("modes.application_cursor_keys", "boolean"),
+        ("modes.application_keypad", "boolean"),
+        ("modes.bracketed_paste", "boolean"),
+        ("modes.mouse_tracking", "boolean"),
+        ("modes.button_event_mouse", "boolean"),
+        ("modes.any_event_mouse", "boolean"),
+        ("modes.mouse_encoding", "string"),
+        ("modes.focus_tracking", "boolean"),
+        ("modes.alt_screen", "boolean"),
+        ("links", "array"),
+        ("links[].group_id", "integer"),
+        ("links[].policy", "string"),
+        ("layout_generation", "integer"),
+        ("tab_title", "string?"),
+    ];
+}
+
 // This will be serialized to JSON via the 'ListClients' command.
 // As such it is intended to be a stable output format,
 // Thus we need to be careful about the stability of the fields and types
@@ -631,10 +1503,12 @@ struct CliListClientsResultItem {
     idle_time: std::time::Duration,
     workspace: String,
     focused_pane_id: Option<mux::pane::PaneId>,
+    server_instance_id: String,
+    output_queue_depth: usize,
 }
 
-impl From<mux::client::ClientInfo> for CliListClientsResultItem {
-    fn from(client_info: mux::client::ClientInfo) -> CliListClientsResultItem {
+impl CliListClientsResultItem {
+    fn new(client_info: mux::client::ClientInfo, server_instance_id: &str) -> Self {
         let now: DateTime<Utc> = Utc::now();
 
         let mux::client::ClientInfo {
@@ -643,6 +1517,7 @@ impl From<mux::client::ClientInfo> for CliListClientsResultItem {
             active_workspace,
             focused_pane_id,
             client_id,
+            output_queue_depth,
             ..
         } = client_info;
 
@@ -666,11 +1541,373 @@ impl From<mux::client::ClientInfo> for CliListClientsResultItem {
             idle_time: idle_time.to_std().unwrap_or(std::time::Duration::ZERO),
             workspace: active_workspace.as_deref().unwrap_or("").to_string(),
             focused_pane_id: focused_pane_id,
+            server_instance_id: server_instance_id.to_string(),
+            output_queue_depth,
+        }
+    }
+}
+
+impl CliJsonItem for CliListClientsResultItem {
+    const COMMAND: &'static str = "list-clients";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("username", "string"),
+        ("hostname", "string"),
+        ("pid", "integer"),
+        ("connection_elapsed.secs", "integer"),
+        ("connection_elapsed.nanos", "integer"),
+        ("idle_time.secs", "integer"),
+        ("idle_time.nanos", "integer"),
+        ("workspace", "string"),
+        ("focused_pane_id", "integer?"),
+        ("server_instance_id", "string"),
+        ("output_queue_depth", "integer"),
+    ];
+}
+
+// This will be serialized to JSON via the 'ListDomains' command.
+// As such it is intended to be a stable output format,
+// Thus we need to be careful about the stability of the fields and types
+// herein as they are directly reflected in the output.
+#[derive(serde::Serialize)]
+struct CliListDomainsResultItem {
+    domain_id: mux::domain::DomainId,
+    name: String,
+    label: String,
+    state: String,
+    spawnable: bool,
+    hidden: bool,
+    num_windows: usize,
+    num_tabs: usize,
+    num_panes: usize,
+    resource_usage: Option<CliResourceUsage>,
+    kind: Option<String>,
+    shadowed_by: Option<mux::domain::DomainId>,
+    queued_spawns: usize,
+    pty_quirks: Option<String>,
+}
+
+impl From<mux::domain::DomainInfo> for CliListDomainsResultItem {
+    fn from(info: mux::domain::DomainInfo) -> CliListDomainsResultItem {
+        CliListDomainsResultItem {
+            domain_id: info.domain_id,
+            name: info.name,
+            label: info.label,
+            state: domain_state_string(info.state).to_string(),
+            spawnable: info.spawnable,
+            hidden: info.hidden,
+            num_windows: info.num_windows,
+            num_tabs: info.num_tabs,
+            num_panes: info.num_panes,
+            resource_usage: info.resource_usage.map(CliResourceUsage::from),
+            kind: info.kind.map(|kind| format!("{:?}", kind)),
+            shadowed_by: info.shadowed_by,
+            queued_spawns: info.queued_spawns,
+            pty_quirks: info.pty_quirks,
+        }
+    }
+}
+
+impl CliJsonItem for CliListDomainsResultItem {
+    const COMMAND: &'static str = "list-domains";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("domain_id", "integer"),
+        ("name", "string"),
+        ("label", "string"),
+        ("state", "string"),
+        ("spawnable", "boolean"),
+        ("hidden", "boolean"),
+        ("num_windows", "integer"),
+        ("num_tabs", "integer"),
+        ("num_panes", "integer"),
+        ("resource_usage", "object?"),
+        ("resource_usage.rss_bytes", "integer"),
+        ("resource_usage.cpu_percent", "number"),
+        ("resource_usage.num_processes", "integer"),
+        ("kind", "string?"),
+        ("shadowed_by", "integer?"),
+        ("queued_spawns", "integer"),
+        ("pty_quirks", "string?"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliSpawnSpan {
+    name: String,
+    start_ms: f64,
+    duration_ms: f64,
+}
+
+// This will be serialized to JSON via the 'LastSpawnTimings' command.
+// As such it is intended to be a stable output format, which is kept
+// apart from mux::spawntiming::SpawnTiming so that the latter is free
+// to change.
+#[derive(serde::Serialize)]
+struct CliSpawnTimingItem {
+    operation: String,
+    domain: String,
+    pane_id: Option<mux::pane::PaneId>,
+    error: Option<String>,
+    total_ms: f64,
+    spans: Vec<CliSpawnSpan>,
+}
+
+impl From<mux::spawntiming::SpawnTiming> for CliSpawnTimingItem {
+    fn from(timing: mux::spawntiming::SpawnTiming) -> CliSpawnTimingItem {
+        CliSpawnTimingItem {
+            operation: timing.operation,
+            domain: timing.domain,
+            pane_id: timing.pane_id,
+            error: timing.error,
+            total_ms: timing.total_ms,
+            spans: timing
+                .spans
+                .into_iter()
+                .map(|span| CliSpawnSpan {
+                    name: span.name,
+                    start_ms: span.start_ms,
+                    duration_ms: span.duration_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl CliJsonItem for CliSpawnTimingItem {
+    const COMMAND: &'static str = "last-spawn-timings";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("operation", "string"),
+        ("domain", "string"),
+        ("pane_id", "integer?"),
+        ("error", "string?"),
+        ("total_ms", "number"),
+        ("spans", "array"),
+        ("spans[].name", "string"),
+        ("spans[].start_ms", "number"),
+        ("spans[].duration_ms", "number"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliOutputWatcherItem {
+    id: WatcherId,
+    regex: String,
+    event: String,
+    once: bool,
+    response: Option<String>,
+    prompt: bool,
+    matches: usize,
+}
+
+impl From<mux::outputwatch::OutputWatcherInfo> for CliOutputWatcherItem {
+    fn from(info: mux::outputwatch::OutputWatcherInfo) -> CliOutputWatcherItem {
+        CliOutputWatcherItem {
+            id: info.id,
+            regex: info.spec.regex,
+            event: info.spec.event,
+            once: info.spec.once,
+            response: info.spec.response,
+            prompt: info.spec.prompt,
+            matches: info.matches,
+        }
+    }
+}
+
+impl CliJsonItem for CliOutputWatcherItem {
+    const COMMAND: &'static str = "output-watchers";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("id", "integer"),
+        ("regex", "string"),
+        ("event", "string"),
+        ("once", "boolean"),
+        ("response", "string?"),
+        ("prompt", "boolean"),
+        ("matches", "integer"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliShareItem {
+    id: ShareId,
+    tab_id: TabId,
+    created: String,
+    expires: String,
+    viewer: Option<String>,
+}
+
+impl From<ShareInfo> for CliShareItem {
+    fn from(share: ShareInfo) -> CliShareItem {
+        CliShareItem {
+            id: share.id,
+            tab_id: share.tab_id,
+            created: share.created.to_rfc3339(),
+            expires: share.expires.to_rfc3339(),
+            viewer: match share.state {
+                ShareState::Pending => None,
+                ShareState::Viewing { viewer } => Some(viewer),
+            },
+        }
+    }
+}
+
+impl CliJsonItem for CliShareItem {
+    const COMMAND: &'static str = "list-shares";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("id", "integer"),
+        ("tab_id", "integer"),
+        ("created", "string"),
+        ("expires", "string"),
+        ("viewer", "string?"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliRecentDirItem {
+    cwd: String,
+    domain: String,
+    last_visited: String,
+    visits: usize,
+}
+
+impl From<RecentDir> for CliRecentDirItem {
+    fn from(dir: RecentDir) -> CliRecentDirItem {
+        CliRecentDirItem {
+            cwd: dir.cwd,
+            domain: dir.domain,
+            last_visited: dir.last_visited.to_rfc3339(),
+            visits: dir.visits,
+        }
+    }
+}
+
+impl CliJsonItem for CliRecentDirItem {
+    const COMMAND: &'static str = "recent-dirs";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("cwd", "string"),
+        ("domain", "string"),
+        ("last_visited", "string"),
+        ("visits", "integer"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliSearchMatchItem {
+    pane_id: PaneId,
+    start_y: StableRowIndex,
+    start_x: usize,
+    end_y: StableRowIndex,
+    end_x: usize,
+    snippet: String,
+    /// Whether the pane had more matches than were kept
+    truncated: bool,
+}
+
+impl CliSearchMatchItem {
+    fn new(found: GlobalSearchMatch, truncated: &[PaneId]) -> CliSearchMatchItem {
+        CliSearchMatchItem {
+            pane_id: found.pane_id,
+            start_y: found.result.start_y,
+            start_x: found.result.start_x,
+            end_y: found.result.end_y,
+            end_x: found.result.end_x,
+            snippet: found.snippet,
+            truncated: truncated.contains(&found.pane_id),
+        }
+    }
+}
+
+impl CliJsonItem for CliSearchMatchItem {
+    const COMMAND: &'static str = "search";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("pane_id", "integer"),
+        ("start_y", "integer"),
+        ("start_x", "integer"),
+        ("end_y", "integer"),
+        ("end_x", "integer"),
+        ("snippet", "string"),
+        ("truncated", "boolean"),
+    ];
+}
+
+#[derive(serde::Serialize)]
+struct CliSpawnLimitItem {
+    name: String,
+    value: Option<usize>,
+    overridden: bool,
+}
+
+impl From<SpawnLimitInfo> for CliSpawnLimitItem {
+    fn from(info: SpawnLimitInfo) -> CliSpawnLimitItem {
+        CliSpawnLimitItem {
+            name: info.limit.name().to_string(),
+            value: info.value,
+            overridden: info.overridden,
+        }
+    }
+}
+
+impl CliJsonItem for CliSpawnLimitItem {
+    const COMMAND: &'static str = "list-limits";
+    const SCHEMA_VERSION: u32 = 1;
+    const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("name", "string"),
+        ("value", "integer?"),
+        ("overridden", "boolean"),
+    ];
+}
+
+/// The schemas of the JSON output of the subcommands
+fn cli_schemas() -> Vec<CliSchema> {
+    vec![
+        CliSchema::of::<CliListResultItem>(),
+        CliSchema::of::<CliListClientsResultItem>(),
+        CliSchema::of::<CliListDomainsResultItem>(),
+        CliSchema::of::<CliSpawnTimingItem>(),
+        CliSchema::of::<CliOutputWatcherItem>(),
+        CliSchema::of::<CliShareItem>(),
+        CliSchema::of::<CliRecentDirItem>(),
+        CliSchema::of::<CliSpawnLimitItem>(),
+        CliSchema::of::<CliSearchMatchItem>(),
+    ]
+}
+
+/// Prints the schema of the JSON output of `command`, or of all
+/// subcommands
+fn print_cli_schema(command: Option<&str>) -> anyhow::Result<()> {
+    let schemas = cli_schemas();
+    let out = std::io::stdout();
+    let mut writer = serde_json::Serializer::pretty(out.lock());
+    match command {
+        Some(command) => {
+            let schema = schemas
+                .iter()
+                .find(|schema| schema.command() == command)
+                .ok_or_else(|| anyhow!("`wezterm cli {}` has no JSON output", command))?;
+            schema.serialize(&mut writer)?;
         }
+        None => writer.collect_seq(&schemas)?,
+    }
+    Ok(())
+}
+
+fn domain_state_string(state: mux::domain::DomainState) -> &'static str {
+    match state {
+        mux::domain::DomainState::Attached => "attached",
+        mux::domain::DomainState::Detached => "detached",
     }
 }
 
 async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow::Result<()> {
+    if let CliSubCommand::Schema { command } = &cli.sub {
+        return print_cli_schema(command.as_deref());
+    }
+
     let mut ui = mux::connui::ConnectionUI::new_headless();
     let initial = true;
 
@@ -685,18 +1922,25 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
     )?;
 
     match cli.sub {
-        CliSubCommand::ListClients(CliOutputFormat { format }) => {
+        CliSubCommand::ListClients(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
             let out = std::io::stdout();
             let clients = client.list_clients(codec::GetClientList).await?;
+            let server_instance_id = client
+                .get_codec_version(codec::GetCodecVersion {})
+                .await?
+                .server_instance_id;
             match format {
                 CliOutputFormatKind::Json => {
                     let clients = clients
                         .clients
                         .iter()
                         .cloned()
-                        .map(CliListClientsResultItem::from);
-                    let mut writer = serde_json::Serializer::pretty(out.lock());
-                    writer.collect_seq(clients)?;
+                        .map(|info| CliListClientsResultItem::new(info, &server_instance_id))
+                        .collect::<Vec<_>>();
+                    cli_schema::write_json(out.lock(), &clients, schema_version)?;
                 }
                 CliOutputFormatKind::Table => {
                     let cols = vec![
@@ -728,6 +1972,14 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                             name: "FOCUS".to_string(),
                             alignment: Alignment::Right,
                         },
+                        Column {
+                            name: "QUEUE".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "INSTANCE".to_string(),
+                            alignment: Alignment::Left,
+                        },
                     ];
                     let mut data = vec![];
                     let now: DateTime<Utc> = Utc::now();
@@ -753,6 +2005,8 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                             info.focused_pane_id
                                 .map(|id| id.to_string())
                                 .unwrap_or_else(String::new),
+                            info.output_queue_depth.to_string(),
+                            server_instance_id.clone(),
                         ]);
                     }
 
@@ -760,7 +2014,121 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                 }
             }
         }
-        CliSubCommand::List(CliOutputFormat { format }) => {
+        CliSubCommand::ListDomains(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
+            let out = std::io::stdout();
+            let domains = client.list_domains(codec::ListDomains).await?.domains;
+            match format {
+                CliOutputFormatKind::Json => {
+                    let domains = domains
+                        .into_iter()
+                        .map(CliListDomainsResultItem::from)
+                        .collect::<Vec<_>>();
+                    cli_schema::write_json(out.lock(), &domains, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "DOMID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "NAME".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "KIND".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "STATE".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "SHADOWED_BY".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "SPAWNABLE".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "HIDDEN".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "WINDOWS".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "TABS".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "PANES".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "QUEUED".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "MEMORY".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "CPU".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "LABEL".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "PTY".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = domains
+                        .into_iter()
+                        .map(|info| {
+                            vec![
+                                info.domain_id.to_string(),
+                                info.name,
+                                info.kind
+                                    .map(|kind| format!("{:?}", kind))
+                                    .unwrap_or_else(|| "-".to_string()),
+                                domain_state_string(info.state).to_string(),
+                                info.shadowed_by
+                                    .map(|id| id.to_string())
+                                    .unwrap_or_else(|| "-".to_string()),
+                                info.spawnable.to_string(),
+                                info.hidden.to_string(),
+                                info.num_windows.to_string(),
+                                info.num_tabs.to_string(),
+                                info.num_panes.to_string(),
+                                info.queued_spawns.to_string(),
+                                info.resource_usage
+                                    .map(|usage| mux::resources::format_bytes(usage.rss_bytes))
+                                    .unwrap_or_else(|| "-".to_string()),
+                                info.resource_usage
+                                    .map(|usage| format!("{:.1}%", usage.cpu_percent))
+                                    .unwrap_or_else(|| "-".to_string()),
+                                info.label,
+                                info.pty_quirks.unwrap_or_else(|| "-".to_string()),
+                            ]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::List(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
             let out = std::io::stdout();
 
             let mut output_items = vec![];
@@ -781,8 +2149,7 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
             }
             match format {
                 CliOutputFormatKind::Json => {
-                    let mut writer = serde_json::Serializer::pretty(out.lock());
-                    writer.collect_seq(output_items.iter())?;
+                    cli_schema::write_json(out.lock(), &output_items, schema_version)?;
                 }
                 CliOutputFormatKind::Table => {
                     let cols = vec![
@@ -879,6 +2246,51 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
 
             log::debug!("{:?}", moved);
         }
+        CliSubCommand::CloneTab {
+            tab_id,
+            pane_id,
+            same_commands,
+        } => {
+            let tab_id = resolve_tab_id(&client, tab_id, pane_id).await?;
+
+            let spawned = client
+                .clone_tab(codec::CloneTab {
+                    tab_id,
+                    same_commands,
+                })
+                .await?;
+
+            log::debug!("{:?}", spawned);
+            println!("{}", spawned.pane_id);
+        }
+        CliSubCommand::KillTab {
+            tab_id,
+            pane_id,
+            force,
+        } => {
+            let tab_id = resolve_tab_id(&client, tab_id, pane_id).await?;
+            let outcome = client
+                .confirm_close(codec::ConfirmClose {
+                    scope: CloseScope::Tab(tab_id),
+                    force,
+                })
+                .await?
+                .outcome;
+            match outcome {
+                CloseOutcome::Closed => {}
+                CloseOutcome::Declined => {
+                    eprintln!("closing tab {} was declined", tab_id);
+                    std::process::exit(1);
+                }
+                CloseOutcome::TimedOut => {
+                    eprintln!("closing tab {} was not confirmed in time", tab_id);
+                    std::process::exit(1);
+                }
+                CloseOutcome::PanesGone => {
+                    eprintln!("the panes of tab {} closed on their own", tab_id);
+                }
+            }
+        }
         CliSubCommand::SplitPane {
             pane_id,
             cwd,
@@ -892,6 +2304,7 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
             cells,
             percent,
             move_pane_id,
+            no_domain_rules,
         } => {
             let pane_id = resolve_pane_id(&client, pane_id).await?;
 
@@ -929,6 +2342,7 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                     },
                     command_dir: canon_cwd(cwd)?,
                     move_pane_id,
+                    no_domain_rules,
                 })
                 .await?;
 
@@ -958,49 +2372,781 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                     .write_to_pane(codec::WriteToPane {
                         pane_id,
                         data: data.as_bytes().to_vec(),
+                        from_cli: true,
                     })
                     .await?;
             } else {
                 client
-                    .send_paste(codec::SendPaste { pane_id, data })
+                    .send_paste(codec::SendPaste {
+                        pane_id,
+                        data,
+                        from_cli: true,
+                    })
                     .await?;
             }
         }
-        CliSubCommand::SpawnCommand {
-            cwd,
-            prog,
+        CliSubCommand::GetLastCommandOutput { pane_id } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let zones = client
+                .get_semantic_zones(codec::GetSemanticZones {
+                    pane_id,
+                    range: None,
+                })
+                .await?
+                .zones;
+            if !zones
+                .iter()
+                .any(|zone| zone.semantic_type == wezterm_term::SemanticType::Prompt)
+            {
+                anyhow::bail!(
+                    "pane {} has no semantic prompt zones; \
+                     is shell integration configured for its shell?",
+                    pane_id
+                );
+            }
+            if let Some(zone) = mux::pane::last_command_output_zone(&zones) {
+                let text = client
+                    .get_semantic_zone_text(codec::GetSemanticZoneText { pane_id, zone })
+                    .await?
+                    .text;
+                println!("{}", text);
+            }
+        }
+        CliSubCommand::SaveScrollback {
             pane_id,
-            domain_name,
-            window_id,
-            new_window,
-            workspace,
+            format,
+            first_row,
+            last_row,
+            out,
         } => {
-            let window_id = if new_window {
-                None
-            } else {
-                match window_id {
-                    Some(w) => Some(w),
-                    None => {
-                        let pane_id = resolve_pane_id(&client, pane_id).await?;
-
-                        let panes = client.list_panes().await?;
-                        let mut window_id = None;
-                        'outer: for tabroot in panes.tabs {
-                            let mut cursor = tabroot.into_tree().cursor();
-
-                            loop {
-                                if let Some(entry) = cursor.leaf_mut() {
-                                    if entry.pane_id == pane_id {
-                                        window_id.replace(entry.window_id);
-                                        break 'outer;
-                                    }
-                                }
-                                match cursor.preorder_next() {
-                                    Ok(c) => cursor = c,
-                                    Err(_) => break,
-                                }
-                            }
-                        }
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let mut range = match (first_row, last_row) {
+                (None, None) => None,
+                (first, last) => Some(
+                    first.unwrap_or(StableRowIndex::MIN)
+                        ..last
+                            .map(|last| last.saturating_add(1))
+                            .unwrap_or(StableRowIndex::MAX),
+                ),
+            };
+            let mut out: Box<dyn std::io::Write> = match &out {
+                Some(path) => Box::new(std::io::BufWriter::new(
+                    std::fs::File::create(path)
+                        .with_context(|| format!("creating {}", path.display()))?,
+                )),
+                None => Box::new(std::io::stdout()),
+            };
+            let mut header = true;
+            loop {
+                let chunk = client
+                    .render_scrollback(codec::RenderScrollback {
+                        pane_id,
+                        format,
+                        range,
+                        header,
+                    })
+                    .await?
+                    .chunk;
+                out.write_all(chunk.text.as_bytes())?;
+                match chunk.remaining {
+                    Some(remaining) => {
+                        range = Some(remaining);
+                        header = false;
+                    }
+                    None => break,
+                }
+            }
+            out.flush()?;
+        }
+        CliSubCommand::EditScrollback {
+            pane_id,
+            lines,
+            escapes,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let spawned = client
+                .edit_scrollback(codec::EditScrollback {
+                    pane_id,
+                    request: mux::editscrollback::EditScrollbackRequest {
+                        lines,
+                        escapes,
+                        local: false,
+                    },
+                })
+                .await?;
+            println!("{}", spawned.pane_id);
+        }
+        CliSubCommand::CancelAttach { domain_name } => {
+            let response = client
+                .cancel_attach(codec::CancelAttach {
+                    domain_name: domain_name.clone(),
+                })
+                .await?;
+            if !response.cancelled {
+                eprintln!("domain {} is not being attached", domain_name);
+            }
+        }
+        CliSubCommand::LinkPanes {
+            pane_id,
+            policy,
+            others,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let mut pane_ids = vec![pane_id];
+            pane_ids.extend(others);
+            client
+                .link_panes(codec::LinkPanes { pane_ids, policy })
+                .await?;
+        }
+        CliSubCommand::AddBookmark { pane_id, row, name } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let bookmark = client
+                .add_bookmark(codec::AddBookmark { pane_id, name, row })
+                .await?
+                .bookmark;
+            println!("{}: row {}", bookmark.name, bookmark.row);
+        }
+        CliSubCommand::AddMarker {
+            pane_id,
+            id,
+            start_line,
+            end_line,
+            underline,
+            fg,
+            bg,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let marker = client
+                .add_marker(codec::AddMarker {
+                    pane_id,
+                    marker: Marker {
+                        id: id.unwrap_or_default(),
+                        start_line,
+                        end_line: end_line.unwrap_or(start_line),
+                        style: MarkerStyle { underline, fg, bg },
+                    },
+                })
+                .await?
+                .marker;
+            println!("{}", marker.id);
+        }
+        CliSubCommand::RemoveMarker { pane_id, id } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            client
+                .remove_marker(codec::RemoveMarker { pane_id, id })
+                .await?;
+        }
+        CliSubCommand::Bookmarks {
+            pane_id,
+            list: _,
+            jump,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            match jump {
+                Some(name) => {
+                    client
+                        .scroll_to_bookmark(codec::ScrollToBookmark { pane_id, name })
+                        .await?;
+                }
+                None => {
+                    let bookmarks = client
+                        .get_bookmarks(codec::GetBookmarks { pane_id })
+                        .await?
+                        .bookmarks;
+                    for bookmark in bookmarks {
+                        if bookmark.trimmed {
+                            println!("{}\t{}\t(content trimmed)", bookmark.name, bookmark.row);
+                        } else {
+                            println!("{}\t{}\t{}", bookmark.name, bookmark.row, bookmark.text);
+                        }
+                    }
+                }
+            }
+        }
+        CliSubCommand::OutputWatchers {
+            pane_id,
+            remove,
+            format:
+                CliOutputFormat {
+                    format,
+                    schema_version,
+                },
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            if let Some(watcher_id) = remove {
+                client
+                    .remove_output_watcher(codec::RemoveOutputWatcher {
+                        pane_id,
+                        watcher_id,
+                    })
+                    .await?;
+                return Ok(());
+            }
+            let watchers = client
+                .list_output_watchers(codec::ListOutputWatchers { pane_id })
+                .await?
+                .watchers
+                .into_iter()
+                .map(CliOutputWatcherItem::from)
+                .collect::<Vec<_>>();
+            let out = std::io::stdout();
+            match format {
+                CliOutputFormatKind::Json => {
+                    cli_schema::write_json(out.lock(), &watchers, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "ID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "EVENT".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "ONCE".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "PROMPT".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "MATCHES".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "RESPONSE".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "REGEX".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = watchers
+                        .iter()
+                        .map(|watcher| {
+                            vec![
+                                watcher.id.to_string(),
+                                watcher.event.clone(),
+                                watcher.once.to_string(),
+                                watcher.prompt.to_string(),
+                                watcher.matches.to_string(),
+                                watcher
+                                    .response
+                                    .as_ref()
+                                    .map(|response| format!("{:?}", response))
+                                    .unwrap_or_else(|| "-".to_string()),
+                                watcher.regex.clone(),
+                            ]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::Exec {
+            domain,
+            cwd,
+            timeout,
+            args,
+        } => {
+            anyhow::ensure!(
+                timeout.is_finite() && timeout > 0.0,
+                "--timeout must be a positive number of seconds"
+            );
+            let domain = match domain {
+                Some(name) => SpawnTabDomain::DomainName(name),
+                None => SpawnTabDomain::DefaultDomain,
+            };
+            let request =
+                ExecRequest::new(args, cwd, Some(std::time::Duration::from_secs_f64(timeout)));
+            let output = client
+                .exec_command(codec::ExecCommand { domain, request })
+                .await?
+                .output;
+            std::io::stdout().write_all(output.stdout.as_bytes())?;
+            std::io::stderr().write_all(output.stderr.as_bytes())?;
+            if output.truncated {
+                eprintln!("output was truncated");
+            }
+            if !output.success {
+                std::io::stdout().flush()?;
+                std::process::exit(output.exit_code.max(1) as i32);
+            }
+        }
+        CliSubCommand::SetLimit { name, value } => {
+            client
+                .set_spawn_limit(codec::SetSpawnLimit { limit: name, value })
+                .await?;
+        }
+        CliSubCommand::ListLimits(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
+            let limits = client
+                .get_spawn_limits(codec::GetSpawnLimits {})
+                .await?
+                .limits
+                .into_iter()
+                .map(CliSpawnLimitItem::from)
+                .collect::<Vec<_>>();
+            let out = std::io::stdout();
+            match format {
+                CliOutputFormatKind::Json => {
+                    cli_schema::write_json(out.lock(), &limits, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "NAME".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "VALUE".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "SOURCE".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = limits
+                        .iter()
+                        .map(|limit| {
+                            vec![
+                                limit.name.clone(),
+                                limit
+                                    .value
+                                    .map(|value| value.to_string())
+                                    .unwrap_or_else(|| "none".to_string()),
+                                if limit.overridden {
+                                    "set-limit".to_string()
+                                } else {
+                                    "config".to_string()
+                                },
+                            ]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::ShareTab { tab_id, expires } => {
+            let shared = client
+                .share_tab(codec::ShareTab { tab_id, expires })
+                .await?;
+            println!("{}", shared.link);
+        }
+        CliSubCommand::ListShares(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
+            let shares = client
+                .list_shares(codec::ListShares {})
+                .await?
+                .shares
+                .into_iter()
+                .map(CliShareItem::from)
+                .collect::<Vec<_>>();
+            let out = std::io::stdout();
+            match format {
+                CliOutputFormatKind::Json => {
+                    cli_schema::write_json(out.lock(), &shares, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "ID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "TABID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "EXPIRES".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "VIEWER".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = shares
+                        .iter()
+                        .map(|share| {
+                            vec![
+                                share.id.to_string(),
+                                share.tab_id.to_string(),
+                                share.expires.clone(),
+                                share.viewer.clone().unwrap_or_else(|| "-".to_string()),
+                            ]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::RecentDirs {
+            limit,
+            format:
+                CliOutputFormat {
+                    format,
+                    schema_version,
+                },
+        } => {
+            let dirs = client
+                .get_recent_dirs(codec::GetRecentDirs { limit })
+                .await?
+                .dirs
+                .into_iter()
+                .map(CliRecentDirItem::from)
+                .collect::<Vec<_>>();
+            let out = std::io::stdout();
+            match format {
+                CliOutputFormatKind::Json => {
+                    cli_schema::write_json(out.lock(), &dirs, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "DOMAIN".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "VISITS".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "CWD".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = dirs
+                        .iter()
+                        .map(|dir| {
+                            vec![dir.domain.clone(), dir.visits.to_string(), dir.cwd.clone()]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::Search {
+            pattern,
+            regex,
+            case_sensitive,
+            domain,
+            workspace,
+            tab_id,
+            pane_id,
+            max_matches_per_pane,
+            format:
+                CliOutputFormat {
+                    format,
+                    schema_version,
+                },
+        } => {
+            let pattern = if regex {
+                mux::pane::Pattern::Regex(pattern)
+            } else if case_sensitive {
+                mux::pane::Pattern::CaseSensitiveString(pattern)
+            } else {
+                mux::pane::Pattern::CaseInSensitiveString(pattern)
+            };
+            let results = client
+                .search_panes(codec::SearchPanes {
+                    pattern,
+                    options: GlobalSearchOptions {
+                        domain,
+                        workspace,
+                        tab_id,
+                        pane_id,
+                        max_matches_per_pane: max_matches_per_pane
+                            .unwrap_or(DEFAULT_MAX_MATCHES_PER_PANE),
+                    },
+                })
+                .await?
+                .results;
+            let truncated = results.truncated;
+            let matches = results
+                .matches
+                .into_iter()
+                .map(|found| CliSearchMatchItem::new(found, &truncated))
+                .collect::<Vec<_>>();
+            let out = std::io::stdout();
+            match format {
+                CliOutputFormatKind::Json => {
+                    cli_schema::write_json(out.lock(), &matches, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "PANEID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "ROW".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "COL".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "TEXT".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                    ];
+                    let data = matches
+                        .iter()
+                        .map(|found| {
+                            vec![
+                                found.pane_id.to_string(),
+                                found.start_y.to_string(),
+                                found.start_x.to_string(),
+                                found.snippet.clone(),
+                            ]
+                        })
+                        .collect::<Vec<_>>();
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                    for pane_id in &truncated {
+                        eprintln!("only the most recent matches of pane {} are shown", pane_id);
+                    }
+                }
+            }
+        }
+        CliSubCommand::RevokeShare { id } => {
+            client.revoke_share(codec::RevokeShare { id }).await?;
+        }
+        CliSubCommand::PickPane { prompt, multi } => {
+            let pane_ids = client
+                .pick_panes(codec::PickPanes { prompt, multi })
+                .await?
+                .pane_ids;
+            match pane_ids {
+                Some(pane_ids) => {
+                    for pane_id in pane_ids {
+                        println!("{}", pane_id);
+                    }
+                }
+                None => std::process::exit(1),
+            }
+        }
+        CliSubCommand::LastSpawnTimings(CliOutputFormat {
+            format,
+            schema_version,
+        }) => {
+            let out = std::io::stdout();
+            let timings = client
+                .get_spawn_timings(codec::GetSpawnTimings {})
+                .await?
+                .timings;
+            match format {
+                CliOutputFormatKind::Json => {
+                    let timings = timings
+                        .into_iter()
+                        .map(CliSpawnTimingItem::from)
+                        .collect::<Vec<_>>();
+                    cli_schema::write_json(out.lock(), &timings, schema_version)?;
+                }
+                CliOutputFormatKind::Table => {
+                    let cols = vec![
+                        Column {
+                            name: "OPERATION".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "DOMAIN".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "PANEID".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "STEP".to_string(),
+                            alignment: Alignment::Left,
+                        },
+                        Column {
+                            name: "START".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                        Column {
+                            name: "DURATION".to_string(),
+                            alignment: Alignment::Right,
+                        },
+                    ];
+                    let mut data = vec![];
+                    for timing in timings {
+                        let pane_id = match (timing.pane_id, &timing.error) {
+                            (Some(pane_id), _) => pane_id.to_string(),
+                            (None, Some(err)) => format!("error: {}", err),
+                            (None, None) => String::new(),
+                        };
+                        data.push(vec![
+                            timing.operation.clone(),
+                            timing.domain.clone(),
+                            pane_id,
+                            "total".to_string(),
+                            String::new(),
+                            format!("{:.1}ms", timing.total_ms),
+                        ]);
+                        for span in &timing.spans {
+                            data.push(vec![
+                                String::new(),
+                                String::new(),
+                                String::new(),
+                                span.name.clone(),
+                                format!("{:.1}ms", span.start_ms),
+                                format!("{:.1}ms", span.duration_ms),
+                            ]);
+                        }
+                    }
+                    tabulate_output(&cols, &data, &mut out.lock())?;
+                }
+            }
+        }
+        CliSubCommand::NudgePane { pane_id } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            client.nudge_pane(codec::NudgePane { pane_id }).await?;
+        }
+        CliSubCommand::VerifyPane { pane_id } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            let response = client.verify_pane(codec::VerifyPane { pane_id }).await?;
+            println!(
+                "pane {}: checksum {:016x} at seqno {}",
+                pane_id, response.checksum, response.seqno
+            );
+        }
+        CliSubCommand::ChangeDir {
+            pane_id,
+            dir,
+            no_enter,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            client
+                .change_dir(codec::ChangeDir {
+                    pane_id,
+                    dir,
+                    enter: !no_enter,
+                })
+                .await?;
+        }
+        CliSubCommand::AdoptPty {
+            pane_id,
+            fd,
+            socket,
+            pid,
+            no_reset,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            adopt_pty(&client, pane_id, fd, socket, pid, !no_reset).await?;
+        }
+        CliSubCommand::SetSerialParams {
+            pane_id,
+            baud,
+            char_size,
+            parity,
+            stop_bits,
+            flow_control,
+        } => {
+            let pane_id = resolve_pane_id(&client, pane_id).await?;
+            client
+                .set_serial_params(codec::SetSerialParams {
+                    pane_id,
+                    params: portable_pty::serial::SerialParams {
+                        baud,
+                        char_size,
+                        parity,
+                        stop_bits,
+                        flow_control,
+                    },
+                })
+                .await?;
+        }
+        CliSubCommand::SpawnCommand {
+            cwd,
+            prog,
+            pane_id,
+            domain_name,
+            window_id,
+            new_window,
+            screen,
+            under_mouse,
+            workspace,
+            reuse,
+            reuse_title,
+            reuse_cwd,
+            reuse_domain,
+            launch_menu,
+            param,
+            no_domain_rules,
+        } => {
+            let entry = match launch_menu {
+                Some(label) => Some(
+                    config
+                        .launch_menu
+                        .iter()
+                        .find(|entry| entry.label.as_deref() == Some(label.as_str()))
+                        .ok_or_else(|| anyhow!("no launch_menu entry is labelled `{}`", label))?
+                        .with_params(&param.into_iter().collect())
+                        .with_context(|| format!("launch_menu entry `{}`", label))?,
+                ),
+                None => None,
+            };
+
+            let reuse = if reuse.is_empty()
+                && reuse_title.is_none()
+                && reuse_cwd.is_none()
+                && reuse_domain.is_none()
+            {
+                None
+            } else {
+                Some(ReusePredicate {
+                    user_vars: reuse.into_iter().collect(),
+                    title: reuse_title,
+                    cwd: reuse_cwd.as_deref().map(CommandDir::parse),
+                    domain: reuse_domain,
+                })
+            }
+            .or_else(|| entry.as_ref().and_then(|entry| entry.reuse.clone()));
+
+            let window_target = match screen {
+                Some(screen) => Some(SpawnWindowTarget::NewWindowOnScreen(screen)),
+                None if under_mouse => Some(SpawnWindowTarget::WindowUnderMouse),
+                None => None,
+            };
+
+            let window_id = if new_window || window_target.is_some() {
+                None
+            } else {
+                match window_id {
+                    Some(w) => Some(w),
+                    None => {
+                        let pane_id = resolve_pane_id(&client, pane_id).await?;
+
+                        let panes = client.list_panes().await?;
+                        let mut window_id = None;
+                        'outer: for tabroot in panes.tabs {
+                            let mut cursor = tabroot.into_tree().cursor();
+
+                            loop {
+                                if let Some(entry) = cursor.leaf_mut() {
+                                    if entry.pane_id == pane_id {
+                                        window_id.replace(entry.window_id);
+                                        break 'outer;
+                                    }
+                                }
+                                match cursor.preorder_next() {
+                                    Ok(c) => cursor = c,
+                                    Err(_) => break,
+                                }
+                            }
+                        }
                         window_id
                     }
                 }
@@ -1010,21 +3156,52 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
 
             let size = config.initial_size(0);
 
+            let domain = match (domain_name, &entry) {
+                (Some(name), _) => SpawnTabDomain::DomainName(name),
+                // There is no current pane from which to take the domain
+                (None, Some(entry)) if entry.domain != SpawnTabDomain::CurrentPaneDomain => {
+                    entry.domain.clone()
+                }
+                (None, _) => SpawnTabDomain::DefaultDomain,
+            };
+
+            let command = match &entry {
+                Some(entry) => entry.args.as_ref().map(|args| {
+                    let mut builder =
+                        CommandBuilder::from_argv(args.iter().map(Into::into).collect());
+                    for (k, v) in entry.set_environment_variables.iter() {
+                        builder.env(k, v);
+                    }
+                    if let Some(label) = &entry.label {
+                        builder.label(label);
+                    }
+                    if let Some(exit_behavior) = entry.exit_behavior {
+                        builder.exit_behavior(exit_behavior.as_str());
+                    }
+                    builder
+                }),
+                None if prog.is_empty() => None,
+                None => Some(CommandBuilder::from_argv(prog)),
+            };
+
+            let no_domain_rules =
+                no_domain_rules || entry.as_ref().map_or(false, |entry| entry.no_domain_rules);
+            let command_dir = match canon_cwd(cwd)? {
+                Some(cwd) => Some(cwd),
+                None => entry.and_then(|entry| entry.cwd),
+            };
+
             let spawned = client
                 .spawn_v2(codec::SpawnV2 {
-                    domain: domain_name.map_or(SpawnTabDomain::DefaultDomain, |name| {
-                        SpawnTabDomain::DomainName(name)
-                    }),
+                    domain,
                     window_id,
-                    command: if prog.is_empty() {
-                        None
-                    } else {
-                        let builder = CommandBuilder::from_argv(prog);
-                        Some(builder)
-                    },
-                    command_dir: canon_cwd(cwd)?,
+                    command,
+                    command_dir,
                     size,
                     workspace,
+                    reuse,
+                    window_target,
+                    no_domain_rules,
                 })
                 .await?;
 
@@ -1041,7 +3218,11 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
             Mux::set_mux(&mux);
             let unix_dom = config.unix_domains.first().unwrap();
             let target = unix_dom.target();
-            let stream = unix_connect_with_retry(&target, false, None)?;
+            let mut stream = unix_connect_with_retry(&target, false, None)?;
+
+            // We're relaying a client on another host, so ask the server
+            // to treat it as remote.  There is no response to this PDU.
+            codec::Pdu::MarkSessionRemote(codec::MarkSessionRemote {}).encode(&mut stream, 0)?;
 
             // Spawn a thread to pull data from the socket and write
             // it to stdout
@@ -1066,15 +3247,29 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
             let creds = client.get_tls_creds().await?;
             codec::Pdu::GetTlsCredsResponse(creds).encode(std::io::stdout().lock(), 0)?;
         }
+        CliSubCommand::Schema { .. } => unreachable!("handled before connecting"),
     }
     Ok(())
 }
 
+/// Returns the exit status of `wezterm cli` for a request that failed
+/// because of `err`.  The statuses are listed in the documentation for
+/// `wezterm cli`, so they must not change.
+fn domain_error_exit_status(err: &DomainError) -> i32 {
+    10 + err.code() as i32
+}
+
 fn run_cli(config: config::ConfigHandle, cli: CliCommand) -> anyhow::Result<()> {
     let executor = promise::spawn::ScopedExecutor::new();
     match promise::spawn::block_on(executor.run(async move { run_cli_async(config, cli).await })) {
         Ok(_) => Ok(()),
-        Err(err) => terminate_with_error(err),
+        Err(err) => match DomainError::find(&err) {
+            Some(domain_err) => {
+                log::error!("{:#}; terminating", err);
+                std::process::exit(domain_error_exit_status(domain_err));
+            }
+            None => terminate_with_error(err),
+        },
     }
 }
 
@@ -15,3 +15,22 @@ pub struct ScreenInfo {
     pub rect: ScreenRect,
     pub scale: f64,
 }
+
+impl Screens {
+    /// Returns the screens from left to right, then from top to bottom,
+    /// which is the order in which `find` numbers them
+    pub fn ordered(&self) -> Vec<&ScreenInfo> {
+        let mut screens: Vec<&ScreenInfo> = self.by_name.values().collect();
+        screens.sort_by_key(|screen| (screen.rect.origin.x, screen.rect.origin.y, &screen.name));
+        screens
+    }
+
+    /// Returns the screen named `name_or_index`, or failing that, the
+    /// screen at that index in `ordered`
+    pub fn find(&self, name_or_index: &str) -> Option<&ScreenInfo> {
+        self.by_name.get(name_or_index).or_else(|| {
+            let index: usize = name_or_index.parse().ok()?;
+            self.ordered().get(index).copied()
+        })
+    }
+}